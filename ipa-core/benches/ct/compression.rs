@@ -0,0 +1,38 @@
+//! Measures the cost of compressing chunks of record streams, to pick the size below which
+//! chunks are sent uncompressed (`CompressionConfig::threshold`).
+//!
+//! Chunks hold boolean shares, one per byte, which is what wide boolean circuits send.
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use ipa_core::net::RecordEncoding;
+use rand::{thread_rng, Rng};
+
+pub fn criterion_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compression");
+
+    for size in [256_usize, 1024, 4096, 16384, 65536] {
+        let chunk = (0..size)
+            .map(|_| u8::from(thread_rng().gen::<bool>()))
+            .collect::<Vec<_>>();
+        group.throughput(Throughput::Bytes(size as u64));
+        for encoding in [RecordEncoding::Gzip, RecordEncoding::Zstd] {
+            let compressed = encoding.compress(&chunk).unwrap();
+            group.bench_with_input(
+                BenchmarkId::new(format!("{encoding}/compress"), size),
+                &chunk,
+                |b, chunk| b.iter(|| encoding.compress(black_box(chunk)).unwrap()),
+            );
+            // Compressed size is part of the id, so the report shows the ratio next to the timings.
+            group.bench_with_input(
+                BenchmarkId::new(
+                    format!("{encoding}/decompress"),
+                    format!("{size}/{}", compressed.len()),
+                ),
+                &compressed,
+                |b, compressed| b.iter(|| encoding.decompress(black_box(compressed)).unwrap()),
+            );
+        }
+    }
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -1,13 +1,13 @@
 use std::{
     num::{NonZeroU32, NonZeroU64, NonZeroUsize},
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 use clap::Parser;
 use ipa_core::{
     error::Error,
     ff::Fp32BitPrime,
-    helpers::{query::IpaQueryConfig, GatewayConfig},
+    helpers::{query::IpaQueryConfig, GatewayConfig, NetworkConditions},
     test_fixture::{
         ipa::{ipa_in_the_clear, test_ipa, test_oprf_ipa, CappingOrder, IpaSecurityModel},
         EventGenerator, EventGeneratorConfig, TestWorld, TestWorldConfig,
@@ -67,6 +67,12 @@ struct Args {
     /// Desired security model for IPA protocol
     #[arg(short = 'm', long, value_enum, default_value_t=IpaSecurityModel::Malicious)]
     mode: IpaSecurityModel,
+    /// One-way latency of links between helpers, in milliseconds.
+    #[arg(long, default_value = "0")]
+    latency_ms: u64,
+    /// Maximum random delay added to the latency, in milliseconds.
+    #[arg(long, default_value = "0")]
+    jitter_ms: u64,
     /// Needed for benches.
     #[arg(long, hide = true)]
     bench: bool,
@@ -81,6 +87,14 @@ impl Args {
             .unwrap_or_else(|| self.query_size.clamp(16, 1024))
     }
 
+    fn network_conditions(&self) -> NetworkConditions {
+        NetworkConditions {
+            latency: Duration::from_millis(self.latency_ms),
+            jitter: Duration::from_millis(self.jitter_ms),
+            bandwidth: None,
+        }
+    }
+
     fn attribution_window(&self) -> Option<NonZeroU32> {
         NonZeroU32::new(self.attribution_window)
     }
@@ -92,6 +106,7 @@ impl Args {
             attribution_window_seconds: self.attribution_window(),
             num_multi_bits: self.num_multi_bits,
             plaintext_match_keys: true,
+            ..Default::default()
         }
     }
 }
@@ -102,6 +117,7 @@ async fn run(args: Args) -> Result<(), Error> {
     let _prep_time = Instant::now();
     let config = TestWorldConfig {
         gateway_config: GatewayConfig::new(args.active()),
+        network_conditions: args.network_conditions(),
         ..TestWorldConfig::default()
     };
 
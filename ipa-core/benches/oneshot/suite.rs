@@ -0,0 +1,308 @@
+//! Runs a fixed set of protocol micro-benchmarks and prints the results as JSON, together with
+//! the information about the environment they were collected in. Reports are meant to be
+//! stored alongside the commit they were produced for. Passing a previous report via
+//! `--baseline` adds a comparison section that highlights regressions.
+use std::{
+    fs,
+    future::Future,
+    num::{NonZeroU32, NonZeroUsize},
+    path::PathBuf,
+    process::{Command, ExitCode},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use clap::Parser;
+use ipa_core::{
+    ff::{Fp31, Fp32BitPrime},
+    helpers::query::IpaQueryConfig,
+    test_fixture::{
+        circuit::{self, AdderStrategy},
+        ipa::{ipa_in_the_clear, test_oprf_ipa, CappingOrder},
+        EventGenerator, EventGeneratorConfig, TestWorld,
+    },
+};
+use rand::{rngs::StdRng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+#[cfg(not(target_env = "msvc"))]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+#[derive(Debug, Parser)]
+#[command(about, long_about = None)]
+struct Args {
+    /// How many times each benchmark is executed. Median is used for comparison.
+    #[arg(short = 'i', long, default_value = "5")]
+    iterations: NonZeroUsize,
+    /// Width of the multiplication circuit.
+    #[arg(long, default_value_t = 10_000)]
+    mul_width: u32,
+    /// Depth of the multiplication circuit.
+    #[arg(long, default_value_t = 8)]
+    mul_depth: u8,
+    /// Number of additions that are executed in parallel by the adder benchmark.
+    #[arg(long, default_value_t = 1)]
+    adder_width: u32,
+    /// Number of rows processed by the attribution benchmark.
+    #[arg(long, default_value_t = 1000)]
+    attribution_rows: usize,
+    /// Seed for the attribution input. It is fixed by default, so reports produced for
+    /// different commits process the same input.
+    #[arg(short = 's', long, default_value_t = 0)]
+    random_seed: u64,
+    /// Write the report to this file instead of standard output.
+    #[arg(short = 'o', long)]
+    output: Option<PathBuf>,
+    /// Report produced by a previous run to compare against.
+    #[arg(long)]
+    baseline: Option<PathBuf>,
+    /// Change (in percent) of the median in the wrong direction that is considered a regression.
+    #[arg(long, default_value_t = 10.0)]
+    threshold: f64,
+    /// Exit with a non-zero code if any benchmark regressed compared to the baseline.
+    #[arg(long, requires = "baseline")]
+    fail_on_regression: bool,
+    /// Cargo passes the bench argument
+    /// https://doc.rust-lang.org/cargo/commands/cargo-bench.html
+    #[arg(long, hide = true)]
+    bench: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Environment {
+    git_commit: Option<String>,
+    git_dirty: Option<bool>,
+    /// Seconds since the Unix epoch.
+    timestamp: u64,
+    os: String,
+    arch: String,
+    cpus: usize,
+    version: String,
+    debug_assertions: bool,
+}
+
+impl Environment {
+    fn current() -> Self {
+        Self {
+            git_commit: git(&["rev-parse", "HEAD"]),
+            git_dirty: git(&["status", "--porcelain"]).map(|s| !s.is_empty()),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            os: std::env::consts::OS.to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+            cpus: std::thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            debug_assertions: cfg!(debug_assertions),
+        }
+    }
+}
+
+/// Returns the trimmed output of a git command or `None` if git is not available.
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BenchResult {
+    name: String,
+    unit: String,
+    higher_is_better: bool,
+    median: f64,
+    samples: Vec<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Comparison {
+    name: String,
+    baseline: f64,
+    current: f64,
+    change_percent: f64,
+    regression: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Report {
+    environment: Environment,
+    results: Vec<BenchResult>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    baseline: Option<Environment>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    comparison: Vec<Comparison>,
+}
+
+impl Report {
+    fn compare(&mut self, baseline: Report, threshold: f64) {
+        self.comparison = self
+            .results
+            .iter()
+            .filter_map(|current| {
+                let base = baseline.results.iter().find(|b| b.name == current.name)?;
+                let change_percent = (current.median - base.median) / base.median * 100.0;
+                let regression = if current.higher_is_better {
+                    change_percent < -threshold
+                } else {
+                    change_percent > threshold
+                };
+                Some(Comparison {
+                    name: current.name.clone(),
+                    baseline: base.median,
+                    current: current.median,
+                    change_percent,
+                    regression,
+                })
+            })
+            .collect();
+        self.baseline = Some(baseline.environment);
+    }
+}
+
+/// Runs `f` the given number of times and converts the time each run took into the metric.
+async fn measure<F, Fut>(
+    name: &str,
+    unit: &str,
+    higher_is_better: bool,
+    iterations: NonZeroUsize,
+    f: F,
+    metric: impl Fn(Duration) -> f64,
+) -> BenchResult
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut samples = Vec::with_capacity(iterations.get());
+    for _ in 0..iterations.get() {
+        let start = Instant::now();
+        f().await;
+        samples.push(metric(start.elapsed()));
+    }
+
+    let mut sorted = samples.clone();
+    sorted.sort_by(f64::total_cmp);
+    let median = sorted[sorted.len() / 2];
+    eprintln!("{name}: {median:.2} {unit}");
+
+    BenchResult {
+        name: name.to_string(),
+        unit: unit.to_string(),
+        higher_is_better,
+        median,
+        samples,
+    }
+}
+
+async fn attribution(args: &Args) {
+    let config = IpaQueryConfig {
+        per_user_credit_cap: 8,
+        max_breakdown_key: 16,
+        attribution_window_seconds: NonZeroU32::new(86_400),
+        num_multi_bits: 3,
+        plaintext_match_keys: true,
+        ..Default::default()
+    };
+    let mut raw_data = EventGenerator::with_config(
+        StdRng::seed_from_u64(args.random_seed),
+        EventGeneratorConfig {
+            max_breakdown_key: NonZeroU32::new(config.max_breakdown_key).unwrap(),
+            max_events_per_user: NonZeroU32::new(50).unwrap(),
+            ..Default::default()
+        },
+    )
+    .take(args.attribution_rows)
+    .collect::<Vec<_>>();
+    raw_data.sort_by_key(|e| e.timestamp);
+    let expected = ipa_in_the_clear(
+        &raw_data,
+        config.per_user_credit_cap,
+        config.attribution_window_seconds,
+        config.max_breakdown_key,
+        &CappingOrder::CapMostRecentFirst,
+    );
+
+    let world = TestWorld::default();
+    test_oprf_ipa::<Fp32BitPrime>(&world, raw_data, &expected, config).await;
+}
+
+#[tokio::main(flavor = "multi_thread", worker_threads = 3)]
+async fn main() -> ExitCode {
+    let args = Args::parse();
+    let iterations = args.iterations;
+    #[allow(clippy::cast_precision_loss)]
+    let attribution_rows = args.attribution_rows as f64;
+
+    let results = vec![
+        measure(
+            "multiply_throughput",
+            "mults/s",
+            true,
+            iterations,
+            || circuit::arithmetic::<Fp31>(args.mul_width, args.mul_depth),
+            |d| f64::from(args.mul_width) * f64::from(args.mul_depth) / d.as_secs_f64(),
+        )
+        .await,
+        measure(
+            "adder_latency",
+            "ms",
+            false,
+            iterations,
+            || circuit::adder(args.adder_width, AdderStrategy::RippleCarry),
+            |d| d.as_secs_f64() * 1000.0,
+        )
+        .await,
+        measure(
+            "parallel_prefix_adder_latency",
+            "ms",
+            false,
+            iterations,
+            || circuit::adder(args.adder_width, AdderStrategy::ParallelPrefix),
+            |d| d.as_secs_f64() * 1000.0,
+        )
+        .await,
+        measure(
+            "attribution_throughput",
+            "rows/s",
+            true,
+            iterations,
+            || attribution(&args),
+            |d| attribution_rows / d.as_secs_f64(),
+        )
+        .await,
+    ];
+
+    let mut report = Report {
+        environment: Environment::current(),
+        results,
+        baseline: None,
+        comparison: Vec::new(),
+    };
+    if let Some(path) = &args.baseline {
+        let baseline = serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+        report.compare(baseline, args.threshold);
+        for c in &report.comparison {
+            eprintln!(
+                "{name}: {baseline:.2} -> {current:.2} ({change:+.1}%){flag}",
+                name = c.name,
+                baseline = c.baseline,
+                current = c.current,
+                change = c.change_percent,
+                flag = if c.regression { " REGRESSION" } else { "" },
+            );
+        }
+    }
+
+    let json = serde_json::to_string_pretty(&report).unwrap();
+    match &args.output {
+        Some(path) => fs::write(path, json).unwrap(),
+        None => println!("{json}"),
+    }
+
+    if args.fail_on_regression && report.comparison.iter().any(|c| c.regression) {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
@@ -0,0 +1,41 @@
+//! Deserializes boolean arrays of every width used in reports. Accepted values must survive a
+//! serialization round trip and must not have any bits set past the width of the array.
+#![no_main]
+
+use generic_array::GenericArray;
+use ipa_core::{
+    ff::{
+        boolean::Boolean,
+        boolean_array::{BA20, BA256, BA3, BA32, BA4, BA5, BA6, BA64, BA7, BA8},
+        Serializable,
+    },
+    secret_sharing::replicated::semi_honest::AdditiveShare,
+};
+use libfuzzer_sys::fuzz_target;
+use typenum::Unsigned;
+
+fn round_trip<T: Serializable>(data: &[u8]) {
+    for chunk in data.chunks_exact(T::Size::USIZE) {
+        if let Ok(v) = T::try_deserialize(GenericArray::from_slice(chunk)) {
+            let mut buf = GenericArray::default();
+            v.serialize(&mut buf);
+            assert_eq!(chunk, buf.as_slice());
+        }
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    round_trip::<Boolean>(data);
+    round_trip::<BA3>(data);
+    round_trip::<BA4>(data);
+    round_trip::<BA5>(data);
+    round_trip::<BA6>(data);
+    round_trip::<BA7>(data);
+    round_trip::<BA8>(data);
+    round_trip::<BA20>(data);
+    round_trip::<BA32>(data);
+    round_trip::<BA64>(data);
+    round_trip::<BA256>(data);
+    round_trip::<AdditiveShare<BA3>>(data);
+    round_trip::<AdditiveShare<BA20>>(data);
+});
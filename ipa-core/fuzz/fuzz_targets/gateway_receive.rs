@@ -0,0 +1,79 @@
+//! Receives records sent by another helper. Payloads are framed the same way the sending end of
+//! the gateway does it, then the transport is free to split the byte stream at any point. Invalid
+//! records must be reported as errors and must not prevent the following records from being
+//! received. Payloads may also be sent without frames, as a misbehaving peer could do, in which
+//! case the stream must end with an error rather than a panic.
+#![no_main]
+
+use std::num::NonZeroUsize;
+
+use futures::{executor::block_on, stream};
+use ipa_core::{
+    ff::{curve_points::RP25519, Fp32BitPrime},
+    helpers::{frame, ChannelId, ChecksumValidator, Error, Role, UnorderedReceiver},
+};
+use libfuzzer_sys::{
+    arbitrary::{self, Arbitrary},
+    fuzz_target,
+};
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    payloads: Vec<Vec<u8>>,
+    /// Whether payloads are framed, or sent to the receiving end as they are.
+    framed: bool,
+    /// Sizes of the chunks the framed byte stream is split into.
+    splits: Vec<u8>,
+    curve_points: bool,
+}
+
+impl Input {
+    fn chunks(&self) -> Vec<Vec<u8>> {
+        let bytes = if self.framed {
+            self.payloads
+                .iter()
+                .cloned()
+                .flat_map(frame)
+                .collect::<Vec<_>>()
+        } else {
+            self.payloads.concat()
+        };
+
+        let mut chunks = Vec::new();
+        let mut rest = bytes.as_slice();
+        for &split in self.splits.iter().cycle().take(rest.len()) {
+            if rest.is_empty() {
+                break;
+            }
+            let (chunk, tail) = rest.split_at(usize::from(split).min(rest.len()));
+            chunks.push(chunk.to_vec());
+            rest = tail;
+        }
+        chunks.push(rest.to_vec());
+
+        chunks
+    }
+}
+
+fn receive_all<M: ipa_core::helpers::Message>(chunks: Vec<Vec<u8>>) {
+    let channel_id = ChannelId::new(Role::H1, "fuzz".into());
+    let receiver = UnorderedReceiver::new(
+        Box::pin(ChecksumValidator::new(channel_id, stream::iter(chunks))),
+        NonZeroUsize::new(16).unwrap(),
+    );
+    for i in 0_usize.. {
+        match block_on(receiver.recv::<M, _>(i)) {
+            Ok(_) | Err(Error::DeserializationError { .. }) => {}
+            Err(_) => break,
+        }
+    }
+}
+
+fuzz_target!(|input: Input| {
+    let chunks = input.chunks();
+    if input.curve_points {
+        receive_all::<RP25519>(chunks);
+    } else {
+        receive_all::<Fp32BitPrime>(chunks);
+    }
+});
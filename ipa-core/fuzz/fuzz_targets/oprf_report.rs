@@ -0,0 +1,27 @@
+//! Deserializes OPRF reports submitted by report collectors. Any byte sequence that is accepted
+//! must be a canonical encoding, i.e. serializing the report again produces the same bytes.
+#![no_main]
+
+use generic_array::GenericArray;
+use ipa_core::{
+    ff::{
+        boolean_array::{BA20, BA3, BA8},
+        Serializable,
+    },
+    report::OprfReport,
+};
+use libfuzzer_sys::fuzz_target;
+use typenum::Unsigned;
+
+type Report = OprfReport<BA8, BA3, BA20>;
+
+fuzz_target!(|data: &[u8]| {
+    let sz = <Report as Serializable>::Size::USIZE;
+    for chunk in data.chunks_exact(sz) {
+        if let Ok(report) = Report::try_deserialize(GenericArray::from_slice(chunk)) {
+            let mut buf = GenericArray::default();
+            report.serialize(&mut buf);
+            assert_eq!(chunk, buf.as_slice());
+        }
+    }
+});
@@ -0,0 +1,57 @@
+//! Parses query input as it arrives from report collectors: a stream of arbitrarily sized chunks
+//! that carry either fixed-size OPRF reports or length-delimited encrypted reports. Parsing may
+//! fail, but it must never panic.
+#![no_main]
+
+use bytes::Bytes;
+use futures::{executor::block_on, stream, StreamExt};
+use ipa_core::{
+    ff::{
+        boolean_array::{BA20, BA3, BA8},
+        Fp32BitPrime, Gf40Bit, Gf8Bit,
+    },
+    helpers::{LengthDelimitedStream, RecordsStream},
+    hpke::{KeyPair, KeyRegistry},
+    report::{EncryptedReport, OprfReport},
+};
+use libfuzzer_sys::{
+    arbitrary::{self, Arbitrary},
+    fuzz_target,
+};
+use rand::{rngs::StdRng, SeedableRng};
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    chunks: Vec<Vec<u8>>,
+}
+
+impl Input {
+    fn stream(
+        &self,
+    ) -> impl futures::Stream<Item = Result<Bytes, ipa_core::error::BoxError>> + Send {
+        stream::iter(
+            self.chunks
+                .iter()
+                .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+                .collect::<Vec<_>>(),
+        )
+    }
+}
+
+fuzz_target!(|input: Input| {
+    block_on(async {
+        let mut reports = RecordsStream::<OprfReport<BA8, BA3, BA20>, _>::new(input.stream());
+        while let Some(Ok(_)) = reports.next().await {}
+
+        let key_registry = KeyRegistry::<KeyPair>::random(1, &mut StdRng::seed_from_u64(0));
+        let mut reports = LengthDelimitedStream::<
+            EncryptedReport<Fp32BitPrime, Gf40Bit, Gf8Bit, Bytes>,
+            _,
+        >::new(input.stream());
+        while let Some(Ok(batch)) = reports.next().await {
+            for report in batch {
+                let _ = report.decrypt(&key_registry);
+            }
+        }
+    });
+});
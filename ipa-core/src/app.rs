@@ -33,7 +33,14 @@ impl Setup {
     pub fn with_key_registry(
         key_registry: KeyRegistry<KeyPair>,
     ) -> (Self, TransportCallbacks<TransportImpl>) {
-        let query_processor = Arc::new(QueryProcessor::new(key_registry));
+        Self::with_query_processor(QueryProcessor::new(key_registry))
+    }
+
+    #[must_use]
+    pub fn with_query_processor(
+        query_processor: QueryProcessor,
+    ) -> (Self, TransportCallbacks<TransportImpl>) {
+        let query_processor = Arc::new(query_processor);
         let this = Self {
             query_processor: Arc::clone(&query_processor),
         };
@@ -54,6 +61,7 @@ impl Setup {
         let iqp = Arc::clone(query_processor);
         let sqp = Arc::clone(query_processor);
         let cqp = Arc::clone(query_processor);
+        let mqp = Arc::clone(query_processor);
 
         TransportCallbacks {
             receive_query: Box::new(move |transport: TransportImpl, receive_query| {
@@ -74,12 +82,16 @@ impl Setup {
             }),
             query_status: Box::new(move |_transport: TransportImpl, query_id| {
                 let processor = Arc::clone(&sqp);
-                Box::pin(async move { processor.query_status(query_id) })
+                Box::pin(async move { processor.query_status_report(query_id) })
             }),
             complete_query: Box::new(move |_transport: TransportImpl, query_id| {
                 let processor = Arc::clone(&cqp);
                 Box::pin(async move { processor.complete(query_id).await })
             }),
+            metrics: Box::new(move |_transport: TransportImpl| {
+                let processor = Arc::clone(&mqp);
+                Box::pin(async move { processor.metrics() })
+            }),
         }
     }
 }
@@ -1,21 +1,31 @@
 use std::{
     fs,
     net::TcpListener,
+    num::{NonZeroU32, NonZeroU64, NonZeroUsize},
     os::fd::{FromRawFd, RawFd},
     path::{Path, PathBuf},
     process,
+    sync::Arc,
+    time::Duration,
 };
 
 use clap::{self, Parser, Subcommand};
 use hyper::http::uri::Scheme;
 use ipa_core::{
     cli::{
-        client_config_setup, keygen, test_setup, ConfGenArgs, KeygenArgs, TestSetupArgs, Verbosity,
+        client_config_setup, keygen, test_setup, validate_config, ConfGenArgs, KeygenArgs,
+        TestSetupArgs, ValidateConfigArgs, Verbosity,
+    },
+    config::{
+        epoch_key_schedule, hpke_registry, persistent_prf_key, HpkeServerConfig, NetworkConfig,
+        ServerConfig, TlsConfig,
     },
-    config::{hpke_registry, HpkeServerConfig, NetworkConfig, ServerConfig, TlsConfig},
     error::BoxError,
-    helpers::HelperIdentity,
+    helpers::{HeartbeatConfig, HelperIdentity},
     net::{ClientIdentity, HttpTransport, MpcHelperClient},
+    query::{
+        FsResultsStore, QueryLog, QueryProcessor, ReportSigner, ResultCache, ResultCacheConfig,
+    },
     AppSetup,
 };
 use tracing::{error, info};
@@ -88,6 +98,67 @@ struct ServerArgs {
     /// Private key for decrypting match keys
     #[arg(long, requires = "mk_public_key")]
     mk_private_key: Option<PathBuf>,
+
+    /// File with an epoch and the hex-encoded secret for it, which keys for decrypting match keys
+    /// of that and later epochs are derived from. Reports encrypted to epoch keys can't be opened
+    /// unless this is set.
+    #[arg(long)]
+    epoch_keys: Option<PathBuf>,
+
+    /// Number of epochs to derive keys for, starting from the epoch in the `epoch_keys` file
+    #[arg(long, default_value = "8", requires = "epoch_keys")]
+    epoch_key_count: u16,
+
+    /// File with the components of the PRF key this helper shares with each of its peers. Match
+    /// key pseudonyms can't be exported unless this is set, and are only valid for as long as the
+    /// key is kept.
+    #[arg(long)]
+    prf_key: Option<PathBuf>,
+
+    /// Directory to keep outputs of completed queries in, so they can be retrieved again later,
+    /// including after a restart
+    #[arg(long)]
+    results_dir: Option<PathBuf>,
+
+    /// Directory to log inputs and outputs of completed queries in, so they can be run again
+    /// later. Logging is disabled unless this is set.
+    #[arg(long)]
+    query_log_dir: Option<PathBuf>,
+
+    /// Number of most recent queries to keep in the query log
+    #[arg(long, default_value = "10")]
+    query_log_retention: NonZeroUsize,
+
+    /// Seconds between heartbeats sent to other helpers while a query is running. Heartbeats are
+    /// disabled unless this is set.
+    #[arg(long)]
+    heartbeat_interval: Option<NonZeroU64>,
+
+    /// Number of heartbeats in a row another helper may miss before queries it takes part in
+    /// fail
+    #[arg(long, default_value = "3")]
+    heartbeat_max_missed: NonZeroU32,
+
+    /// Number of query outputs to keep in memory, so queries submitted again with the same input
+    /// are not run again. Caching is disabled unless this is set.
+    #[arg(long)]
+    result_cache_size: Option<NonZeroUsize>,
+
+    /// Seconds a cached query output can be reused for
+    #[arg(long, default_value = "3600")]
+    result_cache_ttl: u64,
+
+    /// File with the hex-encoded Ed25519 secret key this helper signs query reports with. Reports
+    /// are handed to report collectors along with query outputs. They are not produced unless this
+    /// is set.
+    #[arg(long)]
+    report_signing_key: Option<PathBuf>,
+
+    /// File with the token operators present to admin endpoints of this helper, such as the one
+    /// that updates the network configuration without a restart. Admin endpoints are disabled
+    /// unless this is set.
+    #[arg(long)]
+    admin_token_file: Option<PathBuf>,
 }
 
 #[derive(Debug, Subcommand)]
@@ -95,6 +166,7 @@ enum HelperCommand {
     Confgen(ConfGenArgs),
     Keygen(KeygenArgs),
     TestSetup(TestSetupArgs),
+    ValidateConfig(ValidateConfigArgs),
 }
 
 fn read_utf8_bytes(path: &Path) -> Result<Vec<u8>, BoxError> {
@@ -130,14 +202,60 @@ async fn server(args: ServerArgs) -> Result<(), BoxError> {
             private_key_file: sk_path,
         });
 
-    let key_registry = hpke_registry(mk_encryption.as_ref()).await?;
-    let (setup, callbacks) = AppSetup::with_key_registry(key_registry);
+    let mut key_registry = hpke_registry(mk_encryption.as_ref()).await?;
+    if let Some(path) = args.epoch_keys {
+        let schedule = epoch_key_schedule(&path).await?;
+        key_registry = key_registry.with_epoch_keys(schedule.key_pairs(args.epoch_key_count));
+    }
+    let mut query_processor = QueryProcessor::new(key_registry);
+    if let Some(path) = args.prf_key {
+        query_processor = query_processor.with_prf_key(Arc::new(persistent_prf_key(&path).await?));
+    }
+    if let Some(results_dir) = args.results_dir {
+        query_processor =
+            query_processor.with_results_store(Arc::new(FsResultsStore::new(results_dir)?));
+    }
+    if let Some(query_log_dir) = args.query_log_dir {
+        query_processor = query_processor.with_query_log(Arc::new(QueryLog::new(
+            query_log_dir,
+            args.query_log_retention,
+        )?));
+    }
+    if let Some(interval) = args.heartbeat_interval {
+        query_processor = query_processor.with_heartbeat(HeartbeatConfig {
+            interval: Duration::from_secs(interval.get()),
+            max_missed: args.heartbeat_max_missed,
+        });
+    }
+    if let Some(capacity) = args.result_cache_size {
+        query_processor =
+            query_processor.with_result_cache(Arc::new(ResultCache::new(ResultCacheConfig {
+                capacity,
+                ttl: Duration::from_secs(args.result_cache_ttl),
+            })));
+    }
+    if let Some(path) = args.report_signing_key {
+        query_processor =
+            query_processor.with_report_signer(Arc::new(ReportSigner::from_file(path)?));
+    }
+    let (setup, callbacks) = AppSetup::with_query_processor(query_processor);
 
+    let admin_token = match args.admin_token_file {
+        Some(path) => {
+            let token = fs::read_to_string(&path)?.trim().to_owned();
+            if token.is_empty() {
+                return Err(format!("admin token file {} is empty", path.display()).into());
+            }
+            Some(token)
+        }
+        None => None,
+    };
     let server_config = ServerConfig {
         port: args.port,
         disable_https: args.disable_https,
         tls: server_tls,
         hpke_config: mk_encryption,
+        admin_token,
     };
 
     let scheme = if args.disable_https {
@@ -199,6 +317,7 @@ pub async fn main() {
         Some(HelperCommand::Keygen(args)) => keygen(&args),
         Some(HelperCommand::TestSetup(args)) => test_setup(args),
         Some(HelperCommand::Confgen(args)) => client_config_setup(args),
+        Some(HelperCommand::ValidateConfig(args)) => validate_config(&args),
     };
 
     if let Err(e) = res {
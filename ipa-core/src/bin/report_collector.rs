@@ -14,8 +14,11 @@ use comfy_table::{Cell, Table};
 use hyper::http::uri::Scheme;
 use ipa_core::{
     cli::{
-        noise::{apply, ApplyDpArgs},
-        playbook::{make_clients, playbook_ipa, playbook_oprf_ipa, validate, InputSource},
+        noise::{apply, estimate, ApplyDpArgs, EstimateDpArgs},
+        playbook::{
+            make_clients, playbook_ipa, playbook_oprf_ipa, replay_ipa, validate, InputSource,
+            ReplayedQuery,
+        },
         CsvSerializer, IpaQueryResult, Verbosity,
     },
     config::NetworkConfig,
@@ -24,6 +27,7 @@ use ipa_core::{
     hpke::{KeyRegistry, PublicKeyOnly},
     net::MpcHelperClient,
     protocol::{BreakdownKey, MatchKey},
+    query::LogEntry,
     report::{KeyIdentifier, DEFAULT_KEY_ID},
     test_fixture::{
         ipa::{ipa_in_the_clear, CappingOrder, IpaQueryStyle, IpaSecurityModel, TestRawDataRecord},
@@ -103,8 +107,16 @@ enum ReportCollectorCommand {
     },
     /// Apply differential privacy noise to IPA inputs
     ApplyDpNoise(ApplyDpArgs),
+    /// Estimate the noise helpers add to IPA outputs, without running a query
+    EstimateDpNoise(EstimateDpArgs),
     /// Execute OPRF IPA in a semi-honest majority setting
     OprfIpa(IpaQueryConfig),
+    /// Run an IPA query logged by helpers again and compare the outputs of both runs
+    Replay {
+        /// Query log entries, one from each helper
+        #[arg(num_args = 3, required = true)]
+        entries: Vec<PathBuf>,
+    },
 }
 
 #[derive(Debug, clap::Args)]
@@ -157,6 +169,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
             gen_args,
         } => gen_inputs(count, seed, args.output_file, gen_args)?,
         ReportCollectorCommand::ApplyDpNoise(ref dp_args) => apply_dp_noise(&args, dp_args)?,
+        ReportCollectorCommand::EstimateDpNoise(ref dp_args) => estimate_dp_noise(&args, dp_args)?,
         ReportCollectorCommand::OprfIpa(config) => {
             ipa(
                 &args,
@@ -168,6 +181,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
             )
             .await?
         }
+        ReportCollectorCommand::Replay { ref entries } => replay(&args, entries, &clients).await?,
     };
 
     Ok(())
@@ -233,6 +247,7 @@ impl KeyRegistries {
     }
 }
 
+#[tracing::instrument("ipa", skip_all, fields(security_model = ?security_model))]
 async fn ipa(
     args: &Args,
     network: &NetworkConfig,
@@ -352,6 +367,69 @@ async fn ipa(
     Ok(())
 }
 
+async fn replay(
+    args: &Args,
+    entries: &[PathBuf],
+    helper_clients: &[MpcHelperClient; 3],
+) -> Result<(), Box<dyn Error>> {
+    let entries: [LogEntry; 3] = std::array::from_fn(|i| LogEntry::open(&entries[i]));
+    let ReplayedQuery { logged, replayed } = replay_ipa(entries, helper_clients).await?;
+
+    if let Some(ref path) = args.output_file {
+        let mut file = File::options()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .map_err(|e| format!("Failed to create output file {}: {e}", path.display()))?;
+
+        write!(file, "{}", serde_json::to_string_pretty(&replayed)?)?;
+    }
+
+    validate(&logged, &replayed.breakdowns);
+
+    Ok(())
+}
+
+fn estimate_dp_noise(args: &Args, dp_args: &EstimateDpArgs) -> Result<(), Box<dyn Error>> {
+    let output = estimate(dp_args)?;
+    let mut table = Table::new();
+    let header = ["Epsilon".to_string(), "Std".to_string()]
+        .into_iter()
+        .chain(
+            dp_args
+                .volume
+                .iter()
+                .map(|volume| format!("Error at {volume}")),
+        )
+        .collect::<Vec<_>>();
+    table.set_header(header);
+
+    for (epsilon, impact) in &output {
+        let row = [
+            Cell::new(format!("{epsilon:.3}")),
+            Cell::new(format!("{:.3}", impact.std)),
+        ]
+        .into_iter()
+        .chain(
+            impact
+                .relative_errors
+                .iter()
+                .map(|error| Cell::new(format!("{:.2}%", 100.0 * error))),
+        )
+        .collect::<Vec<_>>();
+        table.add_row(row);
+    }
+
+    println!("{table}");
+
+    if let Some(file) = &args.output_file {
+        let mut file = File::create(file)?;
+        serde_json::to_writer_pretty(&mut file, &output)?;
+    }
+
+    Ok(())
+}
+
 fn apply_dp_noise(args: &Args, dp_args: &ApplyDpArgs) -> Result<(), Box<dyn Error>> {
     let IpaQueryResult { breakdowns, .. } =
         serde_json::from_slice(&InputSource::from(&args.input).to_vec()?)?;
@@ -13,4 +13,8 @@ pub struct QueryResult {
     )]
     pub latency: Duration,
     pub breakdowns: Vec<u32>,
+    /// Number of users with at least one attributed conversion in every breakdown, if the query
+    /// counts them.
+    #[serde(default)]
+    pub attributed_users: Option<Vec<u32>>,
 }
@@ -12,6 +12,8 @@ mod paths;
 pub mod playbook;
 #[cfg(feature = "web-app")]
 mod test_setup;
+#[cfg(feature = "web-app")]
+mod validate_config;
 mod verbosity;
 
 #[cfg(feature = "web-app")]
@@ -24,4 +26,6 @@ pub use metric_collector::{install_collector, CollectorHandle};
 pub use paths::PathExt as CliPaths;
 #[cfg(feature = "web-app")]
 pub use test_setup::{test_setup, TestSetupArgs};
+#[cfg(feature = "web-app")]
+pub use validate_config::{validate_config, ValidateConfigArgs};
 pub use verbosity::Verbosity;
@@ -7,7 +7,7 @@ use clap::Args;
 use rand::rngs::StdRng;
 use rand_core::SeedableRng;
 
-use crate::protocol::dp::InsecureDiscreteDp;
+use crate::protocol::dp::{Error, InsecureDiscreteDp, NoiseEstimate};
 
 #[derive(Debug, Args)]
 #[clap(about = "Apply differential privacy noise to the given input")]
@@ -29,6 +29,30 @@ pub struct ApplyDpArgs {
     cap: u32,
 }
 
+#[derive(Debug, Args)]
+#[clap(about = "Estimate the impact of the noise helpers add to the output of a query")]
+pub struct EstimateDpArgs {
+    /// Various epsilon values to estimate the noise for.
+    #[arg(long, short = 'e', required = true)]
+    epsilon: Vec<f64>,
+
+    /// Delta parameter for (\epsilon, \delta) DP.
+    #[arg(long, short = 'd', default_value = "1e-7")]
+    delta: f64,
+
+    /// Maximum contribution allowed per user to all breakdowns combined.
+    #[arg(long, short = 'c')]
+    cap: u32,
+
+    /// Number of breakdowns in the output.
+    #[arg(long, short = 'b')]
+    breakdowns: u32,
+
+    /// Totals of all breakdowns to report the relative error for.
+    #[arg(long, short = 'v')]
+    pub volume: Vec<u64>,
+}
+
 #[derive(Debug)]
 #[cfg_attr(feature = "enable-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NoisyOutput {
@@ -111,3 +135,36 @@ pub fn apply<I: AsRef<[u32]>>(input: I, args: &ApplyDpArgs) -> BTreeMap<EpsilonB
 
     result
 }
+
+#[derive(Debug)]
+#[cfg_attr(feature = "enable-serde", derive(serde::Serialize))]
+pub struct NoiseImpact {
+    /// Standard deviation of the noise added to every breakdown.
+    pub std: f64,
+    /// Relative error of a single breakdown for every volume, in the order they were given.
+    pub relative_errors: Box<[f64]>,
+}
+
+/// Estimates the noise helpers add for every epsilon in `args`, see [`NoiseEstimate`].
+///
+/// ## Errors
+/// If helpers would refuse to add noise for any of the epsilons.
+pub fn estimate(args: &EstimateDpArgs) -> Result<BTreeMap<EpsilonBits, NoiseImpact>, Error> {
+    args.epsilon
+        .iter()
+        .map(|&epsilon| {
+            let estimate = NoiseEstimate::new(epsilon, args.delta, args.cap, args.breakdowns)?;
+            Ok((
+                epsilon.into(),
+                NoiseImpact {
+                    std: estimate.std(),
+                    relative_errors: args
+                        .volume
+                        .iter()
+                        .map(|&volume| estimate.relative_error(volume))
+                        .collect(),
+                },
+            ))
+        })
+        .collect()
+}
@@ -16,8 +16,8 @@ use crate::{
     cli::IpaQueryResult,
     ff::{PrimeField, Serializable},
     helpers::{
-        query::{IpaQueryConfig, QueryInput, QuerySize},
-        BodyStream,
+        query::{BreakdownKeyOverflow, InputPart, IpaQueryConfig, QueryInput, QuerySize},
+        BodyStream, Role,
     },
     hpke::PublicKeyRegistry,
     ipa_test_input,
@@ -139,6 +139,7 @@ where
     run_query_and_validate::<F>(inputs, query_size, clients, query_id, query_config).await
 }
 
+#[tracing::instrument("run_query", skip_all, fields(query_id = %query_id, sz = query_size))]
 pub async fn run_query_and_validate<F>(
     inputs: [BodyStream; 3],
     query_size: usize,
@@ -158,6 +159,7 @@ where
             .map(|(input_stream, client)| {
                 client.query_input(QueryInput {
                     query_id,
+                    part: InputPart::All,
                     input_stream,
                 })
             }),
@@ -167,19 +169,39 @@ where
 
     let mut delay = Duration::from_millis(125);
     loop {
-        if try_join_all(clients.iter().map(|client| client.query_status(query_id)))
+        let reports = try_join_all(clients.iter().map(|client| client.query_status(query_id)))
             .await
-            .unwrap()
-            .into_iter()
-            .all(|status| status == QueryStatus::Completed)
+            .unwrap();
+        if reports
+            .iter()
+            .all(|report| report.status == QueryStatus::Completed)
         {
+            for (role, report) in Role::all().iter().zip(&reports) {
+                for stage in report.metrics.iter().flat_map(|m| &m.stages) {
+                    tracing::info!(
+                        "{role:?}: {} took {:?}, sent {} and received {} bytes",
+                        stage.name,
+                        stage.elapsed,
+                        stage.bytes_sent,
+                        stage.bytes_received,
+                    );
+                }
+            }
             break;
         }
 
+        // Helpers run the protocol in lockstep, the slowest one determines how far along it is.
+        if let Some(percent) = reports
+            .iter()
+            .filter_map(|report| report.progress?.percent_complete())
+            .min_by(f64::total_cmp)
+        {
+            tracing::info!("Query {query_id} is {percent:.1}% complete");
+        }
+
         sleep(delay).await;
         delay = min(Duration::from_secs(5), delay * 2);
-        // TODO: Add a timeout of some sort. Possibly, add some sort of progress indicator to
-        // the status API so we can check whether the query is making progress.
+        // TODO: Add a timeout of some sort.
     }
 
     // wait until helpers have processed the query and get the results from them
@@ -189,31 +211,84 @@ where
         .try_into()
         .unwrap();
 
-    let results: Vec<F> = results
-        .map(|bytes| AdditiveShare::<F>::from_byte_slice(&bytes).collect::<Vec<_>>())
-        .reconstruct();
-
     let lat = mpc_time.elapsed();
 
     tracing::info!("Running IPA for {query_size:?} records took {t:?}", t = lat);
-    let mut breakdowns = vec![0; usize::try_from(query_config.max_breakdown_key).unwrap()];
+    let (breakdowns, attributed_users) = reconstruct_ipa_output::<F, _>(&results, &query_config);
+
+    IpaQueryResult {
+        input_size: QuerySize::try_from(query_size).unwrap(),
+        config: query_config,
+        latency: lat,
+        breakdowns,
+        attributed_users,
+    }
+}
+
+/// Reconstructs per-breakdown totals from the results returned by each helper, along with
+/// attributed user counts if the query counts them. Helpers return the counts after the totals.
+///
+/// ## Panics
+/// If trigger values or users were attributed to breakdowns that do not exist.
+pub fn reconstruct_ipa_output<F, B>(
+    results: &[B; 3],
+    query_config: &IpaQueryConfig,
+) -> (Vec<u32>, Option<Vec<u32>>)
+where
+    F: PrimeField,
+    AdditiveShare<F>: Serializable,
+    B: AsRef<[u8]>,
+{
+    // reports with breakdown keys that are too wide may be attributed to an extra breakdown
+    let max_breakdown_key = query_config.max_breakdown_key
+        + u32::from(query_config.breakdown_key_overflow == BreakdownKeyOverflow::Overflow);
+    if !query_config.count_attributed_users {
+        return (
+            reconstruct_breakdowns::<F, _>(results, max_breakdown_key),
+            None,
+        );
+    }
+
+    let halves: [(&[u8], &[u8]); 3] = std::array::from_fn(|i| {
+        let bytes = results[i].as_ref();
+        bytes.split_at(bytes.len() / 2)
+    });
+    (
+        reconstruct_breakdowns::<F, _>(&halves.map(|(totals, _)| totals), max_breakdown_key),
+        Some(reconstruct_breakdowns::<F, _>(
+            &halves.map(|(_, users)| users),
+            max_breakdown_key,
+        )),
+    )
+}
+
+/// Reconstructs IPA outputs from the results returned by each helper.
+///
+/// ## Panics
+/// If trigger values were attributed to breakdowns that do not exist.
+pub fn reconstruct_breakdowns<F, B>(results: &[B; 3], max_breakdown_key: u32) -> Vec<u32>
+where
+    F: PrimeField,
+    AdditiveShare<F>: Serializable,
+    B: AsRef<[u8]>,
+{
+    let shares: [Vec<AdditiveShare<F>>; 3] =
+        std::array::from_fn(|i| AdditiveShare::<F>::from_byte_slice(results[i].as_ref()).collect());
+    let results: Vec<F> = shares.reconstruct();
+
+    let max_breakdown_key = usize::try_from(max_breakdown_key).unwrap();
+    let mut breakdowns = vec![0; max_breakdown_key];
     for (breakdown_key, trigger_value) in results.into_iter().enumerate() {
         // TODO: make the data type used consistent with `ipa_in_the_clear`
         // I think using u32 is wrong, we should move to u128
         assert!(
-            breakdown_key < query_config.max_breakdown_key.try_into().unwrap()
-                || trigger_value == F::ZERO,
+            breakdown_key < max_breakdown_key || trigger_value == F::ZERO,
             "trigger values were attributed to buckets more than max breakdown key"
         );
-        if breakdown_key < query_config.max_breakdown_key.try_into().unwrap() {
+        if breakdown_key < max_breakdown_key {
             breakdowns[breakdown_key] += u32::try_from(trigger_value.as_u128()).unwrap();
         }
     }
 
-    IpaQueryResult {
-        input_size: QuerySize::try_from(query_size).unwrap(),
-        config: query_config,
-        latency: lat,
-        breakdowns,
-    }
+    breakdowns
 }
@@ -1,6 +1,7 @@
 mod input;
 mod ipa;
 mod multiply;
+mod replay;
 
 use core::fmt::Debug;
 use std::{fs, path::Path, time::Duration};
@@ -9,6 +10,7 @@ use comfy_table::{Cell, Color, Table};
 use hyper::http::uri::Scheme;
 pub use input::InputSource;
 pub use multiply::secure_mul;
+pub use replay::{replay_ipa, ReplayedQuery};
 use tokio::time::sleep;
 
 pub use self::ipa::{playbook_ipa, playbook_oprf_ipa};
@@ -79,6 +81,7 @@ pub async fn make_clients(
                 PeerConfig::new("localhost:3001".parse().unwrap(), None),
                 PeerConfig::new("localhost:3002".parse().unwrap(), None),
             ],
+            report_collectors: Vec::new(),
             client: ClientConfig::default(),
         }
     };
@@ -8,7 +8,10 @@ use typenum::Unsigned;
 
 use crate::{
     ff::{Field, Serializable},
-    helpers::{query::QueryInput, BodyStream},
+    helpers::{
+        query::{InputPart, QueryInput},
+        BodyStream,
+    },
     net::MpcHelperClient,
     protocol::QueryId,
     secret_sharing::{replicated::semi_honest::AdditiveShare as Replicated, IntoShares},
@@ -57,6 +60,7 @@ where
             .map(|(input_stream, client)| {
                 client.query_input(QueryInput {
                     query_id,
+                    part: InputPart::All,
                     input_stream,
                 })
             }),
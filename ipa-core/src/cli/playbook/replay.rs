@@ -0,0 +1,83 @@
+#![cfg(all(feature = "web-app", feature = "cli"))]
+use std::io;
+
+use crate::{
+    cli::{
+        playbook::ipa::{reconstruct_ipa_output, run_query_and_validate},
+        IpaQueryResult,
+    },
+    error::BoxError,
+    ff::{FieldType, Fp32BitPrime},
+    helpers::{query::QueryType, BodyStream, Role},
+    net::MpcHelperClient,
+    query::{LogEntry, QueryManifest},
+};
+
+/// IPA query that was run again on the input logged by helpers.
+#[derive(Debug)]
+pub struct ReplayedQuery {
+    /// Output of the query when it was logged.
+    pub logged: Vec<u32>,
+    /// Output of the query now.
+    pub replayed: IpaQueryResult,
+}
+
+/// Runs an IPA query logged by helpers again, on exactly the same input, and returns the output
+/// of both runs. `entries` must contain one log entry from each helper, in any order. Every entry
+/// is sent to the client that takes the role the entry was logged with, so `clients` must be
+/// listed in the same order as when the query was logged.
+///
+/// ## Errors
+/// If log entries cannot be read, do not belong to the same IPA query or the query fails.
+pub async fn replay_ipa(
+    entries: [LogEntry; 3],
+    clients: &[MpcHelperClient; 3],
+) -> Result<ReplayedQuery, BoxError> {
+    let manifests = entries
+        .iter()
+        .map(LogEntry::manifest)
+        .collect::<io::Result<Vec<_>>>()?;
+    let config = manifests[0].config;
+    if manifests.iter().any(|m| m.config != config) {
+        return Err("log entries belong to different queries".into());
+    }
+
+    // Line up entries with helper clients. Query coordinator is always H1.
+    let mut ordered = Vec::with_capacity(3);
+    for role in Role::all() {
+        let i = manifests
+            .iter()
+            .position(|QueryManifest { role: r, .. }| r == role)
+            .ok_or_else(|| format!("no log entry from {role:?}"))?;
+        ordered.push(&entries[i]);
+    }
+    let ordered = <[_; 3]>::try_from(ordered).unwrap();
+
+    let ipa_config = match config.query_type {
+        QueryType::SemiHonestIpa(c) | QueryType::MaliciousIpa(c) | QueryType::OprfIpa(c) => c,
+        _ => {
+            return Err(format!("{} queries can't be replayed", config.query_type.as_ref()).into())
+        }
+    };
+    if config.field_type != FieldType::Fp32BitPrime {
+        return Err(format!("{:?} field is not supported", config.field_type).into());
+    }
+
+    let [o1, o2, o3] = ordered.map(LogEntry::output);
+    let (logged, _) = reconstruct_ipa_output::<Fp32BitPrime, _>(&[o1?, o2?, o3?], &ipa_config);
+
+    let [i1, i2, i3] = ordered.map(LogEntry::input);
+    let inputs = [i1?, i2?, i3?].map(BodyStream::from);
+    let query_id = clients[0].create_query(config).await?;
+    tracing::info!("Replaying query as {query_id}");
+    let replayed = run_query_and_validate::<Fp32BitPrime>(
+        inputs,
+        usize::from(config.size),
+        clients,
+        query_id,
+        ipa_config,
+    )
+    .await;
+
+    Ok(ReplayedQuery { logged, replayed })
+}
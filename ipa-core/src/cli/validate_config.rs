@@ -0,0 +1,430 @@
+use std::{
+    fmt::{Display, Formatter},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use clap::{Args, ValueEnum};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::{ClientConfig, NetworkConfig, PeerConfig},
+    error::BoxError,
+    hpke::Serializable as _,
+};
+
+#[derive(Debug, Args)]
+#[clap(
+    name = "validate-config",
+    about = "Check that configurations of all three helpers are consistent with each other",
+    next_help_heading = "Validate Config Options"
+)]
+pub struct ValidateConfigArgs {
+    /// Network configuration files used by helpers 1, 2 and 3, in this order.
+    #[arg(long, num_args = 3, value_name = "FILE", required = true)]
+    networks: Vec<PathBuf>,
+
+    /// TLS certificates of helpers 1, 2 and 3. If specified, every network configuration must
+    /// list the same certificates.
+    #[arg(long, num_args = 3, value_name = "FILE")]
+    tls_certs: Option<Vec<PathBuf>>,
+
+    /// Public keys for match key encryption of helpers 1, 2 and 3. If specified, every network
+    /// configuration must list the same keys.
+    #[arg(long, num_args = 3, value_name = "FILE")]
+    mk_public_keys: Option<Vec<PathBuf>>,
+
+    /// Gates helpers 1, 2 and 3 are built with. If specified, all of them must be the same,
+    /// because helpers built with different gates name protocol steps differently and can't run
+    /// queries together.
+    #[arg(long, num_args = 3, value_name = "GATE", value_enum)]
+    gates: Option<Vec<GateKind>>,
+
+    /// Helpers use insecure HTTP, so TLS certificates are not required.
+    #[arg(short = 'k', long)]
+    disable_https: bool,
+}
+
+/// How a helper binary names protocol steps, selected by the `descriptive-gate` feature.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum GateKind {
+    Compact,
+    Descriptive,
+}
+
+impl GateKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Compact => "compact",
+            Self::Descriptive => "descriptive",
+        }
+    }
+}
+
+/// Describes a single disagreement between the helper configurations. Every value is attributed
+/// to the file it was taken from, so the operator knows which one to fix.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Inconsistency {
+    what: String,
+    values: Vec<(String, String)>,
+}
+
+impl Display for Inconsistency {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.what)?;
+        for (source, value) in &self.values {
+            write!(f, "\n    {source}: {value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Loads network configurations of all three helpers and checks that they agree with each
+/// other and with the key material each helper uses.
+///
+/// ## Errors
+/// If any of the files can't be read or parsed, or if configurations are not consistent. In the
+/// latter case, error lists every disagreement found.
+pub fn validate_config(args: &ValidateConfigArgs) -> Result<(), BoxError> {
+    let configs = load_all(&args.networks, |path| {
+        Ok(NetworkConfig::from_toml_str(&fs::read_to_string(path)?)?)
+    })?;
+    let tls_certs = args
+        .tls_certs
+        .as_deref()
+        .map(|paths| load_all(paths, read_certificate))
+        .transpose()?;
+    let mk_public_keys = args
+        .mk_public_keys
+        .as_deref()
+        .map(|paths| {
+            load_all(paths, |path| {
+                Ok(fs::read_to_string(path)?.trim().to_owned())
+            })
+        })
+        .transpose()?;
+
+    let sources = args
+        .networks
+        .iter()
+        .map(|p| p.display().to_string())
+        .collect::<Vec<_>>();
+    let mut inconsistencies = check_network_configs(&sources, &configs, args.disable_https);
+    if let Some(certs) = tls_certs {
+        inconsistencies.extend(check_local_files(
+            "certificate",
+            &sources,
+            &configs,
+            args.tls_certs.as_deref().unwrap(),
+            &certs.map(|der| fingerprint(&der)),
+            certificate_fingerprint,
+        ));
+    }
+    if let Some(keys) = mk_public_keys {
+        inconsistencies.extend(check_local_files(
+            "hpke.public_key",
+            &sources,
+            &configs,
+            args.mk_public_keys.as_deref().unwrap(),
+            &keys,
+            public_key,
+        ));
+    }
+
+    if let Some(gates) = &args.gates {
+        inconsistencies.extend(check_gates(gates));
+    }
+
+    if inconsistencies.is_empty() {
+        tracing::info!("configurations of all three helpers are consistent");
+        Ok(())
+    } else {
+        Err(format!(
+            "found {} inconsistencies between helper configurations:\n{}",
+            inconsistencies.len(),
+            inconsistencies
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join("\n")
+        )
+        .into())
+    }
+}
+
+fn load_all<T, F>(paths: &[PathBuf], f: F) -> Result<[T; 3], BoxError>
+where
+    F: Fn(&Path) -> Result<T, BoxError>,
+{
+    let values = paths
+        .iter()
+        .map(|path| {
+            f(path).map_err(|e| BoxError::from(format!("failed to load {}: {e}", path.display())))
+        })
+        .collect::<Result<Vec<_>, BoxError>>()?;
+
+    values
+        .try_into()
+        .map_err(|_| "exactly three files must be provided".into())
+}
+
+fn read_certificate(path: &Path) -> Result<Vec<u8>, BoxError> {
+    rustls_pemfile::certs(&mut fs::read(path)?.as_slice())?
+        .into_iter()
+        .next()
+        .ok_or_else(|| "no certificate found".into())
+}
+
+fn fingerprint(der: &[u8]) -> String {
+    format!("sha256:{}", hex::encode(Sha256::digest(der)))
+}
+
+fn certificate_fingerprint(peer: &PeerConfig) -> String {
+    peer.certificate
+        .as_ref()
+        .map_or_else(|| String::from("<none>"), |cert| fingerprint(&cert.0))
+}
+
+fn public_key(peer: &PeerConfig) -> String {
+    peer.hpke_config.as_ref().map_or_else(
+        || String::from("<none>"),
+        |hpke| hex::encode(hpke.public_key.to_bytes()),
+    )
+}
+
+/// Returns `None` if all values are the same, otherwise attributes each value to its source.
+fn disagreement<'a, I>(what: String, values: I) -> Option<Inconsistency>
+where
+    I: IntoIterator<Item = (&'a String, String)>,
+{
+    let values = values
+        .into_iter()
+        .map(|(source, value)| (source.clone(), value))
+        .collect::<Vec<_>>();
+    if values.windows(2).all(|w| w[0].1 == w[1].1) {
+        None
+    } else {
+        Some(Inconsistency { what, values })
+    }
+}
+
+/// Checks that all helpers have the same view of the network.
+fn check_network_configs(
+    sources: &[String],
+    configs: &[NetworkConfig; 3],
+    disable_https: bool,
+) -> Vec<Inconsistency> {
+    type Field = (&'static str, fn(&PeerConfig) -> String);
+    const FIELDS: [Field; 3] = [
+        ("url", |peer| peer.url.to_string()),
+        ("certificate", certificate_fingerprint),
+        ("hpke.public_key", public_key),
+    ];
+    const CLIENT_FIELDS: [(&str, fn(&ClientConfig) -> String); 4] = [
+        ("http_config", |client| format!("{:?}", client.http_config)),
+        ("multiplex_streams", |client| {
+            client.multiplex_streams.to_string()
+        }),
+        ("compression", |client| format!("{:?}", client.compression)),
+        ("bandwidth_limit", |client| {
+            format!("{:?}", client.bandwidth_limit)
+        }),
+    ];
+
+    let mut inconsistencies = Vec::new();
+    for i in 0..3 {
+        for (name, f) in FIELDS {
+            inconsistencies.extend(disagreement(
+                format!("peers[{i}].{name} is different"),
+                sources.iter().zip(configs.iter().map(|c| f(&c.peers[i]))),
+            ));
+        }
+    }
+    for (name, f) in CLIENT_FIELDS {
+        inconsistencies.extend(disagreement(
+            format!("client.{name} is different"),
+            sources.iter().zip(configs.iter().map(|c| f(&c.client))),
+        ));
+    }
+
+    for (source, config) in sources.iter().zip(configs) {
+        for i in 0..3 {
+            for j in i + 1..3 {
+                if config.peers[i].url == config.peers[j].url {
+                    inconsistencies.push(Inconsistency {
+                        what: format!("peers[{i}] and peers[{j}] share the same url"),
+                        values: vec![(source.clone(), config.peers[i].url.to_string())],
+                    });
+                }
+            }
+            if !disable_https && config.peers[i].certificate.is_none() {
+                inconsistencies.push(Inconsistency {
+                    what: format!("peers[{i}].certificate is required when HTTPS is enabled"),
+                    values: vec![(source.clone(), String::from("<none>"))],
+                });
+            }
+        }
+    }
+
+    inconsistencies
+}
+
+/// Checks that all helpers are built with the same gate.
+fn check_gates(gates: &[GateKind]) -> Option<Inconsistency> {
+    let sources = (1..=3).map(|i| format!("helper {i}")).collect::<Vec<_>>();
+    disagreement(
+        String::from("gate is different"),
+        sources
+            .iter()
+            .zip(gates.iter().map(|gate| gate.as_str().to_owned())),
+    )
+}
+
+/// Checks that every network configuration lists the key material helpers actually use.
+fn check_local_files(
+    name: &str,
+    sources: &[String],
+    configs: &[NetworkConfig; 3],
+    paths: &[PathBuf],
+    local: &[String],
+    f: fn(&PeerConfig) -> String,
+) -> Vec<Inconsistency> {
+    (0..3)
+        .filter_map(|i| {
+            let local_source = paths[i].display().to_string();
+            disagreement(
+                format!("peers[{i}].{name} does not match helper's own file"),
+                std::iter::once((&local_source, local[i].clone()))
+                    .chain(sources.iter().zip(configs.iter().map(|c| f(&c.peers[i])))),
+            )
+        })
+        .collect()
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use std::path::PathBuf;
+
+    use hyper::Uri;
+
+    use super::{check_gates, check_local_files, check_network_configs, public_key, GateKind};
+    use crate::net::test::TestConfigBuilder;
+
+    fn sources() -> Vec<String> {
+        vec!["h1.toml".into(), "h2.toml".into(), "h3.toml".into()]
+    }
+
+    #[test]
+    fn consistent() {
+        let network = TestConfigBuilder::with_http_and_default_test_ports()
+            .build()
+            .network;
+        let configs = [network.clone(), network.clone(), network];
+        assert!(check_network_configs(&sources(), &configs, true).is_empty());
+    }
+
+    #[test]
+    fn url_mismatch() {
+        let network = TestConfigBuilder::with_http_and_default_test_ports()
+            .build()
+            .network;
+        let mut other = network.clone();
+        other.peers[1].url = Uri::from_static("http://localhost:4000");
+        let configs = [network.clone(), other, network];
+
+        let inconsistencies = check_network_configs(&sources(), &configs, true);
+        assert_eq!(1, inconsistencies.len());
+        assert_eq!(
+            "peers[1].url is different\n    \
+             h1.toml: http://localhost:3001/\n    \
+             h2.toml: http://localhost:4000/\n    \
+             h3.toml: http://localhost:3001/",
+            inconsistencies[0].to_string()
+        );
+    }
+
+    #[test]
+    fn client_feature_mismatch() {
+        let network = TestConfigBuilder::with_http_and_default_test_ports()
+            .build()
+            .network;
+        let mut other = network.clone();
+        other.client.multiplex_streams = !network.client.multiplex_streams;
+        let configs = [network.clone(), network, other];
+
+        let inconsistencies = check_network_configs(&sources(), &configs, true);
+        assert_eq!(1, inconsistencies.len());
+        assert_eq!(
+            format!(
+                "client.multiplex_streams is different\n    \
+                 h1.toml: {a}\n    \
+                 h2.toml: {a}\n    \
+                 h3.toml: {b}",
+                a = configs[0].client.multiplex_streams,
+                b = configs[2].client.multiplex_streams,
+            ),
+            inconsistencies[0].to_string()
+        );
+    }
+
+    #[test]
+    fn gate_mismatch() {
+        assert_eq!(None, check_gates(&[GateKind::Compact; 3]));
+        assert_eq!(
+            "gate is different\n    \
+             helper 1: compact\n    \
+             helper 2: descriptive\n    \
+             helper 3: compact",
+            check_gates(&[GateKind::Compact, GateKind::Descriptive, GateKind::Compact])
+                .unwrap()
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn duplicate_url_and_missing_certificate() {
+        let mut network = TestConfigBuilder::with_http_and_default_test_ports()
+            .build()
+            .network;
+        network.peers[2].url = network.peers[0].url.clone();
+        let configs = [network.clone(), network.clone(), network];
+
+        // duplicate url is reported for every config, missing certificates - for every peer
+        assert_eq!(3, check_network_configs(&sources(), &configs, true).len());
+        assert_eq!(
+            3 + 9,
+            check_network_configs(&sources(), &configs, false).len()
+        );
+    }
+
+    #[test]
+    fn local_key_mismatch() {
+        let network = TestConfigBuilder::with_http_and_default_test_ports()
+            .build()
+            .network;
+        let configs = [network.clone(), network.clone(), network];
+        let mut keys = configs[0].peers.iter().map(public_key).collect::<Vec<_>>();
+        let paths: [PathBuf; 3] = ["mk1.pub".into(), "mk2.pub".into(), "mk3.pub".into()];
+        assert!(check_local_files(
+            "hpke.public_key",
+            &sources(),
+            &configs,
+            &paths,
+            &keys,
+            public_key
+        )
+        .is_empty());
+
+        keys[2] = String::from("00");
+        let inconsistencies = check_local_files(
+            "hpke.public_key",
+            &sources(),
+            &configs,
+            &paths,
+            &keys,
+            public_key,
+        );
+        assert_eq!(1, inconsistencies.len());
+        assert_eq!(4, inconsistencies[0].values.len());
+    }
+}
@@ -21,6 +21,11 @@ pub struct Verbosity {
     /// Verbose mode (-v, or -vv for even more verbose)
     #[arg(short, long, action = clap::ArgAction::Count, global = true)]
     verbose: u8,
+
+    /// Export traces to the OpenTelemetry collector at this endpoint (e.g. http://localhost:4317)
+    #[cfg(feature = "otel")]
+    #[arg(long, global = true)]
+    otlp_endpoint: Option<String>,
 }
 
 pub struct LoggingHandle {
@@ -28,6 +33,13 @@ pub struct LoggingHandle {
     metrics_handle: Option<CollectorHandle>,
 }
 
+#[cfg(feature = "otel")]
+impl Drop for LoggingHandle {
+    fn drop(&mut self) {
+        crate::telemetry::otel::shutdown();
+    }
+}
+
 impl Verbosity {
     #[must_use]
     pub fn setup_logging(&self) -> LoggingHandle {
@@ -37,11 +49,15 @@ impl Verbosity {
             .with_ansi(std::io::stderr().is_terminal())
             .with_writer(stderr);
 
-        tracing_subscriber::registry()
+        let registry = tracing_subscriber::registry()
             .with(self.log_filter())
             .with(fmt_layer)
-            .with(MetricsLayer::new())
-            .init();
+            .with(MetricsLayer::new());
+
+        #[cfg(feature = "otel")]
+        let registry = registry.with(self.otel_layer());
+
+        registry.init();
 
         let handle = LoggingHandle {
             metrics_handle: (!self.quiet).then(install_collector),
@@ -53,6 +69,26 @@ impl Verbosity {
         handle
     }
 
+    #[cfg(feature = "otel")]
+    fn otel_layer<S>(&self) -> Option<impl tracing_subscriber::Layer<S>>
+    where
+        S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+    {
+        let endpoint = self.otlp_endpoint.as_deref()?;
+        let service_name = std::env::args()
+            .next()
+            .as_deref()
+            .and_then(|arg0| std::path::Path::new(arg0).file_name())
+            .map_or_else(|| "ipa".to_string(), |s| s.to_string_lossy().into_owned());
+        match crate::telemetry::otel::layer(service_name, endpoint) {
+            Ok(layer) => Some(layer),
+            Err(e) => {
+                eprintln!("failed to set up OpenTelemetry exporter: {e}");
+                None
+            }
+        }
+    }
+
     fn log_filter(&self) -> EnvFilter {
         EnvFilter::builder()
             .with_default_directive(
@@ -3,7 +3,8 @@ use std::{
     borrow::{Borrow, Cow},
     fmt::{Debug, Formatter},
     iter::Zip,
-    path::PathBuf,
+    num::NonZeroU64,
+    path::{Path, PathBuf},
     slice,
     time::Duration,
 };
@@ -16,10 +17,14 @@ use tokio::fs;
 
 use crate::{
     error::BoxError,
-    helpers::HelperIdentity,
+    ff::{ec_prime_field::Fp25519, Serializable as _},
+    helpers::{query::InputPart, HelperIdentity},
     hpke::{
-        Deserializable as _, IpaPrivateKey, IpaPublicKey, KeyPair, KeyRegistry, Serializable as _,
+        Deserializable as _, EpochKeySchedule, EpochSecret, IpaPrivateKey, IpaPublicKey, KeyPair,
+        KeyRegistry, Serializable as _,
     },
+    net::RecordEncoding,
+    protocol::ipa_prf::prf_eval::PersistentPrfKey,
 };
 
 #[derive(Debug, thiserror::Error)]
@@ -43,6 +48,11 @@ pub struct NetworkConfig {
     /// helper identities are stable, roles are assigned per query.
     pub peers: [PeerConfig; 3],
 
+    /// Report collectors that submit a part of query inputs, see [`InputPart`]. Report collectors
+    /// that submit whole inputs don't need to be listed.
+    #[serde(default)]
+    pub report_collectors: Vec<ReportCollectorConfig>,
+
     /// HTTP client configuration.
     #[serde(default)]
     pub client: ClientConfig,
@@ -66,7 +76,11 @@ impl NetworkConfig {
     }
 
     pub fn new(peers: [PeerConfig; 3], client: ClientConfig) -> Self {
-        Self { peers, client }
+        Self {
+            peers,
+            report_collectors: Vec::new(),
+            client,
+        }
     }
 
     pub fn peers(&self) -> &[PeerConfig; 3] {
@@ -135,6 +149,24 @@ impl PeerConfig {
     }
 }
 
+/// Report collector that is entitled to submit a part of query inputs. Helpers accept split inputs
+/// only from report collectors that authenticate as entitled to the part they submit.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ReportCollectorConfig {
+    /// Name of the report collector, for logs.
+    pub name: String,
+
+    /// The part of query inputs this report collector submits.
+    pub input_part: InputPart,
+
+    /// Report collector's TLS client certificate
+    ///
+    /// Must be specified unless HTTPS is disabled, in the same format as helper certificates, see
+    /// [`PeerConfig::certificate`].
+    #[serde(default, deserialize_with = "certificate_from_pem")]
+    pub certificate: Option<Certificate>,
+}
+
 /// Match key encryption client configuration. To encrypt match keys towards a helper node, clients
 /// need to know helper's public key.
 #[derive(Clone, Deserialize)]
@@ -257,6 +289,53 @@ pub async fn hpke_registry(
     ))]))
 }
 
+/// Reads the schedule that derives per-epoch match key encryption keys of this helper. The file
+/// holds the earliest epoch keys can be derived for and the hex-encoded secret for that epoch,
+/// separated by whitespace.
+///
+/// # Errors
+/// If the file cannot be read or its contents are malformed.
+pub async fn epoch_key_schedule(path: &Path) -> Result<EpochKeySchedule, BoxError> {
+    let contents = fs::read_to_string(path).await?;
+    let mut parts = contents.split_whitespace();
+    let (Some(epoch), Some(secret), None) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(format!("{}: expected an epoch and a secret", path.display()).into());
+    };
+    let secret = EpochSecret::try_from(hex::decode(secret)?.as_slice())?;
+
+    Ok(EpochKeySchedule::new(epoch.parse()?, secret))
+}
+
+/// Reads the components of the PRF key this helper shares with its peers. The file holds two
+/// pairs of a peer helper identity and the hex-encoded component shared with that peer, separated
+/// by whitespace.
+///
+/// # Errors
+/// If the file cannot be read or its contents are malformed.
+pub async fn persistent_prf_key(path: &Path) -> Result<PersistentPrfKey, BoxError> {
+    let contents = fs::read_to_string(path).await?;
+    let parts = contents.split_whitespace().collect::<Vec<_>>();
+    let [left_id, left, right_id, right] = parts[..] else {
+        return Err(format!("{}: expected two peers and key components", path.display()).into());
+    };
+    let component = |id: &str, component: &str| -> Result<_, BoxError> {
+        let id = HelperIdentity::try_from(id.parse::<usize>()?)?;
+        let component = <[u8; 32]>::try_from(hex::decode(component)?.as_slice())?;
+
+        Ok((id, Fp25519::deserialize(&component.into())))
+    };
+    let components = [component(left_id, left)?, component(right_id, right)?];
+    if components[0].0 == components[1].0 {
+        return Err(format!(
+            "{}: key components must be shared with different peers",
+            path.display()
+        )
+        .into());
+    }
+
+    Ok(PersistentPrfKey::new(components))
+}
+
 /// Configuration information for launching an instance of the helper party web service.
 #[derive(Clone, Debug)]
 pub struct ServerConfig {
@@ -271,6 +350,10 @@ pub struct ServerConfig {
 
     /// Configuration needed for encrypting and decrypting match keys
     pub hpke_config: Option<HpkeServerConfig>,
+
+    /// Token that operators present to use admin endpoints, such as the one that updates the
+    /// network configuration. Admin endpoints are disabled if not set.
+    pub admin_token: Option<String>,
 }
 
 pub trait HyperClientConfigurator {
@@ -280,6 +363,17 @@ pub trait HyperClientConfigurator {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ClientConfig {
     pub http_config: HttpClientConfigurator,
+    /// If true, record streams sent to a peer during a query share a single long-lived request
+    /// instead of using a request per step.
+    #[serde(default)]
+    pub multiplex_streams: bool,
+    /// If set, record streams sent to a peer over step requests are compressed. Streams sent over
+    /// a multiplexed connection are not.
+    #[serde(default)]
+    pub compression: Option<CompressionConfig>,
+    /// If set, caps the rate at which records are sent to each peer.
+    #[serde(default)]
+    pub bandwidth_limit: Option<BandwidthLimit>,
 }
 
 impl Default for ClientConfig {
@@ -298,6 +392,9 @@ impl ClientConfig {
     pub fn configure_http2(conf: Http2Configurator) -> Self {
         Self {
             http_config: HttpClientConfigurator::Http2(conf),
+            multiplex_streams: false,
+            compression: None,
+            bandwidth_limit: None,
         }
     }
 
@@ -305,10 +402,88 @@ impl ClientConfig {
     pub fn use_http1() -> Self {
         Self {
             http_config: HttpClientConfigurator::http1(),
+            multiplex_streams: false,
+            compression: None,
+            bandwidth_limit: None,
+        }
+    }
+
+    /// Send all record streams of a query to a peer over a single request.
+    #[must_use]
+    pub fn with_multiplexed_streams(mut self) -> Self {
+        self.multiplex_streams = true;
+        self
+    }
+
+    /// Compress record streams sent to peers, see [`CompressionConfig`].
+    #[must_use]
+    pub fn with_compression(mut self, compression: CompressionConfig) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Limit the rate at which records are sent to every peer, see [`BandwidthLimit`].
+    #[must_use]
+    pub fn with_bandwidth_limit(mut self, limit: BandwidthLimit) -> Self {
+        self.bandwidth_limit = Some(limit);
+        self
+    }
+}
+
+/// Controls compression of record streams, see [`RecordEncoding`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompressionConfig {
+    pub encoding: RecordEncoding,
+    /// Chunks of records smaller than this many bytes are sent uncompressed. Compressing small
+    /// chunks costs more CPU time than it saves on the wire; `criterion_compression` benchmark
+    /// shows the break-even point for different encodings.
+    #[serde(default = "CompressionConfig::default_threshold")]
+    pub threshold: usize,
+}
+
+impl CompressionConfig {
+    /// Default value for [`Self::threshold`].
+    pub const DEFAULT_THRESHOLD: usize = 4096;
+
+    fn default_threshold() -> usize {
+        Self::DEFAULT_THRESHOLD
+    }
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            encoding: RecordEncoding::Zstd,
+            threshold: Self::DEFAULT_THRESHOLD,
         }
     }
 }
 
+/// Caps the bandwidth a helper uses to send records to a single peer, so that a query can't
+/// saturate a link shared with other traffic. Every peer gets its own budget, which all record
+/// streams sent to it share.
+///
+/// Sending is throttled once the burst is used up, and the budget refills at the configured rate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BandwidthLimit {
+    pub bytes_per_second: NonZeroU64,
+    /// Bytes that can be sent at once after the link to the peer has been idle for a while.
+    #[serde(default = "BandwidthLimit::default_burst_bytes")]
+    pub burst_bytes: NonZeroU64,
+}
+
+impl BandwidthLimit {
+    /// Default value for [`Self::burst_bytes`].
+    pub const DEFAULT_BURST_BYTES: NonZeroU64 = match NonZeroU64::new(1 << 20) {
+        Some(v) => v,
+        None => panic!("burst must not be zero"),
+    };
+
+    fn default_burst_bytes() -> NonZeroU64 {
+        Self::DEFAULT_BURST_BYTES
+    }
+}
+
 impl<B: Borrow<ClientConfig>> HyperClientConfigurator for B {
     fn configure<'a>(&self, client_builder: &'a mut Builder) -> &'a mut Builder {
         self.borrow().http_config.configure(client_builder)
@@ -438,6 +613,44 @@ mod tests {
     const URI_2: &str = "http://localhost:3001";
     const URI_3: &str = "http://localhost:3002";
 
+    #[tokio::test]
+    async fn read_epoch_key_schedule() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("epoch_keys");
+
+        std::fs::write(&path, format!("12 {}\n", hex::encode([5_u8; 32]))).unwrap();
+        let schedule = epoch_key_schedule(&path).await.unwrap();
+        assert_eq!(12, schedule.epoch());
+        assert_eq!(&[5_u8; 32], schedule.secret());
+
+        std::fs::write(&path, "12").unwrap();
+        assert!(epoch_key_schedule(&path).await.is_err());
+        std::fs::write(&path, "12 abcd").unwrap();
+        assert!(epoch_key_schedule(&path).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn read_persistent_prf_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("prf_key");
+
+        let (c12, c31) = (hex::encode([3_u8; 32]), hex::encode([7_u8; 32]));
+        std::fs::write(&path, format!("2 {c12}\n3 {c31}\n")).unwrap();
+        let key = persistent_prf_key(&path).await.unwrap();
+        assert!(key
+            .share(HelperIdentity::THREE, HelperIdentity::TWO)
+            .is_some());
+
+        std::fs::write(&path, format!("2 {c12}")).unwrap();
+        assert!(persistent_prf_key(&path).await.is_err());
+        std::fs::write(&path, format!("2 {c12}\n2 {c31}")).unwrap();
+        assert!(persistent_prf_key(&path).await.is_err());
+        std::fs::write(&path, format!("4 {c12}\n3 {c31}")).unwrap();
+        assert!(persistent_prf_key(&path).await.is_err());
+        std::fs::write(&path, format!("2 {c12}\n3 abcd")).unwrap();
+        assert!(persistent_prf_key(&path).await.is_err());
+    }
+
     #[test]
     fn parse_config() {
         let conf = TestConfigBuilder::with_http_and_default_test_ports().build();
@@ -458,6 +671,25 @@ mod tests {
         assert_eq!(value3.url, uri3);
     }
 
+    #[test]
+    fn parse_report_collectors() {
+        let peers = [URI_1, URI_2, URI_3]
+            .map(|uri| format!("[[peers]]\nurl = \"{uri}\"\n"))
+            .concat();
+        let conf = NetworkConfig::from_toml_str(&format!(
+            "{peers}[[report_collectors]]\nname = \"advertiser\"\ninput_part = \"trigger_events\"\n"
+        ))
+        .unwrap();
+        assert_eq!(1, conf.report_collectors.len());
+        let collector = &conf.report_collectors[0];
+        assert_eq!("advertiser", collector.name);
+        assert_eq!(InputPart::TriggerEvents, collector.input_part);
+        assert!(collector.certificate.is_none());
+
+        let conf = NetworkConfig::from_toml_str(&peers).unwrap();
+        assert!(conf.report_collectors.is_empty());
+    }
+
     #[test]
     fn debug_hpke_client_config() {
         let mut rng = StdRng::seed_from_u64(1);
@@ -481,6 +713,9 @@ mod tests {
                     expected.http_config, actual.http_config
                 ),
             };
+            assert_eq!(expected.multiplex_streams, actual.multiplex_streams);
+            assert_eq!(expected.compression, actual.compression);
+            assert_eq!(expected.bandwidth_limit, actual.bandwidth_limit);
         }
 
         assert!(serde_json::from_str::<ClientConfig>(
@@ -505,5 +740,26 @@ mod tests {
                 ping_interval: Some(Duration::from_secs(132)),
             }),
         );
+        assert_config_eq(
+            r#"{ "http_config": { "version": "http1" }, "multiplex_streams": true }"#,
+            &ClientConfig::use_http1().with_multiplexed_streams(),
+        );
+        assert_config_eq(
+            r#"{ "http_config": { "version": "http2" }, "compression": { "encoding": "gzip" } }"#,
+            &ClientConfig::use_http2().with_compression(CompressionConfig {
+                encoding: RecordEncoding::Gzip,
+                threshold: CompressionConfig::DEFAULT_THRESHOLD,
+            }),
+        );
+        assert_config_eq(
+            r#"{
+                "http_config": { "version": "http2" },
+                "bandwidth_limit": { "bytes_per_second": 1000 }
+            }"#,
+            &ClientConfig::use_http2().with_bandwidth_limit(BandwidthLimit {
+                bytes_per_second: NonZeroU64::new(1000).unwrap(),
+                burst_bytes: BandwidthLimit::DEFAULT_BURST_BYTES,
+            }),
+        );
     }
 }
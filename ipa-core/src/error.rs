@@ -2,7 +2,11 @@ use std::{backtrace::Backtrace, fmt::Debug};
 
 use thiserror::Error;
 
-use crate::{report::InvalidReportError, task::JoinError};
+use crate::{
+    protocol::ipa_prf::prf_sharding::UserRowsError,
+    report::{InvalidPseudonymError, InvalidReportError},
+    task::JoinError,
+};
 
 /// An error raised by the IPA protocol.
 ///
@@ -36,6 +40,8 @@ pub enum Error {
     MaliciousSecurityCheckFailed,
     #[error("malicious reveal failed")]
     MaliciousRevealFailed,
+    #[error("shuffle verification failed: {0}")]
+    ShuffleVerificationFailed(String),
     #[error("problem during IO: {0}")]
     Io(#[from] std::io::Error),
     // TODO remove if this https://github.com/awslabs/shuttle/pull/109 gets approved
@@ -56,6 +62,10 @@ pub enum Error {
     InvalidQueryParameter(BoxError),
     #[error("invalid report: {0}")]
     InvalidReport(#[from] InvalidReportError),
+    #[error("invalid pseudonym: {0}")]
+    InvalidPseudonym(#[from] InvalidPseudonymError),
+    #[error("invalid input rows: {0}")]
+    InvalidUserRows(#[from] UserRowsError),
     #[error("unsupported: {0}")]
     Unsupported(String),
     #[error("Decompressing invalid elliptic curve point: {0}")]
@@ -69,6 +79,16 @@ impl Default for Error {
 }
 
 impl Error {
+    /// Returns the step this error happened at, if the protocol failed while exchanging records
+    /// with peers.
+    #[must_use]
+    pub fn gate(&self) -> Option<&str> {
+        match self {
+            Self::InfraError(e) => e.gate(),
+            _ => None,
+        }
+    }
+
     #[must_use]
     pub fn path_parse_error(source: &str) -> Error {
         Error::ParseError(format!("unexpected value \"{source}\" in path").into())
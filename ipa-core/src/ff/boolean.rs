@@ -3,7 +3,7 @@ use typenum::U1;
 
 use super::Gf32Bit;
 use crate::{
-    ff::{Field, Serializable},
+    ff::{Error, Field, Serializable},
     secret_sharing::{replicated::malicious::ExtendableField, Block, SharedValue},
 };
 
@@ -49,6 +49,14 @@ impl Serializable for Boolean {
         assert!(buf[0] < 2u8);
         Boolean(buf[0] != 0)
     }
+
+    fn try_deserialize(buf: &GenericArray<u8, Self::Size>) -> Result<Self, Error> {
+        match buf[0] {
+            0 => Ok(Boolean(false)),
+            1 => Ok(Boolean(true)),
+            _ => Err(Error::invalid_encoding::<Self>(buf)),
+        }
+    }
 }
 
 ///generate random bool
@@ -139,6 +147,10 @@ impl Field for Boolean {
     fn truncate_from<T: Into<u128>>(v: T) -> Self {
         Boolean((v.into() % 2u128) != 0)
     }
+
+    fn and_bits(&self) -> Option<Vec<bool>> {
+        Some(vec![self.0])
+    }
 }
 
 ///implement `TryFrom` since required by Field
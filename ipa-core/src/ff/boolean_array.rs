@@ -46,7 +46,7 @@ macro_rules! boolean_array_impl {
         mod $modname {
             use super::*;
             use crate::{
-                ff::{boolean::Boolean, ArrayAccess, Expand, Field, Serializable},
+                ff::{boolean::Boolean, ArrayAccess, Error, Expand, Field, Serializable},
                 secret_sharing::{
                     replicated::semi_honest::{ASIterator, AdditiveShare},
                     SharedValue,
@@ -92,6 +92,16 @@ macro_rules! boolean_array_impl {
                 fn deserialize(buf: &GenericArray<u8, Self::Size>) -> Self {
                     Self(<Store>::new(assert_copy(*buf).into()))
                 }
+
+                /// Rejects values that have any of the bits above `BITS` set.
+                fn try_deserialize(buf: &GenericArray<u8, Self::Size>) -> Result<Self, Error> {
+                    let store = <Store>::new(assert_copy(*buf).into());
+                    if store[$bits..].any() {
+                        Err(Error::invalid_encoding::<Self>(buf))
+                    } else {
+                        Ok(Self(store))
+                    }
+                }
             }
 
             impl std::ops::Add for $name {
@@ -143,11 +153,26 @@ macro_rules! boolean_array_impl {
 
                     val
                 }
+
+                fn and_bits(&self) -> Option<Vec<bool>> {
+                    let bits = usize::try_from(Self::BITS).unwrap();
+                    Some(self.0[..bits].iter().by_vals().collect())
+                }
             }
 
             impl rand::distributions::Distribution<$name> for rand::distributions::Standard {
                 fn sample<R: crate::rand::Rng + ?Sized>(&self, rng: &mut R) -> $name {
-                    <$name>::truncate_from(rng.gen::<u128>())
+                    let mut val = <$name>::truncate_from(rng.gen::<u128>());
+                    // arrays wider than 128 bits need more than one `u128` worth of randomness
+                    let bits = usize::try_from(<$name>::BITS).unwrap();
+                    for start in (128..bits).step_by(128) {
+                        let v = rng.gen::<u128>();
+                        for i in start..std::cmp::min(start + 128, bits) {
+                            val.set(i, Boolean::from((v >> (i - start) & 1) == 1));
+                        }
+                    }
+
+                    val
                 }
             }
 
@@ -267,6 +292,13 @@ macro_rules! boolean_array_impl {
                     assert_eq!(ba.get(i), Some(a));
                 }
 
+                #[test]
+                fn sample_all_bits() {
+                    let mut rng = thread_rng();
+                    let last = usize::try_from(<$name>::BITS).unwrap() - 1;
+                    assert!((0..64).any(|_| rng.gen::<$name>().get(last) == Some(Boolean::ONE)));
+                }
+
                 #[test]
                 fn iterate_boolean_array() {
                     let bits = $name::ONE;
@@ -279,6 +311,34 @@ macro_rules! boolean_array_impl {
                         }
                     }
                 }
+
+                #[test]
+                fn try_deserialize() {
+                    let v = thread_rng().gen::<$name>();
+                    let mut buf = GenericArray::default();
+                    v.serialize(&mut buf);
+                    assert_eq!(v, <$name>::try_deserialize(&buf).unwrap());
+
+                    let mut store = <Store>::new(assert_copy(buf).into());
+                    if store.len() > $bits {
+                        store.set($bits, true);
+                        buf.copy_from_slice(store.as_raw_slice());
+                        assert!(<$name>::try_deserialize(&buf).is_err());
+                    }
+                }
+
+                #[test]
+                fn sign_extend() {
+                    use crate::ff::{boolean_array::BA256, CustomArray};
+
+                    let v = thread_rng().gen::<$name>();
+                    let last = usize::try_from(<$name>::BITS).unwrap() - 1;
+                    assert_eq!(v.get(last).unwrap(), v.sign_bit());
+
+                    let wide = v.sign_extend::<BA256>();
+                    assert!((last..256).all(|i| wide.get(i) == Some(v.sign_bit())));
+                    assert_eq!(v, wide.sign_extend::<$name>());
+                }
             }
 
             #[test]
@@ -324,6 +384,15 @@ boolean_array_impl!(boolean_array_7, BA7, 7, 1, [1, 0, 0, 0, 0, 0, 0]);
 //impl BA8
 boolean_array_impl!(boolean_array_8, BA8, 8, 1, [1, 0, 0, 0, 0, 0, 0, 0]);
 
+//impl BA16
+boolean_array_impl!(
+    boolean_array_16,
+    BA16,
+    16,
+    2,
+    [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+);
+
 //impl BA20
 boolean_array_impl!(
     boolean_array_20,
@@ -6,8 +6,11 @@ use generic_array::GenericArray;
 use typenum::U32;
 
 use crate::{
-    ff::{ec_prime_field::Fp25519, Serializable},
-    secret_sharing::{Block, WeakSharedValue},
+    ff::{ec_prime_field::Fp25519, Error, Serializable},
+    secret_sharing::{
+        replicated::{semi_honest::AdditiveShare, ReplicatedSecretSharing},
+        Block, WeakSharedValue,
+    },
 };
 
 impl Block for CompressedRistretto {
@@ -25,7 +28,7 @@ impl Block for CompressedRistretto {
 /// since we always generate curve points from scalars (elements in Fp25519) and
 /// only deserialize previously serialized valid points, panics will not occur
 /// However, we still added a debug assert to deserialize since values are sent by other servers
-#[derive(Clone, Copy, PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub struct RP25519(CompressedRistretto);
 
 /// Implementing trait for secret sharing
@@ -46,6 +49,15 @@ impl Serializable for RP25519 {
         debug_assert!(CompressedRistretto((*buf).into()).decompress().is_some());
         RP25519(CompressedRistretto((*buf).into()))
     }
+
+    fn try_deserialize(buf: &GenericArray<u8, Self::Size>) -> Result<Self, Error> {
+        let point = CompressedRistretto((*buf).into());
+        if point.decompress().is_some() {
+            Ok(RP25519(point))
+        } else {
+            Err(Error::invalid_encoding::<Self>(buf))
+        }
+    }
 }
 
 ///## Panics
@@ -129,6 +141,35 @@ impl From<Fp25519> for RP25519 {
     }
 }
 
+/// Lifts a sharing of scalar `x` to a sharing of the curve point `g^x`. This is a local operation,
+/// each share is mapped independently and the result is a valid sharing because the mapping is
+/// a group homomorphism.
+impl From<AdditiveShare<Fp25519>> for AdditiveShare<RP25519> {
+    fn from(s: AdditiveShare<Fp25519>) -> Self {
+        AdditiveShare::new(RP25519::from(s.left()), RP25519::from(s.right()))
+    }
+}
+
+///Scalar multiplication of a shared curve point by a public scalar. Like the `From` conversion
+/// above, it does not require communication between helpers.
+///## Panics
+/// Panics when decompressing invalid curve point.
+impl std::ops::Mul<Fp25519> for &AdditiveShare<RP25519> {
+    type Output = AdditiveShare<RP25519>;
+
+    fn mul(self, rhs: Fp25519) -> Self::Output {
+        AdditiveShare::new(self.left() * rhs, self.right() * rhs)
+    }
+}
+
+impl std::ops::Mul<Fp25519> for AdditiveShare<RP25519> {
+    type Output = Self;
+
+    fn mul(self, rhs: Fp25519) -> Self::Output {
+        std::ops::Mul::mul(&self, rhs)
+    }
+}
+
 impl From<CompressedRistretto> for RP25519 {
     fn from(s: CompressedRistretto) -> Self {
         RP25519(s)
@@ -182,7 +223,8 @@ mod test {
 
     use crate::{
         ff::{curve_points::RP25519, ec_prime_field::Fp25519, Serializable},
-        secret_sharing::WeakSharedValue,
+        secret_sharing::{replicated::semi_honest::AdditiveShare, IntoShares, WeakSharedValue},
+        test_fixture::Reconstruct,
     };
 
     cp_hash_impl!(u32);
@@ -228,6 +270,25 @@ mod test {
         assert_eq!(RP25519::ZERO, fp_h * Scalar::ZERO.into());
     }
 
+    ///testing that operations on shared curve points agree with operations on the clear values
+    #[test]
+    fn shared_curve_arithmetics() {
+        let mut rng = thread_rng();
+        let x = rng.gen::<Fp25519>();
+        let k = rng.gen::<Fp25519>();
+
+        let shared_scalar: [AdditiveShare<Fp25519>; 3] = x.share();
+        let shared_point = shared_scalar.map(AdditiveShare::<RP25519>::from);
+        assert_eq!(RP25519::from(x), shared_point.reconstruct());
+
+        let blinded = shared_point.map(|s| s * k);
+        assert_eq!(RP25519::from(x * k), blinded.reconstruct());
+
+        let mut buf = GenericArray::default();
+        blinded[0].serialize(&mut buf);
+        assert_eq!(blinded[0], AdditiveShare::<RP25519>::deserialize(&buf));
+    }
+
     ///testing curve to unsigned integer conversion has entropy (!= 0)
     #[test]
     fn curve_point_to_hash() {
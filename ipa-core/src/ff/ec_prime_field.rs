@@ -15,7 +15,7 @@ impl Block for Scalar {
 
 ///implements the Scalar field for elliptic curve 25519
 /// we use elements in Fp25519 to generate curve points and operate on the curve
-#[derive(Clone, Copy, PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub struct Fp25519(<Self as SharedValue>::Storage);
 
 impl Fp25519 {
@@ -201,16 +201,13 @@ impl Field for Fp25519 {
     }
 }
 
-///implement `TryFrom` since required by Field
+///implement `TryFrom` since required by Field. Every `u128` is smaller than the field prime,
+/// so this conversion never fails and, unlike `truncate_from`, preserves the value.
 impl TryFrom<u128> for Fp25519 {
     type Error = crate::error::Error;
 
     fn try_from(v: u128) -> Result<Self, Self::Error> {
-        let mut bits = [0u8; 32];
-        bits[..].copy_from_slice(&v.to_le_bytes());
-        let f: Fp25519 = Fp25519::ONE;
-        f.serialize((&mut bits).into());
-        Ok(f)
+        Ok(Fp25519(Scalar::from(v)))
     }
 }
 
@@ -223,7 +220,14 @@ mod test {
 
     use crate::{
         ff::{ec_prime_field::Fp25519, Serializable},
+        protocol::{
+            basics::{Reveal, SecureMul},
+            context::Context,
+            RecordId,
+        },
         secret_sharing::SharedValue,
+        test_executor::run,
+        test_fixture::{Runner, TestWorld},
     };
 
     sc_hash_impl!(u32);
@@ -285,4 +289,43 @@ mod test {
         let ia = a.invert();
         assert_eq!(a * ia, Fp25519(Scalar::ONE));
     }
+
+    ///test conversion from u128 preserves the value
+    #[test]
+    fn try_from_u128() {
+        let a = Fp25519::try_from(2u128).unwrap();
+        let b = Fp25519::try_from(3u128).unwrap();
+        assert_eq!(Fp25519::try_from(6u128).unwrap(), a * b);
+        assert_eq!(
+            Fp25519(Scalar::from(u128::MAX)),
+            Fp25519::try_from(u128::MAX).unwrap()
+        );
+    }
+
+    ///test that shares of Fp25519 can be multiplied and revealed using the standard context API
+    #[test]
+    fn multiply_shares() {
+        run(|| async {
+            let world = TestWorld::default();
+            let mut rng = thread_rng();
+            let a = rng.gen::<Fp25519>();
+            let b = rng.gen::<Fp25519>();
+
+            let result = world
+                .semi_honest((a, b), |ctx, (a_share, b_share)| async move {
+                    let ctx = ctx.set_total_records(1);
+                    let product = a_share
+                        .multiply(&b_share, ctx.narrow("mul"), RecordId::FIRST)
+                        .await
+                        .unwrap();
+                    product
+                        .reveal(ctx.narrow("reveal"), RecordId::FIRST)
+                        .await
+                        .unwrap()
+                })
+                .await;
+
+            assert_eq!([a * b; 3], result);
+        });
+    }
 }
@@ -27,6 +27,13 @@ pub trait Field: SharedValue + TryFrom<u128, Error = error::Error> + Into<Self::
     /// Blanket implementation to represent the instance of this trait as 16 byte integer.
     /// Uses the fact that such conversion already exists via `Self` -> `Self::Integer` -> `Into<u128>`
     fn as_u128(&self) -> u128;
+
+    /// Bits of this value, if values of this type are multiplied bit by bit, as booleans and
+    /// arrays of them are. Distributed zero-knowledge proofs verify such multiplications one bit
+    /// at a time, see [`crate::protocol::dzkp`].
+    fn and_bits(&self) -> Option<Vec<bool>> {
+        None
+    }
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
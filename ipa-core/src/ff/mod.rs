@@ -25,6 +25,22 @@ pub use prime_field::{Fp32BitPrime, PrimeField};
 pub enum Error {
     #[error("unknown field type {type_str}")]
     UnknownField { type_str: String },
+    #[error("{bytes} is not a valid encoding of {type_name}")]
+    InvalidEncoding {
+        type_name: &'static str,
+        bytes: String,
+    },
+}
+
+impl Error {
+    /// Reports that `buf` does not hold a valid value of type `T`.
+    #[must_use]
+    pub fn invalid_encoding<T>(buf: &[u8]) -> Self {
+        Self::InvalidEncoding {
+            type_name: std::any::type_name::<T>(),
+            bytes: hex::encode(buf),
+        }
+    }
 }
 
 /// Addition and subtraction operations that are supported by secret sharings and shared values.
@@ -56,6 +72,19 @@ pub trait Serializable: Sized {
     ///
     /// [`serialize`]: Self::serialize
     fn deserialize(buf: &GenericArray<u8, Self::Size>) -> Self;
+
+    /// Deserialize message from a sequence of bytes that was received from outside of this
+    /// helper. Unlike [`deserialize`], this must not panic if `buf` holds a byte sequence that
+    /// is not a valid encoding of `Self`. Types that accept any byte sequence can rely on the
+    /// default implementation.
+    ///
+    /// ## Errors
+    /// If `buf` does not hold a valid value of this type.
+    ///
+    /// [`deserialize`]: Self::deserialize
+    fn try_deserialize(buf: &GenericArray<u8, Self::Size>) -> Result<Self, Error> {
+        Ok(Self::deserialize(buf))
+    }
 }
 
 pub trait ArrayAccess {
@@ -82,6 +111,36 @@ where
     Self: ArrayAccess<Output = Self::Element> + Expand<Input = Self::Element>,
 {
     type Element;
+
+    /// Returns the last (most significant) element, which holds the sign of two's complement
+    /// integers.
+    ///
+    /// ## Panics
+    /// If the array is empty.
+    fn sign_bit(&self) -> Self::Element {
+        (0..).map_while(|i| self.get(i)).last().unwrap()
+    }
+
+    /// Converts this array into one of a different length, preserving the value of two's
+    /// complement integers. Elements that don't exist in this array are set to its sign bit.
+    /// When `T` is shorter, the most significant elements are dropped.
+    ///
+    /// ## Panics
+    /// If this array is empty.
+    fn sign_extend<T>(&self) -> T
+    where
+        T: CustomArray<Element = Self::Element>,
+    {
+        let mut result = T::expand(&self.sign_bit());
+        let len = (0..).take_while(|&i| result.get(i).is_some()).count();
+        for i in 0..len {
+            match self.get(i) {
+                Some(e) => result.set(i, e),
+                None => break,
+            }
+        }
+        result
+    }
 }
 
 /// impl Custom Array for all compatible structs
@@ -2,7 +2,7 @@ use generic_array::GenericArray;
 
 use super::Field;
 use crate::{
-    ff::Serializable,
+    ff::{Error, Serializable},
     secret_sharing::{Block, SharedValue},
 };
 
@@ -26,6 +26,14 @@ impl<F: PrimeField> Serializable for F {
 
         Self::try_from(u128::from_le_bytes(buf_to)).unwrap()
     }
+
+    fn try_deserialize(buf: &GenericArray<u8, Self::Size>) -> Result<Self, Error> {
+        let mut buf_to = [0u8; 16];
+        buf_to[..buf.len()].copy_from_slice(buf);
+
+        Self::try_from(u128::from_le_bytes(buf_to))
+            .map_err(|_| Error::invalid_encoding::<Self>(buf))
+    }
 }
 
 macro_rules! field_impl {
@@ -32,6 +32,9 @@ struct State {
     /// Once `written + spare` is greater than the buffer capacity,
     /// data is available to the stream.
     spare: NonZeroUsize,
+    /// Once this many bytes have been written, data is available to the stream.
+    /// Never exceeds the buffer capacity.
+    write_size: usize,
     /// How many bytes have been written and are available.
     written: usize,
     /// The sender is closed.
@@ -47,6 +50,7 @@ impl State {
         Self {
             buf: vec![0; capacity.get() + spare.get()],
             spare,
+            write_size: capacity.get(),
             written: 0,
             closed: false,
             write_ready: None,
@@ -95,7 +99,7 @@ impl State {
     }
 
     fn take(&mut self, cx: &Context<'_>) -> Poll<Vec<u8>> {
-        if self.written > 0 && (self.written + self.spare.get() >= self.buf.len() || self.closed) {
+        if self.written > 0 && (self.written >= self.write_size || self.closed) {
             let v = self.buf[..self.written].to_vec();
             self.written = 0;
 
@@ -119,7 +123,17 @@ impl State {
     ///
     /// [`write`]: Self::write
     fn accept_writes(&self) -> bool {
-        self.written + self.spare.get() < self.buf.len()
+        self.written < self.write_size
+    }
+
+    fn set_write_size(&mut self, write_size: NonZeroUsize) {
+        let capacity = self.buf.len() - self.spare.get();
+        self.write_size = write_size.get().min(capacity);
+        if !self.accept_writes() {
+            Self::wake(&mut self.stream_ready);
+        } else {
+            Self::wake(&mut self.write_ready);
+        }
     }
 }
 
@@ -317,6 +331,16 @@ impl OrderingSender {
         Send { i, m, sender: self }
     }
 
+    /// Changes the number of bytes that need to be written before data becomes available to the
+    /// stream. It can't exceed the capacity this sender was created with, larger values are
+    /// capped at it.
+    ///
+    /// ## Panics
+    /// If the internal mutex is poisoned.
+    pub fn set_write_size(&self, write_size: NonZeroUsize) {
+        self.state.lock().unwrap().set_write_size(write_size);
+    }
+
     /// Close the sender at index `i`.
     /// This method blocks until all previous messages are sent.
     ///
@@ -520,6 +544,21 @@ mod test {
         });
     }
 
+    /// Lowering the write size makes data that is already written available to the stream.
+    #[test]
+    fn lower_write_size() {
+        run(|| async {
+            let input = Fp31::truncate_from(7_u128);
+            let sender = sender();
+            sender.send(0, input).await;
+            sender.set_write_size(NonZeroUsize::new(1).unwrap());
+
+            let taken = sender.as_stream().next().now_or_never().flatten().unwrap();
+            let output = Fp31::deserialize(GenericArray::from_slice(&taken));
+            assert_eq!(input, output);
+        });
+    }
+
     /// Generate a send and close the stream.
     #[test]
     fn send_close_recv() {
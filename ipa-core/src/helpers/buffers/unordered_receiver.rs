@@ -11,27 +11,31 @@ use generic_array::GenericArray;
 use typenum::Unsigned;
 
 use crate::{
+    error::BoxError,
+    ff::Error as FieldError,
     helpers::{Error, Message},
     protocol::RecordId,
     sync::{Arc, Mutex},
 };
 
 /// A future for receiving item `i` from an `UnorderedReceiver`.
-pub struct Receiver<S, C, M>
+pub struct Receiver<S, C, E, M>
 where
-    S: Stream<Item = C> + Send,
+    S: Stream<Item = Result<C, E>> + Send,
     C: AsRef<[u8]>,
+    E: Into<BoxError>,
     M: Message,
 {
     i: usize,
-    receiver: Arc<Mutex<OperatingState<S, C>>>,
+    receiver: Arc<Mutex<OperatingState<S, C, E>>>,
     _marker: PhantomData<M>,
 }
 
-impl<S, C, M> Future for Receiver<S, C, M>
+impl<S, C, E, M> Future for Receiver<S, C, E, M>
 where
-    S: Stream<Item = C> + Send,
+    S: Stream<Item = Result<C, E>> + Send,
     C: AsRef<[u8]>,
+    E: Into<BoxError>,
     M: Message,
 {
     type Output = Result<M, Error>;
@@ -57,10 +61,10 @@ struct Spare {
 
 impl Spare {
     /// Read a message from the buffer.  Returns `None` if there isn't enough data.
-    fn read<M: Message>(&mut self) -> Option<M> {
+    fn read<M: Message>(&mut self) -> Option<Result<M, FieldError>> {
         let end = self.offset + M::Size::USIZE;
         if end <= self.buf.len() {
-            let m = M::deserialize(GenericArray::from_slice(&self.buf[self.offset..end]));
+            let m = M::try_deserialize(GenericArray::from_slice(&self.buf[self.offset..end]));
             self.offset = end;
             Some(m)
         } else {
@@ -79,7 +83,7 @@ impl Spare {
     /// This returns a message if there is enough data.
     /// This returns a value because it can be more efficient in cases where
     /// received chunks don't align with messages.
-    fn extend<M: Message>(&mut self, v: &[u8]) -> Option<M> {
+    fn extend<M: Message>(&mut self, v: &[u8]) -> Option<Result<M, FieldError>> {
         let sz = <M::Size as Unsigned>::USIZE;
         let remainder = self.buf.len() - self.offset;
         if remainder + v.len() < sz {
@@ -98,19 +102,20 @@ impl Spare {
             tmp[..remainder].copy_from_slice(&self.buf[self.offset..]);
             tmp[remainder..].copy_from_slice(&v[..needed]);
             self.replace(&v[needed..]);
-            M::deserialize(&tmp)
+            M::try_deserialize(&tmp)
         } else {
             self.replace(&v[sz..]);
-            M::deserialize(GenericArray::from_slice(&v[..sz]))
+            M::try_deserialize(GenericArray::from_slice(&v[..sz]))
         };
         Some(m)
     }
 }
 
-pub struct OperatingState<S, C>
+pub struct OperatingState<S, C, E>
 where
-    S: Stream<Item = C>,
+    S: Stream<Item = Result<C, E>>,
     C: AsRef<[u8]>,
+    E: Into<BoxError>,
 {
     /// The stream we're reading from.
     stream: Pin<Box<S>>,
@@ -152,13 +157,14 @@ where
     overflow_wakers: Vec<(Waker, usize)>,
     #[cfg(not(feature = "stall-detection"))]
     overflow_wakers: Vec<Waker>,
-    _marker: PhantomData<C>,
+    _marker: PhantomData<(C, E)>,
 }
 
-impl<S, C> OperatingState<S, C>
+impl<S, C, E> OperatingState<S, C, E>
 where
-    S: Stream<Item = C> + Send,
+    S: Stream<Item = Result<C, E>> + Send,
     C: AsRef<[u8]>,
+    E: Into<BoxError>,
 {
     /// Determine whether `i` is the next record that we expect to receive.
     fn is_next(&self, i: usize) -> bool {
@@ -218,13 +224,23 @@ where
         }
     }
 
+    /// Hand over the message for the current record and move on to the next one.
+    /// Invalid messages are reported as errors and do not stall the following records.
+    fn deliver<M: Message>(&mut self, m: Result<M, FieldError>) -> Result<M, Error> {
+        let record_id = RecordId::from(self.next);
+        self.wake_next();
+        m.map_err(|e| Error::DeserializationError {
+            record_id,
+            inner: e.into(),
+        })
+    }
+
     /// Poll for the next record.  This should only be invoked when
     /// the future for the next message is polled.
     fn poll_next<M: Message>(&mut self, cx: &mut Context<'_>) -> Poll<Result<M, Error>> {
         self.max_polled_idx = std::cmp::max(self.max_polled_idx, self.next);
         if let Some(m) = self.spare.read() {
-            self.wake_next();
-            return Poll::Ready(Ok(m));
+            return Poll::Ready(self.deliver(m));
         }
 
         loop {
@@ -232,12 +248,17 @@ where
                 Poll::Pending => {
                     return Poll::Pending;
                 }
-                Poll::Ready(Some(b)) => {
+                Poll::Ready(Some(Ok(b))) => {
                     if let Some(m) = self.spare.extend(b.as_ref()) {
-                        self.wake_next();
-                        return Poll::Ready(Ok(m));
+                        return Poll::Ready(self.deliver(m));
                     }
                 }
+                Poll::Ready(Some(Err(e))) => {
+                    return Poll::Ready(Err(Error::CorruptedStream {
+                        record_id: RecordId::from(self.next),
+                        inner: e.into(),
+                    }));
+                }
                 Poll::Ready(None) => {
                     return Poll::Ready(Err(Error::EndOfStream {
                         record_id: RecordId::from(self.next),
@@ -289,20 +310,23 @@ where
 }
 
 /// Take an ordered stream of bytes and make messages from that stream
-/// available in any order.
-pub struct UnorderedReceiver<S, C>
+/// available in any order. An error in the stream is reported as
+/// [`Error::CorruptedStream`] for the record that is being received.
+pub struct UnorderedReceiver<S, C, E>
 where
-    S: Stream<Item = C>,
+    S: Stream<Item = Result<C, E>>,
     C: AsRef<[u8]>,
+    E: Into<BoxError>,
 {
-    inner: Arc<Mutex<OperatingState<S, C>>>,
+    inner: Arc<Mutex<OperatingState<S, C, E>>>,
 }
 
 #[allow(dead_code)]
-impl<S, C> UnorderedReceiver<S, C>
+impl<S, C, E> UnorderedReceiver<S, C, E>
 where
-    S: Stream<Item = C> + Send,
+    S: Stream<Item = Result<C, E>> + Send,
     C: AsRef<[u8]>,
+    E: Into<BoxError>,
 {
     /// Wrap a stream for unordered reading.
     ///
@@ -336,7 +360,7 @@ where
     /// Only if there are multiple invocations for the same `i`.
     /// If one future is resolved, the other will panic when polled.
     /// If both futures are polled by different contexts, the second will panic.
-    pub fn recv<M: Message, I: Into<usize>>(&self, i: I) -> Receiver<S, C, M> {
+    pub fn recv<M: Message, I: Into<usize>>(&self, i: I) -> Receiver<S, C, E, M> {
         Receiver {
             i: i.into(),
             receiver: Arc::clone(&self.inner),
@@ -355,10 +379,11 @@ where
     }
 }
 
-impl<S, C> Clone for UnorderedReceiver<S, C>
+impl<S, C, E> Clone for UnorderedReceiver<S, C, E>
 where
-    S: Stream<Item = C> + Send,
+    S: Stream<Item = Result<C, E>> + Send,
     C: AsRef<[u8]>,
+    E: Into<BoxError>,
 {
     fn clone(&self) -> Self {
         Self {
@@ -369,7 +394,7 @@ where
 
 #[cfg(all(test, any(unit_test, feature = "shuttle")))]
 mod test {
-    use std::num::NonZeroUsize;
+    use std::{convert::Infallible, num::NonZeroUsize};
 
     use futures::{
         future::{try_join, try_join_all},
@@ -386,10 +411,13 @@ mod test {
 
     use crate::{
         ff::{Field, Fp31, Fp32BitPrime, Serializable},
-        helpers::buffers::unordered_receiver::UnorderedReceiver,
+        helpers::{buffers::unordered_receiver::UnorderedReceiver, Error},
+        protocol::RecordId,
     };
 
-    fn receiver<I, T>(it: I) -> UnorderedReceiver<impl Stream<Item = T>, T>
+    fn receiver<I, T>(
+        it: I,
+    ) -> UnorderedReceiver<impl Stream<Item = Result<T, Infallible>>, T, Infallible>
     where
         I: IntoIterator<Item = T> + 'static,
         I::IntoIter: Send,
@@ -397,7 +425,7 @@ mod test {
     {
         // Use a small capacity so that we can overflow it easily.
         let capacity = NonZeroUsize::new(3).unwrap();
-        UnorderedReceiver::new(Box::pin(iter(it)), capacity)
+        UnorderedReceiver::new(Box::pin(iter(it.into_iter().map(Ok))), capacity)
     }
 
     #[cfg(not(feature = "shuttle"))]
@@ -487,6 +515,25 @@ mod test {
         });
     }
 
+    /// Values that cannot be deserialized are reported as errors without blocking the
+    /// records that follow them.
+    #[test]
+    fn invalid_value() {
+        const DATA: &[u8] = &[7, 31, 2];
+
+        run(|| async {
+            let recv = receiver(vec![DATA.to_vec()]);
+            let f: Fp31 = recv.recv(0_usize).await.unwrap();
+            assert_eq!(f, Fp31::try_from(7).unwrap());
+            assert!(matches!(
+                recv.recv::<Fp31, _>(1_usize).await,
+                Err(Error::DeserializationError { .. })
+            ));
+            let f: Fp31 = recv.recv(2_usize).await.unwrap();
+            assert_eq!(f, Fp31::try_from(2).unwrap());
+        });
+    }
+
     /// Encode 10 values and then read them out.
     /// This splits the buffer into three chunks.
     #[test]
@@ -555,6 +602,24 @@ mod test {
         }
     }
 
+    /// Errors of the underlying stream are reported for the record that was being received.
+    #[test]
+    #[cfg(not(feature = "shuttle"))]
+    fn stream_error() {
+        use futures::FutureExt;
+
+        let recv = UnorderedReceiver::new(
+            Box::pin(iter([Ok(vec![18]), Err("corrupted")])),
+            NonZeroUsize::new(3).unwrap(),
+        );
+        let f: Fp31 = recv.recv(0_usize).now_or_never().unwrap().unwrap();
+        assert_eq!(f, Fp31::try_from(18_u128).unwrap());
+        assert!(matches!(
+            recv.recv::<Fp31, _>(1_usize).now_or_never().unwrap(),
+            Err(Error::CorruptedStream { record_id, .. }) if record_id == RecordId::from(1)
+        ));
+    }
+
     /// Register more reads than the receiver has the capacity to track.
     /// Start by registering those that are furthest into the future to
     /// exercise the overflow tracking mechanism.
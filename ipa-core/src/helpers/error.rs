@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use thiserror::Error;
 use tokio::sync::mpsc::error::SendError;
 
@@ -34,6 +36,14 @@ pub enum Error {
         #[source]
         inner: BoxError,
     },
+    #[error("Timed out after {ttl:?} waiting to receive {record_id:?} from {channel_id:?}")]
+    ReceiveTimeout {
+        channel_id: ChannelId,
+        record_id: RecordId,
+        ttl: Duration,
+    },
+    #[error("{peer:?} did not send a heartbeat for {silent_for:?} and is considered down")]
+    PeerUnresponsive { peer: Role, silent_for: Duration },
     #[error("Expected to receive {record_id:?} but hit end of stream")]
     EndOfStream {
         // TODO(mt): add more fields, like step and role.
@@ -46,6 +56,18 @@ pub enum Error {
         #[source]
         inner: BoxError,
     },
+    #[error("Data received for {record_id:?} is not a valid message: {inner}")]
+    DeserializationError {
+        record_id: RecordId,
+        #[source]
+        inner: BoxError,
+    },
+    #[error("Data received for {record_id:?} is corrupted: {inner}")]
+    CorruptedStream {
+        record_id: RecordId,
+        #[source]
+        inner: BoxError,
+    },
     #[error("Encountered unknown identity {0:?}")]
     UnknownIdentity(HelperIdentity),
     #[error("record ID {record_id:?} is out of range for {channel_id:?} (expected {total_records:?} records)")]
@@ -54,9 +76,53 @@ pub enum Error {
         channel_id: ChannelId,
         total_records: TotalRecords,
     },
+    #[error("{record_id:?} on {channel_id:?} is not in the captured traffic")]
+    NotCaptured {
+        channel_id: ChannelId,
+        record_id: RecordId,
+    },
+    #[error("{record_id:?} on {channel_id:?} does not match the captured traffic")]
+    ReplayMismatch {
+        channel_id: ChannelId,
+        record_id: RecordId,
+    },
 }
 
 impl Error {
+    /// Returns the step this error happened at, if it happened while exchanging records with
+    /// peers.
+    #[must_use]
+    pub fn gate(&self) -> Option<&str> {
+        match self {
+            Self::SendError { channel, .. } => Some(channel.gate.as_ref()),
+            Self::ReceiveTimeout { channel_id, .. }
+            | Self::TooManyRecords { channel_id, .. }
+            | Self::NotCaptured { channel_id, .. }
+            | Self::ReplayMismatch { channel_id, .. } => Some(channel_id.gate.as_ref()),
+            Self::ReceiveError { step, .. } | Self::SerializationError { step, .. } => Some(step),
+            Self::OrderedChannelError { .. }
+            | Self::PollSendError { .. }
+            | Self::PeerUnresponsive { .. }
+            | Self::EndOfStream { .. }
+            | Self::DeserializationError { .. }
+            | Self::CorruptedStream { .. }
+            | Self::UnknownIdentity(_) => None,
+        }
+    }
+
+    /// Whether this error was caused by a peer that failed, misbehaved or could not be reached,
+    /// as opposed to a problem on this helper.
+    #[must_use]
+    pub fn is_peer_failure(&self) -> bool {
+        !matches!(
+            self,
+            Self::SerializationError { .. }
+                | Self::TooManyRecords { .. }
+                | Self::NotCaptured { .. }
+                | Self::ReplayMismatch { .. }
+        )
+    }
+
     pub fn send_error<E: Into<Box<dyn std::error::Error + Send + Sync + 'static>>>(
         channel: ChannelId,
         inner: E,
@@ -0,0 +1,278 @@
+//! Capture and replay of records exchanged by a helper.
+//!
+//! When helpers disagree, the first record where they diverge is usually much closer to the bug
+//! than the error the query eventually fails with. A gateway that captures traffic writes every
+//! record it sends or receives to a file, one line per record:
+//!
+//! ```text
+//! sent	H2	/protocol/run-0/mul	0	1e
+//! received	H3	/protocol/run-0/mul	0	07
+//! ```
+//!
+//! with the direction, the peer, the step, the record id and the serialized record in hex.
+//!
+//! A gateway that replays a capture doesn't talk to peers at all. Records it receives are read
+//! from the capture and records it sends are compared to the captured ones, failing on the first
+//! one that is different. Together with the PRSS of the original run, this re-executes the
+//! protocol of a single helper locally, where it can be stepped through or instrumented at will.
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use generic_array::GenericArray;
+use typenum::Unsigned;
+
+use crate::{
+    ff::Serializable,
+    helpers::{ChannelId, Error, Message, Role},
+    protocol::{step::Gate, RecordId},
+    sync::{Arc, Mutex},
+};
+
+/// What a gateway does with the records it exchanges with peers.
+#[derive(Clone, Default)]
+pub enum Traffic {
+    /// Records are sent to and received from peers.
+    #[default]
+    Live,
+    /// Same as `Live`, but every record is also written to the capture.
+    Capture(Arc<TrafficCapture>),
+    /// Records are not exchanged with peers: received records are read from a previous capture
+    /// and sent records are checked against it.
+    Replay(Arc<CapturedTraffic>),
+}
+
+impl Traffic {
+    #[must_use]
+    pub fn is_replay(&self) -> bool {
+        matches!(self, Self::Replay(_))
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub(super) enum TrafficDirection {
+    Sent,
+    Received,
+}
+
+impl TrafficDirection {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Sent => "sent",
+            Self::Received => "received",
+        }
+    }
+}
+
+impl TryFrom<&str> for TrafficDirection {
+    type Error = io::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "sent" => Ok(Self::Sent),
+            "received" => Ok(Self::Received),
+            other => Err(invalid_data(format!("unknown direction {other}"))),
+        }
+    }
+}
+
+/// Writes records a gateway exchanges with peers to a file.
+pub struct TrafficCapture {
+    out: Mutex<BufWriter<File>>,
+}
+
+impl TrafficCapture {
+    /// Creates the capture file at `path`, replacing it if it exists.
+    ///
+    /// ## Errors
+    /// If the file can't be created.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self {
+            out: Mutex::new(BufWriter::new(File::create(path)?)),
+        })
+    }
+
+    pub(super) fn record<M: Message>(
+        &self,
+        direction: TrafficDirection,
+        channel_id: &ChannelId,
+        record_id: RecordId,
+        msg: &M,
+    ) {
+        let mut buf = GenericArray::default();
+        msg.serialize(&mut buf);
+        let mut out = self.out.lock().unwrap();
+        if let Err(e) = writeln!(
+            out,
+            "{}\t{}\t/{}\t{}\t{}",
+            direction.as_str(),
+            channel_id.role.as_static_str(),
+            channel_id.gate.as_ref().trim_start_matches('/'),
+            usize::from(record_id),
+            hex::encode(buf),
+        ) {
+            tracing::warn!("failed to capture {record_id:?} on {channel_id:?}: {e}");
+        }
+    }
+
+    /// Writes records captured so far to the file. Records are also written once the capture is
+    /// dropped, along with the gateway that uses it.
+    ///
+    /// ## Errors
+    /// If writing to the file fails.
+    ///
+    /// ## Panics
+    /// If the capture mutex is poisoned.
+    pub fn flush(&self) -> io::Result<()> {
+        self.out.lock().unwrap().flush()
+    }
+}
+
+/// Records captured by [`TrafficCapture`], read back to replay them.
+#[derive(Debug, Default)]
+pub struct CapturedTraffic {
+    records: HashMap<(TrafficDirection, ChannelId, RecordId), Vec<u8>>,
+}
+
+impl CapturedTraffic {
+    /// Reads the capture written to `path`.
+    ///
+    /// ## Errors
+    /// If the file can't be read or is not a valid capture.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut records = HashMap::new();
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            let [direction, role, gate, record_id, data] = line
+                .split('\t')
+                .collect::<Vec<_>>()
+                .try_into()
+                .map_err(|_| invalid_data(format!("malformed line {line}")))?;
+            let role = Role::try_from(role).map_err(|e| invalid_data(e.to_string()))?;
+            let record_id = record_id
+                .parse::<usize>()
+                .map_err(|e| invalid_data(e.to_string()))?;
+            let data = hex::decode(data).map_err(|e| invalid_data(e.to_string()))?;
+            records.insert(
+                (
+                    TrafficDirection::try_from(direction)?,
+                    ChannelId::new(role, Gate::from(gate)),
+                    RecordId::from(record_id),
+                ),
+                data,
+            );
+        }
+
+        Ok(Self { records })
+    }
+
+    fn get(
+        &self,
+        direction: TrafficDirection,
+        channel_id: &ChannelId,
+        record_id: RecordId,
+    ) -> Result<&[u8], Error> {
+        self.records
+            .get(&(direction, channel_id.clone(), record_id))
+            .map(Vec::as_slice)
+            .ok_or_else(|| Error::NotCaptured {
+                channel_id: channel_id.clone(),
+                record_id,
+            })
+    }
+
+    pub(super) fn received<M: Message>(
+        &self,
+        channel_id: &ChannelId,
+        record_id: RecordId,
+    ) -> Result<M, Error> {
+        let data = self.get(TrafficDirection::Received, channel_id, record_id)?;
+        if data.len() != M::Size::USIZE {
+            return Err(Error::ReplayMismatch {
+                channel_id: channel_id.clone(),
+                record_id,
+            });
+        }
+
+        M::try_deserialize(GenericArray::from_slice(data)).map_err(|e| {
+            Error::DeserializationError {
+                record_id,
+                inner: e.into(),
+            }
+        })
+    }
+
+    pub(super) fn check_sent<M: Message>(
+        &self,
+        channel_id: &ChannelId,
+        record_id: RecordId,
+        msg: &M,
+    ) -> Result<(), Error> {
+        let mut buf = GenericArray::<u8, M::Size>::default();
+        msg.serialize(&mut buf);
+        if self.get(TrafficDirection::Sent, channel_id, record_id)? == buf.as_slice() {
+            Ok(())
+        } else {
+            Err(Error::ReplayMismatch {
+                channel_id: channel_id.clone(),
+                record_id,
+            })
+        }
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{CapturedTraffic, TrafficDirection};
+    use crate::{
+        ff::boolean::Boolean,
+        helpers::{ChannelId, Error, Role},
+        protocol::{step::Gate, RecordId},
+    };
+
+    #[test]
+    fn corrupt_received_record() {
+        let channel_id = ChannelId::new(Role::H2, Gate::default());
+        let captured = CapturedTraffic {
+            records: [(0, vec![1]), (1, vec![2]), (2, vec![]), (3, vec![0, 1])]
+                .into_iter()
+                .map(|(i, data)| {
+                    (
+                        (
+                            TrafficDirection::Received,
+                            channel_id.clone(),
+                            RecordId::from(i),
+                        ),
+                        data,
+                    )
+                })
+                .collect::<HashMap<_, _>>(),
+        };
+
+        assert_eq!(
+            Boolean::from(true),
+            captured
+                .received::<Boolean>(&channel_id, RecordId::from(0))
+                .unwrap()
+        );
+        assert!(matches!(
+            captured.received::<Boolean>(&channel_id, RecordId::from(1)),
+            Err(Error::DeserializationError { .. })
+        ));
+        for i in [2, 3] {
+            assert!(matches!(
+                captured.received::<Boolean>(&channel_id, RecordId::from(i)),
+                Err(Error::ReplayMismatch { .. })
+            ));
+        }
+    }
+}
@@ -0,0 +1,249 @@
+//! Integrity checks for record streams exchanged between helpers.
+//!
+//! Every chunk produced by the sending end of a channel is wrapped into a frame that carries the
+//! chunk length and its xxHash (XXH3, 64 bits) checksum. Transports are free to split or merge
+//! chunks, so the receiving end reassembles frames before validating them. A mismatch, or a
+//! stream that ends in the middle of a frame, is reported with the byte offset of the offending
+//! frame, instead of surfacing later as garbage shares.
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::{ready, Stream};
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::helpers::ChannelId;
+
+/// Frame header: payload length (`u32`) followed by the payload checksum (`u64`), both
+/// little-endian.
+const HEADER_SIZE: usize = 12;
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum ChecksumError {
+    #[error("Checksum mismatch for the frame at offset {offset}: expected {expected:#018x}, got {actual:#018x}")]
+    Mismatch {
+        offset: usize,
+        expected: u64,
+        actual: u64,
+    },
+    #[error("Stream ended in the middle of the frame at offset {offset}: {buffered} bytes are left unprocessed")]
+    Truncated { offset: usize, buffered: usize },
+}
+
+/// Wraps a chunk of records into a frame that can be validated on the receiving side.
+///
+/// ## Panics
+/// If chunk is larger than 4Gb.
+#[must_use]
+pub fn frame(chunk: Vec<u8>) -> Vec<u8> {
+    let len = u32::try_from(chunk.len()).expect("chunk size must fit into u32");
+    let mut frame = Vec::with_capacity(HEADER_SIZE + chunk.len());
+    frame.extend_from_slice(&len.to_le_bytes());
+    frame.extend_from_slice(&xxh3_64(&chunk).to_le_bytes());
+    frame.extend_from_slice(&chunk);
+
+    frame
+}
+
+/// Reassembles frames produced by [`frame`] from a stream of arbitrarily sized chunks.
+#[derive(Default)]
+struct Deframer {
+    buf: Vec<u8>,
+    /// Stream offset of the first byte in `buf`.
+    offset: usize,
+}
+
+impl Deframer {
+    fn extend(&mut self, chunk: &[u8]) {
+        self.buf.extend_from_slice(chunk);
+    }
+
+    /// Returns the payload of the next complete frame or `None` if more data is required.
+    fn next_frame(&mut self) -> Result<Option<Vec<u8>>, ChecksumError> {
+        if self.buf.len() < HEADER_SIZE {
+            return Ok(None);
+        }
+        let len = usize::try_from(u32::from_le_bytes(self.buf[..4].try_into().unwrap())).unwrap();
+        let end = HEADER_SIZE + len;
+        if self.buf.len() < end {
+            return Ok(None);
+        }
+
+        let expected = u64::from_le_bytes(self.buf[4..HEADER_SIZE].try_into().unwrap());
+        let actual = xxh3_64(&self.buf[HEADER_SIZE..end]);
+        if expected != actual {
+            return Err(ChecksumError::Mismatch {
+                offset: self.offset,
+                expected,
+                actual,
+            });
+        }
+
+        let rest = self.buf.split_off(end);
+        let mut payload = std::mem::replace(&mut self.buf, rest);
+        payload.drain(..HEADER_SIZE);
+        self.offset += end;
+
+        Ok(Some(payload))
+    }
+
+    fn finish(&self) -> Result<(), ChecksumError> {
+        if self.buf.is_empty() {
+            Ok(())
+        } else {
+            Err(ChecksumError::Truncated {
+                offset: self.offset,
+                buffered: self.buf.len(),
+            })
+        }
+    }
+}
+
+/// Validates frames received over the given channel and yields their payloads. The first invalid
+/// frame is yielded as an error, which ends the stream.
+pub struct ChecksumValidator<S> {
+    inner: S,
+    channel_id: ChannelId,
+    deframer: Deframer,
+    failed: bool,
+}
+
+impl<S> ChecksumValidator<S> {
+    pub fn new(channel_id: ChannelId, inner: S) -> Self {
+        Self {
+            inner,
+            channel_id,
+            deframer: Deframer::default(),
+            failed: false,
+        }
+    }
+}
+
+impl<S> ChecksumValidator<S> {
+    fn fail(&mut self, e: ChecksumError) -> Poll<Option<Result<Vec<u8>, ChecksumError>>> {
+        tracing::error!("{:?}: {e}", self.channel_id);
+        self.failed = true;
+        Poll::Ready(Some(Err(e)))
+    }
+}
+
+impl<S: Stream<Item = Vec<u8>> + Unpin> Stream for ChecksumValidator<S> {
+    type Item = Result<Vec<u8>, ChecksumError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        // There is no way to recover from corrupted data, so nothing is read past it.
+        if this.failed {
+            return Poll::Ready(None);
+        }
+        loop {
+            match this.deframer.next_frame() {
+                Ok(Some(payload)) => return Poll::Ready(Some(Ok(payload))),
+                Ok(None) => {}
+                Err(e) => return this.fail(e),
+            }
+
+            match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(chunk) => this.deframer.extend(&chunk),
+                None => {
+                    if let Err(e) = this.deframer.finish() {
+                        return this.fail(e);
+                    }
+                    return Poll::Ready(None);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use futures::{stream, StreamExt, TryStreamExt};
+
+    use super::{frame, ChecksumError, ChecksumValidator, Deframer, HEADER_SIZE};
+    use crate::helpers::{ChannelId, Role};
+
+    fn channel() -> ChannelId {
+        ChannelId::new(Role::H2, "checksum".into())
+    }
+
+    #[tokio::test]
+    async fn reassembles_split_frames() {
+        let chunks = [vec![1_u8, 2, 3], vec![], vec![4_u8; 100]];
+        let bytes = chunks.iter().cloned().flat_map(frame).collect::<Vec<_>>();
+
+        // deliver frames byte by byte
+        let received =
+            ChecksumValidator::new(channel(), stream::iter(bytes.into_iter().map(|b| vec![b])))
+                .try_collect::<Vec<_>>()
+                .await;
+
+        assert_eq!(Ok(chunks.to_vec()), received);
+    }
+
+    #[test]
+    fn detects_corruption() {
+        let mut bytes = frame(vec![0_u8; 10]);
+        let second = bytes.len();
+        bytes.extend(frame(vec![1_u8; 10]));
+        bytes[second + HEADER_SIZE + 5] ^= 1;
+
+        let mut deframer = Deframer::default();
+        deframer.extend(&bytes);
+        assert_eq!(Ok(Some(vec![0_u8; 10])), deframer.next_frame());
+        assert!(matches!(
+            deframer.next_frame(),
+            Err(ChecksumError::Mismatch { offset, .. }) if offset == second
+        ));
+    }
+
+    #[test]
+    fn detects_truncation() {
+        let bytes = frame(vec![0_u8; 10]);
+        let mut deframer = Deframer::default();
+        deframer.extend(&bytes[..bytes.len() - 1]);
+        assert_eq!(Ok(None), deframer.next_frame());
+        assert_eq!(
+            Err(ChecksumError::Truncated {
+                offset: 0,
+                buffered: bytes.len() - 1
+            }),
+            deframer.finish()
+        );
+    }
+
+    #[tokio::test]
+    async fn corrupted_stream_ends_with_error() {
+        let mut bytes = frame(vec![7_u8; 4]);
+        bytes.extend(frame(vec![8_u8; 4]));
+        bytes[HEADER_SIZE] = 0;
+
+        let received = ChecksumValidator::new(channel(), stream::iter([bytes]))
+            .collect::<Vec<_>>()
+            .await;
+
+        assert!(matches!(
+            received.as_slice(),
+            [Err(ChecksumError::Mismatch { offset: 0, .. })]
+        ));
+    }
+
+    #[tokio::test]
+    async fn truncated_stream_ends_with_error() {
+        let bytes = frame(vec![7_u8; 4]);
+
+        let received =
+            ChecksumValidator::new(channel(), stream::iter([bytes[..HEADER_SIZE].to_vec()]))
+                .collect::<Vec<_>>()
+                .await;
+
+        assert_eq!(
+            vec![Err(ChecksumError::Truncated {
+                offset: 0,
+                buffered: HEADER_SIZE
+            })],
+            received
+        );
+    }
+}
@@ -0,0 +1,81 @@
+use std::collections::BTreeMap;
+
+use dashmap::DashMap;
+
+use crate::{
+    protocol::step::Gate,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Traffic and randomness used by all queries in a single protocol stage.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct StageCounters {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub records_sent: u64,
+    /// Number of values generated by PRSS, both indexed and sequential.
+    pub prss_generated: u64,
+}
+
+/// Counters shared by all queries running on this helper. Unlike [`ProgressTracker`], which is
+/// created for every query and goes away with it, these keep growing for as long as the helper
+/// runs, so they can be exported to monitoring systems.
+///
+/// Steps are grouped by the top-level step they were narrowed from, the same way
+/// [`QueryMetrics`] groups them, to keep the number of distinct counters small.
+///
+/// [`ProgressTracker`]: super::ProgressTracker
+/// [`QueryMetrics`]: super::QueryMetrics
+#[derive(Debug, Default)]
+pub struct HelperCounters {
+    stages: DashMap<String, StageCounters>,
+    active_queries: AtomicUsize,
+}
+
+/// Point-in-time copy of [`HelperCounters`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct HelperMetrics {
+    /// Number of queries whose gateway is still alive.
+    pub active_queries: usize,
+    pub stages: BTreeMap<String, StageCounters>,
+}
+
+impl HelperCounters {
+    pub(super) fn update<F: FnOnce(&mut StageCounters)>(&self, gate: &Gate, f: F) {
+        f(&mut self.stages.entry(stage_name(gate)).or_default());
+    }
+
+    pub(super) fn query_started(&self) {
+        self.active_queries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn query_finished(&self) {
+        self.active_queries.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    #[must_use]
+    pub fn snapshot(&self) -> HelperMetrics {
+        HelperMetrics {
+            active_queries: self.active_queries.load(Ordering::Relaxed),
+            stages: self
+                .stages
+                .iter()
+                .map(|entry| (entry.key().clone(), *entry.value()))
+                .collect(),
+        }
+    }
+}
+
+/// Returns the name of the top-level step `gate` was narrowed from.
+pub(super) fn stage_name(gate: &Gate) -> String {
+    // Descriptive gates start with the root step, compact ones don't.
+    let root = Gate::default();
+    let path = gate.as_ref();
+    let path = path
+        .strip_prefix(root.as_ref())
+        .map_or(path, |p| p.trim_start_matches('/'));
+    let top = path.split('/').next().unwrap_or_default();
+
+    // step-trace builds qualify step names with their types
+    top.rsplit("::").next().unwrap_or(top).to_string()
+}
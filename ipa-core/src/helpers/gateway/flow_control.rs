@@ -0,0 +1,402 @@
+//! Credit-based flow control between helpers.
+//!
+//! Without it, how far a sender gets ahead of its peer is only bounded by the number of records
+//! the network and the peer are willing to buffer. When one helper is much slower than the
+//! others, everything sent to it piles up in memory. With flow control enabled, receivers
+//! advertise how many records of each channel they have consumed, and senders do not send
+//! records that are more than a window of records ahead of that.
+//!
+//! Credits are sent over a dedicated channel to each peer. Channels are identified by a hash of
+//! their gate, because gates don't have a fixed-size representation. All helpers must use the
+//! same window, otherwise senders wait for credits that are never granted.
+use std::num::NonZeroUsize;
+
+use ::tokio::sync::Notify;
+use dashmap::DashMap;
+use futures::{
+    channel::oneshot,
+    future::{select, Either},
+};
+use generic_array::GenericArray;
+use sha2::{Digest, Sha256};
+#[cfg(all(test, feature = "shuttle"))]
+use shuttle::future as tokio;
+use typenum::U12;
+
+use crate::{
+    ff::Serializable,
+    helpers::{
+        gateway::{send::SendingEnd, Gateway},
+        ChannelId, Error, Message, Role, TotalRecords,
+    },
+    protocol::{
+        step::{Gate, Step, StepNarrow},
+        RecordId,
+    },
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+pub struct FlowControlStep;
+
+impl AsRef<str> for FlowControlStep {
+    fn as_ref(&self) -> &str {
+        "flow-control"
+    }
+}
+
+impl Step for FlowControlStep {}
+
+/// Returns the number of records receivers consume before they grant more credits. Senders
+/// never buffer more records than that, otherwise the window may run out before a batch is
+/// complete.
+pub(super) fn grant_size(window: NonZeroUsize) -> NonZeroUsize {
+    NonZeroUsize::new(window.get() / 2).unwrap_or(window)
+}
+
+fn channel_key(gate: &Gate) -> u64 {
+    let digest = Sha256::digest(gate.as_ref().as_bytes());
+    u64::from_le_bytes(digest[..8].try_into().unwrap())
+}
+
+/// Tells the sender that the receiver has consumed `received` records of a channel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Credit {
+    channel: u64,
+    received: u32,
+}
+
+impl Serializable for Credit {
+    type Size = U12;
+
+    fn serialize(&self, buf: &mut GenericArray<u8, Self::Size>) {
+        buf[..8].copy_from_slice(&self.channel.to_le_bytes());
+        buf[8..].copy_from_slice(&self.received.to_le_bytes());
+    }
+
+    fn deserialize(buf: &GenericArray<u8, Self::Size>) -> Self {
+        Self {
+            channel: u64::from_le_bytes(buf[..8].try_into().unwrap()),
+            received: u32::from_le_bytes(buf[8..].try_into().unwrap()),
+        }
+    }
+}
+
+impl Message for Credit {}
+
+/// Flow control state of a gateway, shared by all of its channels.
+#[derive(Default)]
+pub(super) struct FlowControl {
+    /// Credits received from each peer.
+    limits: DashMap<Role, Arc<Limits>>,
+    /// Credits sent to each peer.
+    grants: DashMap<Role, Arc<Grants>>,
+    /// Number of records consumed from each channel.
+    received: DashMap<ChannelId, Arc<AtomicUsize>>,
+}
+
+/// Credits granted by a peer for channels that send to it. They are received by a background
+/// task that runs until the gateway and all of its sending ends are dropped.
+struct Limits {
+    granted: DashMap<u64, usize>,
+    updated: Notify,
+    failed: Mutex<Option<String>>,
+    _stop: oneshot::Sender<()>,
+}
+
+struct Grants {
+    tx: SendingEnd<Credit>,
+    next: AtomicUsize,
+}
+
+/// Makes a sending end wait until the receiver is ready to take its records.
+pub(super) struct SendCredits {
+    window: usize,
+    channel: u64,
+    limits: Arc<Limits>,
+}
+
+/// Makes a receiving end grant credits to the sender as records are consumed.
+pub(super) struct ReceiveCredits {
+    every: usize,
+    channel: u64,
+    received: Arc<AtomicUsize>,
+    grants: Arc<Grants>,
+}
+
+impl FlowControl {
+    fn gate() -> Gate {
+        Gate::default().narrow(&FlowControlStep)
+    }
+
+    /// Returns credits for the sending end of `channel_id`, or `None` if the channel is not
+    /// subject to flow control.
+    pub(super) fn send_credits(
+        &self,
+        gateway: &Gateway,
+        channel_id: &ChannelId,
+        total_records: TotalRecords,
+    ) -> Option<SendCredits> {
+        let window = gateway.config().flow_control?;
+        if total_records.is_indeterminate() || channel_id.gate == Self::gate() {
+            return None;
+        }
+
+        let limits = self
+            .limits
+            .entry(channel_id.role)
+            .or_insert_with(|| Limits::start(gateway, channel_id.role))
+            .clone();
+
+        Some(SendCredits {
+            window: window.get(),
+            channel: channel_key(&channel_id.gate),
+            limits,
+        })
+    }
+
+    /// Returns credits for the receiving end of `channel_id`, or `None` if the channel is not
+    /// subject to flow control.
+    pub(super) fn receive_credits(
+        &self,
+        gateway: &Gateway,
+        channel_id: &ChannelId,
+    ) -> Option<ReceiveCredits> {
+        let window = gateway.config().flow_control?;
+        if channel_id.gate == Self::gate() {
+            return None;
+        }
+
+        let grants = self
+            .grants
+            .entry(channel_id.role)
+            .or_insert_with(|| {
+                Arc::new(Grants {
+                    tx: gateway.get_sender(
+                        &ChannelId::new(channel_id.role, Self::gate()),
+                        TotalRecords::Indeterminate,
+                    ),
+                    next: AtomicUsize::new(0),
+                })
+            })
+            .clone();
+
+        Some(ReceiveCredits {
+            every: grant_size(window).get(),
+            channel: channel_key(&channel_id.gate),
+            received: Arc::clone(&self.received.entry(channel_id.clone()).or_default()),
+            grants,
+        })
+    }
+}
+
+impl Limits {
+    fn start(gateway: &Gateway, peer: Role) -> Arc<Self> {
+        let (stop_tx, stop_rx) = oneshot::channel();
+        let limits = Arc::new(Self {
+            granted: DashMap::new(),
+            updated: Notify::new(),
+            failed: Mutex::new(None),
+            _stop: stop_tx,
+        });
+
+        let rx = gateway.get_receiver::<Credit>(&ChannelId::new(peer, FlowControl::gate()));
+        let weak = Arc::downgrade(&limits);
+        tokio::spawn(async move {
+            let receive = Box::pin(async move {
+                for i in 0_u32.. {
+                    let credit = rx.receive(RecordId::from(i)).await;
+                    let Some(limits) = weak.upgrade() else {
+                        break;
+                    };
+                    if !limits.update(credit, peer) {
+                        break;
+                    }
+                }
+            });
+            // Sending ends are gone, credits are not needed anymore.
+            if let Either::Right(_) = select(receive, stop_rx).await {
+                tracing::trace!("stopped receiving credits from {peer:?}");
+            }
+        });
+
+        limits
+    }
+
+    /// Applies credits received from `peer`. Returns `false` if no more credits can be received.
+    fn update(&self, credit: Result<Credit, Error>, peer: Role) -> bool {
+        let ok = match credit {
+            Ok(Credit { channel, received }) => {
+                let received = usize::try_from(received).unwrap();
+                let mut granted = self.granted.entry(channel).or_default();
+                *granted = (*granted).max(received);
+                true
+            }
+            Err(e) => {
+                tracing::warn!("failed to receive credits from {peer:?}: {e}");
+                *self.failed.lock().unwrap() = Some(e.to_string());
+                false
+            }
+        };
+        self.updated.notify_waiters();
+
+        ok
+    }
+
+    fn granted(&self, channel: u64) -> usize {
+        self.granted.get(&channel).map_or(0, |v| *v)
+    }
+}
+
+impl SendCredits {
+    /// Waits until the receiver has granted enough credits to send `record_id`.
+    ///
+    /// ## Errors
+    /// If credits can no longer be received from the peer.
+    pub async fn acquire(&self, channel_id: &ChannelId, record_id: RecordId) -> Result<(), Error> {
+        let i = usize::from(record_id);
+        loop {
+            // Notifications are captured from this point, so updates can't be missed.
+            let updated = self.limits.updated.notified();
+            if i < self.limits.granted(self.channel) + self.window {
+                return Ok(());
+            }
+            if let Some(e) = self.limits.failed.lock().unwrap().clone() {
+                return Err(Error::send_error(
+                    channel_id.clone(),
+                    format!("no credits to send {record_id:?}: {e}"),
+                ));
+            }
+            updated.await;
+        }
+    }
+}
+
+impl ReceiveCredits {
+    /// Accounts for a consumed record and grants more credits if enough of them have been
+    /// consumed since the last grant.
+    ///
+    /// ## Errors
+    /// If sending credits fails.
+    pub async fn record_received(&self) -> Result<(), Error> {
+        let received = self.received.fetch_add(1, Ordering::Relaxed) + 1;
+        if received % self.every == 0 {
+            let record_id = RecordId::from(self.grants.next.fetch_add(1, Ordering::Relaxed));
+            let credit = Credit {
+                channel: self.channel,
+                received: u32::try_from(received).unwrap(),
+            };
+            self.grants.tx.send(record_id, credit).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use std::{num::NonZeroUsize, time::Duration};
+
+    use futures::future::{join, try_join_all};
+    use generic_array::GenericArray;
+
+    use super::Credit;
+    use crate::{
+        ff::{Field, Fp31, Serializable},
+        helpers::{GatewayConfig, Role},
+        protocol::{context::Context, RecordId},
+        test_fixture::{TestWorld, TestWorldConfig},
+    };
+
+    fn world(window: usize) -> TestWorld {
+        TestWorld::new_with(TestWorldConfig {
+            gateway_config: GatewayConfig {
+                flow_control: NonZeroUsize::new(window),
+                ..GatewayConfig::new(16)
+            },
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn credit_serialization() {
+        let credit = Credit {
+            channel: 0x0102_0304_0506_0708,
+            received: 42,
+        };
+        let mut buf = GenericArray::default();
+        credit.serialize(&mut buf);
+        assert_eq!(credit, Credit::deserialize(&buf));
+    }
+
+    /// Senders can't get more than a window of records ahead of the receiver.
+    #[tokio::test]
+    async fn sender_waits_for_credits() {
+        const WINDOW: usize = 4;
+        let world = world(WINDOW);
+        let [ctx1, ctx2, _] = world.contexts();
+        let ctx1 = ctx1.narrow("credits").set_total_records(2 * WINDOW);
+        let ctx2 = ctx2.narrow("credits").set_total_records(2 * WINDOW);
+        let send = ctx1.send_channel::<Fp31>(Role::H2);
+        let recv = ctx2.recv_channel::<Fp31>(Role::H1);
+
+        for i in 0..WINDOW {
+            send.send(RecordId::from(i), Fp31::ONE).await.unwrap();
+        }
+        tokio::time::timeout(
+            Duration::from_millis(50),
+            send.send(RecordId::from(WINDOW), Fp31::ONE),
+        )
+        .await
+        .expect_err("sender must wait until the receiver consumes some records");
+
+        for i in 0..WINDOW / 2 {
+            recv.receive(RecordId::from(i)).await.unwrap();
+        }
+        for i in WINDOW..WINDOW + WINDOW / 2 {
+            tokio::time::timeout(
+                Duration::from_secs(5),
+                send.send(RecordId::from(i), Fp31::ONE),
+            )
+            .await
+            .expect("credits should be granted")
+            .unwrap();
+        }
+    }
+
+    /// Channels with more records than the window go through when records are sent and received
+    /// concurrently, even if the window is smaller than a batch.
+    #[tokio::test]
+    async fn window_smaller_than_batch() {
+        const TOTAL: usize = 100;
+        for window in [1, 3, 8] {
+            let world = world(window);
+            let [ctx1, ctx2, _] = world.contexts();
+            let ctx1 = ctx1.narrow("window").set_total_records(TOTAL);
+            let ctx2 = ctx2.narrow("window").set_total_records(TOTAL);
+            let send = ctx1.send_channel::<Fp31>(Role::H2);
+            let recv = ctx2.recv_channel::<Fp31>(Role::H1);
+
+            let (sent, received) = join(
+                try_join_all((0..TOTAL).map(|i| {
+                    send.send(
+                        RecordId::from(i),
+                        Fp31::truncate_from(u128::try_from(i).unwrap()),
+                    )
+                })),
+                try_join_all((0..TOTAL).map(|i| recv.receive(RecordId::from(i)))),
+            )
+            .await;
+
+            sent.unwrap();
+            assert_eq!(
+                (0..TOTAL)
+                    .map(|i| Fp31::truncate_from(u128::try_from(i).unwrap()))
+                    .collect::<Vec<_>>(),
+                received.unwrap()
+            );
+        }
+    }
+}
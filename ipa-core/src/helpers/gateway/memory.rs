@@ -0,0 +1,309 @@
+//! Soft accounting of the memory a query uses.
+//!
+//! Helpers run queries on shared machines, and a query that buffers more than the machine can
+//! hold gets the whole helper killed. [`MemoryBudget`] counts bytes held in gateway send buffers,
+//! in input streams and by protocols that buffer shares, against the budget configured for the
+//! query. Only payload bytes are counted, not allocations, so the numbers are approximate.
+//!
+//! Going over the budget logs a warning. With backpressure enabled, senders also wait for usage to
+//! drop below the budget before they buffer more records. They never wait longer than the
+//! configured delay: send buffers are only drained once they hold a full batch, so senders that
+//! wait for them indefinitely could deadlock the query.
+use std::{num::NonZeroUsize, time::Duration};
+
+use ::tokio::sync::Notify;
+
+use crate::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// Memory budget of a query, see [`GatewayConfig::memory_budget`].
+///
+/// [`GatewayConfig::memory_budget`]: crate::helpers::GatewayConfig::memory_budget
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryBudgetConfig {
+    /// Number of bytes the query is expected to stay within.
+    pub bytes: NonZeroUsize,
+
+    /// If set, senders wait up to this long for usage to drop below the budget before they buffer
+    /// more records. `None` only logs a warning when the budget is exceeded.
+    pub backpressure: Option<Duration>,
+}
+
+impl MemoryBudgetConfig {
+    #[must_use]
+    pub fn new(bytes: NonZeroUsize) -> Self {
+        Self {
+            bytes,
+            backpressure: None,
+        }
+    }
+
+    /// Makes senders wait up to `max_delay` for memory to be released when the budget is exceeded.
+    #[must_use]
+    pub fn with_backpressure(mut self, max_delay: Duration) -> Self {
+        self.backpressure = Some(max_delay);
+        self
+    }
+}
+
+/// Tracks the number of bytes a query holds in memory. Every helper has one per query, shared by
+/// its gateway and all contexts of the query, see [`Context::memory`].
+///
+/// [`Context::memory`]: crate::protocol::context::Context::memory
+#[derive(Debug, Default)]
+pub struct MemoryBudget {
+    config: Option<MemoryBudgetConfig>,
+    used: AtomicUsize,
+    peak: AtomicUsize,
+    /// Number of times usage went over the budget.
+    exceeded: AtomicUsize,
+    released: Notify,
+}
+
+impl MemoryBudget {
+    /// Creates a tracker for the given budget. Without one, usage is still counted, but never
+    /// exceeds the budget.
+    #[must_use]
+    pub fn new(config: Option<MemoryBudgetConfig>) -> Self {
+        Self {
+            config,
+            ..Self::default()
+        }
+    }
+
+    #[must_use]
+    pub fn limit(&self) -> Option<NonZeroUsize> {
+        self.config.map(|config| config.bytes)
+    }
+
+    /// Number of bytes currently accounted for.
+    #[must_use]
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Largest number of bytes accounted for at any point so far.
+    #[must_use]
+    pub fn peak(&self) -> usize {
+        self.peak.load(Ordering::Relaxed)
+    }
+
+    #[must_use]
+    pub fn is_exceeded(&self) -> bool {
+        self.limit().is_some_and(|limit| self.used() > limit.get())
+    }
+
+    /// Number of times usage went over the budget so far.
+    #[must_use]
+    pub fn times_exceeded(&self) -> usize {
+        self.exceeded.load(Ordering::Relaxed)
+    }
+
+    /// Accounts for `bytes` until the returned reservation is resized or dropped.
+    pub fn reserve(self: &Arc<Self>, bytes: usize) -> MemoryReservation {
+        self.add(bytes);
+        MemoryReservation {
+            budget: Arc::clone(self),
+            bytes,
+        }
+    }
+
+    /// Waits until usage drops below the budget, if backpressure is enabled, but no longer than
+    /// the delay it is configured with.
+    pub async fn wait_for_capacity(&self) {
+        let Some(max_delay) = self.config.and_then(|config| config.backpressure) else {
+            return;
+        };
+        let below_budget = async {
+            loop {
+                // Notifications are captured from this point, so releases can't be missed.
+                let released = self.released.notified();
+                if !self.is_exceeded() {
+                    break;
+                }
+                released.await;
+            }
+        };
+        if ::tokio::time::timeout(max_delay, below_budget)
+            .await
+            .is_err()
+        {
+            tracing::debug!(
+                "memory budget is still exceeded after {max_delay:?}, proceeding anyway"
+            );
+        }
+    }
+
+    pub(super) fn add(&self, bytes: usize) {
+        let used = self.used.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        self.peak.fetch_max(used, Ordering::Relaxed);
+        let Some(limit) = self.limit() else {
+            return;
+        };
+        // only report usage crossing the budget, not every allocation beyond it
+        if used > limit.get() && used - bytes <= limit.get() {
+            if self.exceeded.fetch_add(1, Ordering::Relaxed) == 0 {
+                tracing::warn!("query uses {used} bytes, over its memory budget of {limit} bytes");
+            } else {
+                tracing::debug!("query uses {used} bytes, over its memory budget of {limit} bytes");
+            }
+        }
+    }
+
+    pub(super) fn release(&self, bytes: usize) {
+        let used = self.used.fetch_sub(bytes, Ordering::Relaxed);
+        debug_assert!(
+            used >= bytes,
+            "released {bytes} bytes, only {used} were used"
+        );
+        if self
+            .config
+            .is_some_and(|config| config.backpressure.is_some())
+        {
+            self.released.notify_waiters();
+        }
+    }
+}
+
+/// Bytes accounted for in a [`MemoryBudget`], released when this is dropped.
+#[derive(Debug)]
+#[must_use]
+pub struct MemoryReservation {
+    budget: Arc<MemoryBudget>,
+    bytes: usize,
+}
+
+impl MemoryReservation {
+    #[must_use]
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Changes the number of bytes accounted for to `bytes`.
+    pub fn resize(&mut self, bytes: usize) {
+        if bytes > self.bytes {
+            self.budget.add(bytes - self.bytes);
+        } else if bytes < self.bytes {
+            self.budget.release(self.bytes - bytes);
+        }
+        self.bytes = bytes;
+    }
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        self.budget.release(self.bytes);
+    }
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use std::{num::NonZeroUsize, time::Duration};
+
+    use super::{MemoryBudget, MemoryBudgetConfig};
+    use crate::{
+        ff::{Field, Fp31},
+        helpers::{GatewayConfig, Role},
+        protocol::{context::Context, RecordId},
+        sync::Arc,
+        test_fixture::{TestWorld, TestWorldConfig},
+    };
+
+    fn budget(bytes: usize) -> MemoryBudgetConfig {
+        MemoryBudgetConfig::new(NonZeroUsize::new(bytes).unwrap())
+    }
+
+    #[test]
+    fn reservations() {
+        let memory = Arc::new(MemoryBudget::new(Some(budget(10))));
+        let mut a = memory.reserve(4);
+        let b = memory.reserve(4);
+        assert_eq!(8, memory.used());
+        assert!(!memory.is_exceeded());
+
+        a.resize(8);
+        assert_eq!(12, memory.used());
+        assert!(memory.is_exceeded());
+        assert_eq!(1, memory.times_exceeded());
+
+        drop(b);
+        a.resize(1);
+        assert_eq!(1, memory.used());
+        assert_eq!(12, memory.peak());
+        assert!(!memory.is_exceeded());
+
+        drop(a);
+        assert_eq!(0, memory.used());
+    }
+
+    #[test]
+    fn unlimited() {
+        let memory = Arc::new(MemoryBudget::new(None));
+        let _reservation = memory.reserve(usize::MAX / 2);
+        assert!(!memory.is_exceeded());
+        assert_eq!(0, memory.times_exceeded());
+    }
+
+    #[tokio::test]
+    async fn backpressure() {
+        let memory = Arc::new(MemoryBudget::new(Some(
+            budget(10).with_backpressure(Duration::from_secs(60)),
+        )));
+        let reservation = memory.reserve(11);
+        tokio::time::timeout(Duration::from_millis(50), memory.wait_for_capacity())
+            .await
+            .expect_err("senders must wait while the budget is exceeded");
+
+        let wait = tokio::spawn({
+            let memory = Arc::clone(&memory);
+            async move { memory.wait_for_capacity().await }
+        });
+        drop(reservation);
+        tokio::time::timeout(Duration::from_secs(5), wait)
+            .await
+            .expect("senders should proceed once memory is released")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn backpressure_is_bounded() {
+        let memory = Arc::new(MemoryBudget::new(Some(
+            budget(10).with_backpressure(Duration::from_millis(10)),
+        )));
+        let _reservation = memory.reserve(11);
+        tokio::time::timeout(Duration::from_secs(5), memory.wait_for_capacity())
+            .await
+            .expect("senders must not wait longer than the configured delay");
+    }
+
+    /// Records are accounted for while they sit in send buffers.
+    #[tokio::test]
+    async fn send_buffers() {
+        let world = TestWorld::new_with(TestWorldConfig {
+            gateway_config: GatewayConfig {
+                memory_budget: Some(budget(1)),
+                ..GatewayConfig::new(16)
+            },
+            ..Default::default()
+        });
+        let [ctx1, ctx2, _] = world.contexts();
+        let ctx1 = ctx1.narrow("buffered").set_total_records(3);
+        let ctx2 = ctx2.narrow("buffered").set_total_records(3);
+        let send = ctx1.send_channel::<Fp31>(Role::H2);
+        let recv = ctx2.recv_channel::<Fp31>(Role::H1);
+
+        send.send(RecordId::from(0), Fp31::ONE).await.unwrap();
+        send.send(RecordId::from(1), Fp31::ONE).await.unwrap();
+        assert_eq!(2, ctx1.memory().used());
+        assert!(ctx1.memory().is_exceeded());
+
+        send.send(RecordId::from(2), Fp31::ONE).await.unwrap();
+        for i in 0..3 {
+            recv.receive(RecordId::from(i)).await.unwrap();
+        }
+        assert_eq!(0, ctx1.memory().used());
+        assert_eq!(3, ctx1.memory().peak());
+    }
+}
@@ -1,3 +1,11 @@
+mod capture;
+pub(super) mod checksum;
+mod counters;
+mod flow_control;
+mod memory;
+mod packed;
+mod profile;
+mod progress;
 mod receive;
 mod send;
 #[cfg(feature = "stall-detection")]
@@ -6,6 +14,15 @@ mod transport;
 
 use std::num::NonZeroUsize;
 
+use once_cell::sync::OnceCell;
+
+pub use capture::{CapturedTraffic, Traffic, TrafficCapture};
+pub use counters::{HelperCounters, HelperMetrics, StageCounters};
+pub use flow_control::FlowControlStep;
+pub use memory::{MemoryBudget, MemoryBudgetConfig, MemoryReservation};
+pub use packed::{PackedReceivingEnd, PackedSendingEnd, BOOLEANS_PER_MESSAGE};
+pub use profile::{StepTraffic, TrafficMismatch, TrafficProfile};
+pub use progress::{ProgressTracker, QueryMetrics, QueryProgress, StageMetrics, StepProgress};
 pub(super) use receive::ReceivingEnd;
 pub(super) use send::SendingEnd;
 #[cfg(all(test, feature = "shuttle"))]
@@ -16,11 +33,17 @@ pub(super) use stall_detection::InstrumentedGateway;
 use crate::{
     helpers::{
         gateway::{
-            receive::GatewayReceivers, send::GatewaySenders, transport::RoleResolvingTransport,
+            flow_control::FlowControl,
+            packed::{packed_total_records, PackedChannels},
+            receive::GatewayReceivers,
+            send::GatewaySenders,
+            transport::RoleResolvingTransport,
         },
-        ChannelId, Message, Role, RoleAssignment, TotalRecords, Transport,
+        ChannelId, Direction, HeartbeatConfig, HelperIdentity, Message, Role, RoleAssignment,
+        TotalRecords, Transport,
     },
     protocol::QueryId,
+    sync::Arc,
 };
 
 /// Alias for the currently configured transport.
@@ -35,6 +58,9 @@ pub type TransportImpl = crate::sync::Arc<crate::net::HttpTransport>;
 
 pub type TransportError = <TransportImpl as Transport>::Error;
 
+/// Default for [`GatewayConfig::max_batch_bytes`].
+const DEFAULT_MAX_BATCH_BYTES: usize = 64 * 1024;
+
 /// Gateway into IPA Network infrastructure. It allows helpers send and receive messages.
 pub struct Gateway {
     config: GatewayConfig,
@@ -43,12 +69,20 @@ pub struct Gateway {
     inner: crate::sync::Arc<State>,
     #[cfg(not(feature = "stall-detection"))]
     inner: State,
+    progress: Arc<ProgressTracker>,
+    memory: Arc<MemoryBudget>,
+    traffic: Traffic,
+    profile: OnceCell<Arc<TrafficProfile>>,
+    #[cfg(feature = "step-graph")]
+    steps: crate::protocol::step::StepRecorder,
 }
 
 #[derive(Default)]
 pub struct State {
     senders: GatewaySenders,
     receivers: GatewayReceivers,
+    packed: PackedChannels,
+    flow_control: FlowControl,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -62,6 +96,43 @@ pub struct GatewayConfig {
     /// send/receive requests
     #[cfg(feature = "stall-detection")]
     pub progress_check_interval: std::time::Duration,
+
+    /// Maximum number of bytes a channel buffers before sending them. Channels buffer up to
+    /// [`active_work`] records, but never more than that many bytes, so steps with large records
+    /// don't wait for a whole batch of them before anything is sent.
+    ///
+    /// [`active_work`]: Self::active_work
+    pub max_batch_bytes: NonZeroUsize,
+
+    /// How long a receiver waits for a single record before giving up. If set, waits that take
+    /// longer fail with [`Error::ReceiveTimeout`] instead of hanging forever. `None` disables
+    /// the limit.
+    ///
+    /// [`Error::ReceiveTimeout`]: crate::helpers::Error::ReceiveTimeout
+    pub record_ttl: Option<std::time::Duration>,
+
+    /// If set, helpers exchange heartbeats while the query is running, so a peer that went down
+    /// fails the query instead of leaving it hanging. See [`with_heartbeats`].
+    ///
+    /// [`with_heartbeats`]: crate::helpers::with_heartbeats
+    pub heartbeat: Option<HeartbeatConfig>,
+
+    /// If set, receivers grant credits to senders as they consume records, and senders don't get
+    /// more than this many records ahead of the credits they were granted on any channel. This
+    /// bounds the memory used for records in flight when one helper is slower than the others.
+    /// All helpers must use the same value. `None` disables flow control.
+    pub flow_control: Option<NonZeroUsize>,
+
+    /// If set, helpers commit to their output shares and exchange the commitments before they
+    /// return the output, so report collectors can tell which helper returned a share it did not
+    /// commit to. All helpers must use the same value. See [`OutputCommitments`].
+    ///
+    /// [`OutputCommitments`]: crate::query::OutputCommitments
+    pub commit_outputs: bool,
+
+    /// If set, the gateway and contexts of the query account for the memory they use against
+    /// this budget and warn when the query exceeds it. See [`MemoryBudget`].
+    pub memory_budget: Option<MemoryBudgetConfig>,
 }
 
 impl Gateway {
@@ -71,6 +142,19 @@ impl Gateway {
         config: GatewayConfig,
         roles: RoleAssignment,
         transport: TransportImpl,
+    ) -> Self {
+        Self::with_traffic(query_id, config, roles, transport, Traffic::Live)
+    }
+
+    /// Same as [`Self::new`], but records exchanged with peers are captured or replayed, see
+    /// [`Traffic`].
+    #[must_use]
+    pub fn with_traffic(
+        query_id: QueryId,
+        config: GatewayConfig,
+        roles: RoleAssignment,
+        transport: TransportImpl,
+        traffic: Traffic,
     ) -> Self {
         #[allow(clippy::useless_conversion)] // not useless in stall-detection build
         Self {
@@ -82,6 +166,12 @@ impl Gateway {
                 config,
             },
             inner: State::default().into(),
+            progress: Arc::new(ProgressTracker::default()),
+            memory: Arc::new(MemoryBudget::new(config.memory_budget)),
+            traffic,
+            profile: OnceCell::new(),
+            #[cfg(feature = "step-graph")]
+            steps: crate::protocol::step::StepRecorder::default(),
         }
     }
 
@@ -90,11 +180,57 @@ impl Gateway {
         self.transport.role()
     }
 
+    /// Returns the identity of the helper that plays the peer role in the given direction
+    /// for this query.
+    #[must_use]
+    pub fn peer_identity(&self, direction: Direction) -> HelperIdentity {
+        self.transport.roles.identity(self.role().peer(direction))
+    }
+
     #[must_use]
     pub fn config(&self) -> &GatewayConfig {
         &self.config
     }
 
+    /// Returns the tracker that counts records sent through this gateway. It remains valid after
+    /// the gateway is dropped, so query processor can report progress of queries that are still
+    /// running.
+    #[must_use]
+    pub fn progress(&self) -> Arc<ProgressTracker> {
+        Arc::clone(&self.progress)
+    }
+
+    /// Same as [`Self::progress`], for callers that don't need to keep the tracker around.
+    #[must_use]
+    pub fn progress_tracker(&self) -> &ProgressTracker {
+        &self.progress
+    }
+
+    /// Returns the memory budget of the query this gateway runs, which accounts for records held
+    /// in its send buffers.
+    #[must_use]
+    pub fn memory(&self) -> &Arc<MemoryBudget> {
+        &self.memory
+    }
+
+    /// Returns the recorder of gates that contexts of this query narrow into, see [`StepGraph`].
+    ///
+    /// [`StepGraph`]: crate::protocol::step::StepGraph
+    #[cfg(feature = "step-graph")]
+    #[must_use]
+    pub fn steps(&self) -> &crate::protocol::step::StepRecorder {
+        &self.steps
+    }
+
+    /// Makes this gateway size send buffers for the traffic in `profile`, see [`TrafficProfile`].
+    /// Steps the profile doesn't know get buffers for [`active_work`] records. Only the first
+    /// call has an effect and only channels opened after it use the profile.
+    ///
+    /// [`active_work`]: GatewayConfig::active_work
+    pub fn expect_traffic(&self, profile: Arc<TrafficProfile>) {
+        let _ = self.profile.set(profile);
+    }
+
     ///
     /// ## Panics
     /// If there is a failure connecting via HTTP
@@ -106,10 +242,16 @@ impl Gateway {
     ) -> send::SendingEnd<M> {
         let (tx, maybe_stream) = self.inner.senders.get_or_create::<M>(
             channel_id,
-            self.config.active_work(),
+            &self.config,
             total_records,
+            &self.progress,
+            &self.memory,
+            &self.traffic,
+            self.profile
+                .get()
+                .and_then(|profile| profile.expected(&channel_id.gate)),
         );
-        if let Some(stream) = maybe_stream {
+        if let Some(stream) = maybe_stream.filter(|_| !self.traffic.is_replay()) {
             tokio::spawn({
                 let channel_id = channel_id.clone();
                 let transport = self.transport.clone();
@@ -123,7 +265,11 @@ impl Gateway {
             });
         }
 
-        send::SendingEnd::new(tx, self.role(), channel_id)
+        let credits = self
+            .inner
+            .flow_control
+            .send_credits(self, channel_id, total_records);
+        send::SendingEnd::new(tx, self.role(), channel_id, credits)
     }
 
     #[must_use]
@@ -133,8 +279,44 @@ impl Gateway {
             self.inner
                 .receivers
                 .get_or_create(channel_id, || self.transport.receive(channel_id)),
+            self.config.record_ttl,
+            Arc::clone(&self.progress),
+            self.inner.flow_control.receive_credits(self, channel_id),
+            self.traffic.clone(),
+        )
+    }
+
+    /// Returns the sending end of a channel that packs [`Boolean`] messages into bitmaps. A
+    /// channel must not be used both packed and unpacked. See [`PackedSendingEnd`].
+    ///
+    /// ## Panics
+    /// If the total number of records is not known.
+    ///
+    /// [`Boolean`]: crate::ff::boolean::Boolean
+    #[must_use]
+    pub fn get_packed_sender(
+        &self,
+        channel_id: &ChannelId,
+        total_records: TotalRecords,
+    ) -> PackedSendingEnd {
+        let (total_records, bitmaps) = packed_total_records(channel_id, total_records);
+        self.inner.packed.sending_end(
+            channel_id,
+            total_records,
+            self.get_sender(channel_id, bitmaps),
         )
     }
+
+    /// Returns the receiving end of a channel that packs [`Boolean`] messages into bitmaps. See
+    /// [`PackedReceivingEnd`].
+    ///
+    /// [`Boolean`]: crate::ff::boolean::Boolean
+    #[must_use]
+    pub fn get_packed_receiver(&self, channel_id: &ChannelId) -> PackedReceivingEnd {
+        self.inner
+            .packed
+            .receiving_end(channel_id, self.get_receiver(channel_id))
+    }
 }
 
 impl Default for GatewayConfig {
@@ -162,6 +344,12 @@ impl GatewayConfig {
             } else {
                 30
             }),
+            max_batch_bytes: NonZeroUsize::new(DEFAULT_MAX_BATCH_BYTES).unwrap(),
+            record_ttl: None,
+            heartbeat: None,
+            flow_control: None,
+            commit_outputs: false,
+            memory_budget: None,
         }
     }
 
@@ -174,13 +362,17 @@ impl GatewayConfig {
 
 #[cfg(all(test, unit_test))]
 mod tests {
-    use std::iter::{repeat, zip};
+    use std::{
+        iter::{repeat, zip},
+        num::NonZeroUsize,
+        time::Duration,
+    };
 
     use futures_util::future::{join, try_join, try_join_all};
 
     use crate::{
         ff::{Field, Fp31, Fp32BitPrime, Gf2},
-        helpers::{Direction, GatewayConfig, Role, SendingEnd},
+        helpers::{Direction, Error, GatewayConfig, QueryProgress, Role, SendingEnd},
         protocol::{context::Context, RecordId},
         test_fixture::{Runner, TestWorld, TestWorldConfig},
     };
@@ -264,6 +456,103 @@ mod tests {
         let _world = unsafe { Box::from_raw(world_ptr) };
     }
 
+    /// Gateway counts records sent on every step against the number of records announced for it.
+    /// Records are accounted for once per batch.
+    #[tokio::test]
+    async fn tracks_progress() {
+        let world = TestWorld::default();
+        let [ctx, _, _] = world.contexts();
+        let ctx = ctx.narrow("progress").set_total_records(4);
+        let channel = ctx.send_channel::<Fp31>(Role::H2);
+        channel.set_batch_size(NonZeroUsize::new(3).unwrap());
+        for i in 0..3_u32 {
+            channel
+                .send(RecordId::from(i), Fp31::truncate_from(i))
+                .await
+                .unwrap();
+        }
+
+        let progress = world.gateway(Role::H1).progress().snapshot();
+        assert_eq!(
+            QueryProgress {
+                completed_records: 3,
+                total_records: 4,
+            },
+            progress
+        );
+        assert_eq!(Some(75.0), progress.percent_complete());
+    }
+
+    /// Records that never arrive must fail the receive once the record TTL expires.
+    #[tokio::test]
+    async fn receive_record_ttl() {
+        let config = TestWorldConfig {
+            gateway_config: GatewayConfig {
+                record_ttl: Some(Duration::from_millis(10)),
+                ..GatewayConfig::default()
+            },
+            ..Default::default()
+        };
+        let world = TestWorld::new_with(config);
+        let [ctx, _, _] = world.contexts();
+        let ctx = ctx.narrow("record-ttl").set_total_records(2);
+
+        let err = ctx
+            .recv_channel::<Fp31>(Role::H2)
+            .receive(RecordId::from(1))
+            .await
+            .unwrap_err();
+
+        assert!(
+            matches!(
+                &err,
+                Error::ReceiveTimeout { channel_id, record_id, .. }
+                    if channel_id.role == Role::H2 && *record_id == RecordId::from(1)
+            ),
+            "unexpected error: {err:?}"
+        );
+    }
+
+    /// Sends a single record out of many and checks that it is delivered without waiting for
+    /// the rest of them.
+    async fn first_record_delivered(world: &TestWorld, set_batch_size: bool) {
+        let [ctx1, ctx2, _] = world.contexts();
+        let ctx1 = ctx1.narrow("batch-size").set_total_records(10);
+        let ctx2 = ctx2.narrow("batch-size").set_total_records(10);
+
+        let send_channel = ctx1.send_channel::<Fp31>(Role::H2);
+        if set_batch_size {
+            send_channel.set_batch_size(NonZeroUsize::new(1).unwrap());
+        }
+        send_channel.send(RecordId::FIRST, Fp31::ONE).await.unwrap();
+
+        let received = tokio::time::timeout(
+            Duration::from_secs(5),
+            ctx2.recv_channel::<Fp31>(Role::H1).receive(RecordId::FIRST),
+        )
+        .await
+        .expect("record should be delivered before the batch is full")
+        .unwrap();
+        assert_eq!(Fp31::ONE, received);
+    }
+
+    #[tokio::test]
+    async fn batch_size_override() {
+        first_record_delivered(&TestWorld::default(), true).await;
+    }
+
+    #[tokio::test]
+    async fn max_batch_bytes() {
+        let config = TestWorldConfig {
+            gateway_config: GatewayConfig {
+                max_batch_bytes: NonZeroUsize::new(1).unwrap(),
+                ..GatewayConfig::default()
+            },
+            ..Default::default()
+        };
+        first_record_delivered(&TestWorld::new_with(config), false).await;
+    }
+
     /// this test requires quite a few threads to simulate send contention and will panic if
     /// there is more than one sender channel created per step.
     #[tokio::test(flavor = "multi_thread", worker_threads = 20)]
@@ -0,0 +1,293 @@
+//! Channels that pack [`Boolean`] messages into bitmaps.
+//!
+//! Every [`Boolean`] share takes a whole byte on the wire, so steps that send one of them per
+//! record use eight times more bandwidth than they need to. Packed channels put messages of
+//! [`BOOLEANS_PER_MESSAGE`] consecutive records into a single bitmap and send it as one message.
+//!
+//! A bitmap can only be sent once messages for all of its records are known, and none of its
+//! records can be received before it arrives. Packed channels are therefore only suitable for
+//! steps where records don't wait on each other, i.e. at least [`BOOLEANS_PER_MESSAGE`] records
+//! are in flight at any time. Otherwise, they deadlock.
+use std::collections::HashMap;
+
+use dashmap::DashMap;
+use futures::{
+    future::{BoxFuture, Shared},
+    FutureExt,
+};
+
+use crate::{
+    ff::{boolean::Boolean, boolean_array::BA8, ArrayAccess},
+    helpers::{
+        gateway::{ReceivingEnd, SendingEnd},
+        ChannelId, Error, TotalRecords,
+    },
+    protocol::RecordId,
+    secret_sharing::SharedValue,
+    sync::{Arc, Mutex},
+};
+
+/// Number of [`Boolean`] messages packed into a single message sent over the network.
+pub const BOOLEANS_PER_MESSAGE: usize = 8;
+
+type ReceivedBitmap = Shared<BoxFuture<'static, Result<BA8, String>>>;
+
+struct PendingBitmap {
+    bits: BA8,
+    sent: u8,
+}
+
+impl Default for PendingBitmap {
+    fn default() -> Self {
+        Self {
+            bits: BA8::ZERO,
+            sent: 0,
+        }
+    }
+}
+
+struct ReceivingBitmap {
+    bits: ReceivedBitmap,
+    remaining: usize,
+}
+
+/// Bitmaps that are not complete yet, indexed by channel and then by bitmap index.
+#[derive(Default)]
+pub(super) struct PackedChannels {
+    senders: DashMap<ChannelId, Arc<Mutex<HashMap<usize, PendingBitmap>>>>,
+    receivers: DashMap<ChannelId, Arc<Mutex<HashMap<usize, ReceivingBitmap>>>>,
+}
+
+/// Sending end of a packed channel.
+pub struct PackedSendingEnd {
+    channel_id: ChannelId,
+    total_records: usize,
+    inner: SendingEnd<BA8>,
+    pending: Arc<Mutex<HashMap<usize, PendingBitmap>>>,
+}
+
+/// Receiving end of a packed channel.
+pub struct PackedReceivingEnd {
+    channel_id: ChannelId,
+    inner: Arc<ReceivingEnd<BA8>>,
+    received: Arc<Mutex<HashMap<usize, ReceivingBitmap>>>,
+}
+
+/// Returns the number of bitmaps required to send `total_records` packed messages.
+pub(super) fn packed_total_records(
+    channel_id: &ChannelId,
+    total_records: TotalRecords,
+) -> (usize, TotalRecords) {
+    let TotalRecords::Specified(count) = total_records else {
+        panic!("packed channel {channel_id:?} requires the total number of records to be known")
+    };
+    let bitmaps = count.get().div_ceil(BOOLEANS_PER_MESSAGE);
+
+    (count.get(), TotalRecords::from(bitmaps))
+}
+
+impl PackedChannels {
+    pub(super) fn sending_end(
+        &self,
+        channel_id: &ChannelId,
+        total_records: usize,
+        inner: SendingEnd<BA8>,
+    ) -> PackedSendingEnd {
+        PackedSendingEnd {
+            channel_id: channel_id.clone(),
+            total_records,
+            inner,
+            pending: Arc::clone(&self.senders.entry(channel_id.clone()).or_default()),
+        }
+    }
+
+    pub(super) fn receiving_end(
+        &self,
+        channel_id: &ChannelId,
+        inner: ReceivingEnd<BA8>,
+    ) -> PackedReceivingEnd {
+        PackedReceivingEnd {
+            channel_id: channel_id.clone(),
+            inner: Arc::new(inner),
+            received: Arc::clone(&self.receivers.entry(channel_id.clone()).or_default()),
+        }
+    }
+}
+
+impl PackedSendingEnd {
+    /// Sends the given message to the recipient once messages for all other records packed
+    /// together with it are known. Returns immediately if some of them are still missing.
+    ///
+    /// ## Errors
+    /// If `record_id` exceeds the channel limit or sending the bitmap fails.
+    ///
+    /// ## Panics
+    /// If the message for `record_id` has already been sent.
+    pub async fn send(&self, record_id: RecordId, msg: Boolean) -> Result<(), Error> {
+        let i = usize::from(record_id);
+        if i >= self.total_records {
+            return Err(Error::TooManyRecords {
+                record_id,
+                channel_id: self.channel_id.clone(),
+                total_records: TotalRecords::from(self.total_records),
+            });
+        }
+
+        let index = i / BOOLEANS_PER_MESSAGE;
+        let offset = i % BOOLEANS_PER_MESSAGE;
+        let len = (self.total_records - index * BOOLEANS_PER_MESSAGE).min(BOOLEANS_PER_MESSAGE);
+        let complete = {
+            let mut pending = self.pending.lock().unwrap();
+            let bitmap = pending.entry(index).or_default();
+            assert_eq!(
+                0,
+                bitmap.sent & (1 << offset),
+                "{record_id:?} has already been sent to {:?}",
+                self.channel_id
+            );
+            bitmap.bits.set(offset, msg);
+            bitmap.sent |= 1 << offset;
+
+            if bitmap.sent.count_ones() as usize == len {
+                pending.remove(&index).map(|bitmap| bitmap.bits)
+            } else {
+                None
+            }
+        };
+
+        if let Some(bits) = complete {
+            self.inner.send(RecordId::from(index), bits).await?;
+        }
+
+        Ok(())
+    }
+}
+
+impl PackedReceivingEnd {
+    /// Receives the message associated with the given record id. This waits for the whole bitmap
+    /// the message was packed into.
+    ///
+    /// ## Errors
+    /// If receiving the bitmap fails.
+    pub async fn receive(&self, record_id: RecordId) -> Result<Boolean, Error> {
+        let i = usize::from(record_id);
+        let index = i / BOOLEANS_PER_MESSAGE;
+        let bits = {
+            let mut received = self.received.lock().unwrap();
+            received
+                .entry(index)
+                .or_insert_with(|| {
+                    let inner = Arc::clone(&self.inner);
+                    ReceivingBitmap {
+                        bits: async move {
+                            inner
+                                .receive(RecordId::from(index))
+                                .await
+                                .map_err(|e| e.to_string())
+                        }
+                        .boxed()
+                        .shared(),
+                        remaining: BOOLEANS_PER_MESSAGE,
+                    }
+                })
+                .bits
+                .clone()
+        };
+
+        let bits = bits.await.map_err(|e| Error::ReceiveError {
+            source: self.channel_id.role,
+            step: self.channel_id.gate.to_string(),
+            inner: e.into(),
+        })?;
+
+        // Bitmaps are dropped once all of their records are received. The last one may be
+        // shorter and is kept until the gateway is dropped.
+        let mut received = self.received.lock().unwrap();
+        if let Some(bitmap) = received.get_mut(&index) {
+            bitmap.remaining -= 1;
+            if bitmap.remaining == 0 {
+                received.remove(&index);
+            }
+        }
+
+        Ok(bits.get(i % BOOLEANS_PER_MESSAGE).unwrap())
+    }
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use futures::future::{join, join_all, try_join_all};
+
+    use super::BOOLEANS_PER_MESSAGE;
+    use crate::{
+        ff::{boolean::Boolean, Field},
+        helpers::{ChannelId, Direction, Error, Role},
+        protocol::{context::Context, RecordId},
+        test_executor::run,
+        test_fixture::TestWorld,
+    };
+
+    #[test]
+    fn packs_and_unpacks() {
+        run(|| async {
+            const TOTAL: usize = 3 * BOOLEANS_PER_MESSAGE + 3;
+            let world = TestWorld::default();
+            let contexts = world.contexts();
+            let values = (0..TOTAL)
+                .map(|i| Boolean::from(i % 3 == 0))
+                .collect::<Vec<_>>();
+
+            let sender = contexts[0].set_total_records(TOTAL);
+            let send = sender.packed_send_channel(Role::H2);
+            let recv = contexts[1].packed_recv_channel(Role::H1);
+
+            // Receive in reverse order to make sure records don't need to arrive in order.
+            let (sent, received) = join(
+                try_join_all(
+                    values
+                        .iter()
+                        .enumerate()
+                        .map(|(i, v)| send.send(RecordId::from(i), *v)),
+                ),
+                join_all((0..TOTAL).rev().map(|i| recv.receive(RecordId::from(i)))),
+            )
+            .await;
+
+            sent.unwrap();
+            let mut received = received.into_iter().collect::<Result<Vec<_>, _>>().unwrap();
+            received.reverse();
+            assert_eq!(values, received);
+
+            let bytes_sent = world
+                .gateway(Role::H1)
+                .progress()
+                .steps()
+                .into_iter()
+                .map(|(_, step)| step.bytes_sent)
+                .sum::<u64>();
+            assert_eq!(
+                u64::try_from(TOTAL.div_ceil(BOOLEANS_PER_MESSAGE)).unwrap(),
+                bytes_sent
+            );
+        });
+    }
+
+    #[test]
+    fn too_many_records() {
+        run(|| async {
+            let world = TestWorld::default();
+            let ctx = world.contexts()[0].set_total_records(1);
+            let send = ctx.packed_send_channel(ctx.role().peer(Direction::Right));
+            let err = send
+                .send(RecordId::from(1), Boolean::ONE)
+                .await
+                .unwrap_err();
+
+            assert!(matches!(
+                err,
+                Error::TooManyRecords { channel_id, .. }
+                    if channel_id == ChannelId::new(Role::H2, ctx.gate().clone())
+            ));
+        });
+    }
+}
@@ -0,0 +1,308 @@
+//! Expected traffic of a query, per step.
+//!
+//! For a given query configuration, the number of records a helper sends on every step is fixed:
+//! it is the number of input rows times the number of bits the step works on, regardless of the
+//! values of the inputs. A traffic profile lists it for every step, one line per step, in the
+//! same spirit as `steps.txt`:
+//!
+//! ```text
+//! /protocol/run-0/mul	3	3
+//! ```
+//!
+//! with the step, the number of records and the number of bytes sent to both peers together.
+//!
+//! A profile is recorded from the [`ProgressTracker`] of a helper once the query is done, and can
+//! be stored next to the configuration it was recorded for. A gateway that is given the profile
+//! of the query it runs sizes send buffers for the records every step actually sends, rather than
+//! for [`active_work`] records, and tests compare the traffic of a run to the profile to catch
+//! protocol changes that send more than they are supposed to.
+//!
+//! [`active_work`]: crate::helpers::GatewayConfig::active_work
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::{Display, Formatter},
+    fs, io,
+    path::Path,
+    str::FromStr,
+};
+
+use crate::{helpers::gateway::progress::ProgressTracker, protocol::step::Gate};
+
+/// Records and bytes a helper sends on a single step.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct StepTraffic {
+    pub records: usize,
+    pub bytes: u64,
+}
+
+/// Traffic of a helper on every step of a query, see the [module] documentation.
+///
+/// [module]: self
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TrafficProfile {
+    steps: BTreeMap<String, StepTraffic>,
+}
+
+/// Step on which a helper did not send what its profile says it should.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TrafficMismatch {
+    pub gate: String,
+    pub expected: StepTraffic,
+    pub actual: StepTraffic,
+}
+
+impl Display for TrafficMismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: expected {} records ({} bytes), sent {} records ({} bytes)",
+            self.gate,
+            self.expected.records,
+            self.expected.bytes,
+            self.actual.records,
+            self.actual.bytes
+        )
+    }
+}
+
+/// Profiles use the same step names as traffic captures, with exactly one leading `/`.
+fn key(gate: &str) -> String {
+    format!("/{}", gate.trim_start_matches('/'))
+}
+
+impl TrafficProfile {
+    /// Records the traffic `progress` has seen so far. Steps this helper only received records on
+    /// are left out.
+    #[must_use]
+    pub fn record(progress: &ProgressTracker) -> Self {
+        progress
+            .steps()
+            .into_iter()
+            .filter(|(_, step)| step.completed_records > 0)
+            .map(|(gate, step)| {
+                (
+                    key(gate.as_ref()),
+                    StepTraffic {
+                        records: step.completed_records,
+                        bytes: step.bytes_sent,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    /// Reads the profile written to `path`.
+    ///
+    /// ## Errors
+    /// If the file can't be read or is not a valid profile.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        fs::read_to_string(path)?.parse()
+    }
+
+    /// Returns the traffic expected on `gate`, `None` if the profile doesn't know the step.
+    #[must_use]
+    pub fn expected(&self, gate: &Gate) -> Option<StepTraffic> {
+        self.steps.get(&key(gate.as_ref())).copied()
+    }
+
+    /// Returns every step on which `actual` differs from this profile, in the order of step
+    /// names. Steps missing from either profile are compared as if they sent nothing.
+    #[must_use]
+    pub fn compare(&self, actual: &TrafficProfile) -> Vec<TrafficMismatch> {
+        self.steps
+            .keys()
+            .chain(actual.steps.keys())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .filter_map(|gate| {
+                let expected = self.steps.get(gate).copied().unwrap_or_default();
+                let actual = actual.steps.get(gate).copied().unwrap_or_default();
+                (expected != actual).then(|| TrafficMismatch {
+                    gate: gate.clone(),
+                    expected,
+                    actual,
+                })
+            })
+            .collect()
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+}
+
+impl<S: AsRef<str>> FromIterator<(S, StepTraffic)> for TrafficProfile {
+    fn from_iter<T: IntoIterator<Item = (S, StepTraffic)>>(iter: T) -> Self {
+        Self {
+            steps: iter
+                .into_iter()
+                .map(|(gate, traffic)| (key(gate.as_ref()), traffic))
+                .collect(),
+        }
+    }
+}
+
+impl Display for TrafficProfile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (gate, traffic) in &self.steps {
+            writeln!(f, "{gate}\t{}\t{}", traffic.records, traffic.bytes)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for TrafficProfile {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.lines()
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let [gate, records, bytes] = line
+                    .split('\t')
+                    .collect::<Vec<_>>()
+                    .try_into()
+                    .map_err(|_| invalid_data(format!("malformed line {line}")))?;
+                let traffic = StepTraffic {
+                    records: records.parse().map_err(|e| invalid_data(format!("{e}")))?,
+                    bytes: bytes.parse().map_err(|e| invalid_data(format!("{e}")))?,
+                };
+                Ok((gate, traffic))
+            })
+            .collect()
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use futures::future::try_join_all;
+
+    use super::{StepTraffic, TrafficMismatch, TrafficProfile};
+    use crate::{
+        ff::{Field, Fp31},
+        helpers::Role,
+        protocol::{basics::SecureMul, context::Context, RecordId},
+        test_fixture::{Runner, TestWorld},
+    };
+
+    const MUL_PROFILE: &str = "/protocol/run-0/mul\t3\t3\n";
+
+    #[test]
+    fn round_trip() {
+        let profile = [
+            (
+                "protocol/a",
+                StepTraffic {
+                    records: 7,
+                    bytes: 28,
+                },
+            ),
+            (
+                "/protocol/b",
+                StepTraffic {
+                    records: 1,
+                    bytes: 32,
+                },
+            ),
+        ]
+        .into_iter()
+        .collect::<TrafficProfile>();
+        let text = profile.to_string();
+        assert_eq!("/protocol/a\t7\t28\n/protocol/b\t1\t32\n", text);
+        assert_eq!(profile, text.parse().unwrap());
+
+        assert!("/protocol/a\t7".parse::<TrafficProfile>().is_err());
+        assert!("/protocol/a\tseven\t28".parse::<TrafficProfile>().is_err());
+    }
+
+    #[test]
+    fn reports_mismatches() {
+        let expected = MUL_PROFILE.parse::<TrafficProfile>().unwrap();
+        let actual = [
+            (
+                "/protocol/run-0/mul",
+                StepTraffic {
+                    records: 4,
+                    bytes: 4,
+                },
+            ),
+            (
+                "/protocol/run-0/extra",
+                StepTraffic {
+                    records: 1,
+                    bytes: 1,
+                },
+            ),
+        ]
+        .into_iter()
+        .collect::<TrafficProfile>();
+
+        assert_eq!(
+            vec![
+                TrafficMismatch {
+                    gate: "/protocol/run-0/extra".to_string(),
+                    expected: StepTraffic::default(),
+                    actual: StepTraffic {
+                        records: 1,
+                        bytes: 1
+                    },
+                },
+                TrafficMismatch {
+                    gate: "/protocol/run-0/mul".to_string(),
+                    expected: StepTraffic {
+                        records: 3,
+                        bytes: 3
+                    },
+                    actual: StepTraffic {
+                        records: 4,
+                        bytes: 4
+                    },
+                },
+            ],
+            expected.compare(&actual)
+        );
+        assert!(expected.compare(&expected).is_empty());
+    }
+
+    /// Every multiplication sends one share to a single peer, so helpers send as many records as
+    /// there are multiplications, whatever the inputs are. Gateways that know the profile size
+    /// their buffers for it and still get all records through.
+    #[tokio::test]
+    async fn multiply_matches_profile() {
+        let world = TestWorld::default();
+        let expected = MUL_PROFILE.parse::<TrafficProfile>().unwrap();
+        for role in Role::all() {
+            world.gateway(*role).expect_traffic(expected.clone().into());
+        }
+
+        let input = (0..6_u32).map(Fp31::truncate_from).collect::<Vec<_>>();
+        world
+            .semi_honest(input.into_iter(), |ctx, input| async move {
+                let ctx = ctx.narrow("mul").set_total_records(3);
+                try_join_all(
+                    input.chunks(2).enumerate().map(|(i, pair)| {
+                        pair[0].multiply(&pair[1], ctx.clone(), RecordId::from(i))
+                    }),
+                )
+                .await
+                .unwrap()
+            })
+            .await;
+
+        for role in Role::all() {
+            let actual = TrafficProfile::record(&world.gateway(*role).progress());
+            let mismatches = expected.compare(&actual);
+            assert!(mismatches.is_empty(), "{role:?}: {mismatches:?}");
+        }
+    }
+}
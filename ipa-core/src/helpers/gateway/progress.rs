@@ -0,0 +1,309 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    helpers::{
+        gateway::counters::{stage_name, HelperCounters},
+        ChannelId, TotalRecords,
+    },
+    protocol::step::Gate,
+    sync::Arc,
+};
+
+/// Snapshot of how far a query has progressed, measured in records sent by this helper.
+///
+/// Steps are only accounted for once the protocol reaches them, so `total_records` keeps growing
+/// while the query is running and the percentage is a lower bound rather than a precise estimate.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct QueryProgress {
+    /// Number of records sent so far.
+    pub completed_records: u64,
+    /// Number of records that steps started so far are going to send.
+    pub total_records: u64,
+}
+
+impl QueryProgress {
+    /// Returns the share of records sent so far as a percentage, or `None` if no step with a
+    /// known number of records has started yet.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn percent_complete(&self) -> Option<f64> {
+        (self.total_records > 0)
+            .then(|| 100.0 * self.completed_records as f64 / self.total_records as f64)
+    }
+}
+
+/// Wall-clock time and traffic of a single protocol stage on this helper.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct StageMetrics {
+    /// Name of the top-level step the stage runs under. For OPRF IPA, `convert_input_rows_to_prf`
+    /// is the PRF evaluation, `binary_validator` is attribution and capping and
+    /// `prime_field_validator` is aggregation.
+    pub name: String,
+    /// Time between the first and the last record this helper sent or received in this stage.
+    pub elapsed: Duration,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+/// Per-stage measurements of a query, ordered by the time stages started.
+///
+/// Stages run concurrently with each other, so elapsed times may add up to more than the time
+/// the whole query took.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct QueryMetrics {
+    pub stages: Vec<StageMetrics>,
+}
+
+/// Progress of a single step.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct StepProgress {
+    pub completed_records: usize,
+    pub total_records: usize,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Time the first record was sent or received on this step.
+    pub first_record_at: Option<Instant>,
+    /// Time the last record was sent or received on this step.
+    pub last_record_at: Option<Instant>,
+    /// Number of values generated by PRSS on this step. Only counted in debug builds.
+    pub prss_generated: u64,
+}
+
+impl StepProgress {
+    fn record_seen(&mut self) {
+        let now = Instant::now();
+        self.first_record_at.get_or_insert(now);
+        self.last_record_at = Some(now);
+    }
+}
+
+/// Counts records and bytes sent and received by this helper on every step. Gateway feeds it with
+/// the number of records announced via `set_total_records` when a channel is opened and with
+/// every record sent or received over it.
+///
+/// If the tracker reports to [`HelperCounters`], it adds everything it counts there as well and
+/// the query is considered active until the tracker is dropped.
+#[derive(Debug, Default)]
+pub struct ProgressTracker {
+    steps: DashMap<Gate, StepProgress>,
+    helper: OnceCell<Arc<HelperCounters>>,
+}
+
+impl ProgressTracker {
+    /// Makes this tracker report to helper-wide `counters`. Only the first call has an effect, so
+    /// the query is counted as active once.
+    pub fn report_to(&self, counters: Arc<HelperCounters>) {
+        if self.helper.set(Arc::clone(&counters)).is_ok() {
+            counters.query_started();
+        }
+    }
+
+    pub(super) fn channel_opened(&self, channel_id: &ChannelId, total_records: TotalRecords) {
+        if let TotalRecords::Specified(count) = total_records {
+            self.steps
+                .entry(channel_id.gate.clone())
+                .or_default()
+                .total_records += count.get();
+        }
+    }
+
+    /// Accounts for `records` sent over the channel, `bytes` in total. Senders count records on
+    /// their own and publish them here in batches, so sending a record does not need to look up
+    /// its step.
+    pub(super) fn records_sent(&self, channel_id: &ChannelId, records: usize, bytes: u64) {
+        let mut step = self.steps.entry(channel_id.gate.clone()).or_default();
+        step.completed_records += records;
+        step.bytes_sent += bytes;
+        step.record_seen();
+        if let Some(helper) = self.helper.get() {
+            helper.update(&channel_id.gate, |stage| {
+                stage.records_sent += records as u64;
+                stage.bytes_sent += bytes;
+            });
+        }
+    }
+
+    pub(super) fn record_received(&self, channel_id: &ChannelId, bytes: u64) {
+        let mut step = self.steps.entry(channel_id.gate.clone()).or_default();
+        step.bytes_received += bytes;
+        step.record_seen();
+        if let Some(helper) = self.helper.get() {
+            helper.update(&channel_id.gate, |stage| stage.bytes_received += bytes);
+        }
+    }
+
+    pub(crate) fn prss_generated(&self, gate: &Gate, count: u64) {
+        #[cfg(debug_assertions)]
+        {
+            self.steps.entry(gate.clone()).or_default().prss_generated += count;
+        }
+        if let Some(helper) = self.helper.get() {
+            helper.update(gate, |stage| stage.prss_generated += count);
+        }
+    }
+
+    /// Returns the progress of a single step, all zero if nothing happened on it yet.
+    #[must_use]
+    pub fn step(&self, gate: &Gate) -> StepProgress {
+        self.steps
+            .get(gate)
+            .map(|entry| *entry.value())
+            .unwrap_or_default()
+    }
+
+    /// Returns the progress of every step this helper has sent data on.
+    #[must_use]
+    pub fn steps(&self) -> Vec<(Gate, StepProgress)> {
+        self.steps
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect()
+    }
+
+    /// Returns the progress of the query across all steps.
+    #[must_use]
+    pub fn snapshot(&self) -> QueryProgress {
+        self.steps
+            .iter()
+            .fold(QueryProgress::default(), |mut acc, entry| {
+                acc.completed_records += entry.completed_records as u64;
+                acc.total_records += entry.total_records as u64;
+                acc
+            })
+    }
+
+    /// Returns the time spent and bytes exchanged by this helper in every stage of the query.
+    /// Stages are identified by the top-level step, steps that did not exchange any records are
+    /// not accounted for.
+    #[must_use]
+    pub fn metrics(&self) -> QueryMetrics {
+        let mut stages = HashMap::<String, (Instant, Instant, StageMetrics)>::new();
+        for entry in &self.steps {
+            let (Some(first), Some(last)) = (entry.first_record_at, entry.last_record_at) else {
+                continue;
+            };
+            let name = stage_name(entry.key());
+            let (start, end, stage) = stages.entry(name.clone()).or_insert_with(|| {
+                (
+                    first,
+                    last,
+                    StageMetrics {
+                        name,
+                        ..StageMetrics::default()
+                    },
+                )
+            });
+            *start = (*start).min(first);
+            *end = (*end).max(last);
+            stage.bytes_sent += entry.bytes_sent;
+            stage.bytes_received += entry.bytes_received;
+        }
+
+        let mut stages = stages.into_values().collect::<Vec<_>>();
+        stages.sort_by_key(|(start, _, _)| *start);
+        QueryMetrics {
+            stages: stages
+                .into_iter()
+                .map(|(start, end, stage)| StageMetrics {
+                    elapsed: end - start,
+                    ..stage
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Drop for ProgressTracker {
+    fn drop(&mut self) {
+        if let Some(helper) = self.helper.get() {
+            helper.query_finished();
+        }
+    }
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use std::time::Duration;
+
+    use super::{ProgressTracker, QueryProgress};
+    use crate::{
+        helpers::{ChannelId, HelperCounters, Role, StageCounters},
+        protocol::step::{Gate, StepNarrow},
+        sync::Arc,
+    };
+
+    #[test]
+    fn percent_complete() {
+        assert_eq!(None, QueryProgress::default().percent_complete());
+        assert_eq!(
+            Some(25.0),
+            QueryProgress {
+                completed_records: 10,
+                total_records: 40,
+            }
+            .percent_complete()
+        );
+    }
+
+    #[test]
+    fn metrics_per_stage() {
+        let channel = |path: &[&str]| {
+            let gate = path
+                .iter()
+                .fold(Gate::default(), |gate, step| gate.narrow(*step));
+            ChannelId::new(Role::H2, gate)
+        };
+        let tracker = ProgressTracker::default();
+        tracker.records_sent(&channel(&["prf", "eval"]), 1, 32);
+        tracker.record_received(&channel(&["prf", "reveal"]), 32);
+        // stages are ordered by the time they started
+        std::thread::sleep(Duration::from_millis(1));
+        tracker.records_sent(&channel(&["attribution", "a"]), 1, 1);
+        tracker.records_sent(&channel(&["attribution", "b", "c"]), 1, 1);
+
+        let stages = tracker.metrics().stages;
+        assert_eq!(
+            vec![("prf", 32, 32), ("attribution", 2, 0)],
+            stages
+                .iter()
+                .map(|s| (s.name.as_str(), s.bytes_sent, s.bytes_received))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn reports_to_helper() {
+        let counters = Arc::new(HelperCounters::default());
+        let channel = ChannelId::new(Role::H2, Gate::default().narrow("prf").narrow("eval"));
+        let queries = [ProgressTracker::default(), ProgressTracker::default()];
+        for tracker in &queries {
+            tracker.report_to(Arc::clone(&counters));
+            tracker.records_sent(&channel, 1, 32);
+            tracker.record_received(&channel, 16);
+            tracker.prss_generated(&channel.gate, 1);
+        }
+        queries[0].report_to(Arc::clone(&counters));
+
+        let metrics = counters.snapshot();
+        assert_eq!(2, metrics.active_queries);
+        assert_eq!(
+            Some(&StageCounters {
+                bytes_sent: 64,
+                bytes_received: 32,
+                records_sent: 2,
+                prss_generated: 2,
+            }),
+            metrics.stages.get("prf")
+        );
+
+        drop(queries);
+        assert_eq!(0, counters.snapshot().active_queries);
+    }
+}
@@ -1,17 +1,33 @@
-use std::marker::PhantomData;
+use std::{marker::PhantomData, time::Duration};
 
 use dashmap::{mapref::entry::Entry, DashMap};
 use futures::Stream;
+use typenum::Unsigned;
 
 use crate::{
-    helpers::{buffers::UnorderedReceiver, ChannelId, Error, Message, Transport, TransportImpl},
+    helpers::{
+        buffers::UnorderedReceiver,
+        gateway::{
+            capture::{Traffic, TrafficDirection},
+            checksum::{ChecksumError, ChecksumValidator},
+            flow_control::ReceiveCredits,
+            progress::ProgressTracker,
+        },
+        ChannelId, Error, Message, Transport, TransportImpl,
+    },
     protocol::RecordId,
+    sync::Arc,
+    telemetry::{labels::STEP, metrics::RECORDS_RECV_EXPIRED},
 };
 
 /// Receiving end end of the gateway channel.
 pub struct ReceivingEnd<M: Message> {
     channel_id: ChannelId,
     unordered_rx: UR,
+    ttl: Option<Duration>,
+    progress: Arc<ProgressTracker>,
+    credits: Option<ReceiveCredits>,
+    traffic: Traffic,
     _phantom: PhantomData<M>,
 }
 
@@ -22,38 +38,85 @@ pub(super) struct GatewayReceivers {
 }
 
 pub(super) type UR = UnorderedReceiver<
-    <TransportImpl as Transport>::RecordsStream,
+    ChecksumValidator<<TransportImpl as Transport>::RecordsStream>,
     <<TransportImpl as Transport>::RecordsStream as Stream>::Item,
+    ChecksumError,
 >;
 
 impl<M: Message> ReceivingEnd<M> {
-    pub(super) fn new(channel_id: ChannelId, rx: UR) -> Self {
+    pub(super) fn new(
+        channel_id: ChannelId,
+        rx: UR,
+        ttl: Option<Duration>,
+        progress: Arc<ProgressTracker>,
+        credits: Option<ReceiveCredits>,
+        traffic: Traffic,
+    ) -> Self {
         Self {
             channel_id,
             unordered_rx: rx,
+            ttl,
+            progress,
+            credits,
+            traffic,
             _phantom: PhantomData,
         }
     }
 
     /// Receive message associated with the given record id. This method does not return until
-    /// message is actually received and deserialized.
+    /// message is actually received and deserialized, or the record TTL configured for the gateway
+    /// expires. If flow control is enabled, the sender is granted more credits once enough
+    /// messages are received.
     ///
     /// ## Errors
-    /// Returns an error if receiving fails
+    /// Returns an error if receiving fails, if the record did not arrive in time or if credits
+    /// can't be sent.
     ///
     /// ## Panics
     /// This will panic if message size does not fit into 8 bytes and it somehow got serialized
     /// and sent to this helper.
     #[tracing::instrument(level = "trace", "receive", skip_all, fields(i = %record_id, from = ?self.channel_id.role, gate = ?self.channel_id.gate.as_ref()))]
     pub async fn receive(&self, record_id: RecordId) -> Result<M, Error> {
-        self.unordered_rx
-            .recv::<M, _>(record_id)
-            .await
-            .map_err(|e| Error::ReceiveError {
-                source: self.channel_id.role,
-                step: self.channel_id.gate.to_string(),
-                inner: Box::new(e),
-            })
+        let m = match &self.traffic {
+            Traffic::Replay(captured) => captured.received(&self.channel_id, record_id)?,
+            Traffic::Live => self.receive_from_peer(record_id).await?,
+            Traffic::Capture(capture) => {
+                let m = self.receive_from_peer(record_id).await?;
+                capture.record(TrafficDirection::Received, &self.channel_id, record_id, &m);
+                m
+            }
+        };
+        self.progress
+            .record_received(&self.channel_id, M::Size::U64);
+        if let Some(credits) = &self.credits {
+            credits.record_received().await?;
+        }
+
+        Ok(m)
+    }
+
+    async fn receive_from_peer(&self, record_id: RecordId) -> Result<M, Error> {
+        let rx = self.unordered_rx.recv::<M, _>(record_id);
+        let r = if let Some(ttl) = self.ttl {
+            tokio::time::timeout(ttl, rx).await.map_err(|_| {
+                metrics::increment_counter!(RECORDS_RECV_EXPIRED,
+                    STEP => self.channel_id.gate.as_ref().to_string()
+                );
+                Error::ReceiveTimeout {
+                    channel_id: self.channel_id.clone(),
+                    record_id,
+                    ttl,
+                }
+            })?
+        } else {
+            rx.await
+        };
+
+        r.map_err(|e| Error::ReceiveError {
+            source: self.channel_id.role,
+            step: self.channel_id.gate.to_string(),
+            inner: Box::new(e),
+        })
     }
 }
 
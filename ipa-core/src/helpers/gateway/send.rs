@@ -10,9 +10,22 @@ use futures::Stream;
 use typenum::Unsigned;
 
 use crate::{
-    helpers::{buffers::OrderingSender, ChannelId, Error, Message, Role, TotalRecords},
+    helpers::{
+        buffers::OrderingSender,
+        gateway::{
+            capture::{Traffic, TrafficDirection},
+            flow_control::{grant_size, SendCredits},
+            memory::MemoryBudget,
+            profile::StepTraffic,
+            progress::ProgressTracker,
+        },
+        ChannelId, Error, GatewayConfig, Message, Role, TotalRecords,
+    },
     protocol::RecordId,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicUsize, Ordering::Relaxed},
+        Arc,
+    },
     telemetry::{
         labels::{ROLE, STEP},
         metrics::{BYTES_SENT, RECORDS_SENT},
@@ -24,6 +37,7 @@ pub struct SendingEnd<M: Message> {
     sender_role: Role,
     channel_id: ChannelId,
     inner: Arc<GatewaySender>,
+    credits: Option<SendCredits>,
     _phantom: PhantomData<M>,
 }
 
@@ -37,6 +51,83 @@ pub(super) struct GatewaySender {
     channel_id: ChannelId,
     ordering_tx: OrderingSender,
     total_records: TotalRecords,
+    progress: Arc<ProgressTracker>,
+    sent_records: AtomicUsize,
+    /// Records sent that are not yet published to `progress`, and their size in bytes.
+    unpublished_records: AtomicUsize,
+    unpublished_bytes: AtomicUsize,
+    memory: Arc<MemoryBudget>,
+    batch: BatchSize,
+    traffic: Traffic,
+}
+
+/// Picks the number of bytes a channel buffers before they become available to the network.
+///
+/// Batches hold at most [`active_work`] records, because there are never more of them in flight,
+/// and at most [`max_batch_bytes`], so that channels with large records start sending before
+/// the whole batch is ready. Record size is traced as records are sent, the batch size follows
+/// the average. With [`flow_control`] enabled, batches are also limited to the number of records
+/// the receiver consumes before it grants more credits.
+///
+/// [`active_work`]: GatewayConfig::active_work
+/// [`max_batch_bytes`]: GatewayConfig::max_batch_bytes
+/// [`flow_control`]: GatewayConfig::flow_control
+struct BatchSize {
+    active: NonZeroUsize,
+    max_bytes: NonZeroUsize,
+    /// Number of records per batch requested by the protocol, 0 if it did not request any.
+    records_override: AtomicUsize,
+    records: AtomicUsize,
+    bytes: AtomicUsize,
+    /// Average record size the current batch size was picked for.
+    record_size: AtomicUsize,
+}
+
+impl BatchSize {
+    fn new(config: &GatewayConfig, record_size: usize) -> Self {
+        Self {
+            active: config.flow_control.map_or(config.active_work(), |window| {
+                config.active_work().min(grant_size(window))
+            }),
+            max_bytes: config.max_batch_bytes,
+            records_override: AtomicUsize::new(0),
+            records: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+            record_size: AtomicUsize::new(record_size),
+        }
+    }
+
+    /// Returns the batch size in bytes for records of the given size.
+    fn write_size(&self, record_size: usize) -> NonZeroUsize {
+        let record_size = record_size.max(1);
+        let records = match self.records_override.load(Relaxed) {
+            0 => (self.max_bytes.get() / record_size).clamp(1, self.active.get()),
+            records => records.min(self.active.get()),
+        };
+
+        NonZeroUsize::new(records * record_size).unwrap()
+    }
+
+    /// Accounts for a record of `size` bytes. Returns the new batch size if it needs to change.
+    fn record_sent(&self, size: usize) -> Option<NonZeroUsize> {
+        let records = self.records.fetch_add(1, Relaxed) + 1;
+        let bytes = self.bytes.fetch_add(size, Relaxed) + size;
+        let average = bytes / records;
+
+        (self.record_size.swap(average, Relaxed) != average).then(|| self.write_size(average))
+    }
+
+    /// Returns the number of records per batch.
+    fn records(&self) -> usize {
+        let record_size = self.record_size.load(Relaxed).max(1);
+        self.write_size(record_size).get() / record_size
+    }
+
+    /// Overrides the number of records per batch and returns the new batch size.
+    fn set_records(&self, records: NonZeroUsize) -> NonZeroUsize {
+        self.records_override.store(records.get(), Relaxed);
+        self.write_size(self.record_size.load(Relaxed))
+    }
 }
 
 pub(super) struct GatewaySendStream {
@@ -44,11 +135,50 @@ pub(super) struct GatewaySendStream {
 }
 
 impl GatewaySender {
-    fn new(channel_id: ChannelId, tx: OrderingSender, total_records: TotalRecords) -> Self {
+    fn new(
+        channel_id: ChannelId,
+        tx: OrderingSender,
+        total_records: TotalRecords,
+        progress: Arc<ProgressTracker>,
+        memory: Arc<MemoryBudget>,
+        batch: BatchSize,
+        traffic: Traffic,
+    ) -> Self {
+        progress.channel_opened(&channel_id, total_records);
+        tx.set_write_size(batch.write_size(batch.record_size.load(Relaxed)));
         Self {
             channel_id,
             ordering_tx: tx,
             total_records,
+            progress,
+            sent_records: AtomicUsize::new(0),
+            unpublished_records: AtomicUsize::new(0),
+            unpublished_bytes: AtomicUsize::new(0),
+            memory,
+            batch,
+            traffic,
+        }
+    }
+
+    /// Counts a record of `size` bytes as sent. Progress is published once per batch, and once
+    /// all records of the channel are sent.
+    fn record_sent(&self, size: usize) {
+        let sent = self.sent_records.fetch_add(1, Relaxed) + 1;
+        let records = self.unpublished_records.fetch_add(1, Relaxed) + 1;
+        self.unpublished_bytes.fetch_add(size, Relaxed);
+        let all_sent =
+            matches!(self.total_records, TotalRecords::Specified(count) if sent == count.get());
+        if records >= self.batch.records() || all_sent {
+            self.publish_progress();
+        }
+    }
+
+    fn publish_progress(&self) {
+        let records = self.unpublished_records.swap(0, Relaxed);
+        let bytes = self.unpublished_bytes.swap(0, Relaxed);
+        if records > 0 {
+            self.progress
+                .records_sent(&self.channel_id, records, bytes as u64);
         }
     }
 
@@ -67,17 +197,42 @@ impl GatewaySender {
             }
         }
 
+        match &self.traffic {
+            Traffic::Live => {}
+            Traffic::Capture(capture) => {
+                capture.record(TrafficDirection::Sent, &self.channel_id, record_id, &msg);
+            }
+            Traffic::Replay(captured) => {
+                // nothing is sent to peers when replaying, the stream is never polled
+                captured.check_sent(&self.channel_id, record_id, &msg)?;
+                self.record_sent(M::Size::USIZE);
+                return Ok(());
+            }
+        }
+
         // TODO: make OrderingSender::send fallible
         // TODO: test channel close
+        // Records stay in the buffer until the stream takes them, which releases their memory.
+        self.memory.wait_for_capacity().await;
+        self.memory.add(M::Size::USIZE);
         let i = usize::from(record_id);
         self.ordering_tx.send(i, msg).await;
         if self.total_records.is_last(record_id) {
             self.ordering_tx.close(i + 1).await;
         }
+        self.record_sent(M::Size::USIZE);
+        if let Some(write_size) = self.batch.record_sent(M::Size::USIZE) {
+            self.ordering_tx.set_write_size(write_size);
+        }
 
         Ok(())
     }
 
+    fn set_batch_records(&self, records: NonZeroUsize) {
+        self.ordering_tx
+            .set_write_size(self.batch.set_records(records));
+    }
+
     #[cfg(feature = "stall-detection")]
     pub fn waiting(&self) -> Vec<usize> {
         self.ordering_tx.waiting()
@@ -89,19 +244,33 @@ impl GatewaySender {
     }
 }
 
+impl Drop for GatewaySender {
+    fn drop(&mut self) {
+        // channels that did not send all of their records
+        self.publish_progress();
+    }
+}
+
 impl<M: Message> SendingEnd<M> {
-    pub(super) fn new(sender: Arc<GatewaySender>, role: Role, channel_id: &ChannelId) -> Self {
+    pub(super) fn new(
+        sender: Arc<GatewaySender>,
+        role: Role,
+        channel_id: &ChannelId,
+        credits: Option<SendCredits>,
+    ) -> Self {
         Self {
             sender_role: role,
             channel_id: channel_id.clone(),
             inner: sender,
+            credits,
             _phantom: PhantomData,
         }
     }
 
     /// Sends the given message to the recipient. This method will block if there is no enough
     /// capacity to hold the message and will return only after message has been confirmed
-    /// for sending.
+    /// for sending. If flow control is enabled, it also waits until the recipient grants credits
+    /// for `record_id`.
     ///
     /// ## Errors
     /// If send operation fails or `record_id` exceeds the channel limit set by [`set_total_records`]
@@ -110,6 +279,9 @@ impl<M: Message> SendingEnd<M> {
     /// [`set_total_records`]: crate::protocol::context::Context::set_total_records
     #[tracing::instrument(level = "trace", "send", skip_all, fields(i = %record_id, total = %self.inner.total_records, to = ?self.channel_id.role, gate = ?self.channel_id.gate.as_ref()))]
     pub async fn send(&self, record_id: RecordId, msg: M) -> Result<(), Error> {
+        if let Some(credits) = &self.credits {
+            credits.acquire(&self.channel_id, record_id).await?;
+        }
         let r = self.inner.send(record_id, msg).await;
         metrics::increment_counter!(RECORDS_SENT,
             STEP => self.channel_id.gate.as_ref().to_string(),
@@ -122,17 +294,31 @@ impl<M: Message> SendingEnd<M> {
 
         r
     }
+
+    /// Overrides the number of records this channel buffers before sending them, which is
+    /// otherwise picked from the size of records sent over it. It can't exceed
+    /// [`active_work`], larger values are capped at it.
+    ///
+    /// [`active_work`]: GatewayConfig::active_work
+    pub fn set_batch_size(&self, records: NonZeroUsize) {
+        self.inner.set_batch_records(records);
+    }
 }
 
 impl GatewaySenders {
     /// Returns or creates a new communication channel. In case if channel is newly created,
     /// returns the receiving end of it as well. It must be send over to the receiver in order for
-    /// messages to get through.
+    /// messages to get through. If the traffic `expected` on the step is known, the buffer of a new
+    /// channel holds no more records than the step sends.
     pub(crate) fn get_or_create<M: Message>(
         &self,
         channel_id: &ChannelId,
-        capacity: NonZeroUsize,
+        config: &GatewayConfig,
         total_records: TotalRecords, // TODO track children for indeterminate senders
+        progress: &Arc<ProgressTracker>,
+        memory: &Arc<MemoryBudget>,
+        traffic: &Traffic,
+        expected: Option<StepTraffic>,
     ) -> (Arc<GatewaySender>, Option<GatewaySendStream>) {
         assert!(
             total_records.is_specified(),
@@ -152,7 +338,11 @@ impl GatewaySenders {
                 } else {
                     // capacity is defined in terms of number of elements, while sender wants bytes
                     // so perform the conversion here
-                    capacity
+                    expected
+                        .and_then(|expected| NonZeroUsize::new(expected.records))
+                        .map_or(config.active_work(), |records| {
+                            records.min(config.active_work())
+                        })
                         .checked_mul(
                             NonZeroUsize::new(M::Size::USIZE)
                                 .expect("Message size should be greater than 0"),
@@ -164,6 +354,10 @@ impl GatewaySenders {
                     channel_id.clone(),
                     OrderingSender::new(write_size, SPARE.unwrap()),
                     total_records,
+                    Arc::clone(progress),
+                    Arc::clone(memory),
+                    BatchSize::new(config, M::Size::USIZE),
+                    traffic.clone(),
                 ));
                 entry.insert(Arc::clone(&sender));
 
@@ -180,6 +374,11 @@ impl Stream for GatewaySendStream {
     type Item = Vec<u8>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        Pin::get_mut(self).inner.ordering_tx.take_next(cx)
+        let inner = &Pin::get_mut(self).inner;
+        let next = inner.ordering_tx.take_next(cx);
+        if let Poll::Ready(Some(bytes)) = &next {
+            inner.memory.release(bytes.len());
+        }
+        next
     }
 }
@@ -73,7 +73,10 @@ mod gateway {
     use super::{receive, send, AtomicUsize, Debug, Formatter, ObserveState, Observed, Weak};
     use crate::{
         helpers::{
-            gateway::{Gateway, State},
+            gateway::{
+                Gateway, MemoryBudget, PackedReceivingEnd, PackedSendingEnd, ProgressTracker,
+                State, Traffic, TrafficProfile,
+            },
             ChannelId, GatewayConfig, Message, ReceivingEnd, Role, RoleAssignment, SendingEnd,
             TotalRecords, TransportImpl,
         },
@@ -97,21 +100,47 @@ mod gateway {
 
                 #[inline]
                 pub fn config(&self) -> &GatewayConfig;
+
+                #[inline]
+                pub fn progress(&self) -> Arc<ProgressTracker>;
+
+                #[inline]
+                pub fn progress_tracker(&self) -> &ProgressTracker;
+
+                #[inline]
+                pub fn memory(&self) -> &Arc<MemoryBudget>;
+
+                #[inline]
+                pub fn expect_traffic(&self, profile: Arc<TrafficProfile>);
+
+                #[cfg(feature = "step-graph")]
+                #[inline]
+                pub fn steps(&self) -> &crate::protocol::step::StepRecorder;
             }
         }
 
-        #[allow(clippy::let_and_return)]
         pub fn new(
             query_id: QueryId,
             config: GatewayConfig,
             roles: RoleAssignment,
             transport: TransportImpl,
+        ) -> Self {
+            Self::with_traffic(query_id, config, roles, transport, Traffic::Live)
+        }
+
+        #[allow(clippy::let_and_return)]
+        pub fn with_traffic(
+            query_id: QueryId,
+            config: GatewayConfig,
+            roles: RoleAssignment,
+            transport: TransportImpl,
+            traffic: Traffic,
         ) -> Self {
             let version = Arc::new(AtomicUsize::default());
             let r = Self::wrap(
                 Arc::downgrade(&version),
                 InstrumentedGateway {
-                    gateway: Gateway::new(query_id, config, roles, transport),
+                    gateway: Gateway::with_traffic(query_id, config, roles, transport, traffic),
                     _sn: version,
                 },
             );
@@ -166,6 +195,22 @@ mod gateway {
             )
         }
 
+        #[must_use]
+        pub fn get_packed_sender(
+            &self,
+            channel_id: &ChannelId,
+            total_records: TotalRecords,
+        ) -> PackedSendingEnd {
+            self.inner()
+                .gateway
+                .get_packed_sender(channel_id, total_records)
+        }
+
+        #[must_use]
+        pub fn get_packed_receiver(&self, channel_id: &ChannelId) -> PackedReceivingEnd {
+            self.inner().gateway.get_packed_receiver(channel_id)
+        }
+
         pub fn to_observed(&self) -> Observed<Weak<State>> {
             // todo: inner.inner
             Observed::wrap(
@@ -272,6 +317,7 @@ mod send {
     use std::{
         collections::BTreeMap,
         fmt::{Debug, Formatter},
+        num::NonZeroUsize,
     };
 
     use super::{ObserveState, Observed};
@@ -291,6 +337,13 @@ mod send {
                 pub async fn send(&self, record_id: RecordId, msg: M) -> Result<(), Error>;
             }
         }
+
+        delegate::delegate! {
+            to self.inner() {
+                #[inline]
+                pub fn set_batch_size(&self, records: NonZeroUsize);
+            }
+        }
     }
 
     pub struct WaitingTasks(BTreeMap<ChannelId, (TotalRecords, Vec<String>)>);
@@ -1,7 +1,9 @@
+use futures::StreamExt;
+
 use crate::{
     helpers::{
         buffers::UnorderedReceiver,
-        gateway::{receive::UR, send::GatewaySendStream},
+        gateway::{checksum, receive::UR, send::GatewaySendStream},
         ChannelId, GatewayConfig, Role, RoleAssignment, RouteId, Transport, TransportImpl,
     },
     protocol::QueryId,
@@ -36,7 +38,7 @@ impl RoleResolvingTransport {
             .send(
                 dest_identity,
                 (RouteId::Records, self.query_id, channel_id.gate.clone()),
-                data,
+                data.map(checksum::frame),
             )
             .await
     }
@@ -50,10 +52,11 @@ impl RoleResolvingTransport {
         );
 
         UnorderedReceiver::new(
-            Box::pin(
+            Box::pin(checksum::ChecksumValidator::new(
+                channel_id.clone(),
                 self.inner
                     .receive(peer, (self.query_id, channel_id.gate.clone())),
-            ),
+            )),
             self.config.active_work(),
         )
     }
@@ -0,0 +1,262 @@
+//! Liveness checks between helpers.
+//!
+//! Helpers may spend a long time computing locally without exchanging any records, so a peer that
+//! crashed looks exactly like a peer that is busy. To tell them apart, helpers exchange heartbeats
+//! with both peers over a dedicated channel for as long as the query runs. A peer that misses too
+//! many heartbeats in a row is considered down and the query fails with an error that names it.
+use std::{future::Future, num::NonZeroU32, pin::pin, time::Duration};
+
+use futures::future::{join, select, try_join, Either};
+use generic_array::GenericArray;
+use tokio::{
+    sync::Notify,
+    time::{sleep, timeout},
+};
+use typenum::U1;
+
+use crate::{
+    ff::{Error as FieldError, Serializable},
+    helpers::{
+        ChannelId, Direction, Error, Gateway, Message, ReceivingEnd, Role, SendingEnd, TotalRecords,
+    },
+    protocol::{
+        step::{Gate, Step, StepNarrow},
+        RecordId,
+    },
+};
+
+pub struct HeartbeatStep;
+
+impl AsRef<str> for HeartbeatStep {
+    fn as_ref(&self) -> &str {
+        "heartbeat"
+    }
+}
+
+impl Step for HeartbeatStep {}
+
+/// Controls how often helpers exchange heartbeats while a query is running.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HeartbeatConfig {
+    /// Time between two heartbeats sent to each peer.
+    pub interval: Duration,
+    /// Number of heartbeats in a row a peer may miss before it is considered down.
+    pub max_missed: NonZeroU32,
+}
+
+impl HeartbeatConfig {
+    /// Returns how long a peer may stay silent before it is considered down.
+    #[must_use]
+    pub fn timeout(&self) -> Duration {
+        self.interval * self.max_missed.get()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Heartbeat {
+    /// Sender is still running the query.
+    Alive,
+    /// Sender has finished the query and is not going to send any more heartbeats.
+    Done,
+}
+
+impl Serializable for Heartbeat {
+    type Size = U1;
+
+    fn serialize(&self, buf: &mut GenericArray<u8, Self::Size>) {
+        buf[0] = match self {
+            Self::Alive => 0,
+            Self::Done => 1,
+        };
+    }
+
+    fn deserialize(buf: &GenericArray<u8, Self::Size>) -> Self {
+        Self::try_deserialize(buf).unwrap()
+    }
+
+    fn try_deserialize(buf: &GenericArray<u8, Self::Size>) -> Result<Self, FieldError> {
+        match buf[0] {
+            0 => Ok(Self::Alive),
+            1 => Ok(Self::Done),
+            _ => Err(FieldError::invalid_encoding::<Self>(buf)),
+        }
+    }
+}
+
+impl Message for Heartbeat {}
+
+/// Runs `query` while exchanging heartbeats with both peers of this helper.
+///
+/// Heartbeats are sent every `config.interval`. If a peer does not send a heartbeat for
+/// [`config.timeout()`], `query` is dropped and [`Error::PeerUnresponsive`] naming that peer is
+/// returned. Once `query` completes, peers are told that this helper is done, so they stop
+/// expecting heartbeats from it.
+///
+/// Heartbeats use a channel that is not part of any protocol, so this can only be done once per
+/// query.
+///
+/// ## Errors
+/// If `query` fails or if a peer goes silent.
+///
+/// [`config.timeout()`]: HeartbeatConfig::timeout
+pub async fn with_heartbeats<F, T, E>(
+    gateway: &Gateway,
+    config: HeartbeatConfig,
+    query: F,
+) -> Result<T, E>
+where
+    F: Future<Output = Result<T, E>>,
+    E: From<Error>,
+{
+    let gate = Gate::default().narrow(&HeartbeatStep);
+    let peers = [Direction::Left, Direction::Right].map(|d| gateway.role().peer(d));
+    let channels = peers.map(|peer| ChannelId::new(peer, gate.clone()));
+    let senders = channels
+        .clone()
+        .map(|c| gateway.get_sender::<Heartbeat>(&c, TotalRecords::Indeterminate));
+    let [left_rx, right_rx] = channels.map(|c| gateway.get_receiver::<Heartbeat>(&c));
+
+    let stop = Notify::new();
+    let run = join(
+        async {
+            let result = query.await;
+            stop.notify_one();
+            result
+        },
+        beat(&senders, config.interval, &stop),
+    );
+    let watch = try_join(
+        watch(&left_rx, peers[0], config),
+        watch(&right_rx, peers[1], config),
+    );
+
+    let (result, beat) = match select(pin!(run), pin!(watch)).await {
+        Either::Left((run, _)) => run,
+        // both peers are done, but this helper is not
+        Either::Right((Ok(_), run)) => run.await,
+        Either::Right((Err(e), _)) => return Err(E::from(e)),
+    };
+    if let Err(e) = beat {
+        tracing::warn!("failed to send heartbeats: {e}");
+    }
+
+    result
+}
+
+/// Sends heartbeats to both peers until `stop` is notified, then tells them that this helper is
+/// done.
+async fn beat(
+    senders: &[SendingEnd<Heartbeat>; 2],
+    interval: Duration,
+    stop: &Notify,
+) -> Result<(), Error> {
+    let mut heartbeat = Heartbeat::Alive;
+    for i in 0_u32.. {
+        let record_id = RecordId::from(i);
+        try_join(
+            senders[0].send(record_id, heartbeat),
+            senders[1].send(record_id, heartbeat),
+        )
+        .await?;
+        if heartbeat == Heartbeat::Done {
+            break;
+        }
+        if let Either::Left(_) = select(pin!(stop.notified()), pin!(sleep(interval))).await {
+            heartbeat = Heartbeat::Done;
+        }
+    }
+
+    Ok(())
+}
+
+/// Receives heartbeats from `peer` until it reports that it is done.
+async fn watch(
+    rx: &ReceivingEnd<Heartbeat>,
+    peer: Role,
+    config: HeartbeatConfig,
+) -> Result<(), Error> {
+    for i in 0_u32.. {
+        let received = timeout(config.timeout(), rx.receive(RecordId::from(i)))
+            .await
+            .map_err(|_| {
+                tracing::error!("{peer:?} missed {} heartbeats in a row", config.max_missed);
+                Error::PeerUnresponsive {
+                    peer,
+                    silent_for: config.timeout(),
+                }
+            })?;
+        if received? == Heartbeat::Done {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use std::{num::NonZeroU32, time::Duration};
+
+    use futures::future::join_all;
+
+    use super::{with_heartbeats, HeartbeatConfig};
+    use crate::{
+        helpers::{Error, Role},
+        test_fixture::TestWorld,
+    };
+
+    const CONFIG: HeartbeatConfig = HeartbeatConfig {
+        interval: Duration::from_millis(10),
+        max_missed: match NonZeroU32::new(3) {
+            Some(v) => v,
+            None => unreachable!(),
+        },
+    };
+
+    /// Runs a query that takes `duration` to complete locally on every given helper.
+    async fn run(world: &TestWorld, helpers: &[(Role, Duration)]) -> Vec<Result<(), Error>> {
+        join_all(helpers.iter().map(|&(role, duration)| {
+            with_heartbeats(world.gateway(role), CONFIG, async move {
+                ::tokio::time::sleep(duration).await;
+                Ok::<_, Error>(())
+            })
+        }))
+        .await
+    }
+
+    #[tokio::test]
+    async fn busy_peers_are_alive() {
+        let world = TestWorld::default();
+        let results = run(
+            &world,
+            &[
+                (Role::H1, Duration::ZERO),
+                (Role::H2, 5 * CONFIG.timeout()),
+                (Role::H3, 10 * CONFIG.timeout()),
+            ],
+        )
+        .await;
+
+        assert!(results.iter().all(Result::is_ok), "{results:?}");
+    }
+
+    #[tokio::test]
+    async fn silent_peer() {
+        let world = TestWorld::default();
+        let results = run(
+            &world,
+            &[
+                (Role::H1, 10 * CONFIG.timeout()),
+                (Role::H2, 10 * CONFIG.timeout()),
+            ],
+        )
+        .await;
+
+        for result in results {
+            assert!(
+                matches!(result, Err(Error::PeerUnresponsive { peer: Role::H3, .. })),
+                "{result:?}"
+            );
+        }
+    }
+}
@@ -8,6 +8,7 @@ use generic_array::GenericArray;
 mod buffers;
 mod error;
 mod gateway;
+pub(crate) mod heartbeat;
 pub(crate) mod prss_protocol;
 mod transport;
 
@@ -16,7 +17,13 @@ use std::ops::{Index, IndexMut};
 /// to validate that transport can actually send streams of this type
 #[cfg(test)]
 pub use buffers::OrderingSender;
+/// Receiving side of helper-to-helper channels, exposed to fuzz the parsing of data that comes
+/// from other helpers.
+#[cfg(feature = "test-fixture")]
+pub use buffers::UnorderedReceiver;
 pub use error::{Error, Result};
+#[cfg(feature = "test-fixture")]
+pub use gateway::checksum::{frame, ChecksumValidator};
 
 #[cfg(feature = "stall-detection")]
 mod gateway_exports {
@@ -39,21 +46,29 @@ mod gateway_exports {
     pub type ReceivingEnd<M> = gateway::ReceivingEnd<M>;
 }
 
-pub use gateway::GatewayConfig;
+pub use gateway::{
+    CapturedTraffic, FlowControlStep, GatewayConfig, HelperCounters, HelperMetrics, MemoryBudget,
+    MemoryBudgetConfig, MemoryReservation, PackedReceivingEnd, PackedSendingEnd, ProgressTracker,
+    QueryMetrics, QueryProgress, StageCounters, StageMetrics, StepProgress, StepTraffic, Traffic,
+    TrafficCapture, TrafficMismatch, TrafficProfile, BOOLEANS_PER_MESSAGE,
+};
 // TODO: this type should only be available within infra. Right now several infra modules
 // are exposed at the root level. That makes it impossible to have a proper hierarchy here.
 pub use gateway::{TransportError, TransportImpl};
 pub use gateway_exports::{Gateway, ReceivingEnd, SendingEnd};
+pub use heartbeat::{with_heartbeats, HeartbeatConfig};
 pub use prss_protocol::negotiate as negotiate_prss;
 #[cfg(feature = "web-app")]
 pub use transport::WrappedAxumBodyStream;
 pub use transport::{
-    callbacks::*, query, BodyStream, BytesStream, LengthDelimitedStream, LogErrors,
-    NoResourceIdentifier, QueryIdBinding, ReceiveRecords, RecordsStream, RouteId, RouteParams,
-    StepBinding, StreamCollection, StreamKey, Transport, WrappedBoxBodyStream,
+    callbacks::*, query, BodyStream, BytesStream, EvictionPolicy, InProcessShardNetwork,
+    InProcessShardTransport, LengthDelimitedStream, LogErrors, NoResourceIdentifier,
+    QueryIdBinding, ReceiveRecords, RecordsError, RecordsStream, RouteId, RouteParams, ShardError,
+    ShardIdentity, ShardTransport, StepBinding, StreamCollection, StreamKey, Transport,
+    TransportIdentity, WrappedBoxBodyStream,
 };
 #[cfg(feature = "in-memory-infra")]
-pub use transport::{InMemoryNetwork, InMemoryTransport};
+pub use transport::{InMemoryNetwork, InMemoryTransport, NetworkConditions};
 use typenum::{Unsigned, U8};
 use x25519_dalek::PublicKey;
 
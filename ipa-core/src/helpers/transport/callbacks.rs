@@ -1,11 +1,14 @@
 use std::{future::Future, pin::Pin};
 
 use crate::{
-    helpers::query::{PrepareQuery, QueryConfig, QueryInput},
+    helpers::{
+        query::{PrepareQuery, QueryConfig, QueryInput},
+        HelperMetrics,
+    },
     protocol::QueryId,
     query::{
         NewQueryError, PrepareQueryError, ProtocolResult, QueryCompletionError, QueryInputError,
-        QueryStatus, QueryStatusError,
+        QueryStatusError, QueryStatusReport,
     },
 };
 
@@ -58,11 +61,15 @@ callbacks! {
 
     /// Called by clients to retrieve query status.
     (QueryStatusCallback, QueryStatusResult):
-        async fn(T, QueryId) -> Result<QueryStatus, QueryStatusError>;
+        async fn(T, QueryId) -> Result<QueryStatusReport, QueryStatusError>;
 
     /// Called by clients to drive query to completion and retrieve results.
     (CompleteQueryCallback, CompleteQueryResult):
         async fn(T, QueryId) -> Result<Box<dyn ProtocolResult>, QueryCompletionError>;
+
+    /// Called by monitoring systems to collect counters of all queries run by this helper.
+    (MetricsCallback, MetricsResult):
+        async fn(T) -> HelperMetrics;
 }
 
 pub struct TransportCallbacks<T> {
@@ -71,6 +78,7 @@ pub struct TransportCallbacks<T> {
     pub query_input: Box<dyn QueryInputCallback<T>>,
     pub query_status: Box<dyn QueryStatusCallback<T>>,
     pub complete_query: Box<dyn CompleteQueryCallback<T>>,
+    pub metrics: Box<dyn MetricsCallback<T>>,
 }
 
 #[cfg(any(test, feature = "in-memory-infra"))]
@@ -96,6 +104,7 @@ impl<T> Default for TransportCallbacks<T> {
             complete_query: Box::new(move |_, _| {
                 Box::pin(async { panic!("unexpected call to complete_query") })
             }),
+            metrics: Box::new(move |_| Box::pin(async { panic!("unexpected call to metrics") })),
         }
     }
 }
@@ -0,0 +1,171 @@
+use std::{num::NonZeroU64, time::Duration};
+
+use ::tokio::{
+    sync::mpsc::unbounded_channel,
+    time::{sleep_until, Instant},
+};
+use futures::{stream, Stream, StreamExt};
+use rand::Rng;
+#[cfg(all(feature = "shuttle", test))]
+use shuttle::future as tokio;
+
+use crate::rand::thread_rng;
+
+/// Properties of the network between in-memory helpers. By default, helpers deliver data to each
+/// other instantly, which hides protocols that take more communication rounds than they need to.
+/// Setting these makes record streams arrive the way they would over a real network, so tests
+/// and benchmarks can tell how many round trips a protocol makes from the time it takes.
+///
+/// Every record stream is delayed independently, as if it was sent over its own connection.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NetworkConditions {
+    /// Time it takes data to reach the peer once it is sent.
+    pub latency: Duration,
+    /// Upper bound of the extra delay added to `latency`, drawn uniformly at random for every
+    /// chunk of data. Chunks are never reordered, a chunk that drew a shorter delay than the one
+    /// before it waits for it.
+    pub jitter: Duration,
+    /// Number of bytes a stream can send per second, `None` if it is not limited.
+    pub bandwidth: Option<NonZeroU64>,
+}
+
+impl NetworkConditions {
+    #[must_use]
+    pub fn with_latency(latency: Duration) -> Self {
+        Self {
+            latency,
+            ..Self::default()
+        }
+    }
+
+    /// Tells whether data is delivered as soon as it is sent.
+    #[must_use]
+    pub fn is_instant(&self) -> bool {
+        self.latency.is_zero() && self.jitter.is_zero() && self.bandwidth.is_none()
+    }
+
+    /// Returns a stream that yields chunks of `data` no sooner than they would arrive at the peer.
+    /// `data` is read eagerly on a separate task, so chunks that are sent one after another
+    /// arrive one after another too, rather than waiting for the latency once per chunk.
+    pub(super) fn delay<S>(self, data: S) -> impl Stream<Item = Vec<u8>> + Send
+    where
+        S: Stream<Item = Vec<u8>> + Send + 'static,
+    {
+        let (tx, rx) = unbounded_channel();
+        tokio::spawn(async move {
+            let mut data = Box::pin(data);
+            let mut link_free_at = Instant::now();
+            let mut last_arrival = link_free_at;
+            while let Some(chunk) = data.next().await {
+                link_free_at = link_free_at.max(Instant::now()) + self.transmission_time(&chunk);
+                last_arrival = last_arrival.max(link_free_at + self.latency + self.draw_jitter());
+                if tx.send((chunk, last_arrival)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        stream::unfold(rx, |mut rx| async move {
+            let (chunk, arrival) = rx.recv().await?;
+            sleep_until(arrival).await;
+            Some((chunk, rx))
+        })
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn transmission_time(&self, chunk: &[u8]) -> Duration {
+        self.bandwidth.map_or(Duration::ZERO, |bandwidth| {
+            Duration::from_secs_f64(chunk.len() as f64 / bandwidth.get() as f64)
+        })
+    }
+
+    fn draw_jitter(&self) -> Duration {
+        if self.jitter.is_zero() {
+            Duration::ZERO
+        } else {
+            thread_rng().gen_range(Duration::ZERO..=self.jitter)
+        }
+    }
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use std::{num::NonZeroU64, time::Duration};
+
+    use futures::{stream, StreamExt};
+    use tokio::time::Instant;
+
+    use super::NetworkConditions;
+    use crate::{
+        ff::{Field, Fp31},
+        protocol::{basics::SecureMul, context::Context, RecordId},
+        test_fixture::{Reconstruct, Runner, TestWorld, TestWorldConfig},
+    };
+
+    const LATENCY: Duration = Duration::from_millis(50);
+
+    #[tokio::test]
+    async fn latency_and_jitter() {
+        let conditions = NetworkConditions {
+            latency: LATENCY,
+            jitter: Duration::from_millis(20),
+            bandwidth: None,
+        };
+        let chunks = (0..20_u8).map(|i| vec![i]).collect::<Vec<_>>();
+
+        let start = Instant::now();
+        let received = conditions
+            .delay(stream::iter(chunks.clone()))
+            .collect::<Vec<_>>()
+            .await;
+        let elapsed = start.elapsed();
+
+        assert_eq!(chunks, received);
+        // latency is paid once, not for every chunk
+        assert!(elapsed >= LATENCY, "{elapsed:?}");
+        assert!(elapsed < 10 * LATENCY, "{elapsed:?}");
+    }
+
+    #[tokio::test]
+    async fn bandwidth() {
+        let conditions = NetworkConditions {
+            bandwidth: NonZeroU64::new(1000),
+            ..NetworkConditions::default()
+        };
+
+        let start = Instant::now();
+        let received = conditions
+            .delay(stream::iter([vec![0; 50], vec![1; 50]]))
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(2, received.len());
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn every_round_takes_latency() {
+        let world = TestWorld::new_with(
+            TestWorldConfig::default()
+                .with_network_conditions(NetworkConditions::with_latency(LATENCY)),
+        );
+        let (a, b) = (Fp31::truncate_from(2_u128), Fp31::truncate_from(3_u128));
+
+        let start = Instant::now();
+        let result = world
+            .semi_honest((a, b), |ctx, (a, b)| async move {
+                let ctx = ctx.set_total_records(1);
+                let ab = a
+                    .multiply(&b, ctx.narrow("first"), RecordId::FIRST)
+                    .await
+                    .unwrap();
+                ab.multiply(&b, ctx.narrow("second"), RecordId::FIRST)
+                    .await
+                    .unwrap()
+            })
+            .await;
+
+        assert_eq!(a * b * b, result.reconstruct());
+        assert!(start.elapsed() >= 2 * LATENCY);
+    }
+}
@@ -1,5 +1,7 @@
+mod conditions;
 mod transport;
 
+pub use conditions::NetworkConditions;
 pub use transport::Setup;
 
 use crate::{
@@ -29,12 +31,21 @@ impl Default for InMemoryNetwork {
 impl InMemoryNetwork {
     #[must_use]
     pub fn new(callbacks: [TransportCallbacks<InMemoryTransport>; 3]) -> Self {
+        Self::with_conditions(callbacks, NetworkConditions::default())
+    }
+
+    /// Creates a network where every link between helpers has the given `conditions`.
+    #[must_use]
+    pub fn with_conditions(
+        callbacks: [TransportCallbacks<InMemoryTransport>; 3],
+        conditions: NetworkConditions,
+    ) -> Self {
         let [mut first, mut second, mut third]: [_; 3] =
             HelperIdentity::make_three().map(Setup::new);
 
-        first.connect(&mut second);
-        second.connect(&mut third);
-        third.connect(&mut first);
+        first.connect_with(&mut second, conditions);
+        second.connect_with(&mut third, conditions);
+        third.connect_with(&mut first, conditions);
 
         let [cb1, cb2, cb3] = callbacks;
 
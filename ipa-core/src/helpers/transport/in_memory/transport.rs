@@ -1,11 +1,12 @@
 use std::{
     borrow::Borrow,
-    collections::{HashMap, HashSet},
+    collections::HashMap,
     convert,
     fmt::{Debug, Formatter},
+    future::Future,
     io,
     pin::Pin,
-    sync::{Arc, Weak},
+    sync::{Arc, Mutex, Weak},
     task::{Context, Poll},
 };
 
@@ -24,13 +25,16 @@ use tracing::Instrument;
 use crate::{
     error::BoxError,
     helpers::{
-        query::{PrepareQuery, QueryConfig},
-        HelperIdentity, NoResourceIdentifier, QueryIdBinding, ReceiveRecords, RouteId, RouteParams,
-        StepBinding, StreamCollection, Transport, TransportCallbacks,
+        query::{PrepareQuery, QueryConfig, QueryInput},
+        CompleteQueryResult, HelperIdentity, NoResourceIdentifier, QueryIdBinding,
+        QueryInputResult, QueryStatusResult, ReceiveQueryResult, ReceiveRecords, RouteId,
+        RouteParams, StepBinding, StreamCollection, Transport, TransportCallbacks,
     },
     protocol::{step::Gate, QueryId},
 };
 
+use super::NetworkConditions;
+
 type Packet = (Addr, InMemoryStream, oneshot::Sender<Result<(), Error>>);
 type ConnectionTx = Sender<Packet>;
 type ConnectionRx = Receiver<Packet>;
@@ -54,19 +58,43 @@ pub enum Error {
 /// In-memory implementation of [`Transport`] backed by Tokio mpsc channels.
 /// Use [`Setup`] to initialize it and call [`Setup::start`] to make it actively listen for
 /// incoming messages.
+///
+/// It is meant to behave exactly like [`HttpTransport`] does: requests that clients send to
+/// HTTP endpoints can be made via [`receive_query`], [`query_input`], [`query_status`] and
+/// [`complete_query`], which invoke the same callbacks, and record streams are kept per query
+/// and released once the query completes.
+///
+/// [`HttpTransport`]: crate::net::HttpTransport
+/// [`receive_query`]: Self::receive_query
+/// [`query_input`]: Self::query_input
+/// [`query_status`]: Self::query_status
+/// [`complete_query`]: Self::complete_query
 pub struct InMemoryTransport {
     identity: HelperIdentity,
     connections: HashMap<HelperIdentity, ConnectionTx>,
-    record_streams: StreamCollection<InMemoryStream>,
+    /// Conditions of links to peers that record streams sent to them go through. Links that are
+    /// not in this map deliver data instantly.
+    conditions: HashMap<HelperIdentity, NetworkConditions>,
+    callbacks: Arc<TransportCallbacks<Weak<Self>>>,
+    /// Inbound record streams, one collection per query. Collection is created when the first
+    /// stream for a query is sent or requested and is removed when that query completes.
+    record_streams: Mutex<HashMap<QueryId, StreamCollection<InMemoryStream>>>,
 }
 
 impl InMemoryTransport {
     #[must_use]
-    fn new(identity: HelperIdentity, connections: HashMap<HelperIdentity, ConnectionTx>) -> Self {
+    fn new(
+        identity: HelperIdentity,
+        connections: HashMap<HelperIdentity, ConnectionTx>,
+        conditions: HashMap<HelperIdentity, NetworkConditions>,
+        callbacks: TransportCallbacks<Weak<Self>>,
+    ) -> Self {
         Self {
             identity,
             connections,
-            record_streams: StreamCollection::default(),
+            conditions,
+            callbacks: Arc::new(callbacks),
+            record_streams: Mutex::default(),
         }
     }
 
@@ -79,38 +107,31 @@ impl InMemoryTransport {
     /// out and processes it, the same way as query processor does. That will allow all tasks to be
     /// created in one place (driver). It does not affect the [`Transport`] interface,
     /// so I'll leave it as is for now.
-    fn listen(self: &Arc<Self>, callbacks: TransportCallbacks<Weak<Self>>, mut rx: ConnectionRx) {
+    fn listen(self: &Arc<Self>, mut rx: ConnectionRx) {
         tokio::spawn(
             {
-                let streams = self.record_streams.clone();
+                let callbacks = Arc::clone(&self.callbacks);
                 let this = Arc::downgrade(self);
                 let dest = this.identity();
                 async move {
-                    let mut active_queries = HashSet::new();
                     while let Some((addr, stream, ack)) = rx.recv().await {
                         tracing::trace!("received new message: {addr:?}");
 
                         let result = match addr.route {
-                            RouteId::ReceiveQuery => {
-                                let qc = addr.into::<QueryConfig>();
-                                (callbacks.receive_query)(Transport::clone_ref(&this), qc)
-                                    .await
-                                    .map(|query_id| {
-                                        assert!(
-                                            active_queries.insert(query_id),
-                                            "the same query id {query_id:?} is generated twice"
-                                        );
-                                    })
-                                    .map_err(|e| Error::Rejected {
-                                        dest,
-                                        inner: Box::new(e),
-                                    })
-                            }
+                            // Same as HTTP, clients start queries, helpers only prepare them
+                            // on each other.
+                            RouteId::ReceiveQuery => Err(Error::Rejected {
+                                dest,
+                                inner: "helpers do not accept queries from other helpers".into(),
+                            }),
                             RouteId::Records => {
                                 let query_id = addr.query_id.unwrap();
                                 let gate = addr.gate.unwrap();
                                 let from = addr.origin.unwrap();
-                                streams.add_stream((query_id, from, gate), stream);
+                                if let Some(this) = this.upgrade() {
+                                    this.query_streams(query_id)
+                                        .add_stream((query_id, from, gate), stream);
+                                }
                                 Ok(())
                             }
                             RouteId::PrepareQuery => {
@@ -144,9 +165,75 @@ impl InMemoryTransport {
             .clone()
     }
 
+    /// Returns the collection of inbound record streams for the given query, creating it if
+    /// this query has not seen any streams yet.
+    fn query_streams(&self, query_id: QueryId) -> StreamCollection<InMemoryStream> {
+        self.record_streams
+            .lock()
+            .unwrap()
+            .entry(query_id)
+            .or_default()
+            .clone()
+    }
+
+    /// Removes record streams of the given query, releasing them even if receivers still hold
+    /// a reference to the collection.
+    fn clear_streams(&self, query_id: QueryId) {
+        let streams = self.record_streams.lock().unwrap().remove(&query_id);
+        if let Some(streams) = streams {
+            streams.clear();
+        }
+    }
+
+    pub fn receive_query(self: &Arc<Self>, req: QueryConfig) -> ReceiveQueryResult {
+        (self.callbacks.receive_query)(Arc::downgrade(self), req)
+    }
+
+    pub fn query_input(self: &Arc<Self>, req: QueryInput) -> QueryInputResult {
+        (self.callbacks.query_input)(Arc::downgrade(self), req)
+    }
+
+    pub fn query_status(self: &Arc<Self>, query_id: QueryId) -> QueryStatusResult {
+        (self.callbacks.query_status)(Arc::downgrade(self), query_id)
+    }
+
+    /// Drives the query to completion. Once it completes or the returned future is dropped,
+    /// record streams of this query are released, so this transport can process the next one.
+    pub fn complete_query(self: &Arc<Self>, query_id: QueryId) -> CompleteQueryResult {
+        struct ClearOnDrop {
+            transport: Weak<InMemoryTransport>,
+            query_id: QueryId,
+            qr: CompleteQueryResult,
+        }
+
+        impl Future for ClearOnDrop {
+            type Output = <CompleteQueryResult as Future>::Output;
+
+            fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+                self.qr.as_mut().poll(cx)
+            }
+        }
+
+        impl Drop for ClearOnDrop {
+            fn drop(&mut self) {
+                if let Some(transport) = self.transport.upgrade() {
+                    transport.clear_streams(self.query_id);
+                }
+            }
+        }
+
+        Box::pin(ClearOnDrop {
+            transport: Arc::downgrade(self),
+            query_id,
+            qr: (self.callbacks.complete_query)(Arc::downgrade(self), query_id),
+        })
+    }
+
     /// Resets this transport, making it forget its state and be ready for processing another query.
     pub fn reset(&self) {
-        self.record_streams.clear();
+        for (_, streams) in self.record_streams.lock().unwrap().drain() {
+            streams.clear();
+        }
     }
 }
 
@@ -175,16 +262,20 @@ impl Transport for Weak<InMemoryTransport> {
         Option<Gate>: From<S>,
     {
         let this = self.upgrade().unwrap();
-        let channel = this.get_channel(dest);
         let addr = Addr::from_route(this.identity, route);
+        // Same as HTTP, the other helper rejects queries sent to it, see `listen`.
+        let channel = this.get_channel(dest);
         let (ack_tx, ack_rx) = oneshot::channel();
+        let data = match this.conditions.get(&dest) {
+            Some(conditions) if matches!(addr.route, RouteId::Records) => {
+                InMemoryStream::wrap(conditions.delay(data))
+            }
+            _ => InMemoryStream::wrap(data),
+        };
 
-        channel
-            .send((addr, InMemoryStream::wrap(data), ack_tx))
-            .await
-            .map_err(|_e| {
-                io::Error::new::<String>(io::ErrorKind::ConnectionAborted, "channel closed".into())
-            })?;
+        channel.send((addr, data, ack_tx)).await.map_err(|_e| {
+            io::Error::new::<String>(io::ErrorKind::ConnectionAborted, "channel closed".into())
+        })?;
 
         ack_rx
             .await
@@ -200,9 +291,10 @@ impl Transport for Weak<InMemoryTransport> {
         from: HelperIdentity,
         route: R,
     ) -> Self::RecordsStream {
+        let query_id = route.query_id();
         ReceiveRecords::new(
-            (route.query_id(), from, route.gate()),
-            self.upgrade().unwrap().record_streams.clone(),
+            (query_id, from, route.gate()),
+            self.upgrade().unwrap().query_streams(query_id),
         )
     }
 }
@@ -319,6 +411,7 @@ pub struct Setup {
     tx: ConnectionTx,
     rx: ConnectionRx,
     connections: HashMap<HelperIdentity, ConnectionTx>,
+    conditions: HashMap<HelperIdentity, NetworkConditions>,
 }
 
 impl Setup {
@@ -330,6 +423,7 @@ impl Setup {
             tx,
             rx,
             connections: HashMap::default(),
+            conditions: HashMap::default(),
         }
     }
 
@@ -348,12 +442,30 @@ impl Setup {
             .is_none());
     }
 
+    /// Same as [`Self::connect`], but record streams sent over the link in either direction
+    /// go through the given `conditions`.
+    ///
+    /// ## Panics
+    /// Panics if there is a link already.
+    pub fn connect_with(&mut self, other: &mut Self, conditions: NetworkConditions) {
+        self.connect(other);
+        if !conditions.is_instant() {
+            self.conditions.insert(other.identity, conditions);
+            other.conditions.insert(self.identity, conditions);
+        }
+    }
+
     fn into_active_conn(
         self,
         callbacks: TransportCallbacks<Weak<InMemoryTransport>>,
     ) -> (ConnectionTx, Arc<InMemoryTransport>) {
-        let transport = Arc::new(InMemoryTransport::new(self.identity, self.connections));
-        transport.listen(callbacks, self.rx);
+        let transport = Arc::new(InMemoryTransport::new(
+            self.identity,
+            self.connections,
+            self.conditions,
+            callbacks,
+        ));
+        transport.listen(self.rx);
 
         (self.tx, transport)
     }
@@ -371,7 +483,10 @@ impl Setup {
 mod tests {
     use std::{io::ErrorKind, num::NonZeroUsize, panic::AssertUnwindSafe, sync::Mutex};
 
-    use futures_util::{stream::poll_immediate, FutureExt, StreamExt};
+    use futures_util::{
+        stream::{self, poll_immediate},
+        FutureExt, StreamExt,
+    };
     use tokio::sync::{mpsc::channel, oneshot};
 
     use super::*;
@@ -381,6 +496,7 @@ mod tests {
             query::QueryType::TestMultiply, transport::in_memory::InMemoryNetwork, HelperIdentity,
             OrderingSender,
         },
+        query::QueryCompletionError,
     };
 
     const STEP: &str = "in-memory-transport";
@@ -400,34 +516,107 @@ mod tests {
     async fn callback_is_called() {
         let (signal_tx, signal_rx) = oneshot::channel();
         let signal_tx = Arc::new(Mutex::new(Some(signal_tx)));
+        let transport = Setup::new(HelperIdentity::ONE).start(TransportCallbacks {
+            receive_query: Box::new(move |_transport, query_config| {
+                let signal_tx = Arc::clone(&signal_tx);
+                Box::pin(async move {
+                    // this works because callback is only called once
+                    signal_tx
+                        .lock()
+                        .unwrap()
+                        .take()
+                        .expect("query callback invoked more than once")
+                        .send(query_config)
+                        .unwrap();
+                    Ok(QueryId::default())
+                })
+            }),
+            ..Default::default()
+        });
+        let expected = QueryConfig::new(TestMultiply, FieldType::Fp32BitPrime, 1u32).unwrap();
+
+        assert_eq!(
+            QueryId::default(),
+            transport.receive_query(expected).await.unwrap()
+        );
+        assert_eq!(expected, signal_rx.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn rejects_query_from_helper() {
+        // default callbacks panic if query callback is called
         let (tx, _transport) =
+            Setup::new(HelperIdentity::ONE).into_active_conn(TransportCallbacks::default());
+        let config = QueryConfig::new(TestMultiply, FieldType::Fp32BitPrime, 1u32).unwrap();
+
+        let (ack_tx, ack_rx) = oneshot::channel();
+        tx.send((
+            Addr::from_route(HelperIdentity::TWO, &config),
+            InMemoryStream::empty(),
+            ack_tx,
+        ))
+        .await
+        .unwrap();
+
+        assert!(matches!(
+            ack_rx.await.unwrap(),
+            Err(Error::Rejected { dest, .. }) if dest == HelperIdentity::ONE
+        ));
+    }
+
+    #[tokio::test]
+    async fn query_sent_to_helper_is_rejected() {
+        let network = InMemoryNetwork::default();
+        let transport = network.transport(HelperIdentity::ONE);
+        let config = QueryConfig::new(TestMultiply, FieldType::Fp32BitPrime, 1u32).unwrap();
+
+        assert!(matches!(
+            transport
+                .send(HelperIdentity::TWO, &config, stream::empty())
+                .await,
+            Err(Error::Rejected { dest, .. }) if dest == HelperIdentity::TWO
+        ));
+    }
+
+    #[tokio::test]
+    async fn complete_query_releases_streams() {
+        let (tx, transport) =
             Setup::new(HelperIdentity::ONE).into_active_conn(TransportCallbacks {
-                receive_query: Box::new(move |_transport, query_config| {
-                    let signal_tx = Arc::clone(&signal_tx);
-                    Box::pin(async move {
-                        // this works because callback is only called once
-                        signal_tx
-                            .lock()
-                            .unwrap()
-                            .take()
-                            .expect("query callback invoked more than once")
-                            .send(query_config)
-                            .unwrap();
-                        Ok(QueryId)
-                    })
+                complete_query: Box::new(|_, query_id| {
+                    Box::pin(async move { Err(QueryCompletionError::NoSuchQuery(query_id)) })
                 }),
                 ..Default::default()
             });
-        let expected = QueryConfig::new(TestMultiply, FieldType::Fp32BitPrime, 1u32).unwrap();
-
+        let gate = Gate::from(STEP);
+        let (stream_tx, stream_rx) = channel(1);
         send_and_ack(
             &tx,
-            Addr::from_route(HelperIdentity::TWO, &expected),
-            InMemoryStream::empty(),
+            Addr::records(HelperIdentity::TWO, QueryId::default(), gate.clone()),
+            InMemoryStream::from(stream_rx),
         )
         .await;
+        assert!(transport
+            .record_streams
+            .lock()
+            .unwrap()
+            .contains_key(&QueryId::default()));
 
-        assert_eq!(expected, signal_rx.await.unwrap());
+        // streams are released even if the query failed
+        assert!(matches!(
+            transport.complete_query(QueryId::default()).await,
+            Err(QueryCompletionError::NoSuchQuery(QueryId::default()))
+        ));
+        assert!(transport.record_streams.lock().unwrap().is_empty());
+
+        drop(stream_tx);
+
+        // the next query waits for its own stream rather than getting the one already consumed
+        let mut recv =
+            Arc::downgrade(&transport).receive(HelperIdentity::TWO, (QueryId::default(), gate));
+        assert!(matches!(
+            poll_immediate(&mut recv).next().await,
+            Some(Poll::Pending)
+        ));
     }
 
     #[tokio::test]
@@ -437,7 +626,8 @@ mod tests {
         let transport = Arc::downgrade(&transport);
         let expected = vec![vec![1], vec![2]];
 
-        let mut stream = transport.receive(HelperIdentity::TWO, (QueryId, Gate::from(STEP)));
+        let mut stream =
+            transport.receive(HelperIdentity::TWO, (QueryId::default(), Gate::from(STEP)));
 
         // make sure it is not ready as it hasn't received the records stream yet.
         assert!(matches!(
@@ -446,7 +636,7 @@ mod tests {
         ));
         send_and_ack(
             &tx,
-            Addr::records(HelperIdentity::TWO, QueryId, Gate::from(STEP)),
+            Addr::records(HelperIdentity::TWO, QueryId::default(), Gate::from(STEP)),
             InMemoryStream::from_iter(expected.clone()),
         )
         .await;
@@ -462,13 +652,13 @@ mod tests {
 
         send_and_ack(
             &tx,
-            Addr::records(HelperIdentity::TWO, QueryId, Gate::from(STEP)),
+            Addr::records(HelperIdentity::TWO, QueryId::default(), Gate::from(STEP)),
             InMemoryStream::from_iter(expected.clone()),
         )
         .await;
 
-        let stream =
-            Arc::downgrade(&transport).receive(HelperIdentity::TWO, (QueryId, Gate::from(STEP)));
+        let stream = Arc::downgrade(&transport)
+            .receive(HelperIdentity::TWO, (QueryId::default(), Gate::from(STEP)));
 
         assert_eq!(expected, stream.collect::<Vec<_>>().await);
     }
@@ -487,14 +677,18 @@ mod tests {
             let to_transport = transports.get(&to).unwrap();
             let gate = Gate::from(STEP);
 
-            let mut recv = to_transport.receive(from, (QueryId, gate.clone()));
+            let mut recv = to_transport.receive(from, (QueryId::default(), gate.clone()));
             assert!(matches!(
                 poll_immediate(&mut recv).next().await,
                 Some(Poll::Pending)
             ));
 
             from_transport
-                .send(to, (RouteId::Records, QueryId, gate.clone()), stream)
+                .send(
+                    to,
+                    (RouteId::Records, QueryId::default(), gate.clone()),
+                    stream,
+                )
                 .await
                 .unwrap();
             stream_tx.send(vec![1, 2, 3]).await.unwrap();
@@ -540,10 +734,11 @@ mod tests {
         let stream = InMemoryStream::from(stream_rx);
         let transport = Arc::downgrade(&owned_transport);
 
-        let mut recv_stream = transport.receive(HelperIdentity::TWO, (QueryId, gate.clone()));
+        let mut recv_stream =
+            transport.receive(HelperIdentity::TWO, (QueryId::default(), gate.clone()));
         send_and_ack(
             &tx,
-            Addr::records(HelperIdentity::TWO, QueryId, gate.clone()),
+            Addr::records(HelperIdentity::TWO, QueryId::default(), gate.clone()),
             stream,
         )
         .await;
@@ -552,7 +747,8 @@ mod tests {
         assert_eq!(vec![4, 5, 6], recv_stream.next().await.unwrap());
 
         // the same stream cannot be received again
-        let mut err_recv = transport.receive(HelperIdentity::TWO, (QueryId, gate.clone()));
+        let mut err_recv =
+            transport.receive(HelperIdentity::TWO, (QueryId::default(), gate.clone()));
         let err = AssertUnwindSafe(err_recv.next()).catch_unwind().await;
         assert_eq!(
             Some(true),
@@ -563,7 +759,8 @@ mod tests {
 
         // even after the input stream is closed
         drop(stream_tx);
-        let mut err_recv = transport.receive(HelperIdentity::TWO, (QueryId, gate.clone()));
+        let mut err_recv =
+            transport.receive(HelperIdentity::TWO, (QueryId::default(), gate.clone()));
         let err = AssertUnwindSafe(err_recv.next()).catch_unwind().await;
         assert_eq!(
             Some(true),
@@ -588,12 +785,12 @@ mod tests {
         transport1
             .send(
                 HelperIdentity::TWO,
-                (RouteId::Records, QueryId, gate.clone()),
+                (RouteId::Records, QueryId::default(), gate.clone()),
                 rx,
             )
             .await
             .unwrap();
-        let mut recv = transport2.receive(HelperIdentity::ONE, (QueryId, gate));
+        let mut recv = transport2.receive(HelperIdentity::ONE, (QueryId::default(), gate));
 
         tx.send(0, Fp31::try_from(0_u128).unwrap()).await;
         // can't receive the value at index 0 because of buffering inside the sender
@@ -1,4 +1,4 @@
-use std::borrow::Borrow;
+use std::{borrow::Borrow, fmt::Debug, hash::Hash};
 
 use async_trait::async_trait;
 use futures::Stream;
@@ -13,18 +13,28 @@ pub mod callbacks;
 mod in_memory;
 pub mod query;
 mod receive;
+mod shard;
 mod stream;
 
 #[cfg(feature = "in-memory-infra")]
-pub use in_memory::{InMemoryNetwork, InMemoryTransport};
+pub use in_memory::{InMemoryNetwork, InMemoryTransport, NetworkConditions};
 pub use receive::{LogErrors, ReceiveRecords};
+pub use shard::{
+    InProcessShardNetwork, InProcessShardTransport, ShardError, ShardIdentity, ShardTransport,
+};
 #[cfg(feature = "web-app")]
 pub use stream::WrappedAxumBodyStream;
 pub use stream::{
-    BodyStream, BytesStream, LengthDelimitedStream, RecordsStream, StreamCollection, StreamKey,
-    WrappedBoxBodyStream,
+    BodyStream, BytesStream, EvictionPolicy, LengthDelimitedStream, RecordsError, RecordsStream,
+    StreamCollection, StreamKey, WrappedBoxBodyStream,
 };
 
+/// Identifies the other end of a transport: another helper for [`Transport`] or another shard of
+/// this helper for [`ShardTransport`].
+pub trait TransportIdentity: Copy + Debug + Eq + Hash + Send + Sync + Unpin + 'static {}
+
+impl TransportIdentity for HelperIdentity {}
+
 pub trait ResourceIdentifier: Sized {}
 pub trait QueryIdBinding: Sized
 where
@@ -3,6 +3,7 @@ pub mod oprf_shuffle;
 use std::{
     fmt::{Debug, Display, Formatter},
     num::NonZeroU32,
+    str::FromStr,
 };
 
 use serde::{Deserialize, Deserializer, Serialize};
@@ -13,7 +14,8 @@ use crate::{
         transport::{BodyStream, NoQueryId, NoStep},
         GatewayConfig, RoleAssignment, RouteId, RouteParams,
     },
-    protocol::{step::Step, QueryId},
+    protocol::{ipa_prf::time_bucket, step::Step, QueryId},
+    report::{Epoch, KeyIdentifier, ReportSchema},
 };
 
 #[derive(Copy, Clone, Debug, Ord, PartialOrd, Eq, PartialEq)]
@@ -84,8 +86,7 @@ impl From<QuerySize> for usize {
     }
 }
 
-#[derive(Copy, Clone, Debug)]
-#[cfg_attr(test, derive(PartialEq, Eq))]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 #[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
 pub struct QueryConfig {
     pub size: QuerySize,
@@ -97,6 +98,23 @@ pub struct QueryConfig {
 pub enum QueryConfigError {
     #[error(transparent)]
     BadQuerySize(#[from] BadQuerySizeError),
+    #[error(
+        "Attribution window of {window} seconds does not fit into {timestamp_bits}-bit timestamps, \
+         it must not exceed {} seconds", (1_u64 << .timestamp_bits) - 1
+    )]
+    AttributionWindowTooLarge { window: u32, timestamp_bits: u32 },
+    #[error("{0} requires reports with timestamps, but the query declares reports without them")]
+    TimestampsRequired(&'static str),
+    #[error("{0}-bit source event priorities are not supported, priorities must be 8 bits wide")]
+    UnsupportedPriorityWidth(u32),
+    #[error("Pseudonymized input {0}")]
+    PseudonymizedInput(&'static str),
+    #[error("per-user credit cap must be positive")]
+    ZeroCreditCap,
+    #[error("{0} is not supported with compact gate yet")]
+    UnsupportedWithCompactGate(&'static str),
+    #[error(transparent)]
+    TimeBuckets(#[from] time_bucket::Error),
 }
 
 #[derive(Clone, Debug)]
@@ -106,6 +124,21 @@ pub struct PrepareQuery {
     pub query_id: QueryId,
     pub config: QueryConfig,
     pub roles: RoleAssignment,
+    pub reservation: ResourceReservation,
+}
+
+/// Resources a helper needs to set aside to run a query. The coordinator derives them from the
+/// query configuration and sends them to followers along with it, so every helper can confirm
+/// that it agrees with them and is able to afford them before any work is done.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+pub struct ResourceReservation {
+    /// Bytes of memory needed to hold query inputs and the state protocols derive from them.
+    pub memory_bytes: u64,
+    /// Bytes of disk space needed to spool query inputs, for helpers that keep a copy of them.
+    pub spool_bytes: u64,
+    /// Number of records processed concurrently.
+    pub parallelism: u32,
 }
 
 impl RouteParams<RouteId, NoQueryId, NoStep> for &QueryConfig {
@@ -160,6 +193,35 @@ impl QueryConfig {
             query_type,
         })
     }
+
+    /// Returns the resources helpers need to reserve to run this query.
+    ///
+    /// ## Panics
+    /// If active work configured for this query does not fit into `u32`.
+    #[must_use]
+    pub fn reservation(&self) -> ResourceReservation {
+        // Protocols keep several shares derived from every input field while the query is
+        // running, in addition to the input itself.
+        const STATE_PER_INPUT_BYTE: u64 = 4;
+
+        let spool_bytes = u64::from(u32::from(self.size)) * self.query_type.input_record_size();
+        ResourceReservation {
+            memory_bytes: spool_bytes * (1 + STATE_PER_INPUT_BYTE),
+            spool_bytes,
+            parallelism: u32::try_from(GatewayConfig::from(self).active_work().get()).unwrap(),
+        }
+    }
+
+    /// Checks parameters that can only be validated against each other.
+    ///
+    /// ## Errors
+    /// If the query type parameters are not consistent.
+    pub fn validate(&self) -> Result<(), QueryConfigError> {
+        match self.query_type {
+            QueryType::OprfIpa(config) => config.validate(),
+            _ => Ok(()),
+        }
+    }
 }
 
 impl RouteParams<RouteId, QueryId, NoStep> for &PrepareQuery {
@@ -188,14 +250,56 @@ impl RouteParams<RouteId, QueryId, NoStep> for &PrepareQuery {
     }
 }
 
+/// Describes which events a query input carries. Normally a single report collector submits all
+/// events at once, but sources and triggers may also come from two different report collectors.
+/// In that case, helpers wait until both parts arrive and process them as a single input.
+///
+/// Helpers only accept a part from the report collector that authenticates as entitled to it,
+/// see `ReportCollectorConfig`.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+#[cfg_attr(
+    feature = "enable-serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum InputPart {
+    #[default]
+    All,
+    SourceEvents,
+    TriggerEvents,
+}
+
+impl InputPart {
+    /// Returns the part that completes this one, if input is split.
+    #[must_use]
+    pub fn complement(self) -> Option<Self> {
+        match self {
+            Self::All => None,
+            Self::SourceEvents => Some(Self::TriggerEvents),
+            Self::TriggerEvents => Some(Self::SourceEvents),
+        }
+    }
+}
+
+impl AsRef<str> for InputPart {
+    fn as_ref(&self) -> &str {
+        match self {
+            Self::All => "all",
+            Self::SourceEvents => "source_events",
+            Self::TriggerEvents => "trigger_events",
+        }
+    }
+}
+
 pub struct QueryInput {
     pub query_id: QueryId,
+    pub part: InputPart,
     pub input_stream: BodyStream,
 }
 
 impl Debug for QueryInput {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "query_inputs[{:?}]", self.query_id)
+        write!(f, "query_inputs[{:?}, {:?}]", self.query_id, self.part)
     }
 }
 
@@ -209,6 +313,7 @@ pub enum QueryType {
     SemiHonestSparseAggregate(SparseAggregateQueryConfig),
     MaliciousSparseAggregate(SparseAggregateQueryConfig),
     OprfIpa(IpaQueryConfig),
+    OprfPseudonyms(PseudonymQueryConfig),
 }
 
 impl QueryType {
@@ -218,6 +323,21 @@ impl QueryType {
     pub const SEMIHONEST_AGGREGATE_STR: &'static str = "semihonest-sparse-aggregate";
     pub const MALICIOUS_AGGREGATE_STR: &'static str = "malicious-sparse-aggregate";
     pub const OPRF_IPA_STR: &'static str = "oprf_ipa";
+    pub const OPRF_PSEUDONYMS_STR: &'static str = "oprf-pseudonyms";
+
+    /// Upper bound on the size of a single input record of this query, in bytes.
+    fn input_record_size(&self) -> u64 {
+        match self {
+            #[cfg(any(test, feature = "cli", feature = "test-fixture"))]
+            QueryType::TestMultiply => 16,
+            QueryType::SemiHonestIpa(_) | QueryType::MaliciousIpa(_) => 128,
+            QueryType::SemiHonestSparseAggregate(_) | QueryType::MaliciousSparseAggregate(_) => 64,
+            // Encrypted reports: match keys, timestamps, breakdown keys and trigger values
+            // encrypted separately, plus HPKE overhead.
+            QueryType::OprfIpa(_) => 256,
+            QueryType::OprfPseudonyms(_) => 128,
+        }
+    }
 }
 
 /// TODO: should this `AsRef` impl (used for `Substep`) take into account config of IPA?
@@ -231,21 +351,302 @@ impl AsRef<str> for QueryType {
             QueryType::SemiHonestSparseAggregate(_) => Self::SEMIHONEST_AGGREGATE_STR,
             QueryType::MaliciousSparseAggregate(_) => Self::MALICIOUS_AGGREGATE_STR,
             QueryType::OprfIpa(_) => Self::OPRF_IPA_STR,
+            QueryType::OprfPseudonyms(_) => Self::OPRF_PSEUDONYMS_STR,
         }
     }
 }
 
 impl Step for QueryType {}
 
+/// Attribution window as specified by report collectors: a number of seconds, optionally followed
+/// by `s`, or a number of hours or days followed by `h` or `d` respectively. For example,
+/// `86400`, `24h` and `1d` all specify the same window.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AttributionWindow {
+    Seconds(NonZeroU32),
+    Hours(NonZeroU32),
+    Days(NonZeroU32),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AttributionWindowError {
+    #[error(
+        "Attribution window must be a positive number of seconds, hours (h) or days (d), got {0:?}"
+    )]
+    Malformed(String),
+    #[error("Attribution window {0} is longer than {} seconds", u32::MAX)]
+    TooLarge(AttributionWindow),
+}
+
+impl AttributionWindow {
+    /// Returns the length of this window in seconds.
+    ///
+    /// ## Errors
+    /// If the window is longer than `u32::MAX` seconds.
+    pub fn seconds(self) -> Result<NonZeroU32, AttributionWindowError> {
+        let (value, unit) = match self {
+            Self::Seconds(v) => (v, 1),
+            Self::Hours(v) => (v, 3600),
+            Self::Days(v) => (v, 86_400),
+        };
+        value
+            .checked_mul(NonZeroU32::new(unit).unwrap())
+            .ok_or(AttributionWindowError::TooLarge(self))
+    }
+
+    /// Parses a window specification and converts it to seconds.
+    ///
+    /// ## Errors
+    /// If the specification is malformed or the window is too long.
+    pub fn parse_seconds(s: &str) -> Result<NonZeroU32, AttributionWindowError> {
+        s.parse::<Self>()?.seconds()
+    }
+
+    /// Accepts either a number of seconds or a window specification string. The former is used
+    /// by helpers to pass the query config to each other, the latter by report collectors.
+    fn deserialize_seconds<'de, D>(deserializer: D) -> Result<Option<NonZeroU32>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Window {
+            Seconds(NonZeroU32),
+            Spec(String),
+        }
+
+        Option::<Window>::deserialize(deserializer)?
+            .map(|window| match window {
+                Window::Seconds(seconds) => Ok(seconds),
+                Window::Spec(spec) => Self::parse_seconds(&spec).map_err(serde::de::Error::custom),
+            })
+            .transpose()
+    }
+}
+
+impl FromStr for AttributionWindow {
+    type Err = AttributionWindowError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let spec = s.trim();
+        let (value, unit): (_, fn(NonZeroU32) -> Self) = if let Some(v) = spec.strip_suffix('d') {
+            (v, Self::Days)
+        } else if let Some(v) = spec.strip_suffix('h') {
+            (v, Self::Hours)
+        } else {
+            (spec.strip_suffix('s').unwrap_or(spec), Self::Seconds)
+        };
+        value
+            .parse::<NonZeroU32>()
+            .map(unit)
+            .map_err(|_| AttributionWindowError::Malformed(s.to_string()))
+    }
+}
+
+impl Display for AttributionWindow {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Seconds(v) => write!(f, "{v}s"),
+            Self::Hours(v) => write!(f, "{v}h"),
+            Self::Days(v) => write!(f, "{v}d"),
+        }
+    }
+}
+
+/// Positive and finite parameter of differential privacy, such as epsilon or delta. NaN values are
+/// rejected, so parameters can be compared bitwise and configs that contain them can implement
+/// `Eq`.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialOrd)]
+#[serde(try_from = "f64", into = "f64")]
+pub struct DpParameter(f64);
+
+impl TryFrom<f64> for DpParameter {
+    type Error = String;
+
+    fn try_from(value: f64) -> Result<Self, Self::Error> {
+        if value.is_finite() && value > 0.0 {
+            Ok(Self(value))
+        } else {
+            Err(format!(
+                "DP parameter must be positive and finite, got {value}"
+            ))
+        }
+    }
+}
+
+impl From<DpParameter> for f64 {
+    fn from(value: DpParameter) -> Self {
+        value.0
+    }
+}
+
+impl PartialEq for DpParameter {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.to_bits() == other.0.to_bits()
+    }
+}
+
+impl Eq for DpParameter {}
+
+impl FromStr for DpParameter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse::<f64>()
+            .map_err(|e| format!("{s:?} is not a valid DP parameter: {e}"))?
+            .try_into()
+    }
+}
+
+impl Display for DpParameter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&self.0, f)
+    }
+}
+
+/// Set of breakdown keys, written as a comma-separated list, like `1,4,7`. Breakdown keys are at
+/// most 8 bits wide, so only keys below 256 can be in the set.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct BreakdownKeySet([u128; 2]);
+
+impl BreakdownKeySet {
+    pub const MAX_KEY: u32 = 255;
+
+    #[must_use]
+    pub fn contains(&self, key: u32) -> bool {
+        key <= Self::MAX_KEY && self.0[usize::from(key >= 128)] & (1 << (key % 128)) != 0
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns keys in the set in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..=Self::MAX_KEY).filter(|&key| self.contains(key))
+    }
+}
+
+impl FromIterator<u8> for BreakdownKeySet {
+    fn from_iter<T: IntoIterator<Item = u8>>(iter: T) -> Self {
+        let mut keys = Self::default();
+        for key in iter {
+            keys.0[usize::from(key / 128)] |= 1 << (key % 128);
+        }
+        keys
+    }
+}
+
+impl FromStr for BreakdownKeySet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+            .map(|key| {
+                key.parse::<u8>().map_err(|_| {
+                    format!(
+                        "{key:?} is not a valid breakdown key, keys must be integers in [0, {}]",
+                        Self::MAX_KEY
+                    )
+                })
+            })
+            .collect()
+    }
+}
+
+impl TryFrom<String> for BreakdownKeySet {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<BreakdownKeySet> for String {
+    fn from(value: BreakdownKeySet) -> Self {
+        value.to_string()
+    }
+}
+
+impl Display for BreakdownKeySet {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (i, key) in self.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{key}")?;
+        }
+        Ok(())
+    }
+}
+
+/// What OPRF IPA does with encrypted reports whose breakdown key is wider than
+/// `breakdown_key_bits`, i.e. reports in which any share of the breakdown key has a bit above that
+/// width set. Helpers agree on the reports that have such keys, so they all handle the same
+/// reports the same way.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(
+    feature = "enable-serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "snake_case")
+)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum BreakdownKeyOverflow {
+    /// Reports are skipped, like any other malformed report.
+    #[default]
+    Skip,
+    /// The query fails.
+    Reject,
+    /// Reports are attributed to an extra breakdown with key `max_breakdown_key`, whose total is
+    /// returned after the totals of all other breakdowns.
+    Overflow,
+}
+
+impl AsRef<str> for BreakdownKeyOverflow {
+    fn as_ref(&self) -> &str {
+        match self {
+            Self::Skip => "skip",
+            Self::Reject => "reject",
+            Self::Overflow => "overflow",
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
 #[cfg_attr(feature = "clap", derive(clap::Args))]
 pub struct IpaQueryConfig {
+    /// The maximum total value a single user can contribute to the query result. OPRF IPA supports
+    /// any positive cap, caps that are powers of two are slightly cheaper to enforce. Caps above
+    /// 256 are enforced with a 16- or 32-bit saturating sum, which makes attribution slower.
     #[cfg_attr(feature = "clap", arg(long, default_value = "5"))]
     pub per_user_credit_cap: u32,
     #[cfg_attr(feature = "clap", arg(long, default_value = "5"))]
     pub max_breakdown_key: u32,
-    #[cfg_attr(feature = "clap", arg(long))]
+    /// Attribution window in seconds. It can be specified in hours or days too, using `h` or `d`
+    /// suffix respectively, see [`AttributionWindow`]. It must fit into `timestamp_bits` bits.
+    #[cfg_attr(
+        feature = "clap",
+        arg(
+            long,
+            visible_alias = "attribution-window",
+            value_parser = AttributionWindow::parse_seconds
+        )
+    )]
+    #[serde(
+        default,
+        alias = "attribution_window",
+        deserialize_with = "AttributionWindow::deserialize_seconds"
+    )]
     pub attribution_window_seconds: Option<NonZeroU32>,
     #[cfg_attr(feature = "clap", arg(long, default_value = "3"))]
     pub num_multi_bits: u32,
@@ -257,6 +658,155 @@ pub struct IpaQueryConfig {
     #[cfg_attr(feature = "clap", arg(long))]
     #[serde(default)]
     pub plaintext_match_keys: bool,
+
+    /// If set, only encrypted reports from this epoch are accepted, others are skipped.
+    #[cfg_attr(feature = "clap", arg(long))]
+    #[serde(default)]
+    pub epoch: Option<Epoch>,
+    /// If set, only reports encrypted with this key are accepted, others are skipped.
+    #[cfg_attr(feature = "clap", arg(long))]
+    #[serde(default)]
+    pub key_id: Option<KeyIdentifier>,
+
+    /// If set, OPRF IPA adds noise to the total of every breakdown, so the output is
+    /// (`dp_epsilon`, `dp_delta`)-differentially private with respect to a single user. Otherwise
+    /// the output is exact.
+    #[cfg_attr(feature = "clap", arg(long))]
+    #[serde(default)]
+    pub dp_epsilon: Option<DpParameter>,
+    /// Delta parameter of differential privacy. Only used if `dp_epsilon` is set, must be less
+    /// than 1.
+    #[cfg_attr(feature = "clap", arg(long, default_value = "1e-7"))]
+    #[serde(default = "IpaQueryConfig::default_dp_delta")]
+    pub dp_delta: DpParameter,
+    /// If set, OPRF IPA adds dummy users with every number of rows from 1 to this, before PRF
+    /// values are revealed. That makes the number of users with each number of rows
+    /// (`dp_epsilon`, `dp_delta`)-differentially private. Requires `dp_epsilon`, at most 64.
+    /// Rows are shuffled so dummy users can't be told apart from real ones, and sorted by
+    /// timestamp again afterwards, so this requires timestamps too.
+    #[cfg_attr(feature = "clap", arg(long))]
+    #[serde(default)]
+    pub dp_padding_max_user_rows: Option<NonZeroU32>,
+
+    /// Width of the breakdown key in OPRF IPA input reports. Supported values are 4, 5 and 8.
+    /// `max_breakdown_key` must fit into this many bits.
+    #[cfg_attr(feature = "clap", arg(long, default_value = "8"))]
+    #[serde(default = "IpaQueryConfig::default_breakdown_key_bits")]
+    pub breakdown_key_bits: u32,
+    /// Width of the trigger value in OPRF IPA input reports. Supported values are 3, 8, 16 and 32.
+    /// Use 16 or 32 bits for trigger values like revenue in cents, together with a matching
+    /// `per_user_credit_cap`.
+    #[cfg_attr(feature = "clap", arg(long, default_value = "3"))]
+    #[serde(default = "IpaQueryConfig::default_trigger_value_bits")]
+    pub trigger_value_bits: u32,
+    /// Width of the timestamp in OPRF IPA input reports. Only 20 is supported at the moment. 0
+    /// declares reports without timestamps, for queries with no attribution window, time buckets
+    /// or sorting by timestamp.
+    #[cfg_attr(feature = "clap", arg(long, default_value = "20"))]
+    #[serde(default = "IpaQueryConfig::default_timestamp_bits")]
+    pub timestamp_bits: u32,
+    /// Width of the source event priority in OPRF IPA input reports. Only 8 is supported. 0
+    /// declares reports without priorities, whose source events with the same timestamp are not
+    /// ordered by priority. Helpers that predate priorities don't send this field, their queries
+    /// declare reports without priorities.
+    #[cfg_attr(feature = "clap", arg(long, default_value = "8"))]
+    #[serde(default)]
+    pub priority_bits: u32,
+
+    /// If set, OPRF IPA stops attributing new users this many seconds after helpers start
+    /// running the query and releases the result over users processed by then, marked as
+    /// partial. Otherwise, all users are processed. Not supported with compact gate.
+    #[cfg_attr(feature = "clap", arg(long))]
+    #[serde(default)]
+    pub deadline_seconds: Option<NonZeroU32>,
+
+    /// If set, OPRF IPA also counts users with at least one attributed conversion in every
+    /// breakdown, regardless of conversion values, and returns the counts after the totals. Each
+    /// user is counted once, towards the breakdown of their first attributed conversion. Not
+    /// supported together with `deadline_seconds`.
+    #[cfg_attr(feature = "clap", arg(long))]
+    #[serde(default)]
+    pub count_attributed_users: bool,
+
+    /// If set, OPRF IPA splits rows of every user into segments of at most this many rows and
+    /// attributes each segment as a separate user, so users with many rows don't slow down the
+    /// whole query. Trigger events are only attributed to source events in the same segment.
+    /// Not supported together with `count_attributed_users`.
+    #[cfg_attr(feature = "clap", arg(long))]
+    #[serde(default)]
+    pub user_segment_rows: Option<NonZeroU32>,
+    /// Maximum number of segments of a single user, only used if `user_segment_rows` is set.
+    /// Every segment is capped at `per_user_credit_cap / max_user_segments`, so a user
+    /// contributes at most `per_user_credit_cap` in total, and rows beyond the last segment are
+    /// dropped. Must not exceed `per_user_credit_cap`.
+    #[cfg_attr(feature = "clap", arg(long, default_value = "4"))]
+    #[serde(default = "IpaQueryConfig::default_max_user_segments")]
+    pub max_user_segments: NonZeroU32,
+
+    /// If set, OPRF IPA divides per-breakdown totals by this value before revealing them,
+    /// rounding stochastically, so totals are reported in coarser units than trigger values, for
+    /// example dollars instead of cents. Rounding is unbiased, but every scaled total may be off
+    /// by up to 2. Requires a field large enough to hold noisy totals along with a random mask.
+    #[cfg_attr(feature = "clap", arg(long))]
+    #[serde(default)]
+    pub output_divisor: Option<NonZeroU32>,
+
+    /// If set, OPRF IPA only aggregates contributions attributed to these breakdown keys, as a
+    /// comma-separated list. Contributions to other breakdowns are zeroed out obliviously after
+    /// attribution, so their totals are zero. Every key in the list costs `breakdown_key_bits - 1`
+    /// multiplications per attributed row.
+    #[cfg_attr(feature = "clap", arg(long))]
+    #[serde(default)]
+    pub breakdown_allow_list: Option<BreakdownKeySet>,
+
+    /// If set, OPRF IPA keys totals by the time bucket of the trigger event as well as by
+    /// breakdown, with buckets this many days long that start at local midnight. Totals of every
+    /// breakdown come one per bucket, in chronological order, and the shape of the output is
+    /// returned along with it. Not supported together with `deadline_seconds`, nor with compact
+    /// gate.
+    #[cfg_attr(feature = "clap", arg(long))]
+    #[serde(default)]
+    pub time_bucket_days: Option<NonZeroU32>,
+    /// Seconds since Unix epoch, UTC, that timestamp 0 of input reports corresponds to, no later
+    /// than 9999-12-31T23:59:59Z. Only used if `time_bucket_days` is set.
+    #[cfg_attr(feature = "clap", arg(long, default_value = "0"))]
+    #[serde(default)]
+    pub query_epoch_seconds: u64,
+    /// UTC offset of the time zone local midnight is taken from, in seconds, within +/- 14 hours.
+    /// Only used if `time_bucket_days` is set.
+    #[cfg_attr(
+        feature = "clap",
+        arg(long, default_value = "0", allow_negative_numbers = true)
+    )]
+    #[serde(default)]
+    pub utc_offset_seconds: i32,
+
+    /// What OPRF IPA does with encrypted reports whose breakdown key does not fit into
+    /// `breakdown_key_bits`. Keys are stored in whole bytes, so only keys that are wider than
+    /// configured but still fit into the same number of bytes can be detected. Reports that are
+    /// not encrypted fail the query if they have such keys. `overflow` requires
+    /// `max_breakdown_key` to fit into `breakdown_key_bits` as well.
+    #[cfg_attr(feature = "clap", arg(long, value_enum, default_value_t))]
+    #[serde(default)]
+    pub breakdown_key_overflow: BreakdownKeyOverflow,
+
+    /// If set, OPRF IPA obliviously sorts rows of every user by timestamp before attributing
+    /// them, for report collectors that can't submit reports in time order. Sorting `n` rows of
+    /// a user takes `O(n log^2 n)` comparisons of timestamps. Rows with the same timestamp end up
+    /// in no particular order. Users can have at most 64 rows.
+    #[cfg_attr(feature = "clap", arg(long))]
+    #[serde(default)]
+    pub sort_by_timestamp: bool,
+
+    /// If set, OPRF IPA input reports carry the pseudonyms of their users that helpers exported
+    /// in an earlier [`QueryType::OprfPseudonyms`] query, in place of match key shares. Helpers
+    /// skip PRF evaluation and group rows of the same user by their pseudonym. Pseudonyms are
+    /// checked against `epoch`, so this requires it, and can't be combined with padding with
+    /// dummy users, who have no pseudonyms. Reports are laid out like those with plaintext match
+    /// keys.
+    #[cfg_attr(feature = "clap", arg(long))]
+    #[serde(default)]
+    pub pseudonymized_input: bool,
 }
 
 impl Default for IpaQueryConfig {
@@ -267,11 +817,142 @@ impl Default for IpaQueryConfig {
             attribution_window_seconds: None,
             num_multi_bits: 3,
             plaintext_match_keys: false,
+            epoch: None,
+            key_id: None,
+            dp_epsilon: None,
+            dp_delta: Self::DEFAULT_DP_DELTA,
+            dp_padding_max_user_rows: None,
+            breakdown_key_bits: Self::DEFAULT_BREAKDOWN_KEY_BITS,
+            trigger_value_bits: Self::DEFAULT_TRIGGER_VALUE_BITS,
+            timestamp_bits: Self::DEFAULT_TIMESTAMP_BITS,
+            priority_bits: Self::DEFAULT_PRIORITY_BITS,
+            deadline_seconds: None,
+            count_attributed_users: false,
+            user_segment_rows: None,
+            max_user_segments: Self::DEFAULT_MAX_USER_SEGMENTS,
+            output_divisor: None,
+            breakdown_allow_list: None,
+            time_bucket_days: None,
+            query_epoch_seconds: 0,
+            utc_offset_seconds: 0,
+            breakdown_key_overflow: BreakdownKeyOverflow::Skip,
+            sort_by_timestamp: false,
+            pseudonymized_input: false,
         }
     }
 }
 
 impl IpaQueryConfig {
+    pub const DEFAULT_BREAKDOWN_KEY_BITS: u32 = 8;
+    pub const DEFAULT_TRIGGER_VALUE_BITS: u32 = 3;
+    pub const DEFAULT_TIMESTAMP_BITS: u32 = 20;
+    pub const DEFAULT_PRIORITY_BITS: u32 = 8;
+    pub const DEFAULT_DP_DELTA: DpParameter = DpParameter(1e-7);
+    pub const DEFAULT_MAX_USER_SEGMENTS: NonZeroU32 = match NonZeroU32::new(4) {
+        Some(v) => v,
+        None => unreachable!(),
+    };
+
+    fn default_breakdown_key_bits() -> u32 {
+        Self::DEFAULT_BREAKDOWN_KEY_BITS
+    }
+
+    fn default_trigger_value_bits() -> u32 {
+        Self::DEFAULT_TRIGGER_VALUE_BITS
+    }
+
+    fn default_timestamp_bits() -> u32 {
+        Self::DEFAULT_TIMESTAMP_BITS
+    }
+
+    fn default_dp_delta() -> DpParameter {
+        Self::DEFAULT_DP_DELTA
+    }
+
+    fn default_max_user_segments() -> NonZeroU32 {
+        Self::DEFAULT_MAX_USER_SEGMENTS
+    }
+
+    /// Returns the layout of input reports this query declares.
+    #[must_use]
+    pub fn report_schema(&self) -> ReportSchema {
+        ReportSchema {
+            breakdown_key_bits: self.breakdown_key_bits,
+            trigger_value_bits: self.trigger_value_bits,
+            timestamp_bits: (self.timestamp_bits > 0).then_some(self.timestamp_bits),
+            priority_bits: (self.priority_bits > 0).then_some(self.priority_bits),
+        }
+    }
+
+    /// Checks that the per-user cap is positive, that the attribution window can be compared
+    /// against the difference of two timestamps, i.e. it fits into `timestamp_bits` bits, that
+    /// reports have timestamps if the query needs them, that their priorities are supported, that
+    /// pseudonymized input can be checked and that this build supports the features the query
+    /// asks for.
+    ///
+    /// ## Errors
+    /// If the per-user cap is 0, the attribution window is too large, reports lack timestamps the
+    /// query needs, their priorities are not 8 bits wide, the query epoch or the UTC offset of
+    /// time buckets are out of range, pseudonymized input comes without an epoch or with
+    /// padding, or the query has a deadline in a build with compact gate.
+    pub fn validate(&self) -> Result<(), QueryConfigError> {
+        if self.per_user_credit_cap == 0 {
+            return Err(QueryConfigError::ZeroCreditCap);
+        }
+        time_bucket::check_query_epoch(self.query_epoch_seconds)?;
+        time_bucket::check_utc_offset(self.utc_offset_seconds)?;
+        if ![0, Self::DEFAULT_PRIORITY_BITS].contains(&self.priority_bits) {
+            return Err(QueryConfigError::UnsupportedPriorityWidth(
+                self.priority_bits,
+            ));
+        }
+        if self.timestamp_bits == 0 {
+            if self.attribution_window_seconds.is_some() {
+                return Err(QueryConfigError::TimestampsRequired("attribution window"));
+            }
+            if self.time_bucket_days.is_some() {
+                return Err(QueryConfigError::TimestampsRequired(
+                    "keying totals by time bucket",
+                ));
+            }
+            if self.sort_by_timestamp {
+                return Err(QueryConfigError::TimestampsRequired("sorting by timestamp"));
+            }
+            if self.dp_padding_max_user_rows.is_some() {
+                return Err(QueryConfigError::TimestampsRequired(
+                    "padding with dummy users",
+                ));
+            }
+        }
+        #[cfg(not(feature = "descriptive-gate"))]
+        if self.deadline_seconds.is_some() {
+            return Err(QueryConfigError::UnsupportedWithCompactGate(
+                "query deadline",
+            ));
+        }
+        if self.pseudonymized_input {
+            if self.epoch.is_none() {
+                return Err(QueryConfigError::PseudonymizedInput(
+                    "requires the epoch to check pseudonyms against",
+                ));
+            }
+            if self.dp_padding_max_user_rows.is_some() {
+                return Err(QueryConfigError::PseudonymizedInput(
+                    "can't be padded with dummy users",
+                ));
+            }
+        }
+        match self.attribution_window_seconds {
+            Some(window) if window.get().checked_shr(self.timestamp_bits).unwrap_or(0) != 0 => {
+                Err(QueryConfigError::AttributionWindowTooLarge {
+                    window: window.get(),
+                    timestamp_bits: self.timestamp_bits,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
     /// ## Panics
     /// If attribution window is 0
     #[must_use]
@@ -290,6 +971,27 @@ impl IpaQueryConfig {
             ),
             num_multi_bits,
             plaintext_match_keys: false,
+            epoch: None,
+            key_id: None,
+            dp_epsilon: None,
+            dp_delta: Self::DEFAULT_DP_DELTA,
+            dp_padding_max_user_rows: None,
+            breakdown_key_bits: Self::DEFAULT_BREAKDOWN_KEY_BITS,
+            trigger_value_bits: Self::DEFAULT_TRIGGER_VALUE_BITS,
+            timestamp_bits: Self::DEFAULT_TIMESTAMP_BITS,
+            priority_bits: Self::DEFAULT_PRIORITY_BITS,
+            deadline_seconds: None,
+            count_attributed_users: false,
+            user_segment_rows: None,
+            max_user_segments: Self::DEFAULT_MAX_USER_SEGMENTS,
+            output_divisor: None,
+            breakdown_allow_list: None,
+            time_bucket_days: None,
+            query_epoch_seconds: 0,
+            utc_offset_seconds: 0,
+            breakdown_key_overflow: BreakdownKeyOverflow::Skip,
+            sort_by_timestamp: false,
+            pseudonymized_input: false,
         }
     }
 
@@ -309,6 +1011,27 @@ impl IpaQueryConfig {
             attribution_window_seconds: None,
             num_multi_bits,
             plaintext_match_keys: false,
+            epoch: None,
+            key_id: None,
+            dp_epsilon: None,
+            dp_delta: Self::DEFAULT_DP_DELTA,
+            dp_padding_max_user_rows: None,
+            breakdown_key_bits: Self::DEFAULT_BREAKDOWN_KEY_BITS,
+            trigger_value_bits: Self::DEFAULT_TRIGGER_VALUE_BITS,
+            timestamp_bits: Self::DEFAULT_TIMESTAMP_BITS,
+            priority_bits: Self::DEFAULT_PRIORITY_BITS,
+            deadline_seconds: None,
+            count_attributed_users: false,
+            user_segment_rows: None,
+            max_user_segments: Self::DEFAULT_MAX_USER_SEGMENTS,
+            output_divisor: None,
+            breakdown_allow_list: None,
+            time_bucket_days: None,
+            query_epoch_seconds: 0,
+            utc_offset_seconds: 0,
+            breakdown_key_overflow: BreakdownKeyOverflow::Skip,
+            sort_by_timestamp: false,
+            pseudonymized_input: false,
         }
     }
 }
@@ -358,3 +1081,264 @@ impl Default for SparseAggregateQueryConfig {
         }
     }
 }
+
+/// Configuration for the query that stops after computing the PRF of match keys and exports
+/// encrypted pseudonyms instead of running attribution.
+///
+/// PRF is computed under the key helpers keep across queries, so the same match key gets the same
+/// pseudonym in every query, and pseudonyms can be submitted in place of match keys, see
+/// [`IpaQueryConfig::pseudonymized_input`].
+///
+/// Pseudonyms link reports of the same user across every query they are used in, so exporting
+/// them must be requested explicitly by setting `allow_linkable_output`, and their lifetime is
+/// limited to at most [`MAX_VALIDITY_EPOCHS`] epochs.
+///
+/// [`MAX_VALIDITY_EPOCHS`]: Self::MAX_VALIDITY_EPOCHS
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "clap", derive(clap::Args))]
+pub struct PseudonymQueryConfig {
+    /// First epoch in which the exported pseudonyms can be used.
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub epoch: Epoch,
+    /// Number of consecutive epochs, starting at `epoch`, in which the exported pseudonyms can be
+    /// used. Must be within `[1, MAX_VALIDITY_EPOCHS]`.
+    #[cfg_attr(feature = "clap", arg(long, default_value = "1"))]
+    pub validity_epochs: u16,
+    /// Acknowledges that the query output allows linking reports of the same user. Helpers
+    /// refuse to run the query unless this is set.
+    #[cfg_attr(feature = "clap", arg(long))]
+    #[cfg_attr(feature = "enable-serde", serde(default))]
+    pub allow_linkable_output: bool,
+}
+
+impl PseudonymQueryConfig {
+    pub const MAX_VALIDITY_EPOCHS: u16 = 4;
+
+    /// Last epoch in which the exported pseudonyms can be used, if the configuration is valid.
+    #[must_use]
+    pub fn last_epoch(&self) -> Option<Epoch> {
+        if (1..=Self::MAX_VALIDITY_EPOCHS).contains(&self.validity_epochs) {
+            self.epoch.checked_add(self.validity_epochs - 1)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use super::{
+        AttributionWindow, BreakdownKeySet, DpParameter, IpaQueryConfig, QueryConfigError,
+    };
+
+    #[test]
+    fn parse_attribution_window() {
+        for (spec, seconds) in [
+            ("3600", 3600),
+            ("3600s", 3600),
+            ("12h", 43_200),
+            (" 7d ", 604_800),
+        ] {
+            assert_eq!(
+                seconds,
+                AttributionWindow::parse_seconds(spec).unwrap().get(),
+                "{spec}"
+            );
+        }
+        for spec in ["", "0", "-1", "1w", "d", "1.5h"] {
+            assert!(AttributionWindow::parse_seconds(spec).is_err(), "{spec}");
+        }
+        assert!(AttributionWindow::parse_seconds("50000d").is_err());
+    }
+
+    #[test]
+    fn attribution_window_fits_timestamp() {
+        let config = |window: u32| IpaQueryConfig {
+            attribution_window_seconds: NonZeroU32::new(window),
+            timestamp_bits: 20,
+            ..Default::default()
+        };
+
+        config((1 << 20) - 1).validate().unwrap();
+        config(0).validate().unwrap();
+        assert!(matches!(
+            config(1 << 20).validate(),
+            Err(QueryConfigError::AttributionWindowTooLarge {
+                window: 1_048_576,
+                timestamp_bits: 20
+            })
+        ));
+    }
+
+    #[test]
+    fn reports_without_timestamps() {
+        let config = IpaQueryConfig {
+            timestamp_bits: 0,
+            ..Default::default()
+        };
+        config.validate().unwrap();
+        assert_eq!(None, config.report_schema().timestamp_bits);
+        assert_eq!(
+            Some(20),
+            IpaQueryConfig::default().report_schema().timestamp_bits
+        );
+
+        for config in [
+            IpaQueryConfig {
+                attribution_window_seconds: NonZeroU32::new(1),
+                ..config
+            },
+            IpaQueryConfig {
+                time_bucket_days: NonZeroU32::new(1),
+                ..config
+            },
+            IpaQueryConfig {
+                sort_by_timestamp: true,
+                ..config
+            },
+            IpaQueryConfig {
+                dp_padding_max_user_rows: NonZeroU32::new(1),
+                ..config
+            },
+        ] {
+            assert!(matches!(
+                config.validate(),
+                Err(QueryConfigError::TimestampsRequired(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn report_priorities() {
+        assert_eq!(
+            Some(8),
+            IpaQueryConfig::default().report_schema().priority_bits
+        );
+        let config = |priority_bits: u32| IpaQueryConfig {
+            priority_bits,
+            ..Default::default()
+        };
+        config(0).validate().unwrap();
+        assert_eq!(None, config(0).report_schema().priority_bits);
+        assert!(matches!(
+            config(16).validate(),
+            Err(QueryConfigError::UnsupportedPriorityWidth(16))
+        ));
+    }
+
+    #[test]
+    fn zero_credit_cap() {
+        let config = IpaQueryConfig {
+            per_user_credit_cap: 0,
+            ..Default::default()
+        };
+        assert!(matches!(
+            config.validate(),
+            Err(QueryConfigError::ZeroCreditCap)
+        ));
+    }
+
+    #[test]
+    fn time_bucket_parameters() {
+        for config in [
+            IpaQueryConfig {
+                query_epoch_seconds: u64::MAX,
+                ..Default::default()
+            },
+            IpaQueryConfig {
+                utc_offset_seconds: i32::MIN,
+                ..Default::default()
+            },
+            IpaQueryConfig {
+                utc_offset_seconds: 15 * 3600,
+                ..Default::default()
+            },
+        ] {
+            assert!(matches!(
+                config.validate(),
+                Err(QueryConfigError::TimeBuckets(_))
+            ));
+        }
+
+        IpaQueryConfig {
+            query_epoch_seconds: 1_700_000_000,
+            utc_offset_seconds: -8 * 3600,
+            ..Default::default()
+        }
+        .validate()
+        .unwrap();
+    }
+
+    #[test]
+    fn deadline() {
+        let config = IpaQueryConfig {
+            deadline_seconds: NonZeroU32::new(60),
+            ..Default::default()
+        };
+        #[cfg(feature = "descriptive-gate")]
+        config.validate().unwrap();
+        #[cfg(not(feature = "descriptive-gate"))]
+        assert!(matches!(
+            config.validate(),
+            Err(QueryConfigError::UnsupportedWithCompactGate(_))
+        ));
+    }
+
+    #[test]
+    fn pseudonymized_input() {
+        let config = IpaQueryConfig {
+            pseudonymized_input: true,
+            epoch: Some(3),
+            ..Default::default()
+        };
+        config.validate().unwrap();
+
+        for config in [
+            IpaQueryConfig {
+                epoch: None,
+                ..config
+            },
+            IpaQueryConfig {
+                dp_padding_max_user_rows: NonZeroU32::new(1),
+                ..config
+            },
+        ] {
+            assert!(matches!(
+                config.validate(),
+                Err(QueryConfigError::PseudonymizedInput(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn parse_dp_parameter() {
+        assert_eq!(Ok(0.5), "0.5".parse::<DpParameter>().map(f64::from));
+        assert_eq!(Ok(1e-7), "1e-7".parse::<DpParameter>().map(f64::from));
+        for spec in ["", "0", "-1", "NaN", "inf", "one"] {
+            assert!(spec.parse::<DpParameter>().is_err(), "{spec}");
+        }
+        assert_eq!(
+            IpaQueryConfig::DEFAULT_DP_DELTA,
+            IpaQueryConfig::DEFAULT_DP_DELTA
+                .to_string()
+                .parse()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_breakdown_key_set() {
+        let keys = " 7, 0,255,7 ".parse::<BreakdownKeySet>().unwrap();
+        assert_eq!(vec![0, 7, 255], keys.iter().collect::<Vec<_>>());
+        assert!(keys.contains(255) && !keys.contains(1) && !keys.contains(256));
+        assert_eq!("0,7,255", keys.to_string());
+        assert_eq!(keys, keys.to_string().parse().unwrap());
+        assert!("".parse::<BreakdownKeySet>().unwrap().is_empty());
+        for spec in ["256", "-1", "1;2", "a"] {
+            assert!(spec.parse::<BreakdownKeySet>().is_err(), "{spec}");
+        }
+    }
+}
@@ -9,7 +9,13 @@ use tracing::error;
 
 use crate::{
     error::BoxError,
-    helpers::transport::stream::{StreamCollection, StreamKey},
+    helpers::{
+        transport::{
+            stream::{StreamCollection, StreamKey},
+            TransportIdentity,
+        },
+        HelperIdentity,
+    },
 };
 
 /// Adapt a stream of `Result<T: Into<Vec<u8>>, Error>` to a stream of `Vec<u8>`.
@@ -66,19 +72,19 @@ where
 /// If stream is not received yet, each poll generates a waker that is used internally to wake up
 /// the task when stream is received.
 /// Once stream is received, it is moved to this struct and it acts as a proxy to it.
-pub struct ReceiveRecords<S> {
-    inner: ReceiveRecordsInner<S>,
+pub struct ReceiveRecords<S, I = HelperIdentity> {
+    inner: ReceiveRecordsInner<S, I>,
 }
 
-impl<S> ReceiveRecords<S> {
-    pub(crate) fn new(key: StreamKey, coll: StreamCollection<S>) -> Self {
+impl<S, I> ReceiveRecords<S, I> {
+    pub(crate) fn new(key: StreamKey<I>, coll: StreamCollection<S, I>) -> Self {
         Self {
             inner: ReceiveRecordsInner::Pending(key, coll),
         }
     }
 }
 
-impl<S: Stream + Unpin> Stream for ReceiveRecords<S> {
+impl<S: Stream + Unpin, I: TransportIdentity> Stream for ReceiveRecords<S, I> {
     type Item = S::Item;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
@@ -87,12 +93,12 @@ impl<S: Stream + Unpin> Stream for ReceiveRecords<S> {
 }
 
 /// Inner state for [`ReceiveRecords`] struct
-enum ReceiveRecordsInner<S> {
-    Pending(StreamKey, StreamCollection<S>),
+enum ReceiveRecordsInner<S, I> {
+    Pending(StreamKey<I>, StreamCollection<S, I>),
     Ready(S),
 }
 
-impl<S: Stream + Unpin> Stream for ReceiveRecordsInner<S> {
+impl<S: Stream + Unpin, I: TransportIdentity> Stream for ReceiveRecordsInner<S, I> {
     type Item = S::Item;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
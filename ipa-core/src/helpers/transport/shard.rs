@@ -0,0 +1,272 @@
+//! Transport between shards of the same helper.
+//!
+//! To handle large queries, a helper may be split into several shards that process parts of the
+//! input in parallel. Shards of the same helper trust each other, so records they exchange don't
+//! go through the helper-to-helper [`Transport`]. [`ShardTransport`] is the counterpart of it for
+//! shards, and [`InProcessShardTransport`] implements it for shards that run in the same process.
+//!
+//! [`Transport`]: crate::helpers::Transport
+use std::{
+    fmt::{Debug, Formatter},
+    num::NonZeroU32,
+    pin::Pin,
+};
+
+use async_trait::async_trait;
+use futures::Stream;
+
+use crate::{
+    helpers::{
+        transport::TransportIdentity, NoResourceIdentifier, ReceiveRecords, RouteParams,
+        StreamCollection,
+    },
+    protocol::{step::Gate, QueryId},
+};
+
+/// Represents an opaque identifier of a shard within a helper. Compare with [`HelperIdentity`]:
+/// there are always three helpers, but the number of shards is picked when helper starts. Shards
+/// are numbered from zero.
+///
+/// [`HelperIdentity`]: crate::helpers::HelperIdentity
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+#[cfg_attr(
+    feature = "enable-serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(transparent)
+)]
+pub struct ShardIdentity(u32);
+
+impl ShardIdentity {
+    pub const FIRST: Self = Self(0);
+
+    /// Returns identities of all shards of a helper that has `count` of them.
+    pub fn all(count: NonZeroU32) -> impl Iterator<Item = Self> {
+        (0..count.get()).map(Self)
+    }
+}
+
+impl From<u32> for ShardIdentity {
+    fn from(value: u32) -> Self {
+        Self(value)
+    }
+}
+
+impl From<ShardIdentity> for u32 {
+    fn from(value: ShardIdentity) -> Self {
+        value.0
+    }
+}
+
+impl From<ShardIdentity> for usize {
+    fn from(value: ShardIdentity) -> Self {
+        usize::try_from(value.0).expect("u32 fits into usize")
+    }
+}
+
+impl Debug for ShardIdentity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "shard-{}", self.0)
+    }
+}
+
+impl TransportIdentity for ShardIdentity {}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ShardError {
+    #[error("{dest:?} does not exist, this helper has {count} shards")]
+    UnknownShard { dest: ShardIdentity, count: usize },
+}
+
+/// Transport that supports per-query, per-step channels between shards of the same helper.
+#[async_trait]
+pub trait ShardTransport: Clone + Send + Sync + 'static {
+    type RecordsStream: Stream<Item = Vec<u8>> + Send + Unpin;
+    type Error: Debug;
+
+    fn identity(&self) -> ShardIdentity;
+
+    /// Sends a stream of records to the given shard for the specific query and step.
+    async fn send<R, D>(&self, dest: ShardIdentity, route: R, data: D) -> Result<(), Self::Error>
+    where
+        R: RouteParams<NoResourceIdentifier, QueryId, Gate>,
+        D: Stream<Item = Vec<u8>> + Send + 'static;
+
+    /// Return the stream of records to be received from another shard for the specific query
+    /// and step
+    fn receive<R: RouteParams<NoResourceIdentifier, QueryId, Gate>>(
+        &self,
+        from: ShardIdentity,
+        route: R,
+    ) -> Self::RecordsStream;
+}
+
+type ShardStream = Pin<Box<dyn Stream<Item = Vec<u8>> + Send>>;
+
+/// Connects shards of a helper that run inside the same process. Record streams are handed over
+/// to the receiving shard as they are, nothing is copied or serialized.
+#[derive(Clone)]
+pub struct InProcessShardNetwork {
+    shards: Vec<StreamCollection<ShardStream, ShardIdentity>>,
+}
+
+/// [`ShardTransport`] for a single shard connected to [`InProcessShardNetwork`].
+#[derive(Clone)]
+pub struct InProcessShardTransport {
+    identity: ShardIdentity,
+    shards: Vec<StreamCollection<ShardStream, ShardIdentity>>,
+}
+
+impl InProcessShardNetwork {
+    #[must_use]
+    pub fn new(count: NonZeroU32) -> Self {
+        Self {
+            shards: ShardIdentity::all(count)
+                .map(|_| StreamCollection::default())
+                .collect(),
+        }
+    }
+
+    /// Returns the transport for the given shard.
+    ///
+    /// ## Panics
+    /// If there is no such shard in this network.
+    #[must_use]
+    pub fn transport(&self, id: ShardIdentity) -> InProcessShardTransport {
+        assert!(
+            usize::from(id) < self.shards.len(),
+            "No transport for {id:?}"
+        );
+        InProcessShardTransport {
+            identity: id,
+            shards: self.shards.clone(),
+        }
+    }
+
+    /// Returns transports for all shards, ordered by their identity.
+    #[must_use]
+    pub fn transports(&self) -> Vec<InProcessShardTransport> {
+        (0..self.shards.len())
+            .map(|i| self.transport(ShardIdentity(u32::try_from(i).unwrap())))
+            .collect()
+    }
+
+    /// Drops all streams that have not been received yet.
+    pub fn reset(&self) {
+        for streams in &self.shards {
+            streams.clear();
+        }
+    }
+}
+
+#[async_trait]
+impl ShardTransport for InProcessShardTransport {
+    type RecordsStream = ReceiveRecords<ShardStream, ShardIdentity>;
+    type Error = ShardError;
+
+    fn identity(&self) -> ShardIdentity {
+        self.identity
+    }
+
+    async fn send<R, D>(&self, dest: ShardIdentity, route: R, data: D) -> Result<(), Self::Error>
+    where
+        R: RouteParams<NoResourceIdentifier, QueryId, Gate>,
+        D: Stream<Item = Vec<u8>> + Send + 'static,
+    {
+        let streams = self
+            .shards
+            .get(usize::from(dest))
+            .ok_or(ShardError::UnknownShard {
+                dest,
+                count: self.shards.len(),
+            })?;
+        streams.add_stream(
+            (route.query_id(), self.identity, route.gate()),
+            Box::pin(data),
+        );
+
+        Ok(())
+    }
+
+    fn receive<R: RouteParams<NoResourceIdentifier, QueryId, Gate>>(
+        &self,
+        from: ShardIdentity,
+        route: R,
+    ) -> Self::RecordsStream {
+        ReceiveRecords::new(
+            (route.query_id(), from, route.gate()),
+            self.shards[usize::from(self.identity)].clone(),
+        )
+    }
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use futures::{stream, StreamExt};
+
+    use super::{InProcessShardNetwork, ShardError, ShardIdentity, ShardTransport};
+    use crate::protocol::{step::Gate, QueryId};
+
+    fn network(count: u32) -> InProcessShardNetwork {
+        InProcessShardNetwork::new(NonZeroU32::new(count).unwrap())
+    }
+
+    #[tokio::test]
+    async fn send_and_receive() {
+        let transports = network(3).transports();
+        let gate = Gate::default();
+        let from = ShardIdentity::from(2);
+
+        // receive before the stream is sent
+        let received = transports[1].receive(from, (QueryId::default(), gate.clone()));
+        transports[2]
+            .send(
+                transports[1].identity(),
+                (QueryId::default(), gate.clone()),
+                stream::iter(vec![vec![1, 2], vec![3]]),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            vec![vec![1, 2], vec![3]],
+            received.collect::<Vec<_>>().await
+        );
+
+        // any shard can send records to any other one
+        transports[0]
+            .send(
+                from,
+                (QueryId::default(), gate.clone()),
+                stream::iter(vec![vec![4]]),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![vec![4]],
+            transports[2]
+                .receive(ShardIdentity::FIRST, (QueryId::default(), gate))
+                .collect::<Vec<_>>()
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_shard() {
+        let transports = network(2).transports();
+        let err = transports[0]
+            .send(
+                ShardIdentity::from(2),
+                (QueryId::default(), Gate::default()),
+                stream::empty(),
+            )
+            .await
+            .unwrap_err();
+
+        assert!(matches!(
+            err,
+            ShardError::UnknownShard { dest, count: 2 } if dest == ShardIdentity::from(2)
+        ));
+    }
+}
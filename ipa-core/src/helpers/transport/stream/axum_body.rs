@@ -4,10 +4,11 @@ use std::{
 };
 
 use axum::extract::{BodyStream, FromRequest, RequestParts};
-use futures::{Stream, TryStreamExt};
+use futures::{Stream, StreamExt, TryStreamExt};
 use hyper::Body;
 use pin_project::pin_project;
 
+use super::BytesStream;
 use crate::error::BoxError;
 
 type AxumInner = futures::stream::MapErr<BodyStream, fn(axum::Error) -> crate::error::BoxError>;
@@ -27,6 +28,17 @@ impl WrappedAxumBodyStream {
     pub(super) fn new_internal(inner: BodyStream) -> Self {
         Self(inner.map_err(axum::Error::into_inner as fn(axum::Error) -> BoxError))
     }
+
+    /// Wrap an arbitrary stream of bytes, returning an instance of `crate::helpers::BodyStream`.
+    pub fn from_bytes_stream<S: BytesStream + 'static>(inner: S) -> Self {
+        Self::from_body(Body::wrap_stream(inner))
+    }
+
+    /// Returns a stream that yields all items from this stream, followed by items from `next`.
+    #[must_use]
+    pub fn append(self, next: Self) -> Self {
+        Self::from_body(Body::wrap_stream(self.chain(next)))
+    }
 }
 
 impl Stream for WrappedAxumBodyStream {
@@ -3,9 +3,9 @@ use std::{
     task::{Context, Poll},
 };
 
-use futures::Stream;
+use futures::{Stream, StreamExt};
 
-use crate::helpers::transport::stream::BoxBytesStream;
+use crate::helpers::transport::stream::{BoxBytesStream, BytesStream};
 
 pub struct WrappedBoxBodyStream(BoxBytesStream);
 
@@ -16,6 +16,17 @@ impl WrappedBoxBodyStream {
     pub fn new(inner: axum::extract::BodyStream) -> Self {
         Self(Box::pin(super::WrappedAxumBodyStream::new_internal(inner)))
     }
+
+    /// Wrap an arbitrary stream of bytes, returning an instance of `crate::helpers::BodyStream`.
+    pub fn from_bytes_stream<S: BytesStream + 'static>(inner: S) -> Self {
+        Self(Box::pin(inner))
+    }
+
+    /// Returns a stream that yields all items from this stream, followed by items from `next`.
+    #[must_use]
+    pub fn append(self, next: Self) -> Self {
+        Self(Box::pin(StreamExt::chain(self, next)))
+    }
 }
 
 impl Stream for WrappedBoxBodyStream {
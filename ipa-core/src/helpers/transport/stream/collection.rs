@@ -7,49 +7,91 @@ use std::{
 use futures::Stream;
 
 use crate::{
-    helpers::HelperIdentity,
+    helpers::{transport::TransportIdentity, HelperIdentity},
     protocol::{step::Gate, QueryId},
     sync::{Arc, Mutex},
 };
 
-/// Each stream is indexed by query id, the identity of helper where stream is originated from
-/// and step.
-pub type StreamKey = (QueryId, HelperIdentity, Gate);
+/// Each stream is indexed by query id, the identity of helper (or shard) where stream is
+/// originated from and step.
+pub type StreamKey<I = HelperIdentity> = (QueryId, I, Gate);
+
+/// Decides when [`StreamCollection`] forgets streams that have been received.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Received streams leave tombstones behind until the collection is cleared, so receiving a
+    /// stream twice or sending it after it has been received is caught.
+    #[default]
+    OnClear,
+    /// Streams of a gate are forgotten as soon as no stream of that gate is waiting to be
+    /// received, so the collection does not grow with the number of gates a query goes through.
+    /// Streams received twice or sent after they have been received are no longer caught once
+    /// their gate is evicted: receivers wait for them and senders' streams are never taken.
+    PerGate,
+}
 
 /// Thread-safe append-only collection of homogeneous record streams.
 /// Streams are indexed by [`StreamKey`] and the lifecycle of each stream is described by the
 /// [`StreamState`] struct.
 ///
 /// Each stream can be inserted and taken away exactly once, any deviation from this behaviour will
-/// result in panic.
-pub struct StreamCollection<S> {
-    inner: Arc<Mutex<HashMap<StreamKey, StreamState<S>>>>,
+/// result in panic, unless [`EvictionPolicy::PerGate`] has evicted the stream already.
+pub struct StreamCollection<S, I = HelperIdentity> {
+    inner: Arc<Mutex<HashMap<(QueryId, Gate), GateStreams<S, I>>>>,
+    eviction: EvictionPolicy,
 }
 
-impl<S> Default for StreamCollection<S> {
+/// Streams of a single gate, one per peer.
+struct GateStreams<S, I> {
+    streams: HashMap<I, StreamState<S>>,
+    /// Streams of this gate that have not been received yet, whether they arrived or not.
+    pending: usize,
+}
+
+impl<S, I> Default for GateStreams<S, I> {
     fn default() -> Self {
         Self {
-            inner: Arc::new(Mutex::new(HashMap::default())),
+            streams: HashMap::default(),
+            pending: 0,
         }
     }
 }
 
-impl<S> Clone for StreamCollection<S> {
+impl<S, I> Default for StreamCollection<S, I> {
+    fn default() -> Self {
+        Self::new(EvictionPolicy::default())
+    }
+}
+
+impl<S, I> Clone for StreamCollection<S, I> {
     fn clone(&self) -> Self {
         Self {
             inner: Arc::clone(&self.inner),
+            eviction: self.eviction,
         }
     }
 }
 
-impl<S: Stream> StreamCollection<S> {
+impl<S, I> StreamCollection<S, I> {
+    #[must_use]
+    pub fn new(eviction: EvictionPolicy) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(HashMap::default())),
+            eviction,
+        }
+    }
+}
+
+impl<S: Stream, I: TransportIdentity> StreamCollection<S, I> {
     /// Adds a new stream associated with the given key.
     ///
     /// ## Panics
     /// If there was another stream associated with the same key some time in the past.
-    pub fn add_stream(&self, key: StreamKey, stream: S) {
-        let mut streams = self.inner.lock().unwrap();
-        match streams.entry(key) {
+    pub fn add_stream(&self, key: StreamKey<I>, stream: S) {
+        let (query_id, origin, gate) = &key;
+        let mut gates = self.inner.lock().unwrap();
+        let gate_streams = gates.entry((*query_id, gate.clone())).or_default();
+        match gate_streams.streams.entry(*origin) {
             Entry::Occupied(mut entry) => match entry.get_mut() {
                 rs @ StreamState::Waiting(_) => {
                     let StreamState::Waiting(waker) =
@@ -61,32 +103,38 @@ impl<S: Stream> StreamCollection<S> {
                 }
                 rs @ (StreamState::Ready(_) | StreamState::Completed) => {
                     let state = format!("{rs:?}");
-                    let key = entry.key().clone();
-                    drop(streams);
+                    drop(gates);
                     panic!("{key:?} entry state expected to be waiting, got {state:?}");
                 }
             },
             Entry::Vacant(entry) => {
                 entry.insert(StreamState::Ready(stream));
+                gate_streams.pending += 1;
             }
         }
     }
 
     /// Adds a new waker to notify when the stream is ready. If stream is ready, this method takes
-    /// it out, leaving a tombstone in its place, and returns it.
+    /// it out, leaving a tombstone in its place, and returns it. The tombstone is removed along
+    /// with the rest of the gate if [`EvictionPolicy::PerGate`] is in effect and no other stream
+    /// of the gate is pending.
     ///
     /// ## Panics
     /// If [`Waker`] that exists already inside this collection will not wake the given one.
-    pub fn add_waker(&self, key: &StreamKey, waker: &Waker) -> Option<S> {
-        let mut streams = self.inner.lock().unwrap();
+    pub fn add_waker(&self, key: &StreamKey<I>, waker: &Waker) -> Option<S> {
+        let (query_id, origin, gate) = key;
+        let mut gates = self.inner.lock().unwrap();
+        let gate_key = (*query_id, gate.clone());
+        let gate_streams = gates.entry(gate_key.clone()).or_default();
 
-        match streams.entry(key.clone()) {
+        let stream = match gate_streams.streams.entry(*origin) {
             Entry::Occupied(mut entry) => {
                 match entry.get_mut() {
                     StreamState::Waiting(old_waker) => {
-                        let will_wake = old_waker.will_wake(waker);
-                        drop(streams); // avoid mutex poisoning
-                        assert!(will_wake);
+                        if !old_waker.will_wake(waker) {
+                            drop(gates); // avoid mutex poisoning
+                            panic!("{key:?} stream is awaited by another receiver");
+                        }
                         None
                     }
                     rs @ StreamState::Ready(_) => {
@@ -95,20 +143,30 @@ impl<S: Stream> StreamCollection<S> {
                         else {
                             unreachable!();
                         };
+                        gate_streams.pending -= 1;
 
                         Some(stream)
                     }
                     StreamState::Completed => {
-                        drop(streams);
+                        drop(gates);
                         panic!("{key:?} stream has been consumed already")
                     }
                 }
             }
             Entry::Vacant(entry) => {
                 entry.insert(StreamState::Waiting(waker.clone()));
+                gate_streams.pending += 1;
                 None
             }
+        };
+
+        if stream.is_some()
+            && self.eviction == EvictionPolicy::PerGate
+            && gates[&gate_key].pending == 0
+        {
+            gates.remove(&gate_key);
         }
+        stream
     }
 
     /// Clears up this collection, leaving no streams inside it.
@@ -116,8 +174,14 @@ impl<S: Stream> StreamCollection<S> {
     /// ## Panics
     /// if mutex is poisoned.
     pub fn clear(&self) {
-        let mut streams = self.inner.lock().unwrap();
-        streams.clear();
+        let mut gates = self.inner.lock().unwrap();
+        gates.clear();
+    }
+
+    /// Returns the number of gates this collection keeps streams or tombstones of.
+    #[cfg(all(test, unit_test))]
+    fn gates(&self) -> usize {
+        self.inner.lock().unwrap().len()
     }
 }
 
@@ -147,3 +211,52 @@ impl<S> Debug for StreamState<S> {
         }
     }
 }
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use futures::{stream, task::noop_waker};
+
+    use super::{EvictionPolicy, StreamCollection};
+    use crate::{
+        helpers::HelperIdentity,
+        protocol::{
+            step::{Gate, StepNarrow},
+            QueryId,
+        },
+    };
+
+    type TestStream = stream::Empty<u8>;
+
+    fn receive_all(eviction: EvictionPolicy) -> StreamCollection<TestStream> {
+        let streams = StreamCollection::new(eviction);
+        let waker = noop_waker();
+        for step in ["a", "b"] {
+            let gate = Gate::default().narrow(step);
+            let left = (QueryId::default(), HelperIdentity::ONE, gate.clone());
+            let right = (QueryId::default(), HelperIdentity::TWO, gate);
+
+            // one stream arrives before it is received, the other one after
+            streams.add_stream(left.clone(), stream::empty());
+            assert!(streams.add_waker(&right, &waker).is_none());
+            assert!(streams.add_waker(&left, &waker).is_some());
+            assert_eq!(1, streams.gates());
+            streams.add_stream(right.clone(), stream::empty());
+            assert!(streams.add_waker(&right, &waker).is_some());
+        }
+
+        streams
+    }
+
+    #[test]
+    fn evicts_received_gates() {
+        assert_eq!(0, receive_all(EvictionPolicy::PerGate).gates());
+    }
+
+    #[test]
+    fn keeps_tombstones_until_cleared() {
+        let streams = receive_all(EvictionPolicy::OnClear);
+        assert_eq!(2, streams.gates());
+        streams.clear();
+        assert_eq!(0, streams.gates());
+    }
+}
@@ -18,7 +18,12 @@ use generic_array::GenericArray;
 use pin_project::pin_project;
 use typenum::{Unsigned, U2};
 
-use crate::{error::BoxError, ff::Serializable, helpers::BytesStream};
+use crate::{
+    error::BoxError,
+    ff::{Error as FieldError, Serializable},
+    helpers::{BytesStream, MemoryBudget, MemoryReservation},
+    sync::Arc,
+};
 
 #[derive(Debug)]
 pub struct BufDeque {
@@ -94,11 +99,18 @@ impl BufDeque {
     ///
     /// Deserializes `count` items of fixed-length-[`Serializable`] type `T` from the stream.
     /// Returns `None` if there are less than `count` items available, or if `count` is zero.
-    fn read_multi<T: Serializable>(&mut self, count: usize) -> Option<Vec<T>> {
+    /// Returns an error with the index of the first item that is not a valid instance of `T`.
+    fn read_multi<T: Serializable>(
+        &mut self,
+        count: usize,
+    ) -> Option<Result<Vec<T>, (usize, FieldError)>> {
         self.read_bytes(count * T::Size::USIZE).map(|bytes| {
             bytes
                 .chunks(T::Size::USIZE)
-                .map(|bytes| T::deserialize(GenericArray::from_slice(bytes)))
+                .enumerate()
+                .map(|(i, bytes)| {
+                    T::try_deserialize(GenericArray::from_slice(bytes)).map_err(|e| (i, e))
+                })
                 .collect()
         })
     }
@@ -150,8 +162,60 @@ enum ExtendResult {
     Error(io::Error),
 }
 
+/// Why a [`RecordsStream`] rejected its input. Every variant points at the first record that could
+/// not be read, by its index and the byte offset it starts at.
+#[derive(Debug, thiserror::Error)]
+pub enum RecordsError {
+    #[error("record {index} at byte offset {offset} is invalid: {source}")]
+    Invalid {
+        index: usize,
+        offset: usize,
+        #[source]
+        source: FieldError,
+    },
+    #[error("record {index} at byte offset {offset} is truncated to {len} of {record_len} bytes")]
+    Truncated {
+        index: usize,
+        offset: usize,
+        record_len: usize,
+        len: usize,
+    },
+    #[error("stream ended before record {index} at byte offset {offset}, expected {expected}")]
+    TooFew {
+        expected: usize,
+        index: usize,
+        offset: usize,
+    },
+    #[error("unexpected record {index} at byte offset {offset}, expected {expected} records")]
+    TooMany {
+        expected: usize,
+        index: usize,
+        offset: usize,
+    },
+}
+
+impl RecordsError {
+    fn kind(&self) -> io::ErrorKind {
+        match self {
+            Self::Invalid { .. } | Self::TooMany { .. } => io::ErrorKind::InvalidData,
+            Self::Truncated { .. } => io::ErrorKind::WriteZero,
+            Self::TooFew { .. } => io::ErrorKind::UnexpectedEof,
+        }
+    }
+}
+
+impl From<RecordsError> for io::Error {
+    fn from(err: RecordsError) -> Self {
+        io::Error::new(err.kind(), err)
+    }
+}
+
 /// Parse a [`Stream`] of [`Bytes`] into a stream of records of some
 /// fixed-length-[`Serializable`] type `T`.
+///
+/// If the size of the input is known up front (see [`Self::with_content_length`] and
+/// [`Self::with_expected_records`]), inputs of the wrong size are rejected before any record is
+/// read, or as soon as the stream goes past it, rather than once the stream is consumed.
 #[pin_project]
 pub struct RecordsStream<T, S>
 where
@@ -165,6 +229,16 @@ where
     #[pin]
     stream: Fuse<S>,
     buffer: BufDeque,
+    /// Number of records read so far.
+    records: usize,
+    /// Number of records the stream must have, if known.
+    expected: Option<usize>,
+    /// Error found before reading anything, returned on the first poll.
+    rejected: Option<RecordsError>,
+    /// Set once the size of the stream was found to be wrong, the inner stream is not polled again.
+    failed: bool,
+    /// Accounts for buffered bytes, if the stream is read for a query with a memory budget.
+    memory: Option<MemoryReservation>,
     phantom_data: PhantomData<T>,
 }
 
@@ -178,9 +252,50 @@ where
         Self {
             stream: stream.fuse(),
             buffer: BufDeque::new(),
+            records: 0,
+            expected: None,
+            rejected: None,
+            failed: false,
+            memory: None,
             phantom_data: PhantomData,
         }
     }
+
+    /// Requires the stream to have exactly `records` records. Replaces the size set by an earlier
+    /// call to this or [`Self::with_content_length`].
+    #[must_use]
+    pub fn with_expected_records(mut self, records: usize) -> Self {
+        self.expected = Some(records);
+        self.rejected = None;
+        self
+    }
+
+    /// Requires the stream to be exactly `len` bytes long, as given by the `Content-Length` of the
+    /// request it is read from, for instance. If `len` is not a multiple of the record size, the
+    /// last record would be truncated, so the stream is rejected on the first poll, without
+    /// reading any of it.
+    #[must_use]
+    pub fn with_content_length(self, len: usize) -> Self {
+        let record_len = T::Size::USIZE;
+        let index = len / record_len;
+        let mut this = self.with_expected_records(index);
+        if len % record_len != 0 {
+            this.rejected = Some(RecordsError::Truncated {
+                index,
+                offset: index * record_len,
+                record_len,
+                len: len % record_len,
+            });
+        }
+        this
+    }
+
+    /// Accounts for the data this stream buffers in `budget`, until it is dropped.
+    #[must_use]
+    pub fn with_memory_budget(mut self, budget: &Arc<MemoryBudget>) -> Self {
+        self.memory = Some(budget.reserve(self.buffer.buffered_size));
+        self
+    }
 }
 
 impl<T, S> Stream for RecordsStream<T, S>
@@ -192,10 +307,49 @@ where
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let mut this = self.project();
+        if *this.failed {
+            return Poll::Ready(None);
+        }
+        if let Some(err) = this.rejected.take() {
+            *this.failed = true;
+            return Poll::Ready(Some(Err(err.into())));
+        }
+
+        let record_len = T::Size::USIZE;
+        let at = |index: usize| (index, index * record_len);
         loop {
-            let count = max(1, this.buffer.contiguous_len() / T::Size::USIZE);
+            if let Some(memory) = this.memory.as_mut() {
+                memory.resize(this.buffer.buffered_size);
+            }
+            let remaining = this.expected.map_or(usize::MAX, |n| n - *this.records);
+            if remaining == 0 && this.buffer.buffered_size > 0 {
+                // Reject extra data as soon as it arrives, rather than reading all of it.
+                let (index, offset) = at(*this.records);
+                *this.failed = true;
+                return Poll::Ready(Some(Err(RecordsError::TooMany {
+                    expected: index,
+                    index,
+                    offset,
+                }
+                .into())));
+            }
+
+            let count = max(1, this.buffer.contiguous_len() / record_len).min(remaining);
             if let Some(items) = this.buffer.read_multi(count) {
-                return Poll::Ready(Some(Ok(items)));
+                if let Some(memory) = this.memory.as_mut() {
+                    memory.resize(this.buffer.buffered_size);
+                }
+                let first = *this.records;
+                *this.records += count;
+                return Poll::Ready(Some(items.map_err(|(i, source)| {
+                    let (index, offset) = at(first + i);
+                    RecordsError::Invalid {
+                        index,
+                        offset,
+                        source,
+                    }
+                    .into()
+                })));
             }
 
             // We need more data, poll the stream
@@ -203,11 +357,27 @@ where
                 return Poll::Pending;
             };
 
-            match this.buffer.extend(polled_item) {
-                ExtendResult::Finished => return Poll::Ready(None),
-                ExtendResult::Error(err) => return Poll::Ready(Some(Err(err))),
-                ExtendResult::Ok => (),
-            }
+            let (index, offset) = at(*this.records);
+            let err = match (polled_item, *this.expected) {
+                (None, _) if this.buffer.buffered_size > 0 => RecordsError::Truncated {
+                    index,
+                    offset,
+                    record_len,
+                    len: this.buffer.buffered_size,
+                },
+                (None, Some(expected)) if index < expected => RecordsError::TooFew {
+                    expected,
+                    index,
+                    offset,
+                },
+                (polled_item, _) => match this.buffer.extend(polled_item) {
+                    ExtendResult::Finished => return Poll::Ready(None),
+                    ExtendResult::Error(err) => return Poll::Ready(Some(Err(err))),
+                    ExtendResult::Ok => continue,
+                },
+            };
+            *this.failed = true;
+            return Poll::Ready(Some(Err(err.into())));
         }
     }
 }
@@ -218,7 +388,7 @@ where
     T: Serializable,
 {
     fn is_terminated(&self) -> bool {
-        self.stream.is_terminated()
+        self.failed || self.stream.is_terminated()
     }
 }
 
@@ -451,7 +621,7 @@ mod test {
     use super::*;
 
     mod unit_test {
-        use futures::{StreamExt, TryStreamExt};
+        use futures::{stream::pending, StreamExt, TryStreamExt};
         use generic_array::GenericArray;
 
         use super::*;
@@ -471,6 +641,14 @@ mod test {
             assert_eq!(collected[0], vec![Fp31::try_from(3).unwrap(); 10]);
         }
 
+        #[tokio::test]
+        async fn records_stream_invalid_value() {
+            // 31 is not a valid Fp31 value
+            let stream = RecordsStream::<Fp31, _>::from(vec![3, 31, 4]);
+            let err = stream.try_collect::<Vec<_>>().await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+
         #[tokio::test]
         async fn records_stream_fp32_bit_prime() {
             const ARR_SIZE: usize = 50;
@@ -504,6 +682,104 @@ mod test {
             assert_eq!(err.kind(), io::ErrorKind::WriteZero);
         }
 
+        fn records_error(err: &io::Error) -> &RecordsError {
+            err.get_ref().unwrap().downcast_ref().unwrap()
+        }
+
+        #[tokio::test]
+        async fn records_stream_locates_invalid_value() {
+            let stream = RecordsStream::<Fp31, _>::from(vec![3, 4, 31, 5]);
+            let err = stream.try_collect::<Vec<_>>().await.unwrap_err();
+            assert!(matches!(
+                records_error(&err),
+                RecordsError::Invalid {
+                    index: 2,
+                    offset: 2,
+                    ..
+                }
+            ));
+            assert!(err
+                .to_string()
+                .starts_with("record 2 at byte offset 2 is invalid"));
+        }
+
+        #[tokio::test]
+        async fn records_stream_locates_truncated_record() {
+            let vec = vec![4u8; 3 * <Fp32BitPrime as Serializable>::Size::USIZE + 1];
+            let err = RecordsStream::<Fp32BitPrime, _>::from(vec)
+                .try_collect::<Vec<_>>()
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+            assert!(matches!(
+                records_error(&err),
+                RecordsError::Truncated {
+                    index: 3,
+                    offset: 12,
+                    record_len: 4,
+                    len: 1,
+                }
+            ));
+        }
+
+        #[tokio::test]
+        async fn records_stream_rejects_content_length_up_front() {
+            // the stream never yields anything, so this would hang if it was polled
+            let mut stream =
+                RecordsStream::<Fp32BitPrime, _>::new(pending()).with_content_length(9);
+            let err = stream.next().await.unwrap().unwrap_err();
+            assert!(matches!(
+                records_error(&err),
+                RecordsError::Truncated {
+                    index: 2,
+                    offset: 8,
+                    record_len: 4,
+                    len: 1,
+                }
+            ));
+            assert!(stream.next().await.is_none());
+        }
+
+        #[tokio::test]
+        async fn records_stream_rejects_extra_records_early() {
+            let chunks = [vec![1u8; 8], vec![2u8; 4]]
+                .map(|vec| Ok(Bytes::from(vec)))
+                .into_iter();
+            let mut stream = RecordsStream::<Fp32BitPrime, _>::new(iter(chunks).chain(pending()))
+                .with_expected_records(2);
+            assert_eq!(stream.next().await.unwrap().unwrap().len(), 2);
+            let err = stream.next().await.unwrap().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(matches!(
+                records_error(&err),
+                RecordsError::TooMany {
+                    expected: 2,
+                    index: 2,
+                    offset: 8,
+                }
+            ));
+            assert!(stream.next().await.is_none());
+        }
+
+        #[tokio::test]
+        async fn records_stream_rejects_missing_records() {
+            let vec = vec![4u8; 3 * <Fp32BitPrime as Serializable>::Size::USIZE];
+            let err = RecordsStream::<Fp32BitPrime, _>::from(vec)
+                .with_content_length(20)
+                .try_collect::<Vec<_>>()
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+            assert!(matches!(
+                records_error(&err),
+                RecordsError::TooFew {
+                    expected: 5,
+                    index: 3,
+                    offset: 12,
+                }
+            ));
+        }
+
         // this test confirms that `RecordsStream` doesn't buffer more than it needs to as it produces
         // bytes
         #[tokio::test]
@@ -529,6 +805,24 @@ mod test {
             }
         }
 
+        #[tokio::test]
+        async fn records_stream_accounts_for_memory() {
+            let memory = Arc::new(MemoryBudget::new(None));
+            let mut stream =
+                RecordsStream::<Fp32BitPrime, _>::from(vec![vec![7_u8; 6], vec![7_u8; 2]])
+                    .with_memory_budget(&memory);
+
+            stream.next().await.unwrap().unwrap();
+            // half of the second record is buffered
+            assert_eq!(2, memory.used());
+            stream.next().await.unwrap().unwrap();
+            assert_eq!(0, memory.used());
+            assert_eq!(6, memory.peak());
+
+            drop(stream);
+            assert_eq!(0, memory.used());
+        }
+
         // checks that the `RecordsStream` will return chunks that are multiples of `SIZE_IN_BYTES`
         #[tokio::test]
         async fn returns_multiples() {
@@ -10,9 +10,9 @@ use std::pin::Pin;
 pub use axum_body::WrappedAxumBodyStream;
 pub use box_body::WrappedBoxBodyStream;
 use bytes::Bytes;
-pub use collection::{StreamCollection, StreamKey};
+pub use collection::{EvictionPolicy, StreamCollection, StreamKey};
 use futures::Stream;
-pub use input::{LengthDelimitedStream, RecordsStream};
+pub use input::{LengthDelimitedStream, RecordsError, RecordsStream};
 
 use crate::error::BoxError;
 
@@ -0,0 +1,156 @@
+//! Per-epoch keys that provide forward secrecy for report encryption.
+//!
+//! Reports encrypted to a long-lived helper key can be opened by anyone who obtains that key,
+//! however long ago they were collected. Instead, report collectors can encrypt reports to a key
+//! that belongs to the epoch the report is from. Epoch keys are derived from a secret that
+//! helpers ratchet forward: the secret for epoch `e + 1` is a one-way function of the secret for
+//! epoch `e`. Once a helper has advanced past an epoch and erased the secrets it held before,
+//! private keys of that epoch can't be recovered, even if the current secret leaks.
+//!
+//! Reports encrypted to epoch keys carry [`EPOCH_KEY_ID`] as their key identifier, the key is
+//! then selected by the epoch in the report header.
+use std::fmt::{Debug, Formatter};
+
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use super::{IpaKem, KeyPair};
+use crate::report::{Epoch, KeyIdentifier};
+
+/// Key identifier reserved for reports that are encrypted to the key of their epoch.
+pub const EPOCH_KEY_ID: KeyIdentifier = KeyIdentifier::MAX;
+
+const RATCHET_LABEL: &[u8] = b"private-attribution epoch ratchet";
+const KEY_LABEL: &[u8] = b"private-attribution epoch key";
+
+pub type EpochSecret = [u8; 32];
+
+/// Derives key pairs for the current and all future epochs from a secret that can only be moved
+/// forward.
+pub struct EpochKeySchedule {
+    epoch: Epoch,
+    secret: EpochSecret,
+}
+
+impl Debug for EpochKeySchedule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "EpochKeySchedule[epoch={}]", self.epoch)
+    }
+}
+
+impl EpochKeySchedule {
+    /// Creates a schedule from the secret for `epoch`.
+    #[must_use]
+    pub fn new(epoch: Epoch, secret: EpochSecret) -> Self {
+        Self { epoch, secret }
+    }
+
+    /// The earliest epoch this schedule can derive keys for.
+    #[must_use]
+    pub fn epoch(&self) -> Epoch {
+        self.epoch
+    }
+
+    /// The secret for [`epoch`]. Helpers need to persist it after every [`advance`] in place of
+    /// the previous one, otherwise the old secret defeats the purpose of moving forward.
+    ///
+    /// [`epoch`]: Self::epoch
+    /// [`advance`]: Self::advance
+    #[must_use]
+    pub fn secret(&self) -> &EpochSecret {
+        &self.secret
+    }
+
+    /// Returns the key pair for `epoch`, or `None` if this schedule has already moved past it.
+    #[must_use]
+    pub fn key_pair(&self, epoch: Epoch) -> Option<KeyPair> {
+        let steps = epoch.checked_sub(self.epoch)?;
+        let secret = (0..steps).fold(self.secret, |secret, _| ratchet(&secret));
+        Some(derive_key_pair(&secret))
+    }
+
+    /// Returns key pairs for `count` epochs starting from [`epoch`], stopping at the last epoch.
+    ///
+    /// [`epoch`]: Self::epoch
+    pub fn key_pairs(&self, count: u16) -> impl Iterator<Item = (Epoch, KeyPair)> {
+        let last = self.epoch.saturating_add(count.saturating_sub(1));
+        let mut secret = self.secret;
+        (self.epoch..=last)
+            .take(usize::from(count))
+            .map(move |epoch| {
+                let key_pair = derive_key_pair(&secret);
+                secret = ratchet(&secret);
+                (epoch, key_pair)
+            })
+    }
+
+    /// Moves this schedule forward to `epoch`, erasing the secret of every earlier epoch. Moving
+    /// backwards is not possible, so this does nothing if `epoch` precedes the current one.
+    pub fn advance(&mut self, epoch: Epoch) {
+        while self.epoch < epoch {
+            self.secret = ratchet(&self.secret);
+            self.epoch += 1;
+        }
+    }
+}
+
+fn expand(secret: &EpochSecret, label: &[u8]) -> EpochSecret {
+    let mut out = EpochSecret::default();
+    Hkdf::<Sha256>::new(None, secret)
+        .expand(label, &mut out)
+        .unwrap(); // 32 bytes is a valid length for SHA-256 output
+    out
+}
+
+fn ratchet(secret: &EpochSecret) -> EpochSecret {
+    expand(secret, RATCHET_LABEL)
+}
+
+fn derive_key_pair(secret: &EpochSecret) -> KeyPair {
+    <IpaKem as hpke::Kem>::derive_keypair(&expand(secret, KEY_LABEL)).into()
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use super::EpochKeySchedule;
+
+    fn schedule() -> EpochKeySchedule {
+        EpochKeySchedule::new(5, [7; 32])
+    }
+
+    #[test]
+    fn keys_differ_between_epochs() {
+        let schedule = schedule();
+        let keys = schedule
+            .key_pairs(3)
+            .map(|(_, key_pair)| key_pair.pk_bytes())
+            .collect::<Vec<_>>();
+
+        assert_eq!(3, keys.len());
+        assert_ne!(keys[0], keys[1]);
+        assert_ne!(keys[1], keys[2]);
+        assert_eq!(keys[2], schedule.key_pair(7).unwrap().pk_bytes());
+    }
+
+    #[test]
+    fn advance_forgets_past_epochs() {
+        let mut schedule = schedule();
+        let expected = schedule.key_pair(8).unwrap().sk_bytes();
+
+        schedule.advance(8);
+        assert_eq!(8, schedule.epoch());
+        assert!(schedule.key_pair(7).is_none());
+        assert_eq!(expected, schedule.key_pair(8).unwrap().sk_bytes());
+
+        // Resuming from the persisted secret gives the same keys.
+        let resumed = EpochKeySchedule::new(schedule.epoch(), *schedule.secret());
+        assert_eq!(expected, resumed.key_pair(8).unwrap().sk_bytes());
+    }
+
+    #[test]
+    fn advance_backwards_is_noop() {
+        let mut schedule = schedule();
+        schedule.advance(2);
+        assert_eq!(5, schedule.epoch());
+    }
+}
@@ -12,15 +12,17 @@ use hpke::{
 use rand_core::{CryptoRng, RngCore};
 use typenum::U16;
 
+mod epoch;
 mod info;
 mod registry;
 
+pub use epoch::{EpochKeySchedule, EpochSecret, EPOCH_KEY_ID};
 pub use info::Info;
 pub use registry::{KeyPair, KeyRegistry, PublicKeyOnly, PublicKeyRegistry};
 
 use crate::{
     ff::{GaloisField, Serializable as IpaSerializable},
-    report::KeyIdentifier,
+    report::{Epoch, KeyIdentifier},
     secret_sharing::replicated::semi_honest::AdditiveShare,
 };
 
@@ -62,6 +64,8 @@ where
 pub enum CryptError {
     #[error("Unknown key {0}")]
     NoSuchKey(KeyIdentifier),
+    #[error("No key for epoch {0}")]
+    NoSuchEpochKey(Epoch),
     #[error("Failed to open ciphertext")]
     Other,
 }
@@ -80,7 +84,8 @@ impl From<io::Error> for CryptError {
 
 /// Opens the given ciphertext in place by first obtaining the secret key from `key_registry`
 /// using epoch and key from the `info` parameter and then applying [`HPKE decryption`]
-/// to the provided ciphertext.
+/// to the provided ciphertext. Ciphertexts that carry [`EPOCH_KEY_ID`] are opened with the key of
+/// their epoch.
 ///
 /// This function mutates the provided ciphertext slice and replaces it with the plaintext obtained
 /// after opening the ciphertext. The result will contain a pointer to the plaintext slice.
@@ -97,14 +102,18 @@ pub fn open_in_place<'a>(
     ciphertext: &'a mut [u8],
     info: &Info,
 ) -> Result<&'a [u8], CryptError> {
-    let key_id = info.key_id;
+    let sk = match info.key_id {
+        EPOCH_KEY_ID => key_registry
+            .epoch_private_key(info.epoch)
+            .ok_or(CryptError::NoSuchEpochKey(info.epoch))?,
+        key_id => key_registry
+            .private_key(key_id)
+            .ok_or(CryptError::NoSuchKey(key_id))?,
+    };
     let info = info.to_bytes();
     let encap_key = <IpaKem as hpke::Kem>::EncappedKey::from_bytes(enc)?;
     let (ct, tag) = ciphertext.split_at_mut(ciphertext.len() - AeadTag::<IpaAead>::size());
     let tag = AeadTag::<IpaAead>::from_bytes(tag)?;
-    let sk = key_registry
-        .private_key(key_id)
-        .ok_or(CryptError::NoSuchKey(key_id))?;
 
     single_shot_open_in_place_detached::<_, IpaKdf, IpaKem>(
         &OpModeR::Base,
@@ -137,11 +146,15 @@ pub(crate) fn seal_in_place<'a, R: CryptoRng + RngCore, K: PublicKeyRegistry>(
     info: &'a Info,
     rng: &mut R,
 ) -> Result<Ciphertext<'a>, CryptError> {
-    let key_id = info.key_id;
+    let pk_r = match info.key_id {
+        EPOCH_KEY_ID => key_registry
+            .epoch_public_key(info.epoch)
+            .ok_or(CryptError::NoSuchEpochKey(info.epoch))?,
+        key_id => key_registry
+            .public_key(key_id)
+            .ok_or(CryptError::NoSuchKey(key_id))?,
+    };
     let info = info.to_bytes();
-    let pk_r = key_registry
-        .public_key(key_id)
-        .ok_or(CryptError::NoSuchKey(key_id))?;
 
     let (encap_key, tag) = single_shot_seal_in_place_detached::<IpaAead, IpaKdf, IpaKem, _>(
         &OpModeS::Base,
@@ -216,6 +229,12 @@ mod tests {
             }
         }
 
+        /// Adds keys for `count` epochs, starting from the epoch `schedule` is at.
+        pub fn with_epoch_keys(mut self, schedule: &EpochKeySchedule, count: u16) -> Self {
+            self.registry = self.registry.with_epoch_keys(schedule.key_pairs(count));
+            self
+        }
+
         pub fn seal_with_info<'a>(
             &mut self,
             info: Info<'a>,
@@ -351,6 +370,51 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn decrypt_with_epoch_key() {
+        let rng = StdRng::from_seed([1_u8; 32]);
+        let schedule = EpochKeySchedule::new(0, [3; 32]);
+        let mut suite = EncryptionSuite::new(1, rng).with_epoch_keys(&schedule, 2);
+        let match_key = new_share(1u64 << 39, 1u64 << 20);
+        suite.advance_epoch();
+
+        let enc = suite.seal(EPOCH_KEY_ID, EventType::Trigger, &match_key);
+        // another epoch's key can't open it
+        let mut wrong_epoch = enc.clone();
+        wrong_epoch.info.epoch = 0;
+        assert!(matches!(
+            open_in_place(
+                &suite.registry,
+                &wrong_epoch.enc,
+                wrong_epoch.ct.as_mut(),
+                &wrong_epoch.info
+            ),
+            Err(CryptError::Other)
+        ));
+
+        assert_eq!(
+            match_key,
+            suite.open(EPOCH_KEY_ID, EventType::Trigger, enc).unwrap()
+        );
+    }
+
+    #[test]
+    fn decrypt_forgotten_epoch() {
+        let mut schedule = EpochKeySchedule::new(0, [3; 32]);
+        let mut suite =
+            EncryptionSuite::new(1, StdRng::from_seed([1_u8; 32])).with_epoch_keys(&schedule, 1);
+        let match_key = new_share(1u64 << 39, 1u64 << 20);
+        let enc = suite.seal(EPOCH_KEY_ID, EventType::Source, &match_key);
+
+        schedule.advance(1);
+        let suite =
+            EncryptionSuite::new(1, StdRng::from_seed([2_u8; 32])).with_epoch_keys(&schedule, 3);
+        assert!(matches!(
+            suite.open(EPOCH_KEY_ID, EventType::Source, enc),
+            Err(CryptError::NoSuchEpochKey(0))
+        ));
+    }
+
     mod proptests {
         use proptest::prelude::ProptestConfig;
         use rand::{distributions::Alphanumeric, Rng};
@@ -1,8 +1,9 @@
-use std::ops::Deref;
+use std::{collections::BTreeMap, ops::Deref};
 
 use hpke::Serializable;
 
 use super::{IpaPrivateKey, IpaPublicKey, KeyIdentifier};
+use crate::report::Epoch;
 
 /// A pair of secret key and public key. Public keys used by UA to encrypt the data towards helpers
 /// secret keys used by helpers to open the ciphertexts. Each helper needs access to both
@@ -60,11 +61,18 @@ impl Deref for PublicKeyOnly {
 
 pub trait PublicKeyRegistry {
     fn public_key(&self, key_id: KeyIdentifier) -> Option<&IpaPublicKey>;
+
+    /// Returns the public key that reports from `epoch` are encrypted to when they carry
+    /// [`EPOCH_KEY_ID`].
+    ///
+    /// [`EPOCH_KEY_ID`]: super::EPOCH_KEY_ID
+    fn epoch_public_key(&self, epoch: Epoch) -> Option<&IpaPublicKey>;
 }
 
 /// A registry that holds all the keys available for helper/UA to use.
 pub struct KeyRegistry<K> {
     keys: Box<[K]>,
+    epoch_keys: BTreeMap<Epoch, K>,
 }
 
 impl<K> KeyRegistry<K> {
@@ -72,7 +80,10 @@ impl<K> KeyRegistry<K> {
     /// but this avoids `Option<KeyRegistry>` when the registry is ultimately not optional.
     #[must_use]
     pub fn empty() -> Self {
-        Self { keys: Box::new([]) }
+        Self {
+            keys: Box::new([]),
+            epoch_keys: BTreeMap::new(),
+        }
     }
 
     pub fn from_keys<const N: usize, I: Into<K>>(pairs: [I; N]) -> Self {
@@ -82,15 +93,33 @@ impl<K> KeyRegistry<K> {
                 .map(Into::into)
                 .collect::<Vec<_>>()
                 .into_boxed_slice(),
+            epoch_keys: BTreeMap::new(),
         }
     }
 
+    /// Adds keys of individual epochs to this registry. See [`EpochKeySchedule`].
+    ///
+    /// [`EpochKeySchedule`]: super::EpochKeySchedule
+    #[must_use]
+    pub fn with_epoch_keys<E: IntoIterator<Item = (Epoch, I)>, I: Into<K>>(
+        mut self,
+        keys: E,
+    ) -> Self {
+        self.epoch_keys
+            .extend(keys.into_iter().map(|(epoch, key)| (epoch, key.into())));
+        self
+    }
+
     fn key(&self, key_id: KeyIdentifier) -> Option<&K> {
         match key_id as usize {
             key_id if key_id < self.keys.len() => Some(&self.keys[key_id]),
             _ => None,
         }
     }
+
+    fn epoch_key(&self, epoch: Epoch) -> Option<&K> {
+        self.epoch_keys.get(&epoch)
+    }
 }
 
 impl KeyRegistry<KeyPair> {
@@ -100,6 +129,7 @@ impl KeyRegistry<KeyPair> {
 
         Self {
             keys: keys.into_boxed_slice(),
+            epoch_keys: BTreeMap::new(),
         }
     }
 
@@ -107,18 +137,31 @@ impl KeyRegistry<KeyPair> {
     pub(super) fn private_key(&self, key_id: KeyIdentifier) -> Option<&IpaPrivateKey> {
         self.key(key_id).map(|v| &v.sk)
     }
+
+    #[must_use]
+    pub(super) fn epoch_private_key(&self, epoch: Epoch) -> Option<&IpaPrivateKey> {
+        self.epoch_key(epoch).map(|v| &v.sk)
+    }
 }
 
 impl PublicKeyRegistry for KeyRegistry<KeyPair> {
     fn public_key(&self, key_id: KeyIdentifier) -> Option<&IpaPublicKey> {
         self.key(key_id).map(|v| &v.pk)
     }
+
+    fn epoch_public_key(&self, epoch: Epoch) -> Option<&IpaPublicKey> {
+        self.epoch_key(epoch).map(|v| &v.pk)
+    }
 }
 
 impl PublicKeyRegistry for KeyRegistry<PublicKeyOnly> {
     fn public_key(&self, key_id: KeyIdentifier) -> Option<&IpaPublicKey> {
         self.key(key_id).map(|pk| &**pk)
     }
+
+    fn epoch_public_key(&self, epoch: Epoch) -> Option<&IpaPublicKey> {
+        self.epoch_key(epoch).map(|pk| &**pk)
+    }
 }
 
 #[cfg(all(test, unit_test))]
@@ -9,6 +9,7 @@ use std::{
 };
 
 use axum::http::uri::{self, Parts, Scheme};
+use bytes::Bytes;
 use futures::{Stream, StreamExt};
 use hyper::{
     body, client::HttpConnector, header::HeaderName, http::HeaderValue, Body, Client, Request,
@@ -20,13 +21,20 @@ use rustls::{Certificate, PrivateKey, RootCertStore};
 use tracing::error;
 
 use crate::{
-    config::{ClientConfig, HyperClientConfigurator, NetworkConfig, PeerConfig},
+    config::{ClientConfig, CompressionConfig, HyperClientConfigurator, NetworkConfig, PeerConfig},
     helpers::{
-        query::{PrepareQuery, QueryConfig, QueryInput},
+        query::{InputPart, PrepareQuery, QueryConfig, QueryInput},
         HelperIdentity,
     },
-    net::{http_serde, server::HTTP_CLIENT_ID_HEADER, Error},
+    net::{
+        compression::compress,
+        http_serde,
+        server::{HTTP_CLIENT_ID_HEADER, HTTP_REPORT_COLLECTOR_HEADER},
+        throttle::{throttle, TokenBucket},
+        Error, IdempotencyKey, WireVersion,
+    },
     protocol::{step::Gate, QueryId},
+    sync::Arc,
 };
 
 #[derive(Clone, Default)]
@@ -36,6 +44,13 @@ pub enum ClientIdentity {
     /// This is only supported for HTTP clients.
     Helper(HelperIdentity),
 
+    /// Claim to be the report collector that submits the specified part of query inputs, without
+    /// any additional authentication.
+    ///
+    /// This is only supported for HTTP clients. Over HTTPS, report collectors authenticate with
+    /// the certificate listed for them in the network configuration.
+    ReportCollector(InputPart),
+
     /// Authenticate with an X.509 certificate or a certificate chain.
     ///
     /// This is only supported for HTTPS clients.
@@ -98,6 +113,10 @@ impl<'a> ResponseFromEndpoint<'a> {
         self.inner.status()
     }
 
+    pub fn headers(&self) -> &hyper::HeaderMap {
+        self.inner.headers()
+    }
+
     pub fn into_body(self) -> Body {
         self.inner.into_body()
     }
@@ -136,6 +155,9 @@ pub struct MpcHelperClient {
     scheme: uri::Scheme,
     authority: uri::Authority,
     auth_header: Option<(HeaderName, HeaderValue)>,
+    multiplex_streams: bool,
+    compression: Option<CompressionConfig>,
+    bandwidth: Option<Arc<TokenBucket>>,
 }
 
 impl MpcHelperClient {
@@ -146,7 +168,8 @@ impl MpcHelperClient {
     ///
     /// `identity` configures whether and how the client will authenticate to the server. It is for
     /// the helper making the calls, so the same one is used for all three of the clients.
-    /// Authentication is not required when calling the report collector APIs.
+    /// Authentication is not required when calling the report collector APIs, except for
+    /// submitting a part of query inputs, see [`ClientIdentity::ReportCollector`].
     #[must_use]
     #[allow(clippy::missing_panics_doc)]
     pub fn from_conf(conf: &NetworkConfig, identity: ClientIdentity) -> [MpcHelperClient; 3] {
@@ -184,6 +207,11 @@ impl MpcHelperClient {
                     HTTP_CLIENT_ID_HEADER.clone(),
                     id.try_into().expect("integer not ascii?"),
                 )),
+                ClientIdentity::ReportCollector(part) => Some((
+                    HTTP_REPORT_COLLECTOR_HEADER.clone(),
+                    HeaderValue::try_from(serde_json::to_string(&part).unwrap())
+                        .expect("input part is ascii"),
+                )),
                 ClientIdentity::None => None,
             };
             (
@@ -208,7 +236,7 @@ impl MpcHelperClient {
                     ClientIdentity::Certificate((cert_chain, pk)) => builder
                         .with_client_auth_cert(cert_chain, pk)
                         .expect("Can setup client authentication with certificate"),
-                    ClientIdentity::Helper(_) => {
+                    ClientIdentity::Helper(_) | ClientIdentity::ReportCollector(_) => {
                         error!("header-passed identity ignored for HTTPS client");
                         builder.with_no_client_auth()
                     }
@@ -234,13 +262,15 @@ impl MpcHelperClient {
     }
 
     #[must_use]
-    fn new_internal<C: HyperClientConfigurator>(
+    fn new_internal(
         addr: Uri,
         connector: HttpsConnector<HttpConnector>,
         auth_header: Option<(HeaderName, HeaderValue)>,
-        conf: &C,
+        conf: &ClientConfig,
     ) -> Self {
         let client = conf.configure(&mut Client::builder()).build(connector);
+        let multiplex_streams = conf.multiplex_streams;
+        let compression = conf.compression;
         let Parts {
             scheme: Some(scheme),
             authority: Some(authority),
@@ -249,18 +279,33 @@ impl MpcHelperClient {
         else {
             panic!("peer URL must have a scheme and authority");
         };
+        let bandwidth = conf
+            .bandwidth_limit
+            .map(|limit| Arc::new(TokenBucket::new(limit, authority.to_string())));
         Self {
             client,
             scheme,
             authority,
             auth_header,
+            multiplex_streams,
+            compression,
+            bandwidth,
         }
     }
 
+    /// Whether record streams sent by this client should share a single request per query.
+    /// See [`Self::step_multiplexed`].
+    #[must_use]
+    pub fn multiplex_streams(&self) -> bool {
+        self.multiplex_streams
+    }
+
     pub fn request(&self, mut req: Request<Body>) -> ResponseFuture<'_> {
         if let Some((k, v)) = self.auth_header.clone() {
             req.headers_mut().insert(k, v);
         }
+        #[cfg(feature = "otel")]
+        crate::telemetry::otel::inject_context(&tracing::Span::current(), req.headers_mut());
         ResponseFuture {
             authority: &self.authority,
             inner: self.client.request(req),
@@ -289,6 +334,8 @@ impl MpcHelperClient {
                 dest: self.authority.to_string(),
                 status: StatusCode::INTERNAL_SERVER_ERROR,
                 reason: "did not receive mirrored echo response".into(),
+                code: None,
+                gate: None,
             })
         } else {
             Err(Error::from_failed_resp(resp).await)
@@ -312,7 +359,22 @@ impl MpcHelperClient {
     /// # Errors
     /// If the request has illegal arguments, or fails to deliver to helper
     pub async fn create_query(&self, data: QueryConfig) -> Result<QueryId, Error> {
-        let req = http_serde::query::create::Request::new(data);
+        self.create_query_with_key(data, None).await
+    }
+
+    /// Same as [`Self::create_query`], but safe to retry. Helper creates at most one query for
+    /// the given idempotency key and returns its id to every request that carries this key.
+    /// # Errors
+    /// If the request has illegal arguments, or fails to deliver to helper
+    pub async fn create_query_with_key(
+        &self,
+        data: QueryConfig,
+        idempotency_key: Option<IdempotencyKey>,
+    ) -> Result<QueryId, Error> {
+        let mut req = http_serde::query::create::Request::new(data);
+        if let Some(key) = idempotency_key {
+            req = req.with_idempotency_key(key);
+        }
         let req = req.try_into_http_request(self.scheme.clone(), self.authority.clone())?;
         let resp = self.request(req).await?;
         if resp.status().is_success() {
@@ -327,14 +389,18 @@ impl MpcHelperClient {
 
     /// Used to communicate from one helper to another. Specifically, the helper that receives a
     /// "create query" from an external party must communicate the intent to start a query to the
-    /// other helpers, which this prepare query does.
+    /// other helpers, which this prepare query does. Returns the wire version the other helper
+    /// agreed to use for records of this query.
     /// # Errors
-    /// If the request has illegal arguments, or fails to deliver to helper
-    pub async fn prepare_query(&self, data: PrepareQuery) -> Result<(), Error> {
+    /// If the request has illegal arguments, or fails to deliver to helper, or the other helper
+    /// replies with a wire version this helper does not support.
+    pub async fn prepare_query(&self, data: PrepareQuery) -> Result<WireVersion, Error> {
         let req = http_serde::query::prepare::Request::new(data);
         let req = req.try_into_http_request(self.scheme.clone(), self.authority.clone())?;
         let resp = self.request(req).await?;
-        Self::resp_ok(resp).await
+        let wire_version = WireVersion::from_headers(resp.headers());
+        Self::resp_ok(resp).await?;
+        wire_version?.supported()
     }
 
     /// Intended to be called externally, e.g. by the report collector. After the report collector
@@ -343,7 +409,22 @@ impl MpcHelperClient {
     /// # Errors
     /// If the request has illegal arguments, or fails to deliver to helper
     pub async fn query_input(&self, data: QueryInput) -> Result<(), Error> {
-        let req = http_serde::query::input::Request::new(data);
+        self.query_input_with_key(data, None).await
+    }
+
+    /// Same as [`Self::query_input`], but safe to retry. Helper accepts the input only once for
+    /// the given idempotency key and acknowledges retries without submitting it again.
+    /// # Errors
+    /// If the request has illegal arguments, or fails to deliver to helper
+    pub async fn query_input_with_key(
+        &self,
+        data: QueryInput,
+        idempotency_key: Option<IdempotencyKey>,
+    ) -> Result<(), Error> {
+        let mut req = http_serde::query::input::Request::new(data);
+        if let Some(key) = idempotency_key {
+            req = req.with_idempotency_key(key);
+        }
         let req = req.try_into_http_request(self.scheme.clone(), self.authority.clone())?;
         let resp = self.request(req).await?;
         Self::resp_ok(resp).await
@@ -352,6 +433,9 @@ impl MpcHelperClient {
     /// Sends a batch of messages associated with a query's step to another helper. Messages are a
     /// contiguous block of records. Also includes [`crate::protocol::RecordId`] information and
     /// [`crate::helpers::network::ChannelId`].
+    ///
+    /// Messages are compressed if this client is configured to do that and the peer supports
+    /// `wire_version` that allows it.
     /// # Errors
     /// If the request has illegal arguments, or fails to deliver to helper
     /// # Panics
@@ -360,10 +444,42 @@ impl MpcHelperClient {
         &self,
         query_id: QueryId,
         gate: &Gate,
+        wire_version: WireVersion,
         data: S,
     ) -> Result<ResponseFuture, Error> {
-        let body = hyper::Body::wrap_stream::<_, _, Error>(data.map(Ok));
-        let req = http_serde::query::step::Request::new(query_id, gate.clone(), body);
+        let compression = self
+            .compression
+            .filter(|_| wire_version >= WireVersion::COMPRESSED_RECORDS);
+        let bandwidth = self.bandwidth.clone();
+        let body = match compression {
+            Some(config) => hyper::Body::wrap_stream::<_, _, Error>(
+                throttle(compress(data, config), bandwidth).map(Ok),
+            ),
+            None => hyper::Body::wrap_stream::<_, _, Error>(throttle(data, bandwidth).map(Ok)),
+        };
+        let mut req = http_serde::query::step::Request::new(query_id, gate.clone(), body)
+            .with_wire_version(wire_version);
+        if let Some(config) = compression {
+            req = req.with_encoding(config.encoding);
+        }
+        let req = req.try_into_http_request(self.scheme.clone(), self.authority.clone())?;
+        Ok(self.request(req))
+    }
+
+    /// Sends record streams for all steps of a query over a single request. `data` must be the
+    /// body produced by a multiplexer; the request is complete once that body ends.
+    /// # Errors
+    /// If the request has illegal arguments, or fails to deliver to helper
+    pub fn step_multiplexed<S: Stream<Item = Bytes> + Send + 'static>(
+        &self,
+        query_id: QueryId,
+        wire_version: WireVersion,
+        data: S,
+    ) -> Result<ResponseFuture, Error> {
+        let body =
+            hyper::Body::wrap_stream::<_, _, Error>(throttle(data, self.bandwidth.clone()).map(Ok));
+        let req =
+            http_serde::query::mux::Request::new(query_id, body).with_wire_version(wire_version);
         let req = req.try_into_http_request(self.scheme.clone(), self.authority.clone())?;
         Ok(self.request(req))
     }
@@ -376,16 +492,23 @@ impl MpcHelperClient {
     pub async fn query_status(
         &self,
         query_id: QueryId,
-    ) -> Result<crate::query::QueryStatus, Error> {
+    ) -> Result<crate::query::QueryStatusReport, Error> {
         let req = http_serde::query::status::Request::new(query_id);
         let req = req.try_into_http_request(self.scheme.clone(), self.authority.clone())?;
 
         let resp = self.request(req).await?;
         if resp.status().is_success() {
             let body_bytes = body::to_bytes(resp.into_body()).await?;
-            let http_serde::query::status::ResponseBody { status } =
-                serde_json::from_slice(&body_bytes)?;
-            Ok(status)
+            let http_serde::query::status::ResponseBody {
+                status,
+                progress,
+                metrics,
+            } = serde_json::from_slice(&body_bytes)?;
+            Ok(crate::query::QueryStatusReport {
+                status,
+                progress,
+                metrics,
+            })
         } else {
             Err(Error::from_failed_resp(resp).await)
         }
@@ -434,14 +557,17 @@ pub(crate) mod tests {
     use crate::{
         ff::{FieldType, Fp31},
         helpers::{
-            query::QueryType::TestMultiply, BytesStream, RoleAssignment, Transport,
-            TransportCallbacks, MESSAGE_PAYLOAD_SIZE_BYTES,
+            query::{InputPart, QueryType::TestMultiply},
+            BytesStream, RoleAssignment, Transport, TransportCallbacks, MESSAGE_PAYLOAD_SIZE_BYTES,
         },
         net::{test::TestServer, HttpTransport},
         protocol::step::StepNarrow,
         query::ProtocolResult,
         secret_sharing::replicated::semi_honest::AdditiveShare as Replicated,
-        sync::Arc,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
     };
 
     // This is a kludgy way of working around `TransportCallbacks` not being `Clone`, so
@@ -459,12 +585,14 @@ pub(crate) mod tests {
             let qi = Arc::clone(inner);
             let si = Arc::clone(inner);
             let ci = Arc::clone(inner);
+            let mi = Arc::clone(inner);
             TransportCallbacks {
                 receive_query: Box::new(move |t, req| (ri.receive_query)(t, req)),
                 prepare_query: Box::new(move |t, req| (pi.prepare_query)(t, req)),
                 query_input: Box::new(move |t, req| (qi.query_input)(t, req)),
                 query_status: Box::new(move |t, req| (si.query_status)(t, req)),
                 complete_query: Box::new(move |t, req| (ci.complete_query)(t, req)),
+                metrics: Box::new(move |t| (mi.metrics)(t)),
             }
         }
 
@@ -554,7 +682,7 @@ pub(crate) mod tests {
 
     #[tokio::test]
     async fn create() {
-        let expected_query_id = QueryId;
+        let expected_query_id = QueryId::default();
         let expected_query_config = QueryConfig::new(TestMultiply, FieldType::Fp31, 1).unwrap();
 
         let cb = TransportCallbacks {
@@ -575,9 +703,12 @@ pub(crate) mod tests {
     #[tokio::test]
     async fn prepare() {
         let input = PrepareQuery {
-            query_id: QueryId,
+            query_id: QueryId::default(),
             config: QueryConfig::new(TestMultiply, FieldType::Fp31, 1).unwrap(),
             roles: RoleAssignment::new(HelperIdentity::make_three()),
+            reservation: QueryConfig::new(TestMultiply, FieldType::Fp31, 1)
+                .unwrap()
+                .reservation(),
         };
         let expected_data = input.clone();
         let cb = TransportCallbacks {
@@ -587,7 +718,7 @@ pub(crate) mod tests {
             }),
             ..Default::default()
         };
-        test_query_command(
+        let wire_version = test_query_command(
             |client| {
                 let req = input.clone();
                 async move { client.prepare_query(req).await.unwrap() }
@@ -595,11 +726,12 @@ pub(crate) mod tests {
             cb,
         )
         .await;
+        assert_eq!(WireVersion::CURRENT, wire_version);
     }
 
     #[tokio::test]
     async fn input() {
-        let expected_query_id = QueryId;
+        let expected_query_id = QueryId::default();
         let expected_input = &[8u8; 25];
         let cb = TransportCallbacks {
             query_input: Box::new(move |_transport, query_input| {
@@ -615,6 +747,7 @@ pub(crate) mod tests {
             |client| async move {
                 let data = QueryInput {
                     query_id: expected_query_id,
+                    part: InputPart::All,
                     input_stream: expected_input.to_vec().into(),
                 };
                 client.query_input(data).await.unwrap()
@@ -624,12 +757,101 @@ pub(crate) mod tests {
         .await;
     }
 
+    #[tokio::test]
+    async fn input_part() {
+        let cb = TransportCallbacks {
+            query_input: Box::new(move |_transport, query_input| {
+                Box::pin(async move {
+                    assert_eq!(query_input.part, InputPart::TriggerEvents);
+                    assert_eq!(&query_input.input_stream.to_vec().await, &[1u8; 4]);
+                    Ok(())
+                })
+            }),
+            ..Default::default()
+        };
+        let TestServer { addr, .. } = TestServer::builder()
+            .disable_https()
+            .with_callbacks(cb)
+            .build()
+            .await;
+        let submit = |identity| {
+            let peer_config = PeerConfig::new(
+                format!("http://localhost:{}", addr.port()).parse().unwrap(),
+                None,
+            );
+            let client = MpcHelperClient::new(&ClientConfig::default(), peer_config, identity);
+            async move {
+                client
+                    .query_input(QueryInput {
+                        query_id: QueryId::default(),
+                        part: InputPart::TriggerEvents,
+                        input_stream: vec![1u8; 4].into(),
+                    })
+                    .await
+            }
+        };
+
+        // Only the report collector entitled to trigger events may submit them.
+        for identity in [
+            ClientIdentity::None,
+            ClientIdentity::ReportCollector(InputPart::SourceEvents),
+        ] {
+            assert!(matches!(
+                submit(identity).await,
+                Err(Error::FailedHttpRequest {
+                    status: StatusCode::UNAUTHORIZED,
+                    ..
+                })
+            ));
+        }
+        submit(ClientIdentity::ReportCollector(InputPart::TriggerEvents))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn input_retry() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let cb = TransportCallbacks {
+            query_input: Box::new({
+                let calls = Arc::clone(&calls);
+                move |_transport, _query_input| {
+                    calls.fetch_add(1, Ordering::Relaxed);
+                    Box::pin(ready(Ok(())))
+                }
+            }),
+            ..Default::default()
+        };
+        test_query_command(
+            |client| async move {
+                let key = IdempotencyKey::random();
+                for _ in 0..2 {
+                    let data = QueryInput {
+                        query_id: QueryId::default(),
+                        part: InputPart::All,
+                        input_stream: vec![1u8; 4].into(),
+                    };
+                    client
+                        .query_input_with_key(data, Some(key.clone()))
+                        .await
+                        .unwrap();
+                }
+            },
+            cb,
+        )
+        .await;
+
+        // retry is deduplicated by both HTTP and HTTPS servers
+        assert_eq!(2, calls.load(Ordering::Relaxed));
+    }
+
     #[tokio::test]
     async fn step() {
         let TestServer {
             client, transport, ..
         } = TestServer::builder().build().await;
-        let expected_query_id = QueryId;
+        let expected_query_id = QueryId::default();
+        transport.open_query_streams(expected_query_id);
         let expected_step = Gate::default().narrow("test-step");
         let expected_payload = vec![7u8; MESSAGE_PAYLOAD_SIZE_BYTES];
 
@@ -637,6 +859,7 @@ pub(crate) mod tests {
             .step(
                 expected_query_id,
                 &expected_step,
+                WireVersion::CURRENT,
                 once(ready(expected_payload.clone())),
             )
             .unwrap()
@@ -645,8 +868,10 @@ pub(crate) mod tests {
 
         MpcHelperClient::resp_ok(resp).await.unwrap();
 
-        let mut stream =
-            Arc::clone(&transport).receive(HelperIdentity::ONE, (QueryId, expected_step.clone()));
+        let mut stream = Arc::clone(&transport).receive(
+            HelperIdentity::ONE,
+            (expected_query_id, expected_step.clone()),
+        );
 
         assert_eq!(
             poll_immediate(&mut stream).next().await,
@@ -660,7 +885,7 @@ pub(crate) mod tests {
             Fp31::try_from(1u128).unwrap(),
             Fp31::try_from(2u128).unwrap(),
         ))]);
-        let expected_query_id = QueryId;
+        let expected_query_id = QueryId::default();
         let raw_results = expected_results.to_vec();
         let cb = TransportCallbacks {
             complete_query: Box::new(move |_transport, query_id| {
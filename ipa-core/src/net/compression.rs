@@ -0,0 +1,339 @@
+//! Compression of MPC record streams sent over individual step requests.
+//!
+//! Boolean shares take a whole byte each when serialized, so record streams of wide boolean
+//! circuits compress well. When it is configured, a helper compresses every chunk of a record
+//! stream on its own and frames it:
+//!
+//! ```text
+//! +------------+-------------+---------------+
+//! | compressed |   length    |    payload    |
+//! |    (u8)    | (u32, LE)   | length bytes  |
+//! +------------+-------------+---------------+
+//! ```
+//!
+//! Chunks smaller than the configured threshold, and chunks that compression does not make
+//! smaller, are sent as is, with the first byte set to zero. Neither the payload nor what it
+//! decompresses to may be larger than [`MAX_CHUNK_LEN`], larger chunks are split before they are
+//! sent, so a peer can't make the receiver buffer more than that for a single chunk. The
+//! encoding is announced in the `x-ipa-record-encoding` header of the step request; the receiver
+//! echoes it in the response.
+//! Helpers only compress streams sent to peers that they negotiated wire version
+//! [`WireVersion::COMPRESSED_RECORDS`] or later with, peers running older versions don't know
+//! about this framing.
+//!
+//! [`WireVersion::COMPRESSED_RECORDS`]: crate::net::WireVersion::COMPRESSED_RECORDS
+use std::{
+    fmt::{Display, Formatter},
+    io::{self, Read, Write},
+    str::FromStr,
+};
+
+use bytes::{Buf, Bytes, BytesMut};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use futures::{stream, Stream, StreamExt};
+use hyper::header::HeaderName;
+use serde::{Deserialize, Serialize};
+
+use crate::{config::CompressionConfig, error::BoxError, net::Error};
+
+pub static RECORD_ENCODING_HEADER: HeaderName = HeaderName::from_static("x-ipa-record-encoding");
+
+const HEADER_LEN: usize = 5;
+
+/// Maximum size of a chunk, before and after compression.
+pub const MAX_CHUNK_LEN: usize = 1 << 24;
+
+/// Compression level used by zstd. Records are compressed on the fly, so speed matters more
+/// than the ratio.
+const ZSTD_LEVEL: i32 = 1;
+
+/// Compression algorithm applied to record streams.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordEncoding {
+    Gzip,
+    Zstd,
+}
+
+impl RecordEncoding {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+        }
+    }
+
+    /// Compresses a single chunk of records.
+    ///
+    /// ## Errors
+    /// If the compressor fails.
+    pub fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Self::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL),
+        }
+    }
+
+    /// Decompresses a chunk produced by [`Self::compress`].
+    ///
+    /// ## Errors
+    /// If `data` is not a valid compressed chunk or decompresses to more than
+    /// [`MAX_CHUNK_LEN`] bytes.
+    pub fn decompress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        // One byte over the limit is enough to tell that the chunk is too large.
+        let limit = u64::try_from(MAX_CHUNK_LEN).unwrap() + 1;
+        let mut buf = Vec::new();
+        match self {
+            Self::Gzip => GzDecoder::new(data).take(limit).read_to_end(&mut buf)?,
+            Self::Zstd => zstd::stream::read::Decoder::new(data)?
+                .take(limit)
+                .read_to_end(&mut buf)?,
+        };
+        if buf.len() > MAX_CHUNK_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("chunk decompresses to more than {MAX_CHUNK_LEN} bytes"),
+            ));
+        }
+        Ok(buf)
+    }
+}
+
+impl Display for RecordEncoding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for RecordEncoding {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gzip" => Ok(Self::Gzip),
+            "zstd" => Ok(Self::Zstd),
+            _ => Err(Error::InvalidHeader(
+                format!("unsupported {RECORD_ENCODING_HEADER}: {s}").into(),
+            )),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum CompressionError {
+    #[error("invalid chunk flag {0}")]
+    InvalidFlag(u8),
+    #[error("failed to decompress {encoding} chunk: {source}")]
+    Decompress {
+        encoding: RecordEncoding,
+        #[source]
+        source: io::Error,
+    },
+    #[error("compressed body ended in the middle of a chunk")]
+    Truncated,
+    #[error("chunk of {0} bytes is larger than the limit of {MAX_CHUNK_LEN} bytes")]
+    TooLarge(usize),
+}
+
+/// Frames a single chunk of at most [`MAX_CHUNK_LEN`] bytes, compressing it if that is worth it.
+fn encode(chunk: &[u8], config: CompressionConfig) -> Vec<u8> {
+    debug_assert!(chunk.len() <= MAX_CHUNK_LEN);
+    let compressed = if chunk.len() >= config.threshold {
+        config
+            .encoding
+            .compress(chunk)
+            .ok()
+            .filter(|compressed| compressed.len() < chunk.len())
+    } else {
+        None
+    };
+    let (flag, payload) = match &compressed {
+        Some(compressed) => (1, compressed.as_slice()),
+        None => (0, chunk),
+    };
+
+    let mut buf = Vec::with_capacity(HEADER_LEN + payload.len());
+    buf.push(flag);
+    buf.extend_from_slice(
+        &u32::try_from(payload.len())
+            .expect("chunk must fit into u32")
+            .to_le_bytes(),
+    );
+    buf.extend_from_slice(payload);
+    buf
+}
+
+/// Takes the next chunk off the front of `buf`, if it has been received in full, and
+/// decompresses it.
+fn decode(buf: &mut BytesMut, encoding: RecordEncoding) -> Result<Option<Bytes>, CompressionError> {
+    if buf.len() < HEADER_LEN {
+        return Ok(None);
+    }
+    let flag = buf[0];
+    let len = u32::from_le_bytes(buf[1..HEADER_LEN].try_into().unwrap()) as usize;
+    if len > MAX_CHUNK_LEN {
+        return Err(CompressionError::TooLarge(len));
+    }
+    if buf.len() < HEADER_LEN + len {
+        return Ok(None);
+    }
+    buf.advance(HEADER_LEN);
+    let payload = buf.split_to(len).freeze();
+    match flag {
+        0 => Ok(Some(payload)),
+        1 => encoding
+            .decompress(&payload)
+            .map(|chunk| Some(Bytes::from(chunk)))
+            .map_err(|source| CompressionError::Decompress { encoding, source }),
+        _ => Err(CompressionError::InvalidFlag(flag)),
+    }
+}
+
+/// Compresses every chunk of `data` according to `config`.
+pub fn compress<S>(data: S, config: CompressionConfig) -> impl Stream<Item = Vec<u8>> + Send
+where
+    S: Stream<Item = Vec<u8>> + Send,
+{
+    data.map(move |chunk| {
+        chunk
+            .chunks(MAX_CHUNK_LEN)
+            .flat_map(|piece| encode(piece, config))
+            .collect()
+    })
+}
+
+/// Restores the record stream compressed by [`compress`] from the body of a step request.
+pub fn decompress<S>(
+    body: S,
+    encoding: RecordEncoding,
+) -> impl Stream<Item = Result<Bytes, BoxError>> + Send
+where
+    S: Stream<Item = Result<Bytes, BoxError>> + Send + Unpin,
+{
+    stream::try_unfold(
+        (body, BytesMut::new()),
+        move |(mut body, mut buf)| async move {
+            loop {
+                if let Some(chunk) = decode(&mut buf, encoding)? {
+                    return Ok::<_, BoxError>(Some((chunk, (body, buf))));
+                }
+                match body.next().await {
+                    Some(bytes) => buf.extend_from_slice(&bytes?),
+                    None if buf.is_empty() => return Ok(None),
+                    None => return Err(CompressionError::Truncated.into()),
+                }
+            }
+        },
+    )
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use bytes::Bytes;
+    use futures::{stream, StreamExt};
+
+    use super::{compress, decompress, encode, RecordEncoding, HEADER_LEN, MAX_CHUNK_LEN};
+    use crate::{config::CompressionConfig, error::BoxError};
+
+    async fn round_trip(chunks: Vec<Vec<u8>>, config: CompressionConfig) -> (usize, Vec<u8>) {
+        let compressed = compress(stream::iter(chunks), config)
+            .collect::<Vec<_>>()
+            .await
+            .concat();
+        let len = compressed.len();
+        // deliver the body in pieces that don't line up with chunks
+        let body = stream::iter(
+            compressed
+                .chunks(7)
+                .map(|piece| Ok::<_, BoxError>(Bytes::copy_from_slice(piece)))
+                .collect::<Vec<_>>(),
+        );
+        let restored = decompress(body, config.encoding)
+            .map(|chunk| chunk.unwrap().to_vec())
+            .collect::<Vec<_>>()
+            .await
+            .concat();
+        (len, restored)
+    }
+
+    #[tokio::test]
+    async fn boolean_shares_compress() {
+        for encoding in [RecordEncoding::Gzip, RecordEncoding::Zstd] {
+            let config = CompressionConfig {
+                encoding,
+                threshold: 64,
+            };
+            let chunks = (0..4)
+                .map(|i| (0..4096_u32).map(|j| u8::from((i + j) % 3 == 0)).collect())
+                .collect::<Vec<Vec<u8>>>();
+            let (len, restored) = round_trip(chunks.clone(), config).await;
+
+            assert_eq!(chunks.concat(), restored);
+            assert!(len < restored.len() / 4, "{encoding} compressed to {len}");
+        }
+    }
+
+    #[tokio::test]
+    async fn small_chunks_are_not_compressed() {
+        let config = CompressionConfig {
+            encoding: RecordEncoding::Zstd,
+            threshold: 64,
+        };
+        let chunk = vec![0; 63];
+        assert_eq!(chunk, encode(&chunk, config)[5..]);
+
+        let (len, restored) = round_trip(vec![chunk.clone(), vec![1; 100]], config).await;
+        assert_eq!([chunk, vec![1; 100]].concat(), restored);
+        assert!(len < 163);
+    }
+
+    #[tokio::test]
+    async fn large_chunks_are_split() {
+        let config = CompressionConfig::default();
+        let chunk = vec![1; MAX_CHUNK_LEN + 1];
+        let (_, restored) = round_trip(vec![chunk.clone()], config).await;
+        assert_eq!(chunk, restored);
+    }
+
+    #[tokio::test]
+    async fn rejects_large_chunks() {
+        let config = CompressionConfig::default();
+        let mut frame = encode(&[1; 10], config);
+        frame[1..HEADER_LEN]
+            .copy_from_slice(&u32::try_from(MAX_CHUNK_LEN + 1).unwrap().to_le_bytes());
+        let body = stream::iter([Ok::<_, BoxError>(Bytes::from(frame))]);
+        let result = decompress(body, config.encoding).collect::<Vec<_>>().await;
+        assert!(result.last().unwrap().is_err());
+    }
+
+    #[test]
+    fn rejects_compression_bombs() {
+        for encoding in [RecordEncoding::Gzip, RecordEncoding::Zstd] {
+            let bomb = encoding.compress(&vec![0; MAX_CHUNK_LEN + 1]).unwrap();
+            assert!(bomb.len() < MAX_CHUNK_LEN);
+            assert!(encoding.decompress(&bomb).is_err());
+            assert_eq!(
+                MAX_CHUNK_LEN,
+                encoding
+                    .decompress(&encoding.compress(&vec![0; MAX_CHUNK_LEN]).unwrap())
+                    .unwrap()
+                    .len()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated() {
+        let config = CompressionConfig::default();
+        let mut frame = encode(&[1; 10], config);
+        frame.pop();
+        let body = stream::iter([Ok::<_, BoxError>(Bytes::from(frame))]);
+        let result = decompress(body, config.encoding).collect::<Vec<_>>().await;
+        assert!(result.last().unwrap().is_err());
+    }
+}
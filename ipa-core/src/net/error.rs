@@ -1,9 +1,16 @@
 use axum::{
     http::StatusCode,
     response::{IntoResponse, Response},
+    Json,
 };
+use serde::{Deserialize, Serialize};
 
-use crate::{error::BoxError, net::client::ResponseFromEndpoint, protocol::QueryId};
+use crate::{
+    error::{BoxError, Error as ProtocolError},
+    helpers::{HelperIdentity, RouteId},
+    net::{client::ResponseFromEndpoint, mux::MuxError, wire_version::WireVersion},
+    protocol::QueryId,
+};
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -25,7 +32,7 @@ pub enum Error {
     BodyAlreadyExtracted(#[from] axum::extract::rejection::BodyAlreadyExtracted),
     #[error(transparent)]
     MissingExtension(#[from] axum::extract::rejection::ExtensionRejection),
-    #[error("query id not found: {}", .0.as_ref())]
+    #[error("query id not found: {0}")]
     QueryIdNotFound(QueryId),
     #[error(transparent)]
     HyperPassthrough(#[from] hyper::Error),
@@ -50,6 +57,9 @@ pub enum Error {
         dest: String,
         status: hyper::StatusCode,
         reason: String,
+        /// Set if the server sent an [`ErrorBody`]. Helpers that predate error codes don't.
+        code: Option<ErrorCode>,
+        gate: Option<String>,
     },
     #[error("Failed to connect to {dest}: {inner}")]
     ConnectError {
@@ -57,8 +67,114 @@ pub enum Error {
         #[source]
         inner: hyper::Error,
     },
+    #[error(transparent)]
+    Mux(#[from] MuxError),
+    #[error("multiplexed connection to {dest:?} was closed before the stream was sent")]
+    MuxConnectionClosed { dest: HelperIdentity },
+    #[error(
+        "wire protocol version {0} is not supported, this helper supports versions {} to {}",
+        WireVersion::MIN_SUPPORTED,
+        WireVersion::CURRENT
+    )]
+    UnsupportedWireVersion(WireVersion),
+    #[error("{route:?} requests are only sent by clients, not to other helpers like {dest:?}")]
+    UnsupportedRoute {
+        dest: HelperIdentity,
+        route: RouteId,
+    },
     #[error("{error}")]
-    Application { code: StatusCode, error: BoxError },
+    Application {
+        code: StatusCode,
+        error_code: ErrorCode,
+        gate: Option<String>,
+        error: BoxError,
+    },
+}
+
+/// Machine-readable category of a failed request, sent to clients in [`ErrorBody`] so they can
+/// tell requests they need to fix from those they may retry and from failures they can only
+/// report.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// Request or query input could not be parsed.
+    InputParse,
+    /// A peer helper failed, misbehaved or could not be reached.
+    PeerFailure,
+    /// Request or query parameters are well-formed, but not valid.
+    Validation,
+    /// This helper can't take the request right now.
+    Capacity,
+    /// Anything else, most likely a bug.
+    Internal,
+}
+
+impl ErrorCode {
+    /// Code of application errors that only come with a status.
+    fn from_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::BAD_REQUEST => Self::InputParse,
+            StatusCode::PAYLOAD_TOO_LARGE
+            | StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::SERVICE_UNAVAILABLE => Self::Capacity,
+            StatusCode::BAD_GATEWAY | StatusCode::GATEWAY_TIMEOUT => Self::PeerFailure,
+            status if status.is_client_error() => Self::Validation,
+            _ => Self::Internal,
+        }
+    }
+
+    /// Status of responses to requests that failed because the protocol failed this way.
+    fn status(self) -> StatusCode {
+        match self {
+            Self::InputParse => StatusCode::BAD_REQUEST,
+            Self::Validation => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::Capacity => StatusCode::SERVICE_UNAVAILABLE,
+            Self::PeerFailure => StatusCode::BAD_GATEWAY,
+            Self::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl From<&ProtocolError> for ErrorCode {
+    fn from(err: &ProtocolError) -> Self {
+        match err {
+            ProtocolError::ParseError(_)
+            | ProtocolError::InvalidReport(_)
+            | ProtocolError::InvalidPseudonym(_)
+            | ProtocolError::DecompressingInvalidCurvePoint(_) => Self::InputParse,
+            #[cfg(feature = "enable-serde")]
+            ProtocolError::Serde(_) => Self::InputParse,
+            ProtocolError::InvalidQueryParameter(_)
+            | ProtocolError::InvalidUserRows(_)
+            | ProtocolError::InvalidId(_)
+            | ProtocolError::InvalidRole
+            | ProtocolError::FieldValueTruncation(_)
+            | ProtocolError::Unsupported(_) => Self::Validation,
+            ProtocolError::InfraError(e) if e.is_peer_failure() => Self::PeerFailure,
+            ProtocolError::NotEnoughHelpers
+            | ProtocolError::TooManyHelpers
+            | ProtocolError::MaliciousSecurityCheckFailed
+            | ProtocolError::MaliciousRevealFailed
+            | ProtocolError::ShuffleVerificationFailed(_) => Self::PeerFailure,
+            ProtocolError::AlreadyExists
+            | ProtocolError::AlreadySetup
+            | ProtocolError::Internal
+            | ProtocolError::NotFound
+            | ProtocolError::Io(_)
+            | ProtocolError::RuntimeError(_)
+            | ProtocolError::InfraError(_) => Self::Internal,
+        }
+    }
+}
+
+/// Body of responses to failed requests.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorBody {
+    pub code: ErrorCode,
+    pub message: String,
+    /// Step the protocol failed at, if it failed while exchanging records with peers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gate: Option<String>,
 }
 
 impl Error {
@@ -79,10 +195,23 @@ impl Error {
         let (endpoint, body) = resp.into_parts();
         hyper::body::to_bytes(body)
             .await
-            .map_or_else(Into::into, |reason_bytes| Error::FailedHttpRequest {
-                dest: endpoint.to_string(),
-                status,
-                reason: String::from_utf8_lossy(&reason_bytes).to_string(),
+            .map_or_else(Into::into, |reason_bytes| {
+                match serde_json::from_slice::<ErrorBody>(&reason_bytes) {
+                    Ok(body) => Error::FailedHttpRequest {
+                        dest: endpoint.to_string(),
+                        status,
+                        reason: body.message,
+                        code: Some(body.code),
+                        gate: body.gate,
+                    },
+                    Err(_) => Error::FailedHttpRequest {
+                        dest: endpoint.to_string(),
+                        status,
+                        reason: String::from_utf8_lossy(&reason_bytes).to_string(),
+                        code: None,
+                        gate: None,
+                    },
+                }
             })
     }
 
@@ -90,6 +219,21 @@ impl Error {
     pub fn application<E: Into<BoxError>>(code: StatusCode, error: E) -> Self {
         Self::Application {
             code,
+            error_code: ErrorCode::from_status(code),
+            gate: None,
+            error: error.into(),
+        }
+    }
+
+    /// Creates an error for a query that failed while running the protocol. Status of the
+    /// response depends on how it failed, see [`ErrorCode`].
+    #[must_use]
+    pub fn protocol(error: ProtocolError) -> Self {
+        let error_code = ErrorCode::from(&error);
+        Self::Application {
+            code: error_code.status(),
+            error_code,
+            gate: error.gate().map(ToOwned::to_owned),
             error: error.into(),
         }
     }
@@ -133,11 +277,14 @@ impl From<axum::extract::rejection::PathRejection> for Error {
 }
 
 impl IntoResponse for Error {
+    /// Responds with an [`ErrorBody`].
     fn into_response(self) -> Response {
-        let status_code = match self {
-            Self::BadQueryString(_) | Self::BadPathString(_) | Self::MissingHeader(_) => {
-                StatusCode::UNPROCESSABLE_ENTITY
-            }
+        let (status_code, code, gate) = match self {
+            Self::BadQueryString(_) | Self::BadPathString(_) | Self::MissingHeader(_) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                ErrorCode::InputParse,
+                None,
+            ),
 
             Self::SerdePassthrough(_)
             | Self::InvalidHeader(_)
@@ -145,18 +292,113 @@ impl IntoResponse for Error {
             | Self::AxumPassthrough(_)
             | Self::InvalidJsonBody(_)
             | Self::QueryIdNotFound(_)
-            | Self::ConnectError { .. } => StatusCode::BAD_REQUEST,
+            | Self::Mux(_)
+            | Self::UnsupportedWireVersion(_) => {
+                (StatusCode::BAD_REQUEST, ErrorCode::InputParse, None)
+            }
+            Self::ConnectError { .. } => (StatusCode::BAD_REQUEST, ErrorCode::PeerFailure, None),
 
+            Self::FailedHttpRequest { .. } | Self::MuxConnectionClosed { .. } => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::PeerFailure,
+                None,
+            ),
             Self::HyperPassthrough { .. }
             | Self::HyperHttpPassthrough(_)
-            | Self::FailedHttpRequest { .. }
+            | Self::UnsupportedRoute { .. }
             | Self::InvalidUri(_)
             | Self::BodyAlreadyExtracted(_)
-            | Self::MissingExtension(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            | Self::MissingExtension(_) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, None)
+            }
 
-            Self::Application { code, .. } => code,
+            Self::Application {
+                code,
+                error_code,
+                ref gate,
+                ..
+            } => (code, error_code, gate.clone()),
         };
 
-        (status_code, self.to_string()).into_response()
+        let body = ErrorBody {
+            code,
+            message: self.to_string(),
+            gate,
+        };
+        (status_code, Json(body)).into_response()
+    }
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use axum::response::IntoResponse;
+    use hyper::StatusCode;
+
+    use super::{Error, ErrorBody, ErrorCode};
+    use crate::{
+        error::Error as ProtocolError,
+        helpers::{ChannelId, Error as InfraError, Role},
+        protocol::step::{Gate, StepNarrow},
+    };
+
+    async fn response_body(err: Error) -> (StatusCode, ErrorBody) {
+        let resp = err.into_response();
+        let status = resp.status();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn application_error() {
+        let (status, body) = response_body(Error::application(
+            StatusCode::TOO_MANY_REQUESTS,
+            "too many queries",
+        ))
+        .await;
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, status);
+        assert_eq!(
+            ErrorBody {
+                code: ErrorCode::Capacity,
+                message: "too many queries".to_string(),
+                gate: None,
+            },
+            body
+        );
+    }
+
+    #[tokio::test]
+    async fn protocol_errors() {
+        let gate = Gate::default().narrow("mul");
+        let (status, body) = response_body(Error::protocol(ProtocolError::InfraError(
+            InfraError::send_error(ChannelId::new(Role::H2, gate.clone()), "peer went away"),
+        )))
+        .await;
+        assert_eq!(StatusCode::BAD_GATEWAY, status);
+        assert_eq!(ErrorCode::PeerFailure, body.code);
+        assert_eq!(Some(gate.as_ref()), body.gate.as_deref());
+
+        let (status, body) = response_body(Error::protocol(ProtocolError::InvalidQueryParameter(
+            "bad cap".into(),
+        )))
+        .await;
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, status);
+        assert_eq!(ErrorCode::Validation, body.code);
+        assert_eq!(None, body.gate);
+
+        let (status, body) = response_body(Error::protocol(ProtocolError::Internal)).await;
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, status);
+        assert_eq!(ErrorCode::Internal, body.code);
+    }
+
+    #[test]
+    fn serde() {
+        let body = ErrorBody {
+            code: ErrorCode::InputParse,
+            message: "bad input".to_string(),
+            gate: None,
+        };
+        let json = serde_json::to_string(&body).unwrap();
+        assert_eq!(r#"{"code":"input_parse","message":"bad input"}"#, json);
+        assert_eq!(body, serde_json::from_str(&json).unwrap());
     }
 }
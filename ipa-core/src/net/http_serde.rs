@@ -79,6 +79,28 @@ pub mod echo {
     pub const AXUM_PATH: &str = "/echo";
 }
 
+pub mod admin {
+    pub mod network {
+        pub const AXUM_PATH: &str = "/admin/network";
+
+        /// Seconds to wait for running queries to complete, unless the request says otherwise.
+        pub const DEFAULT_DRAIN_TIMEOUT_SECS: u64 = 300;
+
+        #[derive(Debug, serde::Deserialize)]
+        pub struct QueryParams {
+            /// Seconds to wait for running queries to complete before the update is given up.
+            pub drain_timeout: Option<u64>,
+        }
+    }
+}
+
+pub mod metrics {
+    pub const AXUM_PATH: &str = "/metrics";
+
+    /// Version 0.0.4 of the Prometheus text exposition format.
+    pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+}
+
 pub mod query {
     use std::fmt::{Display, Formatter};
 
@@ -87,7 +109,7 @@ pub mod query {
 
     use crate::{
         ff::FieldType,
-        helpers::query::{QueryConfig, QuerySize, QueryType},
+        helpers::query::{BreakdownKeyOverflow, QueryConfig, QuerySize, QueryType},
         net::Error,
     };
 
@@ -143,6 +165,10 @@ pub mod query {
                     let Query(q) = req.extract().await?;
                     Ok(QueryType::OprfIpa(q))
                 }
+                QueryType::OPRF_PSEUDONYMS_STR => {
+                    let Query(q) = req.extract().await?;
+                    Ok(QueryType::OprfPseudonyms(q))
+                }
                 other => Err(Error::bad_query_value("query_type", other)),
             }?;
             Ok(QueryConfigQueryParams(QueryConfig {
@@ -182,6 +208,79 @@ pub mod query {
                         write!(f, "&attribution_window_seconds={}", window.get())?;
                     }
 
+                    if let Some(epoch) = config.epoch {
+                        write!(f, "&epoch={epoch}")?;
+                    }
+
+                    if let Some(key_id) = config.key_id {
+                        write!(f, "&key_id={key_id}")?;
+                    }
+
+                    if let Some(epsilon) = config.dp_epsilon {
+                        write!(f, "&dp_epsilon={epsilon}")?;
+                    }
+                    write!(f, "&dp_delta={}", config.dp_delta)?;
+                    if let Some(rows) = config.dp_padding_max_user_rows {
+                        write!(f, "&dp_padding_max_user_rows={rows}")?;
+                    }
+
+                    write!(
+                        f,
+                        "&breakdown_key_bits={}&trigger_value_bits={}&timestamp_bits={}\
+                         &priority_bits={}",
+                        config.breakdown_key_bits,
+                        config.trigger_value_bits,
+                        config.timestamp_bits,
+                        config.priority_bits,
+                    )?;
+
+                    if let Some(seconds) = config.deadline_seconds {
+                        write!(f, "&deadline_seconds={seconds}")?;
+                    }
+
+                    if config.count_attributed_users {
+                        write!(f, "&count_attributed_users=true")?;
+                    }
+
+                    if let Some(rows) = config.user_segment_rows {
+                        write!(
+                            f,
+                            "&user_segment_rows={rows}&max_user_segments={}",
+                            config.max_user_segments
+                        )?;
+                    }
+
+                    if let Some(divisor) = config.output_divisor {
+                        write!(f, "&output_divisor={divisor}")?;
+                    }
+
+                    if let Some(keys) = config.breakdown_allow_list {
+                        write!(f, "&breakdown_allow_list={keys}")?;
+                    }
+
+                    if let Some(days) = config.time_bucket_days {
+                        write!(
+                            f,
+                            "&time_bucket_days={days}&query_epoch_seconds={}&utc_offset_seconds={}",
+                            config.query_epoch_seconds, config.utc_offset_seconds
+                        )?;
+                    }
+
+                    if config.breakdown_key_overflow != BreakdownKeyOverflow::Skip {
+                        write!(
+                            f,
+                            "&breakdown_key_overflow={}",
+                            config.breakdown_key_overflow.as_ref()
+                        )?;
+                    }
+
+                    if config.sort_by_timestamp {
+                        write!(f, "&sort_by_timestamp=true")?;
+                    }
+                    if config.pseudonymized_input {
+                        write!(f, "&pseudonymized_input=true")?;
+                    }
+
                     Ok(())
                 }
                 QueryType::SemiHonestSparseAggregate(config)
@@ -192,6 +291,19 @@ pub mod query {
                         config.contribution_bits, config.num_contributions,
                     )?;
 
+                    Ok(())
+                }
+                QueryType::OprfPseudonyms(config) => {
+                    write!(
+                        f,
+                        "&epoch={}&validity_epochs={}",
+                        config.epoch, config.validity_epochs,
+                    )?;
+
+                    if config.allow_linkable_output {
+                        write!(f, "&allow_linkable_output=true")?;
+                    }
+
                     Ok(())
                 }
             }
@@ -209,7 +321,8 @@ pub mod query {
             helpers::query::QueryConfig,
             net::{
                 http_serde::query::{QueryConfigQueryParams, BASE_AXUM_PATH},
-                Error,
+                idempotency::IDEMPOTENCY_KEY_HEADER,
+                Error, IdempotencyKey,
             },
             protocol::QueryId,
         };
@@ -217,11 +330,21 @@ pub mod query {
         #[derive(Debug, Clone)]
         pub struct Request {
             pub query_config: QueryConfig,
+            pub idempotency_key: Option<IdempotencyKey>,
         }
 
         impl Request {
             pub fn new(query_config: QueryConfig) -> Request {
-                Request { query_config }
+                Request {
+                    query_config,
+                    idempotency_key: None,
+                }
+            }
+
+            #[must_use]
+            pub fn with_idempotency_key(mut self, key: IdempotencyKey) -> Self {
+                self.idempotency_key = Some(key);
+                self
             }
 
             pub fn try_into_http_request(
@@ -238,7 +361,11 @@ pub mod query {
                         QueryConfigQueryParams(self.query_config)
                     ))
                     .build()?;
-                Ok(hyper::Request::post(uri).body(hyper::Body::empty())?)
+                let mut req = hyper::Request::post(uri);
+                if let Some(key) = self.idempotency_key {
+                    req = req.header(&IDEMPOTENCY_KEY_HEADER, key.as_ref());
+                }
+                Ok(req.body(hyper::Body::empty())?)
             }
         }
 
@@ -248,7 +375,11 @@ pub mod query {
 
             async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
                 let QueryConfigQueryParams(query_config) = req.extract().await?;
-                Ok(Self { query_config })
+                let idempotency_key = IdempotencyKey::from_headers(req.headers())?;
+                Ok(Self {
+                    query_config,
+                    idempotency_key,
+                })
             }
         }
 
@@ -270,21 +401,30 @@ pub mod query {
         use hyper::header::CONTENT_TYPE;
 
         use crate::{
-            helpers::{query::PrepareQuery, RoleAssignment},
+            helpers::{
+                query::{PrepareQuery, ResourceReservation},
+                RoleAssignment,
+            },
             net::{
                 http_serde::query::{QueryConfigQueryParams, BASE_AXUM_PATH},
-                Error,
+                Error, WireVersion,
             },
         };
 
         #[derive(Debug, Clone)]
         pub struct Request {
             pub data: PrepareQuery,
+            /// Version of the record framing to use for this query. The leader proposes its
+            /// current version; on the server side, this is the version negotiated with it.
+            pub wire_version: WireVersion,
         }
 
         impl Request {
             pub fn new(data: PrepareQuery) -> Self {
-                Self { data }
+                Self {
+                    data,
+                    wire_version: WireVersion::CURRENT,
+                }
             }
             pub fn try_into_http_request(
                 self,
@@ -297,17 +437,20 @@ pub mod query {
                     .path_and_query(format!(
                         "{}/{}?{}",
                         BASE_AXUM_PATH,
-                        self.data.query_id.as_ref(),
+                        self.data.query_id,
                         QueryConfigQueryParams(self.data.config),
                     ))
                     .build()?;
                 let body = RequestBody {
                     roles: self.data.roles,
+                    reservation: self.data.reservation,
                 };
                 let body = hyper::Body::from(serde_json::to_string(&body)?);
-                Ok(hyper::Request::post(uri)
+                let mut req = hyper::Request::post(uri)
                     .header(CONTENT_TYPE, "application/json")
-                    .body(body)?)
+                    .body(body)?;
+                self.wire_version.insert_into(req.headers_mut());
+                Ok(req)
             }
         }
 
@@ -320,13 +463,16 @@ pub mod query {
             ) -> Result<Self, Self::Rejection> {
                 let Path(query_id) = req.extract().await?;
                 let QueryConfigQueryParams(config) = req.extract().await?;
-                let Json(RequestBody { roles }) = req.extract().await?;
+                let wire_version = WireVersion::from_headers(req.headers())?.negotiate()?;
+                let Json(RequestBody { roles, reservation }) = req.extract().await?;
                 Ok(Request {
                     data: PrepareQuery {
                         query_id,
                         config,
                         roles,
+                        reservation,
                     },
+                    wire_version,
                 })
             }
         }
@@ -334,6 +480,7 @@ pub mod query {
         #[cfg_attr(feature = "enable-serde", derive(serde::Serialize, serde::Deserialize))]
         struct RequestBody {
             roles: RoleAssignment,
+            reservation: ResourceReservation,
         }
 
         pub const AXUM_PATH: &str = "/:query_id";
@@ -342,24 +489,37 @@ pub mod query {
     pub mod input {
         use async_trait::async_trait;
         use axum::{
-            extract::{FromRequest, Path, RequestParts},
+            extract::{FromRequest, Path, Query, RequestParts},
             http::uri,
         };
         use hyper::{header::CONTENT_TYPE, Body};
 
         use crate::{
-            helpers::query::QueryInput,
-            net::{http_serde::query::BASE_AXUM_PATH, Error},
+            helpers::query::{InputPart, QueryInput},
+            net::{
+                http_serde::query::BASE_AXUM_PATH, idempotency::IDEMPOTENCY_KEY_HEADER, Error,
+                IdempotencyKey,
+            },
         };
 
         #[derive(Debug)]
         pub struct Request {
             pub query_input: QueryInput,
+            pub idempotency_key: Option<IdempotencyKey>,
         }
 
         impl Request {
             pub fn new(query_input: QueryInput) -> Self {
-                Self { query_input }
+                Self {
+                    query_input,
+                    idempotency_key: None,
+                }
+            }
+
+            #[must_use]
+            pub fn with_idempotency_key(mut self, key: IdempotencyKey) -> Self {
+                self.idempotency_key = Some(key);
+                self
             }
 
             #[allow(clippy::type_complexity)] // to be addressed in follow-up
@@ -368,19 +528,24 @@ pub mod query {
                 scheme: uri::Scheme,
                 authority: uri::Authority,
             ) -> Result<hyper::Request<Body>, Error> {
+                let mut path_and_query =
+                    format!("{}/{}/input", BASE_AXUM_PATH, self.query_input.query_id,);
+                if self.query_input.part != InputPart::All {
+                    path_and_query.push_str("?part=");
+                    path_and_query.push_str(self.query_input.part.as_ref());
+                }
                 let uri = uri::Uri::builder()
                     .scheme(scheme)
                     .authority(authority)
-                    .path_and_query(format!(
-                        "{}/{}/input",
-                        BASE_AXUM_PATH,
-                        self.query_input.query_id.as_ref(),
-                    ))
+                    .path_and_query(path_and_query)
                     .build()?;
                 let body = Body::wrap_stream(self.query_input.input_stream);
-                Ok(hyper::Request::post(uri)
-                    .header(CONTENT_TYPE, "application/octet-stream")
-                    .body(body)?)
+                let mut req =
+                    hyper::Request::post(uri).header(CONTENT_TYPE, "application/octet-stream");
+                if let Some(key) = self.idempotency_key {
+                    req = req.header(&IDEMPOTENCY_KEY_HEADER, key.as_ref());
+                }
+                Ok(req.body(body)?)
             }
         }
 
@@ -389,14 +554,24 @@ pub mod query {
             type Rejection = Error;
 
             async fn from_request(req: &mut RequestParts<Body>) -> Result<Self, Self::Rejection> {
+                #[derive(serde::Deserialize)]
+                struct InputParams {
+                    #[serde(default)]
+                    part: InputPart,
+                }
+
                 let Path(query_id) = req.extract().await?;
+                let Query(InputParams { part }) = req.extract().await?;
+                let idempotency_key = IdempotencyKey::from_headers(req.headers())?;
                 let input_stream = req.extract().await?;
 
                 Ok(Request {
                     query_input: QueryInput {
                         query_id,
+                        part,
                         input_stream,
                     },
+                    idempotency_key,
                 })
             }
         }
@@ -408,12 +583,15 @@ pub mod query {
         use async_trait::async_trait;
         use axum::{
             extract::{FromRequest, Path, RequestParts},
-            http::uri,
+            http::{uri, HeaderValue},
         };
 
         use crate::{
             helpers::BodyStream,
-            net::{http_serde::query::BASE_AXUM_PATH, Error},
+            net::{
+                compression::RECORD_ENCODING_HEADER, http_serde::query::BASE_AXUM_PATH, Error,
+                RecordEncoding, WireVersion,
+            },
             protocol::{step::Gate, QueryId},
         };
 
@@ -423,6 +601,9 @@ pub mod query {
         pub struct Request<B> {
             pub query_id: QueryId,
             pub gate: Gate,
+            pub wire_version: WireVersion,
+            /// Set if records in the body are compressed.
+            pub encoding: Option<RecordEncoding>,
             pub body: B,
         }
 
@@ -431,9 +612,23 @@ pub mod query {
                 Self {
                     query_id,
                     gate,
+                    wire_version: WireVersion::CURRENT,
+                    encoding: None,
                     body,
                 }
             }
+
+            #[must_use]
+            pub fn with_wire_version(mut self, wire_version: WireVersion) -> Self {
+                self.wire_version = wire_version;
+                self
+            }
+
+            #[must_use]
+            pub fn with_encoding(mut self, encoding: RecordEncoding) -> Self {
+                self.encoding = Some(encoding);
+                self
+            }
         }
 
         /// Convert to hyper request. Used on client side.
@@ -449,11 +644,19 @@ pub mod query {
                     .path_and_query(format!(
                         "{}/{}/step/{}",
                         BASE_AXUM_PATH,
-                        self.query_id.as_ref(),
+                        self.query_id,
                         self.gate.as_ref()
                     ))
                     .build()?;
-                Ok(hyper::Request::post(uri).body(self.body)?)
+                let mut req = hyper::Request::post(uri).body(self.body)?;
+                self.wire_version.insert_into(req.headers_mut());
+                if let Some(encoding) = self.encoding {
+                    req.headers_mut().insert(
+                        &RECORD_ENCODING_HEADER,
+                        HeaderValue::from_static(encoding.as_str()),
+                    );
+                }
+                Ok(req)
             }
         }
 
@@ -473,10 +676,18 @@ pub mod query {
             // Error. Writing `Path` twice somehow avoids that.
             async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
                 let Path((query_id, gate)) = req.extract::<Path<_>>().await?;
+                let wire_version = WireVersion::from_headers(req.headers())?.supported()?;
+                let encoding = req
+                    .headers()
+                    .get(&RECORD_ENCODING_HEADER)
+                    .map(|v| v.to_str().map_err(Error::from).and_then(str::parse))
+                    .transpose()?;
                 let body = req.extract().await?;
                 Ok(Self {
                     query_id,
                     gate,
+                    wire_version,
+                    encoding,
                     body,
                 })
             }
@@ -485,12 +696,98 @@ pub mod query {
         pub const AXUM_PATH: &str = "/:query_id/step/*step";
     }
 
+    pub mod mux {
+        use async_trait::async_trait;
+        use axum::{
+            extract::{FromRequest, Path, RequestParts},
+            http::uri,
+        };
+
+        use crate::{
+            helpers::BodyStream,
+            net::{http_serde::query::BASE_AXUM_PATH, Error, WireVersion},
+            protocol::QueryId,
+        };
+
+        /// Carries record streams for every gate of a query over a single request. See
+        /// `net::mux` module for the body format.
+        #[derive(Debug)]
+        pub struct Request<B> {
+            pub query_id: QueryId,
+            pub wire_version: WireVersion,
+            pub body: B,
+        }
+
+        impl<B> Request<B> {
+            pub fn new(query_id: QueryId, body: B) -> Self {
+                Self {
+                    query_id,
+                    wire_version: WireVersion::CURRENT,
+                    body,
+                }
+            }
+
+            #[must_use]
+            pub fn with_wire_version(mut self, wire_version: WireVersion) -> Self {
+                self.wire_version = wire_version;
+                self
+            }
+        }
+
+        /// Convert to hyper request. Used on client side.
+        impl Request<hyper::Body> {
+            pub fn try_into_http_request(
+                self,
+                scheme: uri::Scheme,
+                authority: uri::Authority,
+            ) -> Result<hyper::Request<hyper::Body>, Error> {
+                let uri = uri::Uri::builder()
+                    .scheme(scheme)
+                    .authority(authority)
+                    .path_and_query(format!("{}/{}/mux", BASE_AXUM_PATH, self.query_id,))
+                    .build()?;
+                let mut req = hyper::Request::post(uri).body(self.body)?;
+                self.wire_version.insert_into(req.headers_mut());
+                Ok(req)
+            }
+        }
+
+        /// Convert from axum request. Used on server side.
+        #[async_trait]
+        impl<B> FromRequest<B> for Request<BodyStream>
+        where
+            B: Send,
+            BodyStream: FromRequest<B>,
+            Error: From<<BodyStream as FromRequest<B>>::Rejection>,
+        {
+            type Rejection = Error;
+
+            async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+                let Path(query_id) = req.extract::<Path<_>>().await?;
+                let wire_version = WireVersion::from_headers(req.headers())?.supported()?;
+                let body = req.extract().await?;
+                Ok(Self {
+                    query_id,
+                    wire_version,
+                    body,
+                })
+            }
+        }
+
+        pub const AXUM_PATH: &str = "/:query_id/mux";
+    }
+
     pub mod status {
         use async_trait::async_trait;
         use axum::extract::{FromRequest, Path, RequestParts};
         use serde::{Deserialize, Serialize};
 
-        use crate::{net::Error, protocol::QueryId, query::QueryStatus};
+        use crate::{
+            helpers::{QueryMetrics, QueryProgress},
+            net::Error,
+            protocol::QueryId,
+            query::QueryStatus,
+        };
 
         #[derive(Debug, Clone)]
         pub struct Request {
@@ -515,7 +812,7 @@ pub mod query {
                     .path_and_query(format!(
                         "{}/{}",
                         crate::net::http_serde::query::BASE_AXUM_PATH,
-                        self.query_id.as_ref()
+                        self.query_id
                     ))
                     .build()?;
                 Ok(hyper::Request::get(uri).body(hyper::Body::empty())?)
@@ -535,6 +832,12 @@ pub mod query {
         #[derive(Clone, Debug, Serialize, Deserialize)]
         pub struct ResponseBody {
             pub status: QueryStatus,
+            /// Progress of the query on this helper, reported only while it is running.
+            #[serde(default)]
+            pub progress: Option<QueryProgress>,
+            /// Per-stage metrics collected by this helper so far.
+            #[serde(default)]
+            pub metrics: Option<QueryMetrics>,
         }
 
         pub const AXUM_PATH: &str = "/:query_id";
@@ -569,7 +872,7 @@ pub mod query {
                     .path_and_query(format!(
                         "{}/{}/complete",
                         crate::net::http_serde::query::BASE_AXUM_PATH,
-                        self.query_id.as_ref()
+                        self.query_id
                     ))
                     .build()?;
                 Ok(hyper::Request::get(uri).body(hyper::Body::empty())?)
@@ -587,5 +890,27 @@ pub mod query {
         }
 
         pub const AXUM_PATH: &str = "/:query_id/complete";
+
+        /// Set on responses with the output of a query that went through the result cache, see
+        /// [`ResultCache`](crate::query::ResultCache). Its value is `hit` or `miss`.
+        pub static CACHE_STATUS_HEADER: axum::http::HeaderName =
+            axum::http::HeaderName::from_static("x-ipa-cache-status");
+
+        /// Set on responses with the output of a query that was stopped by its deadline before
+        /// it processed all users. Its value is `<processed users>/<total users>`.
+        pub static PROCESSED_USERS_HEADER: axum::http::HeaderName =
+            axum::http::HeaderName::from_static("x-ipa-processed-users");
+
+        /// Set on responses with the output of a query that keys totals by time bucket as well as
+        /// by breakdown, see [`OutputShape`](crate::protocol::ipa_prf::pipeline::OutputShape). Its
+        /// value is `<breakdowns>x<time buckets>`.
+        pub static OUTPUT_SHAPE_HEADER: axum::http::HeaderName =
+            axum::http::HeaderName::from_static("x-ipa-output-shape");
+
+        /// Set on responses with the output of a query if the helper signs query reports. Its
+        /// value is the [`SignedQueryReport`](crate::query::SignedQueryReport) serialized to
+        /// JSON.
+        pub static QUERY_REPORT_HEADER: axum::http::HeaderName =
+            axum::http::HeaderName::from_static("x-ipa-query-report");
     }
 }
@@ -0,0 +1,291 @@
+//! Deduplication of retried requests sent by report collectors.
+//!
+//! Requests that change query state (`create_query` and `query_input`) may carry an
+//! `Idempotency-Key` header. The helper remembers the outcome of every successful request made
+//! with a key and, for the duration of the retention window, responds to any request with the
+//! same key with that outcome instead of executing it again. Failed requests are forgotten, so
+//! they can be retried with the same key.
+//!
+//! Keys are scoped to the endpoint they were used with. Reusing a key with a different request
+//! to the same endpoint is rejected.
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Formatter},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use hyper::{header::HeaderName, http::HeaderMap, StatusCode};
+
+use crate::{
+    net::Error,
+    rand::{thread_rng, RngCore},
+    sync::Mutex,
+};
+
+pub static IDEMPOTENCY_KEY_HEADER: HeaderName = HeaderName::from_static("idempotency-key");
+
+/// How long the outcome of a request is remembered, unless configured otherwise.
+pub const DEFAULT_RETENTION: Duration = Duration::from_secs(60 * 60);
+
+const MAX_KEY_LEN: usize = 255;
+
+/// A client-chosen token that identifies a single logical request across retries.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct IdempotencyKey(String);
+
+impl IdempotencyKey {
+    /// Generates a new random key.
+    #[must_use]
+    pub fn random() -> Self {
+        let mut bytes = [0_u8; 16];
+        thread_rng().fill_bytes(&mut bytes);
+        Self(hex::encode(bytes))
+    }
+
+    /// Reads the key from request headers, if it is present.
+    ///
+    /// ## Errors
+    /// If the header value is not a valid key.
+    pub fn from_headers(headers: &HeaderMap) -> Result<Option<Self>, Error> {
+        headers
+            .get(&IDEMPOTENCY_KEY_HEADER)
+            .map(|v| v.to_str().map_err(Error::from).and_then(str::parse))
+            .transpose()
+    }
+}
+
+impl FromStr for IdempotencyKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() || s.len() > MAX_KEY_LEN || !s.bytes().all(|b| b.is_ascii_graphic()) {
+            Err(Error::InvalidHeader(
+                format!(
+                    "{IDEMPOTENCY_KEY_HEADER} must consist of 1 to {MAX_KEY_LEN} visible ASCII characters"
+                )
+                .into(),
+            ))
+        } else {
+            Ok(Self(s.to_string()))
+        }
+    }
+}
+
+impl AsRef<str> for IdempotencyKey {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Debug for IdempotencyKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "IdempotencyKey[{}]", self.0)
+    }
+}
+
+enum Entry<R, T> {
+    InProgress {
+        request: R,
+    },
+    Completed {
+        request: R,
+        outcome: T,
+        completed_at: Instant,
+    },
+}
+
+impl<R, T> Entry<R, T> {
+    fn request(&self) -> &R {
+        match self {
+            Self::InProgress { request } | Self::Completed { request, .. } => request,
+        }
+    }
+}
+
+/// Remembers outcomes of requests of type `R` that produced `T`, indexed by idempotency key.
+pub struct IdempotencyCache<R, T> {
+    retention: Duration,
+    entries: Mutex<HashMap<IdempotencyKey, Entry<R, T>>>,
+}
+
+impl<R, T> Default for IdempotencyCache<R, T> {
+    fn default() -> Self {
+        Self::new(DEFAULT_RETENTION)
+    }
+}
+
+/// Result of an attempt to execute a request with an idempotency key.
+pub enum Reservation<'a, R, T> {
+    /// This key has not been seen before. The request must be executed and, if it succeeds,
+    /// its outcome recorded via [`ReservationGuard::complete`].
+    New(ReservationGuard<'a, R, T>),
+    /// The request was executed before, this is its outcome.
+    Completed(T),
+}
+
+impl<R, T> IdempotencyCache<R, T> {
+    #[must_use]
+    pub fn new(retention: Duration) -> Self {
+        Self {
+            retention,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<R: PartialEq, T: Clone> IdempotencyCache<R, T> {
+    /// Claims the key for the given request.
+    ///
+    /// ## Errors
+    /// If the key was already used for a different request, or if a request with this key is
+    /// still being processed.
+    pub fn reserve(&self, key: IdempotencyKey, request: R) -> Result<Reservation<'_, R, T>, Error> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| match entry {
+            Entry::InProgress { .. } => true,
+            Entry::Completed { completed_at, .. } => completed_at.elapsed() < self.retention,
+        });
+
+        if let Some(entry) = entries.get(&key) {
+            if entry.request() != &request {
+                return Err(Error::application(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    format!("{key:?} was already used for a different request"),
+                ));
+            }
+            return match entry {
+                Entry::InProgress { .. } => Err(Error::application(
+                    StatusCode::CONFLICT,
+                    format!("request with {key:?} is still in progress"),
+                )),
+                Entry::Completed { outcome, .. } => Ok(Reservation::Completed(outcome.clone())),
+            };
+        }
+
+        entries.insert(key.clone(), Entry::InProgress { request });
+        Ok(Reservation::New(ReservationGuard {
+            cache: self,
+            key: Some(key),
+        }))
+    }
+}
+
+/// Releases the key if dropped before the request is completed, so a failed request can be
+/// retried.
+pub struct ReservationGuard<'a, R, T> {
+    cache: &'a IdempotencyCache<R, T>,
+    key: Option<IdempotencyKey>,
+}
+
+impl<R, T> ReservationGuard<'_, R, T> {
+    /// Records the outcome of a successful request.
+    pub fn complete(mut self, outcome: T) {
+        let key = self.key.take().unwrap();
+        let mut entries = self.cache.entries.lock().unwrap();
+        if let Some(Entry::InProgress { request }) = entries.remove(&key) {
+            entries.insert(
+                key,
+                Entry::Completed {
+                    request,
+                    outcome,
+                    completed_at: Instant::now(),
+                },
+            );
+        }
+    }
+}
+
+impl<R, T> Drop for ReservationGuard<'_, R, T> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.cache.entries.lock().unwrap().remove(&key);
+        }
+    }
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use std::time::Duration;
+
+    use hyper::{http::HeaderMap, StatusCode};
+
+    use super::{IdempotencyCache, IdempotencyKey, Reservation, IDEMPOTENCY_KEY_HEADER};
+    use crate::net::Error;
+
+    fn key(s: &str) -> IdempotencyKey {
+        s.parse().unwrap()
+    }
+
+    fn assert_status<T>(expected: StatusCode, result: Result<T, Error>) {
+        match result {
+            Err(Error::Application { code, .. }) => assert_eq!(expected, code),
+            Err(e) => panic!("unexpected error: {e}"),
+            Ok(_) => panic!("expected request to be rejected"),
+        }
+    }
+
+    #[test]
+    fn parse_key() {
+        assert!("a1b2-c3".parse::<IdempotencyKey>().is_ok());
+        assert!("".parse::<IdempotencyKey>().is_err());
+        assert!("has space".parse::<IdempotencyKey>().is_err());
+        assert!("x".repeat(256).parse::<IdempotencyKey>().is_err());
+        assert_ne!(IdempotencyKey::random(), IdempotencyKey::random());
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(None, IdempotencyKey::from_headers(&headers).unwrap());
+        headers.insert(&IDEMPOTENCY_KEY_HEADER, "foo".parse().unwrap());
+        assert_eq!(
+            Some(key("foo")),
+            IdempotencyKey::from_headers(&headers).unwrap()
+        );
+    }
+
+    #[test]
+    fn returns_original_outcome() {
+        let cache = IdempotencyCache::<u32, &str>::default();
+        let Reservation::New(guard) = cache.reserve(key("k"), 1).unwrap() else {
+            panic!("key must be new");
+        };
+        // retry while the first request is in flight
+        assert_status(StatusCode::CONFLICT, cache.reserve(key("k"), 1));
+        guard.complete("done");
+
+        assert!(matches!(
+            cache.reserve(key("k"), 1),
+            Ok(Reservation::Completed("done"))
+        ));
+        assert_status(StatusCode::UNPROCESSABLE_ENTITY, cache.reserve(key("k"), 2));
+        assert!(matches!(
+            cache.reserve(key("other"), 2),
+            Ok(Reservation::New(_))
+        ));
+    }
+
+    #[test]
+    fn failed_request_can_be_retried() {
+        let cache = IdempotencyCache::<u32, ()>::default();
+        let guard = cache.reserve(key("k"), 1).unwrap();
+        drop(guard);
+
+        assert!(matches!(
+            cache.reserve(key("k"), 1),
+            Ok(Reservation::New(_))
+        ));
+    }
+
+    #[test]
+    fn expires_after_retention() {
+        let cache = IdempotencyCache::<u32, ()>::new(Duration::ZERO);
+        let Reservation::New(guard) = cache.reserve(key("k"), 1).unwrap() else {
+            panic!("key must be new");
+        };
+        guard.complete(());
+
+        assert!(matches!(
+            cache.reserve(key("k"), 2),
+            Ok(Reservation::New(_))
+        ));
+    }
+}
@@ -1,12 +1,20 @@
 mod client;
+mod compression;
 mod error;
 mod http_serde;
+mod idempotency;
+mod mux;
 mod server;
 #[cfg(all(test, not(feature = "shuttle")))]
 pub mod test;
+mod throttle;
 mod transport;
+mod wire_version;
 
 pub use client::{ClientIdentity, MpcHelperClient};
-pub use error::Error;
+pub use compression::RecordEncoding;
+pub use error::{Error, ErrorBody, ErrorCode};
+pub use idempotency::IdempotencyKey;
 pub use server::{MpcHelperServer, TracingSpanMaker};
 pub use transport::HttpTransport;
+pub use wire_version::WireVersion;
@@ -0,0 +1,518 @@
+//! Multiplexing of MPC record streams over a single HTTP request.
+//!
+//! By default, every (query, gate) stream is sent as its own HTTP request, which creates thousands
+//! of requests for deep circuits. When multiplexing is enabled, a helper opens one long-lived
+//! request per query and peer instead and sends all of its streams over it, chopped into frames
+//! tagged with a stream id:
+//!
+//! ```text
+//! +------+-------------+-------------+---------------+
+//! | kind |  stream id  |   length    |    payload    |
+//! | (u8) | (u32, LE)   | (u32, LE)   | length bytes  |
+//! +------+-------------+-------------+---------------+
+//! ```
+//!
+//! An `Open` frame carries the gate of a new stream, `Data` frames carry its records and a `Close`
+//! frame marks its end. Streams are polled in round-robin order, so one busy gate can't starve the
+//! others sharing the connection.
+//!
+//! On the receiving side, every stream buffers up to [`STREAM_BUFFER`] frames. Once a stream that
+//! the protocol does not read yet fills its buffer, the connection stops reading until it does,
+//! which pushes back on the sender instead of buffering the whole stream in memory. Helpers that
+//! multiplex streams should enable flow control, so that no stream gets that far ahead of the
+//! protocol.
+//!
+//! Frames carry at most [`MAX_FRAME_LEN`] bytes of payload. Larger chunks are split into several
+//! frames by the sender, and a receiver rejects frames that claim to be larger, before buffering
+//! any of their payload.
+
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    str,
+    task::{Context, Poll},
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::{
+    channel::{mpsc, oneshot},
+    future::ready,
+    stream::{self, BoxStream, SelectAll},
+    SinkExt, Stream, StreamExt,
+};
+
+use crate::{error::BoxError, helpers::BodyStream, protocol::step::Gate};
+
+const HEADER_LEN: usize = 9;
+
+/// Number of frames every demultiplexed stream buffers before the connection stops reading.
+pub const STREAM_BUFFER: usize = 64;
+
+/// Largest payload a single frame may carry.
+pub const MAX_FRAME_LEN: usize = 1 << 20;
+
+/// Frame ready to be written to the connection, along with whoever waits for it to be written.
+struct Outgoing {
+    frame: Bytes,
+    on_written: Option<oneshot::Sender<()>>,
+}
+
+impl From<Bytes> for Outgoing {
+    fn from(frame: Bytes) -> Self {
+        Self {
+            frame,
+            on_written: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FrameKind {
+    Open = 0,
+    Data = 1,
+    Close = 2,
+}
+
+impl TryFrom<u8> for FrameKind {
+    type Error = MuxError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Open),
+            1 => Ok(Self::Data),
+            2 => Ok(Self::Close),
+            _ => Err(MuxError::UnknownFrameKind(value)),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum MuxError {
+    #[error("unknown frame kind {0}")]
+    UnknownFrameKind(u8),
+    #[error("stream {0} is already open")]
+    DuplicateStream(u32),
+    #[error("stream {0} is not open")]
+    UnknownStream(u32),
+    #[error("gate name is not valid UTF-8: {0}")]
+    InvalidGate(#[from] str::Utf8Error),
+    #[error("frame of {0} bytes exceeds the limit of {} bytes", MAX_FRAME_LEN)]
+    FrameTooLarge(usize),
+    #[error("multiplexed body ended in the middle of a frame or with streams still open")]
+    Truncated,
+    #[error("failed to read multiplexed body: {0}")]
+    Body(#[source] BoxError),
+}
+
+/// Encodes a single frame.
+///
+/// ## Panics
+/// If payload is larger than [`MAX_FRAME_LEN`] bytes.
+fn encode(kind: FrameKind, stream_id: u32, payload: &[u8]) -> Bytes {
+    assert!(
+        payload.len() <= MAX_FRAME_LEN,
+        "frame payload must not exceed {MAX_FRAME_LEN} bytes"
+    );
+    let mut buf = BytesMut::with_capacity(HEADER_LEN + payload.len());
+    buf.put_u8(kind as u8);
+    buf.put_u32_le(stream_id);
+    buf.put_u32_le(u32::try_from(payload.len()).unwrap());
+    buf.put_slice(payload);
+    buf.freeze()
+}
+
+/// Takes the next frame off the front of `buf`, if it has been received in full.
+fn decode(buf: &mut BytesMut) -> Result<Option<(FrameKind, u32, Bytes)>, MuxError> {
+    if buf.len() < HEADER_LEN {
+        return Ok(None);
+    }
+    let kind = FrameKind::try_from(buf[0])?;
+    let stream_id = u32::from_le_bytes(buf[1..5].try_into().unwrap());
+    let len = u32::from_le_bytes(buf[5..HEADER_LEN].try_into().unwrap()) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(MuxError::FrameTooLarge(len));
+    }
+    if buf.len() < HEADER_LEN + len {
+        return Ok(None);
+    }
+    buf.advance(HEADER_LEN);
+    Ok(Some((kind, stream_id, buf.split_to(len).freeze())))
+}
+
+/// Sending half of a multiplexed connection. Record streams added to it are interleaved into
+/// the [`MuxBody`] returned alongside.
+pub struct Multiplexer {
+    next_stream_id: u32,
+    streams: mpsc::UnboundedSender<BoxStream<'static, Outgoing>>,
+}
+
+impl Multiplexer {
+    #[must_use]
+    pub fn new() -> (Self, MuxBody) {
+        let (tx, rx) = mpsc::unbounded();
+        (
+            Self {
+                next_stream_id: 0,
+                streams: tx,
+            },
+            MuxBody {
+                incoming: rx,
+                incoming_done: false,
+                active: SelectAll::new(),
+                written: None,
+            },
+        )
+    }
+
+    /// Adds `data` sent on `gate` to this connection. The returned receiver resolves once all of
+    /// `data` has been written to the connection, or fails if the connection is dropped before
+    /// that.
+    pub fn add<S>(&mut self, gate: &Gate, data: S) -> oneshot::Receiver<()>
+    where
+        S: Stream<Item = Vec<u8>> + Send + 'static,
+    {
+        let stream_id = self.next_stream_id;
+        self.next_stream_id += 1;
+
+        let (tx, rx) = oneshot::channel();
+        // Gate is encoded the same way it appears in the step URL path.
+        let open = encode(
+            FrameKind::Open,
+            stream_id,
+            format!("/{}", gate.as_ref()).as_bytes(),
+        );
+        let frames = stream::once(ready(Outgoing::from(open)))
+            .chain(data.flat_map(move |chunk| {
+                stream::iter(
+                    chunk
+                        .chunks(MAX_FRAME_LEN)
+                        .map(|payload| encode(FrameKind::Data, stream_id, payload).into())
+                        .collect::<Vec<Outgoing>>(),
+                )
+            }))
+            .chain(stream::once(ready(Outgoing {
+                frame: encode(FrameKind::Close, stream_id, &[]),
+                on_written: Some(tx),
+            })));
+
+        // If the connection is gone, `tx` is dropped along with `frames` and the caller gets
+        // notified through the receiver.
+        let _ = self.streams.unbounded_send(frames.boxed());
+
+        rx
+    }
+}
+
+/// Body of a multiplexed request. Ends once the [`Multiplexer`] is dropped and all streams
+/// added to it are fully sent.
+pub struct MuxBody {
+    incoming: mpsc::UnboundedReceiver<BoxStream<'static, Outgoing>>,
+    incoming_done: bool,
+    active: SelectAll<BoxStream<'static, Outgoing>>,
+    /// Notified once the last frame this body yielded is written, which is the case when the
+    /// connection asks for the next one.
+    written: Option<oneshot::Sender<()>>,
+}
+
+impl Stream for MuxBody {
+    type Item = Bytes;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if let Some(tx) = this.written.take() {
+            // receiver may be gone if the sender is no longer interested in the outcome
+            let _ = tx.send(());
+        }
+        while !this.incoming_done {
+            match this.incoming.poll_next_unpin(cx) {
+                Poll::Ready(Some(stream)) => this.active.push(stream),
+                Poll::Ready(None) => this.incoming_done = true,
+                Poll::Pending => break,
+            }
+        }
+
+        // `SelectAll` yields one frame from a stream before moving it to the back of its queue.
+        match this.active.poll_next_unpin(cx) {
+            Poll::Ready(Some(Outgoing { frame, on_written })) => {
+                this.written = on_written;
+                Poll::Ready(Some(frame))
+            }
+            Poll::Ready(None) if this.incoming_done => Poll::Ready(None),
+            Poll::Ready(None) | Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Splits a multiplexed `body` back into record streams, calling `on_stream` for every stream
+/// as soon as it is opened. Resolves once `body` is fully consumed.
+///
+/// Each stream buffers up to [`STREAM_BUFFER`] frames, and reading `body` waits for streams with
+/// full buffers, see the [module documentation](self).
+///
+/// ## Errors
+/// If `body` fails or is not a valid multiplexed stream. The error is also delivered to every
+/// stream that was open at the time.
+pub async fn demultiplex<S, F>(mut body: S, mut on_stream: F) -> Result<(), MuxError>
+where
+    S: Stream<Item = Result<Bytes, BoxError>> + Unpin,
+    F: FnMut(Gate, BodyStream),
+{
+    let mut buf = BytesMut::new();
+    let mut streams = HashMap::new();
+
+    let result = async {
+        loop {
+            while let Some((kind, stream_id, payload)) = decode(&mut buf)? {
+                match kind {
+                    FrameKind::Open => {
+                        let gate = Gate::from(str::from_utf8(&payload)?);
+                        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+                        if streams.insert(stream_id, tx).is_some() {
+                            return Err(MuxError::DuplicateStream(stream_id));
+                        }
+                        on_stream(gate, BodyStream::from_bytes_stream(rx));
+                    }
+                    FrameKind::Data => {
+                        // receiver may be dropped if the query is done with this stream
+                        let _ = streams
+                            .get_mut(&stream_id)
+                            .ok_or(MuxError::UnknownStream(stream_id))?
+                            .send(Ok(payload))
+                            .await;
+                    }
+                    FrameKind::Close => {
+                        streams
+                            .remove(&stream_id)
+                            .ok_or(MuxError::UnknownStream(stream_id))?;
+                    }
+                }
+            }
+
+            match body.next().await {
+                Some(chunk) => buf.extend_from_slice(&chunk.map_err(MuxError::Body)?),
+                None if buf.is_empty() && streams.is_empty() => return Ok(()),
+                None => return Err(MuxError::Truncated),
+            }
+        }
+    }
+    .await;
+
+    if let Err(e) = &result {
+        // Streams with full buffers end without the error, which their receivers see as a
+        // truncated stream.
+        for tx in streams.values_mut() {
+            let _ = tx.try_send(Err(e.to_string().into()));
+        }
+    }
+
+    result
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use std::collections::HashMap;
+
+    use std::pin::pin;
+
+    use bytes::Bytes;
+    use futures::{channel::mpsc, future::join, poll, stream, StreamExt};
+
+    use super::{
+        demultiplex, encode, FrameKind, Multiplexer, MuxError, HEADER_LEN, MAX_FRAME_LEN,
+        STREAM_BUFFER,
+    };
+    use crate::{
+        error::BoxError,
+        helpers::{BodyStream, BytesStream},
+        protocol::step::{Gate, StepNarrow},
+    };
+
+    /// Re-chunks `body` into pieces of `size` bytes, so frames end up split across chunks.
+    fn rechunk(body: &[Bytes], size: usize) -> Vec<Result<Bytes, BoxError>> {
+        body.concat()
+            .chunks(size)
+            .map(|c| Ok(Bytes::copy_from_slice(c)))
+            .collect()
+    }
+
+    async fn collect(
+        body: Vec<Result<Bytes, BoxError>>,
+    ) -> (Result<(), MuxError>, Vec<BodyStream>) {
+        let mut streams = Vec::new();
+        let result = demultiplex(stream::iter(body), |gate, stream| {
+            streams.push((gate, stream));
+        })
+        .await;
+        streams.sort_by(|a, b| a.0.cmp(&b.0));
+
+        (
+            result,
+            streams.into_iter().map(|(_, stream)| stream).collect(),
+        )
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let gates = ["a", "b", "c"].map(|s| Gate::default().narrow(s));
+        let data = HashMap::from([
+            (gates[0].clone(), vec![vec![1_u8, 2, 3], vec![4, 5]]),
+            (gates[1].clone(), vec![]),
+            (gates[2].clone(), vec![vec![6_u8; 100]]),
+        ]);
+
+        let (mut mux, body) = Multiplexer::new();
+        let sent = gates
+            .iter()
+            .map(|gate| mux.add(gate, stream::iter(data[gate].clone())))
+            .collect::<Vec<_>>();
+        drop(mux);
+
+        let body = body.collect::<Vec<_>>().await;
+        for s in sent {
+            s.await.unwrap();
+        }
+
+        for size in [1, 7, 1000] {
+            let (result, streams) = collect(rechunk(&body, size)).await;
+            result.unwrap();
+            assert_eq!(gates.len(), streams.len());
+            for (gate, stream) in gates.iter().zip(streams) {
+                assert_eq!(data[gate].concat(), stream.to_vec().await, "{gate:?}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn interleaves_streams() {
+        let (mut mux, body) = Multiplexer::new();
+        let _a = mux.add(
+            &Gate::default().narrow("a"),
+            stream::iter(vec![vec![1_u8]; 3]),
+        );
+        let _b = mux.add(
+            &Gate::default().narrow("b"),
+            stream::iter(vec![vec![2_u8]; 3]),
+        );
+        drop(mux);
+
+        let payloads = body
+            .filter_map(
+                |frame| async move { (frame[0] == FrameKind::Data as u8).then(|| frame[9]) },
+            )
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(vec![1, 2, 1, 2, 1, 2], payloads);
+    }
+
+    #[tokio::test]
+    async fn notifies_once_written() {
+        let (mut mux, mut body) = Multiplexer::new();
+        let mut sent = mux.add(&Gate::default().narrow("a"), stream::iter(vec![vec![1_u8]]));
+        drop(mux);
+
+        // open, data and close frames
+        for _ in 0..3 {
+            body.next().await.unwrap();
+        }
+        assert_eq!(Ok(None), sent.try_recv());
+        assert!(body.next().await.is_none());
+        assert_eq!(Ok(Some(())), sent.try_recv());
+    }
+
+    #[tokio::test]
+    async fn full_stream_holds_up_connection() {
+        let (mut mux, body) = Multiplexer::new();
+        let _sent = mux.add(
+            &Gate::default().narrow("a"),
+            stream::iter(vec![vec![1_u8]; 2 * STREAM_BUFFER]),
+        );
+        drop(mux);
+        let body = body.map(Ok::<_, BoxError>).collect::<Vec<_>>().await;
+
+        let (tx, mut rx) = mpsc::unbounded();
+        let mut demux = pin!(demultiplex(stream::iter(body), move |_, stream| {
+            tx.unbounded_send(stream).unwrap();
+        }));
+        assert!(poll!(&mut demux).is_pending());
+
+        let stream = rx.next().await.unwrap();
+        let (result, received) = join(demux, stream.to_vec()).await;
+        result.unwrap();
+        assert_eq!(vec![1_u8; 2 * STREAM_BUFFER], received);
+    }
+
+    #[tokio::test]
+    async fn dropped_body() {
+        let (mut mux, body) = Multiplexer::new();
+        let sent = mux.add(&Gate::default().narrow("a"), stream::iter(vec![vec![1_u8]]));
+        drop(body);
+
+        sent.await.unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn truncated() {
+        let (mut mux, body) = Multiplexer::new();
+        let _sent = mux.add(
+            &Gate::default().narrow("a"),
+            stream::iter(vec![vec![1_u8; 10]]),
+        );
+        drop(mux);
+
+        let mut body = rechunk(&body.collect::<Vec<_>>().await, 1);
+        body.truncate(body.len() - 1);
+        let (result, streams) = collect(body).await;
+
+        assert!(matches!(result, Err(MuxError::Truncated)));
+        let received = streams
+            .into_iter()
+            .next()
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        assert!(received.last().unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn unknown_stream() {
+        let body = vec![Ok(encode(FrameKind::Data, 1, &[1, 2, 3]))];
+        let (result, _) = collect(body).await;
+
+        assert!(matches!(result, Err(MuxError::UnknownStream(1))));
+    }
+
+    #[tokio::test]
+    async fn splits_large_chunks() {
+        let (mut mux, body) = Multiplexer::new();
+        let data = vec![7_u8; 2 * MAX_FRAME_LEN + 1];
+        let _sent = mux.add(
+            &Gate::default().narrow("a"),
+            stream::iter(vec![data.clone()]),
+        );
+        drop(mux);
+
+        let body = body.collect::<Vec<_>>().await;
+        // open, three data frames and close
+        assert_eq!(5, body.len());
+        assert!(body
+            .iter()
+            .all(|frame| frame.len() <= HEADER_LEN + MAX_FRAME_LEN));
+
+        let (result, streams) = collect(rechunk(&body, 4096)).await;
+        result.unwrap();
+        assert_eq!(data, streams.into_iter().next().unwrap().to_vec().await);
+    }
+
+    #[tokio::test]
+    async fn frame_too_large() {
+        let mut header = vec![FrameKind::Data as u8];
+        header.extend_from_slice(&0_u32.to_le_bytes());
+        header.extend_from_slice(&u32::MAX.to_le_bytes());
+        let (result, _) = collect(vec![Ok(Bytes::from(header))]).await;
+
+        assert!(matches!(
+            result,
+            Err(MuxError::FrameTooLarge(len)) if len == u32::MAX as usize
+        ));
+    }
+}
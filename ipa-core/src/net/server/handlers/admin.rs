@@ -0,0 +1,188 @@
+use std::time::Duration;
+
+use axum::{
+    extract::Query,
+    http::{header::AUTHORIZATION, HeaderMap},
+    routing::put,
+    Extension, Router,
+};
+use hyper::StatusCode;
+
+use crate::{
+    config::NetworkConfig,
+    net::{
+        http_serde::{self, admin::network::DEFAULT_DRAIN_TIMEOUT_SECS},
+        Error, HttpTransport,
+    },
+    sync::Arc,
+};
+
+/// Token that authorizes requests to admin endpoints, see [`ServerConfig::admin_token`].
+///
+/// [`ServerConfig::admin_token`]: crate::config::ServerConfig::admin_token
+#[derive(Clone)]
+struct AdminToken(Arc<str>);
+
+impl AdminToken {
+    /// ## Errors
+    /// If the request does not carry this token in its `Authorization: Bearer` header.
+    fn authorize(&self, headers: &HeaderMap) -> Result<(), Error> {
+        let token = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        // Compare all bytes, so that response times don't tell how much of a guess was right.
+        let authorized = token.is_some_and(|token| {
+            token.len() == self.0.len()
+                && token
+                    .bytes()
+                    .zip(self.0.bytes())
+                    .fold(0, |acc, (a, b)| acc | (a ^ b))
+                    == 0
+        });
+        if authorized {
+            Ok(())
+        } else {
+            Err(Error::application(
+                StatusCode::UNAUTHORIZED,
+                "admin token is missing or not valid",
+            ))
+        }
+    }
+}
+
+/// Replaces the network configuration of this helper with the one in the request body, which has
+/// the format of the network configuration file helpers are started with. Responds once running
+/// queries completed and the new configuration is in use, see [`HttpTransport::update_network`].
+async fn update_network(
+    transport: Extension<Arc<HttpTransport>>,
+    token: Extension<AdminToken>,
+    headers: HeaderMap,
+    Query(params): Query<http_serde::admin::network::QueryParams>,
+    body: String,
+) -> Result<(), Error> {
+    token.authorize(&headers)?;
+    let network = NetworkConfig::from_toml_str(&body)
+        .map_err(|e| Error::application(StatusCode::UNPROCESSABLE_ENTITY, e))?;
+    let drain_timeout =
+        Duration::from_secs(params.drain_timeout.unwrap_or(DEFAULT_DRAIN_TIMEOUT_SECS));
+    transport.update_network(network, drain_timeout).await
+}
+
+/// Admin endpoints are only served if the server is configured with an admin token.
+pub fn router(transport: Arc<HttpTransport>) -> Router {
+    let Some(token) = transport.server_config().admin_token.clone() else {
+        return Router::new();
+    };
+    Router::new()
+        .route(http_serde::admin::network::AXUM_PATH, put(update_network))
+        .layer(Extension(transport))
+        .layer(Extension(AdminToken(token.into())))
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use std::future::ready;
+
+    use hyper::{http::uri::Scheme, Body, Request};
+
+    use super::*;
+    use crate::{
+        helpers::{HelperMetrics, TransportCallbacks},
+        net::test::TestServer,
+    };
+
+    const TOKEN: &str = "correct horse battery staple";
+
+    const NETWORK: &str = r#"
+[[peers]]
+url = "helper1.example.com:443"
+
+[[peers]]
+url = "helper2.example.com:443"
+
+[[peers]]
+url = "helper3.example.com:443"
+"#;
+
+    async fn server(active_queries: usize) -> TestServer {
+        let cb = TransportCallbacks {
+            metrics: Box::new(move |_transport| {
+                Box::pin(ready(HelperMetrics {
+                    active_queries,
+                    ..Default::default()
+                }))
+            }),
+            ..Default::default()
+        };
+        TestServer::builder()
+            .disable_https()
+            .with_admin_token(TOKEN)
+            .with_callbacks(cb)
+            .build()
+            .await
+    }
+
+    fn req(token: Option<&str>, query: &str) -> Request<Body> {
+        let mut req = Request::put(format!("{}{query}", http_serde::admin::network::AXUM_PATH));
+        if let Some(token) = token {
+            req = req.header(AUTHORIZATION, format!("Bearer {token}"));
+        }
+        req.body(Body::from(NETWORK)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn updates_network() {
+        let TestServer {
+            server, transport, ..
+        } = server(0).await;
+        let resp = server.handle_req(req(Some(TOKEN), "")).await;
+        assert_eq!(StatusCode::OK, resp.status());
+
+        let peers = transport.peers();
+        let url = &peers.network.peers()[1].url;
+        assert_eq!(Some("helper2.example.com"), url.host());
+        // the server does not use HTTPS, so neither do its clients
+        assert_eq!(Some(&Scheme::HTTP), url.scheme());
+        // new queries are welcome again
+        transport.check_accepting_queries().unwrap();
+    }
+
+    #[tokio::test]
+    async fn requires_token() {
+        let TestServer {
+            server, transport, ..
+        } = server(0).await;
+        let url = transport.peers().network.peers()[0].url.clone();
+        for token in [
+            None,
+            Some("correct horse"),
+            Some("wrong horse battery staple"),
+        ] {
+            let resp = server.handle_req(req(token, "")).await;
+            assert_eq!(StatusCode::UNAUTHORIZED, resp.status(), "{token:?}");
+        }
+        assert_eq!(url, transport.peers().network.peers()[0].url);
+    }
+
+    #[tokio::test]
+    async fn disabled_without_token() {
+        let TestServer { server, .. } = TestServer::builder().disable_https().build().await;
+        let resp = server.handle_req(req(Some(TOKEN), "")).await;
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+    }
+
+    #[tokio::test]
+    async fn waits_for_running_queries() {
+        let TestServer {
+            server, transport, ..
+        } = server(1).await;
+        let url = transport.peers().network.peers()[0].url.clone();
+        let resp = server
+            .handle_req(req(Some(TOKEN), "?drain_timeout=0"))
+            .await;
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, resp.status());
+        assert_eq!(url, transport.peers().network.peers()[0].url);
+        transport.check_accepting_queries().unwrap();
+    }
+}
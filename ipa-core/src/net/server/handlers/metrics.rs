@@ -0,0 +1,143 @@
+use std::fmt::Write;
+
+use axum::{
+    http::{header::CONTENT_TYPE, HeaderValue},
+    response::{IntoResponse, Response},
+    routing::get,
+    Extension, Router,
+};
+
+use crate::{
+    helpers::{HelperMetrics, StageCounters, Transport},
+    net::{http_serde, HttpTransport},
+    sync::Arc,
+    telemetry::labels::STEP,
+};
+
+/// Per-stage counters exported by this endpoint: name, description and how to read it.
+const STAGE_COUNTERS: [(&str, &str, fn(&StageCounters) -> u64); 4] = [
+    ("ipa_bytes_sent_total", "Bytes sent to other helpers", |s| {
+        s.bytes_sent
+    }),
+    (
+        "ipa_bytes_received_total",
+        "Bytes received from other helpers",
+        |s| s.bytes_received,
+    ),
+    (
+        "ipa_records_sent_total",
+        "Records sent to other helpers",
+        |s| s.records_sent,
+    ),
+    (
+        "ipa_prss_generated_total",
+        "Values generated by PRSS",
+        |s| s.prss_generated,
+    ),
+];
+
+/// Exports counters of all queries run by this helper in Prometheus text format. Steps are
+/// grouped by their top-level step, which is reported in the `step` label.
+async fn handler(transport: Extension<Arc<HttpTransport>>) -> Response {
+    let transport = Transport::clone_ref(&*transport);
+    let metrics = transport.metrics().await;
+    let mut response = render(&metrics).into_response();
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(http_serde::metrics::CONTENT_TYPE),
+    );
+    response
+}
+
+fn render(metrics: &HelperMetrics) -> String {
+    let mut out = String::new();
+    for (name, help, value) in STAGE_COUNTERS {
+        writeln!(out, "# HELP {name} {help}.").unwrap();
+        writeln!(out, "# TYPE {name} counter").unwrap();
+        for (stage, counters) in &metrics.stages {
+            writeln!(
+                out,
+                "{name}{{{STEP}=\"{}\"}} {}",
+                escape_label(stage),
+                value(counters)
+            )
+            .unwrap();
+        }
+    }
+    writeln!(
+        out,
+        "# HELP ipa_active_queries Queries running on this helper."
+    )
+    .unwrap();
+    writeln!(out, "# TYPE ipa_active_queries gauge").unwrap();
+    writeln!(out, "ipa_active_queries {}", metrics.active_queries).unwrap();
+
+    out
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+pub fn router(transport: Arc<HttpTransport>) -> Router {
+    Router::new()
+        .route(http_serde::metrics::AXUM_PATH, get(handler))
+        .layer(Extension(transport))
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use std::future::ready;
+
+    use super::*;
+    use crate::{helpers::TransportCallbacks, net::test::TestServer};
+
+    #[tokio::test]
+    async fn prometheus_format() {
+        let cb = TransportCallbacks {
+            metrics: Box::new(|_transport| {
+                Box::pin(ready(HelperMetrics {
+                    active_queries: 1,
+                    stages: [(
+                        String::from("binary_validator"),
+                        StageCounters {
+                            bytes_sent: 64,
+                            bytes_received: 32,
+                            records_sent: 8,
+                            prss_generated: 16,
+                        },
+                    )]
+                    .into_iter()
+                    .collect(),
+                }))
+            }),
+            ..Default::default()
+        };
+        let TestServer { transport, .. } = TestServer::builder().with_callbacks(cb).build().await;
+        let response = handler(Extension(transport)).await;
+        assert_eq!(
+            http_serde::metrics::CONTENT_TYPE,
+            response.headers().get(CONTENT_TYPE).unwrap()
+        );
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        let samples = body
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                "ipa_bytes_sent_total{step=\"binary_validator\"} 64",
+                "ipa_bytes_received_total{step=\"binary_validator\"} 32",
+                "ipa_records_sent_total{step=\"binary_validator\"} 8",
+                "ipa_prss_generated_total{step=\"binary_validator\"} 16",
+                "ipa_active_queries 1",
+            ],
+            samples
+        );
+    }
+}
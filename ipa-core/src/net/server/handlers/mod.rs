@@ -1,4 +1,6 @@
+mod admin;
 mod echo;
+mod metrics;
 mod query;
 
 use axum::Router;
@@ -9,10 +11,13 @@ use crate::{
 };
 
 pub fn router(transport: Arc<HttpTransport>) -> Router {
-    echo::router().nest(
-        http_serde::query::BASE_AXUM_PATH,
-        Router::new()
-            .merge(query::query_router(Arc::clone(&transport)))
-            .merge(query::h2h_router(transport)),
-    )
+    echo::router()
+        .merge(admin::router(Arc::clone(&transport)))
+        .merge(metrics::router(Arc::clone(&transport)))
+        .nest(
+            http_serde::query::BASE_AXUM_PATH,
+            Router::new()
+                .merge(query::query_router(Arc::clone(&transport)))
+                .merge(query::h2h_router(transport)),
+        )
 }
@@ -2,24 +2,58 @@ use axum::{routing::post, Extension, Json, Router};
 use hyper::StatusCode;
 
 use crate::{
-    helpers::Transport,
-    net::{http_serde, Error, HttpTransport},
+    helpers::{query::QueryConfig, Transport},
+    net::{
+        http_serde,
+        idempotency::{IdempotencyCache, Reservation},
+        Error, HttpTransport,
+    },
+    protocol::QueryId,
     query::NewQueryError,
     sync::Arc,
 };
 
+type CreateQueryCache = IdempotencyCache<QueryConfig, QueryId>;
+
 /// Takes details from the HTTP request and creates a `[TransportCommand]::CreateQuery` that is sent
-/// to the [`HttpTransport`].
+/// to the [`HttpTransport`]. Retried requests that carry the same idempotency key get the
+/// original query id back instead of creating another query.
 async fn handler(
     transport: Extension<Arc<HttpTransport>>,
+    cache: Extension<Arc<CreateQueryCache>>,
     req: http_serde::query::create::Request,
 ) -> Result<Json<http_serde::query::create::ResponseBody>, Error> {
+    transport.check_accepting_queries()?;
+    let guard = match req.idempotency_key {
+        Some(key) => match cache.reserve(key, req.query_config)? {
+            Reservation::Completed(query_id) => {
+                return Ok(Json(http_serde::query::create::ResponseBody { query_id }))
+            }
+            Reservation::New(guard) => Some(guard),
+        },
+        None => None,
+    };
+
     let transport = Transport::clone_ref(&*transport);
     match transport.receive_query(req.query_config).await {
-        Ok(query_id) => Ok(Json(http_serde::query::create::ResponseBody { query_id })),
+        Ok(query_id) => {
+            if let Some(guard) = guard {
+                guard.complete(query_id);
+            }
+            Ok(Json(http_serde::query::create::ResponseBody { query_id }))
+        }
         Err(err @ NewQueryError::State { .. }) => {
             Err(Error::application(StatusCode::CONFLICT, err))
         }
+        Err(err @ (NewQueryError::Config(_) | NewQueryError::Reservation(_))) => {
+            Err(Error::application(StatusCode::UNPROCESSABLE_ENTITY, err))
+        }
+        Err(NewQueryError::Admission(err)) if err.is_transient() => {
+            Err(Error::application(StatusCode::TOO_MANY_REQUESTS, err))
+        }
+        Err(NewQueryError::Admission(err)) => {
+            Err(Error::application(StatusCode::PAYLOAD_TOO_LARGE, err))
+        }
         Err(err) => Err(Error::application(StatusCode::INTERNAL_SERVER_ERROR, err)),
     }
 }
@@ -28,6 +62,7 @@ pub fn router(transport: Arc<HttpTransport>) -> Router {
     Router::new()
         .route(http_serde::query::create::AXUM_PATH, post(handler))
         .layer(Extension(transport))
+        .layer(Extension(Arc::new(CreateQueryCache::default())))
 }
 
 #[cfg(all(test, unit_test))]
@@ -44,21 +79,27 @@ mod tests {
     use crate::{
         ff::FieldType,
         helpers::{
-            query::{IpaQueryConfig, QueryConfig, QueryType, SparseAggregateQueryConfig},
+            query::{
+                BreakdownKeyOverflow, IpaQueryConfig, PseudonymQueryConfig, QueryType,
+                SparseAggregateQueryConfig,
+            },
             TransportCallbacks,
         },
         net::{
             server::handlers::query::test_helpers::{assert_req_fails_with, IntoFailingReq},
             test::TestServer,
+            IdempotencyKey,
         },
         protocol::QueryId,
+        query::AdmissionError,
+        sync::atomic::{AtomicUsize, Ordering},
     };
 
     async fn create_test(expected_query_config: QueryConfig) {
         let cb = TransportCallbacks {
             receive_query: Box::new(move |_transport, query_config| {
                 assert_eq!(query_config, expected_query_config);
-                Box::pin(ready(Ok(QueryId)))
+                Box::pin(ready(Ok(QueryId::default())))
             }),
             ..Default::default()
         };
@@ -77,7 +118,93 @@ mod tests {
 
         let http_serde::query::create::ResponseBody { query_id } =
             serde_json::from_slice(&body_bytes).unwrap();
-        assert_eq!(QueryId, query_id);
+        assert_eq!(QueryId::default(), query_id);
+    }
+
+    #[tokio::test]
+    async fn retry_with_idempotency_key() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let cb = TransportCallbacks {
+            receive_query: Box::new({
+                let calls = Arc::clone(&calls);
+                move |_transport, _query_config| {
+                    calls.fetch_add(1, Ordering::Relaxed);
+                    Box::pin(ready(Ok(QueryId::default())))
+                }
+            }),
+            ..Default::default()
+        };
+        let TestServer { transport, .. } = TestServer::builder().with_callbacks(cb).build().await;
+        let cache = Extension(Arc::new(CreateQueryCache::default()));
+        let config = QueryConfig::new(QueryType::TestMultiply, FieldType::Fp31, 1).unwrap();
+        let key = IdempotencyKey::random();
+        let req =
+            || http_serde::query::create::Request::new(config).with_idempotency_key(key.clone());
+
+        for _ in 0..2 {
+            let Json(resp) = handler(Extension(Arc::clone(&transport)), cache.clone(), req())
+                .await
+                .unwrap();
+            assert_eq!(QueryId::default(), resp.query_id);
+        }
+        assert_eq!(1, calls.load(Ordering::Relaxed));
+
+        // same key, different request
+        let other = QueryConfig::new(QueryType::TestMultiply, FieldType::Fp32BitPrime, 1).unwrap();
+        let resp = handler(
+            Extension(transport),
+            cache,
+            http_serde::query::create::Request::new(other).with_idempotency_key(key),
+        )
+        .await;
+        assert!(matches!(
+            resp,
+            Err(Error::Application {
+                code: StatusCode::UNPROCESSABLE_ENTITY,
+                ..
+            })
+        ));
+        assert_eq!(1, calls.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn admission_rejected() {
+        for (err, expected) in [
+            (
+                AdmissionError::TooManyQueries {
+                    running: 1,
+                    limit: 1,
+                },
+                StatusCode::TOO_MANY_REQUESTS,
+            ),
+            (
+                AdmissionError::TooManyRecords {
+                    requested: 2,
+                    limit: 1,
+                },
+                StatusCode::PAYLOAD_TOO_LARGE,
+            ),
+            (
+                AdmissionError::InputTooLarge {
+                    requested: 32,
+                    limit: 16,
+                },
+                StatusCode::PAYLOAD_TOO_LARGE,
+            ),
+        ] {
+            let cb = TransportCallbacks {
+                receive_query: Box::new(move |_transport, _query_config| {
+                    Box::pin(ready(Err(NewQueryError::Admission(err.clone()))))
+                }),
+                ..Default::default()
+            };
+            let TestServer { server, .. } = TestServer::builder().with_callbacks(cb).build().await;
+            let config = QueryConfig::new(QueryType::TestMultiply, FieldType::Fp31, 1).unwrap();
+            let req = http_serde::query::create::Request::new(config)
+                .try_into_http_request(Scheme::HTTP, Authority::from_static("localhost"))
+                .unwrap();
+            assert_eq!(expected, server.handle_req(req).await.status());
+        }
     }
 
     #[tokio::test]
@@ -95,6 +222,7 @@ mod tests {
                     attribution_window_seconds: None,
                     num_multi_bits: 3,
                     plaintext_match_keys: true,
+                    ..Default::default()
                 }),
                 FieldType::Fp32BitPrime,
                 1,
@@ -115,6 +243,78 @@ mod tests {
                 attribution_window_seconds: NonZeroU32::new(86_400),
                 num_multi_bits: 3,
                 plaintext_match_keys: true,
+                ..Default::default()
+            }),
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn create_test_oprf_ipa_custom_widths() {
+        create_test(QueryConfig {
+            size: 1.try_into().unwrap(),
+            field_type: FieldType::Fp32BitPrime,
+            query_type: QueryType::OprfIpa(IpaQueryConfig {
+                per_user_credit_cap: 8,
+                max_breakdown_key: 16,
+                plaintext_match_keys: true,
+                epoch: Some(3),
+                key_id: Some(1),
+                breakdown_key_bits: 4,
+                trigger_value_bits: 8,
+                breakdown_key_overflow: BreakdownKeyOverflow::Overflow,
+                ..Default::default()
+            }),
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn create_test_oprf_ipa_pseudonymized_input() {
+        create_test(QueryConfig {
+            size: 1.try_into().unwrap(),
+            field_type: FieldType::Fp32BitPrime,
+            query_type: QueryType::OprfIpa(IpaQueryConfig {
+                per_user_credit_cap: 8,
+                max_breakdown_key: 16,
+                epoch: Some(3),
+                pseudonymized_input: true,
+                ..Default::default()
+            }),
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn create_test_oprf_ipa_dp() {
+        create_test(QueryConfig {
+            size: 1.try_into().unwrap(),
+            field_type: FieldType::Fp32BitPrime,
+            query_type: QueryType::OprfIpa(IpaQueryConfig {
+                per_user_credit_cap: 8,
+                max_breakdown_key: 16,
+                dp_epsilon: Some(0.5.try_into().unwrap()),
+                dp_delta: 1e-9.try_into().unwrap(),
+                dp_padding_max_user_rows: NonZeroU32::new(10),
+                ..Default::default()
+            }),
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn create_test_oprf_ipa_time_buckets() {
+        create_test(QueryConfig {
+            size: 1.try_into().unwrap(),
+            field_type: FieldType::Fp32BitPrime,
+            query_type: QueryType::OprfIpa(IpaQueryConfig {
+                per_user_credit_cap: 8,
+                max_breakdown_key: 16,
+                time_bucket_days: NonZeroU32::new(1),
+                query_epoch_seconds: 1_700_000_000,
+                utc_offset_seconds: -5 * 3600,
+                sort_by_timestamp: true,
+                ..Default::default()
             }),
         })
         .await;
@@ -142,6 +342,20 @@ mod tests {
         .await;
     }
 
+    #[tokio::test]
+    async fn create_test_oprf_pseudonyms() {
+        create_test(QueryConfig {
+            size: 1.try_into().unwrap(),
+            field_type: FieldType::Fp32BitPrime,
+            query_type: QueryType::OprfPseudonyms(PseudonymQueryConfig {
+                epoch: 3,
+                validity_epochs: 2,
+                allow_linkable_output: true,
+            }),
+        })
+        .await;
+    }
+
     struct OverrideReq {
         field_type: String,
         query_type_params: String,
@@ -282,6 +496,32 @@ mod tests {
         assert_req_fails_with(req, StatusCode::UNPROCESSABLE_ENTITY).await;
     }
 
+    #[tokio::test]
+    async fn attribution_window_in_days() {
+        let cb = TransportCallbacks {
+            receive_query: Box::new(move |_transport, query_config| {
+                let QueryType::OprfIpa(config) = query_config.query_type else {
+                    panic!("unexpected query type: {query_config:?}");
+                };
+                assert_eq!(
+                    NonZeroU32::new(7 * 86_400),
+                    config.attribution_window_seconds
+                );
+                Box::pin(ready(Ok(QueryId::default())))
+            }),
+            ..Default::default()
+        };
+        let TestServer { server, .. } = TestServer::builder().with_callbacks(cb).build().await;
+        let req = OverrideIPAReq {
+            query_type: QueryType::OPRF_IPA_STR.to_string(),
+            attribution_window_seconds: Some("7d".to_string()),
+            ..Default::default()
+        }
+        .into_req(0);
+
+        assert_eq!(StatusCode::OK, server.handle_req(req).await.status());
+    }
+
     #[tokio::test]
     async fn malformed_attribution_window_seconds_ipa() {
         let req = OverrideIPAReq {
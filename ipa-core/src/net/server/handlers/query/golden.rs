@@ -0,0 +1,281 @@
+//! Golden traces of step and create query requests.
+//!
+//! Helpers are upgraded one by one, so for a while every helper talks to peers that run the
+//! previous release. The traces below are what helpers send and expect over the wire: the method,
+//! path and headers of requests, their bodies and the status and headers of responses. Tests
+//! check that requests built by this helper still match them byte for byte and that this helper
+//! still accepts the traces, including ones that older helpers send. A change that breaks any of
+//! these tests breaks compatibility with deployed helpers; if it is intended, it must be rolled
+//! out the way [`WireVersion`] describes, and the new trace added next to the old one.
+//!
+//! [`WireVersion`]: crate::net::WireVersion
+use std::{future::ready, task::Poll};
+
+use futures::{
+    stream::{self, poll_immediate},
+    StreamExt,
+};
+use hyper::{
+    body::HttpBody,
+    header::CONTENT_TYPE,
+    http::uri::{Authority, Scheme},
+    Body, HeaderMap, StatusCode,
+};
+
+use crate::{
+    config::CompressionConfig,
+    ff::FieldType,
+    helpers::{
+        query::{IpaQueryConfig, QueryConfig, QueryType},
+        HelperIdentity, Transport, TransportCallbacks,
+    },
+    net::{
+        compression::compress, http_serde, server::ClientIdentity, test::TestServer, RecordEncoding,
+    },
+    protocol::{
+        step::{Gate, StepNarrow},
+        QueryId,
+    },
+    sync::Arc,
+};
+
+/// Records sent in step traces.
+const RECORDS: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+
+/// [`RECORDS`] framed as a single chunk that is not compressed, see `compression` module.
+const FRAMED_RECORDS: [u8; 13] = [0, 8, 0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8];
+
+const STEP_REQUEST: &str = "POST /query/0/step/protocol/golden\nx-ipa-wire-version: 2\n";
+const STEP_RESPONSE: &str = "200 OK\nx-ipa-wire-version: 2\n";
+
+const COMPRESSED_STEP_REQUEST: &str = "POST /query/0/step/protocol/golden\n\
+    x-ipa-record-encoding: gzip\n\
+    x-ipa-wire-version: 2\n";
+const COMPRESSED_STEP_RESPONSE: &str = "200 OK\n\
+    x-ipa-record-encoding: gzip\n\
+    x-ipa-wire-version: 2\n";
+
+/// Sent by helpers that predate wire versions.
+const UNVERSIONED_STEP_REQUEST: &str = "POST /query/0/step/protocol/golden\n";
+const UNVERSIONED_STEP_RESPONSE: &str = "200 OK\nx-ipa-wire-version: 1\n";
+
+const CREATE_TEST_MULTIPLY_REQUEST: &str =
+    "POST /query?query_type=test-multiply&field_type=Fp31&size=1\n";
+const CREATE_OPRF_IPA_REQUEST: &str = "POST /query?query_type=oprf_ipa&field_type=Fp32BitPrime\
+    &size=10&per_user_credit_cap=3&max_breakdown_key=20&num_multi_bits=3&dp_delta=0.0000001\
+    &breakdown_key_bits=8&trigger_value_bits=3&timestamp_bits=20&priority_bits=8\n";
+/// Sent by helpers that predate source event priorities.
+const UNPRIORITIZED_CREATE_OPRF_IPA_REQUEST: &str = "POST /query?query_type=oprf_ipa\
+    &field_type=Fp32BitPrime&size=10&per_user_credit_cap=3&max_breakdown_key=20&num_multi_bits=3\
+    &dp_delta=0.0000001&breakdown_key_bits=8&trigger_value_bits=3&timestamp_bits=20\n";
+const CREATE_RESPONSE: &str = "200 OK\ncontent-type: application/json\n";
+const CREATE_RESPONSE_BODY: &str = r#"{"query_id":"0"}"#;
+
+fn gate() -> Gate {
+    Gate::default().narrow("golden")
+}
+
+fn test_multiply_config() -> QueryConfig {
+    QueryConfig::new(QueryType::TestMultiply, FieldType::Fp31, 1).unwrap()
+}
+
+fn oprf_ipa_config() -> QueryConfig {
+    QueryConfig::new(
+        QueryType::OprfIpa(IpaQueryConfig::default()),
+        FieldType::Fp32BitPrime,
+        10,
+    )
+    .unwrap()
+}
+
+fn unprioritized_oprf_ipa_config() -> QueryConfig {
+    QueryConfig::new(
+        QueryType::OprfIpa(IpaQueryConfig {
+            priority_bits: 0,
+            ..IpaQueryConfig::default()
+        }),
+        FieldType::Fp32BitPrime,
+        10,
+    )
+    .unwrap()
+}
+
+/// Writes the first line of a trace followed by `headers`, one per line and sorted by name.
+fn trace<'a, I>(first_line: &str, headers: I) -> String
+where
+    I: IntoIterator<Item = (&'a str, &'a str)>,
+{
+    let mut headers = headers.into_iter().collect::<Vec<_>>();
+    headers.sort_unstable();
+    headers
+        .into_iter()
+        .fold(format!("{first_line}\n"), |trace, (name, value)| {
+            trace + &format!("{name}: {value}\n")
+        })
+}
+
+fn header_pairs(headers: &HeaderMap) -> impl Iterator<Item = (&str, &str)> {
+    headers
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.to_str().unwrap()))
+}
+
+fn request_trace(req: &hyper::Request<Body>) -> String {
+    trace(
+        &format!("{} {}", req.method(), req.uri().path_and_query().unwrap()),
+        header_pairs(req.headers()),
+    )
+}
+
+/// Response headers that are part of the protocol, as opposed to those set by the HTTP stack.
+fn response_trace(resp: &hyper::Response<axum::body::BoxBody>) -> String {
+    trace(
+        &resp.status().to_string(),
+        header_pairs(resp.headers())
+            .filter(|(name, _)| name.starts_with("x-ipa-") || *name == CONTENT_TYPE),
+    )
+}
+
+/// Builds the request described by `trace`, the way a peer would send it.
+fn parse_request(trace: &str, body: &[u8]) -> hyper::Request<Body> {
+    let mut lines = trace.lines();
+    let (method, path) = lines.next().unwrap().split_once(' ').unwrap();
+    lines
+        .fold(
+            hyper::Request::builder()
+                .method(method)
+                .uri(format!("http://localhost{path}")),
+            |req, line| {
+                let (name, value) = line.split_once(": ").unwrap();
+                req.header(name, value)
+            },
+        )
+        .body(Body::from(body.to_vec()))
+        .unwrap()
+}
+
+async fn body_bytes<B: HttpBody>(body: B) -> Vec<u8>
+where
+    B::Error: std::fmt::Debug,
+{
+    hyper::body::to_bytes(body).await.unwrap().to_vec()
+}
+
+#[tokio::test]
+async fn step_request_matches_trace() {
+    let req = http_serde::query::step::Request::new(
+        QueryId::default(),
+        gate(),
+        Body::from(RECORDS.to_vec()),
+    )
+    .try_into_http_request(Scheme::HTTP, Authority::from_static("localhost"))
+    .unwrap();
+    assert_eq!(STEP_REQUEST, request_trace(&req));
+    assert_eq!(RECORDS.to_vec(), body_bytes(req.into_body()).await);
+}
+
+#[tokio::test]
+async fn compressed_step_request_matches_trace() {
+    let config = CompressionConfig {
+        encoding: RecordEncoding::Gzip,
+        threshold: 1024,
+    };
+    let body = compress(stream::iter([RECORDS.to_vec()]), config)
+        .concat()
+        .await;
+    let req = http_serde::query::step::Request::new(QueryId::default(), gate(), Body::from(body))
+        .with_encoding(config.encoding)
+        .try_into_http_request(Scheme::HTTP, Authority::from_static("localhost"))
+        .unwrap();
+    assert_eq!(COMPRESSED_STEP_REQUEST, request_trace(&req));
+    assert_eq!(FRAMED_RECORDS.to_vec(), body_bytes(req.into_body()).await);
+}
+
+/// Sends `request` with `body` to a helper and checks that it responds with `response` and
+/// receives [`RECORDS`].
+async fn accepts_step(request: &str, body: &[u8], response: &str) {
+    let TestServer {
+        server, transport, ..
+    } = TestServer::builder().build().await;
+    transport.open_query_streams(QueryId::default());
+    let mut req = parse_request(request, body);
+    req.extensions_mut()
+        .insert(ClientIdentity(HelperIdentity::ONE));
+
+    let resp = server.handle_req(req).await;
+    assert_eq!(response, response_trace(&resp));
+
+    let mut stream =
+        Arc::clone(&transport).receive(HelperIdentity::ONE, (QueryId::default(), gate()));
+    assert_eq!(
+        poll_immediate(&mut stream).next().await,
+        Some(Poll::Ready(RECORDS.to_vec()))
+    );
+}
+
+#[tokio::test]
+async fn accepts_step_trace() {
+    accepts_step(STEP_REQUEST, &RECORDS, STEP_RESPONSE).await;
+}
+
+#[tokio::test]
+async fn accepts_compressed_step_trace() {
+    accepts_step(
+        COMPRESSED_STEP_REQUEST,
+        &FRAMED_RECORDS,
+        COMPRESSED_STEP_RESPONSE,
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn accepts_unversioned_step_trace() {
+    accepts_step(
+        UNVERSIONED_STEP_REQUEST,
+        &RECORDS,
+        UNVERSIONED_STEP_RESPONSE,
+    )
+    .await;
+}
+
+#[test]
+fn create_request_matches_trace() {
+    for (config, expected) in [
+        (test_multiply_config(), CREATE_TEST_MULTIPLY_REQUEST),
+        (oprf_ipa_config(), CREATE_OPRF_IPA_REQUEST),
+    ] {
+        let req = http_serde::query::create::Request::new(config)
+            .try_into_http_request(Scheme::HTTP, Authority::from_static("localhost"))
+            .unwrap();
+        assert_eq!(expected, request_trace(&req));
+    }
+}
+
+#[tokio::test]
+async fn accepts_create_trace() {
+    for (config, request) in [
+        (test_multiply_config(), CREATE_TEST_MULTIPLY_REQUEST),
+        (oprf_ipa_config(), CREATE_OPRF_IPA_REQUEST),
+        (
+            unprioritized_oprf_ipa_config(),
+            UNPRIORITIZED_CREATE_OPRF_IPA_REQUEST,
+        ),
+    ] {
+        let cb = TransportCallbacks {
+            receive_query: Box::new(move |_transport, query_config| {
+                assert_eq!(config, query_config);
+                Box::pin(ready(Ok(QueryId::default())))
+            }),
+            ..Default::default()
+        };
+        let TestServer { server, .. } = TestServer::builder().with_callbacks(cb).build().await;
+
+        let resp = server.handle_req(parse_request(request, &[])).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!(CREATE_RESPONSE, response_trace(&resp));
+        assert_eq!(
+            CREATE_RESPONSE_BODY.as_bytes(),
+            body_bytes(resp.into_body()).await
+        );
+    }
+}
@@ -2,26 +2,66 @@ use axum::{routing::post, Extension, Router};
 use hyper::StatusCode;
 
 use crate::{
-    helpers::Transport,
-    net::{http_serde, Error, HttpTransport},
+    helpers::{query::InputPart, Transport},
+    net::{
+        http_serde,
+        idempotency::{IdempotencyCache, Reservation},
+        server::ReportCollectorIdentity,
+        Error, HttpTransport,
+    },
+    protocol::QueryId,
     sync::Arc,
 };
 
+type QueryInputCache = IdempotencyCache<(QueryId, InputPart), ()>;
+
+/// Retried requests that carry the same idempotency key are acknowledged without submitting
+/// the input again.
+///
+/// A part of split inputs is only accepted from the report collector authenticated as entitled
+/// to it.
 async fn handler(
     transport: Extension<Arc<HttpTransport>>,
+    cache: Extension<Arc<QueryInputCache>>,
+    collector: Option<Extension<ReportCollectorIdentity>>,
     req: http_serde::query::input::Request,
 ) -> Result<(), Error> {
+    let query_input = req.query_input;
+    let entitled = collector.map(|Extension(ReportCollectorIdentity(part))| part);
+    if query_input.part != InputPart::All && entitled != Some(query_input.part) {
+        return Err(Error::application(
+            StatusCode::UNAUTHORIZED,
+            format!(
+                "{:?} of query inputs can only be submitted by the report collector entitled to it",
+                query_input.part
+            ),
+        ));
+    }
+    let guard = match req.idempotency_key {
+        Some(key) => match cache.reserve(key, (query_input.query_id, query_input.part))? {
+            Reservation::Completed(()) => return Ok(()),
+            Reservation::New(guard) => Some(guard),
+        },
+        None => None,
+    };
+
     let transport = Transport::clone_ref(&*transport);
     transport
-        .query_input(req.query_input)
+        .query_input(query_input)
         .await
-        .map_err(|e| Error::application(StatusCode::INTERNAL_SERVER_ERROR, e))
+        .map_err(|e| Error::application(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    if let Some(guard) = guard {
+        guard.complete(());
+    }
+
+    Ok(())
 }
 
 pub fn router(transport: Arc<HttpTransport>) -> Router {
     Router::new()
         .route(http_serde::query::input::AXUM_PATH, post(handler))
         .layer(Extension(transport))
+        .layer(Extension(Arc::new(QueryInputCache::default())))
 }
 
 #[cfg(all(test, unit_test))]
@@ -31,9 +71,15 @@ mod tests {
 
     use super::*;
     use crate::{
-        helpers::{query::QueryInput, BytesStream, TransportCallbacks},
+        helpers::{
+            query::{InputPart, QueryInput},
+            BytesStream, TransportCallbacks,
+        },
         net::{
-            server::handlers::query::test_helpers::{assert_req_fails_with, IntoFailingReq},
+            server::handlers::query::{
+                test_helpers::{assert_req_fails_with, IntoFailingReq},
+                MaybeExtensionExt,
+            },
             test::TestServer,
         },
         protocol::QueryId,
@@ -41,7 +87,7 @@ mod tests {
 
     #[tokio::test]
     async fn input_test() {
-        let expected_query_id = QueryId;
+        let expected_query_id = QueryId::default();
         let expected_input = &[4u8; 4];
         let cb = TransportCallbacks {
             query_input: Box::new(move |_transport, query_input| {
@@ -56,25 +102,62 @@ mod tests {
         let TestServer { transport, .. } = TestServer::builder().with_callbacks(cb).build().await;
         let req = http_serde::query::input::Request::new(QueryInput {
             query_id: expected_query_id,
+            part: InputPart::All,
             input_stream: expected_input.to_vec().into(),
         });
-        handler(Extension(transport), req).await.unwrap();
+        handler(Extension(transport), Extension(Arc::default()), None, req)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn input_part_from_collector() {
+        let cb = TransportCallbacks {
+            query_input: Box::new(move |_transport, query_input| {
+                Box::pin(async move {
+                    assert_eq!(query_input.part, InputPart::SourceEvents);
+                    Ok(())
+                })
+            }),
+            ..Default::default()
+        };
+        let TestServer { transport, .. } = TestServer::builder().with_callbacks(cb).build().await;
+        let req = http_serde::query::input::Request::new(QueryInput {
+            query_id: QueryId::default(),
+            part: InputPart::SourceEvents,
+            input_stream: vec![4u8; 4].into(),
+        });
+        handler(
+            Extension(transport),
+            Extension(Arc::default()),
+            Some(Extension(ReportCollectorIdentity(InputPart::SourceEvents))),
+            req,
+        )
+        .await
+        .unwrap();
     }
 
     struct OverrideReq {
         query_id: String,
+        part: Option<InputPart>,
+        collector: Option<ReportCollectorIdentity>,
         input_stream: Vec<u8>,
     }
 
     impl IntoFailingReq for OverrideReq {
         fn into_req(self, port: u16) -> Request<Body> {
-            let uri = format!(
+            let mut uri = format!(
                 "http://localhost:{}{}/{}/input",
                 port,
                 http_serde::query::BASE_AXUM_PATH,
                 self.query_id
             );
+            if let Some(part) = self.part {
+                uri.push_str("?part=");
+                uri.push_str(part.as_ref());
+            }
             hyper::Request::post(uri)
+                .maybe_extension(self.collector)
                 .body(hyper::Body::from(self.input_stream))
                 .unwrap()
         }
@@ -83,7 +166,9 @@ mod tests {
     impl Default for OverrideReq {
         fn default() -> Self {
             Self {
-                query_id: QueryId.as_ref().to_string(),
+                query_id: QueryId::default().to_string(),
+                part: None,
+                collector: None,
                 input_stream: vec![4; 4],
             }
         }
@@ -97,4 +182,19 @@ mod tests {
         };
         assert_req_fails_with(req, StatusCode::UNPROCESSABLE_ENTITY).await;
     }
+
+    #[tokio::test]
+    async fn input_part_from_unauthorized_client() {
+        for collector in [
+            None,
+            Some(ReportCollectorIdentity(InputPart::TriggerEvents)),
+        ] {
+            let req = OverrideReq {
+                part: Some(InputPart::SourceEvents),
+                collector,
+                ..Default::default()
+            };
+            assert_req_fails_with(req, StatusCode::UNAUTHORIZED).await;
+        }
+    }
 }
@@ -1,5 +1,8 @@
 mod create;
+#[cfg(all(test, unit_test))]
+mod golden;
 mod input;
+mod mux;
 mod prepare;
 mod results;
 mod status;
@@ -46,7 +49,8 @@ pub fn query_router(transport: Arc<HttpTransport>) -> Router {
 pub fn h2h_router(transport: Arc<HttpTransport>) -> Router {
     Router::new()
         .merge(prepare::router(Arc::clone(&transport)))
-        .merge(step::router(transport))
+        .merge(step::router(Arc::clone(&transport)))
+        .merge(mux::router(transport))
         .layer(layer_fn(HelperAuthentication::new))
 }
 
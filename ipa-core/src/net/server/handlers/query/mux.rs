@@ -0,0 +1,149 @@
+use axum::{
+    response::{IntoResponse, Response},
+    routing::post,
+    Extension, Router,
+};
+
+use crate::{
+    helpers::{BodyStream, Transport},
+    net::{
+        http_serde,
+        mux::demultiplex,
+        server::{ClientIdentity, Error},
+        HttpTransport,
+    },
+    sync::Arc,
+};
+
+/// Unlike the step handler, this one keeps running for as long as the peer keeps the request
+/// open, registering record streams with the transport as they arrive.
+async fn handler(
+    transport: Extension<Arc<HttpTransport>>,
+    from: Extension<ClientIdentity>,
+    req: http_serde::query::mux::Request<BodyStream>,
+) -> Result<Response, Error> {
+    let transport = Transport::clone_ref(&*transport);
+    let query_id = req.query_id;
+    demultiplex(req.body, |gate, stream| {
+        // Records of a query that is not running here are dropped, the peer will learn about
+        // it from the query status.
+        if let Err(e) = Arc::clone(&transport).receive_stream(query_id, gate, **from, stream) {
+            tracing::warn!("dropping multiplexed stream: {e}");
+        }
+    })
+    .await?;
+    let mut response = ().into_response();
+    req.wire_version.insert_into(response.headers_mut());
+    Ok(response)
+}
+
+pub fn router(transport: Arc<HttpTransport>) -> Router {
+    Router::new()
+        .route(http_serde::query::mux::AXUM_PATH, post(handler))
+        .layer(Extension(transport))
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use axum::http::Request;
+    use futures::{stream, StreamExt};
+    use hyper::{Body, StatusCode};
+
+    use super::*;
+    use crate::{
+        helpers::{HelperIdentity, MESSAGE_PAYLOAD_SIZE_BYTES},
+        net::{
+            mux::Multiplexer,
+            server::handlers::query::{
+                test_helpers::{assert_req_fails_with, IntoFailingReq},
+                MaybeExtensionExt,
+            },
+            test::TestServer,
+        },
+        protocol::{
+            step::{Gate, StepNarrow},
+            QueryId,
+        },
+    };
+
+    async fn mux_body(streams: &[(Gate, Vec<u8>)]) -> Vec<u8> {
+        let (mut mux, body) = Multiplexer::new();
+        for (gate, payload) in streams {
+            let _sent = mux.add(gate, stream::once(std::future::ready(payload.clone())));
+        }
+        drop(mux);
+
+        body.collect::<Vec<_>>().await.concat()
+    }
+
+    #[tokio::test]
+    async fn mux() {
+        let TestServer { transport, .. } = TestServer::builder().build().await;
+        transport.open_query_streams(QueryId::default());
+
+        let streams = ["a", "b"].map(|s| {
+            (
+                Gate::default().narrow(s),
+                vec![s.as_bytes()[0]; MESSAGE_PAYLOAD_SIZE_BYTES],
+            )
+        });
+        let req = http_serde::query::mux::Request::new(
+            QueryId::default(),
+            mux_body(&streams).await.into(),
+        );
+
+        handler(
+            Extension(Arc::clone(&transport)),
+            Extension(ClientIdentity(HelperIdentity::TWO)),
+            req,
+        )
+        .await
+        .unwrap();
+
+        for (gate, payload) in streams {
+            let stream =
+                Arc::clone(&transport).receive(HelperIdentity::TWO, (QueryId::default(), gate));
+            assert_eq!(vec![payload], stream.collect::<Vec<_>>().await);
+        }
+    }
+
+    struct OverrideReq {
+        client_id: Option<ClientIdentity>,
+        body: Vec<u8>,
+    }
+
+    impl IntoFailingReq for OverrideReq {
+        fn into_req(self, port: u16) -> Request<Body> {
+            let uri = format!(
+                "http://localhost:{}{}/{}/mux",
+                port,
+                http_serde::query::BASE_AXUM_PATH,
+                QueryId::default(),
+            );
+            hyper::Request::post(uri)
+                .maybe_extension(self.client_id)
+                .body(hyper::Body::from(self.body))
+                .unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn auth_required() {
+        let req = OverrideReq {
+            client_id: None,
+            body: Vec::new(),
+        };
+        assert_req_fails_with(req, StatusCode::UNAUTHORIZED).await;
+    }
+
+    #[tokio::test]
+    async fn truncated_body() {
+        let mut body = mux_body(&[(Gate::default().narrow("a"), vec![1; 10])]).await;
+        body.pop();
+        let req = OverrideReq {
+            client_id: Some(ClientIdentity(HelperIdentity::ONE)),
+            body,
+        };
+        assert_req_fails_with(req, StatusCode::BAD_REQUEST).await;
+    }
+}
@@ -1,26 +1,57 @@
 use std::sync::Arc;
 
-use axum::{response::IntoResponse, routing::post, Extension, Router};
+use axum::{
+    response::{IntoResponse, Response},
+    routing::post,
+    Extension, Router,
+};
 use hyper::StatusCode;
 
 use crate::{
+    helpers::Role,
     net::{http_serde, server::ClientIdentity, HttpTransport},
     query::PrepareQueryError,
 };
 
 /// Called by whichever peer helper is the leader for an individual query, to initiatialize
 /// processing of that query.
+///
+/// The leader always takes [`Role::H1`], so requests from any other helper are rejected, even
+/// though that helper is authenticated.
+///
+/// Responds with the wire version negotiated with the leader, which is used for records
+/// exchanged with it while the query runs.
 async fn handler(
     transport: Extension<Arc<HttpTransport>>,
-    _from: Extension<ClientIdentity>, // require that client is an authenticated helper
+    from: Extension<ClientIdentity>,
     req: http_serde::query::prepare::Request,
-) -> Result<(), PrepareQueryError> {
-    Arc::clone(&transport).prepare_query(req.data).await
+) -> Result<Response, PrepareQueryError> {
+    if req.data.roles.role(**from) != Role::H1 {
+        return Err(PrepareQueryError::NotCoordinator { from: **from });
+    }
+    if let Err(e) = transport.check_accepting_queries() {
+        return Ok(e.into_response());
+    }
+    let query_id = req.data.query_id;
+    Arc::clone(&transport).prepare_query(req.data).await?;
+    transport.set_wire_version(query_id, **from, req.wire_version);
+
+    let mut response = ().into_response();
+    req.wire_version.insert_into(response.headers_mut());
+    Ok(response)
 }
 
 impl IntoResponse for PrepareQueryError {
     fn into_response(self) -> axum::response::Response {
-        (StatusCode::BAD_REQUEST, self.to_string()).into_response()
+        let status = match self {
+            PrepareQueryError::NotCoordinator { .. } => StatusCode::FORBIDDEN,
+            PrepareQueryError::Admission(ref err) if err.is_transient() => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            PrepareQueryError::Admission(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            _ => StatusCode::BAD_REQUEST,
+        };
+        (status, self.to_string()).into_response()
     }
 }
 
@@ -53,6 +84,7 @@ mod tests {
                 ClientIdentity,
             },
             test::TestServer,
+            WireVersion,
         },
         protocol::QueryId,
     };
@@ -60,9 +92,12 @@ mod tests {
     #[tokio::test]
     async fn prepare_test() {
         let req = http_serde::query::prepare::Request::new(PrepareQuery {
-            query_id: QueryId,
+            query_id: QueryId::default(),
             config: QueryConfig::new(TestMultiply, FieldType::Fp31, 1).unwrap(),
             roles: RoleAssignment::new(HelperIdentity::make_three()),
+            reservation: QueryConfig::new(TestMultiply, FieldType::Fp31, 1)
+                .unwrap()
+                .reservation(),
         });
         let expected_prepare_query = req.data.clone();
 
@@ -74,13 +109,48 @@ mod tests {
             ..Default::default()
         };
         let TestServer { transport, .. } = TestServer::builder().with_callbacks(cb).build().await;
-        handler(
-            Extension(transport),
-            Extension(ClientIdentity(HelperIdentity::TWO)),
+        let resp = handler(
+            Extension(Arc::clone(&transport)),
+            Extension(ClientIdentity(HelperIdentity::ONE)),
             req.clone(),
         )
         .await
         .unwrap();
+
+        assert_eq!(
+            WireVersion::CURRENT,
+            WireVersion::from_headers(resp.headers()).unwrap()
+        );
+        assert_eq!(
+            WireVersion::CURRENT,
+            transport.wire_version(QueryId::default(), HelperIdentity::ONE)
+        );
+    }
+
+    #[tokio::test]
+    async fn not_coordinator() {
+        let config = QueryConfig::new(TestMultiply, FieldType::Fp31, 1).unwrap();
+        let req = http_serde::query::prepare::Request::new(PrepareQuery {
+            query_id: QueryId::default(),
+            config,
+            roles: RoleAssignment::new(HelperIdentity::make_three()),
+            reservation: config.reservation(),
+        });
+        // default callbacks fail the test if the request is passed on to the query processor
+        let TestServer { transport, .. } = TestServer::default().await;
+        let err = handler(
+            Extension(transport),
+            Extension(ClientIdentity(HelperIdentity::TWO)),
+            req,
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(
+            err,
+            PrepareQueryError::NotCoordinator { from } if from == HelperIdentity::TWO
+        ));
+        assert_eq!(StatusCode::FORBIDDEN, err.into_response().status());
     }
 
     // since we tested `QueryType` with `create`, skip it here
@@ -116,7 +186,7 @@ mod tests {
                 .to_vec();
             Self {
                 client_id: Some(ClientIdentity(HelperIdentity::TWO)),
-                query_id: QueryId.as_ref().to_string(),
+                query_id: QueryId::default().to_string(),
                 field_type: format!("{:?}", FieldType::Fp31),
                 size: Some(1),
                 roles,
@@ -1,22 +1,71 @@
 use std::sync::Arc;
 
-use axum::{routing::get, Extension, Router};
+use axum::{
+    http::HeaderValue,
+    response::{IntoResponse, Response},
+    routing::get,
+    Extension, Router,
+};
 use hyper::StatusCode;
 
 use crate::{
     helpers::Transport,
     net::{http_serde, server::Error, HttpTransport},
+    query::QueryCompletionError,
 };
 
 /// Handles the completion of the query by blocking the sender until query is completed.
 async fn handler(
     transport: Extension<Arc<HttpTransport>>,
     req: http_serde::query::results::Request,
-) -> Result<Vec<u8>, Error> {
+) -> Result<Response, Error> {
     // TODO: we may be able to stream the response
     let transport = Transport::clone_ref(&*transport);
     match transport.complete_query(req.query_id).await {
-        Ok(result) => Ok(result.into_bytes()),
+        Ok(result) => {
+            let cache_status = result.cache_status();
+            let partial = result.partial();
+            let shape = result.shape();
+            let report = result
+                .report()
+                .map(|report| serde_json::to_string(report).unwrap());
+            let mut response = result.into_bytes().into_response();
+            if let Some(status) = cache_status {
+                response.headers_mut().insert(
+                    &http_serde::query::results::CACHE_STATUS_HEADER,
+                    HeaderValue::from_static(status.as_str()),
+                );
+            }
+            if let Some(completeness) = partial {
+                response.headers_mut().insert(
+                    &http_serde::query::results::PROCESSED_USERS_HEADER,
+                    HeaderValue::try_from(format!(
+                        "{}/{}",
+                        completeness.processed_users, completeness.total_users
+                    ))
+                    .unwrap(),
+                );
+            }
+            if let Some(shape) = shape {
+                response.headers_mut().insert(
+                    &http_serde::query::results::OUTPUT_SHAPE_HEADER,
+                    HeaderValue::try_from(format!("{}x{}", shape.breakdowns, shape.time_buckets))
+                        .unwrap(),
+                );
+            }
+            if let Some(report) = report {
+                match HeaderValue::try_from(report) {
+                    Ok(value) => {
+                        response
+                            .headers_mut()
+                            .insert(&http_serde::query::results::QUERY_REPORT_HEADER, value);
+                    }
+                    Err(e) => tracing::warn!("query report can't be sent in a header: {e}"),
+                }
+            }
+            Ok(response)
+        }
+        Err(QueryCompletionError::ExecutionError(e)) => Err(Error::protocol(e)),
         Err(e) => Err(Error::application(StatusCode::INTERNAL_SERVER_ERROR, e)),
     }
 }
@@ -36,14 +85,26 @@ mod tests {
 
     use super::*;
     use crate::{
-        ff::Fp31,
-        helpers::TransportCallbacks,
+        error::Error as ProtocolError,
+        ff::{FieldType, Fp31},
+        helpers::{
+            query::{QueryConfig, QueryType},
+            Error as InfraError, Role, TransportCallbacks,
+        },
         net::{
             server::handlers::query::test_helpers::{assert_req_fails_with, IntoFailingReq},
             test::TestServer,
+            ErrorBody, ErrorCode,
+        },
+        protocol::{
+            ipa_prf::pipeline::{Completeness, OutputShape, PartialAggregate},
+            step::{Gate, StepNarrow},
+            QueryId,
+        },
+        query::{
+            CacheStatus, ProtocolResult, QueryManifest, QueryReport, ReportSigner,
+            SignedQueryReport,
         },
-        protocol::QueryId,
-        query::ProtocolResult,
         secret_sharing::replicated::semi_honest::AdditiveShare as Replicated,
     };
 
@@ -53,7 +114,7 @@ mod tests {
             Fp31::try_from(1u128).unwrap(),
             Fp31::try_from(2u128).unwrap(),
         ))]);
-        let expected_query_id = QueryId;
+        let expected_query_id = QueryId::default();
         let raw_results = expected_results.to_vec();
         let cb = TransportCallbacks {
             complete_query: Box::new(move |_transport, query_id| {
@@ -64,11 +125,196 @@ mod tests {
             ..Default::default()
         };
         let TestServer { transport, .. } = TestServer::builder().with_callbacks(cb).build().await;
-        let req = http_serde::query::results::Request::new(QueryId);
-        let results = handler(Extension(transport), req.clone()).await.unwrap();
+        let req = http_serde::query::results::Request::new(QueryId::default());
+        let response = handler(Extension(transport), req.clone()).await.unwrap();
+        assert!(response
+            .headers()
+            .get(&http_serde::query::results::CACHE_STATUS_HEADER)
+            .is_none());
+        let results = hyper::body::to_bytes(response.into_body()).await.unwrap();
         assert_eq!(results, expected_results.into_bytes());
     }
 
+    #[derive(Debug)]
+    struct CachedOutput;
+
+    impl ProtocolResult for CachedOutput {
+        fn into_bytes(self: Box<Self>) -> Vec<u8> {
+            vec![1, 2, 3]
+        }
+
+        fn cache_status(&self) -> Option<CacheStatus> {
+            Some(CacheStatus::Hit)
+        }
+    }
+
+    #[tokio::test]
+    async fn cache_status() {
+        let cb = TransportCallbacks {
+            complete_query: Box::new(|_transport, _query_id| {
+                Box::pin(ready(Ok(Box::new(CachedOutput) as Box<dyn ProtocolResult>)))
+            }),
+            ..Default::default()
+        };
+        let TestServer { transport, .. } = TestServer::builder().with_callbacks(cb).build().await;
+        let req = http_serde::query::results::Request::new(QueryId::default());
+        let response = handler(Extension(transport), req).await.unwrap();
+
+        assert_eq!(
+            "hit",
+            response
+                .headers()
+                .get(&http_serde::query::results::CACHE_STATUS_HEADER)
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn partial_result() {
+        let cb = TransportCallbacks {
+            complete_query: Box::new(|_transport, _query_id| {
+                let output = PartialAggregate {
+                    breakdowns: vec![Replicated::<Fp31>::ZERO],
+                    completeness: Completeness {
+                        processed_users: 15,
+                        total_users: 16,
+                    },
+                    shape: None,
+                    group_sizes: None,
+                    attributed_users: None,
+                };
+                Box::pin(ready(Ok(Box::new(output) as Box<dyn ProtocolResult>)))
+            }),
+            ..Default::default()
+        };
+        let TestServer { transport, .. } = TestServer::builder().with_callbacks(cb).build().await;
+        let req = http_serde::query::results::Request::new(QueryId::default());
+        let response = handler(Extension(transport), req).await.unwrap();
+
+        assert_eq!(
+            "15/16",
+            response
+                .headers()
+                .get(&http_serde::query::results::PROCESSED_USERS_HEADER)
+                .unwrap()
+        );
+        assert!(response
+            .headers()
+            .get(&http_serde::query::results::OUTPUT_SHAPE_HEADER)
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn output_shape() {
+        let cb = TransportCallbacks {
+            complete_query: Box::new(|_transport, _query_id| {
+                let output = PartialAggregate {
+                    breakdowns: vec![Replicated::<Fp31>::ZERO; 6],
+                    completeness: Completeness::complete(16),
+                    shape: Some(OutputShape {
+                        breakdowns: 2,
+                        time_buckets: 3,
+                    }),
+                    group_sizes: None,
+                    attributed_users: None,
+                };
+                Box::pin(ready(Ok(Box::new(output) as Box<dyn ProtocolResult>)))
+            }),
+            ..Default::default()
+        };
+        let TestServer { transport, .. } = TestServer::builder().with_callbacks(cb).build().await;
+        let req = http_serde::query::results::Request::new(QueryId::default());
+        let response = handler(Extension(transport), req).await.unwrap();
+
+        assert_eq!(
+            "2x3",
+            response
+                .headers()
+                .get(&http_serde::query::results::OUTPUT_SHAPE_HEADER)
+                .unwrap()
+        );
+        assert!(response
+            .headers()
+            .get(&http_serde::query::results::PROCESSED_USERS_HEADER)
+            .is_none());
+    }
+
+    #[derive(Debug)]
+    struct ReportedOutput(SignedQueryReport);
+
+    impl ProtocolResult for ReportedOutput {
+        fn into_bytes(self: Box<Self>) -> Vec<u8> {
+            vec![1, 2, 3]
+        }
+
+        fn report(&self) -> Option<&SignedQueryReport> {
+            Some(&self.0)
+        }
+    }
+
+    #[tokio::test]
+    async fn query_report() {
+        let signer = ReportSigner::new(ed25519_dalek::SigningKey::from_bytes(&[1; 32]));
+        let report = signer.sign(QueryReport {
+            manifest: QueryManifest {
+                config: QueryConfig::new(QueryType::TestMultiply, FieldType::Fp31, 1).unwrap(),
+                role: Role::H3,
+            },
+            input_digest: [2; 32],
+            dp: None,
+            stages: Vec::new(),
+            output_digest: [3; 32],
+            commitments: None,
+        });
+        let cb = TransportCallbacks {
+            complete_query: Box::new(move |_transport, _query_id| {
+                let output = ReportedOutput(report.clone());
+                Box::pin(ready(Ok(Box::new(output) as Box<dyn ProtocolResult>)))
+            }),
+            ..Default::default()
+        };
+        let TestServer { transport, .. } = TestServer::builder().with_callbacks(cb).build().await;
+        let req = http_serde::query::results::Request::new(QueryId::default());
+        let response = handler(Extension(transport), req).await.unwrap();
+
+        let header = response
+            .headers()
+            .get(&http_serde::query::results::QUERY_REPORT_HEADER)
+            .unwrap();
+        let received = serde_json::from_slice::<SignedQueryReport>(header.as_bytes()).unwrap();
+        received.verify(&signer.verifying_key()).unwrap();
+        assert_eq!(Role::H3, received.report.manifest.role);
+    }
+
+    #[tokio::test]
+    async fn execution_error() {
+        let gate = Gate::default().narrow("mul");
+        let step = gate.to_string();
+        let cb = TransportCallbacks {
+            complete_query: Box::new(move |_transport, _query_id| {
+                let err = InfraError::ReceiveError {
+                    source: Role::H2,
+                    step: step.clone(),
+                    inner: "connection reset".into(),
+                };
+                Box::pin(ready(Err(ProtocolError::InfraError(err).into())))
+            }),
+            ..Default::default()
+        };
+        let TestServer { transport, .. } = TestServer::builder().with_callbacks(cb).build().await;
+        let req = http_serde::query::results::Request::new(QueryId::default());
+        let response = handler(Extension(transport), req)
+            .await
+            .unwrap_err()
+            .into_response();
+
+        assert_eq!(StatusCode::BAD_GATEWAY, response.status());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = serde_json::from_slice::<ErrorBody>(&body).unwrap();
+        assert_eq!(ErrorCode::PeerFailure, body.code);
+        assert_eq!(Some(gate.as_ref()), body.gate.as_deref());
+    }
+
     struct OverrideReq {
         query_id: String,
     }
@@ -14,7 +14,11 @@ async fn handler(
 ) -> Result<Json<status::ResponseBody>, Error> {
     let transport = Transport::clone_ref(&*transport);
     match transport.query_status(req.query_id).await {
-        Ok(state) => Ok(Json(status::ResponseBody { status: state })),
+        Ok(report) => Ok(Json(status::ResponseBody {
+            status: report.status,
+            progress: report.progress,
+            metrics: report.metrics,
+        })),
         Err(e) => Err(Error::application(StatusCode::INTERNAL_SERVER_ERROR, e)),
     }
 }
@@ -27,40 +31,65 @@ pub fn router(transport: Arc<HttpTransport>) -> Router {
 
 #[cfg(all(test, unit_test))]
 mod tests {
-    use std::future::ready;
+    use std::{future::ready, time::Duration};
 
     use axum::http::Request;
     use hyper::StatusCode;
 
     use super::*;
     use crate::{
-        helpers::TransportCallbacks,
+        helpers::{QueryMetrics, QueryProgress, StageMetrics, TransportCallbacks},
         net::{
             http_serde,
             server::handlers::query::test_helpers::{assert_req_fails_with, IntoFailingReq},
             test::TestServer,
         },
         protocol::QueryId,
-        query::QueryStatus,
+        query::{QueryStatus, QueryStatusReport},
     };
 
     #[tokio::test]
     async fn status_test() {
-        let expected_status = QueryStatus::Running;
-        let expected_query_id = QueryId;
+        let expected_status = QueryStatusReport {
+            status: QueryStatus::Running,
+            progress: Some(QueryProgress {
+                completed_records: 3,
+                total_records: 4,
+            }),
+            metrics: Some(QueryMetrics {
+                stages: vec![StageMetrics {
+                    name: "convert_input_rows_to_prf".to_string(),
+                    elapsed: Duration::from_millis(12),
+                    bytes_sent: 128,
+                    bytes_received: 64,
+                }],
+            }),
+        };
+        let expected_query_id = QueryId::default();
         let cb = TransportCallbacks {
             query_status: Box::new(move |_transport, query_id| {
                 assert_eq!(query_id, expected_query_id);
-                Box::pin(ready(Ok(expected_status)))
+                Box::pin(ready(Ok(expected_status.clone())))
             }),
             ..Default::default()
         };
         let TestServer { transport, .. } = TestServer::builder().with_callbacks(cb).build().await;
-        let req = http_serde::query::status::Request::new(QueryId);
+        let req = http_serde::query::status::Request::new(QueryId::default());
         let response = handler(Extension(transport), req.clone()).await.unwrap();
 
-        let Json(http_serde::query::status::ResponseBody { status }) = response;
-        assert_eq!(status, expected_status);
+        let Json(http_serde::query::status::ResponseBody {
+            status,
+            progress,
+            metrics,
+        }) = response;
+        assert_eq!(
+            QueryStatusReport {
+                status,
+                progress,
+                metrics
+            },
+            expected_status
+        );
     }
 
     struct OverrideReq {
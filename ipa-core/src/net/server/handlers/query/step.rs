@@ -1,8 +1,14 @@
-use axum::{routing::post, Extension, Router};
+use axum::{
+    http::HeaderValue,
+    response::{IntoResponse, Response},
+    routing::post,
+    Extension, Router,
+};
 
 use crate::{
     helpers::{BodyStream, Transport},
     net::{
+        compression::{decompress, RECORD_ENCODING_HEADER},
         http_serde,
         server::{ClientIdentity, Error},
         HttpTransport,
@@ -10,15 +16,30 @@ use crate::{
     sync::Arc,
 };
 
+/// Responds with the wire version of the request, which the request extractor has already
+/// checked to be supported, and the encoding of records, if they are compressed.
 #[allow(clippy::unused_async)] // axum doesn't like synchronous handler
 async fn handler(
     transport: Extension<Arc<HttpTransport>>,
     from: Extension<ClientIdentity>,
     req: http_serde::query::step::Request<BodyStream>,
-) -> Result<(), Error> {
+) -> Result<Response, Error> {
     let transport = Transport::clone_ref(&*transport);
-    transport.receive_stream(req.query_id, req.gate, **from, req.body);
-    Ok(())
+    let body = match req.encoding {
+        Some(encoding) => BodyStream::from_bytes_stream(decompress(req.body, encoding)),
+        None => req.body,
+    };
+    transport.receive_stream(req.query_id, req.gate, **from, body)?;
+
+    let mut response = ().into_response();
+    req.wire_version.insert_into(response.headers_mut());
+    if let Some(encoding) = req.encoding {
+        response.headers_mut().insert(
+            &RECORD_ENCODING_HEADER,
+            HeaderValue::from_static(encoding.as_str()),
+        );
+    }
+    Ok(response)
 }
 
 pub fn router(transport: Arc<HttpTransport>) -> Router {
@@ -32,18 +53,25 @@ mod tests {
     use std::task::Poll;
 
     use axum::http::Request;
-    use futures::{stream::poll_immediate, StreamExt};
+    use futures::{
+        stream::{self, poll_immediate},
+        StreamExt,
+    };
     use hyper::{Body, StatusCode};
 
     use super::*;
     use crate::{
+        config::CompressionConfig,
         helpers::{HelperIdentity, MESSAGE_PAYLOAD_SIZE_BYTES},
         net::{
+            compression::compress,
             server::handlers::query::{
                 test_helpers::{assert_req_fails_with, IntoFailingReq},
                 MaybeExtensionExt,
             },
             test::TestServer,
+            wire_version::WIRE_VERSION_HEADER,
+            RecordEncoding, WireVersion,
         },
         protocol::{
             step::{Gate, StepNarrow},
@@ -56,21 +84,72 @@ mod tests {
     #[tokio::test]
     async fn step() {
         let TestServer { transport, .. } = TestServer::builder().build().await;
+        transport.open_query_streams(QueryId::default());
 
         let step = Gate::default().narrow("test");
         let payload = vec![213; DATA_LEN * MESSAGE_PAYLOAD_SIZE_BYTES];
+        let req = http_serde::query::step::Request::new(
+            QueryId::default(),
+            step.clone(),
+            payload.clone().into(),
+        );
+
+        let resp = handler(
+            Extension(Arc::clone(&transport)),
+            Extension(ClientIdentity(HelperIdentity::TWO)),
+            req,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            WireVersion::CURRENT,
+            WireVersion::from_headers(resp.headers()).unwrap()
+        );
+
+        let mut stream =
+            Arc::clone(&transport).receive(HelperIdentity::TWO, (QueryId::default(), step));
+
+        assert_eq!(
+            poll_immediate(&mut stream).next().await,
+            Some(Poll::Ready(payload))
+        );
+    }
+
+    #[tokio::test]
+    async fn compressed_step() {
+        let TestServer { transport, .. } = TestServer::builder().build().await;
+        transport.open_query_streams(QueryId::default());
+
+        let step = Gate::default().narrow("test");
+        let payload = vec![1; DATA_LEN * MESSAGE_PAYLOAD_SIZE_BYTES];
+        let config = CompressionConfig {
+            encoding: RecordEncoding::Gzip,
+            threshold: 1,
+        };
+        let body = compress(stream::iter([payload.clone()]), config)
+            .concat()
+            .await;
+        assert!(body.len() < payload.len());
         let req =
-            http_serde::query::step::Request::new(QueryId, step.clone(), payload.clone().into());
+            http_serde::query::step::Request::new(QueryId::default(), step.clone(), body.into())
+                .with_encoding(config.encoding);
 
-        handler(
+        let resp = handler(
             Extension(Arc::clone(&transport)),
             Extension(ClientIdentity(HelperIdentity::TWO)),
             req,
         )
         .await
         .unwrap();
+        assert_eq!(
+            Some("gzip"),
+            resp.headers()
+                .get(&RECORD_ENCODING_HEADER)
+                .map(|v| v.to_str().unwrap())
+        );
 
-        let mut stream = Arc::clone(&transport).receive(HelperIdentity::TWO, (QueryId, step));
+        let mut stream =
+            Arc::clone(&transport).receive(HelperIdentity::TWO, (QueryId::default(), step));
 
         assert_eq!(
             poll_immediate(&mut stream).next().await,
@@ -78,10 +157,31 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn unknown_query() {
+        let TestServer { transport, .. } = TestServer::builder().build().await;
+
+        let req = http_serde::query::step::Request::new(
+            QueryId::default(),
+            Gate::default().narrow("test"),
+            vec![1; DATA_LEN * MESSAGE_PAYLOAD_SIZE_BYTES].into(),
+        );
+        assert!(matches!(
+            handler(
+                Extension(Arc::clone(&transport)),
+                Extension(ClientIdentity(HelperIdentity::TWO)),
+                req,
+            )
+            .await,
+            Err(Error::QueryIdNotFound(_))
+        ));
+    }
+
     struct OverrideReq {
         client_id: Option<ClientIdentity>,
         query_id: String,
         gate: Gate,
+        wire_version: Option<&'static str>,
         payload: Vec<u8>,
     }
 
@@ -94,10 +194,11 @@ mod tests {
                 self.query_id,
                 self.gate.as_ref()
             );
-            hyper::Request::post(uri)
-                .maybe_extension(self.client_id)
-                .body(hyper::Body::from(self.payload))
-                .unwrap()
+            let mut req = hyper::Request::post(uri).maybe_extension(self.client_id);
+            if let Some(version) = self.wire_version {
+                req = req.header(&WIRE_VERSION_HEADER, version);
+            }
+            req.body(hyper::Body::from(self.payload)).unwrap()
         }
     }
 
@@ -105,8 +206,9 @@ mod tests {
         fn default() -> Self {
             Self {
                 client_id: Some(ClientIdentity(HelperIdentity::ONE)),
-                query_id: QueryId.as_ref().to_string(),
+                query_id: QueryId::default().to_string(),
                 gate: Gate::default().narrow("test"),
+                wire_version: None,
                 payload: vec![1; DATA_LEN * MESSAGE_PAYLOAD_SIZE_BYTES],
             }
         }
@@ -121,6 +223,24 @@ mod tests {
         assert_req_fails_with(req, StatusCode::UNPROCESSABLE_ENTITY).await;
     }
 
+    #[tokio::test]
+    async fn unsupported_wire_version_fails() {
+        let req = OverrideReq {
+            wire_version: Some("0"),
+            ..Default::default()
+        };
+        assert_req_fails_with(req, StatusCode::BAD_REQUEST).await;
+    }
+
+    #[tokio::test]
+    async fn malformed_wire_version_fails() {
+        let req = OverrideReq {
+            wire_version: Some("not-a-version"),
+            ..Default::default()
+        };
+        assert_req_fails_with(req, StatusCode::BAD_REQUEST).await;
+    }
+
     #[tokio::test]
     async fn auth_required() {
         let req = OverrideReq {
@@ -44,7 +44,7 @@ use tracing::{error, Span};
 use crate::{
     config::{NetworkConfig, ServerConfig, TlsConfig},
     error::BoxError,
-    helpers::HelperIdentity,
+    helpers::{query::InputPart, HelperIdentity, Transport},
     net::{Error, HttpTransport},
     sync::Arc,
     task::JoinHandle,
@@ -77,20 +77,26 @@ impl TracingSpanMaker for () {
 pub struct MpcHelperServer {
     transport: Arc<HttpTransport>,
     config: ServerConfig,
-    network_config: NetworkConfig,
 }
 
 impl MpcHelperServer {
-    pub fn new(
-        transport: Arc<HttpTransport>,
-        config: ServerConfig,
-        network_config: NetworkConfig,
-    ) -> Self {
-        MpcHelperServer {
-            transport,
-            config,
-            network_config,
-        }
+    pub fn new(transport: Arc<HttpTransport>, config: ServerConfig) -> Self {
+        MpcHelperServer { transport, config }
+    }
+
+    /// Creates the TLS configuration to start the server with, from the network configuration
+    /// of the transport. The transport keeps a reference to it, to reload it when the network
+    /// configuration is updated.
+    async fn rustls_config(&self) -> RustlsConfig {
+        let rustls_config = rustls_config(
+            &self.config,
+            &self.transport.peers().network,
+            self.transport.identity(),
+        )
+        .await
+        .expect("invalid TLS configuration");
+        self.transport.set_server_tls(rustls_config.clone());
+        rustls_config
     }
 
     fn router(&self) -> Router {
@@ -132,7 +138,25 @@ impl MpcHelperServer {
 
         let svc = self.router().layer(
             TraceLayer::new_for_http()
-                .make_span_with(move |_request: &hyper::Request<hyper::Body>| tracing.make_span())
+                .make_span_with(move |request: &hyper::Request<hyper::Body>| {
+                    let span = tracing.make_span();
+                    // Requests coming from other helpers and report collectors carry the trace
+                    // context of the caller, this links server-side spans to the same trace.
+                    #[cfg(feature = "otel")]
+                    let span = {
+                        let span = tracing::info_span!(
+                            parent: &span,
+                            "request",
+                            method = %request.method(),
+                            path = %request.uri().path(),
+                        );
+                        crate::telemetry::otel::set_remote_parent(&span, request.headers());
+                        span
+                    };
+                    #[cfg(not(feature = "otel"))]
+                    let _ = request;
+                    span
+                })
                 .on_request(|request: &hyper::Request<hyper::Body>, _: &Span| {
                     increment_counter!(RequestProtocolVersion::from(request.version()));
                     increment_counter!(REQUESTS_RECEIVED);
@@ -155,12 +179,10 @@ impl MpcHelperServer {
                 spawn_server(axum_server::bind(addr), handle.clone(), svc).await
             }
             (false, Some(listener)) => {
-                let rustls_config = rustls_config(&self.config, &self.network_config)
-                    .await
-                    .expect("invalid TLS configuration");
+                let rustls_config = self.rustls_config().await;
                 spawn_server(
                     axum_server::from_tcp_rustls(listener, rustls_config).map(|a| {
-                        ClientCertRecognizingAcceptor::new(a, self.network_config.clone())
+                        ClientCertRecognizingAcceptor::new(a, Arc::clone(&self.transport))
                     }),
                     handle.clone(),
                     svc.into_make_service(),
@@ -169,12 +191,10 @@ impl MpcHelperServer {
             }
             (false, None) => {
                 let addr = SocketAddr::new(BIND_ADDRESS.into(), self.config.port.unwrap_or(0));
-                let rustls_config = rustls_config(&self.config, &self.network_config)
-                    .await
-                    .expect("invalid TLS configuration");
+                let rustls_config = self.rustls_config().await;
                 spawn_server(
                     axum_server::bind_rustls(addr, rustls_config).map(|a| {
-                        ClientCertRecognizingAcceptor::new(a, self.network_config.clone())
+                        ClientCertRecognizingAcceptor::new(a, Arc::clone(&self.transport))
                     }),
                     handle.clone(),
                     svc.into_make_service(),
@@ -242,7 +262,7 @@ where
     })
 }
 
-async fn certificate_and_key(
+pub(super) async fn certificate_and_key(
     config: &ServerConfig,
 ) -> Result<(Vec<Certificate>, PrivateKey), BoxError> {
     let (cert, key) = match &config.tls {
@@ -282,24 +302,44 @@ async fn certificate_and_key(
 /// we import as `RustlsServerConfig`. Since we have particular needs related to client
 /// certificates, we build a native rustls config, and then convert it into the axum config type.
 ///
+/// Helpers authenticate each other and report collectors that submit parts of query inputs by the
+/// certificates listed in the network configuration, so all of them must be there, and the one
+/// listed for `identity` must be the one this server presents.
+///
 /// # Errors
 /// If there is a problem with the TLS configuration.
-async fn rustls_config(
+pub(super) async fn rustls_config(
     config: &ServerConfig,
     network: &NetworkConfig,
+    identity: HelperIdentity,
 ) -> Result<RustlsConfig, BoxError> {
     let (cert, key) = certificate_and_key(config).await?;
 
     let mut trusted_certs = RootCertStore::empty();
-    for cert in network
-        .peers()
-        .iter()
-        .filter_map(|peer| peer.certificate.as_ref())
-    {
+    for (id, peer) in network.enumerate_peers() {
+        let peer_cert = peer.certificate.as_ref().ok_or_else(|| {
+            format!("network configuration does not have a certificate for helper {id:?}")
+        })?;
+        if id == identity && cert.first() != Some(peer_cert) {
+            return Err(format!(
+                "certificate of this server does not match the one listed for helper {id:?} in \
+                 network configuration"
+            )
+            .into());
+        }
         // Note that this uses `webpki::TrustAnchor::try_from_cert_der`, which *does not* validate
         // the certificate. That is not required for security, but might be desirable to flag
         // configuration errors.
-        trusted_certs.add(cert)?;
+        trusted_certs.add(peer_cert)?;
+    }
+    for collector in &network.report_collectors {
+        let collector_cert = collector.certificate.as_ref().ok_or_else(|| {
+            format!(
+                "network configuration does not have a certificate for report collector {}",
+                collector.name
+            )
+        })?;
+        trusted_certs.add(collector_cert)?;
     }
     let verifier = AllowAnyAnonymousOrAuthenticatedClient::new(trusted_certs);
 
@@ -330,43 +370,55 @@ impl Deref for ClientIdentity {
     }
 }
 
-/// `Accept`or that sets an axum `Extension` indiciating the authenticated remote helper identity.
+/// Axum `Extension` indicating that the client is an authenticated report collector, and the part
+/// of query inputs it is entitled to submit. See [`ClientIdentity`] for how its presence is used.
+#[derive(Clone, Copy, Debug)]
+struct ReportCollectorIdentity(pub InputPart);
+
+/// `Accept`or that sets an axum `Extension` indiciating the authenticated remote helper identity,
+/// or the authenticated report collector.
+///
+/// Clients are identified by the network configuration of the transport at the time they connect,
+/// which may change while the server is running.
 #[derive(Clone)]
 struct ClientCertRecognizingAcceptor {
     inner: RustlsAcceptor,
-    network_config: Arc<NetworkConfig>,
+    transport: Arc<HttpTransport>,
 }
 
 impl ClientCertRecognizingAcceptor {
-    fn new(inner: RustlsAcceptor, network_config: NetworkConfig) -> Self {
-        Self {
-            inner,
-            network_config: Arc::new(network_config),
-        }
+    fn new(inner: RustlsAcceptor, transport: Arc<HttpTransport>) -> Self {
+        Self { inner, transport }
     }
 
     // This can't be a method (at least not that takes `&self`) because it needs to go in a 'static future.
     fn identify_client(
         network_config: &NetworkConfig,
         cert_option: Option<&Certificate>,
-    ) -> Option<ClientIdentity> {
+    ) -> (Option<ClientIdentity>, Option<ReportCollectorIdentity>) {
         let Some(cert) = cert_option else {
-            return None;
+            return (None, None);
         };
         // We currently require an exact match with the peer cert (i.e. we don't support verifying
         // the certificate against a truststore and identifying the peer by the certificate
         // subject). This could be changed if the need arises.
         for (id, peer) in network_config.enumerate_peers() {
             if peer.certificate.as_ref() == Some(cert) {
-                return Some(ClientIdentity(id));
+                return (Some(ClientIdentity(id)), None);
+            }
+        }
+        for collector in &network_config.report_collectors {
+            if collector.certificate.as_ref() == Some(cert) {
+                return (None, Some(ReportCollectorIdentity(collector.input_part)));
             }
         }
         // It might be nice to log something here. We could log the certificate base64?
         error!(
-            "A client certificate was presented that does not match a known helper. Certificate: {}",
+            "A client certificate was presented that does not match a known helper or report \
+             collector. Certificate: {}",
             BASE64.encode(cert),
         );
-        None
+        (None, None)
     }
 }
 
@@ -381,7 +433,7 @@ where
 
     fn accept(&self, stream: I, service: S) -> Self::Future {
         let acceptor = self.inner.clone();
-        let network_config = Arc::clone(&self.network_config);
+        let transport = Arc::clone(&self.transport);
 
         Box::pin(async move {
             let (stream, service) = acceptor.accept(stream, service).await.map_err(|err| {
@@ -389,22 +441,26 @@ where
                 err
             })?;
 
-            // The return from `identify_client` is an `Option<HelperIdentity>`.
+            // The return from `identify_client` is the identity of a helper or a report collector.
             // No client identity will be associated with the connection if:
             //  * No certificate was supplied.
             //  * There was a problem interpreting the certificate. It is unlikely to see an invalid
             //    certificate here, because the certificate must have passed full verification at
             //    connection time. But it's possible the certificate subject is not something we
-            //    recognize as a helper.
-            let id = Self::identify_client(
-                &network_config,
+            //    recognize as a helper or a report collector.
+            let (id, collector) = Self::identify_client(
+                &transport.peers().network,
                 stream
                     .get_ref()
                     .1
                     .peer_certificates()
                     .and_then(<[_]>::first),
             );
-            let service = SetClientIdentityFromCertificate { inner: service, id };
+            let service = SetClientIdentityFromCertificate {
+                inner: service,
+                id,
+                collector,
+            };
             Ok((stream, service))
         })
     }
@@ -414,6 +470,7 @@ where
 struct SetClientIdentityFromCertificate<S> {
     inner: S,
     id: Option<ClientIdentity>,
+    collector: Option<ReportCollectorIdentity>,
 }
 
 impl<B, S: Service<Request<B>>> Service<Request<B>> for SetClientIdentityFromCertificate<S> {
@@ -430,6 +487,9 @@ impl<B, S: Service<Request<B>>> Service<Request<B>> for SetClientIdentityFromCer
         if let Some(id) = self.id {
             req.extensions_mut().insert(id);
         }
+        if let Some(collector) = self.collector {
+            req.extensions_mut().insert(collector);
+        }
         self.inner.call(req)
     }
 }
@@ -438,7 +498,13 @@ impl<B, S: Service<Request<B>>> Service<Request<B>> for SetClientIdentityFromCer
 pub static HTTP_CLIENT_ID_HEADER: HeaderName =
     HeaderName::from_static("x-unverified-client-identity");
 
-/// Service wrapper that gets a client helper identity from a header.
+/// Name of the header that passes the part of query inputs a report collector submits when not
+/// using HTTPS.
+pub static HTTP_REPORT_COLLECTOR_HEADER: HeaderName =
+    HeaderName::from_static("x-unverified-report-collector");
+
+/// Service wrapper that gets a client helper identity, or the part of query inputs a report
+/// collector is entitled to submit, from a header.
 ///
 /// Since this allows a client to claim any identity, it is completely
 /// insecure. It must only be used in contexts where that is acceptable.
@@ -474,6 +540,15 @@ impl<B, S: Service<Request<B>, Response = Response>> Service<Request<B>>
                 Err(err) => return ready(Ok(err.into_response())).right_future(),
             };
         }
+        if let Some(header_value) = req.headers().get(&HTTP_REPORT_COLLECTOR_HEADER) {
+            let part_result = serde_json::from_slice(header_value.as_ref()).map_err(|e| {
+                Error::InvalidHeader(format!("{HTTP_REPORT_COLLECTOR_HEADER}: {e}").into())
+            });
+            match part_result {
+                Ok(part) => req.extensions_mut().insert(ReportCollectorIdentity(part)),
+                Err(err) => return ready(Ok(err.into_response())).right_future(),
+            };
+        }
         self.inner.call(req).left_future()
     }
 }
@@ -493,7 +568,10 @@ mod e2e_tests {
 
     use super::*;
     use crate::{
-        net::{http_serde, test::TestServer},
+        net::{
+            http_serde,
+            test::{TestConfig, TestServer},
+        },
         test_fixture::metrics::MetricsHandle,
     };
 
@@ -581,6 +659,28 @@ mod e2e_tests {
         assert_eq!(expected, resp_body);
     }
 
+    #[tokio::test]
+    async fn certificates_bound_to_identities() {
+        let TestConfig {
+            mut network,
+            servers,
+            ..
+        } = TestConfig::builder().build();
+
+        rustls_config(&servers[0], &network, HelperIdentity::ONE)
+            .await
+            .unwrap();
+        // server of helper 2 presents a certificate of another helper
+        assert!(rustls_config(&servers[1], &network, HelperIdentity::ONE)
+            .await
+            .is_err());
+
+        network.peers[2].certificate = None;
+        assert!(rustls_config(&servers[0], &network, HelperIdentity::ONE)
+            .await
+            .is_err());
+    }
+
     /// Ensures that server tracks number of requests it received and emits a corresponding metric.
     /// In order for this test not to be flaky, we rely on tokio::test macro to set up a
     /// new runtime per test (which it currently does) and set up metric recorders per thread (done
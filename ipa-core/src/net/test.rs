@@ -71,6 +71,7 @@ fn server_config_insecure_http(port: u16, matchkey_encryption: bool) -> ServerCo
         disable_https: true,
         tls: None,
         hpke_config: get_dummy_matchkey_encryption_info(matchkey_encryption),
+        admin_token: None,
     }
 }
 
@@ -89,6 +90,7 @@ pub fn server_config_https(
             private_key: String::from_utf8(private_key.to_owned()).unwrap(),
         }),
         hpke_config: get_dummy_matchkey_encryption_info(matchkey_encryption),
+        admin_token: None,
     }
 }
 
@@ -184,6 +186,7 @@ impl TestConfigBuilder {
             .unwrap();
         let network = NetworkConfig {
             peers,
+            report_collectors: Vec::new(),
             client: self
                 .use_http1
                 .then(ClientConfig::use_http1)
@@ -237,6 +240,7 @@ pub struct TestServerBuilder {
     disable_https: bool,
     use_http1: bool,
     disable_matchkey_encryption: bool,
+    admin_token: Option<String>,
 }
 
 impl TestServerBuilder {
@@ -267,6 +271,13 @@ impl TestServerBuilder {
         self
     }
 
+    #[cfg(all(test, unit_test))]
+    #[must_use]
+    pub fn with_admin_token(mut self, token: &str) -> Self {
+        self.admin_token = Some(token.to_owned());
+        self
+    }
+
     #[cfg(all(test, web_test))]
     pub fn use_http1(mut self) -> Self {
         self.use_http1 = true;
@@ -286,13 +297,14 @@ impl TestServerBuilder {
             .build();
         let TestConfig {
             network: network_config,
-            servers: [server_config, _, _],
+            servers: [mut server_config, _, _],
             sockets: Some([server_socket, _, _]),
             ..
         } = test_config
         else {
             panic!("TestConfig should have allocated ports");
         };
+        server_config.admin_token = self.admin_token;
         let clients = MpcHelperClient::from_conf(&network_config, identity.clone());
         let (transport, server) = HttpTransport::new(
             HelperIdentity::ONE,
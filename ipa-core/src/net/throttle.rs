@@ -0,0 +1,174 @@
+//! Bandwidth limits on record streams sent to peers.
+//!
+//! Every client holds a [`TokenBucket`] for the peer it talks to, if [`BandwidthLimit`] is
+//! configured. Chunks of records take as many tokens out of the bucket as they have bytes before
+//! they are handed to the HTTP client, and wait for the bucket to refill if it ran out. A chunk
+//! larger than the burst is still sent, once the bucket had time to refill the bytes it is short.
+use std::time::{Duration, Instant};
+
+use futures::{Stream, StreamExt};
+
+use crate::{
+    config::BandwidthLimit,
+    sync::{Arc, Mutex},
+    telemetry::{
+        labels::PEER,
+        metrics::{THROTTLED_SENDS, THROTTLE_WAIT_MICROS},
+    },
+};
+
+#[derive(Debug)]
+struct State {
+    /// Negative if senders reserved more than the bucket had, they wait for it to refill.
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+#[derive(Debug)]
+pub struct TokenBucket {
+    bytes_per_second: f64,
+    burst_bytes: f64,
+    /// Labels metrics of throttled sends.
+    peer: String,
+    state: Mutex<State>,
+}
+
+impl TokenBucket {
+    #[allow(clippy::cast_precision_loss)]
+    pub fn new(limit: BandwidthLimit, peer: String) -> Self {
+        let burst_bytes = limit.burst_bytes.get() as f64;
+        Self {
+            bytes_per_second: limit.bytes_per_second.get() as f64,
+            burst_bytes,
+            peer,
+            state: Mutex::new(State {
+                tokens: burst_bytes,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes `bytes` tokens out of the bucket at `now` and returns how long the sender must wait
+    /// before sending them.
+    #[allow(clippy::cast_precision_loss)]
+    fn reserve(&self, bytes: usize, now: Instant) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let elapsed = now.saturating_duration_since(state.refilled_at);
+        state.tokens =
+            (state.tokens + elapsed.as_secs_f64() * self.bytes_per_second).min(self.burst_bytes);
+        state.refilled_at = now;
+        state.tokens -= bytes as f64;
+
+        if state.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.tokens / self.bytes_per_second)
+        }
+    }
+
+    /// Waits until `bytes` can be sent to the peer.
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = self.reserve(bytes, Instant::now());
+        if !wait.is_zero() {
+            metrics::increment_counter!(THROTTLED_SENDS, PEER => self.peer.clone());
+            metrics::counter!(
+                THROTTLE_WAIT_MICROS,
+                u64::try_from(wait.as_micros()).unwrap_or(u64::MAX),
+                PEER => self.peer.clone()
+            );
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Holds every chunk of `data` back until `bucket` allows it to be sent. Chunks are let through
+/// as is if there is no bucket.
+pub fn throttle<S>(data: S, bucket: Option<Arc<TokenBucket>>) -> impl Stream<Item = S::Item>
+where
+    S: Stream,
+    S::Item: AsRef<[u8]>,
+{
+    data.then(move |chunk| {
+        let bucket = bucket.clone();
+        async move {
+            if let Some(bucket) = bucket {
+                bucket.acquire(chunk.as_ref().len()).await;
+            }
+            chunk
+        }
+    })
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use std::{
+        num::NonZeroU64,
+        time::{Duration, Instant},
+    };
+
+    use futures::{stream, StreamExt};
+
+    use super::{throttle, TokenBucket};
+    use crate::{config::BandwidthLimit, sync::Arc};
+
+    fn bucket(bytes_per_second: u64, burst_bytes: u64) -> TokenBucket {
+        TokenBucket::new(
+            BandwidthLimit {
+                bytes_per_second: NonZeroU64::new(bytes_per_second).unwrap(),
+                burst_bytes: NonZeroU64::new(burst_bytes).unwrap(),
+            },
+            "localhost".to_string(),
+        )
+    }
+
+    fn assert_wait(expected_millis: f64, wait: Duration) {
+        assert!(
+            (wait.as_secs_f64() * 1000.0 - expected_millis).abs() < 1e-6,
+            "expected to wait {expected_millis}ms, got {wait:?}"
+        );
+    }
+
+    #[test]
+    fn reserve() {
+        let bucket = bucket(1000, 500);
+        let start = Instant::now();
+
+        // burst goes through right away
+        assert_wait(0.0, bucket.reserve(500, start));
+        // the next chunk waits for the bucket to refill
+        assert_wait(100.0, bucket.reserve(100, start));
+        // so does the one after it, behind the previous one
+        assert_wait(300.0, bucket.reserve(200, start));
+        // the bucket refills, but never above the burst
+        let later = start + Duration::from_secs(10);
+        assert_wait(0.0, bucket.reserve(500, later));
+        assert_wait(1.0, bucket.reserve(1, later));
+    }
+
+    #[test]
+    fn chunk_larger_than_burst() {
+        let bucket = bucket(1000, 100);
+        assert_wait(900.0, bucket.reserve(1000, Instant::now()));
+    }
+
+    #[tokio::test]
+    async fn throttles_stream() {
+        let bucket = Arc::new(bucket(10_000, 100));
+        let start = Instant::now();
+        let chunks = throttle(stream::iter(vec![vec![0_u8; 100]; 3]), Some(bucket))
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(3, chunks.len());
+        // the first chunk uses up the burst, the other two wait 10ms each
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[tokio::test]
+    async fn no_limit() {
+        let chunks = throttle(stream::iter(vec![vec![0_u8; 100]; 3]), None)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(vec![vec![0_u8; 100]; 3], chunks);
+    }
+}
@@ -1,39 +1,71 @@
 use std::{
     borrow::Borrow,
+    collections::HashMap,
     future::Future,
     pin::Pin,
     task::{Context, Poll},
+    time::Duration,
 };
 
 use async_trait::async_trait;
+use axum_server::tls_rustls::RustlsConfig;
 use bytes::Bytes;
-use futures::{Stream, TryFutureExt};
+use futures::{channel::oneshot, Stream, TryFutureExt};
+use hyper::{http::uri::Scheme, StatusCode};
 
 use crate::{
     config::{NetworkConfig, ServerConfig},
     error::BoxError,
     helpers::{
         query::{PrepareQuery, QueryConfig, QueryInput},
-        BodyStream, CompleteQueryResult, HelperIdentity, LogErrors, NoResourceIdentifier,
-        PrepareQueryResult, QueryIdBinding, QueryInputResult, QueryStatusResult,
-        ReceiveQueryResult, ReceiveRecords, RouteId, RouteParams, StepBinding, StreamCollection,
-        Transport, TransportCallbacks,
+        BodyStream, CompleteQueryResult, EvictionPolicy, HelperIdentity, LogErrors, MetricsResult,
+        NoResourceIdentifier, PrepareQueryResult, QueryIdBinding, QueryInputResult,
+        QueryStatusResult, ReceiveQueryResult, ReceiveRecords, RouteId, RouteParams, StepBinding,
+        StreamCollection, Transport, TransportCallbacks,
+    },
+    net::{
+        client::{ClientIdentity, MpcHelperClient},
+        error::Error,
+        mux::Multiplexer,
+        server::{certificate_and_key, rustls_config},
+        MpcHelperServer, WireVersion,
     },
-    net::{client::MpcHelperClient, error::Error, MpcHelperServer},
     protocol::{step::Gate, QueryId},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
 };
 
 type LogHttpErrors = LogErrors<BodyStream, Bytes, BoxError>;
 
+/// Configuration of the helper network and clients connected to its helpers. Replaced as a whole
+/// when the configuration is updated, see [`HttpTransport::update_network`].
+pub(crate) struct Peers {
+    pub network: NetworkConfig,
+    pub clients: [MpcHelperClient; 3],
+}
+
 /// HTTP transport for IPA helper service.
 pub struct HttpTransport {
     identity: HelperIdentity,
     callbacks: TransportCallbacks<Arc<HttpTransport>>,
-    clients: [MpcHelperClient; 3],
-    // TODO(615): supporting multiple queries likely require a hashmap here. It will be ok if we
-    // only allow one query at a time.
-    record_streams: StreamCollection<LogHttpErrors>,
+    server_config: ServerConfig,
+    peers: Mutex<Arc<Peers>>,
+    /// TLS configuration of the server, set once it starts listening for HTTPS connections.
+    server_tls: Mutex<Option<RustlsConfig>>,
+    /// Set while the network configuration is updated. Helper does not take new queries then.
+    draining: AtomicBool,
+    /// Inbound record streams, one collection per query. Collection is created when this helper
+    /// prepares the query and is removed when that query completes, streams that arrive later are
+    /// rejected.
+    record_streams: Mutex<HashMap<QueryId, StreamCollection<LogHttpErrors>>>,
+    /// Outbound multiplexed connections, one per query and peer. Only used if clients are
+    /// configured to multiplex record streams. Connection is closed when its query completes.
+    multiplexers: Mutex<HashMap<(QueryId, HelperIdentity), Multiplexer>>,
+    /// Wire versions negotiated with peers when queries were prepared. Removed when the query
+    /// completes.
+    wire_versions: Mutex<HashMap<(QueryId, HelperIdentity), WireVersion>>,
 }
 
 impl HttpTransport {
@@ -45,30 +77,244 @@ impl HttpTransport {
         clients: [MpcHelperClient; 3],
         callbacks: TransportCallbacks<Arc<HttpTransport>>,
     ) -> (Arc<Self>, MpcHelperServer) {
-        let transport = Self::new_internal(identity, clients, callbacks);
-        let server = MpcHelperServer::new(Arc::clone(&transport), server_config, network_config);
+        let transport = Self::new_internal(
+            identity,
+            server_config.clone(),
+            Peers {
+                network: network_config,
+                clients,
+            },
+            callbacks,
+        );
+        let server = MpcHelperServer::new(Arc::clone(&transport), server_config);
         (transport, server)
     }
 
     fn new_internal(
         identity: HelperIdentity,
-        clients: [MpcHelperClient; 3],
+        server_config: ServerConfig,
+        peers: Peers,
         callbacks: TransportCallbacks<Arc<HttpTransport>>,
     ) -> Arc<Self> {
         Arc::new(Self {
             identity,
             callbacks,
-            clients,
-            record_streams: StreamCollection::default(),
+            server_config,
+            peers: Mutex::new(Arc::new(peers)),
+            server_tls: Mutex::default(),
+            draining: AtomicBool::new(false),
+            record_streams: Mutex::default(),
+            multiplexers: Mutex::default(),
+            wire_versions: Mutex::default(),
+        })
+    }
+
+    pub(crate) fn server_config(&self) -> &ServerConfig {
+        &self.server_config
+    }
+
+    /// Returns the current configuration of the helper network and clients connected to it.
+    pub(crate) fn peers(&self) -> Arc<Peers> {
+        Arc::clone(&self.peers.lock().unwrap())
+    }
+
+    fn client(&self, dest: HelperIdentity) -> MpcHelperClient {
+        self.peers.lock().unwrap().clients[dest].clone()
+    }
+
+    /// Remembers the TLS configuration the server was started with, so it can be reloaded when
+    /// the network configuration changes.
+    pub(crate) fn set_server_tls(&self, tls: RustlsConfig) {
+        *self.server_tls.lock().unwrap() = Some(tls);
+    }
+
+    /// ## Errors
+    /// If the network configuration is being updated, new queries must wait until that is done.
+    pub(crate) fn check_accepting_queries(&self) -> Result<(), Error> {
+        if self.draining.load(Ordering::Acquire) {
+            return Err(Error::application(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "helper is updating its network configuration, try again later",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Replaces the network configuration of this helper while it is running, so that helpers can
+    /// rotate their certificates without restarting at the same time. From now on, the server
+    /// trusts certificates listed in `network` and presents the certificate currently found in its
+    /// TLS configuration, and clients connect to peers at their new addresses with that
+    /// certificate.
+    ///
+    /// Running queries complete with the old configuration first. This helper does not take new
+    /// queries until they do, and waits no longer than `drain_timeout` for them.
+    ///
+    /// ## Errors
+    /// If another update is in progress, if `network` or the TLS configuration of this helper are
+    /// not valid, or if queries are still running after `drain_timeout`. Configuration is left as
+    /// it was in that case.
+    pub async fn update_network(
+        self: &Arc<Self>,
+        network: NetworkConfig,
+        drain_timeout: Duration,
+    ) -> Result<(), Error> {
+        struct Draining<'a>(&'a AtomicBool);
+
+        impl Drop for Draining<'_> {
+            fn drop(&mut self) {
+                self.0.store(false, Ordering::Release);
+            }
+        }
+
+        if self.draining.swap(true, Ordering::AcqRel) {
+            return Err(Error::application(
+                StatusCode::CONFLICT,
+                "network configuration is already being updated",
+            ));
+        }
+        let _draining = Draining(&self.draining);
+
+        let (peers, tls) = self
+            .connect(network)
+            .await
+            .map_err(|e| Error::application(StatusCode::UNPROCESSABLE_ENTITY, e))?;
+        self.drain(drain_timeout).await?;
+
+        if let (Some(current), Some(tls)) = (self.server_tls.lock().unwrap().as_ref(), tls) {
+            current.reload_from_config(tls.get_inner());
+        }
+        *self.peers.lock().unwrap() = Arc::new(peers);
+        tracing::info!("network configuration updated");
+        Ok(())
+    }
+
+    /// Builds clients and server TLS configuration for `network`, without using them yet.
+    async fn connect(
+        &self,
+        network: NetworkConfig,
+    ) -> Result<(Peers, Option<RustlsConfig>), BoxError> {
+        let scheme = if self.server_config.disable_https {
+            Scheme::HTTP
+        } else {
+            Scheme::HTTPS
+        };
+        let network = network.override_scheme(&scheme);
+        if self.server_config.disable_https {
+            let clients =
+                MpcHelperClient::from_conf(&network, ClientIdentity::Helper(self.identity));
+            return Ok((Peers { network, clients }, None));
+        }
+
+        // Clients present the certificate of the server, which may have been rotated since.
+        let identity = ClientIdentity::Certificate(certificate_and_key(&self.server_config).await?);
+        let tls = rustls_config(&self.server_config, &network, self.identity).await?;
+        let clients = MpcHelperClient::from_conf(&network, identity);
+        Ok((Peers { network, clients }, Some(tls)))
+    }
+
+    /// Waits until no queries are running, but no longer than `timeout`.
+    async fn drain(self: &Arc<Self>, timeout: Duration) -> Result<(), Error> {
+        const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+        let drained = async {
+            while Arc::clone(self).metrics().await.active_queries > 0 {
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        };
+        tokio::time::timeout(timeout, drained).await.map_err(|_| {
+            Error::application(
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("queries are still running after {timeout:?}"),
+            )
         })
     }
 
+    /// Sends `data` over the multiplexed connection to `dest`, opening it if this is the first
+    /// stream sent there for this query.
+    fn send_multiplexed<D: Stream<Item = Vec<u8>> + Send + 'static>(
+        &self,
+        dest: HelperIdentity,
+        query_id: QueryId,
+        gate: &Gate,
+        data: D,
+    ) -> oneshot::Receiver<()> {
+        self.multiplexers
+            .lock()
+            .unwrap()
+            .entry((query_id, dest))
+            .or_insert_with(|| {
+                let (mux, body) = Multiplexer::new();
+                let client = self.client(dest);
+                let wire_version = self.wire_version(query_id, dest);
+                tokio::spawn(async move {
+                    let resp = async {
+                        let resp = client
+                            .step_multiplexed(query_id, wire_version, body)?
+                            .await?;
+                        MpcHelperClient::resp_ok(resp).await
+                    };
+                    if let Err(e) = resp.await {
+                        tracing::error!("multiplexed connection to {dest:?} failed: {e}");
+                    }
+                });
+                mux
+            })
+            .add(gate, data)
+    }
+
+    /// Returns the version of record framing to use with `peer` for the given query. Peers
+    /// that this helper did not negotiate a version with get the lowest supported one, see
+    /// [`WireVersion`].
+    pub fn wire_version(&self, query_id: QueryId, peer: HelperIdentity) -> WireVersion {
+        self.wire_versions
+            .lock()
+            .unwrap()
+            .get(&(query_id, peer))
+            .copied()
+            .unwrap_or(WireVersion::MIN_SUPPORTED)
+    }
+
+    /// Remembers the version of record framing negotiated with `peer` for the given query.
+    pub fn set_wire_version(&self, query_id: QueryId, peer: HelperIdentity, version: WireVersion) {
+        self.wire_versions
+            .lock()
+            .unwrap()
+            .insert((query_id, peer), version);
+    }
+
+    /// Starts accepting inbound record streams for the given query. Streams of every gate are
+    /// forgotten as soon as they are received, long queries go through too many gates to keep
+    /// them until the end.
+    pub(crate) fn open_query_streams(&self, query_id: QueryId) {
+        self.record_streams
+            .lock()
+            .unwrap()
+            .entry(query_id)
+            .or_insert_with(|| StreamCollection::new(EvictionPolicy::PerGate));
+    }
+
+    /// Returns the collection of inbound record streams for the given query, if this helper is
+    /// running it.
+    fn query_streams(&self, query_id: QueryId) -> Option<StreamCollection<LogHttpErrors>> {
+        self.record_streams.lock().unwrap().get(&query_id).cloned()
+    }
+
     pub fn receive_query(self: Arc<Self>, req: QueryConfig) -> ReceiveQueryResult {
-        (Arc::clone(&self).callbacks.receive_query)(self, req)
+        Box::pin(async move {
+            let query_id =
+                (Arc::clone(&self).callbacks.receive_query)(Arc::clone(&self), req).await?;
+            self.open_query_streams(query_id);
+            Ok(query_id)
+        })
     }
 
     pub fn prepare_query(self: Arc<Self>, req: PrepareQuery) -> PrepareQueryResult {
-        (Arc::clone(&self).callbacks.prepare_query)(self, req)
+        Box::pin(async move {
+            let query_id = req.query_id;
+            (Arc::clone(&self).callbacks.prepare_query)(Arc::clone(&self), req).await?;
+            self.open_query_streams(query_id);
+            Ok(())
+        })
     }
 
     pub fn query_input(self: Arc<Self>, req: QueryInput) -> QueryInputResult {
@@ -79,11 +325,17 @@ impl HttpTransport {
         (Arc::clone(&self).callbacks.query_status)(self, query_id)
     }
 
+    pub fn metrics(self: Arc<Self>) -> MetricsResult {
+        (Arc::clone(&self).callbacks.metrics)(self)
+    }
+
     pub fn complete_query(self: Arc<Self>, query_id: QueryId) -> CompleteQueryResult {
-        /// Cleans up the `records_stream` collection after drop to ensure this transport
-        /// can process the next query even in case of a panic.
+        /// Removes record streams of the completed query after drop to ensure this transport
+        /// can process the next query even in case of a panic. Streams that belong to other
+        /// queries are left untouched.
         struct ClearOnDrop {
             transport: Arc<HttpTransport>,
+            query_id: QueryId,
             qr: CompleteQueryResult,
         }
 
@@ -97,12 +349,34 @@ impl HttpTransport {
 
         impl Drop for ClearOnDrop {
             fn drop(&mut self) {
-                self.transport.record_streams.clear();
+                let streams = self
+                    .transport
+                    .record_streams
+                    .lock()
+                    .unwrap()
+                    .remove(&self.query_id);
+                // Receivers may still hold a reference to this collection, make sure streams
+                // are released nevertheless.
+                if let Some(streams) = streams {
+                    streams.clear();
+                }
+                // Dropping the multiplexer lets its connection finish once all streams are sent.
+                self.transport
+                    .multiplexers
+                    .lock()
+                    .unwrap()
+                    .retain(|(query_id, _), _| *query_id != self.query_id);
+                self.transport
+                    .wire_versions
+                    .lock()
+                    .unwrap()
+                    .retain(|(query_id, _), _| *query_id != self.query_id);
             }
         }
 
         Box::pin(ClearOnDrop {
             transport: Arc::clone(&self),
+            query_id,
             qr: Box::pin((Arc::clone(&self).callbacks.complete_query)(self, query_id)),
         })
     }
@@ -110,15 +384,21 @@ impl HttpTransport {
     /// Connect an inbound stream of MPC record data.
     ///
     /// This is called by peer helpers via the HTTP server.
+    ///
+    /// ## Errors
+    /// If this helper is not running the given query, for instance because it has completed
+    /// already.
     pub fn receive_stream(
         self: Arc<Self>,
         query_id: QueryId,
         gate: Gate,
         from: HelperIdentity,
         stream: BodyStream,
-    ) {
-        self.record_streams
+    ) -> Result<(), Error> {
+        self.query_streams(query_id)
+            .ok_or(Error::QueryIdNotFound(query_id))?
             .add_stream((query_id, from, gate), LogErrors::new(stream));
+        Ok(())
     }
 }
 
@@ -154,7 +434,15 @@ impl Transport for Arc<HttpTransport> {
                     .expect("query_id required when sending records");
                 let step =
                     <Option<Gate>>::from(route.gate()).expect("step required when sending records");
-                let resp_future = self.clients[dest].step(query_id, &step, data)?;
+                let client = self.client(dest);
+                if client.multiplex_streams() {
+                    return self
+                        .send_multiplexed(dest, query_id, &step, data)
+                        .await
+                        .map_err(|_| Error::MuxConnectionClosed { dest });
+                }
+                let wire_version = self.wire_version(query_id, dest);
+                let resp_future = client.step(query_id, &step, wire_version, data)?;
                 // we don't need to spawn a task here. Gateway's sender interface already does that
                 // so this can just poll this future.
                 resp_future
@@ -164,12 +452,16 @@ impl Transport for Arc<HttpTransport> {
                 Ok(())
             }
             RouteId::PrepareQuery => {
-                let req = serde_json::from_str(route.extra().borrow()).unwrap();
-                self.clients[dest].prepare_query(req).await
-            }
-            RouteId::ReceiveQuery => {
-                unimplemented!("attempting to send ReceiveQuery to another helper")
+                let req: PrepareQuery = serde_json::from_str(route.extra().borrow()).unwrap();
+                let query_id = req.query_id;
+                let wire_version = self.client(dest).prepare_query(req).await?;
+                self.set_wire_version(query_id, dest, wire_version);
+                Ok(())
             }
+            RouteId::ReceiveQuery => Err(Error::UnsupportedRoute {
+                dest,
+                route: route_id,
+            }),
         }
     }
 
@@ -178,10 +470,13 @@ impl Transport for Arc<HttpTransport> {
         from: HelperIdentity,
         route: R,
     ) -> Self::RecordsStream {
-        ReceiveRecords::new(
-            (route.query_id(), from, route.gate()),
-            self.record_streams.clone(),
-        )
+        let query_id = route.query_id();
+        // Query may have completed already, in which case nothing will ever be sent to this
+        // stream. It waits on a collection of its own rather than bringing the query back.
+        let streams = self
+            .query_streams(query_id)
+            .unwrap_or_else(|| StreamCollection::new(EvictionPolicy::PerGate));
+        ReceiveRecords::new((query_id, from, route.gate()), streams)
     }
 }
 
@@ -201,7 +496,7 @@ mod tests {
     use crate::{
         config::{NetworkConfig, ServerConfig},
         ff::{FieldType, Fp31, Serializable},
-        helpers::query::QueryType::TestMultiply,
+        helpers::query::{InputPart, QueryType::TestMultiply},
         net::{
             client::ClientIdentity,
             test::{get_test_identity, TestConfig, TestConfigBuilder, TestServer},
@@ -226,11 +521,14 @@ mod tests {
         );
 
         // Register the stream with the transport (normally called by step data HTTP API handler)
-        Arc::clone(&transport).receive_stream(QueryId, STEP.clone(), HelperIdentity::TWO, body);
+        transport.open_query_streams(QueryId::default());
+        Arc::clone(&transport)
+            .receive_stream(QueryId::default(), STEP.clone(), HelperIdentity::TWO, body)
+            .unwrap();
 
         // Request step data reception (normally called by protocol)
         let mut stream =
-            Arc::clone(&transport).receive(HelperIdentity::TWO, (QueryId, STEP.clone()));
+            Arc::clone(&transport).receive(HelperIdentity::TWO, (QueryId::default(), STEP.clone()));
 
         // make sure it is not ready as it hasn't received any data yet.
         assert!(matches!(
@@ -257,6 +555,28 @@ mod tests {
 
     // TODO(651): write a test for an error while reading the body (after error handling is finalized)
 
+    #[tokio::test]
+    async fn completed_query_stays_completed() {
+        let TestServer { transport, .. } = TestServer::default().await;
+        let query_id = QueryId::from(1);
+        transport.open_query_streams(query_id);
+        // transport forgets the query once completion is done or abandoned
+        drop(Arc::clone(&transport).complete_query(query_id));
+
+        // records requested or sent after the query has completed do not bring it back
+        let _stream = Arc::clone(&transport).receive(HelperIdentity::TWO, (query_id, STEP.clone()));
+        assert!(matches!(
+            Arc::clone(&transport).receive_stream(
+                query_id,
+                STEP.clone(),
+                HelperIdentity::TWO,
+                BodyStream::from(Vec::<u8>::new())
+            ),
+            Err(Error::QueryIdNotFound(_))
+        ));
+        assert!(transport.record_streams.lock().unwrap().is_empty());
+    }
+
     async fn make_helpers(
         sockets: [TcpListener; 3],
         server_config: [ServerConfig; 3],
@@ -321,6 +641,21 @@ mod tests {
         test_multiply(&clients).await;
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_queries() {
+        let mut conf = TestConfigBuilder::with_open_ports().build();
+        let clients = MpcHelperClient::from_conf(&conf.network, ClientIdentity::None);
+        let _helpers = make_helpers(
+            conf.sockets.take().unwrap(),
+            conf.servers,
+            &conf.network,
+            conf.disable_https,
+        )
+        .await;
+
+        futures::join!(test_multiply(&clients), test_multiply(&clients));
+    }
+
     async fn test_multiply(clients: &[MpcHelperClient; 3]) {
         const SZ: usize = <AdditiveShare<Fp31> as Serializable>::Size::USIZE;
 
@@ -346,6 +681,7 @@ mod tests {
         for (i, input_stream) in helper_shares.into_iter().enumerate() {
             let data = QueryInput {
                 query_id,
+                part: InputPart::All,
                 input_stream,
             };
             handle_resps.push(clients[i].query_input(data));
@@ -371,6 +707,15 @@ mod tests {
         test_three_helpers(conf).await;
     }
 
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn three_helpers_http_multiplexed() {
+        let mut conf = TestConfigBuilder::with_open_ports()
+            .with_disable_https_option(true)
+            .build();
+        conf.network.client = conf.network.client.with_multiplexed_streams();
+        test_three_helpers(conf).await;
+    }
+
     #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
     async fn three_helpers_https() {
         let conf = TestConfigBuilder::with_open_ports().build();
@@ -0,0 +1,150 @@
+//! Versioning of the framing used to send records between helpers.
+//!
+//! Requests that carry records (`step` and `mux`) and responses to them have a
+//! `x-ipa-wire-version` header with the version of the framing used in the body. Helpers that
+//! predate versioning don't send it, the framing they use is [`WireVersion::FIRST`].
+//!
+//! Every helper is able to read versions from [`WireVersion::MIN_SUPPORTED`] to
+//! [`WireVersion::CURRENT`]. When a query is prepared, the leader sends its current version to
+//! each of the other helpers and they reply with the highest version both of them support; that
+//! version is used for records sent between them for the duration of the query. Helpers that did
+//! not negotiate a version with each other, which are the two helpers that are not leading the
+//! query, use the lowest version they support. Rolling out a new framing therefore takes two
+//! releases: one that supports it and another one that no longer supports the previous one, and
+//! helpers can be upgraded one by one as long as none of them is two releases behind.
+use std::{
+    fmt::{Display, Formatter},
+    str::FromStr,
+};
+
+use hyper::{
+    header::HeaderName,
+    http::{HeaderMap, HeaderValue},
+};
+
+use crate::net::Error;
+
+pub static WIRE_VERSION_HEADER: HeaderName = HeaderName::from_static("x-ipa-wire-version");
+
+/// Version of the framing of record streams exchanged between helpers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct WireVersion(u16);
+
+impl WireVersion {
+    /// Framing used before versions were introduced.
+    pub const FIRST: Self = Self(1);
+    /// Record streams sent over step requests may be compressed, see `compression` module.
+    pub const COMPRESSED_RECORDS: Self = Self(2);
+    /// Version this helper sends when it is free to choose.
+    pub const CURRENT: Self = Self::COMPRESSED_RECORDS;
+    /// Lowest version this helper is still able to read and write.
+    pub const MIN_SUPPORTED: Self = Self(1);
+
+    /// Reads the version from request or response headers. Peers that don't set the header use
+    /// [`Self::FIRST`].
+    ///
+    /// ## Errors
+    /// If the header value is not a valid version.
+    pub fn from_headers(headers: &HeaderMap) -> Result<Self, Error> {
+        headers
+            .get(&WIRE_VERSION_HEADER)
+            .map_or(Ok(Self::FIRST), |v| {
+                v.to_str().map_err(Error::from).and_then(str::parse)
+            })
+    }
+
+    /// Sets the header with this version.
+    pub fn insert_into(self, headers: &mut HeaderMap) {
+        headers.insert(&WIRE_VERSION_HEADER, HeaderValue::from(self.0));
+    }
+
+    /// Returns this version if this helper supports it.
+    ///
+    /// ## Errors
+    /// If it doesn't.
+    pub fn supported(self) -> Result<Self, Error> {
+        if (Self::MIN_SUPPORTED..=Self::CURRENT).contains(&self) {
+            Ok(self)
+        } else {
+            Err(Error::UnsupportedWireVersion(self))
+        }
+    }
+
+    /// Picks the highest version supported by both this helper and a peer whose current version
+    /// is `self`.
+    ///
+    /// ## Errors
+    /// If there is no such version, i.e. the peer is too far behind.
+    pub fn negotiate(self) -> Result<Self, Error> {
+        self.min(Self::CURRENT).supported()
+    }
+}
+
+impl Display for WireVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for WireVersion {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.parse()?))
+    }
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use hyper::http::HeaderMap;
+
+    use super::{WireVersion, WIRE_VERSION_HEADER};
+    use crate::net::Error;
+
+    #[test]
+    fn missing_header_is_first_version() {
+        assert_eq!(
+            WireVersion::FIRST,
+            WireVersion::from_headers(&HeaderMap::new()).unwrap()
+        );
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let mut headers = HeaderMap::new();
+        WireVersion::CURRENT.insert_into(&mut headers);
+        assert_eq!(
+            WireVersion::CURRENT,
+            WireVersion::from_headers(&headers).unwrap()
+        );
+    }
+
+    #[test]
+    fn invalid_header() {
+        let mut headers = HeaderMap::new();
+        headers.insert(&WIRE_VERSION_HEADER, "one".parse().unwrap());
+        assert!(matches!(
+            WireVersion::from_headers(&headers),
+            Err(Error::InvalidHeader(_))
+        ));
+    }
+
+    #[test]
+    fn negotiate() {
+        assert_eq!(
+            WireVersion::CURRENT,
+            WireVersion::CURRENT.negotiate().unwrap()
+        );
+        // peers running an older release get theirs
+        assert_eq!(WireVersion::FIRST, WireVersion::FIRST.negotiate().unwrap());
+        // peers running a newer release fall back to ours
+        assert_eq!(
+            WireVersion::CURRENT,
+            WireVersion(u16::MAX).negotiate().unwrap()
+        );
+        assert!(matches!(
+            WireVersion(0).negotiate(),
+            Err(Error::UnsupportedWireVersion(WireVersion(0)))
+        ));
+    }
+}
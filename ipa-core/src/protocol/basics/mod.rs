@@ -5,6 +5,7 @@ pub(crate) mod mul;
 mod partial_reveal;
 mod reshare;
 mod reveal;
+mod reveal_to;
 mod share_known_value;
 pub mod sum_of_product;
 
@@ -14,6 +15,7 @@ pub use mul::{MultiplyZeroPositions, SecureMul, ZeroPositions};
 pub use partial_reveal::PartialReveal;
 pub use reshare::Reshare;
 pub use reveal::Reveal;
+pub use reveal_to::{RevealTo, Revealed};
 pub use share_known_value::ShareKnownValue;
 pub use sum_of_product::SumOfProducts;
 
@@ -37,6 +39,7 @@ pub trait BasicProtocols<C: Context, V: SharedValue>:
     + Reshare<C, RecordId>
     + Reveal<C, RecordId, Output = V>
     + PartialReveal<C, RecordId, Output = V>
+    + RevealTo<C, RecordId, Output = V>
     + SecureMul<C>
     + ShareKnownValue<C, V>
     + SumOfProducts<C>
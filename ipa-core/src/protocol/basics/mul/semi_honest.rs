@@ -1,3 +1,5 @@
+#[cfg(feature = "descriptive-gate")]
+use crate::protocol::basics::ZeroPositions;
 use crate::{
     error::Error,
     ff::Field,
@@ -41,14 +43,26 @@ where
     let [need_to_recv, need_to_send, need_random_right] = zeros.work_for(role);
     zeros.0.check(role, "a", a);
     zeros.1.check(role, "b", b);
+    // Proofs need every helper to do the full work, so that all of them see the same
+    // multiplications.
+    #[cfg(feature = "descriptive-gate")]
+    let batch = ctx.dzkp_batch();
+    #[cfg(feature = "descriptive-gate")]
+    if batch.is_some() && zeros != ZeroPositions::NONE {
+        return Err(Error::Unsupported(
+            "sparse multiplications can't be verified with distributed zero-knowledge proofs"
+                .to_string(),
+        ));
+    }
 
     // Shared randomness used to mask the values that are sent.
     let (s0, s1) = ctx.prss().generate_fields(record_id);
 
     let mut rhs = a.right() * b.right();
+    let mut right_d = F::ZERO;
     if need_to_send {
         // Compute the value (d_i) we want to send to the right helper (i+1).
-        let right_d = a.left() * b.right() + a.right() * b.left() - s0;
+        right_d = a.left() * b.right() + a.right() * b.left() - s0;
 
         ctx.send_channel(role.peer(Direction::Right))
             .send(record_id, right_d)
@@ -66,8 +80,9 @@ where
 
     // Sleep until helper on the left sends us their (d_i-1) value.
     let mut lhs = a.left() * b.left();
+    let mut left_d = F::ZERO;
     if need_to_recv {
-        let left_d = ctx
+        left_d = ctx
             .recv_channel(role.peer(Direction::Left))
             .receive(record_id)
             .await?;
@@ -78,6 +93,11 @@ where
         lhs += s0;
     }
 
+    #[cfg(feature = "descriptive-gate")]
+    if let Some(batch) = batch {
+        batch.push(ctx.gate(), record_id, a, b, (s0, s1), (right_d, left_d))?;
+    }
+
     Ok(Replicated::new(lhs, rhs))
 }
 
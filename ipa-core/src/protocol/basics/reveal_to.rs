@@ -0,0 +1,297 @@
+use async_trait::async_trait;
+use futures::future::try_join;
+
+use crate::{
+    error::Error,
+    helpers::{Direction, Role},
+    protocol::{
+        context::{Context, UpgradedMaliciousContext},
+        RecordBinding, RecordId,
+    },
+    secret_sharing::{
+        replicated::{
+            malicious::{AdditiveShare as MaliciousReplicated, ExtendableField},
+            semi_honest::AdditiveShare as Replicated,
+        },
+        WeakSharedValue,
+    },
+};
+
+/// The outcome of revealing a secret to a single helper.
+///
+/// The recipient gets [`Revealed::Known`], the other two helpers get [`Revealed::Hidden`] and
+/// learn nothing about the value. This type deliberately does not implement [`Serializable`], so
+/// the revealed value can't be sent to other helpers by accident, which would make it a full
+/// reveal. Protocols that need it revealed to everyone must use [`Reveal`] instead.
+///
+/// [`Serializable`]: crate::ff::Serializable
+/// [`Reveal`]: crate::protocol::basics::Reveal
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[must_use]
+pub enum Revealed<V> {
+    /// This helper is the recipient and knows the value.
+    Known(V),
+    /// The value was revealed to `recipient` only.
+    Hidden { recipient: Role },
+}
+
+impl<V> Revealed<V> {
+    /// Returns the revealed value if this helper is the recipient.
+    #[must_use]
+    pub fn known(self) -> Option<V> {
+        match self {
+            Self::Known(v) => Some(v),
+            Self::Hidden { .. } => None,
+        }
+    }
+
+    #[must_use]
+    pub fn is_known(&self) -> bool {
+        matches!(self, Self::Known(_))
+    }
+}
+
+/// Trait for reveal protocol to open a shared secret to one designated helper inside the MPC ring.
+#[async_trait]
+pub trait RevealTo<C: Context, B: RecordBinding>: Sized {
+    type Output;
+    /// reveal the secret to `recipient` only. All helpers must agree on the recipient, otherwise
+    /// the protocol deadlocks. Note that after method is called, it must be assumed that the
+    /// secret value has been revealed to `recipient`. Even in case when method never terminates,
+    /// returns an error, etc.
+    async fn reveal_to<'fut>(
+        &self,
+        ctx: C,
+        record_binding: B,
+        recipient: Role,
+    ) -> Result<Revealed<Self::Output>, Error>
+    where
+        C: 'fut;
+}
+
+/// Recipient is missing the share held by both of its peers. In semi-honest setting, it is enough
+/// to get it from the peer on the left, so this requires a single message per record.
+#[async_trait]
+impl<C: Context, V: WeakSharedValue> RevealTo<C, RecordId> for Replicated<V> {
+    type Output = V;
+
+    async fn reveal_to<'fut>(
+        &self,
+        ctx: C,
+        record_id: RecordId,
+        recipient: Role,
+    ) -> Result<Revealed<V>, Error>
+    where
+        C: 'fut,
+    {
+        let (left, right) = self.as_tuple();
+
+        if ctx.role() == recipient {
+            let share = ctx
+                .recv_channel(recipient.peer(Direction::Left))
+                .receive(record_id)
+                .await?;
+
+            Ok(Revealed::Known(left + right + share))
+        } else {
+            if ctx.role().peer(Direction::Right) == recipient {
+                ctx.send_channel(recipient).send(record_id, left).await?;
+            }
+
+            Ok(Revealed::Hidden { recipient })
+        }
+    }
+}
+
+/// In malicious setting, recipient receives the missing share from both peers and validates that
+/// they match.
+#[async_trait]
+impl<'a, F: ExtendableField> RevealTo<UpgradedMaliciousContext<'a, F>, RecordId>
+    for MaliciousReplicated<F>
+{
+    type Output = F;
+
+    async fn reveal_to<'fut>(
+        &self,
+        ctx: UpgradedMaliciousContext<'a, F>,
+        record_id: RecordId,
+        recipient: Role,
+    ) -> Result<Revealed<F>, Error>
+    where
+        UpgradedMaliciousContext<'a, F>: 'fut,
+    {
+        use crate::secret_sharing::replicated::malicious::ThisCodeIsAuthorizedToDowngradeFromMalicious;
+
+        let (left, right) = self.x().access_without_downgrade().as_tuple();
+
+        if ctx.role() == recipient {
+            let left_receiver = ctx.recv_channel::<F>(recipient.peer(Direction::Left));
+            let right_receiver = ctx.recv_channel::<F>(recipient.peer(Direction::Right));
+            let (share_from_left, share_from_right) = try_join(
+                left_receiver.receive(record_id),
+                right_receiver.receive(record_id),
+            )
+            .await?;
+
+            if share_from_left == share_from_right {
+                Ok(Revealed::Known(left + right + share_from_left))
+            } else {
+                Err(Error::MaliciousRevealFailed)
+            }
+        } else {
+            // recipient on the right is missing our left share and vice versa
+            let share = if ctx.role().peer(Direction::Right) == recipient {
+                left
+            } else {
+                right
+            };
+            ctx.send_channel(recipient).send(record_id, share).await?;
+
+            Ok(Revealed::Hidden { recipient })
+        }
+    }
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use std::iter::zip;
+
+    use futures::future::try_join3;
+
+    use super::Revealed;
+    use crate::{
+        error::Error,
+        ff::{Field, Fp31},
+        helpers::{Direction, Role},
+        protocol::{
+            basics::RevealTo,
+            context::{
+                Context, UpgradableContext, UpgradedContext, UpgradedMaliciousContext, Validator,
+            },
+            RecordId,
+        },
+        rand::{thread_rng, Rng},
+        secret_sharing::{
+            replicated::malicious::{
+                AdditiveShare as MaliciousReplicated, ExtendableField,
+                ThisCodeIsAuthorizedToDowngradeFromMalicious,
+            },
+            IntoShares,
+        },
+        test_fixture::{join3v, Runner, TestWorld},
+    };
+
+    #[tokio::test]
+    pub async fn semi_honest() {
+        let mut rng = thread_rng();
+        let world = TestWorld::default();
+
+        for &recipient in Role::all() {
+            let input = rng.gen::<Fp31>();
+            let results = world
+                .semi_honest(input, |ctx, share| async move {
+                    share
+                        .reveal_to(ctx.set_total_records(1), RecordId::from(0), recipient)
+                        .await
+                        .unwrap()
+                })
+                .await;
+
+            for (&role, result) in zip(Role::all(), results) {
+                if role == recipient {
+                    assert_eq!(Revealed::Known(input), result);
+                } else {
+                    assert_eq!(Revealed::Hidden { recipient }, result);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    pub async fn malicious() {
+        let mut rng = thread_rng();
+        let world = TestWorld::default();
+
+        for &recipient in Role::all() {
+            let sh_ctx = world.malicious_contexts();
+            let v = sh_ctx.map(UpgradableContext::validator);
+            let m_ctx: [_; 3] = v
+                .iter()
+                .map(|v| v.context().set_total_records(1))
+                .collect::<Vec<_>>()
+                .try_into()
+                .unwrap();
+
+            let record_id = RecordId::from(0);
+            let input: Fp31 = rng.gen();
+
+            let m_shares = join3v(
+                zip(m_ctx.iter(), input.share_with(&mut rng))
+                    .map(|(m_ctx, share)| async { m_ctx.upgrade(share).await }),
+            )
+            .await;
+
+            let results = join3v(
+                zip(m_ctx.into_iter(), m_shares).map(|(m_ctx, m_share)| async move {
+                    m_share.reveal_to(m_ctx, record_id, recipient).await
+                }),
+            )
+            .await;
+
+            assert_eq!(
+                vec![input],
+                results
+                    .into_iter()
+                    .filter_map(Revealed::known)
+                    .collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[tokio::test]
+    pub async fn malicious_validation_fail() {
+        let mut rng = thread_rng();
+        let world = TestWorld::default();
+        let sh_ctx = world.malicious_contexts();
+        let v = sh_ctx.map(UpgradableContext::validator);
+        let m_ctx: [_; 3] = v
+            .iter()
+            .map(|v| v.context().set_total_records(1))
+            .collect::<Vec<_>>()
+            .try_into()
+            .unwrap();
+
+        let record_id = RecordId::from(0);
+        let input: Fp31 = rng.gen();
+
+        let m_shares = join3v(
+            zip(m_ctx.iter(), input.share_with(&mut rng))
+                .map(|(m_ctx, share)| async { m_ctx.upgrade(share).await }),
+        )
+        .await;
+        let result = try_join3(
+            m_shares[0].reveal_to(m_ctx[0].clone(), record_id, Role::H1),
+            m_shares[1].reveal_to(m_ctx[1].clone(), record_id, Role::H1),
+            reveal_with_additive_attack(m_ctx[2].clone(), record_id, &m_shares[2], Fp31::ONE),
+        )
+        .await;
+
+        assert!(matches!(result, Err(Error::MaliciousRevealFailed)));
+    }
+
+    /// Sends a corrupted share from H3 to H1.
+    async fn reveal_with_additive_attack<F: ExtendableField>(
+        ctx: UpgradedMaliciousContext<'_, F>,
+        record_id: RecordId,
+        input: &MaliciousReplicated<F>,
+        additive_error: F,
+    ) -> Result<Revealed<F>, Error> {
+        let (left, _) = input.x().access_without_downgrade().as_tuple();
+        let recipient = ctx.role().peer(Direction::Right);
+        ctx.send_channel(recipient)
+            .send(record_id, left + additive_error)
+            .await?;
+
+        Ok(Revealed::Hidden { recipient })
+    }
+}
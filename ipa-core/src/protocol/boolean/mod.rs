@@ -17,6 +17,7 @@ pub mod bitwise_less_than_prime;
 pub mod comparison;
 pub mod generate_random_bits;
 pub mod or;
+pub mod prefix;
 pub mod random_bits_generator;
 pub mod solved_bits;
 mod xor;
@@ -0,0 +1,280 @@
+//! Prefix-OR and prefix-AND of secret-shared bits.
+//!
+//! Output bit `i` is the OR (AND) of input bits `0..=i`. Both are computed with the Kogge-Stone
+//! scan: in round `r`, every bit is combined with the one `2^r` positions below it, so `n` bits
+//! take `ceil(log2(n))` rounds of multiplications instead of `n - 1` sequential ones.
+//!
+//! Bits are either given one share per bit, in any field, or packed into a single share of a
+//! boolean array, in which case every round is a single multiplication of arrays. Rounds narrow
+//! the context to [`BitOpStep`] with the round number.
+use crate::{
+    error::Error,
+    ff::{CustomArray, Field},
+    protocol::{basics::SecureMul, boolean::or::or, context::Context, step::BitOpStep, RecordId},
+    secret_sharing::{
+        replicated::semi_honest::AdditiveShare, BitDecomposed, Linear as LinearSecretSharing,
+        WeakSharedValue,
+    },
+};
+
+/// Computes prefix-OR of `bits`, which must be shares of 0 or 1.
+///
+/// ## Errors
+/// Propagates errors from multiplications.
+///
+/// ## Panics
+/// If there are more than 64 bits.
+pub async fn prefix_or<F, C, S>(
+    ctx: C,
+    record_id: RecordId,
+    bits: &[S],
+) -> Result<BitDecomposed<S>, Error>
+where
+    F: Field,
+    C: Context,
+    S: LinearSecretSharing<F> + SecureMul<C>,
+{
+    let mut acc = bits.to_vec();
+    for (round, distance) in distances(acc.len()) {
+        let ctx = ctx.narrow(&BitOpStep::from(round));
+        let combined = ctx
+            .parallel_join((distance..acc.len()).map(|i| {
+                let ctx = ctx.narrow(&BitOpStep::from(i));
+                let (lo, hi) = (&acc[i - distance], &acc[i]);
+                async move { or(ctx, record_id, hi, lo).await }
+            }))
+            .await?;
+        acc.splice(distance.., combined);
+    }
+
+    Ok(BitDecomposed::new(acc))
+}
+
+/// Computes prefix-AND of `bits`, which must be shares of 0 or 1.
+///
+/// ## Errors
+/// Propagates errors from multiplications.
+///
+/// ## Panics
+/// If there are more than 64 bits.
+pub async fn prefix_and<F, C, S>(
+    ctx: C,
+    record_id: RecordId,
+    bits: &[S],
+) -> Result<BitDecomposed<S>, Error>
+where
+    F: Field,
+    C: Context,
+    S: LinearSecretSharing<F> + SecureMul<C>,
+{
+    let mut acc = bits.to_vec();
+    for (round, distance) in distances(acc.len()) {
+        let ctx = ctx.narrow(&BitOpStep::from(round));
+        let combined = ctx
+            .parallel_join((distance..acc.len()).map(|i| {
+                acc[i].multiply(
+                    &acc[i - distance],
+                    ctx.narrow(&BitOpStep::from(i)),
+                    record_id,
+                )
+            }))
+            .await?;
+        acc.splice(distance.., combined);
+    }
+
+    Ok(BitDecomposed::new(acc))
+}
+
+/// Computes prefix-OR of elements of `x`, in the order of their indices.
+///
+/// ## Errors
+/// Propagates errors from multiplications.
+pub async fn prefix_or_array<C, S>(
+    ctx: C,
+    record_id: RecordId,
+    x: &AdditiveShare<S>,
+) -> Result<AdditiveShare<S>, Error>
+where
+    C: Context,
+    S: CustomArray + Field,
+    S::Element: Field,
+{
+    let mut acc = x.clone();
+    for (round, distance) in distances(len::<S>()) {
+        let shifted = shift_up(&acc, distance);
+        let both = acc
+            .multiply(&shifted, ctx.narrow(&BitOpStep::from(round)), record_id)
+            .await?;
+        // a OR b = a + b - ab
+        acc = &(&acc + &shifted) - &both;
+    }
+
+    Ok(acc)
+}
+
+/// Computes prefix-AND of elements of `x`, in the order of their indices.
+///
+/// ## Errors
+/// Propagates errors from multiplications.
+pub async fn prefix_and_array<C, S>(
+    ctx: C,
+    record_id: RecordId,
+    x: &AdditiveShare<S>,
+) -> Result<AdditiveShare<S>, Error>
+where
+    C: Context,
+    S: CustomArray + Field,
+    S::Element: Field,
+{
+    let mut acc = x.clone();
+    for (round, distance) in distances(len::<S>()) {
+        let mut both = acc
+            .multiply(
+                &shift_up(&acc, distance),
+                ctx.narrow(&BitOpStep::from(round)),
+                record_id,
+            )
+            .await?;
+        // elements without a counterpart `distance` positions below are already final
+        for i in 0..distance {
+            both.set(i, acc.get(i).unwrap());
+        }
+        acc = both;
+    }
+
+    Ok(acc)
+}
+
+/// Returns the number of rounds the scan of `len` elements takes.
+#[must_use]
+pub fn rounds(len: usize) -> usize {
+    distances(len).count()
+}
+
+/// Yields every round of the scan along with the distance between elements combined in it.
+fn distances(len: usize) -> impl Iterator<Item = (usize, usize)> {
+    (0..)
+        .map(|round| (round, 1 << round))
+        .take_while(move |&(_, distance)| distance < len)
+}
+
+pub(crate) fn len<S: WeakSharedValue>() -> usize {
+    usize::try_from(S::BITS).unwrap()
+}
+
+/// Moves every element of `x` `distance` positions up, filling the lowest positions with zeros.
+pub(crate) fn shift_up<S>(x: &AdditiveShare<S>, distance: usize) -> AdditiveShare<S>
+where
+    S: CustomArray + Field,
+    S::Element: Field,
+{
+    let mut shifted = AdditiveShare::<S>::ZERO;
+    for i in distance..len::<S>() {
+        shifted.set(i, x.get(i - distance).unwrap());
+    }
+    shifted
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use rand::Rng;
+
+    use super::{prefix_and, prefix_and_array, prefix_or, prefix_or_array, rounds};
+    use crate::{
+        ff::{boolean::Boolean, boolean_array::BA8, ArrayAccess, Field, Fp31},
+        protocol::{context::Context, RecordId},
+        rand::thread_rng,
+        test_fixture::{Reconstruct, Runner, TestWorld},
+    };
+
+    fn expected(bits: &[bool], or: bool) -> Vec<bool> {
+        bits.iter()
+            .scan(!or, |acc, &bit| {
+                *acc = if or { *acc || bit } else { *acc && bit };
+                Some(*acc)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn decomposed() {
+        let world = TestWorld::default();
+        let mut rng = thread_rng();
+
+        for len in [1, 2, 5, 8, 13] {
+            let bits = (0..len).map(|_| rng.gen::<bool>()).collect::<Vec<_>>();
+            let input = bits
+                .iter()
+                .map(|&b| Fp31::truncate_from(u128::from(b)))
+                .collect::<Vec<_>>();
+            let (or, and) = world
+                .semi_honest(input.into_iter(), |ctx, bits| async move {
+                    let ctx = ctx.set_total_records(1);
+                    (
+                        prefix_or(ctx.narrow("or"), RecordId::FIRST, &bits)
+                            .await
+                            .unwrap()
+                            .to_vec(),
+                        prefix_and(ctx.narrow("and"), RecordId::FIRST, &bits)
+                            .await
+                            .unwrap()
+                            .to_vec(),
+                    )
+                })
+                .await
+                .reconstruct();
+
+            let to_bool = |v: Vec<Fp31>| v.into_iter().map(|b| b == Fp31::ONE).collect::<Vec<_>>();
+            assert_eq!(expected(&bits, true), to_bool(or), "{bits:?}");
+            assert_eq!(expected(&bits, false), to_bool(and), "{bits:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn array() {
+        let world = TestWorld::default();
+        let mut rng = thread_rng();
+
+        for _ in 0..10 {
+            // mostly ones, otherwise prefix-AND is almost always zero
+            let bits = (0..8).map(|_| rng.gen_bool(0.8)).collect::<Vec<_>>();
+            let input = bits.iter().enumerate().fold(BA8::ZERO, |mut acc, (i, &b)| {
+                acc.set(i, Boolean::from(b));
+                acc
+            });
+            let flipped = input + BA8::truncate_from(u128::MAX);
+
+            let (or, and) = world
+                .semi_honest((flipped, input), |ctx, (flipped, input)| async move {
+                    let ctx = ctx.set_total_records(1);
+                    (
+                        prefix_or_array(ctx.narrow("or"), RecordId::FIRST, &flipped)
+                            .await
+                            .unwrap(),
+                        prefix_and_array(ctx.narrow("and"), RecordId::FIRST, &input)
+                            .await
+                            .unwrap(),
+                    )
+                })
+                .await
+                .reconstruct();
+
+            let flipped = bits.iter().map(|b| !b).collect::<Vec<_>>();
+            let to_bool = |v: BA8| {
+                (0..8)
+                    .map(|i| v.get(i).unwrap() == Boolean::ONE)
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(expected(&flipped, true), to_bool(or), "{bits:?}");
+            assert_eq!(expected(&bits, false), to_bool(and), "{bits:?}");
+        }
+    }
+
+    #[test]
+    fn log_depth() {
+        assert_eq!(0, rounds(1));
+        assert_eq!(1, rounds(2));
+        assert_eq!(3, rounds(8));
+        assert_eq!(6, rounds(64));
+    }
+}
@@ -0,0 +1,66 @@
+//! Accounting of the randomness and communication used by a step, against what it declared.
+//!
+//! Protocols declare the number of records they process with [`Context::set_total_records`] and
+//! every channel they open buffers space for that many records. [`StepBudget`] tells how much of
+//! it was actually used, so tests and adaptive protocols can check that a step stays within its
+//! declared totals and notice records that were declared but never sent.
+//!
+//! [`Context::set_total_records`]: super::Context::set_total_records
+use crate::helpers::{StepProgress, TotalRecords};
+
+/// Usage of a single step, as returned by [`Context::budget`]. Counters are shared by all contexts
+/// narrowed to the same step on this helper.
+///
+/// [`Context::budget`]: super::Context::budget
+#[derive(Clone, Copy, Debug)]
+pub struct StepBudget {
+    /// Number of records the context was set to process.
+    pub total_records: TotalRecords,
+    /// Records declared on the sending channels this step has opened so far, summed over channels.
+    pub declared_records: usize,
+    /// Records sent by this step so far, summed over channels.
+    pub sent_records: usize,
+    /// Values generated by PRSS on this step so far, both indexed and sequential.
+    pub prss_generated: u64,
+}
+
+impl StepBudget {
+    pub(super) fn new(total_records: TotalRecords, progress: &StepProgress) -> Self {
+        Self {
+            total_records,
+            declared_records: progress.total_records,
+            sent_records: progress.completed_records,
+            prss_generated: progress.prss_generated,
+        }
+    }
+
+    /// Returns the number of records declared on open channels that have not been sent yet. Once
+    /// the step is done, anything other than zero means that it declared more records than it
+    /// needed.
+    #[must_use]
+    pub fn unsent_records(&self) -> usize {
+        self.declared_records.saturating_sub(self.sent_records)
+    }
+
+    /// Returns the number of PRSS values the step may still generate if every record takes
+    /// `per_record` of them, `None` if the number of records is not known. Steps that generated
+    /// more than that are over budget and get zero.
+    #[must_use]
+    pub fn remaining_prss(&self, per_record: u64) -> Option<u64> {
+        let records = self.total_records.count()? as u64;
+        Some(
+            records
+                .saturating_mul(per_record)
+                .saturating_sub(self.prss_generated),
+        )
+    }
+
+    /// Tells whether the step generated at most `per_record` PRSS values for every record it was
+    /// set to process. Always true if the number of records is not known.
+    #[must_use]
+    pub fn is_within(&self, per_record: u64) -> bool {
+        self.total_records.count().map_or(true, |records| {
+            self.prss_generated <= (records as u64).saturating_mul(per_record)
+        })
+    }
+}
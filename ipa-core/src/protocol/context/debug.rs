@@ -0,0 +1,102 @@
+//! Revealing secret-shared intermediates while debugging protocols.
+//!
+//! Finding out where a protocol goes wrong usually means reconstructing its intermediate values,
+//! which the in-memory test world only does for outputs. [`DebugReveal::debug_reveal`] opens a
+//! share to all helpers in the middle of a protocol and logs the value, so a protocol author can
+//! bisect a logic error by sprinkling calls through the code under test rather than writing a
+//! test for every stage of it.
+//!
+//! Revealing intermediates breaks every privacy guarantee of the protocol, so this is only
+//! compiled with the `debug-reveal` feature in debug builds. Calls to it must be gated the same
+//! way, and they are not meant to be committed:
+//!
+//! ```ignore
+//! #[cfg(all(feature = "debug-reveal", debug_assertions))]
+//! ctx.debug_reveal("capped", record_id, &capped).await?;
+//! ```
+use std::fmt::Debug;
+
+use async_trait::async_trait;
+
+use crate::{
+    error::Error,
+    protocol::{basics::Reveal, context::Context, RecordId},
+};
+
+#[async_trait]
+pub trait DebugReveal: Context {
+    /// Reveals `share` to all helpers on a step called `name` below this context, logs its value
+    /// along with the step and the role of this helper, and returns it.
+    ///
+    /// Every helper must call it for the same records, like any other protocol. `name` must be
+    /// unique within this context and the context must know its total number of records.
+    ///
+    /// ## Errors
+    /// If the reveal fails.
+    async fn debug_reveal<S>(
+        &self,
+        name: &str,
+        record_id: RecordId,
+        share: &S,
+    ) -> Result<S::Output, Error>
+    where
+        S: Reveal<Self, RecordId> + Sync,
+        S::Output: Debug + Send;
+}
+
+#[async_trait]
+impl<C: Context> DebugReveal for C {
+    async fn debug_reveal<S>(
+        &self,
+        name: &str,
+        record_id: RecordId,
+        share: &S,
+    ) -> Result<S::Output, Error>
+    where
+        S: Reveal<Self, RecordId> + Sync,
+        S::Output: Debug + Send,
+    {
+        let ctx = self.narrow(name);
+        let gate = ctx.gate().clone();
+        let value = share.reveal(ctx, record_id).await?;
+        tracing::debug!(role = ?self.role(), %gate, ?record_id, ?value, "debug reveal");
+
+        Ok(value)
+    }
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use super::DebugReveal;
+    use crate::{
+        ff::{Field, Fp31},
+        protocol::{basics::SecureMul, context::Context, RecordId},
+        test_fixture::{Reconstruct, Runner, TestWorld},
+    };
+
+    #[tokio::test]
+    async fn reveals_intermediate() {
+        let world = TestWorld::default();
+        let (a, b) = (Fp31::truncate_from(3_u128), Fp31::truncate_from(7_u128));
+
+        let results = world
+            .semi_honest((a, b), |ctx, (a, b)| async move {
+                let ctx = ctx.set_total_records(1);
+                let product = a
+                    .multiply(&b, ctx.narrow("mul"), RecordId::FIRST)
+                    .await
+                    .unwrap();
+                let revealed = ctx
+                    .debug_reveal("product", RecordId::FIRST, &product)
+                    .await
+                    .unwrap();
+                (revealed, product)
+            })
+            .await;
+
+        for (revealed, _) in &results {
+            assert_eq!(a * b, *revealed);
+        }
+        assert_eq!(a * b, results.map(|(_, product)| product).reconstruct());
+    }
+}
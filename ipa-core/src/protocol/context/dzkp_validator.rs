@@ -0,0 +1,701 @@
+//! Validation of binary multiplications with distributed zero-knowledge proofs.
+//!
+//! [`Validator`]s check multiplications with MACs over an extension field, which makes every
+//! multiplication of a bit as expensive as two multiplications in that field. Circuits over bits,
+//! like the attribution one, are checked much more cheaply by recording the inputs, randomness
+//! and messages of every multiplication as it happens, and having every helper prove to the other
+//! two that its messages were correct, once all of them are made. Proofs are sublinear in the
+//! number of multiplications, see [`crate::protocol::dzkp`].
+//!
+//! Only multiplications are checked. Shares that protocols start with are used as they are, and
+//! values revealed before validation are not protected either.
+//!
+//! [`Validator`]: super::Validator
+use std::{
+    collections::BTreeMap,
+    fmt::{Debug, Formatter},
+    num::NonZeroUsize,
+};
+
+use async_trait::async_trait;
+use futures::future::{try_join, try_join3};
+use generic_array::GenericArray;
+use ipa_macros::Step;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+#[cfg(debug_assertions)]
+use super::StepBudget;
+use crate::{
+    error::Error,
+    ff::{boolean::Boolean, boolean_array::BA256, Field, Fp32BitPrime, Serializable},
+    helpers::{
+        Direction, MemoryBudget, Message, PackedReceivingEnd, PackedSendingEnd, ReceivingEnd, Role,
+        SendingEnd, TotalRecords,
+    },
+    protocol::{
+        context::{
+            Base, Context, InstrumentedIndexedSharedRandomness,
+            InstrumentedSequentialSharedRandomness, MaliciousContext, SemiHonestContext,
+            UpgradableContext, UpgradedSemiHonestContext,
+        },
+        dzkp::{
+            claimed_sum, left_verifier_vector, mask, prover_vectors, right_verifier_vector, rounds,
+            BitMultiplication, Compression, PROOF_LENGTH,
+        },
+        prss::SharedRandomness,
+        step::{Gate, Step as StepTrait, StepNarrow},
+        RecordId,
+    },
+    secret_sharing::replicated::{
+        semi_honest::AdditiveShare as Replicated, ReplicatedSecretSharing,
+    },
+    seq_join::SeqJoin,
+    sync::{Arc, Mutex},
+};
+
+/// Field that proofs are computed in. Every round of a proof is sound up to `2^-28`.
+type ProofField = Fp32BitPrime;
+
+/// Runs binary protocols at the security level of the context it was created from and checks
+/// their multiplications, see the [module documentation](self).
+#[async_trait]
+pub trait DZKPValidator<B: UpgradableContext> {
+    fn context(&self) -> B::DZKPUpgradedContext;
+
+    /// Checks every multiplication made in [`Self::context`] and returns `values` if all of them
+    /// are correct.
+    ///
+    /// ## Errors
+    /// If a helper sent messages that are not the ones the protocol asks for, or communication
+    /// with other helpers fails.
+    async fn validate<T: Send>(self, values: T) -> Result<T, Error>;
+}
+
+/// Multiplications made in a context that a [`DZKPValidator`] checks.
+#[derive(Default)]
+pub struct Batch {
+    /// Sorted by gate and record, so that all helpers prove and verify them in the same order.
+    multiplications: Mutex<BTreeMap<(Gate, RecordId), Vec<BitMultiplication>>>,
+}
+
+impl Batch {
+    /// Records the multiplication of `a` and `b` that used randomness `prss`, shared with the
+    /// helpers to the left and right, and exchanged `messages`, the one sent to the right and the
+    /// one received from the left.
+    ///
+    /// ## Errors
+    /// If values of `F` are not multiplied bit by bit.
+    ///
+    /// ## Panics
+    /// If the mutex is poisoned.
+    pub(crate) fn push<F: Field>(
+        &self,
+        gate: &Gate,
+        record_id: RecordId,
+        a: &Replicated<F>,
+        b: &Replicated<F>,
+        prss: (F, F),
+        messages: (F, F),
+    ) -> Result<(), Error> {
+        let bits = [
+            a.left(),
+            a.right(),
+            b.left(),
+            b.right(),
+            prss.0,
+            prss.1,
+            messages.0,
+            messages.1,
+        ]
+        .iter()
+        .map(Field::and_bits)
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| {
+            Error::Unsupported(format!(
+                "distributed zero-knowledge proofs only verify multiplications of bits, not of {}",
+                std::any::type_name::<F>()
+            ))
+        })?;
+
+        let multiplications = (0..bits[0].len())
+            .map(|i| BitMultiplication {
+                x_left: bits[0][i],
+                x_right: bits[1][i],
+                y_left: bits[2][i],
+                y_right: bits[3][i],
+                prss_left: bits[4][i],
+                prss_right: bits[5][i],
+                z_right: bits[6][i],
+                z_left: bits[7][i],
+            })
+            .collect();
+        self.multiplications
+            .lock()
+            .unwrap()
+            .insert((gate.clone(), record_id), multiplications);
+
+        Ok(())
+    }
+
+    /// Takes all multiplications recorded so far, in order.
+    fn take(&self) -> Vec<BitMultiplication> {
+        std::mem::take(&mut *self.multiplications.lock().unwrap())
+            .into_values()
+            .flatten()
+            .collect()
+    }
+
+    #[cfg(all(test, unit_test))]
+    fn update<U: FnOnce(&mut BitMultiplication)>(&self, update: U) {
+        let mut multiplications = self.multiplications.lock().unwrap();
+        let first = multiplications.values_mut().next().unwrap();
+        update(&mut first[0]);
+    }
+}
+
+/// Context for binary protocols that records their multiplications into a [`Batch`].
+#[derive(Clone)]
+pub struct DZKPUpgraded<'a> {
+    inner: Base<'a>,
+    batch: Arc<Batch>,
+}
+
+impl<'a> DZKPUpgraded<'a> {
+    fn new(inner: Base<'a>, batch: Arc<Batch>) -> Self {
+        Self { inner, batch }
+    }
+}
+
+impl<'a> Context for DZKPUpgraded<'a> {
+    fn role(&self) -> Role {
+        self.inner.role()
+    }
+
+    fn gate(&self) -> &Gate {
+        self.inner.gate()
+    }
+
+    fn narrow<S: StepTrait + ?Sized>(&self, step: &S) -> Self
+    where
+        Gate: StepNarrow<S>,
+    {
+        Self::new(self.inner.narrow(step), Arc::clone(&self.batch))
+    }
+
+    fn set_total_records<T: Into<TotalRecords>>(&self, total_records: T) -> Self {
+        Self::new(
+            self.inner.set_total_records(total_records),
+            Arc::clone(&self.batch),
+        )
+    }
+
+    fn total_records(&self) -> TotalRecords {
+        self.inner.total_records()
+    }
+
+    fn prss(&self) -> InstrumentedIndexedSharedRandomness<'_> {
+        self.inner.prss()
+    }
+
+    fn prss_rng(
+        &self,
+    ) -> (
+        InstrumentedSequentialSharedRandomness,
+        InstrumentedSequentialSharedRandomness,
+    ) {
+        self.inner.prss_rng()
+    }
+
+    fn send_channel<M: Message>(&self, role: Role) -> SendingEnd<M> {
+        self.inner.send_channel(role)
+    }
+
+    fn recv_channel<M: Message>(&self, role: Role) -> ReceivingEnd<M> {
+        self.inner.recv_channel(role)
+    }
+
+    fn packed_send_channel(&self, role: Role) -> PackedSendingEnd {
+        self.inner.packed_send_channel(role)
+    }
+
+    fn packed_recv_channel(&self, role: Role) -> PackedReceivingEnd {
+        self.inner.packed_recv_channel(role)
+    }
+
+    #[cfg(debug_assertions)]
+    fn budget(&self) -> StepBudget {
+        self.inner.budget()
+    }
+
+    fn memory(&self) -> &Arc<MemoryBudget> {
+        self.inner.memory()
+    }
+
+    fn dzkp_batch(&self) -> Option<&Batch> {
+        Some(&self.batch)
+    }
+}
+
+impl<'a> SeqJoin for DZKPUpgraded<'a> {
+    fn active_work(&self) -> NonZeroUsize {
+        self.inner.active_work()
+    }
+}
+
+impl Debug for DZKPUpgraded<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "DZKPMaliciousContext")
+    }
+}
+
+pub struct SemiHonestDZKPValidator<'a> {
+    context: UpgradedSemiHonestContext<'a, Boolean>,
+}
+
+impl<'a> SemiHonestDZKPValidator<'a> {
+    pub(super) fn new(inner: Base<'a>) -> Self {
+        Self {
+            context: UpgradedSemiHonestContext::new(inner),
+        }
+    }
+}
+
+#[async_trait]
+impl<'a> DZKPValidator<SemiHonestContext<'a>> for SemiHonestDZKPValidator<'a> {
+    fn context(&self) -> UpgradedSemiHonestContext<'a, Boolean> {
+        self.context.clone()
+    }
+
+    async fn validate<T: Send>(self, values: T) -> Result<T, Error> {
+        Ok(values)
+    }
+}
+
+impl Debug for SemiHonestDZKPValidator<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SemiHonestDZKPValidator")
+    }
+}
+
+#[derive(Step)]
+pub(crate) enum Step {
+    /// For the execution of binary protocols.
+    DzkpProtocol,
+    /// Proving and verifying multiplications of those protocols.
+    DzkpValidate,
+}
+
+#[derive(Step)]
+pub(crate) enum ValidateStep {
+    /// Verifiers agree on weights of the statements of every prover.
+    Weights,
+    #[dynamic(64)]
+    Round(usize),
+    /// Verifiers exchange what they need to check the last round.
+    Verify,
+}
+
+#[derive(Step)]
+pub(crate) enum RoundStep {
+    /// Provers mask their vectors before the last round.
+    Mask,
+    /// Provers share their polynomials with verifiers.
+    ProofShare,
+    /// Verifiers pick the point vectors are compressed at.
+    Challenge,
+}
+
+pub struct MaliciousDZKPValidator<'a> {
+    batch: Arc<Batch>,
+    protocol_ctx: DZKPUpgraded<'a>,
+    validate_ctx: Base<'a>,
+}
+
+impl<'a> MaliciousDZKPValidator<'a> {
+    #[must_use]
+    pub fn new(ctx: MaliciousContext<'a>) -> Self {
+        let base = ctx.base_context();
+        let batch = Arc::new(Batch::default());
+        Self {
+            protocol_ctx: DZKPUpgraded::new(base.narrow(&Step::DzkpProtocol), Arc::clone(&batch)),
+            validate_ctx: base.narrow(&Step::DzkpValidate),
+            batch,
+        }
+    }
+}
+
+#[async_trait]
+impl<'a> DZKPValidator<MaliciousContext<'a>> for MaliciousDZKPValidator<'a> {
+    fn context(&self) -> DZKPUpgraded<'a> {
+        self.protocol_ctx.clone()
+    }
+
+    #[tracing::instrument(name = "dzkp_validate", skip_all, fields(gate = %self.validate_ctx.gate().as_ref()))]
+    async fn validate<T: Send>(self, values: T) -> Result<T, Error> {
+        let multiplications = self.batch.take();
+        if !multiplications.is_empty() {
+            verify(self.validate_ctx, &multiplications).await?;
+        }
+        Ok(values)
+    }
+}
+
+impl Debug for MaliciousDZKPValidator<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "MaliciousDZKPValidator")
+    }
+}
+
+/// What a helper holds of one proof in every role it plays: `u` and `v` of its own proof, `u` of
+/// the proof of the helper to its right and `v` of the proof of the helper to its left, along
+/// with its shares of the inner products they claim.
+struct Vectors {
+    u: Vec<ProofField>,
+    v: Vec<ProofField>,
+    left_verifier: Verifier,
+    right_verifier: Verifier,
+}
+
+/// Vector a verifier knows of a proof, its share of the inner product the prover claims, and
+/// its shares of how far off claims of earlier rounds were, which add up to zero for honest
+/// provers.
+struct Verifier {
+    values: Vec<ProofField>,
+    claim: ProofField,
+    differences: Vec<ProofField>,
+}
+
+impl Verifier {
+    fn new(values: Vec<ProofField>, claim: ProofField) -> Self {
+        Self {
+            values,
+            claim,
+            differences: Vec::new(),
+        }
+    }
+
+    /// Checks the proof share `p` of a round and compresses values at `r`.
+    fn round(
+        &mut self,
+        compression: &Compression<ProofField>,
+        p: &[ProofField],
+        r: ProofField,
+        masked: bool,
+    ) {
+        self.differences.push(claimed_sum(p, masked) - self.claim);
+        self.values = compression.fold(&self.values, r);
+        self.claim = compression.eval(p, r);
+    }
+
+    /// What the other verifier needs to check the proof.
+    fn transcript(&self) -> Vec<ProofField> {
+        let mut transcript = vec![self.values[0], self.claim];
+        transcript.extend(&self.differences);
+        transcript
+    }
+
+    /// Returns whether the transcript of the other verifier agrees with this one.
+    fn accepts(&self, other: &[ProofField]) -> bool {
+        let (value, claim, differences) = (other[0], other[1], &other[2..]);
+        self.values[0] * value == self.claim + claim
+            && self
+                .differences
+                .iter()
+                .zip(differences)
+                .all(|(&a, &b)| a + b == ProofField::ZERO)
+    }
+}
+
+/// Proves the multiplications this helper made and verifies the ones of the other helpers.
+async fn verify(ctx: Base<'_>, multiplications: &[BitMultiplication]) -> Result<(), Error> {
+    let compression = Compression::<ProofField>::default();
+    let (prover_weights, left_verifier_weights) =
+        weights(ctx.narrow(&ValidateStep::Weights), multiplications.len()).await?;
+    let (u, v) = prover_vectors(multiplications, &prover_weights);
+    let mut vectors = Vectors {
+        u,
+        v,
+        left_verifier: Verifier::new(
+            left_verifier_vector(multiplications, &left_verifier_weights),
+            left_verifier_weights
+                .iter()
+                .fold(ProofField::ZERO, |acc, &w| acc + w),
+        ),
+        right_verifier: Verifier::new(right_verifier_vector(multiplications), ProofField::ZERO),
+    };
+
+    let rounds = rounds(vectors.u.len());
+    for round in 0..=rounds {
+        let ctx = ctx.narrow(&ValidateStep::Round(round));
+        let masked = round == rounds;
+        if masked {
+            let mask_ctx = ctx.narrow(&RoundStep::Mask);
+            let prss = mask_ctx.prss();
+            let (u_mask, left_verifier_mask) = prss.generate_fields(0_u128);
+            let (right_verifier_mask, v_mask) = prss.generate_fields(1_u128);
+            vectors.u = mask(&vectors.u, u_mask);
+            vectors.v = mask(&vectors.v, v_mask);
+            let left = &mut vectors.left_verifier.values;
+            *left = mask(left, left_verifier_mask);
+            let right = &mut vectors.right_verifier.values;
+            *right = mask(right, right_verifier_mask);
+        }
+        proof_round(ctx, &compression, &mut vectors, masked).await?;
+    }
+
+    let ctx = ctx
+        .narrow(&ValidateStep::Verify)
+        .set_total_records(rounds + 3);
+    let (_, from_left, from_right) = try_join3(
+        try_join(
+            send(&ctx, Direction::Left, &vectors.left_verifier.transcript()),
+            send(&ctx, Direction::Right, &vectors.right_verifier.transcript()),
+        ),
+        receive(&ctx, Direction::Left, rounds + 3),
+        receive(&ctx, Direction::Right, rounds + 3),
+    )
+    .await?;
+
+    if vectors.left_verifier.accepts(&from_left) && vectors.right_verifier.accepts(&from_right) {
+        Ok(())
+    } else {
+        Err(Error::MaliciousSecurityCheckFailed)
+    }
+}
+
+/// Weights of the statements of this helper, which the verifier to its right sends to it, and of
+/// the helper to its right, which this helper shares with the helper to its left.
+async fn weights(ctx: Base<'_>, count: usize) -> Result<(Vec<ProofField>, Vec<ProofField>), Error> {
+    let ctx = ctx.set_total_records(1);
+    let (mut left, mut right) = ctx.prss_rng();
+    let left_verifier_seed = left.gen::<BA256>();
+    let (_, prover_seed) = try_join(
+        send(&ctx, Direction::Left, &[right.gen::<BA256>()]),
+        receive::<BA256>(&ctx, Direction::Right, 1),
+    )
+    .await?;
+
+    let expand = |seed: BA256| {
+        let mut buf = GenericArray::default();
+        seed.serialize(&mut buf);
+        let mut rng = StdRng::from_seed(buf.into());
+        (0..count).map(|_| rng.gen()).collect::<Vec<_>>()
+    };
+    Ok((expand(prover_seed[0]), expand(left_verifier_seed)))
+}
+
+/// Shares the polynomial of this helper's proof with its verifiers, and compresses vectors of
+/// every proof at the challenge of its verifiers.
+async fn proof_round(
+    ctx: Base<'_>,
+    compression: &Compression<ProofField>,
+    vectors: &mut Vectors,
+    masked: bool,
+) -> Result<(), Error> {
+    let share_ctx = ctx
+        .narrow(&RoundStep::ProofShare)
+        .set_total_records(PROOF_LENGTH);
+    // The left share is shared randomness, the prover sends the right one.
+    let p = compression.prove(&vectors.u, &vectors.v);
+    let (p_left, left_verifier_p): (Vec<ProofField>, Vec<ProofField>) = (0..PROOF_LENGTH)
+        .map(|t| share_ctx.prss().generate_fields(u128::try_from(t).unwrap()))
+        .unzip();
+    let p_right = p
+        .iter()
+        .zip(&p_left)
+        .map(|(&p, &left)| p - left)
+        .collect::<Vec<_>>();
+    let (_, right_verifier_p) = try_join(
+        send(&share_ctx, Direction::Right, &p_right),
+        receive(&share_ctx, Direction::Left, PROOF_LENGTH),
+    )
+    .await?;
+
+    // Challenges are sent only once the prover shared its polynomial.
+    let challenge_ctx = ctx.narrow(&RoundStep::Challenge).set_total_records(1);
+    let (left_verifier_r, right_verifier_r) = challenge_ctx.prss().generate_fields(0_u128);
+    let (_, r) = try_join(
+        send(&challenge_ctx, Direction::Left, &[right_verifier_r]),
+        receive(&challenge_ctx, Direction::Right, 1),
+    )
+    .await?;
+
+    vectors.u = compression.fold(&vectors.u, r[0]);
+    vectors.v = compression.fold(&vectors.v, r[0]);
+    vectors
+        .left_verifier
+        .round(compression, &left_verifier_p, left_verifier_r, masked);
+    vectors
+        .right_verifier
+        .round(compression, &right_verifier_p, right_verifier_r, masked);
+
+    Ok(())
+}
+
+async fn send<M: Message + Copy>(
+    ctx: &Base<'_>,
+    direction: Direction,
+    values: &[M],
+) -> Result<(), Error> {
+    let channel = ctx.send_channel(ctx.role().peer(direction));
+    for (i, &value) in values.iter().enumerate() {
+        channel.send(RecordId::from(i), value).await?;
+    }
+    Ok(())
+}
+
+async fn receive<M: Message>(
+    ctx: &Base<'_>,
+    direction: Direction,
+    count: usize,
+) -> Result<Vec<M>, Error> {
+    let channel = ctx.recv_channel(ctx.role().peer(direction));
+    let mut values = Vec::with_capacity(count);
+    for i in 0..count {
+        values.push(channel.receive(RecordId::from(i)).await?);
+    }
+    Ok(values)
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use super::DZKPValidator;
+    use crate::{
+        error::Error,
+        ff::{
+            boolean::Boolean,
+            boolean_array::{BA32, BA8},
+            Field,
+        },
+        helpers::Role,
+        protocol::{
+            basics::SecureMul,
+            context::{Context, UpgradableContext},
+            ipa_prf::boolean_ops::addition_sequential::integer_add,
+            RecordId,
+        },
+        rand::{thread_rng, Rng},
+        secret_sharing::replicated::semi_honest::AdditiveShare as Replicated,
+        seq_join::SeqJoin,
+        test_executor::run,
+        test_fixture::{Reconstruct, Runner, TestWorld},
+    };
+
+    #[test]
+    fn multiplications_of_bits() {
+        run(|| async move {
+            let mut rng = thread_rng();
+            let (a, b): (Vec<Boolean>, Vec<Boolean>) =
+                (0..100).map(|_| (rng.gen(), rng.gen())).unzip();
+            let expected = a.iter().zip(&b).map(|(&a, &b)| a * b).collect::<Vec<_>>();
+
+            let result =
+                TestWorld::default()
+                    .malicious(
+                        a.into_iter().zip(b),
+                        |ctx, pairs: Vec<(Replicated<Boolean>, Replicated<Boolean>)>| async move {
+                            let validator = ctx.dzkp_validator();
+                            let ctx = validator.context().set_total_records(pairs.len());
+                            let products = ctx
+                                .try_join(pairs.iter().enumerate().map(|(i, (a, b))| {
+                                    a.multiply(b, ctx.clone(), RecordId::from(i))
+                                }))
+                                .await
+                                .unwrap();
+                            validator.validate(products).await.unwrap()
+                        },
+                    )
+                    .await
+                    .reconstruct();
+            assert_eq!(expected, result);
+        });
+    }
+
+    #[test]
+    fn additions() {
+        run(|| async move {
+            let mut rng = thread_rng();
+            let (x, y): (Vec<BA32>, Vec<BA32>) = (0..10)
+                .map(|_| {
+                    let (x, y) = (rng.gen::<u32>() >> 1, rng.gen::<u32>() >> 1);
+                    (BA32::truncate_from(x), BA32::truncate_from(y))
+                })
+                .unzip();
+            let expected = x
+                .iter()
+                .zip(&y)
+                .map(|(x, y)| x.as_u128() + y.as_u128())
+                .collect::<Vec<_>>();
+
+            let result: Vec<BA32> = TestWorld::default()
+                .malicious(
+                    x.into_iter().zip(y),
+                    |ctx, pairs: Vec<(Replicated<BA32>, Replicated<BA32>)>| async move {
+                        let validator = ctx.dzkp_validator();
+                        let ctx = validator.context().set_total_records(pairs.len());
+                        let sums = ctx
+                            .try_join(pairs.iter().enumerate().map(|(i, (x, y))| {
+                                integer_add::<_, BA32, BA32>(ctx.clone(), RecordId::from(i), x, y)
+                            }))
+                            .await
+                            .unwrap();
+                        let sums = sums.into_iter().map(|(sum, _)| sum).collect::<Vec<_>>();
+                        validator.validate(sums).await.unwrap()
+                    },
+                )
+                .await
+                .reconstruct();
+            assert_eq!(
+                expected,
+                result.iter().map(Field::as_u128).collect::<Vec<_>>()
+            );
+        });
+    }
+
+    #[test]
+    fn detects_wrong_messages() {
+        run(|| async move {
+            let a = BA8::truncate_from(0xA5_u128);
+            let b = BA8::truncate_from(0x3C_u128);
+            let results = TestWorld::default()
+                .malicious((a, b), |ctx, (a, b)| async move {
+                    let role = ctx.role();
+                    let validator = ctx.dzkp_validator();
+                    let ctx = validator.context().set_total_records(1);
+                    let product = a.multiply(&b, ctx.clone(), RecordId::FIRST).await.unwrap();
+                    // The helper to the left of this one sent a different message than the
+                    // one it should have for one of the bits.
+                    if role == Role::H2 {
+                        ctx.dzkp_batch().unwrap().update(|m| m.z_left = !m.z_left);
+                    }
+                    validator.validate(product).await
+                })
+                .await;
+            // The helper to the left of the one that got the wrong message is the prover, it
+            // doesn't verify its own proof.
+            assert!(results[0].is_ok());
+            for result in &results[1..] {
+                assert!(matches!(result, Err(Error::MaliciousSecurityCheckFailed)));
+            }
+        });
+    }
+
+    #[test]
+    fn semi_honest() {
+        run(|| async move {
+            let result: Boolean = TestWorld::default()
+                .semi_honest(
+                    (Boolean::from(true), Boolean::from(true)),
+                    |ctx, (a, b): (Replicated<Boolean>, Replicated<Boolean>)| async move {
+                        let validator = ctx.dzkp_validator();
+                        let ctx = validator.context().set_total_records(1);
+                        let product = a.multiply(&b, ctx, RecordId::FIRST).await.unwrap();
+                        validator.validate(product).await.unwrap()
+                    },
+                )
+                .await
+                .reconstruct();
+            assert_eq!(Boolean::from(true), result);
+        });
+    }
+}
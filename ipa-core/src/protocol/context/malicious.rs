@@ -7,10 +7,19 @@ use std::{
 use async_trait::async_trait;
 use ipa_macros::Step;
 
+#[cfg(debug_assertions)]
+use super::StepBudget;
 use super::{UpgradeContext, UpgradeToMalicious};
+#[cfg(feature = "descriptive-gate")]
+use crate::protocol::context::dzkp_validator::{
+    DZKPUpgraded, MaliciousDZKPValidator as DZKPValidator,
+};
 use crate::{
     error::Error,
-    helpers::{ChannelId, Gateway, Message, ReceivingEnd, Role, SendingEnd, TotalRecords},
+    helpers::{
+        ChannelId, Gateway, MemoryBudget, Message, PackedReceivingEnd, PackedSendingEnd,
+        ReceivingEnd, Role, SendingEnd, TotalRecords,
+    },
     protocol::{
         basics::{
             mul::malicious::Step::RandomnessForValidation, SecureMul, ShareKnownValue,
@@ -118,15 +127,41 @@ impl<'a> super::Context for Context<'a> {
     fn recv_channel<M: Message>(&self, role: Role) -> ReceivingEnd<M> {
         self.inner.recv_channel(role)
     }
+
+    fn packed_send_channel(&self, role: Role) -> PackedSendingEnd {
+        self.inner.packed_send_channel(role)
+    }
+
+    fn packed_recv_channel(&self, role: Role) -> PackedReceivingEnd {
+        self.inner.packed_recv_channel(role)
+    }
+
+    #[cfg(debug_assertions)]
+    fn budget(&self) -> StepBudget {
+        self.inner.budget()
+    }
+
+    fn memory(&self) -> &Arc<MemoryBudget> {
+        self.inner.memory()
+    }
 }
 
 impl<'a> UpgradableContext for Context<'a> {
     type UpgradedContext<F: ExtendableField> = Upgraded<'a, F>;
     type Validator<F: ExtendableField> = Validator<'a, F>;
+    #[cfg(feature = "descriptive-gate")]
+    type DZKPUpgradedContext = DZKPUpgraded<'a>;
+    #[cfg(feature = "descriptive-gate")]
+    type DZKPValidator = DZKPValidator<'a>;
 
     fn validator<F: ExtendableField>(self) -> Self::Validator<F> {
         Validator::new(self)
     }
+
+    #[cfg(feature = "descriptive-gate")]
+    fn dzkp_validator(self) -> Self::DZKPValidator {
+        DZKPValidator::new(self)
+    }
 }
 
 impl<'a> SeqJoin for Context<'a> {
@@ -286,18 +321,30 @@ impl<'a, F: ExtendableField> super::Context for Upgraded<'a, F> {
     where
         Gate: StepNarrow<S>,
     {
+        let gate = self.gate.narrow(step);
+        #[cfg(feature = "step-graph")]
+        self.inner
+            .gateway
+            .steps()
+            .observe(&gate, self.total_records);
         Self {
             inner: Arc::clone(&self.inner),
-            gate: self.gate.narrow(step),
+            gate,
             total_records: self.total_records,
         }
     }
 
     fn set_total_records<T: Into<TotalRecords>>(&self, total_records: T) -> Self {
+        let total_records = self.total_records.overwrite(total_records);
+        #[cfg(feature = "step-graph")]
+        self.inner
+            .gateway
+            .steps()
+            .observe(&self.gate, total_records);
         Self {
             inner: Arc::clone(&self.inner),
             gate: self.gate.clone(),
-            total_records: self.total_records.overwrite(total_records),
+            total_records,
         }
     }
 
@@ -308,7 +355,12 @@ impl<'a, F: ExtendableField> super::Context for Upgraded<'a, F> {
     fn prss(&self) -> InstrumentedIndexedSharedRandomness<'_> {
         let prss = self.inner.prss.indexed(self.gate());
 
-        InstrumentedIndexedSharedRandomness::new(prss, &self.gate, self.role())
+        InstrumentedIndexedSharedRandomness::new(
+            prss,
+            &self.gate,
+            self.role(),
+            self.inner.gateway.progress_tracker(),
+        )
     }
 
     fn prss_rng(
@@ -319,8 +371,18 @@ impl<'a, F: ExtendableField> super::Context for Upgraded<'a, F> {
     ) {
         let (left, right) = self.inner.prss.sequential(self.gate());
         (
-            InstrumentedSequentialSharedRandomness::new(left, self.gate(), self.role()),
-            InstrumentedSequentialSharedRandomness::new(right, self.gate(), self.role()),
+            InstrumentedSequentialSharedRandomness::new(
+                left,
+                self.gate(),
+                self.role(),
+                self.inner.gateway.progress_tracker(),
+            ),
+            InstrumentedSequentialSharedRandomness::new(
+                right,
+                self.gate(),
+                self.role(),
+                self.inner.gateway.progress_tracker(),
+            ),
         )
     }
 
@@ -335,6 +397,30 @@ impl<'a, F: ExtendableField> super::Context for Upgraded<'a, F> {
             .gateway
             .get_receiver(&ChannelId::new(role, self.gate.clone()))
     }
+
+    fn packed_send_channel(&self, role: Role) -> PackedSendingEnd {
+        self.inner
+            .gateway
+            .get_packed_sender(&ChannelId::new(role, self.gate.clone()), self.total_records)
+    }
+
+    fn packed_recv_channel(&self, role: Role) -> PackedReceivingEnd {
+        self.inner
+            .gateway
+            .get_packed_receiver(&ChannelId::new(role, self.gate.clone()))
+    }
+
+    #[cfg(debug_assertions)]
+    fn budget(&self) -> StepBudget {
+        StepBudget::new(
+            self.total_records,
+            &self.inner.gateway.progress_tracker().step(&self.gate),
+        )
+    }
+
+    fn memory(&self) -> &Arc<MemoryBudget> {
+        self.inner.gateway.memory()
+    }
 }
 
 impl<'a, F: ExtendableField> SeqJoin for Upgraded<'a, F> {
@@ -1,3 +1,9 @@
+#[cfg(debug_assertions)]
+pub mod budget;
+#[cfg(all(feature = "debug-reveal", debug_assertions))]
+pub mod debug;
+#[cfg(feature = "descriptive-gate")]
+pub mod dzkp_validator;
 pub mod malicious;
 pub mod prss;
 pub mod semi_honest;
@@ -7,6 +13,14 @@ pub mod validator;
 use std::{num::NonZeroUsize, sync::Arc};
 
 use async_trait::async_trait;
+#[cfg(debug_assertions)]
+pub use budget::StepBudget;
+#[cfg(all(feature = "debug-reveal", debug_assertions))]
+pub use debug::DebugReveal;
+#[cfg(feature = "descriptive-gate")]
+pub use dzkp_validator::{
+    Batch as DZKPBatch, DZKPUpgraded as DZKPUpgradedMaliciousContext, DZKPValidator,
+};
 pub use malicious::{Context as MaliciousContext, Upgraded as UpgradedMaliciousContext};
 use prss::{InstrumentedIndexedSharedRandomness, InstrumentedSequentialSharedRandomness};
 pub use semi_honest::{Context as SemiHonestContext, Upgraded as UpgradedSemiHonestContext};
@@ -15,7 +29,10 @@ pub use validator::Validator;
 
 use crate::{
     error::Error,
-    helpers::{ChannelId, Gateway, Message, ReceivingEnd, Role, SendingEnd, TotalRecords},
+    helpers::{
+        ChannelId, Gateway, MemoryBudget, Message, PackedReceivingEnd, PackedSendingEnd,
+        ReceivingEnd, Role, SendingEnd, TotalRecords,
+    },
     protocol::{
         basics::ZeroPositions,
         prss::Endpoint as PrssEndpoint,
@@ -80,13 +97,50 @@ pub trait Context: Clone + Send + Sync + SeqJoin {
 
     fn send_channel<M: Message>(&self, role: Role) -> SendingEnd<M>;
     fn recv_channel<M: Message>(&self, role: Role) -> ReceivingEnd<M>;
+
+    /// Like [`send_channel`], but packs [`Boolean`] messages of consecutive records into bitmaps.
+    /// Only use it in steps where records don't wait on each other, see [`PackedSendingEnd`].
+    ///
+    /// [`send_channel`]: Self::send_channel
+    /// [`Boolean`]: crate::ff::boolean::Boolean
+    fn packed_send_channel(&self, role: Role) -> PackedSendingEnd;
+    fn packed_recv_channel(&self, role: Role) -> PackedReceivingEnd;
+
+    /// Returns how much randomness and communication the step of this context has used so far,
+    /// against the number of records it declared. Only available in debug builds, PRSS is not
+    /// accounted for otherwise.
+    #[cfg(debug_assertions)]
+    #[must_use]
+    fn budget(&self) -> StepBudget;
+
+    /// Memory budget of the query. Protocols that buffer many shares should reserve memory for
+    /// them, so it is accounted for along with gateway buffers.
+    #[must_use]
+    fn memory(&self) -> &Arc<MemoryBudget>;
+
+    /// Multiplications made in this context that a [`DZKPValidator`] checks. Contexts that check
+    /// multiplications with MACs, or don't check them at all, have none.
+    #[cfg(feature = "descriptive-gate")]
+    fn dzkp_batch(&self) -> Option<&DZKPBatch> {
+        None
+    }
 }
 
 pub trait UpgradableContext: Context {
     type UpgradedContext<F: ExtendableField>: UpgradedContext<F>;
     type Validator<F: ExtendableField>: Validator<Self, F>;
+    /// Context for binary protocols that [`Self::DZKPValidator`] checks.
+    #[cfg(feature = "descriptive-gate")]
+    type DZKPUpgradedContext: Context;
+    #[cfg(feature = "descriptive-gate")]
+    type DZKPValidator: DZKPValidator<Self>;
 
     fn validator<F: ExtendableField>(self) -> Self::Validator<F>;
+
+    /// Returns a validator that checks multiplications of bits with distributed zero-knowledge
+    /// proofs, which is much cheaper than [`Self::validator`] for binary protocols.
+    #[cfg(feature = "descriptive-gate")]
+    fn dzkp_validator(self) -> Self::DZKPValidator;
 }
 
 #[async_trait]
@@ -196,18 +250,30 @@ impl<'a> Context for Base<'a> {
     where
         Gate: StepNarrow<S>,
     {
+        let gate = self.gate.narrow(step);
+        #[cfg(feature = "step-graph")]
+        self.inner
+            .gateway
+            .steps()
+            .observe(&gate, self.total_records);
         Self {
             inner: Arc::clone(&self.inner),
-            gate: self.gate.narrow(step),
+            gate,
             total_records: self.total_records,
         }
     }
 
     fn set_total_records<T: Into<TotalRecords>>(&self, total_records: T) -> Self {
+        let total_records = self.total_records.overwrite(total_records);
+        #[cfg(feature = "step-graph")]
+        self.inner
+            .gateway
+            .steps()
+            .observe(&self.gate, total_records);
         Self {
             inner: Arc::clone(&self.inner),
             gate: self.gate.clone(),
-            total_records: self.total_records.overwrite(total_records),
+            total_records,
         }
     }
 
@@ -218,7 +284,12 @@ impl<'a> Context for Base<'a> {
     fn prss(&self) -> InstrumentedIndexedSharedRandomness {
         let prss = self.inner.prss.indexed(self.gate());
 
-        InstrumentedIndexedSharedRandomness::new(prss, &self.gate, self.role())
+        InstrumentedIndexedSharedRandomness::new(
+            prss,
+            &self.gate,
+            self.role(),
+            self.inner.gateway.progress_tracker(),
+        )
     }
 
     fn prss_rng(
@@ -229,8 +300,18 @@ impl<'a> Context for Base<'a> {
     ) {
         let (left, right) = self.inner.prss.sequential(self.gate());
         (
-            InstrumentedSequentialSharedRandomness::new(left, self.gate(), self.role()),
-            InstrumentedSequentialSharedRandomness::new(right, self.gate(), self.role()),
+            InstrumentedSequentialSharedRandomness::new(
+                left,
+                self.gate(),
+                self.role(),
+                self.inner.gateway.progress_tracker(),
+            ),
+            InstrumentedSequentialSharedRandomness::new(
+                right,
+                self.gate(),
+                self.role(),
+                self.inner.gateway.progress_tracker(),
+            ),
         )
     }
 
@@ -245,6 +326,30 @@ impl<'a> Context for Base<'a> {
             .gateway
             .get_receiver(&ChannelId::new(role, self.gate.clone()))
     }
+
+    fn packed_send_channel(&self, role: Role) -> PackedSendingEnd {
+        self.inner
+            .gateway
+            .get_packed_sender(&ChannelId::new(role, self.gate.clone()), self.total_records)
+    }
+
+    fn packed_recv_channel(&self, role: Role) -> PackedReceivingEnd {
+        self.inner
+            .gateway
+            .get_packed_receiver(&ChannelId::new(role, self.gate.clone()))
+    }
+
+    #[cfg(debug_assertions)]
+    fn budget(&self) -> StepBudget {
+        StepBudget::new(
+            self.total_records,
+            &self.inner.gateway.progress_tracker().step(&self.gate),
+        )
+    }
+
+    fn memory(&self) -> &Arc<MemoryBudget> {
+        self.inner.gateway.memory()
+    }
 }
 
 impl<'a> SeqJoin for Base<'a> {
@@ -499,6 +604,52 @@ mod tests {
         }
     }
 
+    #[cfg(debug_assertions)]
+    #[tokio::test]
+    async fn budget() {
+        let world = TestWorld::default();
+        let input = (0..10u128).map(Fp31::truncate_from).collect::<Vec<_>>();
+        let input_len = input.len();
+
+        world
+            .semi_honest(input.into_iter(), |ctx, shares| async move {
+                let ctx = ctx.narrow("budget").set_total_records(input_len);
+                let budget = ctx.budget();
+                assert_eq!(Some(input_len), budget.total_records.count());
+                assert_eq!(0, budget.declared_records);
+                assert_eq!(0, budget.prss_generated);
+
+                // records are declared once the channel is open
+                let send_channel = ctx.send_channel(ctx.role().peer(Direction::Left));
+                let recv_channel = ctx.recv_channel::<Fp31>(ctx.role().peer(Direction::Right));
+                assert_eq!(input_len, ctx.budget().unsent_records());
+
+                join_all(shares.iter().enumerate().map(|(i, share)| {
+                    let ctx = ctx.clone();
+                    let (send_channel, recv_channel) = (&send_channel, &recv_channel);
+                    async move {
+                        let record_id = RecordId::from(i);
+                        let (l, _) = ctx.prss().generate_fields::<Fp31, _>(record_id);
+                        try_join!(
+                            send_channel.send(record_id, share.l() - l),
+                            recv_channel.receive(record_id),
+                        )
+                        .unwrap();
+                    }
+                }))
+                .await;
+
+                let budget = ctx.budget();
+                assert_eq!(input_len, budget.sent_records);
+                assert_eq!(0, budget.unsent_records());
+                assert_eq!(input_len as u64, budget.prss_generated);
+                assert!(budget.is_within(1));
+                assert!(!budget.is_within(0));
+                assert_eq!(Some(input_len as u64), budget.remaining_prss(2));
+            })
+            .await;
+    }
+
     /// validates that malicious upgrade can be called more than once on contexts narrowed down
     /// to unique steps
     #[tokio::test]
@@ -3,7 +3,7 @@
 use rand_core::{Error, RngCore};
 
 use crate::{
-    helpers::Role,
+    helpers::{ProgressTracker, Role},
     protocol::{
         prss::{IndexedSharedRandomness, SequentialSharedRandomness, SharedRandomness},
         step::Gate,
@@ -15,20 +15,31 @@ use crate::{
     },
 };
 
-/// Wrapper around `IndexedSharedRandomness` that instrument calls to `generate_values`
+/// Wrapper around `IndexedSharedRandomness` that instrument calls to `generate_values`. It also
+/// counts them in `progress`, see [`Context::budget`] and [`HelperCounters`].
+///
+/// [`Context::budget`]: crate::protocol::context::Context::budget
+/// [`HelperCounters`]: crate::helpers::HelperCounters
 pub struct InstrumentedIndexedSharedRandomness<'a> {
     inner: Arc<IndexedSharedRandomness>,
     step: &'a Gate,
     role: Role,
+    progress: &'a ProgressTracker,
 }
 
 impl<'a> InstrumentedIndexedSharedRandomness<'a> {
     #[must_use]
-    pub fn new(source: Arc<IndexedSharedRandomness>, step: &'a Gate, role: Role) -> Self {
+    pub fn new(
+        source: Arc<IndexedSharedRandomness>,
+        step: &'a Gate,
+        role: Role,
+        progress: &'a ProgressTracker,
+    ) -> Self {
         Self {
             inner: source,
             step,
             role,
+            progress,
         }
     }
 }
@@ -40,8 +51,17 @@ impl SharedRandomness for InstrumentedIndexedSharedRandomness<'_> {
         // PRSS. Gauge infrastructure is not supported yet, `Metrics` struct needs to be able to
         // handle gauges
         metrics::increment_counter!(INDEXED_PRSS_GENERATED, STEP => step, ROLE => self.role.as_static_str());
+        self.progress.prss_generated(self.step, 1);
         self.inner.generate_values(index)
     }
+
+    fn fill_values(&self, start: u128, left: &mut [u128], right: &mut [u128]) {
+        let step = self.step.as_ref().to_string();
+        let count = u64::try_from(left.len()).unwrap();
+        metrics::counter!(INDEXED_PRSS_GENERATED, count, STEP => step, ROLE => self.role.as_static_str());
+        self.progress.prss_generated(self.step, count);
+        self.inner.fill_values(start, left, right);
+    }
 }
 
 /// Wrapper for `SequentialSharedRandomness` that instrument calls to generate random values.
@@ -49,15 +69,22 @@ pub struct InstrumentedSequentialSharedRandomness<'a> {
     inner: SequentialSharedRandomness,
     step: &'a Gate,
     role: Role,
+    progress: &'a ProgressTracker,
 }
 
 impl<'a> InstrumentedSequentialSharedRandomness<'a> {
     #[must_use]
-    pub fn new(source: SequentialSharedRandomness, step: &'a Gate, role: Role) -> Self {
+    pub fn new(
+        source: SequentialSharedRandomness,
+        step: &'a Gate,
+        role: Role,
+        progress: &'a ProgressTracker,
+    ) -> Self {
         Self {
             inner: source,
             step,
             role,
+            progress,
         }
     }
 }
@@ -71,6 +98,7 @@ impl RngCore for InstrumentedSequentialSharedRandomness<'_> {
     fn next_u64(&mut self) -> u64 {
         let step = self.step.as_ref().to_string();
         metrics::increment_counter!(SEQUENTIAL_PRSS_GENERATED, STEP => step, ROLE => self.role.as_static_str());
+        self.progress.prss_generated(self.step, 1);
         self.inner.next_u64()
     }
 
@@ -8,10 +8,15 @@ use std::{
 use async_trait::async_trait;
 use ipa_macros::Step;
 
+#[cfg(debug_assertions)]
+use super::StepBudget;
 use super::{Context as SuperContext, UpgradeContext, UpgradeToMalicious};
 use crate::{
     error::Error,
-    helpers::{Gateway, Message, ReceivingEnd, Role, SendingEnd, TotalRecords},
+    helpers::{
+        Gateway, MemoryBudget, Message, PackedReceivingEnd, PackedSendingEnd, ReceivingEnd, Role,
+        SendingEnd, TotalRecords,
+    },
     protocol::{
         basics::{ShareKnownValue, ZeroPositions},
         context::{
@@ -27,7 +32,10 @@ use crate::{
         malicious::ExtendableField, semi_honest::AdditiveShare as Replicated,
     },
     seq_join::SeqJoin,
+    sync::Arc,
 };
+#[cfg(feature = "descriptive-gate")]
+use crate::{ff::boolean::Boolean, protocol::context::dzkp_validator::SemiHonestDZKPValidator};
 
 #[derive(Clone)]
 pub struct Context<'a> {
@@ -41,7 +49,6 @@ impl<'a> Context<'a> {
         }
     }
 
-    #[cfg(test)]
     #[must_use]
     pub fn from_base(base: Base<'a>) -> Self {
         Self { inner: base }
@@ -96,15 +103,41 @@ impl<'a> super::Context for Context<'a> {
     fn recv_channel<M: Message>(&self, role: Role) -> ReceivingEnd<M> {
         self.inner.recv_channel(role)
     }
+
+    fn packed_send_channel(&self, role: Role) -> PackedSendingEnd {
+        self.inner.packed_send_channel(role)
+    }
+
+    fn packed_recv_channel(&self, role: Role) -> PackedReceivingEnd {
+        self.inner.packed_recv_channel(role)
+    }
+
+    #[cfg(debug_assertions)]
+    fn budget(&self) -> StepBudget {
+        self.inner.budget()
+    }
+
+    fn memory(&self) -> &Arc<MemoryBudget> {
+        self.inner.memory()
+    }
 }
 
 impl<'a> UpgradableContext for Context<'a> {
     type UpgradedContext<F: ExtendableField> = Upgraded<'a, F>;
     type Validator<F: ExtendableField> = Validator<'a, F>;
+    #[cfg(feature = "descriptive-gate")]
+    type DZKPUpgradedContext = Upgraded<'a, Boolean>;
+    #[cfg(feature = "descriptive-gate")]
+    type DZKPValidator = SemiHonestDZKPValidator<'a>;
 
     fn validator<F: ExtendableField>(self) -> Self::Validator<F> {
         Self::Validator::new(self.inner)
     }
+
+    #[cfg(feature = "descriptive-gate")]
+    fn dzkp_validator(self) -> Self::DZKPValidator {
+        Self::DZKPValidator::new(self.inner)
+    }
 }
 
 impl<'a> SeqJoin for Context<'a> {
@@ -178,6 +211,23 @@ impl<'a, F: ExtendableField> super::Context for Upgraded<'a, F> {
     fn recv_channel<M: Message>(&self, role: Role) -> ReceivingEnd<M> {
         self.inner.recv_channel(role)
     }
+
+    fn packed_send_channel(&self, role: Role) -> PackedSendingEnd {
+        self.inner.packed_send_channel(role)
+    }
+
+    fn packed_recv_channel(&self, role: Role) -> PackedReceivingEnd {
+        self.inner.packed_recv_channel(role)
+    }
+
+    #[cfg(debug_assertions)]
+    fn budget(&self) -> StepBudget {
+        self.inner.budget()
+    }
+
+    fn memory(&self) -> &Arc<MemoryBudget> {
+        self.inner.memory()
+    }
 }
 
 impl<'a, F: ExtendableField> SeqJoin for Upgraded<'a, F> {
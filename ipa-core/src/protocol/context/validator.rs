@@ -15,8 +15,8 @@ use crate::{
     protocol::{
         basics::{check_zero, Reveal},
         context::{
-            Base, Context, MaliciousContext, SemiHonestContext, UpgradableContext,
-            UpgradedMaliciousContext, UpgradedSemiHonestContext,
+            Base, Context, MaliciousContext, SemiHonestContext, UpgradableContext, UpgradeContext,
+            UpgradeToMalicious, UpgradedMaliciousContext, UpgradedSemiHonestContext,
         },
         prss::SharedRandomness,
         RecordId,
@@ -29,21 +29,58 @@ use crate::{
     sync::{Arc, Mutex, Weak},
 };
 
+/// Runs a protocol at the security level of the context it was created from and checks its
+/// result.
+///
+/// Protocols may mix security levels deliberately: stages that need malicious security work on
+/// shares brought in with [`Self::upgrade`], and once they are validated, stages that don't need
+/// it continue with cheaper semi-honest shares and context returned by
+/// [`Self::validate_and_downgrade`]. These are the only two places where shares change their
+/// security level, so it is easy to audit which stages run with which one.
 #[async_trait]
 pub trait Validator<B: UpgradableContext, F: ExtendableField> {
+    /// Context returned by [`Self::validate_and_downgrade`].
+    type Downgraded: UpgradableContext;
+
     fn context(&self) -> B::UpgradedContext<F>;
     async fn validate<D: DowngradeMalicious>(self, values: D) -> Result<D::Target, Error>;
+
+    /// Upgrades semi-honest shares produced by earlier stages, so that protocols running in
+    /// [`Self::context`] can use them. This validator checks them along with everything else
+    /// computed in that context.
+    ///
+    /// ## Errors
+    /// When the multiplication fails. Additive attacks are only detected by validation.
+    async fn upgrade<T, M>(&self, input: T) -> Result<M, Error>
+    where
+        T: Send,
+        for<'u> UpgradeContext<'u, B::UpgradedContext<F>, F>: UpgradeToMalicious<'u, T, M>;
+
+    /// Validates `values` and returns them as semi-honest shares, along with a semi-honest
+    /// context for the stages that follow. Those stages are not protected against malicious
+    /// helpers, so this is only meant for stages that can't be used to attack the result, like
+    /// local aggregation or revealing values that are validated already. The context is narrowed
+    /// to its own step, so it never clashes with the steps of [`Self::context`].
+    ///
+    /// ## Errors
+    /// Same as [`Self::validate`].
+    async fn validate_and_downgrade<D: DowngradeMalicious>(
+        self,
+        values: D,
+    ) -> Result<(D::Target, Self::Downgraded), Error>;
 }
 
 pub struct SemiHonest<'a, F: ExtendableField> {
     context: UpgradedSemiHonestContext<'a, F>,
+    base: Base<'a>,
     _f: PhantomData<F>,
 }
 
 impl<'a, F: ExtendableField> SemiHonest<'a, F> {
     pub(super) fn new(inner: Base<'a>) -> Self {
         Self {
-            context: UpgradedSemiHonestContext::new(inner),
+            context: UpgradedSemiHonestContext::new(inner.clone()),
+            base: inner,
             _f: PhantomData,
         }
     }
@@ -51,6 +88,8 @@ impl<'a, F: ExtendableField> SemiHonest<'a, F> {
 
 #[async_trait]
 impl<'a, F: ExtendableField> Validator<SemiHonestContext<'a>, F> for SemiHonest<'a, F> {
+    type Downgraded = SemiHonestContext<'a>;
+
     fn context(&self) -> UpgradedSemiHonestContext<'a, F> {
         self.context.clone()
     }
@@ -59,6 +98,23 @@ impl<'a, F: ExtendableField> Validator<SemiHonestContext<'a>, F> for SemiHonest<
         use crate::secret_sharing::replicated::malicious::ThisCodeIsAuthorizedToDowngradeFromMalicious;
         Ok(values.downgrade().await.access_without_downgrade())
     }
+
+    async fn upgrade<T, M>(&self, input: T) -> Result<M, Error>
+    where
+        T: Send,
+        for<'u> UpgradeContext<'u, UpgradedSemiHonestContext<'a, F>, F>:
+            UpgradeToMalicious<'u, T, M>,
+    {
+        self.context().upgrade(input).await
+    }
+
+    async fn validate_and_downgrade<D: DowngradeMalicious>(
+        self,
+        values: D,
+    ) -> Result<(D::Target, SemiHonestContext<'a>), Error> {
+        let downgraded = SemiHonestContext::from_base(self.base.narrow(&Step::Downgraded));
+        Ok((self.validate(values).await?, downgraded))
+    }
 }
 
 impl<F: ExtendableField> Debug for SemiHonest<'_, F> {
@@ -75,6 +131,8 @@ pub(crate) enum Step {
     MaliciousProtocol,
     /// The final validation steps.
     Validate,
+    /// For stages that follow validation without malicious security.
+    Downgraded,
 }
 
 #[derive(Step)]
@@ -199,10 +257,13 @@ pub struct Malicious<'a, F: ExtendableField> {
     u_and_w: Arc<Mutex<AccumulatorState<F::ExtendedField>>>,
     protocol_ctx: UpgradedMaliciousContext<'a, F>,
     validate_ctx: Base<'a>,
+    base: Base<'a>,
 }
 
 #[async_trait]
 impl<'a, F: ExtendableField> Validator<MaliciousContext<'a>, F> for Malicious<'a, F> {
+    type Downgraded = SemiHonestContext<'a>;
+
     /// Get a copy of the context that can be used for malicious protocol execution.
     fn context<'b>(&'b self) -> UpgradedMaliciousContext<'a, F> {
         self.protocol_ctx.clone()
@@ -243,6 +304,23 @@ impl<'a, F: ExtendableField> Validator<MaliciousContext<'a>, F> for Malicious<'a
             Err(Error::MaliciousSecurityCheckFailed)
         }
     }
+
+    async fn upgrade<T, M>(&self, input: T) -> Result<M, Error>
+    where
+        T: Send,
+        for<'u> UpgradeContext<'u, UpgradedMaliciousContext<'a, F>, F>:
+            UpgradeToMalicious<'u, T, M>,
+    {
+        self.context().upgrade(input).await
+    }
+
+    async fn validate_and_downgrade<D: DowngradeMalicious>(
+        self,
+        values: D,
+    ) -> Result<(D::Target, SemiHonestContext<'a>), Error> {
+        let downgraded = SemiHonestContext::from_base(self.base.narrow(&Step::Downgraded));
+        Ok((self.validate(values).await?, downgraded))
+    }
 }
 
 impl<'a, F: ExtendableField> Malicious<'a, F> {
@@ -260,6 +338,7 @@ impl<'a, F: ExtendableField> Malicious<'a, F> {
         let accumulator = MaliciousAccumulator::<F> {
             inner: Arc::downgrade(&u_and_w),
         };
+        let base = ctx.clone().base_context();
         let validate_ctx = ctx.narrow(&Step::Validate).base_context();
         let protocol_ctx = ctx.upgrade(&Step::MaliciousProtocol, accumulator, r_share.clone());
         Self {
@@ -267,6 +346,7 @@ impl<'a, F: ExtendableField> Malicious<'a, F> {
             u_and_w,
             protocol_ctx,
             validate_ctx,
+            base,
         }
     }
 
@@ -397,6 +477,31 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn mixed_security_levels() {
+        let world = TestWorld::default();
+        let mut rng = thread_rng();
+        let (a, b, c) = (rng.gen::<Fp31>(), rng.gen::<Fp31>(), rng.gen::<Fp31>());
+
+        let result = world
+            .malicious((a, (b, c)), |ctx, (a, (b, c))| async move {
+                let v = ctx.validator();
+                let (a, b) = v.upgrade((a, b)).await.unwrap();
+                let ab = a
+                    .multiply(&b, v.context().set_total_records(1), RecordId::FIRST)
+                    .await
+                    .unwrap();
+
+                // the last multiplication is not protected against malicious helpers
+                let (ab, ctx) = v.validate_and_downgrade(ab).await.unwrap();
+                ab.multiply(&c, ctx.set_total_records(1), RecordId::FIRST)
+                    .await
+                    .unwrap()
+            })
+            .await;
+        assert_eq!(a * b * c, result.reconstruct());
+    }
+
     #[tokio::test]
     async fn upgrade_only() {
         let world = TestWorld::default();
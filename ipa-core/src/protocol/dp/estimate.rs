@@ -0,0 +1,111 @@
+use crate::{
+    helpers::query::IpaQueryConfig,
+    protocol::dp::{insecure::Error, noise::SAMPLES_PER_VALUE, BinomialNoise},
+};
+
+/// Expected impact of the noise helpers add to the breakdowns of a query, so report collectors
+/// can pick the privacy budget and the per-user cap before they run it.
+///
+/// It is derived from the same [`BinomialNoise`] helpers sample from, see [`add_noise`].
+///
+/// [`add_noise`]: crate::protocol::dp::add_noise
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NoiseEstimate {
+    noise: BinomialNoise,
+    breakdowns: u32,
+}
+
+impl NoiseEstimate {
+    /// Estimates the noise of a query with `breakdowns` breakdowns, made
+    /// (`epsilon`, `delta`)-differentially private for users contributing at most `cap`.
+    ///
+    /// ## Errors
+    /// If helpers would refuse to add noise with these parameters.
+    pub fn new(epsilon: f64, delta: f64, cap: u32, breakdowns: u32) -> Result<Self, Error> {
+        Ok(Self {
+            noise: BinomialNoise::new(epsilon, delta, cap)?,
+            breakdowns: breakdowns.max(1),
+        })
+    }
+
+    /// Estimates the noise of an IPA query run with `config`, `None` if it does not add any.
+    ///
+    /// ## Errors
+    /// Same as [`Self::new`].
+    pub fn for_query(config: &IpaQueryConfig) -> Result<Option<Self>, Error> {
+        config
+            .dp_epsilon
+            .map(|epsilon| {
+                Self::new(
+                    epsilon.into(),
+                    config.dp_delta.into(),
+                    config.per_user_credit_cap,
+                    config.max_breakdown_key,
+                )
+            })
+            .transpose()
+    }
+
+    /// Returns the standard deviation of the noise added to every breakdown.
+    #[must_use]
+    pub fn std(&self) -> f64 {
+        f64::from(SAMPLES_PER_VALUE).sqrt() * self.noise.std()
+    }
+
+    /// Returns the standard deviation of the noise relative to the value of a single breakdown,
+    /// if breakdowns add up to `volume` and all of them have the same value.
+    #[must_use]
+    pub fn relative_error(&self, volume: u64) -> f64 {
+        #[allow(clippy::cast_precision_loss)]
+        let volume = volume as f64;
+        self.std() * f64::from(self.breakdowns) / volume
+    }
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use super::NoiseEstimate;
+    use crate::{helpers::query::IpaQueryConfig, protocol::dp::BinomialNoise};
+
+    #[test]
+    fn std() {
+        // same noise as in `noise_distribution` test of `add_noise`, which has variance 48
+        let estimate = NoiseEstimate::new(10.0, 1e-6, 1, 1).unwrap();
+        assert!((estimate.std() - f64::sqrt(48.0)).abs() < 1e-9);
+
+        let noise = BinomialNoise::new(1.0, 1e-7, 8).unwrap();
+        let estimate = NoiseEstimate::new(1.0, 1e-7, 8, 20).unwrap();
+        assert!((estimate.std() - f64::sqrt(3.0) * noise.std()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn relative_error() {
+        let estimate = NoiseEstimate::new(1.0, 1e-7, 8, 20).unwrap();
+        let error = estimate.relative_error(1_000_000);
+        assert!((error - estimate.std() * 20.0 / 1_000_000.0).abs() < 1e-12);
+        assert!((estimate.relative_error(2_000_000) - error / 2.0).abs() < 1e-12);
+        assert!(
+            NoiseEstimate::new(1.0, 1e-7, 8, 40)
+                .unwrap()
+                .relative_error(1_000_000)
+                > error
+        );
+    }
+
+    #[test]
+    fn for_query() {
+        assert_eq!(
+            None,
+            NoiseEstimate::for_query(&IpaQueryConfig::default()).unwrap()
+        );
+
+        let config = IpaQueryConfig {
+            dp_epsilon: Some(1.0.try_into().unwrap()),
+            ..IpaQueryConfig::default()
+        };
+        assert_eq!(
+            Some(NoiseEstimate::new(1.0, 1e-7, 3, 20).unwrap()),
+            NoiseEstimate::for_query(&config).unwrap()
+        );
+    }
+}
@@ -13,6 +13,8 @@ pub enum Error {
     BadEpsilon(f64),
     #[error("Valid values for DP-delta are within {:?}, got: {0}", f64::MIN_POSITIVE..1.0 - f64::MIN_POSITIVE)]
     BadDelta(f64),
+    #[error("Noise with standard deviation {0} is too large to be generated")]
+    TooMuchNoise(f64),
 }
 
 /// Applies DP to the inputs in the clear using continuous Gaussian noise. Works with floats only, so
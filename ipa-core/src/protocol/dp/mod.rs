@@ -1,5 +1,10 @@
 mod distributions;
+mod estimate;
 mod insecure;
+mod noise;
 
+pub use estimate::NoiseEstimate;
 #[cfg(any(test, feature = "test-fixture", feature = "cli"))]
 pub use insecure::DiscreteDp as InsecureDiscreteDp;
+pub use insecure::Error;
+pub use noise::{add_noise, BinomialNoise};
@@ -0,0 +1,184 @@
+use rand_core::RngCore;
+
+use crate::{
+    ff::PrimeField,
+    protocol::{
+        context::Context,
+        dp::insecure::{DiscreteDp, Error},
+    },
+    secret_sharing::replicated::{
+        semi_honest::AdditiveShare as Replicated, ReplicatedSecretSharing,
+    },
+};
+
+/// Noise is never sampled from more trials than that (2^32), regardless of the privacy budget.
+/// It takes seconds to generate that many random bits for every breakdown.
+const MAX_TRIALS: f64 = 4_294_967_296.0;
+
+/// Number of independent samples [`add_noise`] adds to every value, one for each pair of helpers.
+pub(super) const SAMPLES_PER_VALUE: u32 = 3;
+
+/// Centered binomial noise, i.e. the number of heads in `trials` fair coin tosses minus
+/// `trials / 2`. It approximates the Gaussian noise of [`DiscreteDp`] with the same variance, but
+/// it only requires random bits to sample, so helpers can derive it from PRSS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BinomialNoise {
+    trials: u64,
+}
+
+impl BinomialNoise {
+    /// Returns noise that makes sums, to which a single user contributes at most `sensitivity`,
+    /// (`epsilon`, `delta`)-differentially private.
+    ///
+    /// ## Errors
+    /// If epsilon or delta is out of range or if the required noise is too large to sample.
+    pub fn new(epsilon: f64, delta: f64, sensitivity: u32) -> Result<Self, Error> {
+        let std = DiscreteDp::new(epsilon, delta, f64::from(sensitivity))?.std();
+        // variance of centered binomial distribution is trials / 4. Number of trials is rounded up
+        // to a multiple of 64, so the noise is sampled from whole `u64` values.
+        let trials = (4.0 * std * std / 64.0).ceil().max(1.0) * 64.0;
+        if trials > MAX_TRIALS {
+            return Err(Error::TooMuchNoise(std));
+        }
+
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let trials = trials as u64;
+        Ok(Self { trials })
+    }
+
+    #[must_use]
+    pub fn trials(&self) -> u64 {
+        self.trials
+    }
+
+    #[must_use]
+    pub fn std(&self) -> f64 {
+        #[allow(clippy::cast_precision_loss)]
+        let trials = self.trials as f64;
+        trials.sqrt() / 2.0
+    }
+
+    /// Returns the largest absolute value of noise [`add_noise`] adds to a single value.
+    #[must_use]
+    pub fn max_abs(&self) -> u64 {
+        3 * (self.trials / 2)
+    }
+
+    /// Returns the number of heads in `trials` coin tosses, i.e. a sample shifted by
+    /// `trials / 2`, which is never negative.
+    pub(crate) fn heads<R: RngCore>(&self, rng: &mut R) -> u64 {
+        (0..self.trials / 64)
+            .map(|_| u64::from(rng.next_u64().count_ones()))
+            .sum()
+    }
+
+    fn sample<R: RngCore>(&self, rng: &mut R) -> i64 {
+        // both values are below `MAX_TRIALS`
+        i64::try_from(self.heads(rng)).unwrap() - i64::try_from(self.trials / 2).unwrap()
+    }
+}
+
+/// Adds secret-shared noise to every value in `values`, without communication.
+///
+/// Every pair of helpers derives one noise sample per value from the randomness they share, so
+/// the noise added to each value is the sum of three independent samples and every helper knows
+/// two of them. The sample a helper does not know is enough to keep the output
+/// differentially private from that helper, as well as from anyone who only learns the output.
+///
+/// This consumes the PRSS of `ctx`, so `ctx` must not be used to generate shared randomness.
+pub fn add_noise<C, F>(ctx: &C, noise: BinomialNoise, values: &mut [Replicated<F>])
+where
+    C: Context,
+    F: PrimeField,
+{
+    let (mut left_rng, mut right_rng) = ctx.prss_rng();
+    for value in values {
+        let left = to_field::<F>(noise.sample(&mut left_rng));
+        let right = to_field::<F>(noise.sample(&mut right_rng));
+        *value += Replicated::new(left, right);
+    }
+}
+
+fn to_field<F: PrimeField>(v: i64) -> F {
+    let abs = F::truncate_from(v.unsigned_abs());
+    if v < 0 {
+        -abs
+    } else {
+        abs
+    }
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use super::{add_noise, BinomialNoise};
+    use crate::{
+        ff::{Field, Fp32BitPrime, PrimeField},
+        protocol::dp::Error,
+        secret_sharing::replicated::semi_honest::AdditiveShare as Replicated,
+        test_fixture::{Reconstruct, Runner, TestWorld},
+    };
+
+    #[test]
+    fn trials() {
+        let noise = BinomialNoise::new(1.0, 1e-7, 8).unwrap();
+        assert_eq!(0, noise.trials() % 64);
+        assert!(noise.std() >= 8.0 * f64::sqrt(2.0 * f64::ln(1.25 / 1e-7)));
+
+        // less privacy budget requires more noise
+        assert!(BinomialNoise::new(0.5, 1e-7, 8).unwrap().trials() > noise.trials());
+        assert!(BinomialNoise::new(1.0, 1e-7, 16).unwrap().trials() > noise.trials());
+
+        // little noise still requires some trials
+        assert_eq!(64, BinomialNoise::new(100.0, 0.5, 1).unwrap().trials());
+    }
+
+    #[test]
+    fn too_much_noise() {
+        assert!(matches!(
+            BinomialNoise::new(1e-6, 1e-7, 128),
+            Err(Error::TooMuchNoise(_))
+        ));
+        assert!(matches!(
+            BinomialNoise::new(0.0, 1e-7, 1),
+            Err(Error::BadEpsilon(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn noise_distribution() {
+        const COUNT: usize = 1000;
+        let noise = BinomialNoise::new(10.0, 1e-6, 1).unwrap();
+        assert_eq!(64, noise.trials());
+
+        let world = TestWorld::default();
+        let result: Vec<Fp32BitPrime> = world
+            .semi_honest((), |ctx, ()| async move {
+                let mut values = vec![Replicated::<Fp32BitPrime>::ZERO; COUNT];
+                add_noise(&ctx, noise, &mut values);
+                values
+            })
+            .await
+            .reconstruct();
+
+        #[allow(clippy::cast_precision_loss)]
+        let samples = result
+            .iter()
+            .map(|v| {
+                let v = i64::try_from(v.as_u128()).unwrap();
+                let p = i64::from(Fp32BitPrime::PRIME);
+                (if v > p / 2 { v - p } else { v }) as f64
+            })
+            .collect::<Vec<_>>();
+        #[allow(clippy::cast_precision_loss)]
+        let mean = samples.iter().sum::<f64>() / COUNT as f64;
+        #[allow(clippy::cast_precision_loss)]
+        let variance = samples.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / COUNT as f64;
+
+        // three samples of 64 trials each are added to every value, so the variance is 48
+        assert!(mean.abs() < 1.5, "mean {mean} is too far from 0");
+        assert!(
+            (38.0..58.0).contains(&variance),
+            "variance {variance} is too far from 48"
+        );
+    }
+}
@@ -0,0 +1,151 @@
+//! Lagrange interpolation of polynomials given by their values at `0, 1, .., n - 1`.
+use crate::ff::{Field, PrimeField};
+
+/// Returns the multiplicative inverse of x, `x^(p - 2)`.
+///
+/// ## Panics
+/// If x is zero.
+#[must_use]
+pub fn invert<F: PrimeField>(x: F) -> F {
+    assert_ne!(F::ZERO, x, "zero has no inverse");
+    let prime: u128 = F::PRIME.into();
+    let mut e = prime - 2;
+    let (mut base, mut result) = (x, F::ONE);
+    while e > 0 {
+        if e & 1 == 1 {
+            result *= base;
+        }
+        base *= base;
+        e >>= 1;
+    }
+    result
+}
+
+/// Evaluates polynomials of degree less than `n` anywhere, given their values at the first `n`
+/// integers.
+#[derive(Clone, Debug)]
+pub struct Lagrange<F: PrimeField> {
+    /// `1 / Π_{s ≠ t} (t - s)` for every node `t`.
+    denominators: Vec<F>,
+}
+
+impl<F: PrimeField> Lagrange<F> {
+    /// ## Panics
+    /// If `n` is zero or not smaller than the prime, nodes would not be distinct.
+    #[must_use]
+    pub fn new(n: usize) -> Self {
+        assert!(n > 0, "no nodes to interpolate");
+        assert!(
+            u128::try_from(n).unwrap() < F::PRIME.into(),
+            "{n} nodes are not distinct"
+        );
+        let node = |t: usize| F::truncate_from(u128::try_from(t).unwrap());
+        let denominators = (0..n)
+            .map(|t| {
+                invert(
+                    (0..n)
+                        .filter(|&s| s != t)
+                        .fold(F::ONE, |acc, s| acc * (node(t) - node(s))),
+                )
+            })
+            .collect();
+        Self { denominators }
+    }
+
+    #[must_use]
+    pub fn nodes(&self) -> usize {
+        self.denominators.len()
+    }
+
+    /// Returns `w` such that `p(x) = Σ w_t p(t)` for every polynomial `p` of degree less than
+    /// [`Self::nodes`]. Products of `x - s` for all nodes but `t` are computed from prefix and
+    /// suffix products, so `x` may be a node as well.
+    #[must_use]
+    pub fn weights(&self, x: F) -> Vec<F> {
+        let mut terms = Vec::with_capacity(self.nodes());
+        let mut node = F::ZERO;
+        for _ in 0..self.nodes() {
+            terms.push(x - node);
+            node += F::ONE;
+        }
+        // `suffix[t]` is the product of terms of all nodes after `t`.
+        let mut suffix = vec![F::ONE; self.nodes()];
+        for t in (1..self.nodes()).rev() {
+            suffix[t - 1] = suffix[t] * terms[t];
+        }
+        let mut prefix = F::ONE;
+        terms
+            .iter()
+            .zip(suffix)
+            .zip(&self.denominators)
+            .map(|((&term, suffix), &denominator)| {
+                let weight = prefix * suffix * denominator;
+                prefix *= term;
+                weight
+            })
+            .collect()
+    }
+
+    /// Returns `p(x)`, where `values` are `p(0), p(1), ..`.
+    ///
+    /// ## Panics
+    /// If there are not as many values as there are nodes.
+    #[must_use]
+    pub fn eval(&self, values: &[F], x: F) -> F {
+        assert_eq!(self.nodes(), values.len());
+        dot(&self.weights(x), values)
+    }
+}
+
+/// Returns `Σ a_i b_i`.
+pub(super) fn dot<F: Field>(a: &[F], b: &[F]) -> F {
+    a.iter().zip(b).fold(F::ZERO, |acc, (&a, &b)| acc + a * b)
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use super::{invert, Lagrange};
+    use crate::{
+        ff::{Field, Fp31, Fp32BitPrime},
+        rand::{thread_rng, Rng},
+    };
+
+    #[test]
+    fn inverse() {
+        for x in 1..31_u128 {
+            let x = Fp31::truncate_from(x);
+            assert_eq!(Fp31::ONE, x * invert(x));
+        }
+        let x = thread_rng().gen::<Fp32BitPrime>();
+        if x != Fp32BitPrime::ZERO {
+            assert_eq!(Fp32BitPrime::ONE, x * invert(x));
+        }
+    }
+
+    #[test]
+    fn interpolates_polynomials() {
+        let mut rng = thread_rng();
+        // p(x) = 3 + 5x + 7x^3
+        let p = |x: Fp32BitPrime| {
+            Fp32BitPrime::truncate_from(3_u128)
+                + Fp32BitPrime::truncate_from(5_u128) * x
+                + Fp32BitPrime::truncate_from(7_u128) * x * x * x
+        };
+        let lagrange = Lagrange::<Fp32BitPrime>::new(4);
+        let values = (0..4_u128)
+            .map(|t| p(Fp32BitPrime::truncate_from(t)))
+            .collect::<Vec<_>>();
+
+        for _ in 0..10 {
+            let x = rng.gen::<Fp32BitPrime>();
+            assert_eq!(p(x), lagrange.eval(&values, x));
+        }
+        // nodes evaluate to their own values
+        for t in 0..4_u128 {
+            assert_eq!(
+                values[usize::try_from(t).unwrap()],
+                lagrange.eval(&values, Fp32BitPrime::truncate_from(t))
+            );
+        }
+    }
+}
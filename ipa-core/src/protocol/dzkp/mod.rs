@@ -0,0 +1,353 @@
+//! Distributed zero-knowledge proofs of binary multiplications.
+//!
+//! This follows "Zero-Knowledge Proofs on Secret-Shared Data via Fully Linear PCPs"
+//! by D. Boneh, E. Boyle, H. Corrigan-Gibbs, N. Gilboa, and Y. Ishai
+//! <https://eprint.iacr.org/2019/188.pdf>, and its use to verify replicated multiplications in
+//! "Practical Fully Secure Three-Party Computation via Sublinear Distributed Zero-Knowledge Proofs"
+//! by E. Boyle, N. Gilboa, Y. Ishai, and A. Nof <https://eprint.iacr.org/2019/1390.pdf>.
+//!
+//! Every helper proves that the messages it sent while multiplying bits are the ones the protocol
+//! asks for. The helper to its left and the helper to its right verify the proof together. Each of
+//! them knows half of what the prover used, and neither of them learns anything about the other
+//! half. The statement for one multiplication, in the prover's view, is
+//! `z_right = x_left·y_right ⊕ x_right·y_left ⊕ prss_left`. It is lifted into a prime field with
+//! `(-1)^(a ⊕ b) = (-1)^a (-1)^b` and `(-1)^(ab) = 1 - 2ab`, which turns it into `⟨u, v⟩ = 1` for
+//! vectors `u`, known to the verifier on the left, and `v`, known to the verifier on the right,
+//! of four entries each. All statements are weighed by random values and added up into a single
+//! inner product, `⟨u, v⟩ = Σ weights`.
+//!
+//! Proofs are recursive. Every round splits `u` and `v` into chunks of [`CHUNK`] values and
+//! interpolates a polynomial of degree `CHUNK - 1` through each of them, `f_j` for `u` and `g_j`
+//! for `v`. The prover secret-shares `p = Σ f_j g_j` between the verifiers, who check that
+//! `p(0) + .. + p(CHUNK - 1)` is the inner product it claims to be, and agree on a random point
+//! `r`. Replacing every chunk by `f_j(r)` and `g_j(r)` leaves `CHUNK` times shorter vectors whose
+//! inner product is `p(r)`. Once they are shorter than a chunk, random values the prover shares
+//! with each verifier are put in front of them, which masks the values verifiers reveal to each
+//! other in the last round: `u` and `v` compressed into one value each, their product has to be
+//! `p(r)`.
+//!
+//! Every round is sound up to `2(CHUNK - 1) / |F|`, the chance of `r` being a root of the
+//! difference of two distinct polynomials of degree `2(CHUNK - 1)`.
+pub mod lagrange;
+
+use lagrange::{dot, Lagrange};
+
+use crate::ff::PrimeField;
+
+/// Number of values every round of a proof compresses into one.
+pub const CHUNK: usize = 8;
+
+/// Number of values of the polynomials provers share in every round.
+pub const PROOF_LENGTH: usize = 2 * CHUNK - 1;
+
+/// Multiplication of two bits, as one helper sees it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BitMultiplication {
+    pub x_left: bool,
+    pub x_right: bool,
+    pub y_left: bool,
+    pub y_right: bool,
+    /// Randomness shared with the helper to the left.
+    pub prss_left: bool,
+    /// Randomness shared with the helper to the right.
+    pub prss_right: bool,
+    /// Sent to the helper to the right.
+    pub z_right: bool,
+    /// Received from the helper to the left.
+    pub z_left: bool,
+}
+
+/// Returns `-1` if `bit` is set and `1` otherwise.
+fn sign<F: PrimeField>(bit: bool) -> F {
+    if bit {
+        F::ZERO - F::ONE
+    } else {
+        F::ONE
+    }
+}
+
+fn lift<F: PrimeField>(bit: bool) -> F {
+    if bit {
+        F::ONE
+    } else {
+        F::ZERO
+    }
+}
+
+/// Entries of `u` for a multiplication, computed from `x_left`, `y_left` and `prss_left` of the
+/// prover and weighed by `weight`.
+fn u_entries<F: PrimeField>(prss: bool, x: bool, y: bool, weight: F) -> [F; 4] {
+    let sigma = weight * sign::<F>(prss);
+    let two = F::ONE + F::ONE;
+    [
+        sigma,
+        F::ZERO - two * sigma * lift(x),
+        F::ZERO - two * sigma * lift(y),
+        two * two * sigma * lift(x && y),
+    ]
+}
+
+/// Entries of `v` for a multiplication, computed from `x_right`, `y_right` and `z_right` of the
+/// prover.
+fn v_entries<F: PrimeField>(z: bool, x: bool, y: bool) -> [F; 4] {
+    let delta = sign::<F>(z);
+    [
+        delta,
+        delta * lift(y),
+        delta * lift(x),
+        delta * lift(x && y),
+    ]
+}
+
+/// Returns `u` of the proof for `multiplications` a helper made, along with `v`.
+#[must_use]
+pub fn prover_vectors<F: PrimeField>(
+    multiplications: &[BitMultiplication],
+    weights: &[F],
+) -> (Vec<F>, Vec<F>) {
+    (
+        multiplications
+            .iter()
+            .zip(weights)
+            .flat_map(|(m, &w)| u_entries(m.prss_left, m.x_left, m.y_left, w))
+            .collect(),
+        multiplications
+            .iter()
+            .flat_map(|m| v_entries(m.z_right, m.x_right, m.y_right))
+            .collect(),
+    )
+}
+
+/// Returns `u` of the proof the helper to the right of the one that saw `multiplications` makes.
+#[must_use]
+pub fn left_verifier_vector<F: PrimeField>(
+    multiplications: &[BitMultiplication],
+    weights: &[F],
+) -> Vec<F> {
+    multiplications
+        .iter()
+        .zip(weights)
+        .flat_map(|(m, &w)| u_entries(m.prss_right, m.x_right, m.y_right, w))
+        .collect()
+}
+
+/// Returns `v` of the proof the helper to the left of the one that saw `multiplications` makes.
+#[must_use]
+pub fn right_verifier_vector<F: PrimeField>(multiplications: &[BitMultiplication]) -> Vec<F> {
+    multiplications
+        .iter()
+        .flat_map(|m| v_entries(m.z_left, m.x_left, m.y_left))
+        .collect()
+}
+
+/// Number of rounds that compress vectors of `len` values, before they are short enough to be
+/// masked.
+#[must_use]
+pub fn rounds(mut len: usize) -> usize {
+    let mut rounds = 0;
+    while len >= CHUNK {
+        len = len.div_ceil(CHUNK);
+        rounds += 1;
+    }
+    rounds
+}
+
+/// Puts `mask` in front of `values`, which must be shorter than a chunk, and pads them to one.
+///
+/// ## Panics
+/// If there are too many values.
+#[must_use]
+pub fn mask<F: PrimeField>(values: &[F], mask: F) -> Vec<F> {
+    assert!(values.len() < CHUNK, "{} values don't fit", values.len());
+    let mut masked = vec![F::ZERO; CHUNK];
+    masked[0] = mask;
+    masked[1..=values.len()].copy_from_slice(values);
+    masked
+}
+
+/// Sum of the values of `p` that must add up to the inner product it proves. The first value
+/// belongs to the masks in the last round.
+#[must_use]
+pub fn claimed_sum<F: PrimeField>(p: &[F], masked: bool) -> F {
+    p[usize::from(masked)..CHUNK]
+        .iter()
+        .fold(F::ZERO, |acc, &v| acc + v)
+}
+
+/// Interpolation tables every proof round uses.
+#[derive(Clone, Debug)]
+pub struct Compression<F: PrimeField> {
+    /// Polynomials through chunks.
+    chunk: Lagrange<F>,
+    /// Products of those polynomials.
+    proof: Lagrange<F>,
+    /// Weights that extend polynomials through chunks to the nodes of their products that chunks
+    /// don't have.
+    extension: Vec<Vec<F>>,
+}
+
+impl<F: PrimeField> Default for Compression<F> {
+    fn default() -> Self {
+        let chunk = Lagrange::new(CHUNK);
+        let extension = (CHUNK..PROOF_LENGTH)
+            .map(|x| chunk.weights(F::truncate_from(u128::try_from(x).unwrap())))
+            .collect();
+        Self {
+            chunk,
+            proof: Lagrange::new(PROOF_LENGTH),
+            extension,
+        }
+    }
+}
+
+impl<F: PrimeField> Compression<F> {
+    /// Returns the values of `p = Σ f_j g_j` at `0, 1, .., PROOF_LENGTH - 1`. Vectors are padded
+    /// with zeros to whole chunks.
+    ///
+    /// ## Panics
+    /// If the vectors are not as long as each other.
+    #[must_use]
+    pub fn prove(&self, u: &[F], v: &[F]) -> Vec<F> {
+        assert_eq!(u.len(), v.len());
+        let mut p = vec![F::ZERO; PROOF_LENGTH];
+        for (f, g) in u.chunks(CHUNK).zip(v.chunks(CHUNK)) {
+            let (f, g) = (pad(f), pad(g));
+            for (p, (&f, &g)) in p.iter_mut().zip(f.iter().zip(&g)) {
+                *p += f * g;
+            }
+            for (e, weights) in self.extension.iter().enumerate() {
+                p[CHUNK + e] += dot(weights, &f) * dot(weights, &g);
+            }
+        }
+        p
+    }
+
+    /// Replaces every chunk of `values` by the value of the polynomial through it at `r`.
+    #[must_use]
+    pub fn fold(&self, values: &[F], r: F) -> Vec<F> {
+        let weights = self.chunk.weights(r);
+        values
+            .chunks(CHUNK)
+            .map(|chunk| dot(&weights, &pad(chunk)))
+            .collect()
+    }
+
+    /// Returns `p(r)`.
+    #[must_use]
+    pub fn eval(&self, p: &[F], r: F) -> F {
+        self.proof.eval(p, r)
+    }
+}
+
+fn pad<F: PrimeField>(chunk: &[F]) -> Vec<F> {
+    let mut padded = chunk.to_vec();
+    padded.resize(CHUNK, F::ZERO);
+    padded
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use super::{
+        claimed_sum, left_verifier_vector, mask, prover_vectors, right_verifier_vector, rounds,
+        BitMultiplication, Compression, CHUNK,
+    };
+    use crate::{
+        ff::{Field, Fp32BitPrime},
+        protocol::dzkp::lagrange::dot,
+        rand::{thread_rng, Rng},
+    };
+
+    /// Views three helpers have of the multiplication of `x` and `y`, for random shares and
+    /// randomness.
+    fn multiply<R: Rng>(x: bool, y: bool, rng: &mut R) -> [BitMultiplication; 3] {
+        let mut share = |v: bool| {
+            let (a, b) = (rng.gen::<bool>(), rng.gen::<bool>());
+            [a, b, v ^ a ^ b]
+        };
+        let (x, y) = (share(x), share(y));
+        // randomness shared by helpers `i` and `i + 1`
+        let prss = [rng.gen::<bool>(), rng.gen::<bool>(), rng.gen::<bool>()];
+        let mut views: [BitMultiplication; 3] = std::array::from_fn(|i| {
+            let (right, left) = ((i + 1) % 3, (i + 2) % 3);
+            BitMultiplication {
+                x_left: x[i],
+                x_right: x[right],
+                y_left: y[i],
+                y_right: y[right],
+                prss_left: prss[left],
+                prss_right: prss[i],
+                z_right: (x[i] & y[right]) ^ (x[right] & y[i]) ^ prss[left],
+                z_left: false,
+            }
+        });
+        let sent = views.map(|view| view.z_right);
+        for (i, view) in views.iter_mut().enumerate() {
+            view.z_left = sent[(i + 2) % 3];
+        }
+        views
+    }
+
+    fn weights(n: usize) -> Vec<Fp32BitPrime> {
+        let mut rng = thread_rng();
+        (0..n).map(|_| rng.gen()).collect()
+    }
+
+    #[test]
+    fn statement() {
+        let mut rng = thread_rng();
+        for (x, y) in [(false, false), (false, true), (true, false), (true, true)] {
+            let views = multiply(x, y, &mut rng);
+            for (i, view) in views.iter().enumerate() {
+                let weight = weights(1);
+                let (u, v) = prover_vectors(std::slice::from_ref(view), &weight);
+                assert_eq!(weight[0], dot(&u, &v));
+                // verifiers know the same vectors
+                assert_eq!(u, left_verifier_vector(&views[(i + 2) % 3..][..1], &weight));
+                assert_eq!(v, right_verifier_vector(&views[(i + 1) % 3..][..1]));
+
+                let mut cheat = *view;
+                cheat.z_right = !cheat.z_right;
+                let (u, v) = prover_vectors(&[cheat], &weight);
+                assert_eq!(Fp32BitPrime::ZERO - weight[0], dot(&u, &v));
+            }
+        }
+    }
+
+    #[test]
+    fn compression_keeps_inner_product() {
+        let compression = Compression::<Fp32BitPrime>::default();
+        let (u, v) = (weights(45), weights(45));
+        let p = compression.prove(&u, &v);
+        assert_eq!(dot(&u, &v), claimed_sum(&p, false));
+
+        let r = thread_rng().gen::<Fp32BitPrime>();
+        let (u, v) = (compression.fold(&u, r), compression.fold(&v, r));
+        assert_eq!(45_usize.div_ceil(CHUNK), u.len());
+        assert_eq!(compression.eval(&p, r), dot(&u, &v));
+    }
+
+    #[test]
+    fn masked_round() {
+        let compression = Compression::<Fp32BitPrime>::default();
+        let (u, v) = (weights(5), weights(5));
+        let masks = weights(2);
+        let (u_masked, v_masked) = (mask(&u, masks[0]), mask(&v, masks[1]));
+        let p = compression.prove(&u_masked, &v_masked);
+        assert_eq!(dot(&u, &v), claimed_sum(&p, true));
+
+        let r = thread_rng().gen::<Fp32BitPrime>();
+        let (u, v) = (
+            compression.fold(&u_masked, r),
+            compression.fold(&v_masked, r),
+        );
+        assert_eq!(compression.eval(&p, r), u[0] * v[0]);
+    }
+
+    #[test]
+    fn number_of_rounds() {
+        assert_eq!(0, rounds(4));
+        assert_eq!(1, rounds(CHUNK));
+        assert_eq!(1, rounds(CHUNK * (CHUNK - 1)));
+        assert_eq!(2, rounds(CHUNK * CHUNK));
+    }
+}
@@ -838,6 +838,7 @@ pub mod tests {
                     attribution_window_seconds: ATTRIBUTION_WINDOW_SECONDS,
                     num_multi_bits: NUM_MULTI_BITS,
                     plaintext_match_keys: true,
+                    ..Default::default()
                 },
                 security,
             )
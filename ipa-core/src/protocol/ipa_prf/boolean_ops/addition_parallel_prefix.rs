@@ -0,0 +1,206 @@
+use std::iter::zip;
+
+use futures::future::try_join;
+use ipa_macros::Step;
+
+use crate::{
+    error::Error,
+    ff::{ArrayAccess, CustomArray, Field},
+    protocol::{basics::SecureMul, context::Context, step::BitOpStep, RecordId},
+    secret_sharing::{replicated::semi_honest::AdditiveShare, WeakSharedValue},
+    seq_join::SeqJoin,
+};
+
+#[derive(Step)]
+pub(crate) enum Step {
+    Generate,
+    Propagate,
+    #[dynamic(8)]
+    PrefixLevel(usize),
+}
+
+/// Non-saturated unsigned integer addition using a Kogge-Stone parallel prefix adder.
+/// This function adds y to x.
+/// The output has same length as x.
+/// Indices of y beyond the length of x are ignored, but
+/// the final carry is returned
+///
+/// Unlike the ripple-carry adder in [`integer_add`], which needs as many communication rounds as
+/// there are bits in x, this adder needs `1 + ceil(log2(n))` rounds for `n` bits. The price is
+/// `O(n log n)` multiplications instead of `n`, so it pays off when latency matters more than
+/// bandwidth.
+///
+/// Bit `i` generates a carry if `g_i = x_i & y_i` and propagates an incoming carry if
+/// `p_i = x_i ⊕ y_i`. At level `k` of the prefix tree, with `d = 2^k`, group signals are combined
+/// with the ones `d` bits below: `G_i = G_i ⊕ (P_i & G_(i-d))` and `P_i = P_i & P_(i-d)`. After the
+/// last level, `G_i` is the carry out of bit `i` and the sum is `s_i = p_i ⊕ G_(i-1)`.
+///
+/// [`integer_add`]: super::addition_sequential::integer_add
+///
+/// # Errors
+/// propagates errors from multiply
+pub async fn integer_add_parallel_prefix<C, XS, YS>(
+    ctx: C,
+    record_id: RecordId,
+    x: &AdditiveShare<XS>,
+    y: &AdditiveShare<YS>,
+) -> Result<(AdditiveShare<XS>, AdditiveShare<XS::Element>), Error>
+where
+    C: Context,
+    for<'a> &'a AdditiveShare<XS>: IntoIterator<Item = AdditiveShare<XS::Element>>,
+    YS: WeakSharedValue + CustomArray<Element = XS::Element>,
+    XS: WeakSharedValue + CustomArray + Field,
+    XS::Element: Field,
+{
+    let x = x.into_iter().collect::<Vec<_>>();
+    let y = (0..x.len())
+        .map(|i| y.get(i).unwrap_or(AdditiveShare::ZERO))
+        .collect::<Vec<_>>();
+    let n = x.len();
+
+    let p = zip(&x, &y).map(|(x, y)| x + y).collect::<Vec<_>>();
+    let generate_ctx = ctx.narrow(&Step::Generate);
+    let mut g = ctx
+        .parallel_join(
+            zip(&x, &y).enumerate().map(|(i, (x, y))| {
+                x.multiply(y, generate_ctx.narrow(&BitOpStep::from(i)), record_id)
+            }),
+        )
+        .await?;
+
+    let mut group_p = p.clone();
+    let mut level = 0;
+    let mut d = 1;
+    while d < n {
+        let level_ctx = ctx.narrow(&Step::PrefixLevel(level));
+        let g_ctx = level_ctx.narrow(&Step::Generate);
+        let p_ctx = level_ctx.narrow(&Step::Propagate);
+        // Group propagate signals are not needed after the last level.
+        let last_level = 2 * d >= n;
+
+        let (g_carried, p_combined) = try_join(
+            ctx.parallel_join((d..n).map(|i| {
+                group_p[i].multiply(&g[i - d], g_ctx.narrow(&BitOpStep::from(i)), record_id)
+            })),
+            ctx.parallel_join((d..n).filter(|_| !last_level).map(|i| {
+                group_p[i].multiply(
+                    &group_p[i - d],
+                    p_ctx.narrow(&BitOpStep::from(i)),
+                    record_id,
+                )
+            })),
+        )
+        .await?;
+
+        for (i, v) in (d..n).zip(g_carried) {
+            g[i] += v;
+        }
+        for (i, v) in (d..n).zip(p_combined) {
+            group_p[i] = v;
+        }
+
+        level += 1;
+        d *= 2;
+    }
+
+    let mut sum = AdditiveShare::<XS>::ZERO;
+    for (i, p_i) in p.into_iter().enumerate() {
+        if i == 0 {
+            sum.set(i, p_i);
+        } else {
+            sum.set(i, p_i + &g[i - 1]);
+        }
+    }
+    let carry = g.pop().unwrap_or(AdditiveShare::ZERO);
+
+    Ok((sum, carry))
+}
+
+#[cfg(all(test, unit_test))]
+mod test {
+    use rand::Rng;
+
+    use crate::{
+        ff::{
+            boolean_array::{BA3, BA32, BA64},
+            Field,
+        },
+        protocol,
+        protocol::{
+            context::Context,
+            ipa_prf::boolean_ops::addition_parallel_prefix::integer_add_parallel_prefix,
+        },
+        rand::thread_rng,
+        test_executor::run,
+        test_fixture::{Reconstruct, Runner, TestWorld},
+    };
+
+    /// testing correctness of parallel prefix addition
+    #[test]
+    fn semi_honest_add() {
+        run(|| async move {
+            let world = TestWorld::default();
+
+            let mut rng = thread_rng();
+
+            let x_ba64 = rng.gen::<BA64>();
+            let y_ba32 = rng.gen::<BA32>();
+            let x = x_ba64.as_u128();
+            let y = y_ba32.as_u128();
+
+            let expected = (x + y) % (1 << 64);
+            let expected_carry = (x + y) >> 64 & 1;
+
+            let (result, carry) = world
+                .semi_honest((x_ba64, y_ba32), |ctx, x_y| async move {
+                    integer_add_parallel_prefix::<_, BA64, BA32>(
+                        ctx.set_total_records(1),
+                        protocol::RecordId(0),
+                        &x_y.0,
+                        &x_y.1,
+                    )
+                    .await
+                    .unwrap()
+                })
+                .await
+                .reconstruct();
+            assert_eq!(
+                (x, y, result.as_u128(), carry.as_u128()),
+                (x, y, expected, expected_carry)
+            );
+        });
+    }
+
+    /// exhaustive test on a width that is not a power of two
+    #[test]
+    fn semi_honest_add_all_ba3() {
+        run(|| async move {
+            let world = TestWorld::default();
+
+            for x in 0..8_u128 {
+                for y in 0..8_u128 {
+                    let (result, carry) = world
+                        .semi_honest(
+                            (BA3::truncate_from(x), BA3::truncate_from(y)),
+                            |ctx, x_y| async move {
+                                integer_add_parallel_prefix::<_, BA3, BA3>(
+                                    ctx.set_total_records(1),
+                                    protocol::RecordId(0),
+                                    &x_y.0,
+                                    &x_y.1,
+                                )
+                                .await
+                                .unwrap()
+                            },
+                        )
+                        .await
+                        .reconstruct();
+                    assert_eq!(
+                        (x, y, result.as_u128(), carry.as_u128()),
+                        (x, y, (x + y) % 8, (x + y) >> 3)
+                    );
+                }
+            }
+        });
+    }
+}
@@ -3,9 +3,9 @@ use ipa_macros::Step;
 
 use crate::{
     error::Error,
-    ff::{ArrayAccess, CustomArray, Field},
+    ff::{boolean::Boolean, ArrayAccess, CustomArray, Field},
     protocol::{basics::SecureMul, context::Context, step::BitOpStep, RecordId},
-    secret_sharing::{replicated::semi_honest::AdditiveShare, WeakSharedValue},
+    secret_sharing::{replicated::semi_honest::AdditiveShare, BitDecomposed, WeakSharedValue},
 };
 
 #[cfg(all(test, unit_test))]
@@ -41,6 +41,128 @@ where
     Ok((sum, carry))
 }
 
+/// Vectorized non-saturated unsigned integer addition
+/// Adds y to x for up to `V::BITS` records at once. Inputs are bit-sliced: lane `j` of `x[i]`
+/// holds bit `i` of record `j`, see [`to_bit_slices`]. Every bit position costs a single
+/// multiplication for the whole chunk instead of one multiplication per record, so the number of
+/// messages sent is `V::BITS` times smaller than calling [`integer_add`] for each record.
+///
+/// This only pays off for independent additions that are all known upfront, such as summing
+/// buckets in [`aggregate_boolean_buckets`]. Attribution adds rows of a user one at a time, every
+/// row needs the sum of the previous one, so it keeps using [`integer_add`].
+///
+/// The output has same length as x.
+/// Indices of y beyond the length of x are ignored, but
+/// the final carry of every lane is returned
+///
+/// # Errors
+/// propagates errors from multiply
+///
+/// [`aggregate_boolean_buckets`]: crate::protocol::ipa_prf::prf_sharding::bucket::aggregate_boolean_buckets
+pub async fn integer_add_vectorized<C, V>(
+    ctx: C,
+    record_id: RecordId,
+    x: &BitDecomposed<AdditiveShare<V>>,
+    y: &BitDecomposed<AdditiveShare<V>>,
+) -> Result<(BitDecomposed<AdditiveShare<V>>, AdditiveShare<V>), Error>
+where
+    C: Context,
+    V: Field,
+{
+    let mut carry = AdditiveShare::<V>::ZERO;
+    let mut sum = Vec::with_capacity(x.len());
+    for (i, v) in x.iter().enumerate() {
+        sum.push(
+            bit_adder(
+                ctx.narrow(&BitOpStep::from(i)),
+                record_id,
+                v,
+                y.get(i),
+                &mut carry,
+            )
+            .await?,
+        );
+    }
+
+    Ok((BitDecomposed::new(sum), carry))
+}
+
+/// Adds `y[j]` to `x[j]` for every record `j` in a chunk of up to `V::BITS` records, using
+/// [`integer_add_vectorized`]. The whole chunk is processed under a single `record_id`.
+/// Returns the sum and the final carry for every record.
+///
+/// # Errors
+/// propagates errors from multiply
+/// # Panics
+/// If `x` and `y` have different lengths, or if there are more records than lanes in `V`.
+pub async fn integer_add_chunk<C, XS, YS, V>(
+    ctx: C,
+    record_id: RecordId,
+    x: &[AdditiveShare<XS>],
+    y: &[AdditiveShare<YS>],
+) -> Result<(Vec<AdditiveShare<XS>>, Vec<AdditiveShare<Boolean>>), Error>
+where
+    C: Context,
+    XS: WeakSharedValue + CustomArray<Element = Boolean>,
+    YS: WeakSharedValue + CustomArray<Element = Boolean>,
+    V: CustomArray<Element = Boolean> + Field,
+{
+    assert_eq!(x.len(), y.len());
+    let (sum, carry) = integer_add_vectorized(
+        ctx,
+        record_id,
+        &to_bit_slices::<XS, V>(x),
+        &to_bit_slices::<YS, V>(y),
+    )
+    .await?;
+
+    let carries = (0..x.len()).map(|j| carry.get(j).unwrap()).collect();
+    Ok((from_bit_slices(&sum, x.len()), carries))
+}
+
+/// Transposes a chunk of records into bit-sliced form: lane `j` of the `i`-th output holds bit `i`
+/// of record `j`. This is a local operation, left and right shares are transposed independently.
+///
+/// # Panics
+/// If there are more records than lanes in `V`, or if `S` is wider than 64 bits.
+pub fn to_bit_slices<S, V>(records: &[AdditiveShare<S>]) -> BitDecomposed<AdditiveShare<V>>
+where
+    S: WeakSharedValue + CustomArray<Element = Boolean>,
+    V: WeakSharedValue + CustomArray<Element = Boolean>,
+{
+    assert!(records.len() <= usize::try_from(V::BITS).unwrap());
+    BitDecomposed::new((0..usize::try_from(S::BITS).unwrap()).map(|i| {
+        let mut slice = AdditiveShare::<V>::ZERO;
+        for (j, record) in records.iter().enumerate() {
+            slice.set(j, record.get(i).unwrap());
+        }
+        slice
+    }))
+}
+
+/// Reverses [`to_bit_slices`], returning the first `count` records.
+///
+/// # Panics
+/// If `count` exceeds the number of lanes in `V`.
+pub fn from_bit_slices<S, V>(
+    slices: &BitDecomposed<AdditiveShare<V>>,
+    count: usize,
+) -> Vec<AdditiveShare<S>>
+where
+    S: WeakSharedValue + CustomArray<Element = Boolean>,
+    V: WeakSharedValue + CustomArray<Element = Boolean>,
+{
+    (0..count)
+        .map(|j| {
+            let mut record = AdditiveShare::<S>::ZERO;
+            for (i, slice) in slices.iter().enumerate() {
+                record.set(i, slice.get(j).unwrap());
+            }
+            record
+        })
+        .collect()
+}
+
 /// saturated unsigned integer addition
 /// currently not used, but it is tested
 /// adds y to x, Output has same length as x (we dont seem to need support for different length)
@@ -180,7 +302,9 @@ mod test {
         protocol,
         protocol::{
             context::Context,
-            ipa_prf::boolean_ops::addition_sequential::{integer_add, integer_sat_add},
+            ipa_prf::boolean_ops::addition_sequential::{
+                integer_add, integer_add_chunk, integer_sat_add,
+            },
         },
         rand::thread_rng,
         test_executor::run,
@@ -311,4 +435,49 @@ mod test {
             );
         });
     }
+
+    /// testing correctness of vectorized addition, including a chunk that does not fill all lanes
+    #[test]
+    fn semi_honest_add_chunk() {
+        run(|| async move {
+            let world = TestWorld::default();
+
+            let mut rng = thread_rng();
+
+            for count in [64, 10] {
+                let x = (0..count).map(|_| rng.gen::<BA32>()).collect::<Vec<_>>();
+                let y = (0..count).map(|_| rng.gen::<BA32>()).collect::<Vec<_>>();
+
+                let expected = x
+                    .iter()
+                    .zip(&y)
+                    .map(|(x, y)| {
+                        let sum = x.as_u128() + y.as_u128();
+                        (sum % (1 << 32), sum >> 32)
+                    })
+                    .unzip::<_, _, Vec<_>, Vec<_>>();
+
+                let (result, carry) = world
+                    .semi_honest((x.into_iter(), y.into_iter()), |ctx, (x, y)| async move {
+                        integer_add_chunk::<_, BA32, BA32, BA64>(
+                            ctx.set_total_records(1),
+                            protocol::RecordId(0),
+                            &x,
+                            &y,
+                        )
+                        .await
+                        .unwrap()
+                    })
+                    .await
+                    .reconstruct();
+                assert_eq!(
+                    (
+                        result.iter().map(Field::as_u128).collect::<Vec<_>>(),
+                        carry.iter().map(Field::as_u128).collect::<Vec<_>>()
+                    ),
+                    expected
+                );
+            }
+        });
+    }
 }
@@ -1,20 +1,28 @@
-#[cfg(all(test, unit_test))]
+#[cfg(feature = "descriptive-gate")]
 use ipa_macros::Step;
 
-#[cfg(all(test, unit_test))]
+#[cfg(feature = "descriptive-gate")]
 use crate::ff::Expand;
 use crate::{
     error::Error,
     ff::{ArrayAccess, CustomArray, Field},
-    protocol::{basics::SecureMul, context::Context, step::BitOpStep, RecordId},
+    protocol::{
+        basics::SecureMul,
+        boolean::prefix::{self, prefix_or_array},
+        context::Context,
+        step::BitOpStep,
+        RecordId,
+    },
     secret_sharing::{replicated::semi_honest::AdditiveShare, WeakSharedValue},
 };
 
-#[cfg(all(test, unit_test))]
+#[cfg(feature = "descriptive-gate")]
 #[derive(Step)]
 pub(crate) enum Step {
     SaturatedSubtraction,
     MultiplyWithCarry,
+    SignedOverflow,
+    SelectSaturated,
 }
 
 /// Comparison operation
@@ -45,6 +53,11 @@ where
 
 /// Comparison operation
 /// outputs x>y
+///
+/// x and y differ in the bits where `x + y` is set and the most significant of them decides
+/// which one is larger. Prefix-OR of these bits, taken from the most significant one, has a single
+/// 0 to 1 transition at that bit, so x>y is the bit of x there. This takes `ceil(log2(n)) + 1`
+/// rounds of communication for n-bit x, rather than n rounds of a subtraction circuit.
 /// # Errors
 /// propagates errors from multiply
 pub async fn compare_gt<C, XS, YS>(
@@ -60,11 +73,30 @@ where
     XS: WeakSharedValue + CustomArray + Field,
     XS::Element: Field + std::ops::Not<Output = XS::Element>,
 {
-    // we need to initialize carry to 0 for x>y
-    let mut carry = AdditiveShare::<XS::Element>::ZERO;
-    // we don't care about the subtraction, we just want the carry
-    let _ = subtraction_circuit(ctx, record_id, x, y, &mut carry).await;
-    Ok(carry)
+    let n = prefix::len::<XS>();
+    // both in order from the most significant bit to the least significant one
+    let mut differs = AdditiveShare::<XS>::ZERO;
+    let mut x_bits = AdditiveShare::<XS>::ZERO;
+    for (i, x_i) in x.into_iter().enumerate() {
+        let y_i = y.get(i).unwrap_or(AdditiveShare::ZERO);
+        differs.set(n - 1 - i, &x_i + &y_i);
+        x_bits.set(n - 1 - i, x_i);
+    }
+
+    let differed = prefix_or_array(ctx.clone(), record_id, &differs).await?;
+    // set only at the first bit where x and y differ, if there is one
+    let first_difference = &differed - &prefix::shift_up(&differed, 1);
+    let selected = first_difference
+        .multiply(
+            &x_bits,
+            ctx.narrow(&BitOpStep::from(prefix::rounds(n))),
+            record_id,
+        )
+        .await?;
+
+    Ok(selected
+        .into_iter()
+        .fold(AdditiveShare::<XS::Element>::ZERO, |acc, bit| acc + bit))
 }
 
 /// non-saturated unsigned integer subtraction
@@ -126,6 +158,123 @@ where
         .await
 }
 
+/// Signed comparison operation
+/// outputs x>y, where x and y are two's complement integers of the same length
+/// # Errors
+/// propagates errors from multiply
+#[cfg(feature = "descriptive-gate")]
+pub async fn compare_gt_signed<C, S>(
+    ctx: C,
+    record_id: RecordId,
+    x: &AdditiveShare<S>,
+    y: &AdditiveShare<S>,
+) -> Result<AdditiveShare<S::Element>, Error>
+where
+    C: Context,
+    for<'a> &'a AdditiveShare<S>: IntoIterator<Item = AdditiveShare<S::Element>>,
+    S: CustomArray + Field,
+    S::Element: Field + std::ops::Not<Output = S::Element>,
+{
+    let gt = compare_gt(ctx, record_id, x, y).await?;
+    Ok(fix_signed_comparison(gt, x, y))
+}
+
+/// Signed comparison operation
+/// outputs x>=y, where x and y are two's complement integers of the same length
+/// # Errors
+/// propagates errors from multiply
+#[cfg(feature = "descriptive-gate")]
+pub async fn compare_geq_signed<C, S>(
+    ctx: C,
+    record_id: RecordId,
+    x: &AdditiveShare<S>,
+    y: &AdditiveShare<S>,
+) -> Result<AdditiveShare<S::Element>, Error>
+where
+    C: Context,
+    for<'a> &'a AdditiveShare<S>: IntoIterator<Item = AdditiveShare<S::Element>>,
+    S: CustomArray + Field,
+    S::Element: Field + std::ops::Not<Output = S::Element>,
+{
+    let mut carry = AdditiveShare(S::Element::ONE, S::Element::ONE);
+    let _ = subtraction_circuit(ctx, record_id, x, y, &mut carry).await?;
+    Ok(fix_signed_comparison(carry, x, y))
+}
+
+/// Turns the result of an unsigned comparison into the result of a signed one.
+/// If signs of x and y are the same, both comparisons agree. Otherwise, the negative value
+/// is the larger one when read as unsigned, so the result needs to be flipped.
+/// This is local and does not require any multiplications.
+#[cfg(feature = "descriptive-gate")]
+fn fix_signed_comparison<S>(
+    unsigned: AdditiveShare<S::Element>,
+    x: &AdditiveShare<S>,
+    y: &AdditiveShare<S>,
+) -> AdditiveShare<S::Element>
+where
+    S: CustomArray + Field,
+    S::Element: Field,
+{
+    unsigned + x.sign_bit() + y.sign_bit()
+}
+
+/// saturated signed integer subtraction
+/// subtracts y from x, where x, y and the output are two's complement integers of the same length
+/// when x-y is less than the smallest value the output can hold, it outputs that value,
+/// when x-y is larger than the largest one, it outputs the largest one
+/// # Errors
+/// propagates errors from multiply
+#[cfg(feature = "descriptive-gate")]
+pub async fn integer_sat_sub_signed<C, S>(
+    ctx: C,
+    record_id: RecordId,
+    x: &AdditiveShare<S>,
+    y: &AdditiveShare<S>,
+) -> Result<AdditiveShare<S>, Error>
+where
+    C: Context,
+    for<'a> &'a AdditiveShare<S>: IntoIterator<Item = AdditiveShare<S::Element>>,
+    S: CustomArray + Field,
+    S::Element: Field + std::ops::Not<Output = S::Element>,
+{
+    let mut carry = AdditiveShare(S::Element::ONE, S::Element::ONE);
+    let result = subtraction_circuit(
+        ctx.narrow(&Step::SaturatedSubtraction),
+        record_id,
+        x,
+        y,
+        &mut carry,
+    )
+    .await?;
+
+    // x-y overflows iff x and y have different signs and the sign of the result differs from
+    // the sign of x, i.e. overflow=(sign(x)+sign(y))*(sign(result)+sign(x))
+    let x_sign = x.sign_bit();
+    let overflow = (&x_sign + &y.sign_bit())
+        .multiply(
+            &(result.sign_bit() + &x_sign),
+            ctx.narrow(&Step::SignedOverflow),
+            record_id,
+        )
+        .await?;
+
+    // on overflow, the output saturates towards the sign of x:
+    // MIN (sign bit set, all other bits unset) when x is negative, MAX otherwise
+    let mut saturated = AdditiveShare::<S>::expand(&!x_sign.clone());
+    saturated.set(usize::try_from(S::BITS).unwrap() - 1, x_sign);
+
+    // if overflow==0 {result} else {saturated}:
+    // compute result+overflow*(saturated-result)
+    Ok(&result
+        + &AdditiveShare::<S>::expand(&overflow)
+            .multiply(
+                &(saturated - &result),
+                ctx.narrow(&Step::SelectSaturated),
+                record_id,
+            )
+            .await?)
+}
+
 /// subtraction using bit subtractor
 /// subtracts y from x, Output has same length as x (carries and indices of y too large for x are ignored)
 /// implementing `https://encrypto.de/papers/KSS09.pdf` from Section 3.1/3.2
@@ -210,14 +359,15 @@ mod test {
     use crate::{
         ff::{
             boolean::Boolean,
-            boolean_array::{BA3, BA32, BA5, BA64},
+            boolean_array::{BA3, BA32, BA5, BA64, BA8},
             Expand, Field,
         },
         protocol,
         protocol::{
             context::Context,
             ipa_prf::boolean_ops::comparison_and_subtraction_sequential::{
-                compare_geq, compare_gt, integer_sat_sub, integer_sub,
+                compare_geq, compare_geq_signed, compare_gt, compare_gt_signed, integer_sat_sub,
+                integer_sat_sub_signed, integer_sub,
             },
         },
         rand::thread_rng,
@@ -475,4 +625,101 @@ mod test {
             assert_eq!((x, y, result), (x, y, expected));
         });
     }
+
+    /// reads `BA8` as a two's complement integer
+    fn signed(v: BA8) -> i128 {
+        let v = i128::try_from(v.as_u128()).unwrap();
+        if v >= 128 {
+            v - 256
+        } else {
+            v
+        }
+    }
+
+    /// pairs of signed values that cover sign combinations and both overflow directions
+    fn signed_pairs() -> Vec<(BA8, BA8)> {
+        let mut rng = thread_rng();
+        let mut pairs = [
+            (-128, 127),
+            (127, -128),
+            (-1, 0),
+            (0, -1),
+            (-1, -1),
+            (-100, 100),
+        ]
+        .into_iter()
+        .map(|(x, y): (i128, i128)| {
+            (
+                BA8::truncate_from(u128::try_from(x.rem_euclid(256)).unwrap()),
+                BA8::truncate_from(u128::try_from(y.rem_euclid(256)).unwrap()),
+            )
+        })
+        .collect::<Vec<_>>();
+        pairs.extend((0..10).map(|_| (rng.gen::<BA8>(), rng.gen::<BA8>())));
+        pairs
+    }
+
+    #[test]
+    fn semi_honest_compare_signed() {
+        run(|| async move {
+            let world = TestWorld::default();
+
+            for (x, y) in signed_pairs() {
+                let (gt, geq) = world
+                    .semi_honest((x, y), |ctx, x_y| async move {
+                        let ctx = ctx.set_total_records(1);
+                        (
+                            compare_gt_signed(
+                                ctx.narrow("gt"),
+                                protocol::RecordId(0),
+                                &x_y.0,
+                                &x_y.1,
+                            )
+                            .await
+                            .unwrap(),
+                            compare_geq_signed(
+                                ctx.narrow("geq"),
+                                protocol::RecordId(0),
+                                &x_y.0,
+                                &x_y.1,
+                            )
+                            .await
+                            .unwrap(),
+                        )
+                    })
+                    .await
+                    .reconstruct();
+
+                let (x, y) = (signed(x), signed(y));
+                assert_eq!((x, y, gt), (x, y, Boolean::from(x > y)));
+                assert_eq!((x, y, geq), (x, y, Boolean::from(x >= y)));
+            }
+        });
+    }
+
+    #[test]
+    fn semi_honest_sat_sub_signed() {
+        run(|| async move {
+            let world = TestWorld::default();
+
+            for (x, y) in signed_pairs() {
+                let result = world
+                    .semi_honest((x, y), |ctx, x_y| async move {
+                        integer_sat_sub_signed(
+                            ctx.set_total_records(1),
+                            protocol::RecordId(0),
+                            &x_y.0,
+                            &x_y.1,
+                        )
+                        .await
+                        .unwrap()
+                    })
+                    .await
+                    .reconstruct();
+
+                let (x, y) = (signed(x), signed(y));
+                assert_eq!((x, y, signed(result)), (x, y, (x - y).clamp(-128, 127)));
+            }
+        });
+    }
 }
@@ -0,0 +1,292 @@
+//! Division of boolean-shared integers by divisors known to all helpers.
+//!
+//! Quotients are computed by long division, starting from the most significant bit of the
+//! dividend: the remainder so far is shifted left to take the next bit, and the divisor is
+//! subtracted from it unless that borrows. Whether it borrows is the next bit of the quotient.
+//! Helpers know the divisor, so subtracting it takes a single multiplication per bit of the
+//! remainder, and so does keeping the remainder when the subtraction borrows. The remainder is
+//! never wider than the divisor plus one bit, so dividing `n`-bit integers by a `k`-bit divisor
+//! takes about `2(n - k)(k + 1)` multiplications, and none for bits the remainder can't reach the
+//! divisor with yet.
+//!
+//! This lets helpers compute averages, for instance the average value of a conversion in every
+//! breakdown, once the number of conversions they are averaged over is public.
+use futures::future::try_join_all;
+use ipa_macros::Step;
+
+use crate::{
+    error::Error,
+    ff::{boolean::Boolean, ArrayAccess, CustomArray, Field},
+    protocol::{
+        basics::{SecureMul, ShareKnownValue},
+        context::Context,
+        ipa_prf::boolean_ops::addition_sequential::integer_add,
+        step::BitOpStep,
+        RecordId,
+    },
+    secret_sharing::{replicated::semi_honest::AdditiveShare, WeakSharedValue},
+};
+
+#[derive(Step)]
+pub(crate) enum Step {
+    AddRoundingOffset,
+    Subtract,
+    Select,
+}
+
+/// How quotients are rounded to integers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Rounding {
+    /// Rounds towards zero, like integer division does.
+    #[default]
+    Down,
+    /// Rounds to the nearest integer, halves are rounded up.
+    Nearest,
+    /// Rounds away from zero.
+    Up,
+}
+
+impl Rounding {
+    /// Returns the value added to dividends, so that dividing them by `divisor` and rounding down
+    /// rounds the quotient as requested.
+    fn offset(self, divisor: u128) -> u128 {
+        match self {
+            Self::Down => 0,
+            Self::Nearest => divisor / 2,
+            Self::Up => divisor - 1,
+        }
+    }
+}
+
+/// Integer division by a public divisor
+/// divides x by `divisor` and rounds the quotient as requested. x is unsigned.
+/// # Errors
+/// propagates errors from multiply
+/// # Panics
+/// If `divisor` is zero or does not fit into `S`.
+pub async fn div_public<C, S>(
+    ctx: C,
+    record_id: RecordId,
+    x: &AdditiveShare<S>,
+    divisor: u128,
+    rounding: Rounding,
+) -> Result<AdditiveShare<S>, Error>
+where
+    C: Context,
+    for<'a> &'a AdditiveShare<S>: IntoIterator<Item = AdditiveShare<Boolean>>,
+    S: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+{
+    assert_ne!(0, divisor, "division by zero");
+    assert_eq!(
+        0,
+        divisor
+            .checked_shr(<S as WeakSharedValue>::BITS)
+            .unwrap_or(0),
+        "divisor {divisor} does not fit into {} bits",
+        <S as WeakSharedValue>::BITS
+    );
+
+    // Bits of the dividend, least significant first. Adding the rounding offset may carry into
+    // one more bit, but the quotient still fits into `S`.
+    let offset = rounding.offset(divisor);
+    let dividend = if offset == 0 {
+        x.into_iter().collect::<Vec<_>>()
+    } else {
+        let offset = AdditiveShare::<S>::share_known_value(&ctx, S::truncate_from(offset));
+        let (sum, carry) =
+            integer_add::<_, S, S>(ctx.narrow(&Step::AddRoundingOffset), record_id, x, &offset)
+                .await?;
+        (&sum).into_iter().chain([carry]).collect()
+    };
+
+    let width = usize::try_from(u128::BITS - divisor.leading_zeros()).unwrap() + 1;
+    let mut remainder = vec![AdditiveShare::<Boolean>::ZERO; width];
+    // Largest value the remainder can have, the divisor is not subtracted while it is smaller.
+    let mut max_remainder = 0_u128;
+    let mut quotient = AdditiveShare::<S>::ZERO;
+    for (i, bit) in dividend.into_iter().enumerate().rev() {
+        // Remainder is smaller than the divisor, so its top bit is always zero.
+        remainder.pop();
+        remainder.insert(0, bit);
+        max_remainder = max_remainder.saturating_mul(2).saturating_add(1);
+        if max_remainder < divisor {
+            continue;
+        }
+        max_remainder = divisor - 1;
+
+        let ctx = ctx.narrow(&BitOpStep::from(i));
+        let (difference, no_borrow) =
+            subtract_constant(ctx.narrow(&Step::Subtract), record_id, &remainder, divisor).await?;
+        remainder = select(
+            ctx.narrow(&Step::Select),
+            record_id,
+            &no_borrow,
+            &difference,
+            &remainder,
+        )
+        .await?;
+        if i < usize::try_from(<S as WeakSharedValue>::BITS).unwrap() {
+            quotient.set(i, no_borrow);
+        }
+    }
+
+    Ok(quotient)
+}
+
+/// Subtracts the public value `y` from x, given as bits, least significant first. Returns the
+/// difference and whether the subtraction did not borrow, i.e. whether x is at least `y`.
+///
+/// Bits of the difference are `x_i ⊕ y_i ⊕ !c_i`, where `c_i` is set if the subtraction did not
+/// borrow from bit `i`. Starting from `c_0 = 1`, the next one is `x_i ∧ c_i` if `y_i` is set and
+/// `x_i ∨ c_i` if it is not, a single multiplication either way. While `c_i` is known to be set,
+/// no multiplication is needed.
+async fn subtract_constant<C: Context>(
+    ctx: C,
+    record_id: RecordId,
+    x: &[AdditiveShare<Boolean>],
+    y: u128,
+) -> Result<(Vec<AdditiveShare<Boolean>>, AdditiveShare<Boolean>), Error> {
+    let mut difference = Vec::with_capacity(x.len());
+    // `None` while nothing can have borrowed yet.
+    let mut no_borrow: Option<AdditiveShare<Boolean>> = None;
+    for (i, bit) in x.iter().enumerate() {
+        let y_i = (y >> i) & 1 == 1;
+        difference.push(match &no_borrow {
+            None if y_i => !bit.clone(),
+            None => bit.clone(),
+            Some(c) if y_i => bit + c,
+            Some(c) => !(bit + c),
+        });
+        no_borrow = match no_borrow {
+            None if y_i => Some(bit.clone()),
+            None => None,
+            Some(c) => {
+                let ctx = ctx.narrow(&BitOpStep::from(i));
+                Some(if y_i {
+                    bit.multiply(&c, ctx, record_id).await?
+                } else {
+                    !(!bit.clone()).multiply(&!c, ctx, record_id).await?
+                })
+            }
+        };
+    }
+    let no_borrow = no_borrow.unwrap_or_else(|| !AdditiveShare::<Boolean>::ZERO);
+
+    Ok((difference, no_borrow))
+}
+
+/// Returns bits of x if `condition` is set and bits of y if it is not.
+async fn select<C: Context>(
+    ctx: C,
+    record_id: RecordId,
+    condition: &AdditiveShare<Boolean>,
+    x: &[AdditiveShare<Boolean>],
+    y: &[AdditiveShare<Boolean>],
+) -> Result<Vec<AdditiveShare<Boolean>>, Error> {
+    try_join_all(x.iter().zip(y).enumerate().map(|(i, (x, y))| {
+        let ctx = ctx.narrow(&BitOpStep::from(i));
+        async move {
+            let difference = condition.multiply(&(x + y), ctx, record_id).await?;
+            Ok::<_, Error>(y + &difference)
+        }
+    }))
+    .await
+}
+
+#[cfg(all(test, unit_test))]
+mod test {
+    use rand::Rng;
+
+    use super::{div_public, Rounding};
+    use crate::{
+        ff::{
+            boolean_array::{BA16, BA8},
+            Field,
+        },
+        protocol::{context::Context, RecordId},
+        rand::thread_rng,
+        seq_join::SeqJoin,
+        test_executor::run,
+        test_fixture::{Reconstruct, Runner, TestWorld},
+    };
+
+    fn expected(x: u128, divisor: u128, rounding: Rounding) -> u128 {
+        match rounding {
+            Rounding::Down => x / divisor,
+            Rounding::Nearest => (2 * x + divisor) / (2 * divisor),
+            Rounding::Up => x.div_ceil(divisor),
+        }
+    }
+
+    #[test]
+    fn semi_honest_div_public() {
+        run(|| async move {
+            let world = TestWorld::default();
+            let mut rng = thread_rng();
+
+            for divisor in [1, 2, 3, 7, 10, 128, 255] {
+                for rounding in [Rounding::Down, Rounding::Nearest, Rounding::Up] {
+                    let mut x = vec![0, 1, divisor - 1, divisor, divisor + 1, 255];
+                    x.extend((0..10).map(|_| rng.gen_range(0..256)));
+                    let input = x.iter().map(|&x| BA8::truncate_from(x)).collect::<Vec<_>>();
+                    let result: Vec<BA8> = world
+                        .semi_honest(input.into_iter(), |ctx, x| async move {
+                            let ctx = ctx.set_total_records(x.len());
+                            ctx.try_join(x.iter().enumerate().map(|(i, x)| {
+                                div_public(ctx.clone(), RecordId::from(i), x, divisor, rounding)
+                            }))
+                            .await
+                            .unwrap()
+                        })
+                        .await
+                        .reconstruct();
+
+                    for (x, result) in x.into_iter().zip(result) {
+                        assert_eq!(
+                            expected(x, divisor, rounding),
+                            result.as_u128(),
+                            "{x} / {divisor}, {rounding:?}"
+                        );
+                    }
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn average() {
+        run(|| async move {
+            // total value of 7 conversions in a breakdown
+            let total = BA16::truncate_from(1000_u128);
+            let result: BA16 = TestWorld::default()
+                .semi_honest(total, |ctx, total| async move {
+                    div_public(
+                        ctx.set_total_records(1),
+                        RecordId::FIRST,
+                        &total,
+                        7,
+                        Rounding::Nearest,
+                    )
+                    .await
+                    .unwrap()
+                })
+                .await
+                .reconstruct();
+            assert_eq!(143, result.as_u128());
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "does not fit into 8 bits")]
+    fn divisor_too_large() {
+        run(|| async move {
+            TestWorld::default()
+                .semi_honest(BA8::truncate_from(1_u128), |ctx, x| async move {
+                    div_public(ctx, RecordId::FIRST, &x, 256, Rounding::Down)
+                        .await
+                        .unwrap()
+                })
+                .await;
+        });
+    }
+}
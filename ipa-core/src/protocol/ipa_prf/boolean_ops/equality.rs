@@ -0,0 +1,121 @@
+use crate::{
+    error::Error,
+    ff::{ArrayAccess, CustomArray, Field},
+    protocol::{boolean::multiply_all_shares, context::Context, RecordId},
+    secret_sharing::{replicated::semi_honest::AdditiveShare, WeakSharedValue},
+};
+
+/// Equality test
+/// outputs x==y
+///
+/// x and y are equal iff all bits of `!(x + y)` are set. Their AND is computed as a binary tree,
+/// which takes `n - 1` multiplications in `ceil(log2(n))` rounds for n-bit x and y.
+/// # Errors
+/// propagates errors from multiply
+pub async fn is_equal<C, S>(
+    ctx: C,
+    record_id: RecordId,
+    x: &AdditiveShare<S>,
+    y: &AdditiveShare<S>,
+) -> Result<AdditiveShare<S::Element>, Error>
+where
+    C: Context,
+    for<'a> &'a AdditiveShare<S>: IntoIterator<Item = AdditiveShare<S::Element>>,
+    S: WeakSharedValue + CustomArray + Field,
+    S::Element: Field + std::ops::Not<Output = S::Element>,
+{
+    let same = (x + y).into_iter().map(|bit| !bit).collect::<Vec<_>>();
+    multiply_all_shares(ctx, record_id, &same).await
+}
+
+/// Equality test against a value known to all helpers
+/// outputs x==c
+///
+/// Same as [`is_equal`], but bits that are equal to bits of `c` are computed without sharing `c`:
+/// they are bits of x where `c` is set and their negation where it is not.
+/// # Errors
+/// propagates errors from multiply
+pub async fn is_equal_to_constant<C, S>(
+    ctx: C,
+    record_id: RecordId,
+    x: &AdditiveShare<S>,
+    c: S,
+) -> Result<AdditiveShare<S::Element>, Error>
+where
+    C: Context,
+    for<'a> &'a AdditiveShare<S>: IntoIterator<Item = AdditiveShare<S::Element>>,
+    S: WeakSharedValue + CustomArray + Field,
+    S::Element: Field + std::ops::Not<Output = S::Element>,
+{
+    let same = x
+        .into_iter()
+        .enumerate()
+        .map(|(i, bit)| {
+            if c.get(i) == Some(S::Element::ONE) {
+                bit
+            } else {
+                !bit
+            }
+        })
+        .collect::<Vec<_>>();
+    multiply_all_shares(ctx, record_id, &same).await
+}
+
+#[cfg(all(test, unit_test))]
+mod test {
+    use rand::Rng;
+
+    use super::{is_equal, is_equal_to_constant};
+    use crate::{
+        ff::{boolean::Boolean, boolean_array::BA64, Field},
+        protocol::{context::Context, RecordId},
+        rand::thread_rng,
+        test_executor::run,
+        test_fixture::{Reconstruct, Runner, TestWorld},
+    };
+
+    #[test]
+    fn semi_honest_is_equal() {
+        run(|| async move {
+            let world = TestWorld::default();
+            let mut rng = thread_rng();
+            let x = rng.gen::<BA64>();
+            // differs from x in a single bit
+            let y = x + BA64::truncate_from(1_u128 << rng.gen_range(0..64));
+
+            for (records, expected) in [(vec![x, y], false), (vec![x, x], true)] {
+                let result = world
+                    .semi_honest(records.into_iter(), |ctx, x_y| async move {
+                        is_equal(ctx.set_total_records(1), RecordId::FIRST, &x_y[0], &x_y[1])
+                            .await
+                            .unwrap()
+                    })
+                    .await
+                    .reconstruct();
+                assert_eq!(result, Boolean::from(expected));
+            }
+        });
+    }
+
+    #[test]
+    fn semi_honest_is_equal_to_constant() {
+        run(|| async move {
+            let world = TestWorld::default();
+            let mut rng = thread_rng();
+            let x = rng.gen::<BA64>();
+            let y = x + BA64::truncate_from(1_u128 << rng.gen_range(0..64));
+
+            for (c, expected) in [(y, false), (x, true)] {
+                let result = world
+                    .semi_honest(x, |ctx, x| async move {
+                        is_equal_to_constant(ctx.set_total_records(1), RecordId::FIRST, &x, c)
+                            .await
+                            .unwrap()
+                    })
+                    .await
+                    .reconstruct();
+                assert_eq!(result, Boolean::from(expected));
+            }
+        });
+    }
+}
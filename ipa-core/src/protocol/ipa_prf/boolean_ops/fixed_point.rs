@@ -0,0 +1,340 @@
+//! Unsigned fixed-point numbers stored in boolean arrays.
+//!
+//! A value `v` is stored as the integer `round(v * 2^frac_bits)`, so the lowest `frac_bits` bits
+//! of the array hold its fractional part. This lets attribution weigh values, for instance decay
+//! credit with the time between source and trigger events, without leaving boolean shares.
+//!
+//! Values are added and subtracted the same way integers are. Multiplying two of them scales the
+//! product by `2^frac_bits` once too many, which [`truncate`] removes. Unlike truncation of
+//! values shared over a prime field, truncation of boolean shares is local: every helper drops the
+//! lowest bits of both of its shares.
+use crate::{
+    error::Error,
+    ff::{boolean::Boolean, CustomArray, Field},
+    protocol::{
+        context::Context,
+        ipa_prf::boolean_ops::{
+            addition_sequential::integer_add, comparison_and_subtraction_sequential::integer_sub,
+        },
+        step::BitOpStep,
+        RecordId,
+    },
+    secret_sharing::{replicated::semi_honest::AdditiveShare, WeakSharedValue},
+};
+
+/// Number of fractional bits in fixed-point values.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FixedPoint {
+    frac_bits: u32,
+}
+
+impl FixedPoint {
+    #[must_use]
+    pub const fn new(frac_bits: u32) -> Self {
+        Self { frac_bits }
+    }
+
+    #[must_use]
+    pub const fn frac_bits(self) -> u32 {
+        self.frac_bits
+    }
+
+    /// Value of the least significant integer bit.
+    fn one(self) -> f64 {
+        2_f64.powi(i32::try_from(self.frac_bits).unwrap())
+    }
+
+    /// Returns the integer that represents `v`, rounded to the nearest one.
+    ///
+    /// ## Panics
+    /// If `v` is negative or does not fit into 128 bits.
+    #[must_use]
+    pub fn scale(self, v: f64) -> u128 {
+        let scaled = (v * self.one()).round();
+        assert!(
+            (0.0..2_f64.powi(128)).contains(&scaled),
+            "{v} is not an unsigned fixed-point value with {} fractional bits",
+            self.frac_bits
+        );
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let scaled = scaled as u128;
+        scaled
+    }
+
+    /// Encodes `v` into `S`, rounding it to the nearest value `S` can hold.
+    ///
+    /// ## Panics
+    /// If `v` is negative or too large for `S`.
+    #[must_use]
+    pub fn encode<S: Field>(self, v: f64) -> S {
+        let scaled = self.scale(v);
+        assert_eq!(
+            0,
+            scaled.checked_shr(S::BITS).unwrap_or(0),
+            "{v} does not fit into {} bits",
+            S::BITS
+        );
+        S::truncate_from(scaled)
+    }
+
+    /// Returns the value `v` encodes.
+    #[must_use]
+    pub fn decode<S: Field>(self, v: S) -> f64 {
+        #[allow(clippy::cast_precision_loss)]
+        let v = v.as_u128() as f64;
+        v / self.one()
+    }
+}
+
+/// Fixed-point addition
+/// adds y to x, the sum wraps around if it does not fit into `S`
+/// # Errors
+/// propagates errors from multiply
+pub async fn add<C, S>(
+    ctx: C,
+    record_id: RecordId,
+    x: &AdditiveShare<S>,
+    y: &AdditiveShare<S>,
+) -> Result<AdditiveShare<S>, Error>
+where
+    C: Context,
+    for<'a> &'a AdditiveShare<S>: IntoIterator<Item = AdditiveShare<Boolean>>,
+    S: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+{
+    Ok(integer_add::<_, S, S>(ctx, record_id, x, y).await?.0)
+}
+
+/// Fixed-point subtraction
+/// subtracts y from x, the difference wraps around if y is larger than x
+/// # Errors
+/// propagates errors from multiply
+pub async fn sub<C, S>(
+    ctx: C,
+    record_id: RecordId,
+    x: &AdditiveShare<S>,
+    y: &AdditiveShare<S>,
+) -> Result<AdditiveShare<S>, Error>
+where
+    C: Context,
+    for<'a> &'a AdditiveShare<S>: IntoIterator<Item = AdditiveShare<Boolean>>,
+    S: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+{
+    integer_sub::<_, S, S>(ctx, record_id, x, y).await
+}
+
+/// Fixed-point multiplication by a value known to all helpers
+/// multiplies x by c, rounded to `format`. The product of x and every set bit of c is a shifted
+/// copy of x, so it takes one addition of `W`-bit integers per set bit of c, except the first.
+/// They are computed in `W`, which must be wide enough to hold the product before it is truncated
+/// back to `format`. The truncated product wraps around if it does not fit into `S`.
+/// # Errors
+/// propagates errors from multiply
+/// # Panics
+/// If `W` is not wide enough for the product.
+pub async fn mul_public<C, S, W>(
+    ctx: C,
+    record_id: RecordId,
+    format: FixedPoint,
+    x: &AdditiveShare<S>,
+    c: f64,
+) -> Result<AdditiveShare<S>, Error>
+where
+    C: Context,
+    for<'a> &'a AdditiveShare<W>: IntoIterator<Item = AdditiveShare<Boolean>>,
+    S: WeakSharedValue + CustomArray<Element = Boolean>,
+    W: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+{
+    let c = format.scale(c);
+    assert!(
+        S::BITS + (u128::BITS - c.leading_zeros()) <= <W as WeakSharedValue>::BITS,
+        "{} bits are not enough to multiply {}-bit values by {c}",
+        <W as WeakSharedValue>::BITS,
+        S::BITS
+    );
+
+    let mut product = None;
+    for k in (0..u128::BITS).filter(|k| (c >> k) & 1 == 1) {
+        let term = move_bits::<S, W>(x, 0, usize::try_from(k).unwrap());
+        product = Some(match product {
+            None => term,
+            Some(product) => {
+                integer_add::<_, W, W>(ctx.narrow(&BitOpStep::from(k)), record_id, &product, &term)
+                    .await?
+                    .0
+            }
+        });
+    }
+
+    Ok(truncate(
+        &product.unwrap_or(AdditiveShare::<W>::ZERO),
+        format.frac_bits,
+    ))
+}
+
+/// Drops the lowest `bits` bits of x, dividing it by `2^bits` and rounding down. The result keeps
+/// as many of the remaining bits as `S` can hold. This is a local operation.
+pub fn truncate<W, S>(x: &AdditiveShare<W>, bits: u32) -> AdditiveShare<S>
+where
+    W: WeakSharedValue + CustomArray<Element = Boolean>,
+    S: WeakSharedValue + CustomArray<Element = Boolean>,
+{
+    move_bits(x, usize::try_from(bits).unwrap(), 0)
+}
+
+/// Copies bit `from + i` of x into bit `to + i` of the result, for every `i` that both of them
+/// have. Other bits of the result are zero.
+fn move_bits<X, Y>(x: &AdditiveShare<X>, from: usize, to: usize) -> AdditiveShare<Y>
+where
+    X: WeakSharedValue + CustomArray<Element = Boolean>,
+    Y: WeakSharedValue + CustomArray<Element = Boolean>,
+{
+    let mut result = AdditiveShare::<Y>::ZERO;
+    for (i, bit) in (to..usize::try_from(Y::BITS).unwrap()).zip((from..).map_while(|i| x.get(i))) {
+        result.set(i, bit);
+    }
+    result
+}
+
+#[cfg(all(test, unit_test))]
+mod test {
+    use std::iter::zip;
+
+    use rand::Rng;
+
+    use super::{add, mul_public, sub, truncate, FixedPoint};
+    use crate::{
+        ff::{
+            boolean_array::{BA16, BA32, BA64},
+            Field,
+        },
+        protocol::{context::Context, RecordId},
+        rand::thread_rng,
+        secret_sharing::{replicated::semi_honest::AdditiveShare, WeakSharedValue},
+        seq_join::SeqJoin,
+        test_executor::run,
+        test_fixture::{Reconstruct, Runner, TestWorld},
+    };
+
+    const FORMAT: FixedPoint = FixedPoint::new(8);
+
+    #[test]
+    fn encode() {
+        assert_eq!(BA16::truncate_from(384_u128), FORMAT.encode::<BA16>(1.5));
+        // rounds to the nearest representable value
+        assert_eq!(BA16::truncate_from(1_u128), FORMAT.encode::<BA16>(0.003));
+        assert!((FORMAT.decode(FORMAT.encode::<BA32>(3.140_625)) - 3.140_625).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    #[should_panic(expected = "does not fit into 16 bits")]
+    fn encode_too_large() {
+        let _ = FORMAT.encode::<BA16>(256.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "is not an unsigned fixed-point value")]
+    fn encode_negative() {
+        let _ = FORMAT.scale(-1.0);
+    }
+
+    #[test]
+    fn truncate_locally() {
+        let x = BA32::truncate_from(0xABCD_u128);
+        let truncated = truncate::<BA32, BA16>(&AdditiveShare(x, BA32::ZERO), 4);
+        assert_eq!(
+            AdditiveShare(BA16::truncate_from(0xABC_u128), BA16::ZERO),
+            truncated
+        );
+
+        // bits that don't fit are dropped
+        let y = BA32::truncate_from(0x1_2345_u128);
+        let narrowed = truncate::<BA32, BA16>(&AdditiveShare(y, x), 0);
+        assert_eq!(
+            AdditiveShare(
+                BA16::truncate_from(0x2345_u128),
+                BA16::truncate_from(0xABCD_u128)
+            ),
+            narrowed
+        );
+    }
+
+    #[test]
+    fn semi_honest_add_sub() {
+        run(|| async move {
+            let mut rng = thread_rng();
+            let x = (0..10)
+                .map(|_| FORMAT.encode::<BA32>(rng.gen_range(0.0..1000.0)))
+                .collect::<Vec<_>>();
+            let y = (0..10)
+                .map(|_| FORMAT.encode::<BA32>(rng.gen_range(0.0..1000.0)))
+                .collect::<Vec<_>>();
+
+            let (sum, difference): (Vec<BA32>, Vec<BA32>) = TestWorld::default()
+                .semi_honest(
+                    (x.clone().into_iter(), y.clone().into_iter()),
+                    |ctx, (x, y)| async move {
+                        let ctx = ctx.set_total_records(x.len());
+                        let sum =
+                            ctx.try_join(zip(&x, &y).enumerate().map(|(i, (x, y))| {
+                                add(ctx.narrow("add"), RecordId::from(i), x, y)
+                            }))
+                            .await
+                            .unwrap();
+                        let difference =
+                            ctx.try_join(zip(&x, &y).enumerate().map(|(i, (x, y))| {
+                                sub(ctx.narrow("sub"), RecordId::from(i), x, y)
+                            }))
+                            .await
+                            .unwrap();
+                        (sum, difference)
+                    },
+                )
+                .await
+                .reconstruct();
+
+            for (i, (x, y)) in zip(x, y).enumerate() {
+                let (x, y) = (FORMAT.decode(x), FORMAT.decode(y));
+                assert!((FORMAT.decode(sum[i]) - (x + y)).abs() < f64::EPSILON);
+                let expected = (x - y).rem_euclid(2_f64.powi(24));
+                assert!((FORMAT.decode(difference[i]) - expected).abs() < f64::EPSILON);
+            }
+        });
+    }
+
+    #[test]
+    fn semi_honest_mul_public() {
+        run(|| async move {
+            let world = TestWorld::default();
+            let mut rng = thread_rng();
+
+            for c in [0.0, 1.0, 0.5, 0.75, 2.25, 0.9] {
+                let x = (0..10)
+                    .map(|_| FORMAT.encode::<BA32>(rng.gen_range(0.0..1000.0)))
+                    .collect::<Vec<_>>();
+                let result: Vec<BA32> = world
+                    .semi_honest(x.clone().into_iter(), |ctx, x| async move {
+                        let ctx = ctx.set_total_records(x.len());
+                        ctx.try_join(x.iter().enumerate().map(|(i, x)| {
+                            mul_public::<_, BA32, BA64>(
+                                ctx.clone(),
+                                RecordId::from(i),
+                                FORMAT,
+                                x,
+                                c,
+                            )
+                        }))
+                        .await
+                        .unwrap()
+                    })
+                    .await
+                    .reconstruct();
+
+                for (x, result) in zip(x, result) {
+                    // rounds down to a multiple of 2^-8
+                    let expected = (x.as_u128() * FORMAT.scale(c)) >> FORMAT.frac_bits();
+                    assert_eq!(expected, result.as_u128(), "{} * {c}", FORMAT.decode(x));
+                }
+            }
+        });
+    }
+}
@@ -1,4 +1,232 @@
+#[cfg(feature = "descriptive-gate")]
+pub mod addition_parallel_prefix;
 pub mod addition_sequential;
 pub mod comparison_and_subtraction_sequential;
+#[cfg(feature = "descriptive-gate")]
+pub mod division;
+pub mod equality;
+pub mod fixed_point;
 mod share_conversion_aby;
 pub use share_conversion_aby::convert_to_fp25519;
+
+#[cfg(feature = "descriptive-gate")]
+use crate::{
+    error::Error,
+    ff::{CustomArray, Field},
+    protocol::{context::Context, RecordId},
+    secret_sharing::{replicated::semi_honest::AdditiveShare, WeakSharedValue},
+};
+
+/// Circuit used to add two boolean-shared integers. The parallel prefix adder is not part of any
+/// protocol yet, so its steps are only known to the descriptive gate.
+#[cfg(feature = "descriptive-gate")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AdderStrategy {
+    /// Ripple-carry adder. One multiplication per bit, as many rounds as there are bits.
+    #[default]
+    RippleCarry,
+    /// Kogge-Stone parallel prefix adder. `O(n log n)` multiplications in `O(log n)` rounds.
+    ParallelPrefix,
+}
+
+/// Non-saturated unsigned integer addition using the given adder circuit.
+/// See [`addition_sequential::integer_add`] and
+/// [`addition_parallel_prefix::integer_add_parallel_prefix`].
+///
+/// # Errors
+/// propagates errors from multiply
+#[cfg(feature = "descriptive-gate")]
+pub async fn integer_add_with<C, XS, YS>(
+    strategy: AdderStrategy,
+    ctx: C,
+    record_id: RecordId,
+    x: &AdditiveShare<XS>,
+    y: &AdditiveShare<YS>,
+) -> Result<(AdditiveShare<XS>, AdditiveShare<XS::Element>), Error>
+where
+    C: Context,
+    for<'a> &'a AdditiveShare<XS>: IntoIterator<Item = AdditiveShare<XS::Element>>,
+    YS: WeakSharedValue + CustomArray<Element = XS::Element>,
+    XS: WeakSharedValue + CustomArray + Field,
+    XS::Element: Field,
+{
+    match strategy {
+        AdderStrategy::RippleCarry => addition_sequential::integer_add(ctx, record_id, x, y).await,
+        AdderStrategy::ParallelPrefix => {
+            addition_parallel_prefix::integer_add_parallel_prefix(ctx, record_id, x, y).await
+        }
+    }
+}
+
+#[cfg(all(test, unit_test))]
+mod test {
+    use rand::Rng;
+
+    /// Returns pairs of `bits`-wide integers to run boolean operations on: the edges of the range,
+    /// where carries reach the top bit, followed by random values.
+    fn width_inputs<R: Rng>(bits: u32, rng: &mut R) -> Vec<(u128, u128)> {
+        let max = u128::MAX >> (u128::BITS - bits);
+        let half = 1 << (bits - 1);
+        let mut inputs = vec![
+            (0, 0),
+            (max, 1),
+            (1, max),
+            (max, max),
+            (max, 0),
+            (0, max),
+            (half, half),
+            (half - 1, half),
+        ];
+        inputs.extend((0..16).map(|_| (rng.gen::<u128>() & max, rng.gen::<u128>() & max)));
+
+        inputs
+    }
+
+    /// Generates a module that checks every boolean operation on integers of the given width
+    /// against the same operation on plain integers. Bugs often show up at a single width only,
+    /// for instance when the carry out of the top bit is handled incorrectly.
+    macro_rules! width_tests {
+        ($modname:ident, $ba:ty) => {
+            mod $modname {
+                use std::iter::zip;
+
+                use super::width_inputs;
+                use crate::{
+                    ff::{boolean::Boolean, Field},
+                    protocol::{
+                        context::Context,
+                        ipa_prf::boolean_ops::{
+                            addition_sequential::{integer_add, integer_sat_add},
+                            comparison_and_subtraction_sequential::{compare_gt, integer_sub},
+                        },
+                        RecordId,
+                    },
+                    rand::thread_rng,
+                    secret_sharing::SharedValue,
+                    seq_join::SeqJoin,
+                    test_executor::run,
+                    test_fixture::{Reconstruct, Runner, TestWorld},
+                };
+
+                const MODULUS: u128 = 1 << <$ba>::BITS;
+
+                fn inputs() -> (Vec<(u128, u128)>, Vec<$ba>, Vec<$ba>) {
+                    let inputs = width_inputs(<$ba>::BITS, &mut thread_rng());
+                    let (x, y) = inputs
+                        .iter()
+                        .map(|&(x, y)| (<$ba>::truncate_from(x), <$ba>::truncate_from(y)))
+                        .unzip();
+                    (inputs, x, y)
+                }
+
+                #[test]
+                fn add() {
+                    run(|| async move {
+                        let (inputs, x, y) = inputs();
+                        let (sum, carry): (Vec<$ba>, Vec<Boolean>) = TestWorld::default()
+                            .semi_honest((x.into_iter(), y.into_iter()), |ctx, (x, y)| async move {
+                                let ctx = ctx.set_total_records(x.len());
+                                ctx.try_join(zip(&x, &y).enumerate().map(|(i, (x, y))| {
+                                    integer_add::<_, $ba, $ba>(ctx.clone(), RecordId::from(i), x, y)
+                                }))
+                                .await
+                                .unwrap()
+                                .into_iter()
+                                .unzip::<_, _, Vec<_>, Vec<_>>()
+                            })
+                            .await
+                            .reconstruct();
+
+                        for (i, (x, y)) in inputs.into_iter().enumerate() {
+                            assert_eq!(
+                                ((x + y) % MODULUS, (x + y) / MODULUS),
+                                (sum[i].as_u128(), carry[i].as_u128()),
+                                "{x} + {y}"
+                            );
+                        }
+                    });
+                }
+
+                #[test]
+                fn sat_add() {
+                    run(|| async move {
+                        let (inputs, x, y) = inputs();
+                        let result: Vec<$ba> = TestWorld::default()
+                            .semi_honest((x.into_iter(), y.into_iter()), |ctx, (x, y)| async move {
+                                let ctx = ctx.set_total_records(x.len());
+                                ctx.try_join(zip(&x, &y).enumerate().map(|(i, (x, y))| {
+                                    integer_sat_add::<_, $ba>(ctx.clone(), RecordId::from(i), x, y)
+                                }))
+                                .await
+                                .unwrap()
+                            })
+                            .await
+                            .reconstruct();
+
+                        for (i, (x, y)) in inputs.into_iter().enumerate() {
+                            assert_eq!((x + y).min(MODULUS - 1), result[i].as_u128(), "{x} + {y}");
+                        }
+                    });
+                }
+
+                #[test]
+                fn sub() {
+                    run(|| async move {
+                        let (inputs, x, y) = inputs();
+                        let result: Vec<$ba> = TestWorld::default()
+                            .semi_honest((x.into_iter(), y.into_iter()), |ctx, (x, y)| async move {
+                                let ctx = ctx.set_total_records(x.len());
+                                ctx.try_join(zip(&x, &y).enumerate().map(|(i, (x, y))| {
+                                    integer_sub::<_, $ba, $ba>(ctx.clone(), RecordId::from(i), x, y)
+                                }))
+                                .await
+                                .unwrap()
+                            })
+                            .await
+                            .reconstruct();
+
+                        for (i, (x, y)) in inputs.into_iter().enumerate() {
+                            assert_eq!(
+                                (x + MODULUS - y) % MODULUS,
+                                result[i].as_u128(),
+                                "{x} - {y}"
+                            );
+                        }
+                    });
+                }
+
+                #[test]
+                fn gt() {
+                    run(|| async move {
+                        let (inputs, x, y) = inputs();
+                        let result: Vec<Boolean> = TestWorld::default()
+                            .semi_honest((x.into_iter(), y.into_iter()), |ctx, (x, y)| async move {
+                                let ctx = ctx.set_total_records(x.len());
+                                ctx.try_join(zip(&x, &y).enumerate().map(|(i, (x, y))| {
+                                    compare_gt::<_, $ba, $ba>(ctx.clone(), RecordId::from(i), x, y)
+                                }))
+                                .await
+                                .unwrap()
+                            })
+                            .await
+                            .reconstruct();
+
+                        for (i, (x, y)) in inputs.into_iter().enumerate() {
+                            assert_eq!(Boolean::from(x > y), result[i], "{x} > {y}");
+                        }
+                    });
+                }
+            }
+        };
+    }
+
+    width_tests!(ba3, crate::ff::boolean_array::BA3);
+    width_tests!(ba4, crate::ff::boolean_array::BA4);
+    width_tests!(ba5, crate::ff::boolean_array::BA5);
+    width_tests!(ba6, crate::ff::boolean_array::BA6);
+    width_tests!(ba7, crate::ff::boolean_array::BA7);
+    width_tests!(ba8, crate::ff::boolean_array::BA8);
+    width_tests!(ba20, crate::ff::boolean_array::BA20);
+    width_tests!(ba32, crate::ff::boolean_array::BA32);
+    width_tests!(ba64, crate::ff::boolean_array::BA64);
+}
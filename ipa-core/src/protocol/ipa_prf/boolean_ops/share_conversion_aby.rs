@@ -1,12 +1,13 @@
 use std::ops::Neg;
 
+use generic_array::GenericArray;
 use ipa_macros::Step;
 
 use crate::{
     error::Error,
     ff::{
         boolean::Boolean, boolean_array::BA256, ec_prime_field::Fp25519, ArrayAccess, CustomArray,
-        Field,
+        Field, Serializable,
     },
     helpers::Role,
     protocol::{
@@ -27,6 +28,14 @@ pub(crate) enum Step {
     RevealY,
 }
 
+/// Assembles a `BA256` out of two 128 bit words, the first one holds the lower bits.
+fn ba256(words: [u128; 2]) -> BA256 {
+    let mut buf = GenericArray::default();
+    buf[..16].copy_from_slice(&words[0].to_le_bytes());
+    buf[16..].copy_from_slice(&words[1].to_le_bytes());
+    BA256::deserialize(&buf)
+}
+
 /// share conversion
 /// from Boolean array of size n to integer mod p, where p is modulus of elliptic curve field `Fp25519`
 /// We follow the ABY3 (`https://eprint.iacr.org/2018/403.pdf`)
@@ -109,10 +118,15 @@ where
 
         // we generate random values r = (r1,r2,r3) using PRSS
         // r: H1: (r1,r2), H2: (r2,r3), H3: (r3, r1)
-        let mut r: AdditiveShare<BA256> = ctx
-            .narrow(&Step::GenerateSecretSharing)
-            .prss()
-            .generate_replicated(record_id);
+        // PRSS values are 128 bits wide, two of them fill all 256 bits of r
+        let mut left = [0; 2];
+        let mut right = [0; 2];
+        ctx.narrow(&Step::GenerateSecretSharing).prss().fill_values(
+            2 * u128::from(record_id),
+            &mut left,
+            &mut right,
+        );
+        let mut r = AdditiveShare::<BA256>::new(ba256(left), ba256(right));
 
         // set 2 highest order bits of r1, r2, r3 to 0
         r.set(255, AdditiveShare::<Boolean>::ZERO);
@@ -0,0 +1,139 @@
+//! Diagnostics of the match key space.
+//!
+//! Once PRF values are revealed, rows are grouped by them, and every group is expected to contain
+//! rows of a single user. Two things break that: different match keys that collide after PRF
+//! (rare) and a single user reporting events under several match keys. The former makes some
+//! groups larger than any user could produce, the latter makes groups smaller and more numerous
+//! than expected. Neither shows up in a single group, but a broken key space shifts the
+//! distribution of group sizes.
+//!
+//! Every helper already sees sizes of all groups, that's how attribution is organized. This
+//! stage releases a differentially private histogram of them to the report collector, who can
+//! compare it against the distribution they expect, without learning anything about individual
+//! groups.
+use ipa_macros::Step;
+
+use crate::{
+    ff::PrimeField,
+    protocol::{
+        basics::ShareKnownValue,
+        context::Context,
+        dp::{add_noise, BinomialNoise},
+        ipa_prf::prf_sharding::GroupingKey,
+    },
+    secret_sharing::replicated::semi_honest::AdditiveShare as Replicated,
+};
+
+#[derive(Step)]
+pub(crate) enum Step {
+    GroupSizeNoise,
+}
+
+/// Controls the histogram of group sizes computed by [`group_size_histogram`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GroupSizeParams {
+    /// Largest number of rows a single user is expected to have. Groups with more rows than that
+    /// are counted together in the last bucket of the histogram.
+    pub max_expected_rows: u32,
+    /// Noise added to every bucket. Adding or removing a user changes the size of one group, so
+    /// it moves one group between two buckets and the noise must be calibrated for sensitivity
+    /// of 2.
+    pub noise: BinomialNoise,
+}
+
+/// Counts groups of rows with every size from 1 to `params.max_expected_rows`, along with groups
+/// that are larger than that, and adds noise to the counts. Returns `max_expected_rows + 1`
+/// secret-shared counts: the count of groups with `i + 1` rows is at index `i` and the count of
+/// larger groups is the last one.
+///
+/// Rows must be grouped by their grouping key. This does not require communication.
+///
+/// ## Panics
+/// If `params.max_expected_rows` is zero.
+pub fn group_size_histogram<C, R, F>(
+    ctx: &C,
+    rows: &[R],
+    params: GroupSizeParams,
+) -> Vec<Replicated<F>>
+where
+    C: Context,
+    R: GroupingKey,
+    F: PrimeField,
+{
+    assert!(
+        params.max_expected_rows > 0,
+        "users are expected to have at least one row"
+    );
+    let max_rows = usize::try_from(params.max_expected_rows).unwrap();
+
+    let mut counts = vec![0_u64; max_rows + 1];
+    let mut group_size = 0;
+    for (i, row) in rows.iter().enumerate() {
+        group_size += 1;
+        let last_in_group = rows.get(i + 1).map_or(true, |next| {
+            next.get_grouping_key() != row.get_grouping_key()
+        });
+        if last_in_group {
+            counts[group_size.min(max_rows + 1) - 1] += 1;
+            group_size = 0;
+        }
+    }
+
+    let mut histogram = counts
+        .into_iter()
+        .map(|count| Replicated::share_known_value(ctx, F::truncate_from(count)))
+        .collect::<Vec<_>>();
+    add_noise(
+        &ctx.narrow(&Step::GroupSizeNoise),
+        params.noise,
+        &mut histogram,
+    );
+
+    histogram
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use super::{group_size_histogram, GroupSizeParams};
+    use crate::{
+        ff::{Field, Fp32BitPrime, PrimeField},
+        protocol::{dp::BinomialNoise, ipa_prf::prf_sharding::GroupingKey},
+        test_fixture::{Reconstruct, Runner, TestWorld},
+    };
+
+    struct Row(u64);
+
+    impl GroupingKey for Row {
+        fn get_grouping_key(&self) -> u64 {
+            self.0
+        }
+    }
+
+    #[tokio::test]
+    async fn counts_group_sizes() {
+        let params = GroupSizeParams {
+            max_expected_rows: 3,
+            noise: BinomialNoise::new(100.0, 0.5, 2).unwrap(),
+        };
+
+        let world = TestWorld::default();
+        let histogram: Vec<Fp32BitPrime> = world
+            .semi_honest((), |ctx, ()| async move {
+                // one group with 1 row, two with 2 rows and one with 5 rows
+                let rows = [7, 3, 3, 9, 9, 4, 4, 4, 4, 4].map(Row);
+                group_size_histogram(&ctx, &rows, params)
+            })
+            .await
+            .reconstruct();
+
+        // three samples of 64 trials each are added to every count, so the standard deviation
+        // of noise is 7
+        assert_eq!(4, histogram.len());
+        for (count, expected) in histogram.iter().zip([1, 2, 0, 1]) {
+            let v = i64::try_from(count.as_u128()).unwrap();
+            let p = i64::from(Fp32BitPrime::PRIME);
+            let noise = (if v > p / 2 { v - p } else { v }) - expected;
+            assert!(noise.abs() < 42, "too much noise: {noise}");
+        }
+    }
+}
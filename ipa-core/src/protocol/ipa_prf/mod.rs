@@ -4,16 +4,19 @@ use ipa_macros::Step;
 
 use crate::{
     error::Error,
-    ff::{boolean::Boolean, boolean_array::BA64, CustomArray, Field, PrimeField, Serializable},
+    ff::{
+        boolean::Boolean, boolean_array::BA64, ec_prime_field::Fp25519, CustomArray, Field,
+        PrimeField, Serializable,
+    },
     protocol::{
-        context::{UpgradableContext, UpgradedContext},
+        context::{Context, UpgradableContext, UpgradedContext},
+        dp::BinomialNoise,
         ipa_prf::{
             boolean_ops::convert_to_fp25519,
+            padding::PaddingParams,
+            pipeline::PipelineBuilder,
             prf_eval::{eval_dy_prf, gen_prf_key},
-            prf_sharding::{
-                attribute_cap_aggregate, compute_histogram_of_users_with_row_count,
-                PrfShardedIpaInputRow,
-            },
+            prf_sharding::{GroupingKey, PrfShardedIpaInputRow},
         },
         RecordId,
     },
@@ -24,44 +27,65 @@ use crate::{
     },
 };
 
-mod boolean_ops;
+pub(crate) mod boolean_ops;
+#[cfg(feature = "descriptive-gate")]
+pub mod diagnostics;
+pub mod padding;
+pub mod pipeline;
 pub mod prf_eval;
 pub mod prf_sharding;
-#[cfg(feature = "descriptive-gate")]
+pub mod scaling;
+pub mod segment;
 pub mod shuffle;
+pub mod time_bucket;
+pub mod timestamp_sort;
 
 #[derive(Step)]
 pub(crate) enum Step {
     ConvertFp25519,
     EvalPrf,
     ConvertInputRowsToPrf,
+    PadInputs,
+    AddNoise,
+    AddNoiseToAttributedUsers,
+    ScaleOutput,
 }
 
 /// IPA OPRF Protocol
 ///
-/// The output of this function is a vector of secret-shared totals, one per breakdown key
+/// The output of this function is a vector of secret-shared totals, one per breakdown key in
+/// `[0, num_breakdowns)`
 /// This protocol performs the following steps
 /// 1. Converts secret-sharings of boolean arrays to secret-sharings of elliptic curve points
 /// 2. Generates a random number of "dummy records" (needed to mask the information that will
 ///    be revealed in a later step, and thereby provide a differential privacy guarantee on that
-///    information leakage) (TBD)
-/// 3. Shuffles the input (TBD)
+///    information leakage), if `padding` is set. See [`padding::pad_inputs`]
+/// 3. Shuffles the input, if `padding` is set, so that dummy rows can't be told apart from the
+///    real ones. See [`shuffle::shuffle_inputs`]
 /// 4. Computes an OPRF of these elliptic curve points and reveals this "pseudonym"
 /// 5. Groups together rows with the same OPRF, and then obliviously sorts each group by the
-///    secret-shared timestamp (TBD)
+///    secret-shared timestamp, if `padding` is set, see [`timestamp_sort`]
 /// 6. Attributes trigger events to source events
-/// 7. Caps each user's total contribution to the final result
+/// 7. Caps each user's total contribution to the final result at `per_user_cap`, summing it in
+///    as many bits as [`pipeline::saturating_sum_bits`] picks for the cap
 /// 8. Aggregates the contributions of all users
 /// 9. Adds random noise to the total for each breakdown key (to provide a differential
-///    privacy guarantee) (TBD)
+///    privacy guarantee), if `noise` is set
+///
+/// To run a different selection of these stages, see [`pipeline::PipelineBuilder`].
 /// # Errors
 /// Propagates errors from config issues or while running the protocol
 /// # Panics
 /// Propagates errors from config issues or while running the protocol
-pub async fn oprf_ipa<C, BK, TV, TS, SS, F>(
+#[tracing::instrument(name = "oprf_ipa", skip_all, fields(sz = input_rows.len()))]
+pub async fn oprf_ipa<C, BK, TV, TS, F>(
     ctx: C,
     input_rows: Vec<OprfReport<BK, TV, TS>>,
     attribution_window_seconds: Option<NonZeroU32>,
+    per_user_cap: u32,
+    num_breakdowns: u32,
+    padding: Option<PaddingParams>,
+    noise: Option<BinomialNoise>,
 ) -> Result<Vec<Replicated<F>>, Error>
 where
     C: UpgradableContext,
@@ -70,38 +94,75 @@ where
     BK: WeakSharedValue + CustomArray<Element = Boolean> + Field,
     TV: WeakSharedValue + CustomArray<Element = Boolean> + Field,
     TS: WeakSharedValue + CustomArray<Element = Boolean> + Field,
-    SS: WeakSharedValue + CustomArray<Element = Boolean> + Field,
-    for<'a> &'a Replicated<SS>: IntoIterator<Item = Replicated<Boolean>>,
     for<'a> &'a Replicated<TS>: IntoIterator<Item = Replicated<Boolean>>,
     for<'a> &'a Replicated<TV>: IntoIterator<Item = Replicated<Boolean>>,
     for<'a> &'a Replicated<BK>: IntoIterator<Item = Replicated<Boolean>>,
-    for<'a> <&'a Replicated<SS> as IntoIterator>::IntoIter: Send,
     for<'a> <&'a Replicated<TV> as IntoIterator>::IntoIter: Send,
     for<'a> <&'a Replicated<TS> as IntoIterator>::IntoIter: Send,
     F: PrimeField + ExtendableField,
     Replicated<F>: Serializable,
 {
-    // TODO (richaj): Add shuffle either before the protocol starts or, after converting match keys to elliptical curve.
-    // We might want to do it earlier as that's a cleaner code
-
-    let prfd_inputs =
-        compute_prf_for_inputs(ctx.narrow(&Step::ConvertInputRowsToPrf), input_rows).await?;
-
-    let histogram = compute_histogram_of_users_with_row_count(&prfd_inputs);
-
     // TODO (richaj) : Call quicksort on match keys followed by timestamp before calling attribution logic
-    attribute_cap_aggregate::<C, BK, TV, TS, SS, Replicated<F>, F>(
-        ctx,
-        prfd_inputs,
-        attribution_window_seconds,
-        &histogram,
-    )
-    .await
+    PipelineBuilder::new(per_user_cap, num_breakdowns)
+        .with_attribution_window(attribution_window_seconds)
+        .with_padding(padding)
+        .with_noise(noise)
+        .build()
+        .run::<C, BK, TV, TS, F>(ctx, input_rows)
+        .await
 }
 
+/// IPA OPRF Protocol with sharded attribution.
+///
+/// Same as [`oprf_ipa`], except that after the PRF is revealed, rows are split into `shard_count`
+/// shards by PRF value and attribution, capping and aggregation run on each shard independently.
+/// See [`prf_sharding::sharding`] for details.
+/// # Errors
+/// Propagates errors from config issues or while running the protocol
+/// # Panics
+/// If `shard_count` exceeds [`prf_sharding::sharding::MAX_SHARDS`].
+#[cfg(feature = "descriptive-gate")]
+#[tracing::instrument(name = "sharded_oprf_ipa", skip_all, fields(sz = input_rows.len()))]
+pub async fn sharded_oprf_ipa<C, BK, TV, TS, F>(
+    ctx: C,
+    input_rows: Vec<OprfReport<BK, TV, TS>>,
+    attribution_window_seconds: Option<NonZeroU32>,
+    per_user_cap: u32,
+    num_breakdowns: u32,
+    shard_count: NonZeroU32,
+    padding: Option<PaddingParams>,
+    noise: Option<BinomialNoise>,
+) -> Result<Vec<Replicated<F>>, Error>
+where
+    C: UpgradableContext,
+    C::UpgradedContext<Boolean>: UpgradedContext<Boolean, Share = Replicated<Boolean>>,
+    C::UpgradedContext<F>: UpgradedContext<F, Share = Replicated<F>>,
+    BK: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    TV: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    TS: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    for<'a> &'a Replicated<TS>: IntoIterator<Item = Replicated<Boolean>>,
+    for<'a> &'a Replicated<TV>: IntoIterator<Item = Replicated<Boolean>>,
+    for<'a> &'a Replicated<BK>: IntoIterator<Item = Replicated<Boolean>>,
+    for<'a> <&'a Replicated<TV> as IntoIterator>::IntoIter: Send,
+    for<'a> <&'a Replicated<TS> as IntoIterator>::IntoIter: Send,
+    F: PrimeField + ExtendableField,
+    Replicated<F>: Serializable,
+{
+    PipelineBuilder::new(per_user_cap, num_breakdowns)
+        .with_attribution_window(attribution_window_seconds)
+        .with_padding(padding)
+        .with_shards(shard_count)
+        .with_noise(noise)
+        .build()
+        .run::<C, BK, TV, TS, F>(ctx, input_rows)
+        .await
+}
+
+#[tracing::instrument(name = "compute_prf", skip_all)]
 async fn compute_prf_for_inputs<C, BK, TV, TS, F>(
     ctx: C,
     input_rows: Vec<OprfReport<BK, TV, TS>>,
+    source_priority: bool,
 ) -> Result<Vec<PrfShardedIpaInputRow<BK, TV, TS>>, Error>
 where
     C: UpgradableContext,
@@ -124,24 +185,102 @@ where
 
     let prf_key = gen_prf_key(&convert_ctx);
 
-    ctx.parallel_join(input_rows.into_iter().enumerate().map(|(idx, record)| {
+    let mut prfd_inputs = ctx
+        .parallel_join(input_rows.into_iter().enumerate().map(|(idx, record)| {
+            let convert_ctx = convert_ctx.clone();
+            let eval_ctx = eval_ctx.clone();
+            let prf_key = prf_key.clone();
+            async move {
+                let record_id = RecordId::from(idx);
+                let elliptic_curve_pt =
+                    convert_to_fp25519::<_, BA64>(convert_ctx, record_id, &record.match_key)
+                        .await?;
+                let elliptic_curve_pt =
+                    eval_dy_prf(eval_ctx, record_id, &prf_key, &elliptic_curve_pt).await?;
+
+                Ok::<_, Error>(PrfShardedIpaInputRow {
+                    prf_of_match_key: elliptic_curve_pt,
+                    is_trigger_bit: record.is_trigger,
+                    breakdown_key: record.breakdown_key,
+                    trigger_value: record.trigger_value,
+                    timestamp: record.timestamp,
+                    priority: source_priority.then_some(record.priority),
+                })
+            }
+        }))
+        .await?;
+
+    // Attribution requires rows of the same user to be adjacent. Source and trigger events may be
+    // submitted by different report collectors, so input does not guarantee that. Sort is stable,
+    // so rows of each user keep their input order. For split inputs, that places all source
+    // events before trigger events, so the query processor runs them with timestamp sort, which
+    // puts rows of every user in time order obliviously.
+    prfd_inputs.sort_by_key(GroupingKey::get_grouping_key);
+
+    Ok(prfd_inputs)
+}
+
+/// Pairs input rows with the PRF values of their match keys, computed in an earlier query, see
+/// [`compute_match_key_pseudonyms`], and groups them by user the same way
+/// [`compute_prf_for_inputs`] does.
+fn rows_with_pseudonyms<BK, TV, TS>(
+    pseudonyms: Vec<u64>,
+    input_rows: Vec<OprfReport<BK, TV, TS>>,
+    source_priority: bool,
+) -> Vec<PrfShardedIpaInputRow<BK, TV, TS>>
+where
+    BK: WeakSharedValue,
+    TV: WeakSharedValue,
+    TS: WeakSharedValue,
+{
+    let mut rows = pseudonyms
+        .into_iter()
+        .zip(input_rows)
+        .map(|(pseudonym, record)| PrfShardedIpaInputRow {
+            prf_of_match_key: pseudonym,
+            is_trigger_bit: record.is_trigger,
+            breakdown_key: record.breakdown_key,
+            trigger_value: record.trigger_value,
+            timestamp: record.timestamp,
+            priority: source_priority.then_some(record.priority),
+        })
+        .collect::<Vec<_>>();
+    rows.sort_by_key(GroupingKey::get_grouping_key);
+
+    rows
+}
+
+/// Computes the PRF of every match key under the given key, without running attribution.
+///
+/// PRF values are revealed to all helpers, the same way they are in [`oprf_ipa`], and are
+/// returned as is. It is up to the caller to make sure they never leave the helper in the clear.
+/// Unlike [`oprf_ipa`], that generates a new key for every query, this takes the key from the
+/// caller, so values computed under a [`PersistentPrfKey`] can be matched across queries.
+///
+/// [`PersistentPrfKey`]: prf_eval::PersistentPrfKey
+/// # Errors
+/// Propagates errors from share conversion or PRF evaluation.
+#[tracing::instrument(name = "compute_pseudonyms", skip_all, fields(sz = match_keys.len()))]
+pub async fn compute_match_key_pseudonyms<C: Context>(
+    ctx: C,
+    prf_key: &Replicated<Fp25519>,
+    match_keys: Vec<Replicated<BA64>>,
+) -> Result<Vec<u64>, Error> {
+    let ctx = ctx
+        .narrow(&Step::ConvertInputRowsToPrf)
+        .set_total_records(match_keys.len());
+    let convert_ctx = ctx.narrow(&Step::ConvertFp25519);
+    let eval_ctx = ctx.narrow(&Step::EvalPrf);
+
+    ctx.parallel_join(match_keys.into_iter().enumerate().map(|(idx, match_key)| {
         let convert_ctx = convert_ctx.clone();
         let eval_ctx = eval_ctx.clone();
         let prf_key = prf_key.clone();
         async move {
             let record_id = RecordId::from(idx);
             let elliptic_curve_pt =
-                convert_to_fp25519::<_, BA64>(convert_ctx, record_id, &record.match_key).await?;
-            let elliptic_curve_pt =
-                eval_dy_prf(eval_ctx, record_id, &prf_key, &elliptic_curve_pt).await?;
-
-            Ok::<_, Error>(PrfShardedIpaInputRow {
-                prf_of_match_key: elliptic_curve_pt,
-                is_trigger_bit: record.is_trigger,
-                breakdown_key: record.breakdown_key,
-                trigger_value: record.trigger_value,
-                timestamp: record.timestamp,
-            })
+                convert_to_fp25519::<_, BA64>(convert_ctx, record_id, &match_key).await?;
+            eval_dy_prf(eval_ctx, record_id, &prf_key, &elliptic_curve_pt).await
         }
     }))
     .await
@@ -150,16 +289,24 @@ where
 pub mod tests {
     use crate::{
         ff::{
-            boolean_array::{BA20, BA3, BA5, BA8},
+            boolean_array::{BA20, BA3, BA8},
             Fp31,
         },
-        protocol::ipa_prf::oprf_ipa,
+        protocol::{
+            dp::BinomialNoise,
+            ipa_prf::{oprf_ipa, padding::PaddingParams},
+        },
         test_executor::run,
         test_fixture::{ipa::TestRawDataRecord, Reconstruct, Runner, TestWorld},
     };
 
+    #[cfg(feature = "descriptive-gate")]
     #[test]
-    fn semi_honest() {
+    fn semi_honest_sharded() {
+        use std::num::NonZeroU32;
+
+        use crate::protocol::ipa_prf::sharded_oprf_ipa;
+
         const EXPECTED: &[u128] = &[0, 2, 5, 0, 0, 0, 0, 0];
 
         run(|| async {
@@ -203,15 +350,23 @@ pub mod tests {
                 },
             ];
 
-            let mut result: Vec<_> = world
+            let result: Vec<_> = world
                 .semi_honest(records.into_iter(), |ctx, input_rows| async move {
-                    oprf_ipa::<_, BA8, BA3, BA20, BA5, Fp31>(ctx, input_rows, None)
-                        .await
-                        .unwrap()
+                    sharded_oprf_ipa::<_, BA8, BA3, BA20, Fp31>(
+                        ctx,
+                        input_rows,
+                        None,
+                        32,
+                        8,
+                        NonZeroU32::new(4).unwrap(),
+                        None,
+                        None,
+                    )
+                    .await
+                    .unwrap()
                 })
                 .await
                 .reconstruct();
-            result.truncate(EXPECTED.len());
             assert_eq!(
                 result,
                 EXPECTED
@@ -221,4 +376,77 @@ pub mod tests {
             );
         });
     }
+
+    #[test]
+    fn semi_honest() {
+        const EXPECTED: &[u128] = &[0, 2, 5, 0, 0, 0, 0, 0];
+
+        run(|| async {
+            let world = TestWorld::default();
+
+            let records: Vec<TestRawDataRecord> = vec![
+                TestRawDataRecord {
+                    timestamp: 0,
+                    user_id: 12345,
+                    is_trigger_report: false,
+                    breakdown_key: 1,
+                    trigger_value: 0,
+                },
+                TestRawDataRecord {
+                    timestamp: 0,
+                    user_id: 12345,
+                    is_trigger_report: false,
+                    breakdown_key: 2,
+                    trigger_value: 0,
+                },
+                TestRawDataRecord {
+                    timestamp: 10,
+                    user_id: 12345,
+                    is_trigger_report: true,
+                    breakdown_key: 0,
+                    trigger_value: 5,
+                },
+                TestRawDataRecord {
+                    timestamp: 0,
+                    user_id: 68362,
+                    is_trigger_report: false,
+                    breakdown_key: 1,
+                    trigger_value: 0,
+                },
+                TestRawDataRecord {
+                    timestamp: 20,
+                    user_id: 68362,
+                    is_trigger_report: true,
+                    breakdown_key: 0,
+                    trigger_value: 2,
+                },
+            ];
+
+            // dummy users must not change the result
+            let padding = PaddingParams {
+                max_user_rows: 3,
+                noise: BinomialNoise::new(10.0, 1e-6, 1).unwrap(),
+            };
+            for padding in [None, Some(padding)] {
+                let result: Vec<_> = world
+                    .semi_honest(records.clone().into_iter(), |ctx, input_rows| async move {
+                        oprf_ipa::<_, BA8, BA3, BA20, Fp31>(
+                            ctx, input_rows, None, 32, 8, padding, None,
+                        )
+                        .await
+                        .unwrap()
+                    })
+                    .await
+                    .reconstruct();
+                assert_eq!(
+                    result,
+                    EXPECTED
+                        .iter()
+                        .map(|i| Fp31::try_from(*i).unwrap())
+                        .collect::<Vec<_>>(),
+                    "{padding:?}"
+                );
+            }
+        });
+    }
 }
@@ -0,0 +1,256 @@
+use std::iter::repeat;
+
+use futures::future::try_join;
+use ipa_macros::Step;
+
+use crate::{
+    error::Error,
+    ff::{
+        boolean_array::{BA32, BA64},
+        Field,
+    },
+    helpers::{Direction, Role},
+    protocol::{context::Context, dp::BinomialNoise, RecordId},
+    rand::RngCore,
+    report::OprfReport,
+    secret_sharing::{
+        replicated::{semi_honest::AdditiveShare as Replicated, ReplicatedSecretSharing},
+        WeakSharedValue,
+    },
+};
+
+/// Attribution processes at most that many rows per user, see
+/// [`UserNthRowStep`](super::prf_sharding::UserNthRowStep).
+pub const MAX_DUMMY_USER_ROWS: u32 = 64;
+
+#[derive(Step)]
+pub(crate) enum Step {
+    RowCount,
+}
+
+/// Controls how many dummy users [`pad_inputs`] adds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PaddingParams {
+    /// Dummy users are added with every number of rows from 1 to this, inclusive. Users with
+    /// more rows than that stand out in the histogram of rows per user. Must not exceed
+    /// [`MAX_DUMMY_USER_ROWS`].
+    pub max_user_rows: u32,
+    /// The number of dummy users with any given number of rows is the number of heads in
+    /// `noise.trials()` coin tosses, so it is never negative.
+    pub noise: BinomialNoise,
+}
+
+/// Adds rows of dummy users to `input_rows`, so that the histogram of rows per user, which is
+/// revealed along with PRF values, is differentially private.
+///
+/// Every pair of helpers adds its own dummy users, drawing their number and match keys from the
+/// randomness they share. The third helper learns how many rows the pair added, but not how many
+/// users they belong to. Dummy users are hidden from a helper by the dummy users of the pair it
+/// is not part of.
+///
+/// Dummy rows are source events with breakdown key 0, so dummy users never get any trigger value
+/// attributed to them and aggregates do not change. There is nothing to strip after aggregation.
+///
+/// All helpers append dummy rows in the same order: rows of the pair (H1, H2) first, followed by
+/// rows of (H2, H3) and (H3, H1).
+///
+/// # Errors
+/// If the number of dummy rows cannot be sent to or received from peers.
+/// # Panics
+/// If `params.max_user_rows` exceeds [`MAX_DUMMY_USER_ROWS`].
+#[tracing::instrument(name = "pad_inputs", skip_all, fields(sz = input_rows.len()))]
+pub async fn pad_inputs<C, BK, TV, TS>(
+    ctx: C,
+    mut input_rows: Vec<OprfReport<BK, TV, TS>>,
+    params: PaddingParams,
+) -> Result<Vec<OprfReport<BK, TV, TS>>, Error>
+where
+    C: Context,
+    BK: WeakSharedValue,
+    TV: WeakSharedValue,
+    TS: WeakSharedValue,
+{
+    assert!(
+        params.max_user_rows <= MAX_DUMMY_USER_ROWS,
+        "dummy users can have at most {MAX_DUMMY_USER_ROWS} rows, got {}",
+        params.max_user_rows
+    );
+
+    let (left_pair, right_pair) = {
+        let (mut left_rng, mut right_rng) = ctx.prss_rng();
+        (
+            dummy_match_keys(&mut left_rng, params),
+            dummy_match_keys(&mut right_rng, params),
+        )
+    };
+
+    let role = ctx.role();
+    let ctx = ctx.narrow(&Step::RowCount).set_total_records(1);
+    let ((), other_pair_rows) = try_join(
+        ctx.send_channel(role.peer(Direction::Left)).send(
+            RecordId::FIRST,
+            BA32::truncate_from(u32::try_from(right_pair.len()).unwrap()),
+        ),
+        ctx.recv_channel::<BA32>(role.peer(Direction::Right))
+            .receive(RecordId::FIRST),
+    )
+    .await?;
+    let other_pair_rows = usize::try_from(other_pair_rows.as_u128()).unwrap();
+    tracing::info!(
+        "adding {} dummy rows",
+        left_pair.len() + right_pair.len() + other_pair_rows
+    );
+
+    // Match keys of dummy users are known to both helpers of the pair and shared as
+    // `0 + mk + 0`, with `mk` being the right share of the first helper in the pair.
+    for first in Role::all() {
+        if *first == role {
+            input_rows.extend(
+                right_pair
+                    .iter()
+                    .map(|&mk| dummy_row(Replicated::new(BA64::ZERO, mk))),
+            );
+        } else if first.peer(Direction::Right) == role {
+            input_rows.extend(
+                left_pair
+                    .iter()
+                    .map(|&mk| dummy_row(Replicated::new(mk, BA64::ZERO))),
+            );
+        } else {
+            input_rows.extend(repeat(dummy_row(Replicated::ZERO)).take(other_pair_rows));
+        }
+    }
+
+    Ok(input_rows)
+}
+
+/// Returns match keys of all dummy rows added by a pair of helpers, one per row. Both helpers
+/// of the pair get the same result from their shared `rng`.
+fn dummy_match_keys<R: RngCore>(rng: &mut R, params: PaddingParams) -> Vec<BA64> {
+    let mut match_keys = Vec::new();
+    for rows in 1..=params.max_user_rows {
+        for _ in 0..params.noise.heads(rng) {
+            let match_key = BA64::truncate_from(rng.next_u64());
+            match_keys.extend(repeat(match_key).take(usize::try_from(rows).unwrap()));
+        }
+    }
+
+    match_keys
+}
+
+fn dummy_row<BK, TV, TS>(match_key: Replicated<BA64>) -> OprfReport<BK, TV, TS>
+where
+    BK: WeakSharedValue,
+    TV: WeakSharedValue,
+    TS: WeakSharedValue,
+{
+    OprfReport {
+        match_key,
+        is_trigger: Replicated::ZERO,
+        breakdown_key: Replicated::ZERO,
+        trigger_value: Replicated::ZERO,
+        timestamp: Replicated::ZERO,
+        priority: Replicated::ZERO,
+    }
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{pad_inputs, PaddingParams};
+    use crate::{
+        ff::{
+            boolean_array::{BA20, BA3, BA8},
+            Field,
+        },
+        protocol::dp::BinomialNoise,
+        report::OprfReport,
+        test_executor::run,
+        test_fixture::{ipa::TestRawDataRecord, Reconstruct, Runner, TestWorld},
+    };
+
+    #[test]
+    fn dummy_users() {
+        run(|| async {
+            let world = TestWorld::default();
+            let records = vec![
+                TestRawDataRecord {
+                    timestamp: 10,
+                    user_id: 12345,
+                    is_trigger_report: true,
+                    breakdown_key: 0,
+                    trigger_value: 5,
+                },
+                TestRawDataRecord {
+                    timestamp: 0,
+                    user_id: 68362,
+                    is_trigger_report: false,
+                    breakdown_key: 1,
+                    trigger_value: 0,
+                },
+            ];
+            let params = PaddingParams {
+                max_user_rows: 3,
+                noise: BinomialNoise::new(10.0, 1e-6, 1).unwrap(),
+            };
+
+            let [h1, h2, h3] = world
+                .semi_honest(
+                    records.into_iter(),
+                    |ctx, input_rows: Vec<OprfReport<BA8, BA3, BA20>>| async move {
+                        pad_inputs(ctx, input_rows, params).await.unwrap()
+                    },
+                )
+                .await;
+            assert_eq!(h1.len(), h2.len());
+            assert_eq!(h1.len(), h3.len());
+
+            let rows = (0..h1.len())
+                .map(|i| {
+                    (
+                        [&h1[i].match_key, &h2[i].match_key, &h3[i].match_key]
+                            .reconstruct()
+                            .as_u128(),
+                        [&h1[i].is_trigger, &h2[i].is_trigger, &h3[i].is_trigger]
+                            .reconstruct()
+                            .as_u128(),
+                        [
+                            &h1[i].breakdown_key,
+                            &h2[i].breakdown_key,
+                            &h3[i].breakdown_key,
+                        ]
+                        .reconstruct()
+                        .as_u128(),
+                        [
+                            &h1[i].trigger_value,
+                            &h2[i].trigger_value,
+                            &h3[i].trigger_value,
+                        ]
+                        .reconstruct()
+                        .as_u128(),
+                    )
+                })
+                .collect::<Vec<_>>();
+
+            // input rows are kept as is
+            assert_eq!((12345, 1, 0, 5), rows[0]);
+            assert_eq!((68362, 0, 1, 0), rows[1]);
+
+            let mut rows_per_user = HashMap::<u128, u32>::new();
+            for &(match_key, is_trigger, breakdown_key, trigger_value) in &rows[2..] {
+                assert_eq!((0, 0, 0), (is_trigger, breakdown_key, trigger_value));
+                *rows_per_user.entry(match_key).or_default() += 1;
+            }
+            let mut users_per_row_count = [0; 3];
+            for rows in rows_per_user.into_values() {
+                users_per_row_count[usize::try_from(rows).unwrap() - 1] += 1;
+            }
+            // every pair adds 32 users with each number of rows on average
+            assert!(
+                users_per_row_count.iter().all(|&users| users > 48),
+                "{users_per_row_count:?}"
+            );
+        });
+    }
+}
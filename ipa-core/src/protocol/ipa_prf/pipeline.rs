@@ -0,0 +1,1125 @@
+//! Custom measurement pipelines built out of OPRF IPA stages.
+//!
+//! [`oprf_ipa`] always runs the same sequence of stages. [`PipelineBuilder`] lets callers choose
+//! which of the optional stages run and how every stage is configured, without copying the
+//! protocol. Stages run in this order:
+//!
+//! 1. Padding with dummy users, see [`pad_inputs`]. Optional.
+//! 2. Oblivious shuffle of input rows, see [`shuffle_inputs`]. Optional, always runs with padding.
+//! 3. PRF evaluation, after which rows are grouped by user. Optionally followed by diagnostics of
+//!    group sizes, see [`group_size_histogram`]. Requires descriptive gate. Pipelines that run
+//!    on pseudonymized input skip this stage and the ones before it, see
+//!    [`Pipeline::run_pseudonymized_partial`].
+//! 4. Oblivious sorting of every user's rows by timestamp, see [`timestamp_sort`]. Optional,
+//!    always runs with shuffle.
+//! 5. Splitting users with many rows into segments with a divided cap, see [`segment`].
+//!    Optional.
+//! 6. Attribution, capping, conversion to prime field and aggregation, either over all rows,
+//!    over shards of them or over batches of them until the deadline, see
+//!    [`attribute_cap_aggregate`], [`sharded_attribute_cap_aggregate`] and
+//!    [`time_boxed_attribute_cap_aggregate`].
+//!    Optionally breaks ties between source events with the same timestamp by their priority.
+//!    Optionally counts users with at least one attributed conversion per breakdown, see
+//!    [`attribute_cap_aggregate_with_attributed_users`], and zeroes out contributions to
+//!    breakdowns that are not allowed, see [`filter`]. Optionally keys totals by time bucket
+//!    of the trigger event as well as by breakdown, see [`time_bucket`]. Requires descriptive gate.
+//! 7. DP noise, see [`add_noise`]. Optional.
+//! 8. Scaling of per-breakdown totals to coarser units, see [`scale_down`]. Optional.
+//!
+//! Every stage runs under its own step and at most once, so pipelines built this way never use a
+//! step twice. A pipeline that runs all stages that [`oprf_ipa`] does uses exactly the same steps.
+//!
+//! [`oprf_ipa`]: super::oprf_ipa
+//! [`shuffle_inputs`]: super::shuffle::shuffle_inputs
+//! [`group_size_histogram`]: super::diagnostics::group_size_histogram
+//! [`timestamp_sort`]: super::timestamp_sort
+//! [`segment`]: super::segment
+//! [`filter`]: super::prf_sharding::filter
+//! [`time_bucket`]: super::time_bucket
+//! [`sharded_attribute_cap_aggregate`]: super::prf_sharding::sharding::sharded_attribute_cap_aggregate
+//! [`time_boxed_attribute_cap_aggregate`]: super::prf_sharding::time_box::time_boxed_attribute_cap_aggregate
+use std::num::NonZeroU32;
+#[cfg(feature = "descriptive-gate")]
+use std::time::Instant;
+
+use ipa_macros::Step;
+
+#[cfg(feature = "descriptive-gate")]
+use super::diagnostics::GroupSizeParams;
+#[cfg(feature = "descriptive-gate")]
+use super::time_bucket::TimeBucketParams;
+use super::{compute_prf_for_inputs, rows_with_pseudonyms, Step};
+use crate::{
+    error::Error,
+    ff::{
+        boolean::Boolean,
+        boolean_array::{BA16, BA3, BA32, BA4, BA5, BA6, BA7, BA8},
+        CustomArray, Field, PrimeField, Serializable,
+    },
+    helpers::query::BreakdownKeySet,
+    protocol::{
+        context::{UpgradableContext, UpgradedContext},
+        dp::{add_noise, BinomialNoise},
+        ipa_prf::{
+            padding::{pad_inputs, PaddingParams},
+            prf_sharding::{
+                attribute_cap_aggregate, attribute_cap_aggregate_with_attributed_users, count_users,
+            },
+            scaling::scale_down,
+            segment::SegmentParams,
+            time_bucket::BucketBoundaries,
+        },
+    },
+    report::OprfReport,
+    secret_sharing::{
+        replicated::{malicious::ExtendableField, semi_honest::AdditiveShare as Replicated},
+        WeakSharedValue,
+    },
+};
+
+#[derive(Step)]
+pub(crate) enum ShuffleStep {
+    ShuffleInputs,
+}
+
+#[derive(Step)]
+pub(crate) enum SortStep {
+    SortByTimestamp,
+}
+
+/// Share of users that contributed to an aggregate. Both numbers include dummy users added by
+/// padding. If users are split into segments, every segment counts as a user.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Completeness {
+    pub processed_users: u64,
+    pub total_users: u64,
+}
+
+impl Completeness {
+    /// Completeness of an aggregate over all `total_users` users.
+    #[must_use]
+    pub fn complete(total_users: u64) -> Self {
+        Self {
+            processed_users: total_users,
+            total_users,
+        }
+    }
+
+    #[must_use]
+    pub fn is_partial(&self) -> bool {
+        self.processed_users < self.total_users
+    }
+
+    /// Returns the fraction of users that contributed to the aggregate, `1.0` if there are none.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn fraction(&self) -> f64 {
+        if self.total_users == 0 {
+            1.0
+        } else {
+            self.processed_users as f64 / self.total_users as f64
+        }
+    }
+}
+
+/// Layout of totals keyed by both breakdown and time bucket. Every breakdown has `time_buckets`
+/// consecutive totals, one per time bucket in chronological order, followed by those of the next
+/// breakdown.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutputShape {
+    pub breakdowns: u32,
+    pub time_buckets: u32,
+}
+
+/// Per-breakdown totals along with the share of users they cover.
+#[derive(Debug)]
+pub struct PartialAggregate<S> {
+    pub breakdowns: Vec<S>,
+    pub completeness: Completeness,
+    /// Layout of `breakdowns` if totals are keyed by time bucket, `None` if there is a single
+    /// total per breakdown.
+    pub shape: Option<OutputShape>,
+    /// Noisy histogram of group sizes, if the pipeline runs diagnostics.
+    pub group_sizes: Option<Vec<S>>,
+    /// Per-breakdown counts of users with at least one attributed conversion, if the pipeline
+    /// counts them.
+    pub attributed_users: Option<Vec<S>>,
+}
+
+/// Configures the stages of a [`Pipeline`].
+#[derive(Clone, Copy, Debug)]
+pub struct PipelineBuilder {
+    per_user_cap: u32,
+    num_breakdowns: u32,
+    attribution_window_seconds: Option<NonZeroU32>,
+    count_attributed_users: bool,
+    breakdown_allow_list: Option<BreakdownKeySet>,
+    padding: Option<PaddingParams>,
+    shuffle: bool,
+    #[cfg(feature = "descriptive-gate")]
+    shard_count: Option<NonZeroU32>,
+    #[cfg(feature = "descriptive-gate")]
+    deadline: Option<Instant>,
+    #[cfg(feature = "descriptive-gate")]
+    group_sizes: Option<GroupSizeParams>,
+    sort_by_timestamp: bool,
+    source_priority: bool,
+    #[cfg(feature = "descriptive-gate")]
+    time_buckets: Option<TimeBucketParams>,
+    segments: Option<SegmentParams>,
+    noise: Option<BinomialNoise>,
+    output_divisor: Option<NonZeroU32>,
+}
+
+impl PipelineBuilder {
+    /// Starts a pipeline that caps each user's contribution at `per_user_cap` and aggregates
+    /// contributions into `num_breakdowns` breakdowns. Only mandatory stages run, unless enabled
+    /// otherwise.
+    #[must_use]
+    pub fn new(per_user_cap: u32, num_breakdowns: u32) -> Self {
+        Self {
+            per_user_cap,
+            num_breakdowns,
+            attribution_window_seconds: None,
+            count_attributed_users: false,
+            breakdown_allow_list: None,
+            padding: None,
+            shuffle: false,
+            #[cfg(feature = "descriptive-gate")]
+            shard_count: None,
+            #[cfg(feature = "descriptive-gate")]
+            deadline: None,
+            #[cfg(feature = "descriptive-gate")]
+            group_sizes: None,
+            sort_by_timestamp: false,
+            source_priority: false,
+            #[cfg(feature = "descriptive-gate")]
+            time_buckets: None,
+            segments: None,
+            noise: None,
+            output_divisor: None,
+        }
+    }
+
+    /// Limits attribution to trigger events that happen within the given number of seconds after
+    /// the source event.
+    #[must_use]
+    pub fn with_attribution_window<W: Into<Option<NonZeroU32>>>(mut self, seconds: W) -> Self {
+        self.attribution_window_seconds = seconds.into();
+        self
+    }
+
+    /// Also counts users with at least one attributed conversion, regardless of its value, in
+    /// every breakdown. Counts are returned along with per-breakdown totals and get the same
+    /// noise. Not supported together with sharding or a deadline.
+    #[must_use]
+    pub fn with_attributed_user_counts(mut self) -> Self {
+        self.count_attributed_users = true;
+        self
+    }
+
+    /// Only aggregates contributions attributed to breakdown keys in `allowed`, contributions to
+    /// other breakdowns are zeroed out after capping.
+    #[must_use]
+    pub fn with_breakdown_allow_list<A: Into<Option<BreakdownKeySet>>>(
+        mut self,
+        allowed: A,
+    ) -> Self {
+        self.breakdown_allow_list = allowed.into();
+        self
+    }
+
+    /// Adds dummy users to the input before anything else happens.
+    ///
+    /// Dummy rows follow the real ones, so helpers could tell them apart once PRF values are
+    /// revealed. Padding therefore shuffles rows as well, see [`Self::with_shuffle`], which
+    /// requires reports with timestamps.
+    #[must_use]
+    pub fn with_padding<P: Into<Option<PaddingParams>>>(mut self, padding: P) -> Self {
+        self.padding = padding.into();
+        if self.padding.is_some() {
+            self = self.with_shuffle();
+        }
+        self
+    }
+
+    /// Shuffles input rows before PRF values are revealed.
+    ///
+    /// Rows of each user end up in random order, while attribution expects source events to
+    /// precede trigger events of the same user, so shuffled rows are sorted by timestamp again,
+    /// see [`Self::with_timestamp_sort`].
+    #[must_use]
+    pub fn with_shuffle(mut self) -> Self {
+        self.shuffle = true;
+        self.with_timestamp_sort()
+    }
+
+    /// Splits rows into shards by PRF value and runs attribution on each one independently. A
+    /// single shard is the same as not sharding at all.
+    #[cfg(feature = "descriptive-gate")]
+    #[must_use]
+    pub fn with_shards<S: Into<Option<NonZeroU32>>>(mut self, shard_count: S) -> Self {
+        self.shard_count = shard_count.into().filter(|&count| count > NonZeroU32::MIN);
+        self
+    }
+
+    /// Stops attributing new users once `deadline` passes on any helper and aggregates over the
+    /// users processed by then. Users are processed in batches, one at a time, so this takes
+    /// precedence over sharding.
+    #[cfg(feature = "descriptive-gate")]
+    #[must_use]
+    pub fn with_deadline<D: Into<Option<Instant>>>(mut self, deadline: D) -> Self {
+        self.deadline = deadline.into();
+        self
+    }
+
+    /// Computes a noisy histogram of group sizes once PRF values are revealed. It is returned
+    /// along with per-breakdown totals.
+    #[cfg(feature = "descriptive-gate")]
+    #[must_use]
+    pub fn with_group_size_diagnostics<P: Into<Option<GroupSizeParams>>>(
+        mut self,
+        params: P,
+    ) -> Self {
+        self.group_sizes = params.into();
+        self
+    }
+
+    /// Sorts rows of every user by timestamp once PRF values are revealed, for inputs that are not
+    /// in time order. Rows with the same timestamp end up in no particular order.
+    #[must_use]
+    pub fn with_timestamp_sort(mut self) -> Self {
+        self.sort_by_timestamp = true;
+        self
+    }
+
+    /// Breaks ties between source events of a user with the same timestamp by the priority that
+    /// input reports carry, the source event with the highest priority is attributed. Otherwise,
+    /// the last of them is.
+    #[must_use]
+    pub fn with_source_priority(mut self) -> Self {
+        self.source_priority = true;
+        self
+    }
+
+    /// Keys totals by the time bucket of the trigger event as well as by breakdown, see
+    /// [`OutputShape`] for the layout of totals. Not supported together with sharding or a
+    /// deadline.
+    #[cfg(feature = "descriptive-gate")]
+    #[must_use]
+    pub fn with_time_buckets<P: Into<Option<TimeBucketParams>>>(mut self, params: P) -> Self {
+        self.time_buckets = params.into();
+        self
+    }
+
+    /// Splits rows of every user into segments once PRF values are revealed and attributes them
+    /// as separate users, each capped at an equal share of `per_user_cap`. Not supported together
+    /// with counting attributed users.
+    #[must_use]
+    pub fn with_segments<S: Into<Option<SegmentParams>>>(mut self, segments: S) -> Self {
+        self.segments = segments.into();
+        self
+    }
+
+    /// Adds noise to the total of every breakdown.
+    #[must_use]
+    pub fn with_noise<N: Into<Option<BinomialNoise>>>(mut self, noise: N) -> Self {
+        self.noise = noise.into();
+        self
+    }
+
+    /// Divides the total of every breakdown by `divisor` before it is revealed, rounding
+    /// stochastically, so totals come out in coarser units than trigger values. Counts of
+    /// attributed users and diagnostics are not scaled.
+    #[must_use]
+    pub fn with_output_divisor<D: Into<Option<NonZeroU32>>>(mut self, divisor: D) -> Self {
+        self.output_divisor = divisor.into();
+        self
+    }
+
+    #[must_use]
+    pub fn build(self) -> Pipeline {
+        Pipeline { config: self }
+    }
+}
+
+/// Returns the width of the saturating sum used to enforce the per-user cap. It must be able to
+/// hold the cap itself and be at least as wide as the trigger value, otherwise the high bits of
+/// trigger values are lost when they are added to the sum. Sums wider than 8 bits are rounded up
+/// to 16 or 32 bits, so large caps, like revenue measured in cents, don't need a circuit per
+/// width.
+#[must_use]
+pub fn saturating_sum_bits(per_user_cap: u32, trigger_value_bits: u32) -> u32 {
+    // smallest width that fits `per_user_cap`, which is at most `2^width`
+    let cap_bits = u32::BITS - per_user_cap.saturating_sub(1).leading_zeros();
+    match cap_bits.max(trigger_value_bits).max(3) {
+        bits @ 3..=8 => bits,
+        9..=16 => 16,
+        _ => 32,
+    }
+}
+
+/// Rows a [`Pipeline`] runs on.
+enum Input<BK, TV, TS>
+where
+    BK: WeakSharedValue,
+    TV: WeakSharedValue,
+    TS: WeakSharedValue,
+{
+    /// Reports whose match keys go through PRF evaluation in the pipeline.
+    Reports(Vec<OprfReport<BK, TV, TS>>),
+    /// Reports along with the PRF values of their match keys, computed in an earlier query.
+    Pseudonymized(Vec<u64>, Vec<OprfReport<BK, TV, TS>>),
+}
+
+/// A sequence of OPRF IPA stages configured by [`PipelineBuilder`].
+#[derive(Clone, Copy, Debug)]
+pub struct Pipeline {
+    config: PipelineBuilder,
+}
+
+impl Pipeline {
+    /// Runs all configured stages on `input_rows` and returns a vector of secret-shared totals,
+    /// one per breakdown key in `[0, num_breakdowns)`. If the pipeline has a deadline, totals may
+    /// cover only some of the users, use [`Self::run_partial`] to find out how many. Capped
+    /// contributions of every user are summed in as many bits as [`saturating_sum_bits`] picks
+    /// for the cap and `TV`.
+    ///
+    /// ## Errors
+    /// Propagates errors from any of the stages.
+    ///
+    /// ## Panics
+    /// If the number of shards exceeds [`MAX_SHARDS`].
+    ///
+    /// [`MAX_SHARDS`]: super::prf_sharding::sharding::MAX_SHARDS
+    pub async fn run<C, BK, TV, TS, F>(
+        &self,
+        ctx: C,
+        input_rows: Vec<OprfReport<BK, TV, TS>>,
+    ) -> Result<Vec<Replicated<F>>, Error>
+    where
+        C: UpgradableContext,
+        C::UpgradedContext<Boolean>: UpgradedContext<Boolean, Share = Replicated<Boolean>>,
+        C::UpgradedContext<F>: UpgradedContext<F, Share = Replicated<F>>,
+        BK: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+        TV: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+        TS: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+        for<'a> &'a Replicated<TS>: IntoIterator<Item = Replicated<Boolean>>,
+        for<'a> &'a Replicated<TV>: IntoIterator<Item = Replicated<Boolean>>,
+        for<'a> &'a Replicated<BK>: IntoIterator<Item = Replicated<Boolean>>,
+        for<'a> <&'a Replicated<TV> as IntoIterator>::IntoIter: Send,
+        for<'a> <&'a Replicated<TS> as IntoIterator>::IntoIter: Send,
+        F: PrimeField + ExtendableField,
+        Replicated<F>: Serializable,
+    {
+        Ok(self
+            .run_partial::<C, BK, TV, TS, F>(ctx, input_rows)
+            .await?
+            .breakdowns)
+    }
+
+    /// Same as [`Self::run`], but also returns the share of users the totals cover. It is less
+    /// than one only if the pipeline has a deadline and it passed before all users were
+    /// processed.
+    ///
+    /// ## Errors
+    /// Propagates errors from any of the stages.
+    ///
+    /// ## Panics
+    /// If the number of shards exceeds [`MAX_SHARDS`], if attributed users are counted by a
+    /// pipeline that has shards, a deadline or segments, if totals are keyed by time bucket by a
+    /// pipeline that has shards or a deadline, or if the per-user cap is less than the number of
+    /// segments.
+    ///
+    /// [`MAX_SHARDS`]: super::prf_sharding::sharding::MAX_SHARDS
+    pub async fn run_partial<C, BK, TV, TS, F>(
+        &self,
+        ctx: C,
+        input_rows: Vec<OprfReport<BK, TV, TS>>,
+    ) -> Result<PartialAggregate<Replicated<F>>, Error>
+    where
+        C: UpgradableContext,
+        C::UpgradedContext<Boolean>: UpgradedContext<Boolean, Share = Replicated<Boolean>>,
+        C::UpgradedContext<F>: UpgradedContext<F, Share = Replicated<F>>,
+        BK: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+        TV: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+        TS: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+        for<'a> &'a Replicated<TS>: IntoIterator<Item = Replicated<Boolean>>,
+        for<'a> &'a Replicated<TV>: IntoIterator<Item = Replicated<Boolean>>,
+        for<'a> &'a Replicated<BK>: IntoIterator<Item = Replicated<Boolean>>,
+        for<'a> <&'a Replicated<TV> as IntoIterator>::IntoIter: Send,
+        for<'a> <&'a Replicated<TS> as IntoIterator>::IntoIter: Send,
+        F: PrimeField + ExtendableField,
+        Replicated<F>: Serializable,
+    {
+        self.run_input::<C, BK, TV, TS, F>(ctx, Input::Reports(input_rows))
+            .await
+    }
+
+    /// Same as [`Self::run_partial`], but over rows whose users are already known: `pseudonyms`
+    /// holds the PRF of the match key of every row in `input_rows`, computed in an earlier query
+    /// under the helpers' persistent PRF key, see [`compute_match_key_pseudonyms`]. Match keys
+    /// of `input_rows` are ignored, and stages that run before PRF evaluation are skipped.
+    ///
+    /// ## Errors
+    /// Propagates errors from any of the stages.
+    ///
+    /// ## Panics
+    /// For the same reasons [`Self::run_partial`] does, if `pseudonyms` and `input_rows` have
+    /// different lengths, or if the pipeline pads inputs with dummy users, who have no pseudonyms.
+    ///
+    /// [`compute_match_key_pseudonyms`]: super::compute_match_key_pseudonyms
+    pub async fn run_pseudonymized_partial<C, BK, TV, TS, F>(
+        &self,
+        ctx: C,
+        pseudonyms: Vec<u64>,
+        input_rows: Vec<OprfReport<BK, TV, TS>>,
+    ) -> Result<PartialAggregate<Replicated<F>>, Error>
+    where
+        C: UpgradableContext,
+        C::UpgradedContext<Boolean>: UpgradedContext<Boolean, Share = Replicated<Boolean>>,
+        C::UpgradedContext<F>: UpgradedContext<F, Share = Replicated<F>>,
+        BK: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+        TV: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+        TS: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+        for<'a> &'a Replicated<TS>: IntoIterator<Item = Replicated<Boolean>>,
+        for<'a> &'a Replicated<TV>: IntoIterator<Item = Replicated<Boolean>>,
+        for<'a> &'a Replicated<BK>: IntoIterator<Item = Replicated<Boolean>>,
+        for<'a> <&'a Replicated<TV> as IntoIterator>::IntoIter: Send,
+        for<'a> <&'a Replicated<TS> as IntoIterator>::IntoIter: Send,
+        F: PrimeField + ExtendableField,
+        Replicated<F>: Serializable,
+    {
+        assert_eq!(
+            pseudonyms.len(),
+            input_rows.len(),
+            "every input row must have a pseudonym"
+        );
+        assert!(
+            self.config.padding.is_none(),
+            "pseudonymized input can't be padded with dummy users"
+        );
+        self.run_input::<C, BK, TV, TS, F>(ctx, Input::Pseudonymized(pseudonyms, input_rows))
+            .await
+    }
+
+    async fn run_input<C, BK, TV, TS, F>(
+        &self,
+        ctx: C,
+        input: Input<BK, TV, TS>,
+    ) -> Result<PartialAggregate<Replicated<F>>, Error>
+    where
+        C: UpgradableContext,
+        C::UpgradedContext<Boolean>: UpgradedContext<Boolean, Share = Replicated<Boolean>>,
+        C::UpgradedContext<F>: UpgradedContext<F, Share = Replicated<F>>,
+        BK: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+        TV: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+        TS: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+        for<'a> &'a Replicated<TS>: IntoIterator<Item = Replicated<Boolean>>,
+        for<'a> &'a Replicated<TV>: IntoIterator<Item = Replicated<Boolean>>,
+        for<'a> &'a Replicated<BK>: IntoIterator<Item = Replicated<Boolean>>,
+        for<'a> <&'a Replicated<TV> as IntoIterator>::IntoIter: Send,
+        for<'a> <&'a Replicated<TS> as IntoIterator>::IntoIter: Send,
+        F: PrimeField + ExtendableField,
+        Replicated<F>: Serializable,
+    {
+        let ss_bits = saturating_sum_bits(self.config.per_user_cap, <TV as WeakSharedValue>::BITS);
+        match ss_bits {
+            3 => self.run_with_sum::<C, BK, TV, TS, BA3, F>(ctx, input).await,
+            4 => self.run_with_sum::<C, BK, TV, TS, BA4, F>(ctx, input).await,
+            5 => self.run_with_sum::<C, BK, TV, TS, BA5, F>(ctx, input).await,
+            6 => self.run_with_sum::<C, BK, TV, TS, BA6, F>(ctx, input).await,
+            7 => self.run_with_sum::<C, BK, TV, TS, BA7, F>(ctx, input).await,
+            8 => self.run_with_sum::<C, BK, TV, TS, BA8, F>(ctx, input).await,
+            16 => {
+                self.run_with_sum::<C, BK, TV, TS, BA16, F>(ctx, input)
+                    .await
+            }
+            32 => {
+                self.run_with_sum::<C, BK, TV, TS, BA32, F>(ctx, input)
+                    .await
+            }
+            _ => unreachable!("saturating sum of {ss_bits} bits is not supported"),
+        }
+    }
+
+    /// Runs the pipeline with contributions of every user summed in `SS`, which must be able to
+    /// hold the per-user cap.
+    async fn run_with_sum<C, BK, TV, TS, SS, F>(
+        &self,
+        ctx: C,
+        input: Input<BK, TV, TS>,
+    ) -> Result<PartialAggregate<Replicated<F>>, Error>
+    where
+        C: UpgradableContext,
+        C::UpgradedContext<Boolean>: UpgradedContext<Boolean, Share = Replicated<Boolean>>,
+        C::UpgradedContext<F>: UpgradedContext<F, Share = Replicated<F>>,
+        BK: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+        TV: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+        TS: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+        SS: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+        for<'a> &'a Replicated<SS>: IntoIterator<Item = Replicated<Boolean>>,
+        for<'a> &'a Replicated<TS>: IntoIterator<Item = Replicated<Boolean>>,
+        for<'a> &'a Replicated<TV>: IntoIterator<Item = Replicated<Boolean>>,
+        for<'a> &'a Replicated<BK>: IntoIterator<Item = Replicated<Boolean>>,
+        for<'a> <&'a Replicated<SS> as IntoIterator>::IntoIter: Send,
+        for<'a> <&'a Replicated<TV> as IntoIterator>::IntoIter: Send,
+        for<'a> <&'a Replicated<TS> as IntoIterator>::IntoIter: Send,
+        F: PrimeField + ExtendableField,
+        Replicated<F>: Serializable,
+    {
+        let mut config = self.config;
+        assert!(
+            !config.count_attributed_users || config.segments.is_none(),
+            "attributed users can't be counted by pipelines with segments"
+        );
+        #[cfg(feature = "descriptive-gate")]
+        assert!(
+            !config.count_attributed_users
+                || (config.shard_count.is_none() && config.deadline.is_none()),
+            "attributed users can't be counted by pipelines with shards or a deadline"
+        );
+        #[cfg(feature = "descriptive-gate")]
+        assert!(
+            config.time_buckets.is_none()
+                || (config.shard_count.is_none() && config.deadline.is_none()),
+            "totals can't be keyed by time bucket by pipelines with shards or a deadline"
+        );
+        #[cfg(feature = "descriptive-gate")]
+        let time_buckets = config
+            .time_buckets
+            .map(|params| params.boundaries(<TS as WeakSharedValue>::BITS))
+            .transpose()
+            .map_err(|e| Error::InvalidQueryParameter(e.into()))?;
+        #[cfg(not(feature = "descriptive-gate"))]
+        let time_buckets = None;
+
+        let prfd_inputs = match input {
+            Input::Reports(input_rows) => {
+                let input_rows = match config.padding {
+                    Some(padding) => {
+                        pad_inputs(ctx.narrow(&Step::PadInputs), input_rows, padding).await?
+                    }
+                    None => input_rows,
+                };
+
+                let input_rows = if config.shuffle {
+                    super::shuffle::shuffle_inputs(
+                        ctx.narrow(&ShuffleStep::ShuffleInputs),
+                        input_rows,
+                    )
+                    .await?
+                } else {
+                    input_rows
+                };
+
+                compute_prf_for_inputs(
+                    ctx.narrow(&Step::ConvertInputRowsToPrf),
+                    input_rows,
+                    config.source_priority,
+                )
+                .await?
+            }
+            Input::Pseudonymized(pseudonyms, input_rows) => {
+                rows_with_pseudonyms(pseudonyms, input_rows, config.source_priority)
+            }
+        };
+
+        #[cfg(feature = "descriptive-gate")]
+        let group_sizes = config
+            .group_sizes
+            .map(|params| super::diagnostics::group_size_histogram(&ctx, &prfd_inputs, params));
+        #[cfg(not(feature = "descriptive-gate"))]
+        let group_sizes = None;
+
+        let prfd_inputs = if config.sort_by_timestamp {
+            super::timestamp_sort::sort_rows_by_timestamp(
+                ctx.narrow(&SortStep::SortByTimestamp),
+                prfd_inputs,
+            )
+            .await?
+        } else {
+            prfd_inputs
+        };
+
+        // every user contributes at most `per_user_cap` to any total and there are no more users
+        // than rows
+        let max_total = u128::from(config.per_user_cap) * prfd_inputs.len() as u128;
+        let prfd_inputs = match config.segments {
+            Some(segments) => {
+                config.per_user_cap = segments
+                    .segment_cap(config.per_user_cap)
+                    .expect("per-user cap must be at least the number of segments");
+                segments.split(prfd_inputs)
+            }
+            None => prfd_inputs,
+        };
+
+        let allowed_breakdowns = config
+            .breakdown_allow_list
+            .map(|keys| keys.iter().collect::<Vec<_>>());
+
+        #[cfg(feature = "descriptive-gate")]
+        let output = if let Some(deadline) = config.deadline {
+            super::prf_sharding::time_box::time_boxed_attribute_cap_aggregate::<
+                C,
+                BK,
+                TV,
+                TS,
+                SS,
+                Replicated<F>,
+                F,
+            >(
+                ctx.clone(),
+                prfd_inputs,
+                config.attribution_window_seconds,
+                config.per_user_cap,
+                config.num_breakdowns,
+                allowed_breakdowns.as_deref(),
+                deadline,
+            )
+            .await
+        } else if let Some(shard_count) = config.shard_count {
+            let users = count_users(&prfd_inputs);
+            super::prf_sharding::sharding::sharded_attribute_cap_aggregate::<
+                C,
+                BK,
+                TV,
+                TS,
+                SS,
+                Replicated<F>,
+                F,
+            >(
+                ctx.clone(),
+                prfd_inputs,
+                config.attribution_window_seconds,
+                config.per_user_cap,
+                config.num_breakdowns,
+                allowed_breakdowns.as_deref(),
+                shard_count,
+            )
+            .await
+            .map(|breakdowns| PartialAggregate {
+                breakdowns,
+                completeness: Completeness::complete(users),
+                shape: None,
+                group_sizes: None,
+                attributed_users: None,
+            })
+        } else {
+            attribute_cap_aggregate_all(
+                ctx.clone(),
+                &config,
+                allowed_breakdowns.as_deref(),
+                time_buckets.as_ref(),
+                prfd_inputs,
+            )
+            .await
+        };
+        #[cfg(not(feature = "descriptive-gate"))]
+        let output = attribute_cap_aggregate_all(
+            ctx.clone(),
+            &config,
+            allowed_breakdowns.as_deref(),
+            time_buckets.as_ref(),
+            prfd_inputs,
+        )
+        .await;
+        let mut output = output?;
+
+        if let Some(noise) = config.noise {
+            add_noise(&ctx.narrow(&Step::AddNoise), noise, &mut output.breakdowns);
+            if let Some(attributed_users) = &mut output.attributed_users {
+                add_noise(
+                    &ctx.narrow(&Step::AddNoiseToAttributedUsers),
+                    noise,
+                    attributed_users,
+                );
+            }
+        }
+        output.group_sizes = group_sizes;
+
+        if let Some(divisor) = config.output_divisor {
+            let max_abs = max_total + config.noise.map_or(0, |noise| u128::from(noise.max_abs()));
+            output.breakdowns = scale_down(
+                ctx.narrow(&Step::ScaleOutput),
+                output.breakdowns,
+                divisor,
+                max_abs,
+            )
+            .await?;
+        }
+
+        Ok(output)
+    }
+}
+
+async fn attribute_cap_aggregate_all<C, BK, TV, TS, SS, F>(
+    ctx: C,
+    config: &PipelineBuilder,
+    allowed_breakdowns: Option<&[u32]>,
+    time_buckets: Option<&BucketBoundaries>,
+    prfd_inputs: Vec<super::prf_sharding::PrfShardedIpaInputRow<BK, TV, TS>>,
+) -> Result<PartialAggregate<Replicated<F>>, Error>
+where
+    C: UpgradableContext,
+    C::UpgradedContext<Boolean>: UpgradedContext<Boolean, Share = Replicated<Boolean>>,
+    C::UpgradedContext<F>: UpgradedContext<F, Share = Replicated<F>>,
+    BK: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    TV: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    TS: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    SS: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    for<'a> &'a Replicated<SS>: IntoIterator<Item = Replicated<Boolean>>,
+    for<'a> &'a Replicated<TS>: IntoIterator<Item = Replicated<Boolean>>,
+    for<'a> &'a Replicated<TV>: IntoIterator<Item = Replicated<Boolean>>,
+    for<'a> &'a Replicated<BK>: IntoIterator<Item = Replicated<Boolean>>,
+    for<'a> <&'a Replicated<SS> as IntoIterator>::IntoIter: Send,
+    for<'a> <&'a Replicated<TV> as IntoIterator>::IntoIter: Send,
+    for<'a> <&'a Replicated<TS> as IntoIterator>::IntoIter: Send,
+    F: PrimeField + ExtendableField,
+    Replicated<F>: Serializable,
+{
+    let users = count_users(&prfd_inputs);
+
+    let (breakdowns, attributed_users) = if config.count_attributed_users {
+        let (breakdowns, attributed_users) =
+            attribute_cap_aggregate_with_attributed_users::<C, BK, TV, TS, SS, Replicated<F>, F>(
+                ctx,
+                prfd_inputs,
+                config.attribution_window_seconds,
+                config.per_user_cap,
+                config.num_breakdowns,
+                allowed_breakdowns,
+                time_buckets,
+            )
+            .await?;
+        (breakdowns, Some(attributed_users))
+    } else {
+        let breakdowns = attribute_cap_aggregate::<C, BK, TV, TS, SS, Replicated<F>, F>(
+            ctx,
+            prfd_inputs,
+            config.attribution_window_seconds,
+            config.per_user_cap,
+            config.num_breakdowns,
+            allowed_breakdowns,
+            time_buckets,
+        )
+        .await?;
+        (breakdowns, None)
+    };
+
+    Ok(PartialAggregate {
+        breakdowns,
+        completeness: Completeness::complete(users),
+        shape: time_buckets.map(|time_buckets| OutputShape {
+            breakdowns: config.num_breakdowns,
+            time_buckets: u32::try_from(time_buckets.len()).unwrap(),
+        }),
+        group_sizes: None,
+        attributed_users,
+    })
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use super::{saturating_sum_bits, PipelineBuilder};
+    use crate::{
+        ff::{
+            boolean_array::{BA20, BA3, BA8},
+            Fp31,
+        },
+        helpers::query::BreakdownKeySet,
+        protocol::ipa_prf::segment::SegmentParams,
+        test_executor::run,
+        test_fixture::{ipa::TestRawDataRecord, Reconstruct, Runner, TestWorld},
+    };
+
+    fn record(
+        timestamp: u64,
+        user_id: u64,
+        breakdown_key: u64,
+        trigger_value: u64,
+    ) -> TestRawDataRecord {
+        TestRawDataRecord {
+            timestamp,
+            user_id,
+            is_trigger_report: trigger_value > 0,
+            breakdown_key,
+            trigger_value,
+        }
+    }
+
+    #[test]
+    fn saturating_sum_width() {
+        assert_eq!(3, saturating_sum_bits(1, 3));
+        assert_eq!(3, saturating_sum_bits(8, 3));
+        assert_eq!(4, saturating_sum_bits(9, 3));
+        assert_eq!(7, saturating_sum_bits(128, 3));
+        assert_eq!(8, saturating_sum_bits(1, 8));
+        assert_eq!(8, saturating_sum_bits(128, 8));
+        assert_eq!(8, saturating_sum_bits(256, 3));
+        assert_eq!(16, saturating_sum_bits(257, 3));
+        assert_eq!(16, saturating_sum_bits(5, 16));
+        assert_eq!(32, saturating_sum_bits(100_000, 8));
+        assert_eq!(32, saturating_sum_bits(1, 32));
+        assert_eq!(32, saturating_sum_bits(u32::MAX, 3));
+    }
+
+    #[test]
+    fn attribution_window() {
+        const EXPECTED: &[u128] = &[0, 5, 0, 0, 0, 0, 0, 0];
+
+        run(|| async {
+            let records = vec![
+                record(0, 12345, 1, 0),
+                record(10, 12345, 0, 5),
+                record(0, 68362, 2, 0),
+                // out of the attribution window
+                record(100, 68362, 0, 2),
+            ];
+            let pipeline = PipelineBuilder::new(32, 8)
+                .with_attribution_window(NonZeroU32::new(50))
+                .build();
+
+            let result: Vec<_> = TestWorld::default()
+                .semi_honest(records.into_iter(), |ctx, input_rows| async move {
+                    pipeline
+                        .run::<_, BA8, BA3, BA20, Fp31>(ctx, input_rows)
+                        .await
+                        .unwrap()
+                })
+                .await
+                .reconstruct();
+            assert_eq!(
+                result,
+                EXPECTED
+                    .iter()
+                    .map(|i| Fp31::try_from(*i).unwrap())
+                    .collect::<Vec<_>>()
+            );
+        });
+    }
+
+    #[test]
+    fn segments() {
+        const EXPECTED: &[u128] = &[0, 3, 4, 0];
+
+        run(|| async {
+            let records = vec![
+                record(0, 12345, 1, 0),
+                record(10, 12345, 0, 3),
+                // in the second segment, without a source event to attribute it to
+                record(20, 12345, 0, 3),
+                record(0, 68362, 2, 0),
+                // capped at half of the per-user cap
+                record(10, 68362, 0, 7),
+            ];
+            let pipeline = PipelineBuilder::new(8, 4)
+                .with_segments(SegmentParams {
+                    max_rows: NonZeroU32::new(2).unwrap(),
+                    max_segments: NonZeroU32::new(2).unwrap(),
+                })
+                .build();
+
+            let result: Vec<_> = TestWorld::default()
+                .semi_honest(records.into_iter(), |ctx, input_rows| async move {
+                    pipeline
+                        .run::<_, BA8, BA3, BA20, Fp31>(ctx, input_rows)
+                        .await
+                        .unwrap()
+                })
+                .await
+                .reconstruct();
+            assert_eq!(
+                result,
+                EXPECTED
+                    .iter()
+                    .map(|i| Fp31::try_from(*i).unwrap())
+                    .collect::<Vec<_>>()
+            );
+        });
+    }
+
+    #[test]
+    fn breakdown_allow_list() {
+        const EXPECTED: &[u128] = &[0, 5, 0, 4];
+
+        run(|| async {
+            let records = vec![
+                record(0, 12345, 1, 0),
+                record(10, 12345, 0, 5),
+                record(0, 68362, 2, 0),
+                record(10, 68362, 0, 2),
+                record(0, 73456, 3, 0),
+                record(10, 73456, 0, 4),
+            ];
+            let pipeline = PipelineBuilder::new(8, 4)
+                .with_breakdown_allow_list([1, 3].into_iter().collect::<BreakdownKeySet>())
+                .build();
+
+            let result: Vec<_> = TestWorld::default()
+                .semi_honest(records.into_iter(), |ctx, input_rows| async move {
+                    pipeline
+                        .run::<_, BA8, BA3, BA20, Fp31>(ctx, input_rows)
+                        .await
+                        .unwrap()
+                })
+                .await
+                .reconstruct();
+            assert_eq!(
+                result,
+                EXPECTED
+                    .iter()
+                    .map(|i| Fp31::try_from(*i).unwrap())
+                    .collect::<Vec<_>>()
+            );
+        });
+    }
+
+    #[cfg(feature = "descriptive-gate")]
+    #[test]
+    fn time_buckets() {
+        use super::OutputShape;
+        use crate::{
+            ff::Field,
+            protocol::ipa_prf::time_bucket::{BucketSize, TimeBucketParams},
+        };
+
+        const DAY: u64 = 86_400;
+        // 20-bit timestamps span 13 days
+        const TIME_BUCKETS: usize = 13;
+
+        run(|| async {
+            let records = vec![
+                record(0, 12345, 1, 0),
+                record(10, 12345, 0, 5),
+                record(0, 68362, 2, 0),
+                record(2 * DAY + 5, 68362, 0, 2),
+                record(DAY + 5, 73456, 3, 0),
+                record(12 * DAY, 73456, 0, 4),
+            ];
+            let pipeline = PipelineBuilder::new(8, 4)
+                .with_time_buckets(TimeBucketParams {
+                    query_epoch: 0,
+                    bucket_size: BucketSize::Days(NonZeroU32::new(1).unwrap()),
+                    utc_offset_seconds: 0,
+                })
+                .build();
+
+            let (results, shapes): (Vec<_>, Vec<_>) = TestWorld::default()
+                .semi_honest(records.into_iter(), |ctx, input_rows| async move {
+                    let output = pipeline
+                        .run_partial::<_, BA8, BA3, BA20, Fp31>(ctx, input_rows)
+                        .await
+                        .unwrap();
+                    (output.breakdowns, output.shape)
+                })
+                .await
+                .into_iter()
+                .unzip();
+
+            assert!(shapes.iter().all(|shape| *shape
+                == Some(OutputShape {
+                    breakdowns: 4,
+                    time_buckets: 13,
+                })));
+            // totals are attributed to the time bucket of the trigger event
+            let mut expected = vec![Fp31::ZERO; 4 * TIME_BUCKETS];
+            expected[TIME_BUCKETS] = Fp31::try_from(5).unwrap();
+            expected[2 * TIME_BUCKETS + 2] = Fp31::try_from(2).unwrap();
+            expected[3 * TIME_BUCKETS + 12] = Fp31::try_from(4).unwrap();
+            assert_eq!(<[_; 3]>::try_from(results).unwrap().reconstruct(), expected);
+        });
+    }
+
+    #[cfg(feature = "descriptive-gate")]
+    #[test]
+    fn timestamp_sort() {
+        const EXPECTED: &[u128] = &[0, 5, 5, 0];
+
+        run(|| async {
+            // trigger events come before the source events they are attributed to
+            let records = vec![
+                record(30, 12345, 0, 5),
+                record(10, 12345, 1, 0),
+                record(20, 68362, 0, 2),
+                record(50, 68362, 0, 3),
+                record(0, 68362, 2, 0),
+            ];
+            for pipeline in [
+                PipelineBuilder::new(32, 4).with_timestamp_sort(),
+                PipelineBuilder::new(32, 4)
+                    .with_shuffle()
+                    .with_timestamp_sort(),
+            ] {
+                let pipeline = pipeline.build();
+                let result: Vec<_> = TestWorld::default()
+                    .semi_honest(records.clone().into_iter(), |ctx, input_rows| async move {
+                        pipeline
+                            .run::<_, BA8, BA3, BA20, Fp31>(ctx, input_rows)
+                            .await
+                            .unwrap()
+                    })
+                    .await
+                    .reconstruct();
+                assert_eq!(
+                    result,
+                    EXPECTED
+                        .iter()
+                        .map(|i| Fp31::try_from(*i).unwrap())
+                        .collect::<Vec<_>>()
+                );
+            }
+        });
+    }
+
+    #[cfg(feature = "descriptive-gate")]
+    #[test]
+    fn deadline() {
+        use std::time::{Duration, Instant};
+
+        use super::Completeness;
+
+        run(|| async {
+            let records = vec![
+                record(0, 12345, 1, 0),
+                record(10, 12345, 0, 5),
+                record(0, 68362, 2, 0),
+                record(20, 68362, 0, 2),
+            ];
+
+            for (deadline, expected, processed_users) in [
+                (Instant::now() + Duration::from_secs(3600), [0, 5, 2, 0], 2),
+                (Instant::now(), [0; 4], 0),
+            ] {
+                let pipeline = PipelineBuilder::new(32, 4).with_deadline(deadline).build();
+                let (results, completeness): (Vec<_>, Vec<_>) = TestWorld::default()
+                    .semi_honest(records.clone().into_iter(), |ctx, input_rows| async move {
+                        let output = pipeline
+                            .run_partial::<_, BA8, BA3, BA20, Fp31>(ctx, input_rows)
+                            .await
+                            .unwrap();
+                        (output.breakdowns, output.completeness)
+                    })
+                    .await
+                    .into_iter()
+                    .unzip();
+
+                assert!(completeness.iter().all(|c| *c
+                    == Completeness {
+                        processed_users,
+                        total_users: 2,
+                    }));
+                assert_eq!(
+                    <[_; 3]>::try_from(results).unwrap().reconstruct(),
+                    expected
+                        .iter()
+                        .map(|i| Fp31::try_from(*i).unwrap())
+                        .collect::<Vec<_>>()
+                );
+            }
+        });
+    }
+}
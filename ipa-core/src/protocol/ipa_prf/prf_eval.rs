@@ -1,8 +1,11 @@
+use std::fmt::{Debug, Formatter};
+
 use ipa_macros::Step;
 
 use crate::{
     error::Error,
     ff::{curve_points::RP25519, ec_prime_field::Fp25519},
+    helpers::HelperIdentity,
     protocol::{
         basics::{Reveal, SecureMul},
         context::Context,
@@ -43,12 +46,6 @@ where
     ctx.try_join(futures).await
 }
 
-impl From<AdditiveShare<Fp25519>> for AdditiveShare<RP25519> {
-    fn from(s: AdditiveShare<Fp25519>) -> Self {
-        AdditiveShare::new(RP25519::from(s.left()), RP25519::from(s.right()))
-    }
-}
-
 /// generates PRF key k as secret sharing over Fp25519
 pub fn gen_prf_key<C>(ctx: &C) -> AdditiveShare<Fp25519>
 where
@@ -59,6 +56,50 @@ where
         .generate_replicated(RecordId(0))
 }
 
+/// PRF key that a helper keeps across queries, so PRF values computed in one query can be
+/// matched with the ones computed in another.
+///
+/// The key is the sum of three components, one for every pair of helpers. Every helper holds the
+/// two components it shares with its peers, so no single helper knows the key. Helpers may play
+/// different roles in different queries, that is why components are tied to helper identities.
+#[derive(Clone)]
+pub struct PersistentPrfKey {
+    components: [(HelperIdentity, Fp25519); 2],
+}
+
+impl PersistentPrfKey {
+    /// Creates the key from the components this helper shares with each of its peers.
+    #[must_use]
+    pub fn new(components: [(HelperIdentity, Fp25519); 2]) -> Self {
+        Self { components }
+    }
+
+    /// Returns the share of the key this helper holds in a query where `left` and `right` are its
+    /// peers, or `None` if it does not have a component shared with one of them.
+    #[must_use]
+    pub fn share(
+        &self,
+        left: HelperIdentity,
+        right: HelperIdentity,
+    ) -> Option<AdditiveShare<Fp25519>> {
+        let component = |peer| {
+            self.components
+                .iter()
+                .find_map(|&(id, component)| (id == peer).then_some(component))
+        };
+
+        Some(AdditiveShare::new(component(left)?, component(right)?))
+    }
+}
+
+impl Debug for PersistentPrfKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PersistentPrfKey")
+            .field("peers", &self.components.map(|(id, _)| id))
+            .finish_non_exhaustive()
+    }
+}
+
 /// evaluates the Dodis-Yampolski PRF g^(1/(k+x))
 /// the input x and k are secret shared over finite field Fp25519, i.e. the scalar field of curve 25519
 /// PRF key k needs to be generated using `gen_prf_key`
@@ -106,7 +147,8 @@ mod test {
 
     use crate::{
         ff::{curve_points::RP25519, ec_prime_field::Fp25519},
-        protocol::ipa_prf::prf_eval::compute_match_key_pseudonym,
+        helpers::HelperIdentity,
+        protocol::ipa_prf::prf_eval::{compute_match_key_pseudonym, PersistentPrfKey},
         secret_sharing::{replicated::semi_honest::AdditiveShare, IntoShares},
         test_executor::run,
         test_fixture::{Reconstruct, Runner, TestWorld},
@@ -195,4 +237,34 @@ mod test {
             assert_eq!(result[0], result[1]);
         });
     }
+
+    #[test]
+    fn persistent_key_shares() {
+        let [c12, c23, c31] = [
+            Fp25519::from(3_u64),
+            Fp25519::from(5_u64),
+            Fp25519::from(7_u64),
+        ];
+        let (one, two, three) = (
+            HelperIdentity::ONE,
+            HelperIdentity::TWO,
+            HelperIdentity::THREE,
+        );
+        let keys = [
+            PersistentPrfKey::new([(two, c12), (three, c31)]),
+            PersistentPrfKey::new([(three, c23), (one, c12)]),
+            PersistentPrfKey::new([(one, c31), (two, c23)]),
+        ];
+
+        // Helpers play roles in the reverse order of their identities.
+        let shares = [
+            keys[2].share(one, two).unwrap(),
+            keys[1].share(three, one).unwrap(),
+            keys[0].share(two, three).unwrap(),
+        ];
+        assert_eq!(c12 + c23 + c31, shares.reconstruct());
+
+        // A helper cannot take part in a query with a helper it does not share a component with.
+        assert!(keys[0].share(one, two).is_none());
+    }
 }
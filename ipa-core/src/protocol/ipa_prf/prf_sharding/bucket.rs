@@ -1,18 +1,34 @@
+use std::iter::zip;
+
 use embed_doc_image::embed_doc_image;
 use ipa_macros::Step;
 
+use futures::{stream::iter as stream_iter, StreamExt, TryStreamExt};
+
 use crate::{
     error::Error,
-    ff::{PrimeField, Serializable},
+    ff::{
+        boolean::Boolean, boolean_array::BA64, CustomArray, Expand, Field, PrimeField, Serializable,
+    },
     protocol::{
-        basics::SecureMul, context::UpgradedContext, ipa_prf::prf_sharding::BinaryTreeDepthStep,
+        basics::{SecureMul, ShareKnownValue, SumOfProducts},
+        context::{Context, UpgradedContext},
+        ipa_prf::{
+            boolean_ops::addition_sequential::integer_add_chunk, prf_sharding::BinaryTreeDepthStep,
+        },
         RecordId,
     },
     secret_sharing::{
-        replicated::malicious::ExtendableField, BitDecomposed, Linear as LinearSecretSharing,
+        replicated::{malicious::ExtendableField, semi_honest::AdditiveShare as Replicated},
+        BitDecomposed, Linear as LinearSecretSharing, SharedValue, WeakSharedValue,
     },
+    seq_join::{seq_join, SeqJoin},
 };
 
+/// Breakdown keys up to this many bits are moved to buckets in a single stage, wider keys are
+/// moved in two stages. See [`move_single_value_to_bucket_two_stage`].
+pub const MAX_SINGLE_STAGE_BREAKDOWN_KEY_BITS: u32 = 8;
+
 #[derive(Step)]
 pub enum BucketStep {
     #[dynamic(256)]
@@ -38,6 +54,12 @@ impl From<usize> for BucketStep {
     }
 }
 
+#[derive(Step)]
+pub(crate) enum TwoStageBucketStep {
+    HighBits,
+    LowBits,
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum MoveToBucketError {
     #[error("Bad value for the breakdown key: {0}")]
@@ -74,22 +96,207 @@ impl From<MoveToBucketError> for Error {
 /// This would potentially not be needed in IPA (as the breakdown key is provided by the report collector, so a bad value only spoils their own result) but useful for PAM.
 /// This can be by passing `robust` as true.
 ///
+/// Only the first `num_breakdowns` buckets are produced, so the tree is pruned at every level to
+/// avoid multiplications for buckets that are not used.
+///
 /// ## Errors
-/// If `breakdown_count` does not fit into `BK` bits or greater than or equal to $2^9$
+/// If `num_breakdowns` does not fit into `BK` bits or greater than or equal to $2^9$
 pub async fn move_single_value_to_bucket<C, S, F>(
     ctx: C,
     record_id: RecordId,
     bd_key: BitDecomposed<S>,
     value: S,
-    breakdown_count: usize,
+    num_breakdowns: u32,
     robust: bool,
 ) -> Result<Vec<S>, Error>
 where
     C: UpgradedContext<F, Share = S>,
     S: LinearSecretSharing<F> + Serializable + SecureMul<C>,
     F: PrimeField + ExtendableField,
+{
+    move_to_bucket(
+        ctx,
+        record_id,
+        bd_key,
+        value,
+        num_breakdowns,
+        robust,
+        Clone::clone,
+    )
+    .await
+}
+
+/// Same as [`move_single_value_to_bucket`], but for values that are boolean arrays, so the
+/// breakdown key bits do not need to be converted to a prime field.
+///
+/// Buckets are boolean arrays of the same width as `value`. They can be added up across rows with
+/// [`aggregate_boolean_buckets`], so that only the final sums need to leave GF(2).
+///
+/// ## Errors
+/// If `num_breakdowns` does not fit into `BK` bits or greater than or equal to $2^9$
+pub async fn move_single_value_to_bucket_boolean<C, V>(
+    ctx: C,
+    record_id: RecordId,
+    bd_key: BitDecomposed<Replicated<Boolean>>,
+    value: Replicated<V>,
+    num_breakdowns: u32,
+    robust: bool,
+) -> Result<Vec<Replicated<V>>, Error>
+where
+    C: Context,
+    V: Field + Expand<Input = Boolean>,
+{
+    // Every bit of the value is multiplied by the same bit of the breakdown key.
+    move_to_bucket(
+        ctx,
+        record_id,
+        bd_key,
+        value,
+        num_breakdowns,
+        robust,
+        Replicated::<V>::expand,
+    )
+    .await
+}
+
+/// Output of [`move_single_value_to_bucket_two_stage`] for a single row. Bucket `k` of the row is
+/// the product of super-bucket `k / low.len()` and indicator `k % low.len()`, it is never computed
+/// on its own.
+pub struct TwoStageBuckets<S> {
+    high: Vec<S>,
+    low: Vec<S>,
+}
+
+/// Same as [`move_single_value_to_bucket`], but for breakdown keys that are too wide to move values
+/// through a single tree, which takes $O(2^{|BK|})$ multiplications per row.
+///
+/// The breakdown key is split into high and low bits, with `l = |BK| / 2` low bits. In the first
+/// stage, `value` is moved to one of the super-buckets by the high bits of the key, every
+/// super-bucket spans `2^l` buckets. In the second stage, a share of one is moved by the low bits,
+/// which produces an indicator of the bucket within the super-bucket. Both stages use the same
+/// tree as [`move_single_value_to_bucket`], so a row takes $O(2^{|BK|/2 + 1})$ multiplications.
+///
+/// Buckets are the products of super-buckets and indicators. Rather than multiplying them out for
+/// every row, [`aggregate_two_stage_buckets`] adds up these products over all rows with a single
+/// multiplication per bucket.
+///
+/// If `robust` is not set, a contribution with a breakdown key outside of `[0, num_breakdowns)`
+/// either ends up in one of the output buckets or is dropped.
+///
+/// ## Errors
+/// If `num_breakdowns` does not fit into `BK` bits or either stage exceeds the number of buckets
+/// [`move_single_value_to_bucket`] supports.
+pub async fn move_single_value_to_bucket_two_stage<C, S, F>(
+    ctx: C,
+    record_id: RecordId,
+    bd_key: BitDecomposed<S>,
+    value: S,
+    num_breakdowns: u32,
+    robust: bool,
+) -> Result<TwoStageBuckets<S>, Error>
+where
+    C: UpgradedContext<F, Share = S>,
+    S: LinearSecretSharing<F> + Serializable + SecureMul<C> + ShareKnownValue<C, F>,
+    F: PrimeField + ExtendableField,
+{
+    let bits = u32::try_from(bd_key.len()).unwrap();
+    if u64::from(num_breakdowns) > 1 << bits {
+        Err(MoveToBucketError::InvalidBreakdownKey(format!(
+            "Asking for more buckets ({num_breakdowns}) than bits in the breakdown key ({bits}) \
+             allow"
+        )))?;
+    }
+    let low_bits = bits / 2;
+    let low_count = num_breakdowns.min(1 << low_bits);
+    let high_count = num_breakdowns.div_ceil(low_count.max(1));
+    let (low_key, high_key) = bd_key.split_at(low_bits);
+    let one = S::share_known_value(&ctx, F::ONE);
+
+    let (high, low) = futures::future::try_join(
+        move_single_value_to_bucket(
+            ctx.narrow(&TwoStageBucketStep::HighBits),
+            record_id,
+            high_key,
+            value,
+            high_count,
+            robust,
+        ),
+        move_single_value_to_bucket(
+            ctx.narrow(&TwoStageBucketStep::LowBits),
+            record_id,
+            low_key,
+            one,
+            low_count,
+            robust,
+        ),
+    )
+    .await?;
+
+    Ok(TwoStageBuckets { high, low })
+}
+
+/// Adds up buckets of all `rows`, as produced by [`move_single_value_to_bucket_two_stage`]. Every
+/// bucket is the sum of products of super-buckets and indicators over all rows, which is computed
+/// with a single multiplication, so this takes `num_breakdowns` multiplications regardless of the
+/// number of rows. The result is all zero if there are no rows.
+///
+/// ## Errors
+/// Propagates errors from multiplications.
+pub async fn aggregate_two_stage_buckets<C, S, F>(
+    ctx: C,
+    rows: &[TwoStageBuckets<S>],
+    num_breakdowns: u32,
+) -> Result<Vec<S>, Error>
+where
+    C: Context,
+    S: LinearSecretSharing<F> + SumOfProducts<C>,
+    F: SharedValue,
+{
+    let breakdown_count = usize::try_from(num_breakdowns).unwrap();
+    let Some(low_count) = rows.first().map(|row| row.low.len()) else {
+        return Ok(vec![S::ZERO; breakdown_count]);
+    };
+
+    let ctx = ctx.set_total_records(breakdown_count);
+    seq_join(
+        ctx.active_work(),
+        stream_iter(0..breakdown_count).map(|k| {
+            let (high, low): (Vec<_>, Vec<_>) = rows
+                .iter()
+                .map(|row| {
+                    (
+                        row.high[k / low_count].clone(),
+                        row.low[k % low_count].clone(),
+                    )
+                })
+                .unzip();
+            let ctx = ctx.clone();
+            async move { S::sum_of_products(ctx, RecordId::from(k), &high, &low).await }
+        }),
+    )
+    .try_collect()
+    .await
+}
+
+/// Moves `value` into one of `num_breakdowns` buckets. `multiplier` turns a bit of `bd_key` into
+/// a share that `value` can be multiplied by.
+async fn move_to_bucket<C, S, B, F, M>(
+    ctx: C,
+    record_id: RecordId,
+    bd_key: BitDecomposed<B>,
+    value: S,
+    num_breakdowns: u32,
+    robust: bool,
+    multiplier: M,
+) -> Result<Vec<S>, Error>
+where
+    C: Context,
+    S: LinearSecretSharing<F> + SecureMul<C>,
+    F: SharedValue,
+    M: Fn(&B) -> S,
 {
     const MAX_BREAKDOWNS: usize = 512; // constrained by the compact step ability to generate dynamic steps
+    let breakdown_count = usize::try_from(num_breakdowns).unwrap();
     let mut step: usize = 1 << bd_key.len();
 
     if breakdown_count > step {
@@ -116,13 +323,18 @@ where
         }
 
         let depth_c = ctx.narrow(&BinaryTreeDepthStep::from(tree_depth));
+        let bit_of_bdkey = multiplier(bit_of_bdkey);
         let mut futures = Vec::with_capacity(breakdown_count / step);
 
         for (i, tree_index) in (0..breakdown_count).step_by(step).enumerate() {
             let bit_c = depth_c.narrow(&BucketStep::from(i));
 
             if robust || tree_index + span < breakdown_count {
-                futures.push(row_contribution[tree_index].multiply(bit_of_bdkey, bit_c, record_id));
+                futures.push(row_contribution[tree_index].multiply(
+                    &bit_of_bdkey,
+                    bit_c,
+                    record_id,
+                ));
             }
         }
         let contributions = ctx.parallel_join(futures).await?;
@@ -141,29 +353,102 @@ where
     Ok(row_contribution)
 }
 
+/// Adds up buckets of all `rows`, as produced by [`move_single_value_to_bucket_boolean`], treating
+/// them as unsigned integers. Sums wrap around at $2^{V::BITS}$, so `V` must be wide enough to
+/// hold the sum of all rows.
+///
+/// Rows are added in pairs, level by level, so this takes $\log_2$ of the number of rows
+/// additions. Every level adds its pairs bit-sliced, 64 at a time, see [`integer_add_chunk`].
+/// The result has `num_breakdowns` buckets, all zero if there are no rows.
+///
+/// ## Errors
+/// Propagates errors from multiplications.
+/// ## Panics
+/// If some row does not have exactly `num_breakdowns` buckets.
+pub async fn aggregate_boolean_buckets<C, V>(
+    ctx: C,
+    mut rows: Vec<Vec<Replicated<V>>>,
+    num_breakdowns: u32,
+) -> Result<Vec<Replicated<V>>, Error>
+where
+    C: Context,
+    V: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    for<'a> &'a Replicated<V>: IntoIterator<Item = Replicated<Boolean>>,
+{
+    let breakdown_count = usize::try_from(num_breakdowns).unwrap();
+    assert!(
+        rows.iter().all(|row| row.len() == breakdown_count),
+        "every row must have {breakdown_count} buckets"
+    );
+
+    let mut depth = 0;
+    while rows.len() > 1 {
+        let unpaired = if rows.len() % 2 == 1 {
+            rows.pop()
+        } else {
+            None
+        };
+        let (left, right): (Vec<_>, Vec<_>) = rows
+            .chunks_exact(2)
+            .flat_map(|pair| zip(pair[0].iter().cloned(), pair[1].iter().cloned()))
+            .unzip();
+        let lanes = usize::try_from(<BA64 as WeakSharedValue>::BITS).unwrap();
+        let ctx = ctx
+            .narrow(&BinaryTreeDepthStep::from(depth))
+            .set_total_records(left.len().div_ceil(lanes));
+        let sums = ctx
+            .try_join(
+                zip(left.chunks(lanes), right.chunks(lanes))
+                    .enumerate()
+                    .map(|(i, (x, y))| {
+                        integer_add_chunk::<_, V, V, BA64>(ctx.clone(), RecordId::from(i), x, y)
+                    }),
+            )
+            .await?
+            .into_iter()
+            .flat_map(|(sums, _carries)| sums)
+            .collect::<Vec<_>>();
+
+        rows = sums
+            .chunks(breakdown_count)
+            .map(<[_]>::to_vec)
+            .chain(unpaired)
+            .collect();
+        depth += 1;
+    }
+
+    Ok(rows
+        .pop()
+        .unwrap_or_else(|| vec![Replicated::ZERO; breakdown_count]))
+}
+
 #[cfg(all(test, unit_test))]
 pub mod tests {
     use rand::thread_rng;
 
     use crate::{
-        ff::{Field, Fp32BitPrime, Gf8Bit, Gf9Bit},
+        ff::{boolean::Boolean, boolean_array::BA8, Field, Fp32BitPrime, Gf8Bit, Gf9Bit},
         protocol::{
             context::{Context, UpgradableContext, Validator},
-            ipa_prf::prf_sharding::bucket::move_single_value_to_bucket,
+            ipa_prf::prf_sharding::bucket::{
+                aggregate_boolean_buckets, aggregate_two_stage_buckets,
+                move_single_value_to_bucket, move_single_value_to_bucket_boolean,
+                move_single_value_to_bucket_two_stage,
+            },
             RecordId,
         },
         rand::Rng,
         secret_sharing::SharedValue,
+        seq_join::SeqJoin,
         test_executor::run,
         test_fixture::{get_bits, Reconstruct, Runner, TestWorld},
     };
 
-    const MAX_BREAKDOWN_COUNT: usize = 256;
+    const MAX_BREAKDOWN_COUNT: u32 = 256;
     const VALUE: u32 = 10;
 
-    async fn move_to_bucket(count: usize, breakdown_key: usize, robust: bool) -> Vec<Fp32BitPrime> {
-        let breakdown_key_bits =
-            get_bits::<Fp32BitPrime>(breakdown_key.try_into().unwrap(), Gf8Bit::BITS);
+    async fn move_to_bucket(count: u32, breakdown_key: u32, robust: bool) -> Vec<Fp32BitPrime> {
+        let breakdown_key_bits = get_bits::<Fp32BitPrime>(breakdown_key, Gf8Bit::BITS);
         let value = Fp32BitPrime::truncate_from(VALUE);
 
         TestWorld::default()
@@ -194,8 +479,8 @@ pub mod tests {
             let mut rng = thread_rng();
             let count = rng.gen_range(1..MAX_BREAKDOWN_COUNT);
             let breakdown_key = rng.gen_range(0..count);
-            let mut expected = vec![Fp32BitPrime::ZERO; count];
-            expected[breakdown_key] = Fp32BitPrime::truncate_from(VALUE);
+            let mut expected = vec![Fp32BitPrime::ZERO; count as usize];
+            expected[breakdown_key as usize] = Fp32BitPrime::truncate_from(VALUE);
 
             let result = move_to_bucket(count, breakdown_key, false).await;
             assert_eq!(result, expected, "expected value at index {breakdown_key}");
@@ -208,8 +493,8 @@ pub mod tests {
             let mut rng = thread_rng();
             let count = rng.gen_range(1..MAX_BREAKDOWN_COUNT);
             let breakdown_key = rng.gen_range(0..count);
-            let mut expected = vec![Fp32BitPrime::ZERO; count];
-            expected[breakdown_key] = Fp32BitPrime::truncate_from(VALUE);
+            let mut expected = vec![Fp32BitPrime::ZERO; count as usize];
+            expected[breakdown_key as usize] = Fp32BitPrime::truncate_from(VALUE);
 
             let result = move_to_bucket(count, breakdown_key, true).await;
             assert_eq!(result, expected, "expected value at index {breakdown_key}");
@@ -224,7 +509,7 @@ pub mod tests {
             let breakdown_key = rng.gen_range(count..MAX_BREAKDOWN_COUNT);
 
             let result = move_to_bucket(count, breakdown_key, false).await;
-            assert_eq!(result.len(), count);
+            assert_eq!(result.len(), count as usize);
             assert_eq!(
                 result.into_iter().sum::<Fp32BitPrime>(),
                 Fp32BitPrime::truncate_from(VALUE)
@@ -240,7 +525,7 @@ pub mod tests {
             let breakdown_key = rng.gen_range(count..MAX_BREAKDOWN_COUNT);
 
             let result = move_to_bucket(count, breakdown_key, true).await;
-            assert_eq!(result.len(), count);
+            assert_eq!(result.len(), count as usize);
             assert!(result.into_iter().all(|x| x == Fp32BitPrime::ZERO));
         });
     }
@@ -281,4 +566,171 @@ pub mod tests {
                 .await;
         });
     }
+
+    async fn move_to_bucket_boolean(count: u32, breakdown_key: u32, robust: bool) -> Vec<BA8> {
+        let breakdown_key_bits = get_bits::<Boolean>(breakdown_key, Gf8Bit::BITS);
+        let value = BA8::truncate_from(VALUE);
+
+        TestWorld::default()
+            .semi_honest(
+                (breakdown_key_bits, value),
+                |ctx, (breakdown_key_share, value_share)| async move {
+                    move_single_value_to_bucket_boolean(
+                        ctx.set_total_records(1),
+                        RecordId::from(0),
+                        breakdown_key_share,
+                        value_share,
+                        count,
+                        robust,
+                    )
+                    .await
+                    .unwrap()
+                },
+            )
+            .await
+            .reconstruct()
+    }
+
+    #[test]
+    fn semi_honest_move_boolean_in_range() {
+        run(|| async move {
+            let mut rng = thread_rng();
+            let count = rng.gen_range(1..MAX_BREAKDOWN_COUNT);
+            let breakdown_key = rng.gen_range(0..count);
+            let mut expected = vec![BA8::ZERO; count as usize];
+            expected[breakdown_key as usize] = BA8::truncate_from(VALUE);
+
+            for robust in [false, true] {
+                let result = move_to_bucket_boolean(count, breakdown_key, robust).await;
+                assert_eq!(result, expected, "expected value at index {breakdown_key}");
+            }
+        });
+    }
+
+    #[test]
+    fn semi_honest_move_boolean_out_of_range_robust() {
+        run(move || async move {
+            let mut rng = thread_rng();
+            let count = rng.gen_range(2..MAX_BREAKDOWN_COUNT - 1);
+            let breakdown_key = rng.gen_range(count..MAX_BREAKDOWN_COUNT);
+
+            let result = move_to_bucket_boolean(count, breakdown_key, true).await;
+            assert_eq!(result, vec![BA8::ZERO; count as usize]);
+        });
+    }
+
+    #[test]
+    fn semi_honest_aggregate_boolean() {
+        const COUNT: u32 = 5;
+
+        run(move || async move {
+            let mut rng = thread_rng();
+            // an odd number of rows leaves one unpaired at the first level
+            let rows = (0..21)
+                .map(|_| (rng.gen_range(0..COUNT), rng.gen_range(0..8_u32)))
+                .collect::<Vec<_>>();
+            let mut expected = vec![0_u128; COUNT as usize];
+            for &(breakdown_key, value) in &rows {
+                expected[breakdown_key as usize] += u128::from(value);
+            }
+
+            let result: Vec<BA8> = TestWorld::default()
+                .semi_honest(
+                    rows.into_iter().map(|(breakdown_key, value)| {
+                        (
+                            get_bits::<Boolean>(breakdown_key, 3),
+                            BA8::truncate_from(value),
+                        )
+                    }),
+                    |ctx, rows| async move {
+                        let move_ctx = ctx.narrow("move").set_total_records(rows.len());
+                        let buckets = move_ctx
+                            .try_join(rows.into_iter().enumerate().map(
+                                |(i, (breakdown_key, value))| {
+                                    move_single_value_to_bucket_boolean(
+                                        move_ctx.clone(),
+                                        RecordId::from(i),
+                                        breakdown_key,
+                                        value,
+                                        COUNT,
+                                        false,
+                                    )
+                                },
+                            ))
+                            .await
+                            .unwrap();
+                        aggregate_boolean_buckets(ctx.narrow("aggregate"), buckets, COUNT)
+                            .await
+                            .unwrap()
+                    },
+                )
+                .await
+                .reconstruct();
+
+            assert_eq!(
+                expected,
+                result.iter().map(Field::as_u128).collect::<Vec<_>>()
+            );
+        });
+    }
+
+    #[test]
+    fn semi_honest_aggregate_two_stage() {
+        // more buckets than a single stage supports
+        const COUNT: u32 = 600;
+        const BITS: u32 = 10;
+
+        run(move || async move {
+            let mut rng = thread_rng();
+            let mut rows = (0..20)
+                .map(|_| (rng.gen_range(0..COUNT), rng.gen_range(0..1000_u32)))
+                .collect::<Vec<_>>();
+            // the last bucket and two rows in the same bucket
+            rows.extend([(COUNT - 1, 7), (5, 3), (5, 4)]);
+            let mut expected = vec![0_u128; COUNT as usize];
+            for &(breakdown_key, value) in &rows {
+                expected[breakdown_key as usize] += u128::from(value);
+            }
+
+            let result: Vec<Fp32BitPrime> = TestWorld::default()
+                .semi_honest(
+                    rows.into_iter().map(|(breakdown_key, value)| {
+                        (
+                            get_bits::<Fp32BitPrime>(breakdown_key, BITS),
+                            Fp32BitPrime::truncate_from(value),
+                        )
+                    }),
+                    |ctx, rows| async move {
+                        let validator = ctx.validator();
+                        let ctx = validator.context();
+                        let move_ctx = ctx.narrow("move").set_total_records(rows.len());
+                        let buckets = move_ctx
+                            .try_join(rows.into_iter().enumerate().map(
+                                |(i, (breakdown_key, value))| {
+                                    move_single_value_to_bucket_two_stage::<_, _, Fp32BitPrime>(
+                                        move_ctx.clone(),
+                                        RecordId::from(i),
+                                        breakdown_key,
+                                        value,
+                                        COUNT,
+                                        false,
+                                    )
+                                },
+                            ))
+                            .await
+                            .unwrap();
+                        aggregate_two_stage_buckets(ctx.narrow("aggregate"), &buckets, COUNT)
+                            .await
+                            .unwrap()
+                    },
+                )
+                .await
+                .reconstruct();
+
+            assert_eq!(
+                expected,
+                result.iter().map(Field::as_u128).collect::<Vec<_>>()
+            );
+        });
+    }
 }
@@ -1,17 +1,30 @@
-use std::{iter::zip, pin::pin};
+use std::{iter::zip, ops::Not, pin::pin};
 
 use futures::{stream::iter as stream_iter, TryStreamExt};
-use futures_util::{future::try_join, stream::unfold, Stream, StreamExt};
+use futures_util::{future::try_join, StreamExt};
 use ipa_macros::Step;
 
 use crate::{
     error::Error,
-    ff::{Field, GaloisField, Gf2, PrimeField, Serializable},
+    ff::{
+        boolean::Boolean, CustomArray, Expand, Field, GaloisField, Gf2, PrimeField, Serializable,
+    },
+    helpers::Role,
     protocol::{
-        basics::{SecureMul, ShareKnownValue},
+        basics::{if_else, SecureMul, ShareKnownValue},
         boolean::or::or,
         context::{Context, UpgradableContext, UpgradedContext, Validator},
-        modulus_conversion::convert_bits,
+        ipa_prf::{
+            boolean_ops::{
+                addition_sequential::integer_add,
+                comparison_and_subtraction_sequential::integer_sub,
+            },
+            prf_sharding::{
+                compute_capped_trigger_value, group_rows_by_user, histogram_of_user_chunks,
+                sum_reached_cap, GroupingKey, UserRowsError,
+            },
+        },
+        modulus_conversion::{convert_bits, BitConversionTriple, ToBitConversionTriples},
         step::BitOpStep,
         RecordId,
     },
@@ -20,7 +33,7 @@ use crate::{
             malicious::ExtendableField, semi_honest::AdditiveShare as Replicated,
             ReplicatedSecretSharing,
         },
-        BitDecomposed, Linear as LinearSecretSharing,
+        BitDecomposed, Linear as LinearSecretSharing, WeakSharedValue,
     },
     seq_join::seq_join,
 };
@@ -31,6 +44,12 @@ pub struct PrfShardedIpaInputRow<FV: GaloisField> {
     feature_vector: Replicated<FV>,
 }
 
+impl<FV: GaloisField> GroupingKey for PrfShardedIpaInputRow<FV> {
+    fn get_grouping_key(&self) -> u64 {
+        self.prf_of_match_key
+    }
+}
+
 struct InputsRequiredFromPrevRow {
     ever_encountered_a_trigger_event: Replicated<Gf2>,
     is_saturated: Replicated<Gf2>,
@@ -137,12 +156,15 @@ pub(crate) enum Step {
     IsAttributedSourceAndPrevRowNotSaturated,
     ComputedCappedFeatureVector,
     ModulusConvertFeatureVectorBits,
+    LabelOfMostRecentTriggerEvent,
+    AttributedLabel,
+    ComputeDifferenceToCap,
+    IsSaturatedAndPrevRowNotSaturated,
+    ModulusConvertLabelAndFeatureVectorBits,
+    MultiplyFeatureVectorByLabel,
 }
 
-fn set_up_contexts<C>(root_ctx: &C, histogram: &[usize]) -> Vec<C>
-where
-    C: UpgradedContext<Gf2, Share = Replicated<Gf2>>,
-{
+fn set_up_contexts<C: Context>(root_ctx: &C, histogram: &[usize]) -> Vec<C> {
     let mut context_per_row_depth = Vec::with_capacity(histogram.len());
     for (row_number, num_users_having_that_row_number) in histogram.iter().enumerate() {
         if row_number == 0 {
@@ -157,33 +179,6 @@ where
     context_per_row_depth
 }
 
-///
-/// Takes an input stream of `PrfShardedIpaInputRecordRow` which is assumed to have all records with a given PRF adjacent
-/// and converts it into a stream of vectors of `PrfShardedIpaInputRecordRow` having the same PRF.
-///
-fn chunk_rows_by_user<FV, IS>(
-    input_stream: IS,
-    first_row: PrfShardedIpaInputRow<FV>,
-) -> impl Stream<Item = Vec<PrfShardedIpaInputRow<FV>>>
-where
-    FV: GaloisField,
-    IS: Stream<Item = PrfShardedIpaInputRow<FV>> + Unpin,
-{
-    unfold(Some((input_stream, first_row)), |state| async move {
-        let (mut s, last_row) = state?;
-        let last_row_prf = last_row.prf_of_match_key;
-        let mut current_chunk = vec![last_row];
-        while let Some(row) = s.next().await {
-            if row.prf_of_match_key == last_row_prf {
-                current_chunk.push(row);
-            } else {
-                return Some((current_chunk, Some((s, row))));
-            }
-        }
-        Some((current_chunk, None))
-    })
-}
-
 /// Sub-protocol of the PRF-sharded IPA Protocol
 ///
 /// After the computation of the per-user PRF, addition of dummy records and shuffling,
@@ -242,35 +237,41 @@ where
     let prime_field_validator = sh_ctx.narrow(&Step::PrimeFieldValidator).validator::<F>();
     let prime_field_ctx = prime_field_validator.context();
 
+    // Chunk the incoming records into vectors of records with the same PRF
+    let rows_chunked_by_user = group_rows_by_user(input_rows);
+    let actual = histogram_of_user_chunks(&rows_chunked_by_user);
+    if histogram != actual {
+        return Err(UserRowsError::HistogramMismatch {
+            given: histogram.to_vec(),
+            actual,
+        }
+        .into());
+    }
+
     // Tricky hacks to work around the limitations of our current infrastructure
-    let num_outputs = input_rows.len() - histogram[0];
+    let num_outputs = rows_chunked_by_user.iter().map(|rows| rows.len() - 1).sum();
+    if num_outputs == 0 {
+        return Ok(vec![S::ZERO; usize::try_from(FV::BITS).unwrap()]);
+    }
     let mut record_id_for_row_depth = vec![0_u32; histogram.len()];
     let ctx_for_row_number = set_up_contexts(&binary_m_ctx, histogram);
 
-    // Chunk the incoming stream of records into stream of vectors of records with the same PRF
-    let mut input_stream = stream_iter(input_rows);
-    let first_row = input_stream.next().await;
-    if first_row.is_none() {
-        return Ok(vec![]);
-    }
-    let first_row = first_row.unwrap();
-    let rows_chunked_by_user = chunk_rows_by_user(input_stream, first_row);
-
     // Convert to a stream of async futures that represent the result of executing the per-user circuit
-    let stream_of_per_user_circuits = pin!(rows_chunked_by_user.then(|rows_for_user| {
-        let num_user_rows = rows_for_user.len();
-        let contexts = ctx_for_row_number[..num_user_rows - 1].to_owned();
-        let record_ids = record_id_for_row_depth[..num_user_rows].to_owned();
-        record_id_for_row_depth[..num_user_rows]
-            .iter_mut()
-            .for_each(|count| *count += 1);
-
-        #[allow(clippy::async_yields_async)]
-        // this is ok, because seq join wants a stream of futures
-        async move {
-            evaluate_per_user_attribution_circuit(contexts, record_ids, rows_for_user)
-        }
-    }));
+    let stream_of_per_user_circuits =
+        pin!(stream_iter(rows_chunked_by_user).then(|rows_for_user| {
+            let num_user_rows = rows_for_user.len();
+            let contexts = ctx_for_row_number[..num_user_rows - 1].to_owned();
+            let record_ids = record_id_for_row_depth[..num_user_rows].to_owned();
+            record_id_for_row_depth[..num_user_rows]
+                .iter_mut()
+                .for_each(|count| *count += 1);
+
+            #[allow(clippy::async_yields_async)]
+            // this is ok, because seq join wants a stream of futures
+            async move {
+                evaluate_per_user_attribution_circuit(contexts, record_ids, rows_for_user)
+            }
+        }));
 
     // Execute all of the async futures (sequentially), and flatten the result
     let flattened_stream = seq_join(sh_ctx.active_work(), stream_of_per_user_circuits)
@@ -367,12 +368,420 @@ where
     ))
 }
 
+/// Input row of [`compute_weighted_feature_label_dot_product`]. Unlike [`PrfShardedIpaInputRow`],
+/// trigger events carry a label, a small unsigned integer that weighs the feature vectors of the
+/// source events they are attributed to. Labels of source events are ignored.
+pub struct PrfShardedIpaWeightedInputRow<FV: WeakSharedValue, L: WeakSharedValue> {
+    prf_of_match_key: u64,
+    is_trigger_bit: Replicated<Boolean>,
+    feature_vector: Replicated<FV>,
+    label: Replicated<L>,
+}
+
+impl<FV: WeakSharedValue, L: WeakSharedValue> GroupingKey for PrfShardedIpaWeightedInputRow<FV, L> {
+    fn get_grouping_key(&self) -> u64 {
+        self.prf_of_match_key
+    }
+}
+
+struct WeightedInputsRequiredFromPrevRow<L: WeakSharedValue, SS: WeakSharedValue> {
+    /// Label of the most recent trigger event before the previous row, zero if there is none.
+    label_of_most_recent_trigger_event: Replicated<L>,
+    prev_row_is_trigger_bit: Replicated<Boolean>,
+    prev_row_label: Replicated<L>,
+    saturating_sum: Replicated<SS>,
+    is_saturated: Replicated<Boolean>,
+    difference_to_cap: Replicated<L>,
+}
+
+/// Feature vector of a source event, along with the label it was weighed with after capping.
+struct CappedWeightedFeatureVector<L: WeakSharedValue, FV: WeakSharedValue> {
+    capped_label: Replicated<L>,
+    feature_vector: Replicated<FV>,
+}
+
+impl<L, FV> ToBitConversionTriples for CappedWeightedFeatureVector<L, FV>
+where
+    L: WeakSharedValue + CustomArray<Element = Boolean>,
+    FV: WeakSharedValue + CustomArray<Element = Boolean>,
+{
+    type Residual = ();
+
+    fn bits(&self) -> u32 {
+        L::BITS + FV::BITS
+    }
+
+    fn triple<F: PrimeField>(&self, role: Role, i: u32) -> BitConversionTriple<Replicated<F>> {
+        assert!(i < self.bits());
+        let i: usize = i.try_into().unwrap();
+        let label_bits: usize = L::BITS.try_into().unwrap();
+        let (left, right) = if i < label_bits {
+            (
+                self.capped_label.0.get(i).unwrap(),
+                self.capped_label.1.get(i).unwrap(),
+            )
+        } else {
+            (
+                self.feature_vector.0.get(i - label_bits).unwrap(),
+                self.feature_vector.1.get(i - label_bits).unwrap(),
+            )
+        };
+        BitConversionTriple::new(role, left == Boolean::ONE, right == Boolean::ONE)
+    }
+
+    fn into_triples<F, I>(
+        self,
+        role: Role,
+        indices: I,
+    ) -> (
+        BitDecomposed<BitConversionTriple<Replicated<F>>>,
+        Self::Residual,
+    )
+    where
+        F: PrimeField,
+        I: IntoIterator<Item = u32>,
+    {
+        (self.triple_range(role, indices), ())
+    }
+}
+
+impl<L, SS> WeightedInputsRequiredFromPrevRow<L, SS>
+where
+    L: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    SS: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+{
+    ///
+    /// Per-user circuit of [`compute_weighted_feature_label_dot_product`].
+    /// Multiple rows of data about a single user are processed in-order from newest to oldest.
+    ///
+    /// Summary:
+    /// - Last touch attribution
+    ///     - Every source event which has a subsequent trigger event receives the label of the
+    ///       closest one
+    /// - Per user capping
+    ///     - A cumulative sum of attributed labels is maintained, the same way the sum of
+    ///       attributed trigger values is in [`attribute_cap_aggregate`](super::attribute_cap_aggregate)
+    ///     - The label of the source event which puts the sum over the cap is lowered to the
+    ///       difference between the cap and the sum of the labels before it
+    ///     - All subsequent rows contribute zero
+    /// - Outputs
+    ///     - If a user has `N` input rows, they will generate `N-1` output rows. (The first row cannot possibly contribute any value to the output)
+    ///     - Each output row is the feature vector of the row, along with its capped label, which
+    ///       is zero unless the row is a source event that received attribution.
+    pub async fn compute_row_with_previous<C, FV>(
+        &mut self,
+        ctx: C,
+        record_id: RecordId,
+        input_row: &PrfShardedIpaWeightedInputRow<FV, L>,
+        per_user_cap: u32,
+    ) -> Result<CappedWeightedFeatureVector<L, FV>, Error>
+    where
+        C: Context,
+        FV: WeakSharedValue,
+        for<'a> &'a Replicated<L>: IntoIterator<Item = Replicated<Boolean>>,
+        for<'a> &'a Replicated<SS>: IntoIterator<Item = Replicated<Boolean>>,
+    {
+        // The label of the previous row is not known to be the label of a trigger event until
+        // now, which is why it is carried over. That saves the multiplications on the first row.
+        let label_of_most_recent_trigger_event = if_else(
+            ctx.narrow(&Step::LabelOfMostRecentTriggerEvent),
+            record_id,
+            &Replicated::<L>::expand(&self.prev_row_is_trigger_bit),
+            &self.prev_row_label,
+            &self.label_of_most_recent_trigger_event,
+        )
+        .await?;
+
+        let attributed_label = label_of_most_recent_trigger_event
+            .multiply(
+                &Replicated::<L>::expand(&input_row.is_trigger_bit.clone().not()),
+                ctx.narrow(&Step::AttributedLabel),
+                record_id,
+            )
+            .await?;
+
+        let (updated_sum, overflow_bit) = integer_add(
+            ctx.narrow(&Step::ComputeSaturatingSum),
+            record_id,
+            &self.saturating_sum,
+            &attributed_label,
+        )
+        .await?;
+
+        // Only the last |L| bits of the difference are computed. That is enough, because the
+        // difference is used only if it does not exceed the attributed label.
+        let (sum_reached_cap, difference_to_cap) = try_join(
+            sum_reached_cap(
+                ctx.clone(),
+                record_id,
+                &updated_sum,
+                overflow_bit,
+                per_user_cap,
+            ),
+            integer_sub(
+                ctx.narrow(&Step::ComputeDifferenceToCap),
+                record_id,
+                &Replicated::<L>::share_known_value(&ctx, L::truncate_from(per_user_cap)),
+                &updated_sum,
+            ),
+        )
+        .await?;
+
+        let sum_reached_cap_and_prev_row_not_saturated = sum_reached_cap
+            .multiply(
+                &self.is_saturated.clone().not(),
+                ctx.narrow(&Step::IsSaturatedAndPrevRowNotSaturated),
+                record_id,
+            )
+            .await?;
+
+        // These conditions cannot both be true, so adding them is the same as OR
+        let is_saturated = &self.is_saturated + &sum_reached_cap_and_prev_row_not_saturated;
+
+        let capped_label = compute_capped_trigger_value(
+            ctx,
+            record_id,
+            &is_saturated,
+            &sum_reached_cap_and_prev_row_not_saturated,
+            &self.difference_to_cap,
+            &attributed_label,
+        )
+        .await?;
+
+        self.label_of_most_recent_trigger_event = label_of_most_recent_trigger_event;
+        self.prev_row_is_trigger_bit = input_row.is_trigger_bit.clone();
+        self.prev_row_label = input_row.label.clone();
+        self.saturating_sum = updated_sum;
+        self.is_saturated = is_saturated;
+        self.difference_to_cap = difference_to_cap;
+
+        Ok(CappedWeightedFeatureVector {
+            capped_label,
+            feature_vector: input_row.feature_vector.clone(),
+        })
+    }
+}
+
+/// Same as [`compute_feature_label_dot_product`], but trigger events carry multi-bit labels.
+///
+/// Every source event that receives attribution contributes its feature vector, multiplied by the
+/// label of the trigger event it is attributed to. Labels attributed to the source events of a user
+/// add up to at most `per_user_cap`, the label of the source event which exceeds it is lowered to
+/// what is left of the cap, and later source events contribute nothing. With 1-bit labels and a cap
+/// of 1, this computes the same dot-product as [`compute_feature_label_dot_product`].
+///
+/// Labels are summed up in `SS`, so the cap must be within `[1, 2^|SS|]`. Capping takes
+/// `4|L| + |SS|` multiplications per row, plus `|SS| + 1` to compare the sum against the cap,
+/// unless it is exactly `2^|SS|`. Capped labels and feature vectors are then converted to
+/// `F`, where every feature is multiplied by the label, so `|L| + |FV|` may not exceed 64 bits.
+///
+/// The result is the weighted sum of every bit of the feature vectors, least significant first.
+///
+/// # Errors
+/// Propagates errors from multiplications
+/// # Panics
+/// If `per_user_cap` is not within `[1, 2^|SS|]`, or if `|L| + |FV|` exceeds 64 bits.
+pub async fn compute_weighted_feature_label_dot_product<C, FV, L, SS, F, S>(
+    sh_ctx: C,
+    input_rows: Vec<PrfShardedIpaWeightedInputRow<FV, L>>,
+    histogram: &[usize],
+    per_user_cap: u32,
+) -> Result<Vec<S>, Error>
+where
+    C: UpgradableContext,
+    C::UpgradedContext<Boolean>: UpgradedContext<Boolean, Share = Replicated<Boolean>>,
+    C::UpgradedContext<F>: UpgradedContext<F, Share = S>,
+    S: LinearSecretSharing<F> + Serializable + SecureMul<C::UpgradedContext<F>>,
+    FV: WeakSharedValue + CustomArray<Element = Boolean>,
+    L: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    SS: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    for<'a> &'a Replicated<L>: IntoIterator<Item = Replicated<Boolean>>,
+    for<'a> &'a Replicated<SS>: IntoIterator<Item = Replicated<Boolean>>,
+    F: PrimeField + ExtendableField,
+{
+    assert!(FV::BITS > 0);
+    assert!(
+        (1..=1 << <SS as WeakSharedValue>::BITS).contains(&u128::from(per_user_cap)),
+        "per-user cap must be within [1, {}], got {per_user_cap}",
+        1_u128 << <SS as WeakSharedValue>::BITS,
+    );
+    assert!(
+        <L as WeakSharedValue>::BITS + FV::BITS <= 64,
+        "labels and feature vectors may not exceed 64 bits together, got {} and {}",
+        <L as WeakSharedValue>::BITS,
+        FV::BITS
+    );
+
+    let binary_validator = sh_ctx.narrow(&Step::BinaryValidator).validator::<Boolean>();
+    let binary_m_ctx = binary_validator.context();
+
+    let prime_field_validator = sh_ctx.narrow(&Step::PrimeFieldValidator).validator::<F>();
+    let prime_field_ctx = prime_field_validator.context();
+
+    let rows_chunked_by_user = group_rows_by_user(input_rows);
+    let actual = histogram_of_user_chunks(&rows_chunked_by_user);
+    if histogram != actual {
+        return Err(UserRowsError::HistogramMismatch {
+            given: histogram.to_vec(),
+            actual,
+        }
+        .into());
+    }
+
+    let num_outputs = rows_chunked_by_user.iter().map(|rows| rows.len() - 1).sum();
+    if num_outputs == 0 {
+        return Ok(vec![S::ZERO; usize::try_from(FV::BITS).unwrap()]);
+    }
+    let mut record_id_for_row_depth = vec![0_u32; histogram.len()];
+    let ctx_for_row_number = set_up_contexts(&binary_m_ctx, histogram);
+
+    let stream_of_per_user_circuits =
+        pin!(stream_iter(rows_chunked_by_user).then(|rows_for_user| {
+            let num_user_rows = rows_for_user.len();
+            let contexts = ctx_for_row_number[..num_user_rows - 1].to_owned();
+            let record_ids = record_id_for_row_depth[..num_user_rows].to_owned();
+            record_id_for_row_depth[..num_user_rows]
+                .iter_mut()
+                .for_each(|count| *count += 1);
+
+            #[allow(clippy::async_yields_async)]
+            // this is ok, because seq join wants a stream of futures
+            async move {
+                evaluate_weighted_per_user_circuit::<_, FV, L, SS>(
+                    contexts,
+                    record_ids,
+                    rows_for_user,
+                    per_user_cap,
+                )
+            }
+        }));
+
+    let flattened_stream = seq_join(sh_ctx.active_work(), stream_of_per_user_circuits)
+        .flat_map(|x| stream_iter(x.unwrap()));
+
+    // modulus convert capped labels and feature vectors from boolean shares to shares in `Z_p`
+    let converted_bits = convert_bits(
+        prime_field_ctx
+            .narrow(&Step::ModulusConvertLabelAndFeatureVectorBits)
+            .set_total_records(num_outputs),
+        flattened_stream,
+        0..<L as WeakSharedValue>::BITS + FV::BITS,
+    );
+
+    let mul_ctx = prime_field_ctx
+        .narrow(&Step::MultiplyFeatureVectorByLabel)
+        .set_total_records(num_outputs);
+    let weighted_feature_vectors = seq_join(
+        sh_ctx.active_work(),
+        converted_bits.enumerate().map(|(i, bits)| {
+            let ctx = mul_ctx.clone();
+            async move {
+                let (label, feature_vector) = bits?.split_at(<L as WeakSharedValue>::BITS);
+                weigh_feature_vector::<_, F, S>(ctx, RecordId::from(i), &label, &feature_vector)
+                    .await
+            }
+        }),
+    );
+
+    weighted_feature_vectors
+        .try_fold(
+            vec![S::ZERO; usize::try_from(FV::BITS).unwrap()],
+            |mut running_sums, row_contribution| async move {
+                for (i, contribution) in row_contribution.iter().enumerate() {
+                    running_sums[i] += contribution;
+                }
+                Ok(running_sums)
+            },
+        )
+        .await
+}
+
+async fn evaluate_weighted_per_user_circuit<C, FV, L, SS>(
+    ctx_for_row_number: Vec<C>,
+    record_id_for_each_depth: Vec<u32>,
+    rows_for_user: Vec<PrfShardedIpaWeightedInputRow<FV, L>>,
+    per_user_cap: u32,
+) -> Result<Vec<CappedWeightedFeatureVector<L, FV>>, Error>
+where
+    C: Context,
+    FV: WeakSharedValue,
+    L: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    SS: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    for<'a> &'a Replicated<L>: IntoIterator<Item = Replicated<Boolean>>,
+    for<'a> &'a Replicated<SS>: IntoIterator<Item = Replicated<Boolean>>,
+{
+    assert!(!rows_for_user.is_empty());
+    if rows_for_user.len() == 1 {
+        return Ok(Vec::new());
+    }
+    let first_row = &rows_for_user[0];
+    let mut prev_row_inputs = WeightedInputsRequiredFromPrevRow::<L, SS> {
+        label_of_most_recent_trigger_event: Replicated::ZERO,
+        prev_row_is_trigger_bit: first_row.is_trigger_bit.clone(),
+        prev_row_label: first_row.label.clone(),
+        saturating_sum: Replicated::ZERO,
+        is_saturated: Replicated::ZERO,
+        difference_to_cap: Replicated::<L>::share_known_value(
+            &ctx_for_row_number[0],
+            L::truncate_from(per_user_cap),
+        ),
+    };
+
+    let mut output = Vec::with_capacity(rows_for_user.len() - 1);
+    for (i, (row, ctx)) in
+        zip(rows_for_user.iter().skip(1), ctx_for_row_number.into_iter()).enumerate()
+    {
+        let record_id_for_this_row_depth = RecordId::from(record_id_for_each_depth[i + 1]); // skip row 0
+
+        output.push(
+            prev_row_inputs
+                .compute_row_with_previous(ctx, record_id_for_this_row_depth, row, per_user_cap)
+                .await?,
+        );
+    }
+
+    Ok(output)
+}
+
+/// Multiplies every bit of the feature vector by the label, both given as bits shared in `Z_p`,
+/// least significant first.
+async fn weigh_feature_vector<C, F, S>(
+    ctx: C,
+    record_id: RecordId,
+    label: &[S],
+    feature_vector: &[S],
+) -> Result<Vec<S>, Error>
+where
+    C: Context,
+    F: PrimeField,
+    S: LinearSecretSharing<F> + SecureMul<C>,
+{
+    let label = label.iter().enumerate().fold(S::ZERO, |acc, (j, bit)| {
+        acc + &(bit.clone() * F::truncate_from(1_u128 << j))
+    });
+    ctx.parallel_join(feature_vector.iter().enumerate().map(|(i, bit)| {
+        let ctx = ctx.narrow(&BitOpStep::from(i));
+        let label = &label;
+        async move { label.multiply(bit, ctx, record_id).await }
+    }))
+    .await
+}
+
 #[cfg(all(test, unit_test))]
 pub mod tests {
     use crate::{
-        ff::{Field, Fp32BitPrime, GaloisField, Gf2, Gf32Bit},
-        protocol::ipa_prf::prf_sharding::feature_label_dot_product::{
-            compute_feature_label_dot_product, PrfShardedIpaInputRow,
+        error::Error,
+        ff::{
+            boolean::Boolean,
+            boolean_array::{BA3, BA32, BA5},
+            Field, Fp32BitPrime, GaloisField, Gf2, Gf32Bit,
+        },
+        protocol::ipa_prf::prf_sharding::{
+            feature_label_dot_product::{
+                compute_feature_label_dot_product, compute_weighted_feature_label_dot_product,
+                PrfShardedIpaInputRow, PrfShardedIpaWeightedInputRow,
+            },
+            UserRowsError,
         },
         rand::Rng,
         secret_sharing::{
@@ -438,6 +847,97 @@ pub mod tests {
         }
     }
 
+    struct WeightedTestInput {
+        prf_of_match_key: u64,
+        is_trigger_bit: Boolean,
+        feature_vector: BA32,
+        label: BA3,
+    }
+
+    fn weighted_source(prf_of_match_key: u64, feature_vector: u32) -> WeightedTestInput {
+        WeightedTestInput {
+            prf_of_match_key,
+            is_trigger_bit: Boolean::ZERO,
+            feature_vector: BA32::truncate_from(feature_vector),
+            label: BA3::ZERO,
+        }
+    }
+
+    fn weighted_trigger(prf_of_match_key: u64, label: u8) -> WeightedTestInput {
+        WeightedTestInput {
+            prf_of_match_key,
+            is_trigger_bit: Boolean::ONE,
+            feature_vector: BA32::ZERO,
+            label: BA3::truncate_from(label),
+        }
+    }
+
+    impl IntoShares<PrfShardedIpaWeightedInputRow<BA32, BA3>> for WeightedTestInput {
+        fn share_with<R: Rng>(self, rng: &mut R) -> [PrfShardedIpaWeightedInputRow<BA32, BA3>; 3] {
+            let WeightedTestInput {
+                prf_of_match_key,
+                is_trigger_bit,
+                feature_vector,
+                label,
+            } = self;
+
+            let [is_trigger_bit0, is_trigger_bit1, is_trigger_bit2] =
+                is_trigger_bit.share_with(rng);
+            let [feature_vector0, feature_vector1, feature_vector2] =
+                feature_vector.share_with(rng);
+            let [label0, label1, label2] = label.share_with(rng);
+
+            [
+                (is_trigger_bit0, feature_vector0, label0),
+                (is_trigger_bit1, feature_vector1, label1),
+                (is_trigger_bit2, feature_vector2, label2),
+            ]
+            .map(|(is_trigger_bit, feature_vector, label)| {
+                PrfShardedIpaWeightedInputRow {
+                    prf_of_match_key,
+                    is_trigger_bit,
+                    feature_vector,
+                    label,
+                }
+            })
+        }
+    }
+
+    async fn run_weighted(
+        records: Vec<WeightedTestInput>,
+        histogram: &[usize],
+        per_user_cap: u32,
+    ) -> Vec<Fp32BitPrime> {
+        TestWorld::default()
+            .semi_honest(records.into_iter(), |ctx, input_rows| async move {
+                compute_weighted_feature_label_dot_product::<
+                    _,
+                    BA32,
+                    BA3,
+                    BA5,
+                    Fp32BitPrime,
+                    Replicated<Fp32BitPrime>,
+                >(ctx, input_rows, histogram, per_user_cap)
+                .await
+                .unwrap()
+            })
+            .await
+            .reconstruct()
+    }
+
+    /// Sums up every bit of the feature vectors, weighted by their labels.
+    fn weighted_sum(weighted_feature_vectors: &[(u128, u32)]) -> Vec<Fp32BitPrime> {
+        (0..32)
+            .map(|i| {
+                let sum = weighted_feature_vectors
+                    .iter()
+                    .map(|&(label, feature_vector)| label * u128::from((feature_vector >> i) & 1))
+                    .sum::<u128>();
+                Fp32BitPrime::truncate_from(sum)
+            })
+            .collect()
+    }
+
     #[test]
     fn semi_honest() {
         run(|| async move {
@@ -495,4 +995,102 @@ pub mod tests {
             assert_eq!(result, &expected);
         });
     }
+
+    #[test]
+    fn histogram_mismatch() {
+        run(|| async move {
+            let world = TestWorld::default();
+            let records = vec![
+                test_input(123, false, 1),
+                test_input(123, true, 0),
+                test_input(234, false, 2),
+                test_input(123, true, 0),
+            ];
+
+            // rows of the first user are not adjacent, so it looks like two users
+            world
+                .semi_honest(records.into_iter(), |ctx, input_rows| async move {
+                    let err = compute_feature_label_dot_product::<
+                        _,
+                        Gf32Bit,
+                        Fp32BitPrime,
+                        Replicated<Fp32BitPrime>,
+                    >(ctx, input_rows, &[2, 1])
+                    .await
+                    .unwrap_err();
+                    assert!(matches!(
+                        err,
+                        Error::InvalidUserRows(UserRowsError::HistogramMismatch { ref actual, .. })
+                            if *actual == [3, 1]
+                    ));
+                })
+                .await;
+        });
+    }
+
+    #[test]
+    fn single_bit_labels() {
+        run(|| async move {
+            // same events as in `semi_honest`, with a label of 1 for every trigger event
+            let records = vec![
+                /* First User */
+                weighted_trigger(123, 1),
+                weighted_source(123, 0b1101_0100_1111_0001_0111_0010_1010_1011),
+                weighted_trigger(123, 1),
+                weighted_source(123, 0b0110_1101_0001_0100_1011_0100_1010_1001),
+                /* Second User */
+                weighted_trigger(234, 1),
+                weighted_source(234, 0b0001_1010_0011_0111_0110_0010_1111_0000),
+                /* Third User */
+                weighted_trigger(345, 1),
+                weighted_trigger(345, 1),
+                weighted_trigger(345, 1),
+                weighted_trigger(345, 1),
+                weighted_source(345, 0b0111_0101_0001_0000_0111_0100_0101_0011),
+                weighted_source(345, 0b1001_1000_1011_1101_0100_0110_0001_0100),
+                weighted_trigger(345, 1),
+                weighted_source(345, 0b1000_1001_0100_0011_0111_0010_0000_1101),
+            ];
+
+            let expected = weighted_sum(&[
+                (1, 0b1101_0100_1111_0001_0111_0010_1010_1011),
+                (1, 0b0001_1010_0011_0111_0110_0010_1111_0000),
+                (1, 0b0111_0101_0001_0000_0111_0100_0101_0011),
+            ]);
+            assert_eq!(
+                expected,
+                run_weighted(records, &[3, 3, 2, 2, 1, 1, 1, 1], 1).await
+            );
+        });
+    }
+
+    #[test]
+    fn weighted_labels_are_capped() {
+        run(|| async move {
+            let records = vec![
+                /* First User: labels add up to 3, 5 and 6, the second one is capped */
+                weighted_trigger(123, 3),
+                weighted_source(123, 0xF0F0),
+                weighted_trigger(123, 2),
+                weighted_source(123, 0x0FF0),
+                weighted_trigger(123, 1),
+                weighted_source(123, 0xFFFF),
+                /* Second User: the first label alone is over the cap */
+                weighted_trigger(234, 7),
+                weighted_source(234, 0x1234),
+                /* Third User: the most recent source event is not attributed, the other one gets
+                the label of the trigger event closest to it */
+                weighted_source(345, 0xFFFF_FFFF),
+                weighted_trigger(345, 5),
+                weighted_trigger(345, 2),
+                weighted_source(345, 0x0101),
+            ];
+
+            let expected = weighted_sum(&[(3, 0xF0F0), (1, 0x0FF0), (4, 0x1234), (2, 0x0101)]);
+            assert_eq!(
+                expected,
+                run_weighted(records, &[3, 3, 2, 2, 1, 1], 4).await
+            );
+        });
+    }
 }
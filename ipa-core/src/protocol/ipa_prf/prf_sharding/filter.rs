@@ -0,0 +1,100 @@
+//! Filtering of attributed contributions by breakdown key.
+//!
+//! Report collectors may only be interested in some of the breakdowns. Contributions attributed
+//! to any other breakdown key are zeroed out after attribution and capping, so they don't show
+//! up in any total, but the per-user cap still accounts for them. Breakdown keys stay secret:
+//! every row is compared against every allowed key, see [`is_equal_to_constant`].
+use crate::{
+    error::Error,
+    ff::{boolean::Boolean, CustomArray, Expand, Field},
+    protocol::{
+        basics::SecureMul,
+        context::Context,
+        ipa_prf::{
+            boolean_ops::equality::is_equal_to_constant,
+            prf_sharding::{CappedAttributionOutputs, Step},
+        },
+        step::BitOpStep,
+        RecordId,
+    },
+    secret_sharing::{replicated::semi_honest::AdditiveShare as Replicated, WeakSharedValue},
+};
+
+/// Returns `allowed` as values of `BK`, leaving out keys that are too wide to be any row's
+/// breakdown key.
+pub fn allowed_keys<BK>(allowed: &[u32]) -> Vec<BK>
+where
+    BK: WeakSharedValue + Field,
+{
+    allowed
+        .iter()
+        .filter(|&&key| u128::from(key) < 1 << <BK as WeakSharedValue>::BITS)
+        .map(|&key| BK::truncate_from(key))
+        .collect()
+}
+
+/// Zeroes out the trigger value of `row`, as well as its attributed user bit, unless its
+/// breakdown key is one of `allowed`. Keys in `allowed` must be distinct.
+///
+/// Takes `|allowed| * (|BK| - 1)` multiplications to find out whether the key is allowed and
+/// `|TV| + 1` more to zero out the row.
+///
+/// ## Errors
+/// Propagates errors from multiplications.
+pub async fn filter_breakdown_key<C, BK, TV>(
+    ctx: C,
+    record_id: RecordId,
+    row: CappedAttributionOutputs<BK, TV>,
+    allowed: &[BK],
+) -> Result<CappedAttributionOutputs<BK, TV>, Error>
+where
+    C: Context,
+    BK: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    TV: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    for<'a> &'a Replicated<BK>: IntoIterator<Item = Replicated<Boolean>>,
+{
+    let is_allowed_ctx = ctx.narrow(&Step::IsBreakdownKeyAllowed);
+    let matches = ctx
+        .parallel_join(allowed.iter().enumerate().map(|(i, &key)| {
+            is_equal_to_constant(
+                is_allowed_ctx.narrow(&BitOpStep::from(i)),
+                record_id,
+                &row.attributed_breakdown_key_bits,
+                key,
+            )
+        }))
+        .await?;
+    // breakdown key is equal to at most one of the distinct allowed keys, so their XOR is the
+    // same as their OR
+    let is_allowed = matches
+        .iter()
+        .fold(Replicated::<Boolean>::ZERO, |acc, m| &acc + m);
+
+    let capped_attributed_trigger_value = row
+        .capped_attributed_trigger_value
+        .multiply(
+            &Replicated::<TV>::expand(&is_allowed),
+            ctx.narrow(&Step::ZeroOutDisallowedTriggerValue),
+            record_id,
+        )
+        .await?;
+    let attributed_user = match &row.attributed_user {
+        Some(attributed_user) => Some(
+            attributed_user
+                .multiply(
+                    &is_allowed,
+                    ctx.narrow(&Step::ZeroOutDisallowedAttributedUser),
+                    record_id,
+                )
+                .await?,
+        ),
+        None => None,
+    };
+
+    Ok(CappedAttributionOutputs {
+        attributed_breakdown_key_bits: row.attributed_breakdown_key_bits,
+        capped_attributed_trigger_value,
+        attributed_user,
+        time_bucket: row.time_bucket,
+    })
+}
@@ -1,24 +1,34 @@
-use std::{num::NonZeroU32, ops::Not, pin::pin};
+use std::{
+    cmp::Reverse,
+    num::NonZeroU32,
+    ops::{AddAssign, Not},
+    pin::pin,
+};
 
-use futures::{stream::iter as stream_iter, TryStreamExt};
+use futures::{stream::iter as stream_iter, TryFutureExt, TryStreamExt};
 use futures_util::{
-    future::{try_join, try_join3},
-    stream::unfold,
+    future::{try_join, try_join3, try_join4},
     Stream, StreamExt,
 };
 use ipa_macros::Step;
 
 use crate::{
     error::Error,
-    ff::{boolean::Boolean, CustomArray, Expand, Field, PrimeField, Serializable},
+    ff::{
+        boolean::Boolean, boolean_array::BA8, ArrayAccess, CustomArray, Expand, Field, PrimeField,
+        Serializable,
+    },
     helpers::Role,
     protocol::{
-        basics::{if_else, SecureMul, ShareKnownValue},
+        basics::{if_else, SecureMul, ShareKnownValue, SumOfProducts},
         boolean::or::or,
         context::{Context, UpgradableContext, UpgradedContext, Validator},
-        ipa_prf::boolean_ops::{
-            addition_sequential::integer_add,
-            comparison_and_subtraction_sequential::{compare_gt, integer_sub},
+        ipa_prf::{
+            boolean_ops::{
+                addition_sequential::integer_add,
+                comparison_and_subtraction_sequential::{compare_geq, compare_gt, integer_sub},
+            },
+            time_bucket::{compute_time_bucket, BucketBoundaries},
         },
         modulus_conversion::{convert_bits, BitConversionTriple, ToBitConversionTriples},
         RecordId,
@@ -36,6 +46,17 @@ use crate::{
 pub mod bucket;
 #[cfg(feature = "descriptive-gate")]
 pub mod feature_label_dot_product;
+pub mod filter;
+#[cfg(feature = "descriptive-gate")]
+pub mod refund;
+#[cfg(feature = "descriptive-gate")]
+pub mod sharding;
+#[cfg(feature = "descriptive-gate")]
+pub mod time_box;
+
+/// Priority of a source event. Among source events of the same user that share a timestamp,
+/// the one with the highest priority is attributed.
+pub type SourcePriority = BA8;
 
 #[derive(Debug)]
 pub struct PrfShardedIpaInputRow<BK: WeakSharedValue, TV: WeakSharedValue, TS: WeakSharedValue> {
@@ -44,6 +65,10 @@ pub struct PrfShardedIpaInputRow<BK: WeakSharedValue, TV: WeakSharedValue, TS: W
     pub breakdown_key: Replicated<BK>,
     pub trigger_value: Replicated<TV>,
     pub timestamp: Replicated<TS>,
+    /// Breaks timestamp ties between source events. Must be set either for all input rows or
+    /// for none of them. If it is not set, the last of the tied source events in the input order
+    /// is attributed.
+    pub priority: Option<Replicated<SourcePriority>>,
 }
 
 impl<BK: WeakSharedValue, TS: WeakSharedValue, TV: WeakSharedValue> GroupingKey
@@ -66,6 +91,10 @@ struct InputsRequiredFromPrevRow<
     is_saturated: Replicated<Boolean>,
     difference_to_cap: Replicated<TV>,
     source_event_timestamp: Replicated<TS>,
+    source_event_priority: Option<Replicated<SourcePriority>>,
+    /// Whether any of the previous rows was an attributed trigger event. Only tracked if
+    /// attributed users are counted.
+    ever_attributed_a_trigger_event: Option<Replicated<Boolean>>,
 }
 
 impl<
@@ -83,10 +112,16 @@ impl<
     /// - Last touch attribution
     ///     - Every trigger event which is preceded by a source event is attributed
     ///     - Trigger events are attributed to the `breakdown_key` of the most recent preceding source event
+    ///     - If input rows carry priorities, a source event that has the same timestamp as the most recent
+    ///       preceding source event replaces it only if its priority is not lower
     /// - Per user capping
     ///     - A cumulative sum of "Attributed Trigger Value" is maintained
     ///     - Bitwise addition is used, and a single bit indicates if the sum is "saturated"
-    ///     - The only available values for "cap" are powers of 2 (i.e. 1, 2, 4, 8, 16, 32, ...)
+    ///     - If trigger values are wider than the sum, they are clamped to `per_user_cap` first,
+    ///       see [`clamp_trigger_value`]
+    ///     - The sum is saturated once it reaches `per_user_cap`. Any cap in `[1, 2^|SS|]` is supported. If the
+    ///       cap is exactly `2^|SS|`, saturation is detected via the adder overflow bit, otherwise the sum is
+    ///       compared against the cap, which costs `|SS| + 1` additional multiplications per row
     ///     - Prior to the cumulative sum reaching saturation, attributed trigger values are passed along
     ///     - The row which puts the cumulative sum over the cap is "capped" to the delta between the cumulative sum of the last row and the cap
     ///     - All subsequent rows contribute zero
@@ -99,12 +134,16 @@ impl<
     ///         - `did_trigger_get_attributed` - a secret-shared bit indicating if this row corresponds to a trigger event
     ///           which was attributed. Might be able to reveal this (after a shuffle and the addition of dummies) to minimize
     ///           the amount of processing work that must be done in the Aggregation stage.
+    ///         - `attributed_user` - if attributed users are counted, a secret-shared bit set only for the first
+    ///           attributed trigger event of the user, regardless of its value. It is set for at most one row per user,
+    ///           so the user is counted once, towards the breakdown of that row.
     pub async fn compute_row_with_previous<C>(
         &mut self,
         ctx: C,
         record_id: RecordId,
         input_row: &PrfShardedIpaInputRow<BK, TV, TS>,
         attribution_window_seconds: Option<NonZeroU32>,
+        per_user_cap: u32,
     ) -> Result<CappedAttributionOutputs<BK, TV>, Error>
     where
         C: Context,
@@ -114,80 +153,141 @@ impl<
     {
         let is_source_event = input_row.is_trigger_bit.clone().not();
 
+        let keep_prev_source_event = match (&self.source_event_priority, &input_row.priority) {
+            (Some(prev_priority), Some(cur_priority)) => {
+                keep_prev_source_event_on_tie(
+                    ctx.clone(),
+                    record_id,
+                    &input_row.is_trigger_bit,
+                    &self.ever_encountered_a_source_event,
+                    (&self.source_event_timestamp, &input_row.timestamp),
+                    (prev_priority, cur_priority),
+                )
+                .await?
+            }
+            _ => input_row.is_trigger_bit.clone(),
+        };
+
         let (
-            ever_encountered_a_source_event,
-            attributed_breakdown_key_bits,
-            source_event_timestamp,
-        ) = try_join3(
-            or(
-                ctx.narrow(&Step::EverEncounteredSourceEvent),
-                record_id,
-                &is_source_event,
-                &self.ever_encountered_a_source_event,
+            (
+                ever_encountered_a_source_event,
+                attributed_breakdown_key_bits,
+                source_event_timestamp,
+                source_event_priority,
             ),
-            breakdown_key_of_most_recent_source_event(
-                ctx.narrow(&Step::AttributedBreakdownKey),
-                record_id,
-                &input_row.is_trigger_bit,
-                &self.attributed_breakdown_key_bits,
-                &input_row.breakdown_key,
+            trigger_value,
+        ) = try_join(
+            try_join4(
+                or(
+                    ctx.narrow(&Step::EverEncounteredSourceEvent),
+                    record_id,
+                    &is_source_event,
+                    &self.ever_encountered_a_source_event,
+                ),
+                breakdown_key_of_most_recent_source_event(
+                    ctx.narrow(&Step::AttributedBreakdownKey),
+                    record_id,
+                    &keep_prev_source_event,
+                    &self.attributed_breakdown_key_bits,
+                    &input_row.breakdown_key,
+                ),
+                timestamp_of_most_recent_source_event(
+                    ctx.narrow(&Step::SourceEventTimestamp),
+                    record_id,
+                    attribution_window_seconds.is_some() || input_row.priority.is_some(),
+                    &keep_prev_source_event,
+                    &self.source_event_timestamp,
+                    &input_row.timestamp,
+                ),
+                priority_of_most_recent_source_event(
+                    ctx.clone(),
+                    record_id,
+                    &keep_prev_source_event,
+                    self.source_event_priority.as_ref(),
+                    input_row.priority.as_ref(),
+                ),
             ),
-            timestamp_of_most_recent_source_event(
-                ctx.narrow(&Step::SourceEventTimestamp),
+            clamp_trigger_value::<_, TV, SS>(
+                ctx.clone(),
                 record_id,
-                attribution_window_seconds,
-                &input_row.is_trigger_bit,
-                &self.source_event_timestamp,
-                &input_row.timestamp,
+                &input_row.trigger_value,
+                per_user_cap,
             ),
         )
         .await?;
 
-        let attributed_trigger_value = zero_out_trigger_value_unless_attributed(
+        let (attributed_trigger_value, is_attributed) = zero_out_trigger_value_unless_attributed(
             ctx.narrow(&Step::AttributedTriggerValue),
             record_id,
             &input_row.is_trigger_bit,
             &ever_encountered_a_source_event,
-            &input_row.trigger_value,
+            &trigger_value,
             attribution_window_seconds,
             &input_row.timestamp,
             &source_event_timestamp,
         )
         .await?;
 
-        let (updated_sum, overflow_bit) = integer_add(
-            ctx.narrow(&Step::ComputeSaturatingSum),
-            record_id,
-            &self.saturating_sum,
-            &attributed_trigger_value,
+        let ((updated_sum, overflow_bit), attributed_user) = try_join(
+            integer_add(
+                ctx.narrow(&Step::ComputeSaturatingSum),
+                record_id,
+                &self.saturating_sum,
+                &attributed_trigger_value,
+            ),
+            is_first_attributed_trigger_event(
+                ctx.clone(),
+                record_id,
+                &is_attributed,
+                self.ever_attributed_a_trigger_event.as_ref(),
+            ),
         )
         .await?;
+        // A clamped trigger value may have one bit more than the sum, which is set only if all the
+        // others are not, so it overflows without a carry.
+        let top_bit = usize::try_from(<SS as WeakSharedValue>::BITS).unwrap();
+        let overflow_bit = match attributed_trigger_value.get(top_bit) {
+            Some(top_bit) => overflow_bit + top_bit,
+            None => overflow_bit,
+        };
 
-        let (overflow_bit_and_prev_row_not_saturated, difference_to_cap) = try_join(
-            overflow_bit.multiply(
-                &self.is_saturated.clone().not(),
-                ctx.narrow(&Step::IsSaturatedAndPrevRowNotSaturated),
+        // Only the last |TV| bits of the difference are computed. That is enough, because the
+        // difference is used only if it does not exceed the attributed trigger value.
+        let (sum_reached_cap, difference_to_cap) = try_join(
+            sum_reached_cap(
+                ctx.clone(),
                 record_id,
+                &updated_sum,
+                overflow_bit,
+                per_user_cap,
             ),
             integer_sub(
                 ctx.narrow(&Step::ComputeDifferenceToCap),
                 record_id,
-                &Replicated::<TV>::ZERO,
+                &Replicated::<TV>::share_known_value(&ctx, TV::truncate_from(per_user_cap)),
                 &updated_sum,
             ),
         )
         .await?;
 
+        let sum_reached_cap_and_prev_row_not_saturated = sum_reached_cap
+            .multiply(
+                &self.is_saturated.clone().not(),
+                ctx.narrow(&Step::IsSaturatedAndPrevRowNotSaturated),
+                record_id,
+            )
+            .await?;
+
         // Tricky way of expressing an `OR` condition, but with no additional multiplications:
         //   Logically: "Did this row just become saturated OR was the previous row already saturated"
         //   This works because these conditions cannot both be true
-        let is_saturated = &self.is_saturated + &overflow_bit_and_prev_row_not_saturated;
+        let is_saturated = &self.is_saturated + &sum_reached_cap_and_prev_row_not_saturated;
 
         let capped_attributed_trigger_value = compute_capped_trigger_value(
             ctx,
             record_id,
             &is_saturated,
-            &overflow_bit_and_prev_row_not_saturated,
+            &sum_reached_cap_and_prev_row_not_saturated,
             &self.difference_to_cap,
             &attributed_trigger_value,
         )
@@ -199,10 +299,19 @@ impl<
         self.is_saturated = is_saturated;
         self.difference_to_cap = difference_to_cap;
         self.source_event_timestamp = source_event_timestamp;
+        self.source_event_priority = source_event_priority;
+        if let (Some(ever_attributed), Some(attributed_user)) =
+            (&mut self.ever_attributed_a_trigger_event, &attributed_user)
+        {
+            // set only if no previous row was attributed, so adding it is the same as OR
+            *ever_attributed += attributed_user;
+        }
 
         let outputs_for_aggregation = CappedAttributionOutputs {
             attributed_breakdown_key_bits,
             capped_attributed_trigger_value,
+            attributed_user,
+            time_bucket: None,
         };
         Ok(outputs_for_aggregation)
     }
@@ -212,6 +321,11 @@ impl<
 pub struct CappedAttributionOutputs<BK: WeakSharedValue, TV: WeakSharedValue> {
     pub attributed_breakdown_key_bits: Replicated<BK>,
     pub capped_attributed_trigger_value: Replicated<TV>,
+    /// Set for the first attributed trigger event of a user, if attributed users are counted.
+    pub attributed_user: Option<Replicated<Boolean>>,
+    /// Bits of the time bucket of the row, least significant first, if aggregates are keyed by
+    /// time bucket as well.
+    pub time_bucket: Option<BitDecomposed<Replicated<Boolean>>>,
 }
 
 impl<
@@ -222,7 +336,13 @@ impl<
     type Residual = ();
 
     fn bits(&self) -> u32 {
-        BK::BITS + TV::BITS
+        BK::BITS
+            + TV::BITS
+            + u32::from(self.attributed_user.is_some())
+            + self
+                .time_bucket
+                .as_ref()
+                .map_or(0, |bits| u32::try_from(bits.len()).unwrap())
     }
 
     fn triple<F: PrimeField>(&self, role: Role, i: u32) -> BitConversionTriple<Replicated<F>> {
@@ -235,13 +355,25 @@ impl<
                 self.attributed_breakdown_key_bits.0.get(i).unwrap() == Boolean::ONE,
                 self.attributed_breakdown_key_bits.1.get(i).unwrap() == Boolean::ONE,
             )
-        } else {
+        } else if i < bk_bits + usize::try_from(TV::BITS).unwrap() {
             let i = i - bk_bits;
             BitConversionTriple::new(
                 role,
                 self.capped_attributed_trigger_value.0.get(i).unwrap() == Boolean::ONE,
                 self.capped_attributed_trigger_value.1.get(i).unwrap() == Boolean::ONE,
             )
+        } else {
+            let i = i - bk_bits - usize::try_from(TV::BITS).unwrap();
+            let bit = match &self.attributed_user {
+                Some(attributed_user) if i == 0 => attributed_user,
+                Some(_) => &self.time_bucket.as_ref().unwrap()[i - 1],
+                None => &self.time_bucket.as_ref().unwrap()[i],
+            };
+            BitConversionTriple::new(
+                role,
+                bit.left() == Boolean::ONE,
+                bit.right() == Boolean::ONE,
+            )
         }
     }
 
@@ -298,37 +430,70 @@ pub(crate) enum Step {
     ComputeTimeDelta,
     CompareTimeDeltaToAttributionWindow,
     SourceEventTimestamp,
+    SourceEventPriority,
+    IsSourceEventTimestampGreater,
+    IsPrevSourceEventPriorityGreater,
+    IsSourceEventAfterAnotherSourceEvent,
+    IsSourceEventTimestampTiedWithLowerPriority,
+    DidSourceEventLoseTie,
+    IsFirstAttributedTriggerEvent,
+    CompareTriggerValueToCap,
+    ClampTriggerValue,
     ComputeSaturatingSum,
+    CompareSaturatingSumToCap,
+    DidSaturatingSumReachCap,
     IsSaturatedAndPrevRowNotSaturated,
     ComputeDifferenceToCap,
     ComputedCappedAttributedTriggerValueNotSaturatedCase,
     ComputedCappedAttributedTriggerValueJustSaturatedCase,
+    ComputeTimeBuckets,
+    FilterBreakdownKeys,
+    IsBreakdownKeyAllowed,
+    ZeroOutDisallowedTriggerValue,
+    ZeroOutDisallowedAttributedUser,
     ModulusConvertBreakdownKeyBitsAndTriggerValues,
     MoveValueToCorrectBreakdown,
+    MoveAttributedUserToCorrectBreakdown,
+    AggregateTwoStageBuckets,
+    AggregateAttributedUsersTwoStage,
+    CheckUserChunkOrder,
+}
+
+/// Input rows that don't match what attribution expects of them.
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum UserRowsError {
+    #[error("histogram of rows per user {given:?} does not match the input, which has {actual:?}")]
+    HistogramMismatch {
+        given: Vec<usize>,
+        actual: Vec<usize>,
+    },
+    #[error("helpers disagree on the order in which user rows are processed")]
+    ChunkOrderMismatch,
 }
 
 pub trait GroupingKey {
     fn get_grouping_key(&self) -> u64;
 }
 
-pub fn compute_histogram_of_users_with_row_count<S>(input: &[S]) -> Vec<usize>
-where
-    S: GroupingKey,
-{
-    let mut histogram = vec![];
-    let mut last_prf = input[0].get_grouping_key() + 1;
-    let mut cur_count = 0;
-    for row in input {
-        if row.get_grouping_key() == last_prf {
-            cur_count += 1;
-        } else {
-            cur_count = 0;
-            last_prf = row.get_grouping_key();
-        }
-        if histogram.len() <= cur_count {
-            histogram.push(0);
+/// Returns the number of users in `rows`, which must be grouped by user.
+#[must_use]
+pub fn count_users<R: GroupingKey>(rows: &[R]) -> u64 {
+    let boundaries = rows
+        .windows(2)
+        .filter(|w| w[0].get_grouping_key() != w[1].get_grouping_key())
+        .count();
+    u64::from(!rows.is_empty()) + boundaries as u64
+}
+
+/// Returns the number of chunks that have a row at every depth: element `i` is the number of
+/// chunks with more than `i` rows. Row `i` of every user is processed in a context of its own,
+/// which expects that many records.
+fn histogram_of_user_chunks<R>(chunks: &[Vec<R>]) -> Vec<usize> {
+    let mut histogram = vec![0; chunks.iter().map(Vec::len).max().unwrap_or(0)];
+    for rows in chunks {
+        for count in &mut histogram[..rows.len()] {
+            *count += 1;
         }
-        histogram[cur_count] += 1;
     }
     histogram
 }
@@ -351,38 +516,97 @@ where
     context_per_row_depth
 }
 
+/// Groups `rows`, which must have all rows of a user adjacent to one another, into one vector of
+/// rows per user, in input order.
+fn group_rows_by_user<R: GroupingKey>(rows: Vec<R>) -> Vec<Vec<R>> {
+    let mut users: Vec<Vec<R>> = Vec::new();
+    for row in rows {
+        match users.last_mut() {
+            Some(user) if user[0].get_grouping_key() == row.get_grouping_key() => user.push(row),
+            _ => users.push(vec![row]),
+        }
+    }
+    users
+}
+
+/// Input rows grouped for attribution, see [`prepare_user_rows`].
+struct UserRows<R> {
+    /// Rows of every user that has more than one row, in processing order.
+    chunks: Vec<Vec<R>>,
+    /// Element `i` is the number of chunks with more than `i` rows.
+    histogram: Vec<usize>,
+    /// Number of rows that produce an output, which is all but the first row of every chunk.
+    num_outputs: usize,
+}
+
+/// Groups `input_rows` by user and orders users for processing, see [`sort_user_chunks`].
 ///
-/// Takes an input stream of `PrfShardedIpaInputRecordRow` which is assumed to have all records with a given PRF adjacent
-/// and converts it into a stream of vectors of `PrfShardedIpaInputRecordRow` having the same PRF.
+/// Users with a single row are left out, since none of their events can be attributed. Inputs
+/// that are empty or only have such users result in no chunks at all.
+fn prepare_user_rows<R: GroupingKey>(input_rows: Vec<R>) -> UserRows<R> {
+    let mut chunks = group_rows_by_user(input_rows);
+    chunks.retain(|rows| rows.len() > 1);
+    sort_user_chunks(&mut chunks);
+    let histogram = histogram_of_user_chunks(&chunks);
+    let num_outputs = chunks.iter().map(|rows| rows.len() - 1).sum();
+    UserRows {
+        chunks,
+        histogram,
+        num_outputs,
+    }
+}
+
+/// Orders chunks of user rows for processing: longest first, ties broken by PRF value.
 ///
-/// Filters out any users that only have a single row, since they will produce no attributed conversions.
+/// Record ids are assigned to rows in this order, so it must be the same on all helpers. Chunk
+/// length and PRF value are known to all of them, and no two chunks share a PRF value, so this
+/// order does not depend on how input rows were staged on each helper.
+fn sort_user_chunks<R: GroupingKey>(chunks: &mut [Vec<R>]) {
+    chunks.sort_unstable_by_key(|rows| (Reverse(rows.len()), rows[0].get_grouping_key()));
+}
+
+/// Makes sure that every helper processes chunks of user rows in the same order as its left
+/// peer. Helpers that disagree would silently misalign record ids, so this catches bugs that
+/// break [`sort_user_chunks`]. It runs in every build, so all helpers exchange the same messages.
 ///
-fn chunk_rows_by_user<IS, BK, TV, TS>(
-    input_stream: IS,
-    first_row: PrfShardedIpaInputRow<BK, TV, TS>,
-) -> impl Stream<Item = Vec<PrfShardedIpaInputRow<BK, TV, TS>>>
+/// ## Errors
+/// If the digest of the processing order cannot be exchanged with peers, or this helper and its
+/// left peer disagree on the processing order.
+async fn check_user_chunk_order<C, BK, TV, TS>(
+    ctx: C,
+    chunks: &[Vec<PrfShardedIpaInputRow<BK, TV, TS>>],
+) -> Result<(), Error>
 where
+    C: Context,
     BK: WeakSharedValue,
     TV: WeakSharedValue,
     TS: WeakSharedValue,
-    IS: Stream<Item = PrfShardedIpaInputRow<BK, TV, TS>> + Unpin,
 {
-    unfold(Some((input_stream, first_row)), |state| async move {
-        let (mut s, last_row) = state?;
-        let mut last_row_prf = last_row.prf_of_match_key;
-        let mut current_chunk = vec![last_row];
-        while let Some(row) = s.next().await {
-            if row.prf_of_match_key == last_row_prf {
-                current_chunk.push(row);
-            } else if current_chunk.len() > 1 {
-                return Some((current_chunk, Some((s, row))));
-            } else {
-                last_row_prf = row.prf_of_match_key;
-                current_chunk = vec![row];
-            }
-        }
-        Some((current_chunk, None))
-    })
+    use crate::{ff::boolean_array::BA64, helpers::Direction};
+
+    // FNV-1a over chunk lengths and PRF values
+    let digest = chunks
+        .iter()
+        .flat_map(|rows| [rows.len() as u64, rows[0].prf_of_match_key])
+        .fold(0xcbf2_9ce4_8422_2325_u64, |acc, v| {
+            (acc ^ v).wrapping_mul(0x0000_0100_0000_01b3)
+        });
+    let digest = BA64::truncate_from(digest);
+
+    let ctx = ctx.set_total_records(1);
+    let ((), left_digest) = try_join(
+        ctx.send_channel(ctx.role().peer(Direction::Right))
+            .send(RecordId::FIRST, digest),
+        ctx.recv_channel::<BA64>(ctx.role().peer(Direction::Left))
+            .receive(RecordId::FIRST),
+    )
+    .await?;
+
+    if left_digest != digest {
+        return Err(UserRowsError::ChunkOrderMismatch.into());
+    }
+
+    Ok(())
 }
 
 /// Sub-protocol of the PRF-sharded IPA Protocol
@@ -396,23 +620,152 @@ where
 ///
 /// This circuit will compute attribution, and per-user capping.
 ///
-/// The output of this circuit is the input to the next stage: Aggregation.
+/// The output of this circuit is the input to the next stage: Aggregation. It contains
+/// `num_breakdowns` values, one per breakdown key in `[0, num_breakdowns)`. Contributions with
+/// breakdown keys outside of this range end up in one of the output buckets. Breakdown keys wider
+/// than [`bucket::MAX_SINGLE_STAGE_BREAKDOWN_KEY_BITS`] are moved to buckets in two stages, see
+/// [`bucket::move_single_value_to_bucket_two_stage`], and out of range contributions may be
+/// dropped instead.
+///
+/// If `breakdown_allow_list` is set, contributions attributed to breakdown keys that are not in it
+/// are zeroed out before aggregation, see [`filter`].
+///
+/// If `time_buckets` is set, contributions are aggregated by breakdown key and by the time bucket
+/// of the trigger event, so the output has `num_breakdowns * time_buckets.len()` values, all time
+/// buckets of breakdown 0 first. Bits of the time bucket are appended below the breakdown key
+/// bits, which widens the tree that moves values to buckets by as many levels, see
+/// [`compute_time_bucket`].
 ///
 /// # Errors
-/// Propagates errors from multiplications
+/// Propagates errors from multiplications. Returns an error if `num_breakdowns` exceeds `2^|BK|`
+/// or if `per_user_cap` is not within `[1, 2^|SS|]`.
 /// # Panics
-/// Propagates errors from multiplications
+/// If source event priority is set only for some of the input rows.
+#[tracing::instrument(name = "attribute_cap_aggregate", skip_all)]
 pub async fn attribute_cap_aggregate<C, BK, TV, TS, SS, S, F>(
     sh_ctx: C,
     input_rows: Vec<PrfShardedIpaInputRow<BK, TV, TS>>,
     attribution_window_seconds: Option<NonZeroU32>,
-    histogram: &[usize],
+    per_user_cap: u32,
+    num_breakdowns: u32,
+    breakdown_allow_list: Option<&[u32]>,
+    time_buckets: Option<&BucketBoundaries>,
 ) -> Result<Vec<S>, Error>
 where
     C: UpgradableContext,
     C::UpgradedContext<Boolean>: UpgradedContext<Boolean, Share = Replicated<Boolean>>,
     C::UpgradedContext<F>: UpgradedContext<F, Share = S>,
-    S: LinearSecretSharing<F> + Serializable + SecureMul<C::UpgradedContext<F>>,
+    S: LinearSecretSharing<F>
+        + Serializable
+        + SecureMul<C::UpgradedContext<F>>
+        + ShareKnownValue<C::UpgradedContext<F>, F>
+        + SumOfProducts<C::UpgradedContext<F>>,
+    BK: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    TV: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    TS: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    SS: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    for<'a> &'a Replicated<SS>: IntoIterator<Item = Replicated<Boolean>>,
+    for<'a> &'a Replicated<TS>: IntoIterator<Item = Replicated<Boolean>>,
+    for<'a> &'a Replicated<TV>: IntoIterator<Item = Replicated<Boolean>>,
+    for<'a> &'a Replicated<BK>: IntoIterator<Item = Replicated<Boolean>>,
+    for<'a> <&'a Replicated<SS> as IntoIterator>::IntoIter: Send,
+    for<'a> <&'a Replicated<TV> as IntoIterator>::IntoIter: Send,
+    for<'a> <&'a Replicated<TS> as IntoIterator>::IntoIter: Send,
+    F: PrimeField + ExtendableField,
+{
+    attribute_cap_aggregate_impl::<_, BK, TV, TS, SS, S, F>(
+        sh_ctx,
+        input_rows,
+        attribution_window_seconds,
+        per_user_cap,
+        num_breakdowns,
+        breakdown_allow_list,
+        time_buckets,
+        false,
+    )
+    .await
+    .map(|(values, _)| values)
+}
+
+/// Same as [`attribute_cap_aggregate`], but also counts users with at least one attributed trigger
+/// event, regardless of its value. Every such user is counted once, towards the breakdown of
+/// their first attributed trigger event, which makes it possible to compute conversion rates
+/// alongside value sums in a single pass.
+///
+/// Returns the sums of values and the counts of users, `num_breakdowns` buckets each. Counting
+/// users takes one more multiplication per row and doubles the cost of moving contributions to
+/// buckets.
+///
+/// # Errors
+/// Same as [`attribute_cap_aggregate`].
+/// # Panics
+/// Same as [`attribute_cap_aggregate`].
+#[tracing::instrument(name = "attribute_cap_aggregate_with_attributed_users", skip_all)]
+pub async fn attribute_cap_aggregate_with_attributed_users<C, BK, TV, TS, SS, S, F>(
+    sh_ctx: C,
+    input_rows: Vec<PrfShardedIpaInputRow<BK, TV, TS>>,
+    attribution_window_seconds: Option<NonZeroU32>,
+    per_user_cap: u32,
+    num_breakdowns: u32,
+    breakdown_allow_list: Option<&[u32]>,
+    time_buckets: Option<&BucketBoundaries>,
+) -> Result<(Vec<S>, Vec<S>), Error>
+where
+    C: UpgradableContext,
+    C::UpgradedContext<Boolean>: UpgradedContext<Boolean, Share = Replicated<Boolean>>,
+    C::UpgradedContext<F>: UpgradedContext<F, Share = S>,
+    S: LinearSecretSharing<F>
+        + Serializable
+        + SecureMul<C::UpgradedContext<F>>
+        + ShareKnownValue<C::UpgradedContext<F>, F>
+        + SumOfProducts<C::UpgradedContext<F>>,
+    BK: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    TV: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    TS: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    SS: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    for<'a> &'a Replicated<SS>: IntoIterator<Item = Replicated<Boolean>>,
+    for<'a> &'a Replicated<TS>: IntoIterator<Item = Replicated<Boolean>>,
+    for<'a> &'a Replicated<TV>: IntoIterator<Item = Replicated<Boolean>>,
+    for<'a> &'a Replicated<BK>: IntoIterator<Item = Replicated<Boolean>>,
+    for<'a> <&'a Replicated<SS> as IntoIterator>::IntoIter: Send,
+    for<'a> <&'a Replicated<TV> as IntoIterator>::IntoIter: Send,
+    for<'a> <&'a Replicated<TS> as IntoIterator>::IntoIter: Send,
+    F: PrimeField + ExtendableField,
+{
+    attribute_cap_aggregate_impl::<_, BK, TV, TS, SS, S, F>(
+        sh_ctx,
+        input_rows,
+        attribution_window_seconds,
+        per_user_cap,
+        num_breakdowns,
+        breakdown_allow_list,
+        time_buckets,
+        true,
+    )
+    .await
+    .map(|(values, users)| (values, users.unwrap()))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn attribute_cap_aggregate_impl<C, BK, TV, TS, SS, S, F>(
+    sh_ctx: C,
+    input_rows: Vec<PrfShardedIpaInputRow<BK, TV, TS>>,
+    attribution_window_seconds: Option<NonZeroU32>,
+    per_user_cap: u32,
+    num_breakdowns: u32,
+    breakdown_allow_list: Option<&[u32]>,
+    time_buckets: Option<&BucketBoundaries>,
+    count_attributed_users: bool,
+) -> Result<(Vec<S>, Option<Vec<S>>), Error>
+where
+    C: UpgradableContext,
+    C::UpgradedContext<Boolean>: UpgradedContext<Boolean, Share = Replicated<Boolean>>,
+    C::UpgradedContext<F>: UpgradedContext<F, Share = S>,
+    S: LinearSecretSharing<F>
+        + Serializable
+        + SecureMul<C::UpgradedContext<F>>
+        + ShareKnownValue<C::UpgradedContext<F>, F>
+        + SumOfProducts<C::UpgradedContext<F>>,
     BK: WeakSharedValue + CustomArray<Element = Boolean> + Field,
     TV: WeakSharedValue + CustomArray<Element = Boolean> + Field,
     TS: WeakSharedValue + CustomArray<Element = Boolean> + Field,
@@ -426,6 +779,38 @@ where
     for<'a> <&'a Replicated<TS> as IntoIterator>::IntoIter: Send,
     F: PrimeField + ExtendableField,
 {
+    if !(1..=1 << <SS as WeakSharedValue>::BITS).contains(&u128::from(per_user_cap)) {
+        return Err(Error::InvalidQueryParameter(
+            format!(
+                "per-user cap must be within [1, {}], got {per_user_cap}",
+                1_u128 << <SS as WeakSharedValue>::BITS
+            )
+            .into(),
+        ));
+    }
+    assert!(
+        input_rows.iter().all(|row| row.priority.is_some())
+            || input_rows.iter().all(|row| row.priority.is_none()),
+        "source event priority must be set either for all rows or for none of them"
+    );
+
+    // Group records with the same PRF. Every helper gets the same chunks, because PRF values are
+    // public, so all of them return early if no user can have attributed conversions.
+    let UserRows {
+        chunks: collected,
+        histogram,
+        num_outputs,
+    } = prepare_user_rows(input_rows);
+    if collected.is_empty() {
+        let len = usize::try_from(num_breakdowns).unwrap()
+            * time_buckets.map_or(1, BucketBoundaries::len);
+        return Ok((
+            vec![S::ZERO; len],
+            count_attributed_users.then(|| vec![S::ZERO; len]),
+        ));
+    }
+    check_user_chunk_order(sh_ctx.narrow(&Step::CheckUserChunkOrder), &collected).await?;
+
     // Get the validator and context to use for Boolean multiplication operations
     let binary_validator = sh_ctx.narrow(&Step::BinaryValidator).validator::<Boolean>();
     let binary_m_ctx = binary_validator.context();
@@ -435,27 +820,22 @@ where
     let prime_field_ctx = prime_field_validator.context();
 
     // Tricky hacks to work around the limitations of our current infrastructure
-    let num_outputs = input_rows.len() - histogram[0];
     let mut record_id_for_row_depth = vec![0_u32; histogram.len()];
-    let ctx_for_row_number = set_up_contexts(&binary_m_ctx, histogram);
-
-    // Chunk the incoming stream of records into stream of vectors of records with the same PRF
-    let mut input_stream = stream_iter(input_rows);
-    let first_row = input_stream.next().await;
-    if first_row.is_none() {
-        return Ok(vec![]);
-    }
-    let first_row = first_row.unwrap();
-    let rows_chunked_by_user = chunk_rows_by_user(input_stream, first_row);
-
-    let mut collected = rows_chunked_by_user.collect::<Vec<_>>().await;
-    collected.sort_by(|a, b| std::cmp::Ord::cmp(&b.len(), &a.len()));
+    let ctx_for_row_number = set_up_contexts(&binary_m_ctx, &histogram);
 
     // Convert to a stream of async futures that represent the result of executing the per-user circuit
+    // Timestamps of rows that produce outputs are kept along with them if outputs are keyed by
+    // time bucket
     let stream_of_per_user_circuits = pin!(stream_iter(collected).then(|rows_for_user| {
         let num_user_rows = rows_for_user.len();
         let contexts = ctx_for_row_number[..num_user_rows - 1].to_owned();
         let record_ids = record_id_for_row_depth[..num_user_rows].to_owned();
+        let timestamps = time_buckets.map(|_| {
+            rows_for_user[1..]
+                .iter()
+                .map(|row| row.timestamp.clone())
+                .collect::<Vec<_>>()
+        });
 
         for count in &mut record_id_for_row_depth[..num_user_rows] {
             *count += 1;
@@ -468,61 +848,334 @@ where
                 record_ids,
                 rows_for_user,
                 attribution_window_seconds,
+                per_user_cap,
+                count_attributed_users,
             )
+            .map_ok(|outputs| (outputs, timestamps))
         }
     }));
 
     // Execute all of the async futures (sequentially), and flatten the result
-    let flattenned_stream = seq_join(sh_ctx.active_work(), stream_of_per_user_circuits)
-        .flat_map(|x| stream_iter(x.unwrap()));
+    let flattenned_stream =
+        seq_join(sh_ctx.active_work(), stream_of_per_user_circuits).flat_map(|x| {
+            let (outputs, timestamps) = x.unwrap();
+            let timestamps = timestamps
+                .into_iter()
+                .flatten()
+                .map(Some)
+                .chain(std::iter::repeat_with(|| None));
+            stream_iter(outputs.into_iter().zip(timestamps))
+        });
+
+    // assign every row to the time bucket of its timestamp
+    let flattenned_stream = match time_buckets {
+        Some(time_buckets) => {
+            let ctx = binary_m_ctx
+                .narrow(&Step::ComputeTimeBuckets)
+                .set_total_records(num_outputs);
+            seq_join(
+                sh_ctx.active_work(),
+                flattenned_stream
+                    .enumerate()
+                    .map(move |(i, (row, timestamp))| {
+                        let ctx = ctx.clone();
+                        async move {
+                            let time_bucket = compute_time_bucket(
+                                ctx,
+                                RecordId::from(i),
+                                &timestamp.unwrap(),
+                                time_buckets,
+                            )
+                            .await?;
+                            Ok::<_, Error>(CappedAttributionOutputs {
+                                time_bucket: Some(time_bucket),
+                                ..row
+                            })
+                        }
+                    }),
+            )
+            .map(Result::unwrap)
+            .left_stream()
+        }
+        None => flattenned_stream.map(|(row, _)| row).right_stream(),
+    };
+
+    // zero out contributions to breakdowns that are not allowed
+    let allowed_keys = breakdown_allow_list.map(filter::allowed_keys::<BK>);
+    let flattenned_stream = match &allowed_keys {
+        Some(allowed_keys) => {
+            let ctx = binary_m_ctx
+                .narrow(&Step::FilterBreakdownKeys)
+                .set_total_records(num_outputs);
+            seq_join(
+                sh_ctx.active_work(),
+                flattenned_stream.enumerate().map(move |(i, row)| {
+                    filter::filter_breakdown_key(ctx.clone(), RecordId::from(i), row, allowed_keys)
+                }),
+            )
+            .map(Result::unwrap)
+            .left_stream()
+        }
+        None => flattenned_stream.right_stream(),
+    };
 
-    // modulus convert breakdown keys and trigger values
+    // modulus convert breakdown keys and trigger values, followed by the attributed user bit and
+    // the time bucket
+    let time_bucket_bits = time_buckets.map_or(0, BucketBoundaries::bucket_index_bits);
     let converted_bks_and_tvs = convert_bits(
         prime_field_ctx
             .narrow(&Step::ModulusConvertBreakdownKeyBitsAndTriggerValues)
             .set_total_records(num_outputs),
         flattenned_stream,
-        0..(<BK as WeakSharedValue>::BITS + <TV as WeakSharedValue>::BITS),
+        0..(<BK as WeakSharedValue>::BITS
+            + <TV as WeakSharedValue>::BITS
+            + u32::from(count_attributed_users)
+            + time_bucket_bits),
     );
+    // time bucket bits are the low bits of the key buckets are picked by, so every breakdown spans
+    // `2^time_bucket_bits` buckets, of which only the first `time_buckets.len()` are used
+    let num_buckets = match time_buckets {
+        Some(time_buckets) => {
+            (num_breakdowns.saturating_sub(1) << time_bucket_bits)
+                + u32::try_from(time_buckets.len()).unwrap()
+        }
+        None => num_breakdowns,
+    };
+    let split_bits = move |bk_and_tv_bits: BitDecomposed<S>| {
+        let (bk_bits, tv_and_user_bits) = bk_and_tv_bits.split_at(<BK as WeakSharedValue>::BITS);
+        let (tv_bits, user_and_time_bits) =
+            tv_and_user_bits.split_at(<TV as WeakSharedValue>::BITS);
+        let (user_bit, time_bits) = user_and_time_bits.split_at(u32::from(count_attributed_users));
+        (
+            BitDecomposed::new(time_bits.into_iter().chain(bk_bits)),
+            tv_bits,
+            user_bit.into_iter().next(),
+        )
+    };
+
+    let move_ctx = prime_field_ctx
+        .narrow(&Step::MoveValueToCorrectBreakdown)
+        .set_total_records(num_outputs);
+    let move_user_ctx = prime_field_ctx
+        .narrow(&Step::MoveAttributedUserToCorrectBreakdown)
+        .set_total_records(num_outputs);
+
+    // wide breakdown keys are moved in two stages, and buckets are added up across all rows at once
+    if <BK as WeakSharedValue>::BITS + time_bucket_bits
+        > bucket::MAX_SINGLE_STAGE_BREAKDOWN_KEY_BITS
+    {
+        let row_buckets_stream = converted_bks_and_tvs
+            .zip(futures::stream::repeat((move_ctx, move_user_ctx)))
+            .enumerate()
+            .map(|(i, (bk_and_tv_bits, (ctx, user_ctx)))| {
+                let record_id: RecordId = RecordId::from(i);
+                let (bk_bits, tv_bits, user_bit) = split_bits(bk_and_tv_bits.unwrap());
+                async move {
+                    try_join(
+                        bucket::move_single_value_to_bucket_two_stage(
+                            ctx,
+                            record_id,
+                            bk_bits.clone(),
+                            BitDecomposed::to_additive_sharing_in_large_field_consuming(tv_bits),
+                            num_buckets,
+                            false,
+                        ),
+                        async move {
+                            match user_bit {
+                                Some(user_bit) => bucket::move_single_value_to_bucket_two_stage(
+                                    user_ctx,
+                                    record_id,
+                                    bk_bits,
+                                    user_bit,
+                                    num_buckets,
+                                    false,
+                                )
+                                .await
+                                .map(Some),
+                                None => Ok(None),
+                            }
+                        },
+                    )
+                    .await
+                }
+            });
+        let (row_buckets, user_buckets): (Vec<_>, Vec<_>) =
+            seq_join(prime_field_ctx.active_work(), row_buckets_stream)
+                .try_collect::<Vec<_>>()
+                .await?
+                .into_iter()
+                .unzip();
+
+        let values = bucket::aggregate_two_stage_buckets(
+            prime_field_ctx.narrow(&Step::AggregateTwoStageBuckets),
+            &row_buckets,
+            num_buckets,
+        )
+        .await?;
+        let users = if count_attributed_users {
+            let user_buckets = user_buckets.into_iter().flatten().collect::<Vec<_>>();
+            Some(
+                bucket::aggregate_two_stage_buckets(
+                    prime_field_ctx.narrow(&Step::AggregateAttributedUsersTwoStage),
+                    &user_buckets,
+                    num_buckets,
+                )
+                .await?,
+            )
+        } else {
+            None
+        };
+        return Ok((
+            drop_unused_buckets(values, time_buckets),
+            users.map(|users| drop_unused_buckets(users, time_buckets)),
+        ));
+    }
 
-    // move each value to the correct bucket
+    // move each value to the correct bucket. Attributed users are moved to buckets that follow
+    // the value buckets, so that both are added up together.
     let row_contributions_stream = converted_bks_and_tvs
-        .zip(futures::stream::repeat(
-            prime_field_ctx
-                .narrow(&Step::MoveValueToCorrectBreakdown)
-                .set_total_records(num_outputs),
-        ))
+        .zip(futures::stream::repeat((move_ctx, move_user_ctx)))
         .enumerate()
-        .map(|(i, (bk_and_tv_bits, ctx))| {
+        .map(|(i, (bk_and_tv_bits, (ctx, user_ctx)))| {
             let record_id: RecordId = RecordId::from(i);
-            let bk_and_tv_bits = bk_and_tv_bits.unwrap();
-            let (bk_bits, tv_bits) = bk_and_tv_bits.split_at(<BK as WeakSharedValue>::BITS);
+            let (bk_bits, tv_bits, user_bit) = split_bits(bk_and_tv_bits.unwrap());
             async move {
-                bucket::move_single_value_to_bucket(
-                    ctx,
-                    record_id,
-                    bk_bits,
-                    BitDecomposed::to_additive_sharing_in_large_field_consuming(tv_bits),
-                    1 << <BK as WeakSharedValue>::BITS,
-                    false,
+                let (mut buckets, user_buckets) = try_join(
+                    bucket::move_single_value_to_bucket(
+                        ctx,
+                        record_id,
+                        bk_bits.clone(),
+                        BitDecomposed::to_additive_sharing_in_large_field_consuming(tv_bits),
+                        num_buckets,
+                        false,
+                    ),
+                    async move {
+                        match user_bit {
+                            Some(user_bit) => {
+                                bucket::move_single_value_to_bucket(
+                                    user_ctx,
+                                    record_id,
+                                    bk_bits,
+                                    user_bit,
+                                    num_buckets,
+                                    false,
+                                )
+                                .await
+                            }
+                            None => Ok(Vec::new()),
+                        }
+                    },
                 )
-                .await
+                .await?;
+                buckets.extend(user_buckets);
+                Ok::<_, Error>(buckets)
             }
         });
 
     // aggregate all row level contributions
     let row_contributions = seq_join(prime_field_ctx.active_work(), row_contributions_stream);
-    row_contributions
-        .try_fold(
-            vec![S::ZERO; 1 << <BK as WeakSharedValue>::BITS],
-            |mut running_sums, row_contribution| async move {
-                for (i, contribution) in row_contribution.iter().enumerate() {
-                    running_sums[i] += contribution;
-                }
-                Ok(running_sums)
-            },
-        )
-        .await
+    let bucket_count = if count_attributed_users {
+        2 * num_buckets
+    } else {
+        num_buckets
+    };
+    let mut values =
+        aggregate_contributions::<_, F, _>(row_contributions, bucket_count, per_user_cap).await?;
+    let users =
+        count_attributed_users.then(|| values.split_off(usize::try_from(num_buckets).unwrap()));
+    Ok((
+        drop_unused_buckets(values, time_buckets),
+        users.map(|users| drop_unused_buckets(users, time_buckets)),
+    ))
+}
+
+/// Drops buckets that no pair of breakdown key and time bucket maps to, see
+/// [`attribute_cap_aggregate`]. Buckets are returned as they are if outputs are not keyed by time
+/// bucket.
+fn drop_unused_buckets<S>(buckets: Vec<S>, time_buckets: Option<&BucketBoundaries>) -> Vec<S> {
+    let Some(time_buckets) = time_buckets else {
+        return buckets;
+    };
+    let span = 1 << time_buckets.bucket_index_bits();
+    buckets
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| i % span < time_buckets.len())
+        .map(|(_, bucket)| bucket)
+        .collect()
+}
+
+/// Adds up row contributions in a binary tree. Contributions are combined as they arrive, the same
+/// way a binary counter is incremented: whenever two subtrees of the same depth are complete,
+/// their sums are added together to form a subtree one level deeper. Only one partial sum per
+/// depth is kept and every contribution goes through a number of additions that is logarithmic
+/// in the number of rows.
+///
+/// No row contributes more than `per_user_cap`. Every time the tree grows a level, the largest
+/// sum the new subtree may hold is compared against the field modulus. Aggregates are not
+/// guaranteed to be correct once it may wrap around, so aggregation fails instead. The number of
+/// contributions is known to all helpers, so all of them fail at the same point.
+///
+/// The result has `num_breakdowns` buckets, all zero if there are no contributions.
+///
+/// ## Errors
+/// Propagates errors from the contributions stream. Returns an error if the sum of all
+/// contributions may exceed the field modulus.
+async fn aggregate_contributions<S, F, St>(
+    contributions: St,
+    num_breakdowns: u32,
+    per_user_cap: u32,
+) -> Result<Vec<S>, Error>
+where
+    S: LinearSecretSharing<F>,
+    F: PrimeField,
+    St: Stream<Item = Result<Vec<S>, Error>>,
+{
+    fn add<S: for<'a> AddAssign<&'a S>>(mut left: Vec<S>, right: Vec<S>) -> Vec<S> {
+        for (l, r) in left.iter_mut().zip(&right) {
+            *l += r;
+        }
+        left
+    }
+
+    let check_bound = |rows: u128| {
+        if rows.saturating_mul(u128::from(per_user_cap)) >= F::PRIME.into() {
+            return Err(Error::InvalidQueryParameter(
+                format!(
+                    "sum of {rows} contributions capped at {per_user_cap} may exceed the field \
+                     modulus, aggregates would be incorrect. Use a larger field"
+                )
+                .into(),
+            ));
+        }
+        Ok(())
+    };
+
+    // `subtrees[d]` holds the sum of 2^d contributions, if there is an incomplete pair at depth d
+    let mut subtrees: Vec<Option<Vec<S>>> = Vec::new();
+    let mut count = 0_u128;
+    let mut contributions = pin!(contributions);
+    while let Some(contribution) = contributions.try_next().await? {
+        count += 1;
+        let mut carry = contribution;
+        let mut depth = 0;
+        while let Some(sibling) = subtrees.get_mut(depth).and_then(Option::take) {
+            carry = add(sibling, carry);
+            depth += 1;
+        }
+        if depth == subtrees.len() {
+            check_bound(1 << depth)?;
+            subtrees.push(None);
+        }
+        subtrees[depth] = Some(carry);
+    }
+    check_bound(count)?;
+
+    Ok(subtrees
+        .into_iter()
+        .flatten()
+        .reduce(add)
+        .unwrap_or_else(|| vec![S::ZERO; usize::try_from(num_breakdowns).unwrap()]))
 }
 
 async fn evaluate_per_user_attribution_circuit<C, BK, TV, TS, SS>(
@@ -530,6 +1183,8 @@ async fn evaluate_per_user_attribution_circuit<C, BK, TV, TS, SS>(
     record_id_for_each_depth: Vec<u32>,
     rows_for_user: Vec<PrfShardedIpaInputRow<BK, TV, TS>>,
     attribution_window_seconds: Option<NonZeroU32>,
+    per_user_cap: u32,
+    count_attributed_users: bool,
 ) -> Result<Vec<CappedAttributionOutputs<BK, TV>>, Error>
 where
     C: Context,
@@ -547,8 +1202,14 @@ where
         return Ok(Vec::new());
     }
     let first_row = &rows_for_user[0];
-    let mut prev_row_inputs =
-        initialize_new_device_attribution_variables::<BK, TV, TS, SS>(first_row);
+    let mut prev_row_inputs = initialize_new_device_attribution_variables::<BK, TV, TS, SS>(
+        first_row,
+        Replicated::<TV>::share_known_value(
+            &ctx_for_row_number[0],
+            TV::truncate_from(per_user_cap),
+        ),
+        count_attributed_users,
+    );
 
     let mut output = Vec::with_capacity(rows_for_user.len() - 1);
     for (i, row) in rows_for_user.iter().skip(1).enumerate() {
@@ -561,6 +1222,7 @@ where
                 record_id_for_this_row_depth,
                 row,
                 attribution_window_seconds,
+                per_user_cap,
             )
             .await?;
 
@@ -575,6 +1237,8 @@ where
 ///
 fn initialize_new_device_attribution_variables<BK, TV, TS, SS>(
     input_row: &PrfShardedIpaInputRow<BK, TV, TS>,
+    per_user_cap: Replicated<TV>,
+    count_attributed_users: bool,
 ) -> InputsRequiredFromPrevRow<BK, TV, TS, SS>
 where
     BK: WeakSharedValue,
@@ -587,10 +1251,11 @@ where
         attributed_breakdown_key_bits: input_row.breakdown_key.clone(),
         saturating_sum: Replicated::<SS>::ZERO,
         is_saturated: Replicated::<Boolean>::ZERO,
-        // This is incorrect in the case that the CAP is less than the maximum value of "trigger value" for a single row
-        // Not a problem if you assume that's an invalid input
-        difference_to_cap: Replicated::<TV>::ZERO,
+        difference_to_cap: per_user_cap,
         source_event_timestamp: input_row.timestamp.clone(),
+        source_event_priority: input_row.priority.clone(),
+        ever_attributed_a_trigger_event: count_attributed_users
+            .then_some(Replicated::<Boolean>::ZERO),
     }
 }
 
@@ -601,10 +1266,13 @@ where
 /// The logic here is extremely simple. For each row:
 /// (a) if it is a source event, take the current `breakdown_key`.
 /// (b) if it is a trigger event, take the `breakdown_key` from the preceding line
+///
+/// A source event that loses a timestamp tie is treated the same way as a trigger event, so
+/// `keep_prev_source_event` is set for both.
 async fn breakdown_key_of_most_recent_source_event<C, BK>(
     ctx: C,
     record_id: RecordId,
-    is_trigger_bit: &Replicated<Boolean>,
+    keep_prev_source_event: &Replicated<Boolean>,
     prev_row_breakdown_key_bits: &Replicated<BK>,
     cur_row_breakdown_key_bits: &Replicated<BK>,
 ) -> Result<Replicated<BK>, Error>
@@ -612,25 +1280,26 @@ where
     C: Context,
     BK: WeakSharedValue + CustomArray<Element = Boolean> + Field,
 {
-    let is_trigger_bit_array = Replicated::<BK>::expand(is_trigger_bit);
+    let keep_prev_source_event_array = Replicated::<BK>::expand(keep_prev_source_event);
 
     if_else(
         ctx,
         record_id,
-        &is_trigger_bit_array,
+        &keep_prev_source_event_array,
         prev_row_breakdown_key_bits,
         cur_row_breakdown_key_bits,
     )
     .await
 }
 
-/// Same as above but for timestamps. If `attribution_window_seconds` is `None`, just
-/// return the previous row's timestamp. The bits aren't used but saves some multiplications.
+/// Same as above but for timestamps. Timestamps are needed only to check the attribution window
+/// and to break ties between source events. If neither is required, just return the previous
+/// row's timestamp. The bits aren't used but saves some multiplications.
 async fn timestamp_of_most_recent_source_event<C, TS>(
     ctx: C,
     record_id: RecordId,
-    attribution_window_seconds: Option<NonZeroU32>,
-    is_trigger_bit: &Replicated<Boolean>,
+    is_timestamp_required: bool,
+    keep_prev_source_event: &Replicated<Boolean>,
     prev_row_timestamp_bits: &Replicated<TS>,
     cur_row_timestamp_bits: &Replicated<TS>,
 ) -> Result<Replicated<TS>, Error>
@@ -638,23 +1307,119 @@ where
     C: Context,
     TS: WeakSharedValue + CustomArray<Element = Boolean> + Field,
 {
-    match attribution_window_seconds {
-        None => Ok(prev_row_timestamp_bits.clone()),
-        Some(_) => {
-            let is_trigger_bit_array = Replicated::<TS>::expand(is_trigger_bit);
+    if is_timestamp_required {
+        let keep_prev_source_event_array = Replicated::<TS>::expand(keep_prev_source_event);
 
-            if_else(
-                ctx,
-                record_id,
-                &is_trigger_bit_array,
-                prev_row_timestamp_bits,
-                cur_row_timestamp_bits,
-            )
-            .await
+        if_else(
+            ctx,
+            record_id,
+            &keep_prev_source_event_array,
+            prev_row_timestamp_bits,
+            cur_row_timestamp_bits,
+        )
+        .await
+    } else {
+        Ok(prev_row_timestamp_bits.clone())
+    }
+}
+
+/// Same as above but for priorities. Priorities are tracked only if input rows carry them, the
+/// context is narrowed only in that case.
+async fn priority_of_most_recent_source_event<C>(
+    ctx: C,
+    record_id: RecordId,
+    keep_prev_source_event: &Replicated<Boolean>,
+    prev_row_priority_bits: Option<&Replicated<SourcePriority>>,
+    cur_row_priority_bits: Option<&Replicated<SourcePriority>>,
+) -> Result<Option<Replicated<SourcePriority>>, Error>
+where
+    C: Context,
+{
+    match (prev_row_priority_bits, cur_row_priority_bits) {
+        (Some(prev), Some(cur)) => {
+            let keep_prev_source_event_array =
+                Replicated::<SourcePriority>::expand(keep_prev_source_event);
+
+            Ok(Some(
+                if_else(
+                    ctx.narrow(&Step::SourceEventPriority),
+                    record_id,
+                    &keep_prev_source_event_array,
+                    prev,
+                    cur,
+                )
+                .await?,
+            ))
         }
+        _ => Ok(None),
     }
 }
 
+///
+/// Rows of each user are sorted by timestamp, so a source event normally replaces the most recent
+/// source event seen so far. If the current row is a source event with the same timestamp as
+/// that one, the event with the higher priority is kept. Equal priorities go to the current row,
+/// which is what happens without priorities.
+///
+/// Returns a bit indicating that the most recent source event stays the same: the current row is
+/// either a trigger event or a source event that lost the tie. These conditions cannot both be
+/// true, so no multiplication is needed to combine them.
+///
+async fn keep_prev_source_event_on_tie<C, TS>(
+    ctx: C,
+    record_id: RecordId,
+    is_trigger_bit: &Replicated<Boolean>,
+    ever_encountered_a_source_event: &Replicated<Boolean>,
+    (prev_timestamp, cur_timestamp): (&Replicated<TS>, &Replicated<TS>),
+    (prev_priority, cur_priority): (&Replicated<SourcePriority>, &Replicated<SourcePriority>),
+) -> Result<Replicated<Boolean>, Error>
+where
+    C: Context,
+    TS: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    for<'a> &'a Replicated<TS>: IntoIterator<Item = Replicated<Boolean>>,
+{
+    let (is_timestamp_greater, is_prev_priority_greater, is_source_after_another_source) =
+        try_join3(
+            compare_gt(
+                ctx.narrow(&Step::IsSourceEventTimestampGreater),
+                record_id,
+                cur_timestamp,
+                prev_timestamp,
+            ),
+            compare_gt(
+                ctx.narrow(&Step::IsPrevSourceEventPriorityGreater),
+                record_id,
+                prev_priority,
+                cur_priority,
+            ),
+            is_trigger_bit.clone().not().multiply(
+                ever_encountered_a_source_event,
+                ctx.narrow(&Step::IsSourceEventAfterAnotherSourceEvent),
+                record_id,
+            ),
+        )
+        .await?;
+
+    // Timestamps never decrease, so if the current one is not greater, they are equal.
+    let is_tied_with_lower_priority = is_timestamp_greater
+        .not()
+        .multiply(
+            &is_prev_priority_greater,
+            ctx.narrow(&Step::IsSourceEventTimestampTiedWithLowerPriority),
+            record_id,
+        )
+        .await?;
+    let did_lose_tie = is_source_after_another_source
+        .multiply(
+            &is_tied_with_lower_priority,
+            ctx.narrow(&Step::DidSourceEventLoseTie),
+            record_id,
+        )
+        .await?;
+
+    Ok(is_trigger_bit + &did_lose_tie)
+}
+
 ///
 /// In this simple "Last Touch Attribution" model, the `trigger_value` of a trigger event is either
 /// (a) Attributed to a single `breakdown_key`
@@ -664,6 +1429,9 @@ where
 /// another secret-shared bit indicating if a given row is within the attribution window. We multiply these two bits together and
 /// multiply it with the bits of the `trigger_value` in order to zero out contributions from unattributed trigger events.
 ///
+/// Returns the `trigger_value` of attributed trigger events and zero otherwise, along with the bit indicating if this row
+/// is an attributed trigger event.
+///
 #[allow(clippy::too_many_arguments)]
 async fn zero_out_trigger_value_unless_attributed<C, TV, TS>(
     ctx: C,
@@ -674,7 +1442,7 @@ async fn zero_out_trigger_value_unless_attributed<C, TV, TS>(
     attribution_window_seconds: Option<NonZeroU32>,
     trigger_event_timestamp: &Replicated<TS>,
     source_event_timestamp: &Replicated<TS>,
-) -> Result<Replicated<TV>, Error>
+) -> Result<(Replicated<TV>, Replicated<Boolean>), Error>
 where
     C: Context,
     TV: WeakSharedValue + CustomArray<Element = Boolean> + Field,
@@ -710,14 +1478,44 @@ where
 
     let zero_out_flag_array = Replicated::<TV>::expand(&zero_out_flag);
 
-    if_else(
+    let attributed_trigger_value = if_else(
         ctx,
         record_id,
         &zero_out_flag_array,
         trigger_value,
         &Replicated::<TV>::ZERO,
     )
-    .await
+    .await?;
+
+    Ok((attributed_trigger_value, zero_out_flag))
+}
+
+///
+/// To count every user with at least one attributed conversion once, only the first attributed trigger event of
+/// each user is marked. It is the one that is attributed while no previous row of the same user was.
+///
+/// Returns `None` if attributed users are not counted.
+///
+async fn is_first_attributed_trigger_event<C>(
+    ctx: C,
+    record_id: RecordId,
+    is_attributed: &Replicated<Boolean>,
+    ever_attributed_a_trigger_event: Option<&Replicated<Boolean>>,
+) -> Result<Option<Replicated<Boolean>>, Error>
+where
+    C: Context,
+{
+    let Some(ever_attributed_a_trigger_event) = ever_attributed_a_trigger_event else {
+        return Ok(None);
+    };
+    is_attributed
+        .multiply(
+            &ever_attributed_a_trigger_event.clone().not(),
+            ctx.narrow(&Step::IsFirstAttributedTriggerEvent),
+            record_id,
+        )
+        .await
+        .map(Some)
 }
 
 /// If the `attribution_window_seconds` is not `None`, we calculate the time
@@ -746,6 +1544,13 @@ where
         )
         .await?;
 
+        // Query config validation makes sure the window fits into `TS`, truncating it would
+        // silently shrink the window.
+        assert!(
+            u128::from(attribution_window_seconds.get()) < 1 << <TS as WeakSharedValue>::BITS,
+            "attribution window {attribution_window_seconds} does not fit into {} bits",
+            <TS as WeakSharedValue>::BITS
+        );
         let constant_bits = TS::truncate_from(attribution_window_seconds.get());
 
         let time_delta_gt_attribution_window = compare_gt(
@@ -762,12 +1567,95 @@ where
     }
 }
 
+///
+/// Clamps the trigger value to the per-user cap, if trigger values are wider than the
+/// saturating sum. Bits of the trigger value that the sum does not have are dropped when it is
+/// added to the sum, so a trigger value that does not fit into `SS` would contribute only its
+/// lowest `|SS|` bits, or nothing at all, instead of saturating the sum.
+///
+/// Clamped trigger values do not exceed `2^|SS|`. Clamping takes `2|TV|` multiplications, so
+/// trigger values that are not wider than the sum are returned as they are. Capping already
+/// lowers the ones that exceed the cap.
+///
+async fn clamp_trigger_value<C, TV, SS>(
+    ctx: C,
+    record_id: RecordId,
+    trigger_value: &Replicated<TV>,
+    per_user_cap: u32,
+) -> Result<Replicated<TV>, Error>
+where
+    C: Context,
+    TV: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    SS: WeakSharedValue,
+    for<'a> &'a Replicated<TV>: IntoIterator<Item = Replicated<Boolean>>,
+{
+    if <TV as WeakSharedValue>::BITS <= <SS as WeakSharedValue>::BITS {
+        return Ok(trigger_value.clone());
+    }
+
+    let cap = Replicated::<TV>::share_known_value(&ctx, TV::truncate_from(per_user_cap));
+    let exceeds_cap = compare_gt(
+        ctx.narrow(&Step::CompareTriggerValueToCap),
+        record_id,
+        trigger_value,
+        &cap,
+    )
+    .await?;
+
+    if_else(
+        ctx.narrow(&Step::ClampTriggerValue),
+        record_id,
+        &Replicated::<TV>::expand(&exceeds_cap),
+        &cap,
+        trigger_value,
+    )
+    .await
+}
+
+///
+/// Determines if the cumulative sum of "Attributed trigger value" has reached the per-user cap.
+/// The sum is only `|SS|` bits wide, so the overflow bit of the addition must be taken into account as well.
+/// When the cap is exactly `2^|SS|`, the overflow bit alone is the answer and no communication is required.
+///
+async fn sum_reached_cap<C, SS>(
+    ctx: C,
+    record_id: RecordId,
+    sum: &Replicated<SS>,
+    overflow_bit: Replicated<Boolean>,
+    per_user_cap: u32,
+) -> Result<Replicated<Boolean>, Error>
+where
+    C: Context,
+    SS: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    for<'a> &'a Replicated<SS>: IntoIterator<Item = Replicated<Boolean>>,
+{
+    if u128::from(per_user_cap) == 1 << <SS as WeakSharedValue>::BITS {
+        return Ok(overflow_bit);
+    }
+
+    let sum_geq_cap = compare_geq(
+        ctx.narrow(&Step::CompareSaturatingSumToCap),
+        record_id,
+        sum,
+        &Replicated::<SS>::share_known_value(&ctx, SS::truncate_from(per_user_cap)),
+    )
+    .await?;
+
+    or(
+        ctx.narrow(&Step::DidSaturatingSumReachCap),
+        record_id,
+        &overflow_bit,
+        &sum_geq_cap,
+    )
+    .await
+}
+
 ///
 /// To provide a differential privacy guarantee, we need to bound the maximum contribution from any given user to some cap.
 ///
 /// The following values are computed for each row:
 /// (1) The uncapped "Attributed trigger value" (which is either the original `trigger_value` bits or zero if it was unattributed)
-/// (2) The cumulative sum of "Attributed trigger value" thus far (which "saturates" at a given power of two as indicated by the `is_saturated` flag)
+/// (2) The cumulative sum of "Attributed trigger value" thus far (which "saturates" at the per-user cap as indicated by the `is_saturated` flag)
 /// (3) The "delta to cap", which is the difference between the "cap" and the cumulative sum (this value is meaningless once the cumulative sum is saturated)
 ///
 /// To perfectly cap each user's contributions at precisely the cap, the "attributed trigger value" will sometimes need to be lowered,
@@ -822,25 +1710,36 @@ where
 
 #[cfg(all(test, unit_test))]
 pub mod tests {
-    use std::num::NonZeroU32;
+    use std::{cmp::Reverse, num::NonZeroU32};
 
-    use super::{CappedAttributionOutputs, PrfShardedIpaInputRow};
+    use rand::seq::SliceRandom;
+
+    use futures::stream::iter as stream_iter;
+
+    use super::{
+        aggregate_contributions, prepare_user_rows, sort_user_chunks, CappedAttributionOutputs,
+        PrfShardedIpaInputRow, SourcePriority, UserRows,
+    };
     use crate::{
+        error::Error,
         ff::{
             boolean::Boolean,
-            boolean_array::{BA20, BA3, BA5, BA8},
-            CustomArray, Field, Fp32BitPrime,
+            boolean_array::{BA20, BA3, BA4, BA5, BA8},
+            CustomArray, Field, Fp31, Fp32BitPrime,
         },
-        protocol::ipa_prf::prf_sharding::attribute_cap_aggregate,
-        rand::Rng,
+        protocol::ipa_prf::prf_sharding::{
+            attribute_cap_aggregate, attribute_cap_aggregate_with_attributed_users,
+        },
+        rand::{thread_rng, Rng},
         secret_sharing::{
-            replicated::semi_honest::AdditiveShare as Replicated, IntoShares, WeakSharedValue,
+            replicated::{semi_honest::AdditiveShare as Replicated, ReplicatedSecretSharing},
+            IntoShares, SecretSharing, WeakSharedValue,
         },
         test_executor::run,
         test_fixture::{Reconstruct, Runner, TestWorld},
     };
 
-    struct PreShardedAndSortedOPRFTestInput<
+    pub(super) struct PreShardedAndSortedOPRFTestInput<
         BK: WeakSharedValue,
         TV: WeakSharedValue,
         TS: WeakSharedValue,
@@ -850,9 +1749,10 @@ pub mod tests {
         breakdown_key: BK,
         trigger_value: TV,
         timestamp: TS,
+        priority: Option<SourcePriority>,
     }
 
-    fn oprf_test_input<BK>(
+    pub(super) fn oprf_test_input<BK>(
         prf_of_match_key: u64,
         is_trigger: bool,
         breakdown_key: u8,
@@ -892,6 +1792,30 @@ pub mod tests {
             breakdown_key: BK::truncate_from(breakdown_key),
             trigger_value: BA3::truncate_from(trigger_value),
             timestamp: BA20::truncate_from(timestamp),
+            priority: None,
+        }
+    }
+
+    fn oprf_test_input_with_priority<BK>(
+        prf_of_match_key: u64,
+        is_trigger: bool,
+        breakdown_key: u8,
+        trigger_value: u8,
+        timestamp: u32,
+        priority: u8,
+    ) -> PreShardedAndSortedOPRFTestInput<BK, BA3, BA20>
+    where
+        BK: WeakSharedValue + Field,
+    {
+        PreShardedAndSortedOPRFTestInput {
+            priority: Some(SourcePriority::truncate_from(priority)),
+            ..oprf_test_input_with_timestamp(
+                prf_of_match_key,
+                is_trigger,
+                breakdown_key,
+                trigger_value,
+                timestamp,
+            )
         }
     }
 
@@ -915,6 +1839,7 @@ pub mod tests {
                 breakdown_key,
                 trigger_value,
                 timestamp,
+                priority,
             } = self;
 
             let [is_trigger_bit0, is_trigger_bit1, is_trigger_bit2] =
@@ -922,6 +1847,7 @@ pub mod tests {
             let [breakdown_key0, breakdown_key1, breakdown_key2] = breakdown_key.share_with(rng);
             let [trigger_value0, trigger_value1, trigger_value2] = trigger_value.share_with(rng);
             let [timestamp0, timestamp1, timestamp2] = timestamp.share_with(rng);
+            let [priority0, priority1, priority2] = priority.share_with(rng);
 
             [
                 PrfShardedIpaInputRow {
@@ -930,6 +1856,7 @@ pub mod tests {
                     breakdown_key: breakdown_key0,
                     trigger_value: trigger_value0,
                     timestamp: timestamp0,
+                    priority: priority0,
                 },
                 PrfShardedIpaInputRow {
                     prf_of_match_key,
@@ -937,6 +1864,7 @@ pub mod tests {
                     breakdown_key: breakdown_key1,
                     trigger_value: trigger_value1,
                     timestamp: timestamp1,
+                    priority: priority1,
                 },
                 PrfShardedIpaInputRow {
                     prf_of_match_key,
@@ -944,6 +1872,7 @@ pub mod tests {
                     breakdown_key: breakdown_key2,
                     trigger_value: trigger_value2,
                     timestamp: timestamp2,
+                    priority: priority2,
                 },
             ]
         }
@@ -977,6 +1906,306 @@ pub mod tests {
         }
     }
 
+    #[test]
+    fn tree_aggregation() {
+        run(|| async move {
+            let mut rng = thread_rng();
+            for rows in 0..20 {
+                let contributions = (0..rows)
+                    .map(|_| {
+                        (0..3)
+                            .map(|_| Replicated::new(rng.gen::<Fp32BitPrime>(), rng.gen()))
+                            .collect::<Vec<_>>()
+                    })
+                    .collect::<Vec<_>>();
+                let expected = contributions.iter().fold(
+                    vec![Replicated::<Fp32BitPrime>::ZERO; 3],
+                    |mut acc, row| {
+                        for (a, v) in acc.iter_mut().zip(row) {
+                            *a += v;
+                        }
+                        acc
+                    },
+                );
+
+                let actual = aggregate_contributions::<_, Fp32BitPrime, _>(
+                    stream_iter(contributions.into_iter().map(Ok)),
+                    3,
+                    1,
+                )
+                .await
+                .unwrap();
+                assert_eq!(expected, actual, "{rows} rows");
+            }
+        });
+    }
+
+    #[test]
+    fn tree_aggregation_overflow() {
+        run(|| async move {
+            // 4 contributions capped at 7 fit into `Fp31`, 5 of them may not
+            for (rows, fits) in [(4, true), (5, false), (8, false)] {
+                let contributions = (0..rows)
+                    .map(|_| vec![Replicated::<Fp31>::ZERO; 2])
+                    .collect::<Vec<_>>();
+                let result = aggregate_contributions::<_, Fp31, _>(
+                    stream_iter(contributions.into_iter().map(Ok)),
+                    2,
+                    7,
+                )
+                .await;
+                assert_eq!(
+                    fits,
+                    !matches!(result, Err(Error::InvalidQueryParameter(_))),
+                    "{rows} rows"
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn user_chunk_order() {
+        // (number of rows, PRF value) of each chunk
+        let chunks = [(1, 5), (2, 9), (2, 3), (1, 1), (3, 7)];
+        let expected = vec![(3, 7), (2, 3), (2, 9), (1, 1), (1, 5)];
+
+        let mut rng = thread_rng();
+        for _ in 0..10 {
+            let mut shuffled = chunks;
+            shuffled.shuffle(&mut rng);
+            let mut user_chunks = shuffled
+                .iter()
+                .map(|&(len, prf)| {
+                    let [rows, _, _]: [Vec<PrfShardedIpaInputRow<BA8, BA3, BA20>>; 3] = (0..len)
+                        .map(|_| oprf_test_input::<BA8>(prf, false, 0, 0))
+                        .share();
+                    rows
+                })
+                .collect::<Vec<_>>();
+
+            sort_user_chunks(&mut user_chunks);
+
+            assert_eq!(
+                expected,
+                user_chunks
+                    .iter()
+                    .map(|rows| (rows.len(), rows[0].prf_of_match_key))
+                    .collect::<Vec<_>>()
+            );
+        }
+    }
+
+    /// Orders of input rows that stress grouping rows by user. Rows are only described by their
+    /// PRF values, which is all that grouping looks at.
+    #[derive(Clone, Copy, Debug)]
+    enum RowOrdering {
+        /// Rows of `users` users with `rows` rows each, one row of every user at a time. Rows
+        /// of a user are not adjacent, which violates what grouping expects, so every row ends
+        /// up in a run of its own.
+        Interleaved { users: u64, rows: usize },
+        /// `users` users with a single row each.
+        Singletons { users: u64 },
+        /// A user with `rows` rows in the middle of `others` users with one or two rows.
+        OneMassiveUser { rows: usize, others: u64 },
+        /// `users` users, user `i` has `i + 1` rows.
+        AscendingLengths { users: u64 },
+        /// `users` users, user `i` has `users - i` rows.
+        DescendingLengths { users: u64 },
+    }
+
+    impl RowOrdering {
+        const ALL: [Self; 7] = [
+            Self::Interleaved { users: 5, rows: 3 },
+            Self::Singletons { users: 1 },
+            Self::Singletons { users: 20 },
+            Self::OneMassiveUser {
+                rows: 200,
+                others: 10,
+            },
+            Self::AscendingLengths { users: 12 },
+            Self::DescendingLengths { users: 12 },
+            Self::DescendingLengths { users: 1 },
+        ];
+
+        /// Returns the PRF value of every input row, in order.
+        fn prfs(self) -> Vec<u64> {
+            let user_rows = |user: u64, rows: usize| std::iter::repeat(user).take(rows);
+            match self {
+                Self::Interleaved { users, rows } => (0..rows).flat_map(|_| 0..users).collect(),
+                Self::Singletons { users } => (0..users).collect(),
+                Self::OneMassiveUser { rows, others } => (0..others / 2)
+                    .flat_map(|user| user_rows(user, 1 + usize::from(user % 2 == 1)))
+                    .chain(user_rows(others, rows))
+                    .chain((others / 2..others).flat_map(|user| user_rows(user, 1)))
+                    .collect(),
+                Self::AscendingLengths { users } => (0..users)
+                    .flat_map(|user| user_rows(user, usize::try_from(user).unwrap() + 1))
+                    .collect(),
+                Self::DescendingLengths { users } => (0..users)
+                    .flat_map(|user| user_rows(user, usize::try_from(users - user).unwrap()))
+                    .collect(),
+            }
+        }
+
+        fn rows(self) -> Vec<PrfShardedIpaInputRow<BA8, BA3, BA20>> {
+            let [rows, _, _] = self
+                .prfs()
+                .into_iter()
+                .map(|prf| oprf_test_input::<BA8>(prf, false, 0, 0))
+                .share();
+            rows
+        }
+    }
+
+    /// Returns maximal runs of rows with the same PRF value, as `(PRF value, number of rows)`.
+    fn runs(prfs: &[u64]) -> Vec<(u64, usize)> {
+        let mut runs: Vec<(u64, usize)> = Vec::new();
+        for &prf in prfs {
+            match runs.last_mut() {
+                Some((last, len)) if *last == prf => *len += 1,
+                _ => runs.push((prf, 1)),
+            }
+        }
+        runs
+    }
+
+    /// Chunks are the runs of rows with the same PRF value, except for runs of a single row, which
+    /// produce no output. They are processed longest first, and rows at every depth get record
+    /// ids `0..n`, where `n` is the number of users that have a row at that depth. Contexts of
+    /// every depth expect exactly that many records.
+    fn check_user_rows(
+        ordering: RowOrdering,
+        user_rows: &UserRows<PrfShardedIpaInputRow<BA8, BA3, BA20>>,
+    ) {
+        let UserRows {
+            chunks,
+            histogram,
+            num_outputs,
+        } = user_rows;
+        let mut expected = runs(&ordering.prfs())
+            .into_iter()
+            .filter(|&(_, len)| len > 1)
+            .collect::<Vec<_>>();
+        expected.sort_unstable_by_key(|&(prf, len)| (Reverse(len), prf));
+        for chunk in chunks {
+            assert!(
+                chunk
+                    .iter()
+                    .all(|row| row.prf_of_match_key == chunk[0].prf_of_match_key),
+                "{ordering:?}: chunk mixes users"
+            );
+        }
+        assert_eq!(
+            expected,
+            chunks
+                .iter()
+                .map(|rows| (rows[0].prf_of_match_key, rows.len()))
+                .collect::<Vec<_>>(),
+            "{ordering:?}"
+        );
+
+        let max_rows = expected.first().map_or(0, |&(_, len)| len);
+        let expected_histogram = (0..max_rows)
+            .map(|depth| expected.iter().filter(|&&(_, len)| len > depth).count())
+            .collect::<Vec<_>>();
+        assert_eq!(expected_histogram, *histogram, "{ordering:?}");
+        assert_eq!(
+            expected.iter().map(|&(_, len)| len - 1).sum::<usize>(),
+            *num_outputs,
+            "{ordering:?}"
+        );
+
+        let mut record_ids = vec![Vec::new(); histogram.len()];
+        let mut next_record_id = vec![0_usize; histogram.len()];
+        for chunk in chunks {
+            for (depth, next) in next_record_id[..chunk.len()].iter_mut().enumerate() {
+                record_ids[depth].push(*next);
+                *next += 1;
+            }
+        }
+        for (depth, ids) in record_ids.iter().enumerate().skip(1) {
+            assert_eq!(
+                (0..histogram[depth]).collect::<Vec<_>>(),
+                *ids,
+                "{ordering:?}: record ids at depth {depth}"
+            );
+        }
+    }
+
+    #[test]
+    fn adversarial_row_orderings() {
+        for ordering in RowOrdering::ALL {
+            check_user_rows(ordering, &prepare_user_rows(ordering.rows()));
+        }
+    }
+
+    /// Inputs without a single user that has more than one row still produce every breakdown.
+    #[test]
+    fn no_attributable_users() {
+        run(|| async move {
+            let world = TestWorld::default();
+            let single_row_users: Vec<PreShardedAndSortedOPRFTestInput<BA5, BA3, BA20>> = vec![
+                oprf_test_input(123, false, 17, 0),
+                oprf_test_input(234, true, 0, 5),
+                oprf_test_input(345, false, 20, 0),
+            ];
+            for records in [Vec::new(), single_row_users] {
+                let result: Vec<Fp32BitPrime> = world
+                    .semi_honest(records.into_iter(), |ctx, input_rows| async move {
+                        let (values, users) =
+                            attribute_cap_aggregate_with_attributed_users::<
+                                _,
+                                BA5,
+                                BA3,
+                                BA20,
+                                BA5,
+                                Replicated<Fp32BitPrime>,
+                                Fp32BitPrime,
+                            >(ctx, input_rows, None, 7, 32, None, None)
+                            .await
+                            .unwrap();
+                        [values, users].concat()
+                    })
+                    .await
+                    .reconstruct();
+                assert_eq!(result, [0_u128; 64]);
+            }
+        });
+    }
+
+    /// Caps come from the query config, those that don't fit into the saturating sum fail the
+    /// query instead of the helper.
+    #[test]
+    fn invalid_per_user_cap() {
+        run(|| async move {
+            let world = TestWorld::default();
+            for per_user_cap in [0, 33] {
+                let records: Vec<PreShardedAndSortedOPRFTestInput<BA5, BA3, BA20>> = vec![
+                    oprf_test_input(123, false, 17, 0),
+                    oprf_test_input(123, true, 0, 5),
+                ];
+                let rejected = world
+                    .semi_honest(records.into_iter(), |ctx, input_rows| async move {
+                        let result = attribute_cap_aggregate::<
+                            _,
+                            BA5,
+                            BA3,
+                            BA20,
+                            BA5,
+                            Replicated<Fp32BitPrime>,
+                            Fp32BitPrime,
+                        >(
+                            ctx, input_rows, None, per_user_cap, 32, None, None
+                        )
+                        .await;
+                        matches!(result, Err(Error::InvalidQueryParameter(_)))
+                    })
+                    .await;
+                assert_eq!([true; 3], rejected, "per-user cap {per_user_cap}");
+            }
+        });
+    }
+
     #[test]
     fn semi_honest_aggregation_capping_attribution() {
         run(|| async move {
@@ -1007,7 +2236,50 @@ pub mod tests {
             expected[17] = 7;
             expected[20] = 10;
 
-            let histogram = [3, 3, 2, 2, 1, 1, 1, 1];
+            let result: Vec<_> = world
+                .semi_honest(records.into_iter(), |ctx, input_rows| async move {
+                    attribute_cap_aggregate::<
+                        _,
+                        BA5,
+                        BA3,
+                        BA20,
+                        BA5,
+                        Replicated<Fp32BitPrime>,
+                        Fp32BitPrime,
+                    >(ctx, input_rows, None, 32, 32, None, None)
+                    .await
+                    .unwrap()
+                })
+                .await
+                .reconstruct();
+            assert_eq!(result, &expected);
+        });
+    }
+
+    #[test]
+    fn non_power_of_two_breakdowns() {
+        const NUM_BREAKDOWNS: u32 = 21;
+
+        run(|| async move {
+            let world = TestWorld::default();
+
+            let records: Vec<PreShardedAndSortedOPRFTestInput<BA5, BA3, BA20>> = vec![
+                /* First User */
+                oprf_test_input(123, false, 17, 0),
+                oprf_test_input(123, true, 0, 7),
+                /* Second User */
+                oprf_test_input(234, false, 20, 0),
+                oprf_test_input(234, true, 0, 5),
+                oprf_test_input(234, true, 0, 3),
+                /* Third User */
+                oprf_test_input(345, false, 0, 0),
+                oprf_test_input(345, true, 0, 6),
+            ];
+
+            let mut expected = [0_u128; NUM_BREAKDOWNS as usize];
+            expected[0] = 6;
+            expected[17] = 7;
+            expected[20] = 8;
 
             let result: Vec<_> = world
                 .semi_honest(records.into_iter(), |ctx, input_rows| async move {
@@ -1019,7 +2291,7 @@ pub mod tests {
                         BA5,
                         Replicated<Fp32BitPrime>,
                         Fp32BitPrime,
-                    >(ctx, input_rows, None, &histogram)
+                    >(ctx, input_rows, None, 32, NUM_BREAKDOWNS, None, None)
                     .await
                     .unwrap()
                 })
@@ -1029,6 +2301,61 @@ pub mod tests {
         });
     }
 
+    #[test]
+    fn attributed_user_counts() {
+        run(|| async move {
+            let world = TestWorld::default();
+
+            let records: Vec<PreShardedAndSortedOPRFTestInput<BA5, BA3, BA20>> = vec![
+                /* First User: counted once, towards the first attributed breakdown */
+                oprf_test_input(123, false, 17, 0),
+                oprf_test_input(123, true, 0, 7),
+                oprf_test_input(123, false, 20, 0),
+                oprf_test_input(123, true, 0, 3),
+                /* Second User */
+                oprf_test_input(234, false, 12, 0),
+                oprf_test_input(234, true, 0, 5),
+                /* Third User: attributed conversions with zero value count too */
+                oprf_test_input(345, false, 3, 0),
+                oprf_test_input(345, true, 0, 0),
+                /* Fourth User: no source events, nothing is attributed */
+                oprf_test_input(456, true, 0, 2),
+                oprf_test_input(456, true, 0, 4),
+            ];
+
+            let mut expected_values = [0_u128; 32];
+            expected_values[12] = 5;
+            expected_values[17] = 7;
+            expected_values[20] = 3;
+            let mut expected_users = [0_u128; 32];
+            expected_users[3] = 1;
+            expected_users[12] = 1;
+            expected_users[17] = 1;
+
+            let result: Vec<_> = world
+                .semi_honest(records.into_iter(), |ctx, input_rows| async move {
+                    let (values, users) =
+                        attribute_cap_aggregate_with_attributed_users::<
+                            _,
+                            BA5,
+                            BA3,
+                            BA20,
+                            BA5,
+                            Replicated<Fp32BitPrime>,
+                            Fp32BitPrime,
+                        >(ctx, input_rows, None, 32, 32, None, None)
+                        .await
+                        .unwrap();
+                    [values, users].concat()
+                })
+                .await
+                .reconstruct();
+            let (values, users) = result.split_at(32);
+            assert_eq!(values, &expected_values);
+            assert_eq!(users, &expected_users);
+        });
+    }
+
     #[test]
     fn semi_honest_aggregation_capping_attribution_with_attribution_window() {
         const ATTRIBUTION_WINDOW_SECONDS: u32 = 200;
@@ -1061,8 +2388,6 @@ pub mod tests {
             expected[17] = 7;
             expected[20] = 6;
 
-            let histogram = [3, 3, 2, 2, 1, 1, 1, 1];
-
             let result: Vec<_> = world
                 .semi_honest(records.into_iter(), |ctx, input_rows| async move {
                     attribute_cap_aggregate::<
@@ -1077,7 +2402,10 @@ pub mod tests {
                         ctx,
                         input_rows,
                         NonZeroU32::new(ATTRIBUTION_WINDOW_SECONDS),
-                        &histogram,
+                        32,
+                        32,
+                        None,
+                        None,
                     )
                     .await
                     .unwrap()
@@ -1090,8 +2418,6 @@ pub mod tests {
 
     #[test]
     fn capping_bugfix() {
-        const HISTOGRAM: [usize; 10] = [5, 5, 5, 5, 5, 5, 5, 2, 1, 1];
-
         run(|| async move {
             let world = TestWorld::default();
 
@@ -1166,7 +2492,236 @@ pub mod tests {
                         SaturatingSumType,
                         Replicated<Fp32BitPrime>,
                         Fp32BitPrime,
-                    >(ctx, input_rows, None, &HISTOGRAM)
+                    >(
+                        ctx,
+                        input_rows,
+                        None,
+                        1 << SaturatingSumType::BITS,
+                        256,
+                        None,
+                        None,
+                    )
+                    .await
+                    .unwrap()
+                })
+                .await
+                .reconstruct();
+            assert_eq!(result, &expected);
+        });
+    }
+
+    #[test]
+    fn non_power_of_two_cap() {
+        const PER_USER_CAP: u32 = 10;
+
+        run(|| async move {
+            let world = TestWorld::default();
+
+            let records: Vec<PreShardedAndSortedOPRFTestInput<BA5, BA3, BA20>> = vec![
+                /* First User (saturates, then one extra) */
+                oprf_test_input(1, false, 3, 0),
+                oprf_test_input(1, true, 0, 4), // running-sum = 4
+                oprf_test_input(1, true, 0, 4), // running-sum = 8
+                oprf_test_input(1, true, 0, 4), // running-sum = 12, capped to 2
+                /* Second User (perfectly saturates, then one extra) */
+                oprf_test_input(2, false, 7, 0),
+                oprf_test_input(2, true, 0, 7), // running-sum = 7
+                oprf_test_input(2, true, 0, 3), // running-sum = 10
+                oprf_test_input(2, true, 0, 5), // running-sum = 15, zeroed out
+                /* Third User (does not saturate) */
+                oprf_test_input(3, false, 9, 0),
+                oprf_test_input(3, true, 0, 6), // running-sum = 6
+                oprf_test_input(3, true, 0, 1), // running-sum = 7
+                /* Fourth User (running sum overflows 4 bits when it saturates) */
+                oprf_test_input(4, false, 11, 0),
+                oprf_test_input(4, true, 0, 5), // running-sum = 5
+                oprf_test_input(4, true, 0, 4), // running-sum = 9
+                oprf_test_input(4, true, 0, 7), // running-sum = 16, capped to 1
+            ];
+
+            let mut expected = [0_u128; 32];
+            expected[3] = 10;
+            expected[7] = 10;
+            expected[9] = 7;
+            expected[11] = 10;
+
+            let result: Vec<_> = world
+                .semi_honest(records.into_iter(), |ctx, input_rows| async move {
+                    attribute_cap_aggregate::<
+                        _,
+                        BA5,
+                        BA3,
+                        BA20,
+                        BA4,
+                        Replicated<Fp32BitPrime>,
+                        Fp32BitPrime,
+                    >(ctx, input_rows, None, PER_USER_CAP, 32, None, None)
+                    .await
+                    .unwrap()
+                })
+                .await
+                .reconstruct();
+            assert_eq!(result, &expected);
+        });
+    }
+
+    #[test]
+    fn cap_below_trigger_value() {
+        const PER_USER_CAP: u32 = 5;
+
+        run(|| async move {
+            let world = TestWorld::default();
+
+            let records: Vec<PreShardedAndSortedOPRFTestInput<BA5, BA3, BA20>> = vec![
+                /* First User (the very first trigger value exceeds the cap) */
+                oprf_test_input(1, false, 1, 0),
+                oprf_test_input(1, true, 0, 7), // running-sum = 7, capped to 5
+                oprf_test_input(1, true, 0, 2), // running-sum = 9, zeroed out
+                /* Second User */
+                oprf_test_input(2, false, 2, 0),
+                oprf_test_input(2, true, 0, 3), // running-sum = 3
+                oprf_test_input(2, true, 0, 3), // running-sum = 6, capped to 2
+            ];
+
+            let mut expected = [0_u128; 32];
+            expected[1] = 5;
+            expected[2] = 5;
+
+            let result: Vec<_> = world
+                .semi_honest(records.into_iter(), |ctx, input_rows| async move {
+                    attribute_cap_aggregate::<
+                        _,
+                        BA5,
+                        BA3,
+                        BA20,
+                        BA3,
+                        Replicated<Fp32BitPrime>,
+                        Fp32BitPrime,
+                    >(ctx, input_rows, None, PER_USER_CAP, 32, None, None)
+                    .await
+                    .unwrap()
+                })
+                .await
+                .reconstruct();
+            assert_eq!(result, &expected);
+        });
+    }
+
+    /// Test input with an 8-bit trigger value, wider than the saturating sum of the tests using it.
+    fn wide_trigger_value_input(
+        prf_of_match_key: u64,
+        is_trigger: bool,
+        breakdown_key: u8,
+        trigger_value: u8,
+    ) -> PreShardedAndSortedOPRFTestInput<BA5, BA8, BA20> {
+        let input = oprf_test_input::<BA5>(prf_of_match_key, is_trigger, breakdown_key, 0);
+        PreShardedAndSortedOPRFTestInput {
+            prf_of_match_key,
+            is_trigger_bit: input.is_trigger_bit,
+            breakdown_key: input.breakdown_key,
+            trigger_value: BA8::truncate_from(trigger_value),
+            timestamp: input.timestamp,
+            priority: None,
+        }
+    }
+
+    #[test]
+    fn trigger_values_wider_than_saturating_sum() {
+        run(|| async move {
+            let world = TestWorld::default();
+
+            // contributions to breakdowns 1, 2 and 3 with either cap
+            for (per_user_cap, contributions) in [(5, [5, 5, 5]), (8, [8, 8, 5])] {
+                let records = vec![
+                    /* First User (the trigger value does not fit into the sum, its lowest
+                    bits are all zero) */
+                    wide_trigger_value_input(1, false, 1, 0),
+                    wide_trigger_value_input(1, true, 0, 200),
+                    wide_trigger_value_input(1, true, 0, 2),
+                    /* Second User (the second trigger value overflows the sum) */
+                    wide_trigger_value_input(2, false, 2, 0),
+                    wide_trigger_value_input(2, true, 0, 3),
+                    wide_trigger_value_input(2, true, 0, 100),
+                    /* Third User */
+                    wide_trigger_value_input(3, false, 3, 0),
+                    wide_trigger_value_input(3, true, 0, 5),
+                ];
+
+                let mut expected = [0_u128; 32];
+                expected[1..=3].copy_from_slice(&contributions);
+
+                let result: Vec<_> = world
+                    .semi_honest(records.into_iter(), |ctx, input_rows| async move {
+                        attribute_cap_aggregate::<
+                            _,
+                            BA5,
+                            BA8,
+                            BA20,
+                            BA3,
+                            Replicated<Fp32BitPrime>,
+                            Fp32BitPrime,
+                        >(
+                            ctx, input_rows, None, per_user_cap, 32, None, None
+                        )
+                        .await
+                        .unwrap()
+                    })
+                    .await
+                    .reconstruct();
+                assert_eq!(result, &expected, "per-user cap {per_user_cap}");
+            }
+        });
+    }
+
+    #[test]
+    fn source_event_priority() {
+        const PER_USER_CAP: u32 = 8;
+
+        run(|| async move {
+            let world = TestWorld::default();
+
+            let records: Vec<PreShardedAndSortedOPRFTestInput<BA5, BA3, BA20>> = vec![
+                /* First User (tie goes to the first source event) */
+                oprf_test_input_with_priority(1, false, 3, 0, 10, 5),
+                oprf_test_input_with_priority(1, false, 7, 0, 10, 2),
+                oprf_test_input_with_priority(1, true, 0, 4, 20, 0),
+                /* Second User (tie goes to the second source event) */
+                oprf_test_input_with_priority(2, false, 3, 0, 10, 2),
+                oprf_test_input_with_priority(2, false, 7, 0, 10, 5),
+                oprf_test_input_with_priority(2, true, 0, 3, 20, 0),
+                /* Third User (no tie, the most recent source event wins) */
+                oprf_test_input_with_priority(3, false, 9, 0, 5, 9),
+                oprf_test_input_with_priority(3, false, 11, 0, 10, 1),
+                oprf_test_input_with_priority(3, true, 0, 2, 20, 0),
+                /* Fourth User (equal priorities, the last source event wins) */
+                oprf_test_input_with_priority(4, false, 13, 0, 10, 4),
+                oprf_test_input_with_priority(4, false, 15, 0, 10, 4),
+                oprf_test_input_with_priority(4, true, 0, 1, 20, 0),
+                /* Fifth User (trigger before the tie is attributed to the first source event) */
+                oprf_test_input_with_priority(5, false, 17, 0, 10, 1),
+                oprf_test_input_with_priority(5, true, 0, 5, 10, 0),
+                oprf_test_input_with_priority(5, false, 19, 0, 10, 0),
+                oprf_test_input_with_priority(5, true, 0, 6, 30, 0),
+            ];
+
+            let mut expected = [0_u128; 32];
+            expected[3] = 4;
+            expected[7] = 3;
+            expected[11] = 2;
+            expected[15] = 1;
+            expected[17] = 8; // 5 + 6, capped
+
+            let result: Vec<_> = world
+                .semi_honest(records.into_iter(), |ctx, input_rows| async move {
+                    attribute_cap_aggregate::<
+                        _,
+                        BA5,
+                        BA3,
+                        BA20,
+                        BA4,
+                        Replicated<Fp32BitPrime>,
+                        Fp32BitPrime,
+                    >(ctx, input_rows, None, PER_USER_CAP, 32, None, None)
                     .await
                     .unwrap()
                 })
@@ -0,0 +1,300 @@
+//! Attribution of signed trigger values.
+//!
+//! Refunds are trigger events with negative values, they subtract from the aggregates purchases
+//! add to. Trigger values are two's complement integers, the most significant bit of `TV` is the
+//! sign. Rows are split by that bit into purchases and refunds, which are attributed, capped and
+//! aggregated separately, as unsigned values, and refunds are subtracted from purchases at the
+//! end. Splitting takes one subtraction and two multiplications of `TV` per row, after which
+//! attribution costs twice as much as it does with unsigned trigger values.
+//!
+//! Purchases of a user are capped at `per_user_cap`, and so are refunds, so every user adds at
+//! most `per_user_cap` to the aggregates and takes at most `per_user_cap` away from them. Their
+//! contributions to all breakdowns add up to `2 * per_user_cap` in absolute value, which noise
+//! added for differential privacy must be calibrated to.
+use std::{iter::zip, num::NonZeroU32, ops::Not};
+
+use futures::future::try_join;
+use ipa_macros::Step;
+
+use crate::{
+    error::Error,
+    ff::{boolean::Boolean, ArrayAccess, CustomArray, Expand, Field, PrimeField, Serializable},
+    protocol::{
+        basics::{SecureMul, ShareKnownValue, SumOfProducts},
+        context::{Context, UpgradableContext, UpgradedContext, Validator},
+        ipa_prf::{
+            boolean_ops::comparison_and_subtraction_sequential::integer_sub,
+            prf_sharding::{attribute_cap_aggregate, PrfShardedIpaInputRow},
+            time_bucket::BucketBoundaries,
+        },
+        RecordId,
+    },
+    secret_sharing::{
+        replicated::{malicious::ExtendableField, semi_honest::AdditiveShare as Replicated},
+        Linear as LinearSecretSharing, WeakSharedValue,
+    },
+    seq_join::SeqJoin,
+};
+
+#[derive(Step)]
+pub enum RefundStep {
+    BinaryValidator,
+    NegateTriggerValue,
+    ZeroOutRefunds,
+    ZeroOutPurchases,
+    AttributePurchases,
+    AttributeRefunds,
+}
+
+/// Same as [`attribute_cap_aggregate`], but trigger values are signed and refunds subtract from
+/// the aggregates, see the [module documentation](self).
+///
+/// # Errors
+/// Same as [`attribute_cap_aggregate`].
+/// # Panics
+/// Same as [`attribute_cap_aggregate`].
+#[tracing::instrument(name = "attribute_cap_aggregate_signed", skip_all)]
+pub async fn attribute_cap_aggregate_signed<C, BK, TV, TS, SS, S, F>(
+    sh_ctx: C,
+    input_rows: Vec<PrfShardedIpaInputRow<BK, TV, TS>>,
+    attribution_window_seconds: Option<NonZeroU32>,
+    per_user_cap: u32,
+    num_breakdowns: u32,
+    breakdown_allow_list: Option<&[u32]>,
+    time_buckets: Option<&BucketBoundaries>,
+) -> Result<Vec<S>, Error>
+where
+    C: UpgradableContext,
+    C::UpgradedContext<Boolean>: UpgradedContext<Boolean, Share = Replicated<Boolean>>,
+    C::UpgradedContext<F>: UpgradedContext<F, Share = S>,
+    S: LinearSecretSharing<F>
+        + Serializable
+        + SecureMul<C::UpgradedContext<F>>
+        + ShareKnownValue<C::UpgradedContext<F>, F>
+        + SumOfProducts<C::UpgradedContext<F>>,
+    BK: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    TV: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    TS: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    SS: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    for<'a> &'a Replicated<SS>: IntoIterator<Item = Replicated<Boolean>>,
+    for<'a> &'a Replicated<TS>: IntoIterator<Item = Replicated<Boolean>>,
+    for<'a> &'a Replicated<TV>: IntoIterator<Item = Replicated<Boolean>>,
+    for<'a> &'a Replicated<BK>: IntoIterator<Item = Replicated<Boolean>>,
+    for<'a> <&'a Replicated<SS> as IntoIterator>::IntoIter: Send,
+    for<'a> <&'a Replicated<TV> as IntoIterator>::IntoIter: Send,
+    for<'a> <&'a Replicated<TS> as IntoIterator>::IntoIter: Send,
+    F: PrimeField + ExtendableField,
+{
+    let binary_validator = sh_ctx
+        .narrow(&RefundStep::BinaryValidator)
+        .validator::<Boolean>();
+    let (purchases, refunds) = split_by_sign(binary_validator.context(), input_rows).await?;
+
+    let (purchases, refunds) = try_join(
+        attribute_cap_aggregate::<_, BK, TV, TS, SS, S, F>(
+            sh_ctx.narrow(&RefundStep::AttributePurchases),
+            purchases,
+            attribution_window_seconds,
+            per_user_cap,
+            num_breakdowns,
+            breakdown_allow_list,
+            time_buckets,
+        ),
+        attribute_cap_aggregate::<_, BK, TV, TS, SS, S, F>(
+            sh_ctx.narrow(&RefundStep::AttributeRefunds),
+            refunds,
+            attribution_window_seconds,
+            per_user_cap,
+            num_breakdowns,
+            breakdown_allow_list,
+            time_buckets,
+        ),
+    )
+    .await?;
+
+    Ok(zip(purchases, refunds)
+        .map(|(purchase, refund)| purchase - refund)
+        .collect())
+}
+
+/// Splits every row into a purchase and a refund that are the same as the row, except for their
+/// trigger values. The purchase keeps the trigger value of the row if it is not negative and the
+/// refund gets its absolute value if it is, the other one gets zero.
+async fn split_by_sign<C, BK, TV, TS>(
+    ctx: C,
+    rows: Vec<PrfShardedIpaInputRow<BK, TV, TS>>,
+) -> Result<
+    (
+        Vec<PrfShardedIpaInputRow<BK, TV, TS>>,
+        Vec<PrfShardedIpaInputRow<BK, TV, TS>>,
+    ),
+    Error,
+>
+where
+    C: Context,
+    BK: WeakSharedValue,
+    TV: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    TS: WeakSharedValue,
+    for<'a> &'a Replicated<TV>: IntoIterator<Item = Replicated<Boolean>>,
+{
+    let ctx = ctx.set_total_records(rows.len());
+    let values = ctx
+        .try_join(rows.iter().enumerate().map(|(i, row)| {
+            let ctx = ctx.clone();
+            async move {
+                let record_id = RecordId::from(i);
+                let value = &row.trigger_value;
+                let is_refund = value
+                    .get(usize::try_from(<TV as WeakSharedValue>::BITS).unwrap() - 1)
+                    .unwrap();
+                let negated = integer_sub(
+                    ctx.narrow(&RefundStep::NegateTriggerValue),
+                    record_id,
+                    &Replicated::<TV>::ZERO,
+                    value,
+                )
+                .await?;
+                try_join(
+                    value.multiply(
+                        &Replicated::<TV>::expand(&is_refund.clone().not()),
+                        ctx.narrow(&RefundStep::ZeroOutRefunds),
+                        record_id,
+                    ),
+                    negated.multiply(
+                        &Replicated::<TV>::expand(&is_refund),
+                        ctx.narrow(&RefundStep::ZeroOutPurchases),
+                        record_id,
+                    ),
+                )
+                .await
+            }
+        }))
+        .await?;
+
+    Ok(zip(rows, values)
+        .map(|(row, (purchase, refund))| {
+            let refund = PrfShardedIpaInputRow {
+                prf_of_match_key: row.prf_of_match_key,
+                is_trigger_bit: row.is_trigger_bit.clone(),
+                breakdown_key: row.breakdown_key.clone(),
+                trigger_value: refund,
+                timestamp: row.timestamp.clone(),
+                priority: row.priority.clone(),
+            };
+            let purchase = PrfShardedIpaInputRow {
+                trigger_value: purchase,
+                ..row
+            };
+            (purchase, refund)
+        })
+        .unzip())
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use super::attribute_cap_aggregate_signed;
+    use crate::{
+        ff::{
+            boolean_array::{BA20, BA3, BA5},
+            Field, Fp32BitPrime,
+        },
+        protocol::ipa_prf::prf_sharding::tests::{
+            oprf_test_input, PreShardedAndSortedOPRFTestInput,
+        },
+        secret_sharing::replicated::semi_honest::AdditiveShare as Replicated,
+        test_executor::run,
+        test_fixture::{Reconstruct, Runner, TestWorld},
+    };
+
+    /// Trigger event of `value`, two's complement in 3 bits.
+    fn trigger(prf: u64, value: i8) -> PreShardedAndSortedOPRFTestInput<BA5, BA3, BA20> {
+        oprf_test_input(prf, true, 0, u8::try_from(value.rem_euclid(8)).unwrap())
+    }
+
+    fn source(prf: u64, breakdown_key: u8) -> PreShardedAndSortedOPRFTestInput<BA5, BA3, BA20> {
+        oprf_test_input(prf, false, breakdown_key, 0)
+    }
+
+    async fn run_signed(
+        records: Vec<PreShardedAndSortedOPRFTestInput<BA5, BA3, BA20>>,
+        per_user_cap: u32,
+    ) -> Vec<Fp32BitPrime> {
+        TestWorld::default()
+            .semi_honest(records.into_iter(), |ctx, input_rows| async move {
+                attribute_cap_aggregate_signed::<
+                    _,
+                    BA5,
+                    BA3,
+                    BA20,
+                    BA5,
+                    Replicated<Fp32BitPrime>,
+                    Fp32BitPrime,
+                >(ctx, input_rows, None, per_user_cap, 32, None, None)
+                .await
+                .unwrap()
+            })
+            .await
+            .reconstruct()
+    }
+
+    fn expected(values: &[(usize, i64)]) -> Vec<Fp32BitPrime> {
+        let mut expected = vec![Fp32BitPrime::ZERO; 32];
+        for &(breakdown_key, value) in values {
+            let magnitude = Fp32BitPrime::truncate_from(value.unsigned_abs());
+            expected[breakdown_key] = if value < 0 {
+                Fp32BitPrime::ZERO - magnitude
+            } else {
+                magnitude
+            };
+        }
+        expected
+    }
+
+    #[test]
+    fn refunds_subtract_from_purchases() {
+        run(|| async move {
+            let records = vec![
+                /* First User: buys for 3 and returns some of it */
+                source(123, 17),
+                trigger(123, 3),
+                trigger(123, -2),
+                /* Second User: refund only, for something bought before the query window */
+                source(234, 12),
+                trigger(234, -4),
+                /* Third User: unattributed refund */
+                trigger(345, -3),
+                source(345, 20),
+                trigger(345, 2),
+            ];
+
+            assert_eq!(
+                expected(&[(17, 1), (12, -4), (20, 2)]),
+                run_signed(records, 32).await
+            );
+        });
+    }
+
+    #[test]
+    fn purchases_and_refunds_are_capped_separately() {
+        run(|| async move {
+            let records = vec![
+                source(123, 3),
+                trigger(123, 3),
+                trigger(123, 3),
+                trigger(123, -4),
+                trigger(123, -1),
+                trigger(123, -4),
+                source(234, 5),
+                trigger(234, -4),
+                trigger(234, -4),
+                trigger(234, 1),
+            ];
+
+            // purchases are capped at 4, and so are refunds
+            assert_eq!(
+                expected(&[(3, 4 - 4), (5, 1 - 4)]),
+                run_signed(records, 4).await
+            );
+        });
+    }
+}
@@ -0,0 +1,193 @@
+//! Sharded attribution.
+//!
+//! Once the PRF of the match key is revealed, rows that belong to different users never interact
+//! with each other until the very last step, when per-breakdown contributions are summed up. This
+//! makes it possible to split the input into shards by PRF value and run attribution and capping
+//! on each shard independently. Because PRF values are pseudo-random, shards are roughly equal in
+//! size. Per-breakdown sums are additive shares, so merging shard outputs does not require any
+//! communication.
+//!
+//! All helpers see the same PRF values, so they agree on which row goes to which shard without
+//! any coordination.
+use std::num::NonZeroU32;
+
+use ipa_macros::Step;
+
+use crate::{
+    error::Error,
+    ff::{boolean::Boolean, CustomArray, Field, PrimeField, Serializable},
+    protocol::{
+        basics::{SecureMul, ShareKnownValue, SumOfProducts},
+        context::{Context, UpgradableContext, UpgradedContext},
+        ipa_prf::prf_sharding::{attribute_cap_aggregate, GroupingKey, PrfShardedIpaInputRow},
+    },
+    secret_sharing::{
+        replicated::{malicious::ExtendableField, semi_honest::AdditiveShare as Replicated},
+        Linear as LinearSecretSharing, WeakSharedValue,
+    },
+    seq_join::SeqJoin,
+};
+
+/// Maximum number of shards supported by the protocol.
+pub const MAX_SHARDS: u32 = 64;
+
+#[derive(Step)]
+pub enum ShardStep {
+    #[dynamic(64)]
+    Shard(usize),
+}
+
+impl From<usize> for ShardStep {
+    fn from(v: usize) -> Self {
+        Self::Shard(v)
+    }
+}
+
+/// Returns the shard that processes all rows with the given grouping key.
+///
+/// ## Panics
+/// If `shard_count` exceeds [`MAX_SHARDS`].
+#[must_use]
+pub fn shard_of(grouping_key: u64, shard_count: NonZeroU32) -> usize {
+    assert!(
+        shard_count.get() <= MAX_SHARDS,
+        "{shard_count} shards requested, but at most {MAX_SHARDS} are supported"
+    );
+    usize::try_from(grouping_key % u64::from(shard_count.get())).unwrap()
+}
+
+/// Splits rows into `shard_count` shards. Relative order of rows inside each shard is preserved,
+/// so if all rows for a given user are adjacent and sorted by time in the input, they remain
+/// so inside the shard.
+///
+/// ## Panics
+/// If `shard_count` exceeds [`MAX_SHARDS`].
+pub fn partition_by_shard<R: GroupingKey>(rows: Vec<R>, shard_count: NonZeroU32) -> Vec<Vec<R>> {
+    let n = usize::try_from(shard_count.get()).unwrap();
+    let mut shards = (0..n)
+        .map(|_| Vec::with_capacity(rows.len() / n))
+        .collect::<Vec<_>>();
+    for row in rows {
+        shards[shard_of(row.get_grouping_key(), shard_count)].push(row);
+    }
+
+    shards
+}
+
+/// Runs [`attribute_cap_aggregate`] on every shard and merges the per-breakdown sums.
+///
+/// # Errors
+/// Propagates errors from multiplications. Returns an error if `num_breakdowns` exceeds `2^|BK|`.
+/// # Panics
+/// If `shard_count` exceeds [`MAX_SHARDS`] or `per_user_cap` is not within `[1, 2^|SS|]`.
+#[tracing::instrument(name = "sharded_attribute_cap_aggregate", skip_all, fields(shards = shard_count.get()))]
+pub async fn sharded_attribute_cap_aggregate<C, BK, TV, TS, SS, S, F>(
+    sh_ctx: C,
+    input_rows: Vec<PrfShardedIpaInputRow<BK, TV, TS>>,
+    attribution_window_seconds: Option<NonZeroU32>,
+    per_user_cap: u32,
+    num_breakdowns: u32,
+    breakdown_allow_list: Option<&[u32]>,
+    shard_count: NonZeroU32,
+) -> Result<Vec<S>, Error>
+where
+    C: UpgradableContext,
+    C::UpgradedContext<Boolean>: UpgradedContext<Boolean, Share = Replicated<Boolean>>,
+    C::UpgradedContext<F>: UpgradedContext<F, Share = S>,
+    S: LinearSecretSharing<F>
+        + Serializable
+        + SecureMul<C::UpgradedContext<F>>
+        + ShareKnownValue<C::UpgradedContext<F>, F>
+        + SumOfProducts<C::UpgradedContext<F>>,
+    BK: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    TV: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    TS: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    SS: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    for<'a> &'a Replicated<SS>: IntoIterator<Item = Replicated<Boolean>>,
+    for<'a> &'a Replicated<TS>: IntoIterator<Item = Replicated<Boolean>>,
+    for<'a> &'a Replicated<TV>: IntoIterator<Item = Replicated<Boolean>>,
+    for<'a> &'a Replicated<BK>: IntoIterator<Item = Replicated<Boolean>>,
+    for<'a> <&'a Replicated<SS> as IntoIterator>::IntoIter: Send,
+    for<'a> <&'a Replicated<TV> as IntoIterator>::IntoIter: Send,
+    for<'a> <&'a Replicated<TS> as IntoIterator>::IntoIter: Send,
+    F: PrimeField + ExtendableField,
+{
+    let shards = partition_by_shard(input_rows, shard_count);
+
+    // Empty shards are skipped. Every helper makes the same decision because PRF values are public.
+    let shard_outputs = sh_ctx
+        .parallel_join(
+            shards
+                .into_iter()
+                .enumerate()
+                .filter(|(_, rows)| !rows.is_empty())
+                .map(|(i, rows)| {
+                    let ctx = sh_ctx.narrow(&ShardStep::from(i));
+                    attribute_cap_aggregate::<_, BK, TV, TS, SS, S, F>(
+                        ctx,
+                        rows,
+                        attribution_window_seconds,
+                        per_user_cap,
+                        num_breakdowns,
+                        breakdown_allow_list,
+                        None,
+                    )
+                }),
+        )
+        .await?;
+
+    Ok(shard_outputs.into_iter().fold(
+        vec![S::ZERO; usize::try_from(num_breakdowns).unwrap()],
+        |mut running_sums, shard_sums| {
+            for (sum, shard_sum) in running_sums.iter_mut().zip(&shard_sums) {
+                *sum += shard_sum;
+            }
+            running_sums
+        },
+    ))
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use super::{partition_by_shard, shard_of};
+    use crate::protocol::ipa_prf::prf_sharding::GroupingKey;
+
+    #[derive(Debug, PartialEq)]
+    struct Row {
+        prf: u64,
+        seq: usize,
+    }
+
+    impl GroupingKey for Row {
+        fn get_grouping_key(&self) -> u64 {
+            self.prf
+        }
+    }
+
+    #[test]
+    fn partition_preserves_order() {
+        let shard_count = NonZeroU32::new(3).unwrap();
+        let prfs = [7, 7, 7, 2, 2, 9, 4, 4, 4, 4, 5];
+        let rows = prfs
+            .iter()
+            .enumerate()
+            .map(|(seq, &prf)| Row { prf, seq })
+            .collect::<Vec<_>>();
+
+        let shards = partition_by_shard(rows, shard_count);
+        assert_eq!(3, shards.len());
+        assert_eq!(prfs.len(), shards.iter().map(Vec::len).sum::<usize>());
+        for (i, shard) in shards.iter().enumerate() {
+            assert!(shard.iter().all(|row| shard_of(row.prf, shard_count) == i));
+            assert!(shard.windows(2).all(|w| w[0].seq < w[1].seq));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "at most 64 are supported")]
+    fn too_many_shards() {
+        let _ = shard_of(1, NonZeroU32::new(65).unwrap());
+    }
+}
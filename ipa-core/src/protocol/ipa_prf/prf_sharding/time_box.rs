@@ -0,0 +1,169 @@
+//! Time-boxed attribution.
+//!
+//! Exploratory queries often don't need an exact answer, and a result computed over most of the
+//! users is more useful if it arrives on time. Time-boxed attribution splits users into batches,
+//! the same way [`sharding`] splits them into shards, and runs attribution, capping and
+//! aggregation on one batch at a time. Before starting the next batch, every helper tells its
+//! peers whether the deadline has passed on its clock. If it has on any of them, all helpers stop
+//! and release the aggregate over the batches processed so far, along with the share of users
+//! it covers.
+//!
+//! PRF values are pseudo-random, so every batch is a random sample of users and the partial
+//! aggregate scales to the full one. Whether a helper stops is revealed to its peers, that does
+//! not depend on the input.
+//!
+//! [`sharding`]: super::sharding
+use std::{num::NonZeroU32, time::Instant};
+
+use futures::future::try_join;
+use ipa_macros::Step;
+
+use crate::{
+    error::Error,
+    ff::{boolean::Boolean, CustomArray, Field, PrimeField, Serializable},
+    helpers::Direction,
+    protocol::{
+        basics::{SecureMul, ShareKnownValue, SumOfProducts},
+        context::{Context, UpgradableContext, UpgradedContext},
+        ipa_prf::{
+            pipeline::{Completeness, PartialAggregate},
+            prf_sharding::{
+                attribute_cap_aggregate, count_users, sharding::partition_by_shard,
+                PrfShardedIpaInputRow,
+            },
+        },
+        RecordId,
+    },
+    secret_sharing::{
+        replicated::{malicious::ExtendableField, semi_honest::AdditiveShare as Replicated},
+        Linear as LinearSecretSharing, WeakSharedValue,
+    },
+};
+
+/// Number of batches users are split into. It is the granularity of partial results: a
+/// query stopped by its deadline covers a multiple of `1/TIME_BOX_BATCHES` of users, roughly.
+pub const TIME_BOX_BATCHES: NonZeroU32 = match NonZeroU32::new(16) {
+    Some(v) => v,
+    None => unreachable!(),
+};
+
+#[derive(Step)]
+pub enum TimeBoxStep {
+    #[dynamic(16)]
+    DeadlinePassed(usize),
+    #[dynamic(16)]
+    Batch(usize),
+}
+
+/// Tells the peers whether the deadline has passed on this helper and returns whether it
+/// has on any helper.
+async fn deadline_passed<C: Context>(ctx: C, deadline: Instant) -> Result<bool, Error> {
+    let ctx = ctx.set_total_records(1);
+    let passed = Boolean::from(Instant::now() >= deadline);
+    let (left, right) = (
+        ctx.role().peer(Direction::Left),
+        ctx.role().peer(Direction::Right),
+    );
+    try_join(
+        ctx.send_channel(left).send(RecordId::FIRST, passed),
+        ctx.send_channel(right).send(RecordId::FIRST, passed),
+    )
+    .await?;
+    let (from_left, from_right) = try_join(
+        ctx.recv_channel::<Boolean>(left).receive(RecordId::FIRST),
+        ctx.recv_channel::<Boolean>(right).receive(RecordId::FIRST),
+    )
+    .await?;
+
+    Ok(bool::from(passed) || bool::from(from_left) || bool::from(from_right))
+}
+
+/// Runs [`attribute_cap_aggregate`] on batches of users, one at a time, until all of them are
+/// processed or `deadline` passes on any helper, and sums per-breakdown totals of the
+/// processed batches.
+///
+/// # Errors
+/// Propagates errors from multiplications. Returns an error if `num_breakdowns` exceeds
+/// `2^|BK|`.
+/// # Panics
+/// If `per_user_cap` is not within `[1, 2^|SS|]`.
+#[tracing::instrument(name = "time_boxed_attribute_cap_aggregate", skip_all)]
+pub async fn time_boxed_attribute_cap_aggregate<C, BK, TV, TS, SS, S, F>(
+    sh_ctx: C,
+    input_rows: Vec<PrfShardedIpaInputRow<BK, TV, TS>>,
+    attribution_window_seconds: Option<NonZeroU32>,
+    per_user_cap: u32,
+    num_breakdowns: u32,
+    breakdown_allow_list: Option<&[u32]>,
+    deadline: Instant,
+) -> Result<PartialAggregate<S>, Error>
+where
+    C: UpgradableContext,
+    C::UpgradedContext<Boolean>: UpgradedContext<Boolean, Share = Replicated<Boolean>>,
+    C::UpgradedContext<F>: UpgradedContext<F, Share = S>,
+    S: LinearSecretSharing<F>
+        + Serializable
+        + SecureMul<C::UpgradedContext<F>>
+        + ShareKnownValue<C::UpgradedContext<F>, F>
+        + SumOfProducts<C::UpgradedContext<F>>,
+    BK: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    TV: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    TS: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    SS: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    for<'a> &'a Replicated<SS>: IntoIterator<Item = Replicated<Boolean>>,
+    for<'a> &'a Replicated<TS>: IntoIterator<Item = Replicated<Boolean>>,
+    for<'a> &'a Replicated<TV>: IntoIterator<Item = Replicated<Boolean>>,
+    for<'a> &'a Replicated<BK>: IntoIterator<Item = Replicated<Boolean>>,
+    for<'a> <&'a Replicated<SS> as IntoIterator>::IntoIter: Send,
+    for<'a> <&'a Replicated<TV> as IntoIterator>::IntoIter: Send,
+    for<'a> <&'a Replicated<TS> as IntoIterator>::IntoIter: Send,
+    F: PrimeField + ExtendableField,
+{
+    let batches = partition_by_shard(input_rows, TIME_BOX_BATCHES)
+        .into_iter()
+        .filter(|rows| !rows.is_empty())
+        .map(|rows| {
+            let users = count_users(&rows);
+            (rows, users)
+        })
+        .collect::<Vec<_>>();
+    let total_users = batches.iter().map(|&(_, users)| users).sum();
+
+    let mut output = PartialAggregate {
+        breakdowns: vec![S::ZERO; usize::try_from(num_breakdowns).unwrap()],
+        completeness: Completeness {
+            processed_users: 0,
+            total_users,
+        },
+        shape: None,
+        group_sizes: None,
+        attributed_users: None,
+    };
+    for (i, (rows, users)) in batches.into_iter().enumerate() {
+        if deadline_passed(sh_ctx.narrow(&TimeBoxStep::DeadlinePassed(i)), deadline).await? {
+            tracing::warn!(
+                "deadline passed after {i} batches, releasing partial result over {} out \
+                 of {total_users} users",
+                output.completeness.processed_users
+            );
+            break;
+        }
+
+        let batch_sums = attribute_cap_aggregate::<_, BK, TV, TS, SS, S, F>(
+            sh_ctx.narrow(&TimeBoxStep::Batch(i)),
+            rows,
+            attribution_window_seconds,
+            per_user_cap,
+            num_breakdowns,
+            breakdown_allow_list,
+            None,
+        )
+        .await?;
+        for (sum, batch_sum) in output.breakdowns.iter_mut().zip(&batch_sums) {
+            *sum += batch_sum;
+        }
+        output.completeness.processed_users += users;
+    }
+
+    Ok(output)
+}
@@ -0,0 +1,247 @@
+//! Scaling of aggregates to the units the report collector asked for.
+//!
+//! Trigger values often come in finer units than the collector needs, like cents when the
+//! collector wants dollars. Dividing revealed totals is easy, but it is also easy to get wrong:
+//! rounding to nearest is biased for small totals and the digits dropped by the collector are
+//! still visible to anyone who gets the output. This stage divides secret-shared totals by a
+//! public divisor `d` before they are revealed, rounding stochastically, so the expected value of
+//! every scaled total is exactly `x / d`.
+//!
+//! Helpers reveal `y = x + o + 1 + u + d * r`, where `o` is a public multiple of `d` that makes
+//! totals with noise non-negative, `u = u_1 + u_2 + u_3` and `r = r_1 + r_2 + r_3` are random,
+//! every `u_i` is uniform in `[0, d)` and every `r_i` is uniform in `[0, 2^m)`. Every component is
+//! known to two helpers, so each helper misses one `u_i`, which makes `y mod d` uniform for it, and
+//! one `r_i`, which statistically hides `floor(y / d)`. The result is
+//! `floor(y / d) - o / d - 1 - r`, because `floor((z + u_i) / d)` has expected value `z / d` for
+//! every integer `z`, and the other two components of `u` add `d - 1` on average, which the extra
+//! `1` rounds up to a full `d`.
+//!
+//! Unlike stochastic rounding to one of the two nearest integers, which would need helpers to
+//! compute the carry of `u_1 + u_2 + u_3` together, the result is within `[floor(x / d) - 1,
+//! floor(x / d) + 2]`. It adds a little variance, much less than DP noise adds in any realistic
+//! query, but no bias and no communication besides the reveal.
+//!
+//! The mask width `m` is as large as the field allows, so the larger the field and the smaller the
+//! totals, the better `r` hides them. Totals are revealed after DP noise is added, so helpers
+//! never learn more than the differentially private output, even if the mask is narrow.
+use std::num::NonZeroU32;
+
+use futures::future::try_join_all;
+use rand::Rng;
+
+use crate::{
+    error::Error,
+    ff::PrimeField,
+    protocol::{
+        basics::{Reveal, ShareKnownValue},
+        context::Context,
+        RecordId,
+    },
+    secret_sharing::replicated::{
+        semi_honest::AdditiveShare as Replicated, ReplicatedSecretSharing,
+    },
+};
+
+/// Largest width of the mask hiding revealed totals from helpers.
+const MAX_MASK_BITS: u32 = 64;
+
+/// Public parameters of the masked reveal of values bounded by `max_abs`.
+#[derive(Debug, PartialEq, Eq)]
+struct Mask {
+    divisor: u128,
+    offset: u128,
+    bits: u32,
+}
+
+impl Mask {
+    /// Returns parameters for values in `[-max_abs, max_abs]`, `None` if the field is too small
+    /// to reveal them without wrapping around.
+    fn new<F: PrimeField>(divisor: NonZeroU32, max_abs: u128) -> Option<Self> {
+        let divisor = u128::from(divisor.get());
+        let prime = F::PRIME.into();
+        let offset = max_abs.checked_add(divisor - 1)? / divisor * divisor;
+        // `x + o + 1 + u` is at most that
+        let unmasked = max_abs.checked_add(offset)?.checked_add(3 * divisor - 2)?;
+        let room = prime.checked_sub(unmasked)?.checked_sub(1)? / (3 * divisor);
+        let bits = (u128::BITS - room.saturating_add(1).leading_zeros())
+            .saturating_sub(1)
+            .min(MAX_MASK_BITS);
+        (bits > 0).then_some(Self {
+            divisor,
+            offset,
+            bits,
+        })
+    }
+
+    /// Draws one component of `u` and one of `r`.
+    fn draw<R: Rng>(&self, rng: &mut R) -> (u128, u128) {
+        let u = rng.gen_range(0..self.divisor);
+        let r = rng.gen_range(0..1_u128 << self.bits);
+        (u, r)
+    }
+}
+
+/// Divides every value in `values` by `divisor`, rounding stochastically, see the [module]
+/// documentation. Values must be in `[-max_abs, max_abs]`, negative ones represented as `p - v`.
+///
+/// This consumes the PRSS of `ctx`, so `ctx` must not be used to generate shared randomness.
+///
+/// ## Errors
+/// If the field is too small to hold values in `[-max_abs, max_abs]` along with the mask, or if
+/// revealing masked values fails.
+///
+/// [module]: self
+pub async fn scale_down<C, F>(
+    ctx: C,
+    values: Vec<Replicated<F>>,
+    divisor: NonZeroU32,
+    max_abs: u128,
+) -> Result<Vec<Replicated<F>>, Error>
+where
+    C: Context,
+    F: PrimeField,
+{
+    let mask = Mask::new::<F>(divisor, max_abs).ok_or_else(|| {
+        Error::InvalidQueryParameter(
+            format!(
+                "values up to {max_abs} can't be divided by {divisor} in a field of size {}",
+                F::PRIME.into()
+            )
+            .into(),
+        )
+    })?;
+
+    let (mut left_rng, mut right_rng) = ctx.prss_rng();
+    let masks = values
+        .iter()
+        .map(|_| {
+            let (left, right) = (mask.draw(&mut left_rng), mask.draw(&mut right_rng));
+            (
+                Replicated::new(
+                    F::truncate_from(left.0 + mask.divisor * left.1),
+                    F::truncate_from(right.0 + mask.divisor * right.1),
+                ),
+                Replicated::new(F::truncate_from(left.1), F::truncate_from(right.1)),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let ctx = ctx.set_total_records(values.len());
+    let shift = Replicated::share_known_value(&ctx, F::truncate_from(mask.offset + 1));
+    let revealed = try_join_all(values.iter().zip(&masks).enumerate().map(
+        |(i, (value, (masked, _)))| {
+            let y = value + &shift + masked;
+            let ctx = ctx.clone();
+            async move { y.reveal(ctx, RecordId::from(i)).await }
+        },
+    ))
+    .await?;
+
+    Ok(revealed
+        .into_iter()
+        .zip(masks)
+        .map(|(y, (_, r))| {
+            let quotient = y.as_u128() / mask.divisor;
+            let scaled = Replicated::share_known_value(
+                &ctx,
+                to_field::<F>(quotient, mask.offset / mask.divisor + 1),
+            );
+            scaled - &r
+        })
+        .collect())
+}
+
+/// Returns `a - b` as a field element.
+fn to_field<F: PrimeField>(a: u128, b: u128) -> F {
+    if a >= b {
+        F::truncate_from(a - b)
+    } else {
+        -F::truncate_from(b - a)
+    }
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use super::{scale_down, Mask};
+    use crate::{
+        ff::{Field, Fp31, Fp32BitPrime, PrimeField},
+        protocol::context::Context,
+        test_fixture::{Reconstruct, Runner, TestWorld},
+    };
+
+    #[test]
+    fn mask_fits_field() {
+        let divisor = NonZeroU32::new(100).unwrap();
+        let mask = Mask::new::<Fp32BitPrime>(divisor, 1_000).unwrap();
+        assert_eq!(1_000, mask.offset);
+        let max = 2_000 + 1 + 3 * 99 + 3 * 100 * ((1 << mask.bits) - 1);
+        assert!(max < u128::from(Fp32BitPrime::PRIME));
+        // twice as wide a mask would not fit
+        assert!(max + 3 * 100 * (1 << mask.bits) >= u128::from(Fp32BitPrime::PRIME));
+
+        assert_eq!(None, Mask::new::<Fp31>(divisor, 10));
+    }
+
+    #[tokio::test]
+    async fn rounds_stochastically() {
+        const DIVISOR: i128 = 10;
+        const RUNS: i128 = 200;
+        let world = TestWorld::default();
+        let values = [0_i128, 7, 10, 123, 4_000, -45];
+        let to_signed = |v: Fp32BitPrime| {
+            let p = i128::from(Fp32BitPrime::PRIME);
+            let v = i128::try_from(v.as_u128()).unwrap();
+            if v > p / 2 {
+                v - p
+            } else {
+                v
+            }
+        };
+
+        let mut totals = [0; 6];
+        for _ in 0..RUNS {
+            let input = values.map(|v| {
+                let abs = Fp32BitPrime::truncate_from(v.unsigned_abs());
+                if v < 0 {
+                    -abs
+                } else {
+                    abs
+                }
+            });
+            let scaled: Vec<_> = world
+                .semi_honest(input.into_iter(), |ctx, values| async move {
+                    scale_down(
+                        ctx.narrow("scale"),
+                        values,
+                        NonZeroU32::new(10).unwrap(),
+                        5_000,
+                    )
+                    .await
+                    .unwrap()
+                })
+                .await
+                .reconstruct();
+
+            for ((total, value), scaled) in totals.iter_mut().zip(values).zip(scaled) {
+                let scaled = to_signed(scaled);
+                let floor = value.div_euclid(DIVISOR);
+                assert!(
+                    (floor - 1..=floor + 2).contains(&scaled),
+                    "{value} -> {scaled}"
+                );
+                *total += scaled;
+            }
+        }
+
+        // expected value of every scaled total is `x / d`, standard deviation of the sum of 200
+        // of them is below 10
+        for (total, value) in totals.into_iter().zip(values) {
+            assert!(
+                (total * DIVISOR - RUNS * value).abs() < 50 * DIVISOR,
+                "{value}: {total}"
+            );
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! Splitting users with many rows into segments.
+//!
+//! Attribution processes rows of a user one after another, so the number of sequential rounds it
+//! takes grows with the number of rows of the largest user. A handful of users with enormous row
+//! counts can dominate the time the whole query takes, even though most users have a few rows.
+//!
+//! Once PRF values are revealed, helpers know how many rows every user has, so they can split
+//! rows of large users into segments of at most [`SegmentParams::max_rows`] rows each, without
+//! any communication. Segments are attributed and capped as if they were separate users, so the
+//! depth of attribution is bounded by the segment size rather than by the largest user.
+//!
+//! Semantics differ from attributing whole users in two ways:
+//! * Trigger events are only attributed to source events in the same segment.
+//! * Every segment is capped at `per_user_cap / max_segments`, so all segments of a user
+//!   together contribute at most `per_user_cap` and DP noise calibrated to it stays valid. Rows
+//!   of a user beyond `max_rows * max_segments` are dropped.
+use std::num::NonZeroU32;
+
+use crate::{
+    protocol::ipa_prf::prf_sharding::PrfShardedIpaInputRow, secret_sharing::WeakSharedValue,
+};
+
+/// Configures how rows of a single user are split into segments.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SegmentParams {
+    /// Maximum number of rows in a segment.
+    pub max_rows: NonZeroU32,
+    /// Maximum number of segments of a single user. The per-user cap is divided evenly between
+    /// them.
+    pub max_segments: NonZeroU32,
+}
+
+impl SegmentParams {
+    /// Returns the cap of every segment, `None` if `per_user_cap` is too small to be divided
+    /// between `max_segments` segments.
+    #[must_use]
+    pub fn segment_cap(&self, per_user_cap: u32) -> Option<u32> {
+        Some(per_user_cap / self.max_segments).filter(|&cap| cap > 0)
+    }
+
+    /// Splits `rows`, which must be grouped by user, into segments. Every segment gets its own
+    /// grouping key, so the rest of the protocol treats it as a separate user. Segments keep the
+    /// order of rows, and rows of each user beyond the last segment are dropped.
+    #[must_use]
+    pub fn split<BK, TV, TS>(
+        &self,
+        rows: Vec<PrfShardedIpaInputRow<BK, TV, TS>>,
+    ) -> Vec<PrfShardedIpaInputRow<BK, TV, TS>>
+    where
+        BK: WeakSharedValue,
+        TV: WeakSharedValue,
+        TS: WeakSharedValue,
+    {
+        let max_rows = u64::from(self.max_rows.get());
+        let max_user_rows = max_rows * u64::from(self.max_segments.get());
+        let mut user = None;
+        let mut user_rows = 0;
+        let mut segment = 0_u64;
+
+        rows.into_iter()
+            .filter_map(|mut row| {
+                if user == Some(row.prf_of_match_key) {
+                    user_rows += 1;
+                    if user_rows % max_rows == 0 {
+                        segment += 1;
+                    }
+                } else {
+                    if user.is_some() {
+                        segment += 1;
+                    }
+                    user = Some(row.prf_of_match_key);
+                    user_rows = 0;
+                }
+
+                (user_rows < max_user_rows).then(|| {
+                    row.prf_of_match_key = segment;
+                    row
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use super::SegmentParams;
+    use crate::{
+        ff::boolean_array::{BA20, BA3, BA8},
+        protocol::ipa_prf::prf_sharding::PrfShardedIpaInputRow,
+        secret_sharing::replicated::semi_honest::AdditiveShare as Replicated,
+    };
+
+    fn row(user: u64) -> PrfShardedIpaInputRow<BA8, BA3, BA20> {
+        PrfShardedIpaInputRow {
+            prf_of_match_key: user,
+            is_trigger_bit: Replicated::default(),
+            breakdown_key: Replicated::default(),
+            trigger_value: Replicated::default(),
+            timestamp: Replicated::default(),
+            priority: None,
+        }
+    }
+
+    fn params(max_rows: u32, max_segments: u32) -> SegmentParams {
+        SegmentParams {
+            max_rows: NonZeroU32::new(max_rows).unwrap(),
+            max_segments: NonZeroU32::new(max_segments).unwrap(),
+        }
+    }
+
+    #[test]
+    fn splits_large_users() {
+        let rows = [7, 7, 7, 7, 7, 3, 9, 9].map(row).into_iter().collect();
+        let keys = params(2, 4)
+            .split(rows)
+            .iter()
+            .map(|row| row.prf_of_match_key)
+            .collect::<Vec<_>>();
+        assert_eq!(vec![0, 0, 1, 1, 2, 3, 4, 4], keys);
+    }
+
+    #[test]
+    fn drops_rows_beyond_last_segment() {
+        let rows = [1, 1, 1, 1, 1, 2].map(row).into_iter().collect();
+        let keys = params(2, 2)
+            .split(rows)
+            .iter()
+            .map(|row| row.prf_of_match_key)
+            .collect::<Vec<_>>();
+        assert_eq!(vec![0, 0, 1, 1, 2], keys);
+    }
+
+    #[test]
+    fn segment_cap() {
+        assert_eq!(Some(8), params(10, 4).segment_cap(32));
+        assert_eq!(Some(1), params(10, 4).segment_cap(7));
+        assert_eq!(None, params(10, 4).segment_cap(3));
+    }
+}
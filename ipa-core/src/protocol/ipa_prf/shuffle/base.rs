@@ -2,12 +2,12 @@ use std::ops::{Add, AddAssign};
 
 use futures::future;
 use ipa_macros::Step;
-use rand::{distributions::Standard, prelude::Distribution, seq::SliceRandom, Rng};
+use rand::{distributions::Standard, prelude::Distribution, Rng};
 
-use super::super::{context::Context, RecordId};
 use crate::{
     error::Error,
     helpers::{Direction, ReceivingEnd, Role},
+    protocol::{context::Context, ipa_prf::shuffle::SharedPermutations, RecordId},
     secret_sharing::{
         replicated::{semi_honest::AdditiveShare, ReplicatedSecretSharing},
         SharedValue,
@@ -25,9 +25,40 @@ pub(crate) enum OPRFShuffleStep {
     TransferY1,
 }
 
+/// Messages that a helper sent or received while shuffling, which are needed to verify the
+/// shuffle afterwards. See [`malicious_shuffle`](super::malicious_shuffle).
+#[cfg_attr(not(feature = "descriptive-gate"), allow(dead_code))]
+pub(super) enum IntermediateMessages<S> {
+    /// `x_1`, computed by H1 and never sent.
+    H1 { x1: Vec<S> },
+    /// `x_2`, received from H1.
+    H2 { x2: Vec<S> },
+    /// `y_1`, received from H2, and `y_2`, computed from it.
+    H3 { y1: Vec<S>, y2: Vec<S> },
+}
+
 /// # Errors
 /// Will propagate errors from transport and a few typecasts
+/// # Panics
+/// If there are more than `u32::MAX` shares.
 pub async fn shuffle<C, I, S>(ctx: C, shares: I) -> Result<Vec<AdditiveShare<S>>, Error>
+where
+    C: Context,
+    I: IntoIterator<Item = AdditiveShare<S>>,
+    I::IntoIter: ExactSizeIterator,
+    S: SharedValue + Add<Output = S>,
+    for<'a> &'a S: Add<S, Output = S>,
+    for<'a> &'a S: Add<&'a S, Output = S>,
+    Standard: Distribution<S>,
+{
+    Ok(shuffle_with_messages(ctx, shares).await?.0)
+}
+
+/// Same as [`shuffle`], but also returns the messages needed to verify it.
+pub(super) async fn shuffle_with_messages<C, I, S>(
+    ctx: C,
+    shares: I,
+) -> Result<(Vec<AdditiveShare<S>>, IntermediateMessages<S>), Error>
 where
     C: Context,
     I: IntoIterator<Item = AdditiveShare<S>>,
@@ -42,11 +73,15 @@ where
     let shares = shares.into_iter();
     let ctx_z = ctx.narrow(&OPRFShuffleStep::GenerateZ);
     let zs = generate_random_tables_with_peers(shares.len(), &ctx_z);
+    let perms = SharedPermutations::new(
+        &ctx.narrow(&OPRFShuffleStep::ApplyPermutations),
+        u32::try_from(shares.len()).expect("number of shares fits into u32"),
+    );
 
     match ctx.role() {
-        Role::H1 => run_h1(&ctx, shares.len(), shares, zs).await,
-        Role::H2 => run_h2(&ctx, shares.len(), shares, zs).await,
-        Role::H3 => run_h3(&ctx, shares.len(), zs).await,
+        Role::H1 => run_h1(&ctx, shares.len(), shares, zs, &perms).await,
+        Role::H2 => run_h2(&ctx, shares.len(), shares, zs, &perms).await,
+        Role::H3 => run_h3(&ctx, shares.len(), zs, &perms).await,
     }
 }
 
@@ -55,7 +90,8 @@ async fn run_h1<C, I, S, Zl, Zr>(
     batch_size: usize,
     shares: I,
     (z_31, z_12): (Zl, Zr),
-) -> Result<Vec<AdditiveShare<S>>, Error>
+    perms: &SharedPermutations,
+) -> Result<(Vec<AdditiveShare<S>>, IntermediateMessages<S>), Error>
 where
     C: Context,
     I: IntoIterator<Item = AdditiveShare<S>>,
@@ -78,17 +114,15 @@ where
         .map(|s: AdditiveShare<S>| s.left().add(s.right()));
     let mut x_1: Vec<S> = add_single_shares(a_add_b_iter, z_12).collect();
 
-    let ctx_perm = ctx.narrow(&OPRFShuffleStep::ApplyPermutations);
-    let (mut rng_perm_l, mut rng_perm_r) = ctx_perm.prss_rng();
-    x_1.shuffle(&mut rng_perm_r);
+    perms.right.apply(&mut x_1);
 
-    let mut x_2 = x_1;
+    let mut x_2 = x_1.clone();
     add_single_shares_in_place(&mut x_2, z_31);
-    x_2.shuffle(&mut rng_perm_l);
+    perms.left.apply(&mut x_2);
     send_to_peer(&x_2, ctx, &OPRFShuffleStep::TransferX2, Direction::Right).await?;
 
     let res = combine_single_shares(a_hat, b_hat).collect::<Vec<_>>();
-    Ok(res)
+    Ok((res, IntermediateMessages::H1 { x1: x_1 }))
 }
 
 async fn run_h2<C, I, S, Zl, Zr>(
@@ -96,7 +130,8 @@ async fn run_h2<C, I, S, Zl, Zr>(
     batch_size: usize,
     shares: I,
     (z_12, z_23): (Zl, Zr),
-) -> Result<Vec<AdditiveShare<S>>, Error>
+    perms: &SharedPermutations,
+) -> Result<(Vec<AdditiveShare<S>>, IntermediateMessages<S>), Error>
 where
     C: Context,
     I: IntoIterator<Item = AdditiveShare<S>>,
@@ -116,9 +151,7 @@ where
     let c = shares.into_iter().map(|s| s.right());
     let mut y_1: Vec<S> = add_single_shares(c, z_12).collect();
 
-    let ctx_perm = ctx.narrow(&OPRFShuffleStep::ApplyPermutations);
-    let (mut rng_perm_l, mut rng_perm_r) = ctx_perm.prss_rng();
-    y_1.shuffle(&mut rng_perm_l);
+    perms.left.apply(&mut y_1);
 
     let mut x_2: Vec<S> = Vec::with_capacity(batch_size);
     future::try_join(
@@ -133,9 +166,9 @@ where
     )
     .await?;
 
-    let mut x_3 = x_2;
+    let mut x_3 = x_2.clone();
     add_single_shares_in_place(&mut x_3, z_23);
-    x_3.shuffle(&mut rng_perm_r);
+    perms.right.apply(&mut x_3);
 
     let mut c_hat_1 = repurpose_allocation(y_1);
     c_hat_1.extend(add_single_shares(x_3.iter(), b_hat.iter()));
@@ -160,14 +193,15 @@ where
 
     let c_hat = add_single_shares(c_hat_1.iter(), c_hat_2.iter());
     let res = combine_single_shares(b_hat, c_hat).collect::<Vec<_>>();
-    Ok(res)
+    Ok((res, IntermediateMessages::H2 { x2: x_2 }))
 }
 
 async fn run_h3<C, S, Zl, Zr>(
     ctx: &C,
     batch_size: usize,
     (z_23, z_31): (Zl, Zr),
-) -> Result<Vec<AdditiveShare<S>>, Error>
+    perms: &SharedPermutations,
+) -> Result<(Vec<AdditiveShare<S>>, IntermediateMessages<S>), Error>
 where
     C: Context,
     S: SharedValue + Add<Output = S>,
@@ -192,16 +226,14 @@ where
     )
     .await?;
 
-    let mut y_2 = y_1;
+    let mut y_2 = y_1.clone();
     add_single_shares_in_place(&mut y_2, z_31);
 
-    let ctx_perm = ctx.narrow(&OPRFShuffleStep::ApplyPermutations);
-    let (mut rng_perm_l, mut rng_perm_r) = ctx_perm.prss_rng();
-    y_2.shuffle(&mut rng_perm_r);
+    perms.right.apply(&mut y_2);
 
-    let mut y_3 = y_2;
+    let mut y_3 = y_2.clone();
     add_single_shares_in_place(&mut y_3, z_23);
-    y_3.shuffle(&mut rng_perm_l);
+    perms.left.apply(&mut y_3);
 
     let c_hat_2: Vec<S> = add_single_shares(y_3.iter(), a_hat.iter()).collect();
     let mut c_hat_1 = repurpose_allocation(y_3);
@@ -224,7 +256,7 @@ where
 
     let c_hat = add_single_shares(c_hat_1, c_hat_2);
     let res = combine_single_shares(c_hat, a_hat).collect::<Vec<_>>();
-    Ok(res)
+    Ok((res, IntermediateMessages::H3 { y1: y_1, y2: y_2 }))
 }
 
 fn add_single_shares<A, B, S, L, R>(l: L, r: R) -> impl Iterator<Item = S>
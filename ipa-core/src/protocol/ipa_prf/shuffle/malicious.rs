@@ -0,0 +1,298 @@
+use futures::future::try_join;
+use generic_array::GenericArray;
+use ipa_macros::Step;
+use sha2::{Digest, Sha256};
+
+use super::{
+    base::{shuffle_with_messages, IntermediateMessages},
+    MAX_DATA_BITS,
+};
+use crate::{
+    error::Error,
+    ff::{boolean_array::BA256, Gf32Bit, Serializable},
+    helpers::{Direction, Role},
+    protocol::{
+        basics::{Reveal, SumOfProducts},
+        context::Context,
+        prss::SharedRandomness,
+        RecordId,
+    },
+    secret_sharing::replicated::{semi_honest::AdditiveShare, ReplicatedSecretSharing},
+    seq_join::SeqJoin,
+};
+
+const CHUNK_BYTES: usize = 4;
+const DATA_CHUNKS: usize = MAX_DATA_BITS / 32;
+
+#[derive(Step)]
+pub(crate) enum Step {
+    GenerateMacKeys,
+    ComputeTags,
+    Shuffle,
+    RevealMacKeys,
+    VerifyX1,
+    VerifyX2,
+    VerifyOutput,
+}
+
+/// Shuffles `rows` like [`shuffle`] does, but detects deviations from the protocol by any single
+/// helper.
+///
+/// Before shuffling, every row is extended with a tag, which is the inner product of its data
+/// with a secret-shared random key. Tags can only be forged by someone who knows the key, and
+/// the key is revealed only after the shuffle is complete. Then, helpers check that every message
+/// exchanged during the shuffle, as well as the output, consists of rows with valid tags. Checks
+/// compare hashes of values that two helpers compute independently, so every message sent by a
+/// helper is checked by two other helpers.
+///
+/// Bits of every row starting from [`MAX_DATA_BITS`] must be zero, they are used for the tag and
+/// are zero in the output as well.
+///
+/// ## Errors
+/// If the shuffle could not be verified or communication with other helpers fails.
+///
+/// [`shuffle`]: super::shuffle
+pub async fn malicious_shuffle<C>(
+    ctx: C,
+    rows: Vec<AdditiveShare<BA256>>,
+) -> Result<Vec<AdditiveShare<BA256>>, Error>
+where
+    C: Context,
+{
+    let keys = {
+        let ctx = ctx.narrow(&Step::GenerateMacKeys);
+        let mut keys = vec![AdditiveShare::<Gf32Bit>::ZERO; DATA_CHUNKS];
+        ctx.prss().fill_replicated(0, &mut keys);
+        keys
+    };
+
+    let tags_ctx = ctx.narrow(&Step::ComputeTags).set_total_records(rows.len());
+    let tags = ctx
+        .try_join(
+            rows.iter().enumerate().map(|(i, row)| {
+                let ctx = tags_ctx.clone();
+                let chunks = data_chunks(row);
+                let keys = &keys;
+                async move {
+                    AdditiveShare::sum_of_products(ctx, RecordId::from(i), &chunks, keys).await
+                }
+            }),
+        )
+        .await?;
+    let tagged = rows
+        .into_iter()
+        .zip(tags)
+        .map(|(row, tag)| {
+            AdditiveShare::new(
+                row.left() + tag_only(tag.left()),
+                row.right() + tag_only(tag.right()),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let (shuffled, messages) = shuffle_with_messages(ctx.narrow(&Step::Shuffle), tagged).await?;
+
+    let reveal_ctx = ctx
+        .narrow(&Step::RevealMacKeys)
+        .set_total_records(keys.len());
+    let keys = ctx
+        .try_join(
+            keys.iter()
+                .enumerate()
+                .map(|(i, key)| key.reveal(reveal_ctx.clone(), RecordId::from(i))),
+        )
+        .await?;
+    verify(&ctx, &keys, &shuffled, messages).await?;
+
+    Ok(shuffled
+        .into_iter()
+        .map(|row| AdditiveShare::new(without_tag(row.left()), without_tag(row.right())))
+        .collect())
+}
+
+async fn verify<C: Context>(
+    ctx: &C,
+    keys: &[Gf32Bit],
+    shuffled: &[AdditiveShare<BA256>],
+    messages: IntermediateMessages<BA256>,
+) -> Result<(), Error> {
+    match (ctx.role(), messages) {
+        (Role::H1, IntermediateMessages::H1 { x1 }) => {
+            // `x_1 + y_1` and the output are shuffled input rows, so their tags must be zero.
+            // H3 has `y_1` and both H2 and H3 have `c_hat`.
+            let (y1, c_hat_h2, c_hat_h3) = (
+                receive_hash(ctx, &Step::VerifyX1, Direction::Left),
+                receive_hash(ctx, &Step::VerifyOutput, Direction::Right),
+                receive_hash(ctx, &Step::VerifyOutput, Direction::Left),
+            );
+            let (y1, (c_hat_h2, c_hat_h3)) = try_join(y1, try_join(c_hat_h2, c_hat_h3)).await?;
+            let a_hat_b_hat = hash_tags(keys, shuffled.iter().map(|s| s.left() + s.right()));
+            check(hash_tags(keys, x1), y1, "x_1 does not match y_1")?;
+            check(a_hat_b_hat, c_hat_h2, "output does not match c_hat of H2")?;
+            check(a_hat_b_hat, c_hat_h3, "output does not match c_hat of H3")
+        }
+        (Role::H2, IntermediateMessages::H2 { x2 }) => {
+            let c_hat = hash_tags(keys, shuffled.iter().map(ReplicatedSecretSharing::right));
+            let (y2, ()) = try_join(
+                receive_hash(ctx, &Step::VerifyX2, Direction::Right),
+                send_hash(ctx, &Step::VerifyOutput, Direction::Left, c_hat),
+            )
+            .await?;
+            check(hash_tags(keys, x2), y2, "x_2 does not match y_2")
+        }
+        (Role::H3, IntermediateMessages::H3 { y1, y2 }) => {
+            let c_hat = hash_tags(keys, shuffled.iter().map(ReplicatedSecretSharing::left));
+            try_join(
+                send_hash(ctx, &Step::VerifyX1, Direction::Right, hash_tags(keys, y1)),
+                try_join(
+                    send_hash(ctx, &Step::VerifyX2, Direction::Left, hash_tags(keys, y2)),
+                    send_hash(ctx, &Step::VerifyOutput, Direction::Right, c_hat),
+                ),
+            )
+            .await?;
+            Ok(())
+        }
+        (role, _) => unreachable!("{role:?} received messages of another helper"),
+    }
+}
+
+fn check(expected: BA256, actual: BA256, what: &str) -> Result<(), Error> {
+    if expected == actual {
+        Ok(())
+    } else {
+        Err(Error::ShuffleVerificationFailed(what.to_string()))
+    }
+}
+
+/// Splits the data part of a row into 32-bit chunks.
+fn data_chunks(row: &AdditiveShare<BA256>) -> Vec<AdditiveShare<Gf32Bit>> {
+    let (left, right) = (to_bytes(row.left()), to_bytes(row.right()));
+    (0..DATA_CHUNKS)
+        .map(|i| AdditiveShare::new(chunk(&left, i), chunk(&right, i)))
+        .collect()
+}
+
+fn chunk(bytes: &GenericArray<u8, <BA256 as Serializable>::Size>, i: usize) -> Gf32Bit {
+    Gf32Bit::deserialize(GenericArray::from_slice(
+        &bytes[i * CHUNK_BYTES..(i + 1) * CHUNK_BYTES],
+    ))
+}
+
+fn to_bytes(v: BA256) -> GenericArray<u8, <BA256 as Serializable>::Size> {
+    let mut buf = GenericArray::default();
+    v.serialize(&mut buf);
+    buf
+}
+
+/// Returns a row that holds `tag` and no data.
+fn tag_only(tag: Gf32Bit) -> BA256 {
+    let mut bytes = GenericArray::default();
+    tag.serialize(GenericArray::from_mut_slice(
+        &mut bytes[DATA_CHUNKS * CHUNK_BYTES..],
+    ));
+    BA256::deserialize(&bytes)
+}
+
+fn without_tag(row: BA256) -> BA256 {
+    let mut bytes = to_bytes(row);
+    bytes[DATA_CHUNKS * CHUNK_BYTES..].fill(0);
+    BA256::deserialize(&bytes)
+}
+
+/// Hashes the tag of every row, recomputed from its data and compared with the tag it carries.
+/// The result is linear, so for rows that sum up to valid rows, values being hashed are the same.
+fn hash_tags<I: IntoIterator<Item = BA256>>(keys: &[Gf32Bit], rows: I) -> BA256 {
+    let mut hasher = Sha256::new();
+    let mut buf = GenericArray::<u8, <Gf32Bit as Serializable>::Size>::default();
+    for row in rows {
+        let bytes = to_bytes(row);
+        let tag = (0..DATA_CHUNKS).fold(chunk(&bytes, DATA_CHUNKS), |acc, i| {
+            acc + keys[i] * chunk(&bytes, i)
+        });
+        tag.serialize(&mut buf);
+        hasher.update(buf);
+    }
+
+    BA256::deserialize(GenericArray::from_slice(&hasher.finalize()))
+}
+
+async fn send_hash<C: Context>(
+    ctx: &C,
+    step: &Step,
+    direction: Direction,
+    hash: BA256,
+) -> Result<(), Error> {
+    ctx.narrow(step)
+        .set_total_records(1)
+        .send_channel(ctx.role().peer(direction))
+        .send(RecordId::FIRST, hash)
+        .await?;
+    Ok(())
+}
+
+async fn receive_hash<C: Context>(
+    ctx: &C,
+    step: &Step,
+    direction: Direction,
+) -> Result<BA256, Error> {
+    Ok(ctx
+        .narrow(step)
+        .set_total_records(1)
+        .recv_channel(ctx.role().peer(direction))
+        .receive(RecordId::FIRST)
+        .await?)
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use super::{malicious_shuffle, MAX_DATA_BITS};
+    use crate::{
+        error::Error,
+        ff::{boolean_array::BA256, ArrayAccess, Field},
+        test_fixture::{Reconstruct, Runner, TestWorld},
+    };
+
+    fn rows() -> Vec<BA256> {
+        (1..=50_u128)
+            .map(|i| BA256::truncate_from(i << 64 | i))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn shuffles_rows() {
+        let records = rows();
+        let actual: Vec<BA256> = TestWorld::default()
+            .semi_honest(records.clone().into_iter(), |ctx, rows| async move {
+                malicious_shuffle(ctx, rows).await.unwrap()
+            })
+            .await
+            .reconstruct();
+
+        assert_ne!(records, actual);
+        let mut actual = actual.iter().map(Field::as_u128).collect::<Vec<_>>();
+        actual.sort_unstable();
+        assert_eq!(
+            records.iter().map(Field::as_u128).collect::<Vec<_>>(),
+            actual
+        );
+    }
+
+    /// Rows that carry data where the tag goes look as if they were corrupted during the
+    /// shuffle.
+    #[tokio::test]
+    async fn detects_corrupted_rows() {
+        let mut records = rows();
+        records[7].set(MAX_DATA_BITS + 3, true.into());
+
+        let [h1, h2, h3] = TestWorld::default()
+            .semi_honest(records.into_iter(), |ctx, rows| async move {
+                malicious_shuffle(ctx, rows).await
+            })
+            .await;
+
+        // H3 only sends hashes for others to check
+        assert!(matches!(h1, Err(Error::ShuffleVerificationFailed(_))));
+        assert!(matches!(h2, Err(Error::ShuffleVerificationFailed(_))));
+        assert!(h3.is_ok());
+    }
+}
@@ -0,0 +1,306 @@
+//! Oblivious shuffle of OPRF IPA input rows.
+//!
+//! Rows are shuffled before PRF values are revealed, so that helpers can't link revealed PRF
+//! values to the rows they received from report collectors. Every share of a row is packed into
+//! a single [`BA256`] value, which is then shuffled by a three-party protocol that applies two
+//! permutations known to each pair of helpers and re-shares the result.
+use generic_array::GenericArray;
+use typenum::Unsigned;
+
+use crate::{
+    error::Error,
+    ff::{
+        boolean::Boolean,
+        boolean_array::{BA256, BA64},
+        Serializable,
+    },
+    protocol::{context::Context, ipa_prf::prf_sharding::SourcePriority},
+    report::OprfReport,
+    secret_sharing::{
+        replicated::{semi_honest::AdditiveShare as Replicated, ReplicatedSecretSharing},
+        WeakSharedValue,
+    },
+};
+
+mod base;
+#[cfg(feature = "descriptive-gate")]
+mod malicious;
+mod permutation;
+
+pub use base::shuffle;
+#[cfg(feature = "descriptive-gate")]
+pub use malicious::malicious_shuffle;
+pub use permutation::{Permutation, SharedPermutations};
+
+/// Rows are authenticated by a tag that occupies their last 32 bits when they are shuffled by
+/// [`malicious_shuffle`], so only the rest of the row can carry data.
+pub const MAX_DATA_BITS: usize = 224;
+
+/// Shuffles OPRF IPA input rows. This protocol is secure against semi-honest helpers only.
+///
+/// ## Errors
+/// If communication with other helpers fails.
+///
+/// ## Panics
+/// If a row does not fit into [`MAX_DATA_BITS`].
+pub async fn shuffle_inputs<C, BK, TV, TS>(
+    ctx: C,
+    input: Vec<OprfReport<BK, TV, TS>>,
+) -> Result<Vec<OprfReport<BK, TV, TS>>, Error>
+where
+    C: Context,
+    BK: WeakSharedValue,
+    TV: WeakSharedValue,
+    TS: WeakSharedValue,
+{
+    let shuffled = shuffle(ctx, input.iter().map(pack)).await?;
+    Ok(shuffled.iter().map(unpack).collect())
+}
+
+/// Shuffles OPRF IPA input rows, aborting if any helper deviates from the protocol. See
+/// [`malicious_shuffle`].
+///
+/// ## Errors
+/// If the shuffle could not be verified or communication with other helpers fails.
+///
+/// ## Panics
+/// If a row does not fit into [`MAX_DATA_BITS`].
+#[cfg(feature = "descriptive-gate")]
+pub async fn malicious_shuffle_inputs<C, BK, TV, TS>(
+    ctx: C,
+    input: Vec<OprfReport<BK, TV, TS>>,
+) -> Result<Vec<OprfReport<BK, TV, TS>>, Error>
+where
+    C: Context,
+    BK: WeakSharedValue,
+    TV: WeakSharedValue,
+    TS: WeakSharedValue,
+{
+    let shuffled = malicious_shuffle(ctx, input.iter().map(pack).collect()).await?;
+    Ok(shuffled.iter().map(unpack).collect())
+}
+
+/// Lays out values one after another, each taking a whole number of bytes.
+#[derive(Default)]
+struct RowWriter {
+    buf: GenericArray<u8, <BA256 as Serializable>::Size>,
+    offset: usize,
+}
+
+impl RowWriter {
+    fn write<V: Serializable>(mut self, v: &V) -> Self {
+        let end = self.offset + V::Size::USIZE;
+        assert!(
+            end * 8 <= MAX_DATA_BITS,
+            "row does not fit into {MAX_DATA_BITS} bits"
+        );
+        v.serialize(GenericArray::from_mut_slice(
+            &mut self.buf[self.offset..end],
+        ));
+        self.offset = end;
+        self
+    }
+
+    fn finish(self) -> BA256 {
+        BA256::deserialize(&self.buf)
+    }
+}
+
+/// Reads values written by [`RowWriter`].
+struct RowReader {
+    buf: GenericArray<u8, <BA256 as Serializable>::Size>,
+    offset: usize,
+}
+
+impl RowReader {
+    fn new(row: BA256) -> Self {
+        let mut buf = GenericArray::default();
+        row.serialize(&mut buf);
+        Self { buf, offset: 0 }
+    }
+
+    /// Reads the next value, ignoring bits above `V::BITS` that don't belong to it. Shuffled rows
+    /// are re-shared with random values, so these bits are random.
+    fn read<V: WeakSharedValue>(&mut self) -> V {
+        let end = self.offset + V::Size::USIZE;
+        let bytes = &mut self.buf[self.offset..end];
+        let bits = usize::try_from(V::BITS).unwrap();
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            let unused = (8 * (i + 1)).saturating_sub(bits).min(8);
+            *byte &= u8::MAX >> unused;
+        }
+        self.offset = end;
+
+        V::deserialize(GenericArray::from_slice(bytes))
+    }
+}
+
+fn pack<BK, TV, TS>(row: &OprfReport<BK, TV, TS>) -> Replicated<BA256>
+where
+    BK: WeakSharedValue,
+    TV: WeakSharedValue,
+    TS: WeakSharedValue,
+{
+    let share = |side: fn(&Replicated<BA64>) -> BA64, b, bk, tv, ts, p| {
+        RowWriter::default()
+            .write(&side(&row.match_key))
+            .write::<Boolean>(&b)
+            .write::<BK>(&bk)
+            .write::<TV>(&tv)
+            .write::<TS>(&ts)
+            .write::<SourcePriority>(&p)
+            .finish()
+    };
+
+    Replicated::new(
+        share(
+            Replicated::left,
+            row.is_trigger.left(),
+            row.breakdown_key.left(),
+            row.trigger_value.left(),
+            row.timestamp.left(),
+            row.priority.left(),
+        ),
+        share(
+            Replicated::right,
+            row.is_trigger.right(),
+            row.breakdown_key.right(),
+            row.trigger_value.right(),
+            row.timestamp.right(),
+            row.priority.right(),
+        ),
+    )
+}
+
+fn unpack<BK, TV, TS>(row: &Replicated<BA256>) -> OprfReport<BK, TV, TS>
+where
+    BK: WeakSharedValue,
+    TV: WeakSharedValue,
+    TS: WeakSharedValue,
+{
+    let mut left = RowReader::new(row.left());
+    let mut right = RowReader::new(row.right());
+    OprfReport {
+        match_key: Replicated::new(left.read(), right.read()),
+        is_trigger: Replicated::new(left.read(), right.read()),
+        breakdown_key: Replicated::new(left.read(), right.read()),
+        trigger_value: Replicated::new(left.read(), right.read()),
+        timestamp: Replicated::new(left.read(), right.read()),
+        priority: Replicated::new(left.read(), right.read()),
+    }
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    #[cfg(feature = "descriptive-gate")]
+    use super::malicious_shuffle_inputs;
+    use super::shuffle_inputs;
+    use crate::{
+        ff::{
+            boolean_array::{BA20, BA3, BA8},
+            Field,
+        },
+        report::OprfReport,
+        test_executor::run,
+        test_fixture::{ipa::TestRawDataRecord, Reconstruct, Runner, TestWorld},
+    };
+
+    type Row = (u128, u128, u128, u128, u128);
+
+    fn records() -> Vec<TestRawDataRecord> {
+        (0..40_u64)
+            .map(|i| TestRawDataRecord {
+                timestamp: 1000 + 7 * i,
+                user_id: i / 3 + (1 << 40),
+                is_trigger_report: i % 2 == 0,
+                breakdown_key: i % 8,
+                trigger_value: i % 5,
+            })
+            .collect()
+    }
+
+    fn reconstruct(rows: &[Vec<OprfReport<BA8, BA3, BA20>>; 3]) -> Vec<Row> {
+        let [h1, h2, h3] = rows;
+        (0..h1.len())
+            .map(|i| {
+                (
+                    [&h1[i].match_key, &h2[i].match_key, &h3[i].match_key]
+                        .reconstruct()
+                        .as_u128(),
+                    [&h1[i].is_trigger, &h2[i].is_trigger, &h3[i].is_trigger]
+                        .reconstruct()
+                        .as_u128(),
+                    [
+                        &h1[i].breakdown_key,
+                        &h2[i].breakdown_key,
+                        &h3[i].breakdown_key,
+                    ]
+                    .reconstruct()
+                    .as_u128(),
+                    [
+                        &h1[i].trigger_value,
+                        &h2[i].trigger_value,
+                        &h3[i].trigger_value,
+                    ]
+                    .reconstruct()
+                    .as_u128(),
+                    [&h1[i].timestamp, &h2[i].timestamp, &h3[i].timestamp]
+                        .reconstruct()
+                        .as_u128(),
+                )
+            })
+            .collect()
+    }
+
+    fn check(shuffled: &[Vec<OprfReport<BA8, BA3, BA20>>; 3]) {
+        let mut expected = records()
+            .into_iter()
+            .map(|r| {
+                (
+                    u128::from(r.user_id),
+                    u128::from(r.is_trigger_report),
+                    u128::from(r.breakdown_key),
+                    u128::from(r.trigger_value),
+                    u128::from(r.timestamp),
+                )
+            })
+            .collect::<Vec<_>>();
+        let mut actual = reconstruct(shuffled);
+        assert_ne!(expected, actual, "rows must be shuffled");
+
+        expected.sort_unstable();
+        actual.sort_unstable();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn semi_honest() {
+        run(|| async {
+            let shuffled = TestWorld::default()
+                .semi_honest(
+                    records().into_iter(),
+                    |ctx, rows: Vec<OprfReport<BA8, BA3, BA20>>| async move {
+                        shuffle_inputs(ctx, rows).await.unwrap()
+                    },
+                )
+                .await;
+            check(&shuffled);
+        });
+    }
+
+    #[cfg(feature = "descriptive-gate")]
+    #[test]
+    fn malicious() {
+        run(|| async {
+            let shuffled = TestWorld::default()
+                .semi_honest(
+                    records().into_iter(),
+                    |ctx, rows: Vec<OprfReport<BA8, BA3, BA20>>| async move {
+                        malicious_shuffle_inputs(ctx, rows).await.unwrap()
+                    },
+                )
+                .await;
+            check(&shuffled);
+        });
+    }
+}
@@ -0,0 +1,190 @@
+//! Permutations applied by the shuffle.
+//!
+//! Every pair of helpers shares a permutation that the third helper doesn't know. Shuffles apply
+//! each of them in turn and re-share the output, so no helper can link input rows to output rows.
+//! Pairs don't need to agree on permutations: both of them derive the permutation from the
+//! randomness they share through PRSS, which is different for every query and every step.
+use rand::{seq::SliceRandom, Rng};
+
+use crate::protocol::{
+    basics::apply_permutation::{apply, apply_inv},
+    context::Context,
+};
+
+/// Permutation of `0..len`. Applying it reorders values `(v_0, ..., v_(len-1))` into
+/// `(v_σ(0), ..., v_σ(len-1))`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Permutation(Vec<u32>);
+
+impl Permutation {
+    #[must_use]
+    pub fn identity(len: u32) -> Self {
+        Self((0..len).collect())
+    }
+
+    /// Draws a permutation of `0..len` uniformly at random with the Fisher-Yates shuffle. Applying
+    /// it reorders values the same way [`SliceRandom::shuffle`] would, given the same `rng`.
+    #[must_use]
+    pub fn random<R: Rng>(len: u32, rng: &mut R) -> Self {
+        let mut this = Self::identity(len);
+        this.0.shuffle(rng);
+        this
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    #[must_use]
+    pub fn as_slice(&self) -> &[u32] {
+        &self.0
+    }
+
+    /// Reorders `values` according to this permutation.
+    ///
+    /// ## Panics
+    /// In debug builds, if `values` and the permutation have different lengths.
+    pub fn apply<T>(&self, values: &mut [T]) {
+        apply(&self.0, values);
+    }
+
+    /// Undoes [`Self::apply`].
+    ///
+    /// ## Panics
+    /// If `values` and the permutation have different lengths.
+    pub fn apply_inv<T>(&self, values: &mut [T]) {
+        apply_inv(&self.0, values);
+    }
+
+    /// Returns the permutation that undoes this one.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)] // permutations have at most `u32::MAX` elements
+    pub fn inverse(&self) -> Self {
+        let mut inverse = vec![0; self.len()];
+        for (i, &p) in self.0.iter().enumerate() {
+            inverse[p as usize] = i as u32;
+        }
+        Self(inverse)
+    }
+
+    /// Returns the permutation that reorders values the same way as applying this permutation and
+    /// then `next` does.
+    ///
+    /// ## Panics
+    /// In debug builds, if permutations have different lengths.
+    #[must_use]
+    pub fn then(&self, next: &Self) -> Self {
+        let mut composed = self.0.clone();
+        apply(&next.0, &mut composed);
+        Self(composed)
+    }
+}
+
+/// Permutations this helper shares with its peers: one known to its left peer and one known to
+/// its right peer.
+#[derive(Clone, Debug)]
+pub struct SharedPermutations {
+    pub left: Permutation,
+    pub right: Permutation,
+}
+
+impl SharedPermutations {
+    /// Derives permutations of `0..len` from PRSS. The peer on the left gets the same permutation
+    /// as `right` of its own, and the peer on the right the same as `left`, as long as they use
+    /// a context narrowed to the same step.
+    ///
+    /// ## Panics
+    /// If PRSS randomness is requested more than once for the step `ctx` is narrowed to.
+    #[must_use]
+    pub fn new<C: Context>(ctx: &C, len: u32) -> Self {
+        let (mut left, mut right) = ctx.prss_rng();
+        Self {
+            left: Permutation::random(len, &mut left),
+            right: Permutation::random(len, &mut right),
+        }
+    }
+
+    /// Returns the permutations in the form the sort protocol's `shuffle_shares` accepts.
+    #[must_use]
+    pub fn as_slices(&self) -> (&[u32], &[u32]) {
+        (self.left.as_slice(), self.right.as_slice())
+    }
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use rand::{rngs::StdRng, seq::SliceRandom, Rng, RngCore, SeedableRng};
+
+    use super::{Permutation, SharedPermutations};
+    use crate::{
+        protocol::context::Context,
+        rand::thread_rng,
+        test_fixture::{TestWorld, TestWorldConfig},
+    };
+
+    const LEN: u32 = 50;
+
+    fn random_values() -> Vec<u64> {
+        let mut rng = thread_rng();
+        (0..LEN).map(|_| rng.next_u64()).collect()
+    }
+
+    #[test]
+    fn matches_slice_shuffle() {
+        let seed = thread_rng().gen();
+        let mut expected = random_values();
+        let mut actual = expected.clone();
+        expected.shuffle(&mut StdRng::from_seed(seed));
+        Permutation::random(LEN, &mut StdRng::from_seed(seed)).apply(&mut actual);
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn composition() {
+        let mut rng = thread_rng();
+        let (sigma, rho) = (
+            Permutation::random(LEN, &mut rng),
+            Permutation::random(LEN, &mut rng),
+        );
+        let values = random_values();
+
+        let mut one_by_one = values.clone();
+        sigma.apply(&mut one_by_one);
+        rho.apply(&mut one_by_one);
+        let mut composed = values.clone();
+        sigma.then(&rho).apply(&mut composed);
+        assert_eq!(one_by_one, composed);
+
+        assert_eq!(Permutation::identity(LEN), sigma.then(&sigma.inverse()));
+        assert_eq!(Permutation::identity(LEN), sigma.inverse().then(&sigma));
+
+        let mut inverted = composed;
+        sigma.then(&rho).apply_inv(&mut inverted);
+        assert_eq!(values, inverted);
+    }
+
+    #[tokio::test]
+    async fn shared_with_peers() {
+        let world = TestWorld::new_with(TestWorldConfig::default().with_seed(42));
+        let [p1, p2, p3] = world
+            .contexts()
+            .map(|ctx| SharedPermutations::new(&ctx.narrow("permutations"), LEN));
+
+        assert_eq!(p1.right, p2.left);
+        assert_eq!(p2.right, p3.left);
+        assert_eq!(p3.right, p1.left);
+        assert_ne!(p1.left, p1.right);
+
+        // different steps produce different permutations
+        let [other, _, _] = world
+            .contexts()
+            .map(|ctx| SharedPermutations::new(&ctx.narrow("other"), LEN));
+        assert_ne!(p1.left, other.left);
+    }
+}
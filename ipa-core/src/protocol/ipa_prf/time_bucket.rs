@@ -0,0 +1,605 @@
+//! Public constants for calendar-aligned time bucketing.
+//!
+//! Report timestamps are secret-shared, so assigning a report to a time slice has to be done
+//! obliviously. The circuit compares the timestamp against a sorted list of public bucket
+//! boundaries and sums the comparison bits: `bucket(ts) = |{ b in boundaries : ts >= b }|`.
+//! This module computes these boundaries from query-level parameters, i.e. the UTC instant that
+//! corresponds to timestamp 0, the bucket size and the UTC offset(s) of the collector's time zone.
+//!
+//! Boundaries are aligned to local time, so daily buckets start at local midnight even if the
+//! time zone observes daylight saving time. Days in which clocks move are 23 or 25 hours long,
+//! and hours that do not exist in local time (spring forward) produce no bucket.
+//!
+//! The circuit itself is [`compute_time_bucket`]. Aggregation keyed by breakdown key and time
+//! bucket appends its output to the breakdown key bits, see [`attribute_cap_aggregate`].
+//!
+//! [`attribute_cap_aggregate`]: crate::protocol::ipa_prf::prf_sharding::attribute_cap_aggregate
+use std::num::NonZeroU32;
+
+use crate::{
+    ff::{boolean::Boolean, CustomArray, Field},
+    protocol::{
+        basics::ShareKnownValue, context::Context,
+        ipa_prf::boolean_ops::comparison_and_subtraction_sequential::compare_gt, step::BitOpStep,
+        RecordId,
+    },
+    secret_sharing::{
+        replicated::semi_honest::AdditiveShare as Replicated, BitDecomposed, WeakSharedValue,
+    },
+};
+
+const SECONDS_IN_HOUR: i64 = 3600;
+const SECONDS_IN_DAY: i64 = 24 * SECONDS_IN_HOUR;
+
+/// Largest UTC offset supported. Real-world time zones are within [-12h, +14h].
+pub const MAX_UTC_OFFSET_SECONDS: i32 = 14 * 3600;
+
+/// Latest query epoch supported, 9999-12-31T23:59:59Z. Bucket boundaries past it can't overflow.
+pub const MAX_QUERY_EPOCH: u64 = 253_402_300_799;
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum Error {
+    #[error("UTC offset {0} seconds is outside of the supported range of +/- 14 hours")]
+    BadUtcOffset(i32),
+    #[error("UTC offset schedule must not be empty")]
+    EmptySchedule,
+    #[error("UTC offset changes must be sorted and unique, but {prev} is followed by {next}")]
+    UnsortedSchedule { prev: u64, next: u64 },
+    #[error("UTC offset change at {0} is too far in the future")]
+    BadOffsetChange(u64),
+    #[error(
+        "Query epoch {0} is after the latest supported one, {}",
+        MAX_QUERY_EPOCH
+    )]
+    BadQueryEpoch(u64),
+    #[error("Timestamps must be at least 1 bit and at most 32 bits wide, got {0}")]
+    BadTimestampWidth(u32),
+}
+
+/// ## Errors
+/// If `utc_offset_seconds` is outside of +/- [`MAX_UTC_OFFSET_SECONDS`].
+pub fn check_utc_offset(utc_offset_seconds: i32) -> Result<(), Error> {
+    if (-MAX_UTC_OFFSET_SECONDS..=MAX_UTC_OFFSET_SECONDS).contains(&utc_offset_seconds) {
+        Ok(())
+    } else {
+        Err(Error::BadUtcOffset(utc_offset_seconds))
+    }
+}
+
+/// ## Errors
+/// If `query_epoch` is after [`MAX_QUERY_EPOCH`].
+pub fn check_query_epoch(query_epoch: u64) -> Result<(), Error> {
+    if query_epoch <= MAX_QUERY_EPOCH {
+        Ok(())
+    } else {
+        Err(Error::BadQueryEpoch(query_epoch))
+    }
+}
+
+/// Size of a single time slice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BucketSize {
+    /// Slices that start at the beginning of a local hour.
+    Hours(NonZeroU32),
+    /// Slices that start at local midnight. Multi-day slices are aligned to 1970-01-01.
+    Days(NonZeroU32),
+}
+
+impl BucketSize {
+    fn local_seconds(self) -> i64 {
+        match self {
+            Self::Hours(n) => i64::from(n.get()) * SECONDS_IN_HOUR,
+            Self::Days(n) => i64::from(n.get()) * SECONDS_IN_DAY,
+        }
+    }
+}
+
+/// Query-level parameters of time buckets for time zones with a fixed UTC offset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeBucketParams {
+    /// Seconds since Unix epoch, UTC, that timestamp 0 corresponds to.
+    pub query_epoch: u64,
+    pub bucket_size: BucketSize,
+    /// Local time minus UTC, in seconds.
+    pub utc_offset_seconds: i32,
+}
+
+impl TimeBucketParams {
+    /// Computes bucket boundaries for timestamps of `timestamp_bits` width.
+    ///
+    /// ## Errors
+    /// If the query epoch, the UTC offset or the timestamp width is not supported.
+    pub fn boundaries(&self, timestamp_bits: u32) -> Result<BucketBoundaries, Error> {
+        BucketBoundaries::new(
+            self.query_epoch,
+            timestamp_bits,
+            self.bucket_size,
+            &OffsetSchedule::fixed(self.utc_offset_seconds)?,
+        )
+    }
+}
+
+/// UTC offset that is in effect starting at the given UTC instant (inclusive).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OffsetChange {
+    /// Seconds since Unix epoch, UTC.
+    pub starting_at: u64,
+    /// Local time minus UTC, in seconds.
+    pub utc_offset_seconds: i32,
+}
+
+/// Public description of how local time relates to UTC over the duration of a query. The first
+/// offset applies to all instants before the first change as well.
+///
+/// Changes are kept as `(starting_at, utc_offset_seconds)` pairs, both already checked to fit
+/// into `i64`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OffsetSchedule(Vec<(i64, i64)>);
+
+impl OffsetSchedule {
+    /// Time zone that does not observe daylight saving time.
+    ///
+    /// ## Errors
+    /// If the offset is out of range.
+    pub fn fixed(utc_offset_seconds: i32) -> Result<Self, Error> {
+        Self::new(vec![OffsetChange {
+            starting_at: 0,
+            utc_offset_seconds,
+        }])
+    }
+
+    /// ## Errors
+    /// If the schedule is empty, not sorted by `starting_at`, has an offset that is out of range
+    /// or a change that can't be represented as `i64`.
+    pub fn new(changes: Vec<OffsetChange>) -> Result<Self, Error> {
+        if changes.is_empty() {
+            return Err(Error::EmptySchedule);
+        }
+        for change in &changes {
+            check_utc_offset(change.utc_offset_seconds)?;
+        }
+        if let Some(w) = changes
+            .windows(2)
+            .find(|w| w[0].starting_at >= w[1].starting_at)
+        {
+            return Err(Error::UnsortedSchedule {
+                prev: w[0].starting_at,
+                next: w[1].starting_at,
+            });
+        }
+
+        changes
+            .into_iter()
+            .map(|c| {
+                i64::try_from(c.starting_at)
+                    .map(|start| (start, i64::from(c.utc_offset_seconds)))
+                    .map_err(|_| Error::BadOffsetChange(c.starting_at))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+
+    fn offset_at(&self, utc: i64) -> i64 {
+        let i = self.0.partition_point(|&(start, _)| start <= utc);
+        self.0[i.saturating_sub(1)].1
+    }
+
+    /// Earliest UTC instant at which local time is at or past `local`. For local times that
+    /// are skipped when clocks move forward this is the moment of transition, for local times
+    /// that repeat when clocks move back this is the first occurrence.
+    fn earliest_utc_reaching(&self, local: i64) -> i64 {
+        let starts = self
+            .0
+            .iter()
+            .enumerate()
+            .map(|(i, &(start, _))| if i == 0 { i64::MIN } else { start })
+            .collect::<Vec<_>>();
+
+        self.0
+            .iter()
+            .enumerate()
+            .filter_map(|(i, &(_, offset))| {
+                let candidate = starts[i].max(local - offset);
+                match starts.get(i + 1) {
+                    Some(&end) if candidate >= end => None,
+                    _ => Some(candidate),
+                }
+            })
+            .min()
+            .expect("last offset is in effect indefinitely")
+    }
+}
+
+/// Bucket boundaries expressed in the same units as report timestamps: seconds since the
+/// query epoch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BucketBoundaries {
+    query_epoch: u64,
+    boundaries: Vec<u32>,
+}
+
+impl BucketBoundaries {
+    /// Computes bucket boundaries for timestamps of `timestamp_bits` width, where timestamp 0
+    /// corresponds to `query_epoch` seconds since Unix epoch, UTC.
+    ///
+    /// Local boundaries that were already reached before `query_epoch`, which happens if clocks
+    /// moved back shortly before it, don't start a new bucket.
+    ///
+    /// ## Errors
+    /// If timestamp width is not supported or `query_epoch` is after [`MAX_QUERY_EPOCH`].
+    pub fn new(
+        query_epoch: u64,
+        timestamp_bits: u32,
+        bucket_size: BucketSize,
+        offsets: &OffsetSchedule,
+    ) -> Result<Self, Error> {
+        if !(1..=32).contains(&timestamp_bits) {
+            return Err(Error::BadTimestampWidth(timestamp_bits));
+        }
+        check_query_epoch(query_epoch)?;
+        let epoch = i64::try_from(query_epoch).map_err(|_| Error::BadQueryEpoch(query_epoch))?;
+        let max_timestamp = (1_i64 << timestamp_bits) - 1;
+        let step = bucket_size.local_seconds();
+
+        let local_start = epoch + offsets.offset_at(epoch);
+        let mut local = (local_start.div_euclid(step) + 1) * step;
+        let mut boundaries = Vec::new();
+        loop {
+            let boundary = offsets.earliest_utc_reaching(local) - epoch;
+            if boundary > max_timestamp {
+                break;
+            }
+            // local boundaries reached at or before timestamp 0 don't start a new bucket
+            if let Ok(boundary) = u32::try_from(boundary) {
+                if boundary > 0 && boundaries.last() != Some(&boundary) {
+                    boundaries.push(boundary);
+                }
+            }
+            local += step;
+        }
+
+        Ok(Self {
+            query_epoch,
+            boundaries,
+        })
+    }
+
+    /// Sorted list of timestamps at which a new bucket starts. Bucket 0 starts at timestamp 0
+    /// and is not included.
+    #[must_use]
+    pub fn boundaries(&self) -> &[u32] {
+        &self.boundaries
+    }
+
+    /// Total number of buckets timestamps can fall into.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.boundaries.len() + 1
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// Number of bits required to represent the bucket index.
+    #[must_use]
+    pub fn bucket_index_bits(&self) -> u32 {
+        usize::BITS - self.boundaries.len().leading_zeros()
+    }
+
+    /// UTC instant (seconds since Unix epoch) at which the given bucket starts.
+    ///
+    /// ## Panics
+    /// If `bucket` is out of range.
+    #[must_use]
+    pub fn bucket_start_utc(&self, bucket: usize) -> u64 {
+        assert!(bucket < self.len(), "bucket {bucket} is out of range");
+        match bucket {
+            0 => self.query_epoch,
+            _ => self.query_epoch + u64::from(self.boundaries[bucket - 1]),
+        }
+    }
+
+    /// Computes the bucket for the given timestamp in the clear. This is what the oblivious
+    /// circuit computes over secret-shared timestamps.
+    #[must_use]
+    pub fn bucket_of(&self, timestamp: u32) -> usize {
+        self.boundaries.partition_point(|&b| b <= timestamp)
+    }
+}
+
+/// Computes the bucket of a secret-shared `timestamp`, the same one [`BucketBoundaries::bucket_of`]
+/// computes in the clear. The result has [`BucketBoundaries::bucket_index_bits`] bits, least
+/// significant first.
+///
+/// The timestamp is compared against every boundary `b_j`, `j` starting at 1, and the bucket is
+/// the number of comparisons `c_j = [ts >= b_j]` that are set. Boundaries are sorted, so these
+/// are exactly `c_1..c_i` for bucket `i`. Counting from `j - 1` to `j` flips bit `k` of the count
+/// if bit `k` of `j` differs from bit `k` of `j - 1`, so bit `k` of the bucket is the XOR of
+/// comparisons at which it flips, which takes no communication on top of the comparisons.
+///
+/// ## Errors
+/// Propagates errors from multiplications.
+pub async fn compute_time_bucket<C, TS>(
+    ctx: C,
+    record_id: RecordId,
+    timestamp: &Replicated<TS>,
+    boundaries: &BucketBoundaries,
+) -> Result<BitDecomposed<Replicated<Boolean>>, crate::error::Error>
+where
+    C: Context,
+    TS: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    for<'a> &'a Replicated<TS>: IntoIterator<Item = Replicated<Boolean>>,
+{
+    let comparisons = ctx
+        .parallel_join(boundaries.boundaries().iter().enumerate().map(|(i, &b)| {
+            // boundaries are positive, bucket 0 starts at 0
+            let before_boundary = Replicated::share_known_value(&ctx, TS::truncate_from(b - 1));
+            let ctx = ctx.narrow(&BitOpStep::from(i));
+            async move { compare_gt(ctx, record_id, timestamp, &before_boundary).await }
+        }))
+        .await?;
+
+    Ok(BitDecomposed::new((0..boundaries.bucket_index_bits()).map(
+        |k| {
+            comparisons
+                .iter()
+                .zip(1_usize..)
+                .filter(|&(_, j)| ((j ^ (j - 1)) >> k) & 1 == 1)
+                .fold(Replicated::<Boolean>::ZERO, |acc, (c, _)| &acc + c)
+        },
+    )))
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use rand::Rng;
+
+    use super::{
+        compute_time_bucket, BucketBoundaries, BucketSize, Error, OffsetChange, OffsetSchedule,
+        MAX_QUERY_EPOCH, MAX_UTC_OFFSET_SECONDS,
+    };
+    use crate::{
+        ff::{boolean::Boolean, boolean_array::BA20, Field},
+        protocol::{context::Context, RecordId},
+        rand::thread_rng,
+        test_fixture::{Reconstruct, Runner, TestWorld},
+    };
+
+    const HOUR: u32 = 3600;
+    const DAY: u32 = 24 * HOUR;
+    const PST: i32 = -8 * 3600;
+    const PDT: i32 = -7 * 3600;
+    /// 2023-03-12T10:00:00Z, US clocks move forward
+    const SPRING_FORWARD: u64 = 1_678_615_200;
+    /// 2023-11-05T09:00:00Z, US clocks move back
+    const FALL_BACK: u64 = 1_699_174_800;
+
+    fn days(n: u32) -> BucketSize {
+        BucketSize::Days(NonZeroU32::new(n).unwrap())
+    }
+
+    fn hours(n: u32) -> BucketSize {
+        BucketSize::Hours(NonZeroU32::new(n).unwrap())
+    }
+
+    fn pacific() -> OffsetSchedule {
+        OffsetSchedule::new(vec![
+            OffsetChange {
+                starting_at: 0,
+                utc_offset_seconds: PST,
+            },
+            OffsetChange {
+                starting_at: SPRING_FORWARD,
+                utc_offset_seconds: PDT,
+            },
+            OffsetChange {
+                starting_at: FALL_BACK,
+                utc_offset_seconds: PST,
+            },
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn utc_days() {
+        // 2023-01-01T12:00:00Z
+        let buckets = BucketBoundaries::new(
+            1_672_574_400,
+            20,
+            days(1),
+            &OffsetSchedule::fixed(0).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(&[DAY / 2, DAY / 2 + DAY], &buckets.boundaries()[..2]);
+        assert_eq!(13, buckets.len());
+        assert_eq!(4, buckets.bucket_index_bits());
+        assert_eq!(0, buckets.bucket_of(0));
+        assert_eq!(0, buckets.bucket_of(DAY / 2 - 1));
+        assert_eq!(1, buckets.bucket_of(DAY / 2));
+        assert_eq!(1_672_617_600, buckets.bucket_start_utc(1));
+    }
+
+    #[test]
+    fn fixed_offset_aligns_to_local_midnight() {
+        // 2023-01-01T00:00:00Z is 2023-01-01T05:30:00+05:30
+        let buckets = BucketBoundaries::new(
+            1_672_531_200,
+            20,
+            days(1),
+            &OffsetSchedule::fixed(5 * 3600 + 1800).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(DAY - (5 * HOUR + HOUR / 2), buckets.boundaries()[0]);
+        assert!(buckets.boundaries().windows(2).all(|w| w[1] - w[0] == DAY));
+    }
+
+    #[test]
+    fn spring_forward_day_is_short() {
+        // 2023-03-11T00:00:00-08:00
+        let buckets = BucketBoundaries::new(1_678_521_600, 20, days(1), &pacific()).unwrap();
+        assert_eq!(
+            &[DAY, 2 * DAY - HOUR, 3 * DAY - HOUR],
+            &buckets.boundaries()[..3]
+        );
+    }
+
+    #[test]
+    fn fall_back_day_is_long() {
+        // 2023-11-04T00:00:00-07:00
+        let buckets = BucketBoundaries::new(1_699_081_200, 20, days(1), &pacific()).unwrap();
+        assert_eq!(
+            &[DAY, 2 * DAY + HOUR, 3 * DAY + HOUR],
+            &buckets.boundaries()[..3]
+        );
+    }
+
+    #[test]
+    fn skipped_hour_has_no_bucket() {
+        // 2023-03-12T00:00:00-08:00, local 02:00-03:00 does not exist
+        let buckets = BucketBoundaries::new(1_678_608_000, 16, hours(1), &pacific()).unwrap();
+        assert_eq!(&[HOUR, 2 * HOUR, 3 * HOUR], &buckets.boundaries()[..3]);
+        assert_eq!(
+            SPRING_FORWARD,
+            buckets.bucket_start_utc(2),
+            "bucket for local 03:00 starts at transition"
+        );
+    }
+
+    #[test]
+    fn repeated_hour_is_one_bucket() {
+        // 2023-11-05T00:00:00-07:00, local 01:00-02:00 happens twice
+        let buckets = BucketBoundaries::new(1_699_167_600, 16, hours(1), &pacific()).unwrap();
+        assert_eq!(&[HOUR, 3 * HOUR, 4 * HOUR], &buckets.boundaries()[..3]);
+    }
+
+    #[tokio::test]
+    async fn semi_honest_time_bucket() {
+        let world = TestWorld::default();
+        let mut rng = thread_rng();
+        // 2023-01-01T12:00:00Z, 13 daily buckets
+        let buckets = BucketBoundaries::new(
+            1_672_574_400,
+            20,
+            days(1),
+            &OffsetSchedule::fixed(0).unwrap(),
+        )
+        .unwrap();
+        let mut timestamps = buckets
+            .boundaries()
+            .iter()
+            .flat_map(|&b| [b - 1, b])
+            .collect::<Vec<_>>();
+        timestamps.extend([0, (1 << 20) - 1, rng.gen_range(0..1 << 20)]);
+
+        let input = timestamps
+            .iter()
+            .map(|&ts| BA20::truncate_from(ts))
+            .collect::<Vec<_>>();
+        let result: Vec<Vec<Boolean>> = world
+            .semi_honest(input.into_iter(), |ctx, timestamps| {
+                let buckets = &buckets;
+                async move {
+                    let ctx = ctx.set_total_records(timestamps.len());
+                    ctx.parallel_join(timestamps.iter().enumerate().map(|(i, ts)| {
+                        let ctx = ctx.clone();
+                        async move {
+                            compute_time_bucket(ctx, RecordId::from(i), ts, buckets)
+                                .await
+                                .map(|bits| bits.to_vec())
+                        }
+                    }))
+                    .await
+                    .unwrap()
+                }
+            })
+            .await
+            .reconstruct();
+
+        for (ts, bits) in timestamps.into_iter().zip(result) {
+            assert_eq!(4, bits.len());
+            let bucket = bits
+                .iter()
+                .enumerate()
+                .map(|(k, &bit)| usize::from(bit == Boolean::ONE) << k)
+                .sum::<usize>();
+            assert_eq!(buckets.bucket_of(ts), bucket, "timestamp {ts}");
+        }
+    }
+
+    #[test]
+    fn bad_parameters() {
+        assert_eq!(Err(Error::EmptySchedule), OffsetSchedule::new(vec![]));
+        assert_eq!(
+            Err(Error::BadUtcOffset(15 * 3600)),
+            OffsetSchedule::fixed(15 * 3600)
+        );
+        assert!(matches!(
+            OffsetSchedule::new(vec![
+                OffsetChange {
+                    starting_at: 10,
+                    utc_offset_seconds: 0
+                },
+                OffsetChange {
+                    starting_at: 10,
+                    utc_offset_seconds: 3600
+                },
+            ]),
+            Err(Error::UnsortedSchedule { .. })
+        ));
+        assert_eq!(
+            Err(Error::BadOffsetChange(u64::MAX)),
+            OffsetSchedule::new(vec![OffsetChange {
+                starting_at: u64::MAX,
+                utc_offset_seconds: 0
+            }])
+        );
+        assert_eq!(
+            Err(Error::BadTimestampWidth(33)),
+            BucketBoundaries::new(0, 33, days(1), &OffsetSchedule::fixed(0).unwrap())
+        );
+        assert_eq!(
+            Err(Error::BadUtcOffset(i32::MIN)),
+            OffsetSchedule::fixed(i32::MIN)
+        );
+        for epoch in [MAX_QUERY_EPOCH + 1, u64::MAX] {
+            assert_eq!(
+                Err(Error::BadQueryEpoch(epoch)),
+                BucketBoundaries::new(epoch, 32, hours(1), &OffsetSchedule::fixed(0).unwrap())
+            );
+        }
+        BucketBoundaries::new(
+            MAX_QUERY_EPOCH,
+            32,
+            hours(1),
+            &OffsetSchedule::fixed(MAX_UTC_OFFSET_SECONDS).unwrap(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn boundaries_before_epoch_are_skipped() {
+        // clocks moved back by 3 hours right before the query epoch, so local times up to 3 hours
+        // past it had already been reached before it
+        let epoch = 1_000 * u64::from(DAY) + u64::from(HOUR / 2);
+        let offsets = OffsetSchedule::new(vec![
+            OffsetChange {
+                starting_at: 0,
+                utc_offset_seconds: 3 * 3600,
+            },
+            OffsetChange {
+                starting_at: epoch - 10,
+                utc_offset_seconds: 0,
+            },
+        ])
+        .unwrap();
+        let buckets = BucketBoundaries::new(epoch, 16, hours(1), &offsets).unwrap();
+        assert_eq!(
+            &[3 * HOUR + HOUR / 2, 4 * HOUR + HOUR / 2],
+            &buckets.boundaries()[..2]
+        );
+    }
+}
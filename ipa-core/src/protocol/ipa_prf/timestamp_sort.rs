@@ -0,0 +1,454 @@
+//! Oblivious sorting of user rows by timestamp.
+//!
+//! Attribution expects rows of every user to be in time order, which report collectors can't
+//! always guarantee, and which is lost once input rows are shuffled. Once PRF values are revealed,
+//! helpers know which rows belong to the same user, so every user's rows can be sorted on their
+//! own, by secret-shared timestamps, with a bitonic sorting network. The network only depends on
+//! the number of rows of a user, which helpers already know, and every comparator swaps rows
+//! obliviously, so sorting reveals nothing about timestamps or the order rows arrived in.
+//!
+//! A user with `n` rows takes `O(n log^2 n)` comparisons of timestamps, in `O(log^2 n)` layers of
+//! comparators that run in parallel. Layers of all users run together, so sorting takes as many
+//! rounds as the network of the largest user has layers. Users can have at most
+//! [`MAX_USER_ROWS`] rows, same as attribution supports. Sorting is not stable: rows of a user
+//! that share a timestamp end up in no particular order, so source events that need to win
+//! timestamp ties must have a priority.
+use futures::try_join;
+use ipa_macros::Step;
+
+use crate::{
+    error::Error,
+    ff::{boolean::Boolean, CustomArray, Expand, Field},
+    protocol::{
+        basics::SecureMul,
+        context::Context,
+        ipa_prf::{
+            boolean_ops::comparison_and_subtraction_sequential::compare_gt,
+            prf_sharding::PrfShardedIpaInputRow,
+        },
+        RecordId,
+    },
+    secret_sharing::{replicated::semi_honest::AdditiveShare as Replicated, WeakSharedValue},
+};
+
+/// Largest number of rows of a single user that can be sorted.
+pub const MAX_USER_ROWS: usize = 64;
+
+#[derive(Step)]
+pub(crate) enum SortStep {
+    /// The network for [`MAX_USER_ROWS`] rows has this many layers, smaller ones have fewer.
+    #[dynamic(21)]
+    Layer(usize),
+    CompareTimestamps,
+    SwapIsTriggerBit,
+    SwapBreakdownKey,
+    SwapTriggerValue,
+    SwapTimestamp,
+    SwapPriority,
+}
+
+/// Comparators of a bitonic sorting network for `n` elements, grouped into layers of comparators
+/// that don't share any element. Comparator `(i, j)` moves the smaller of the two elements to
+/// position `i`. Works for any `n`, not just powers of two.
+fn bitonic_network(n: usize) -> Vec<Vec<(usize, usize)>> {
+    fn push(layers: &mut Vec<Vec<(usize, usize)>>, depth: usize, comparator: (usize, usize)) {
+        if layers.len() <= depth {
+            layers.resize_with(depth + 1, Vec::new);
+        }
+        layers[depth].push(comparator);
+    }
+
+    /// Sorts `n` elements starting at `lo`, beginning at layer `depth`. Returns the first layer
+    /// after the last one it uses.
+    fn sort(
+        layers: &mut Vec<Vec<(usize, usize)>>,
+        lo: usize,
+        n: usize,
+        ascending: bool,
+        depth: usize,
+    ) -> usize {
+        if n <= 1 {
+            return depth;
+        }
+        let m = n / 2;
+        let first = sort(layers, lo, m, !ascending, depth);
+        let second = sort(layers, lo + m, n - m, ascending, depth);
+        merge(layers, lo, n, ascending, first.max(second))
+    }
+
+    /// Merges a bitonic sequence of `n` elements starting at `lo`.
+    fn merge(
+        layers: &mut Vec<Vec<(usize, usize)>>,
+        lo: usize,
+        n: usize,
+        ascending: bool,
+        depth: usize,
+    ) -> usize {
+        if n <= 1 {
+            return depth;
+        }
+        // largest power of two that is less than `n`
+        let m = 1 << (usize::BITS - 1 - (n - 1).leading_zeros());
+        for i in lo..lo + n - m {
+            push(
+                layers,
+                depth,
+                if ascending { (i, i + m) } else { (i + m, i) },
+            );
+        }
+        let first = merge(layers, lo, m, ascending, depth + 1);
+        let second = merge(layers, lo + m, n - m, ascending, depth + 1);
+        first.max(second)
+    }
+
+    let mut layers = Vec::new();
+    sort(&mut layers, 0, n, true, 0);
+    layers
+}
+
+/// Returns `(a, b)` if `swap` is a share of 0 and `(b, a)` if it is a share of 1. `swap` must be
+/// expanded to every element of `V`.
+async fn swap_if<C, V>(
+    ctx: C,
+    record_id: RecordId,
+    swap: &Replicated<V>,
+    a: &Replicated<V>,
+    b: &Replicated<V>,
+) -> Result<(Replicated<V>, Replicated<V>), Error>
+where
+    C: Context,
+    V: WeakSharedValue + Field,
+{
+    let diff = (a + b).multiply(swap, ctx, record_id).await?;
+    Ok((a + &diff, b + &diff))
+}
+
+/// Returns rows `a` and `b` of the same user in the order of their timestamps.
+async fn compare_and_swap<C, BK, TV, TS>(
+    ctx: C,
+    record_id: RecordId,
+    a: &PrfShardedIpaInputRow<BK, TV, TS>,
+    b: &PrfShardedIpaInputRow<BK, TV, TS>,
+) -> Result<
+    (
+        PrfShardedIpaInputRow<BK, TV, TS>,
+        PrfShardedIpaInputRow<BK, TV, TS>,
+    ),
+    Error,
+>
+where
+    C: Context,
+    BK: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    TV: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    TS: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    for<'a> &'a Replicated<TS>: IntoIterator<Item = Replicated<Boolean>>,
+{
+    let swap = compare_gt(
+        ctx.narrow(&SortStep::CompareTimestamps),
+        record_id,
+        &a.timestamp,
+        &b.timestamp,
+    )
+    .await?;
+
+    let priority = async {
+        match (&a.priority, &b.priority) {
+            (Some(a_priority), Some(b_priority)) => swap_if(
+                ctx.narrow(&SortStep::SwapPriority),
+                record_id,
+                &Replicated::expand(&swap),
+                a_priority,
+                b_priority,
+            )
+            .await
+            .map(|(a, b)| (Some(a), Some(b))),
+            // attribution rejects rows that only have priorities some of the time
+            _ => Ok((a.priority.clone(), b.priority.clone())),
+        }
+    };
+    let (is_trigger_bit, breakdown_key, trigger_value, timestamp, priority) = try_join!(
+        swap_if(
+            ctx.narrow(&SortStep::SwapIsTriggerBit),
+            record_id,
+            &swap,
+            &a.is_trigger_bit,
+            &b.is_trigger_bit,
+        ),
+        swap_if(
+            ctx.narrow(&SortStep::SwapBreakdownKey),
+            record_id,
+            &Replicated::<BK>::expand(&swap),
+            &a.breakdown_key,
+            &b.breakdown_key,
+        ),
+        swap_if(
+            ctx.narrow(&SortStep::SwapTriggerValue),
+            record_id,
+            &Replicated::<TV>::expand(&swap),
+            &a.trigger_value,
+            &b.trigger_value,
+        ),
+        swap_if(
+            ctx.narrow(&SortStep::SwapTimestamp),
+            record_id,
+            &Replicated::<TS>::expand(&swap),
+            &a.timestamp,
+            &b.timestamp,
+        ),
+        priority,
+    )?;
+
+    let row =
+        |is_trigger_bit, breakdown_key, trigger_value, timestamp, priority| PrfShardedIpaInputRow {
+            prf_of_match_key: a.prf_of_match_key,
+            is_trigger_bit,
+            breakdown_key,
+            trigger_value,
+            timestamp,
+            priority,
+        };
+    Ok((
+        row(
+            is_trigger_bit.0,
+            breakdown_key.0,
+            trigger_value.0,
+            timestamp.0,
+            priority.0,
+        ),
+        row(
+            is_trigger_bit.1,
+            breakdown_key.1,
+            trigger_value.1,
+            timestamp.1,
+            priority.1,
+        ),
+    ))
+}
+
+/// Sorts rows of every user by timestamp, see the [module] documentation. `rows` must be grouped
+/// by user. Users stay in the same order.
+///
+/// ## Errors
+/// Propagates errors from multiplications.
+///
+/// [module]: self
+pub async fn sort_rows_by_timestamp<C, BK, TV, TS>(
+    ctx: C,
+    rows: Vec<PrfShardedIpaInputRow<BK, TV, TS>>,
+) -> Result<Vec<PrfShardedIpaInputRow<BK, TV, TS>>, Error>
+where
+    C: Context,
+    BK: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    TV: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    TS: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+    for<'a> &'a Replicated<TS>: IntoIterator<Item = Replicated<Boolean>>,
+{
+    let mut users: Vec<Vec<PrfShardedIpaInputRow<BK, TV, TS>>> = Vec::new();
+    for row in rows {
+        match users.last_mut() {
+            Some(user) if user[0].prf_of_match_key == row.prf_of_match_key => user.push(row),
+            _ => users.push(vec![row]),
+        }
+    }
+    let max_rows = users.iter().map(Vec::len).max().unwrap_or(0);
+    if max_rows > MAX_USER_ROWS {
+        return Err(Error::Unsupported(format!(
+            "can't sort {max_rows} rows of a user by timestamp, at most {MAX_USER_ROWS} are \
+             supported"
+        )));
+    }
+
+    // users with the same number of rows are sorted by the same network
+    let networks = (0..=max_rows).map(bitonic_network).collect::<Vec<_>>();
+    let layers = networks.iter().map(Vec::len).max().unwrap_or(0);
+    for layer in 0..layers {
+        // every comparator of this layer, across all users, is a record on the step of the layer
+        let comparators = users
+            .iter()
+            .enumerate()
+            .flat_map(|(user, rows)| {
+                networks[rows.len()]
+                    .get(layer)
+                    .into_iter()
+                    .flatten()
+                    .map(move |&(i, j)| (user, i, j))
+            })
+            .collect::<Vec<_>>();
+        let ctx = ctx
+            .narrow(&SortStep::Layer(layer))
+            .set_total_records(comparators.len());
+        let sorted = ctx
+            .try_join(comparators.iter().enumerate().map(|(k, &(user, i, j))| {
+                compare_and_swap(
+                    ctx.clone(),
+                    RecordId::from(k),
+                    &users[user][i],
+                    &users[user][j],
+                )
+            }))
+            .await?;
+        for (&(user, i, j), (lo, hi)) in comparators.iter().zip(sorted) {
+            users[user][i] = lo;
+            users[user][j] = hi;
+        }
+    }
+
+    Ok(users.into_iter().flatten().collect())
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use rand::seq::SliceRandom;
+
+    use super::{bitonic_network, sort_rows_by_timestamp, MAX_USER_ROWS};
+    use crate::{
+        error::Error,
+        ff::{
+            boolean_array::{BA20, BA3, BA8},
+            Field,
+        },
+        protocol::ipa_prf::prf_sharding::PrfShardedIpaInputRow,
+        rand::thread_rng,
+        secret_sharing::replicated::semi_honest::AdditiveShare as Replicated,
+        test_fixture::{Reconstruct, Runner, TestWorld},
+    };
+
+    /// A network sorts every input if it sorts every input of zeros and ones.
+    #[test]
+    fn network_sorts() {
+        for n in 0..=12 {
+            let network = bitonic_network(n);
+            for input in 0..1_u32 << n {
+                let mut bits = (0..n).map(|i| (input >> i) & 1).collect::<Vec<_>>();
+                for layer in &network {
+                    for &(i, j) in layer {
+                        if bits[i] > bits[j] {
+                            bits.swap(i, j);
+                        }
+                    }
+                }
+                assert!(bits.windows(2).all(|w| w[0] <= w[1]), "{n}: {input:b}");
+            }
+        }
+    }
+
+    #[test]
+    fn network_layers_are_disjoint() {
+        for n in [5, 8, 13, 64] {
+            for layer in bitonic_network(n) {
+                let mut positions = layer.iter().flat_map(|&(i, j)| [i, j]).collect::<Vec<_>>();
+                positions.sort_unstable();
+                positions.dedup();
+                assert_eq!(2 * layer.len(), positions.len());
+            }
+        }
+    }
+
+    /// Every layer needs a step of its own.
+    #[test]
+    fn network_layers_fit_steps() {
+        assert_eq!(21, bitonic_network(MAX_USER_ROWS).len());
+        assert!((0..MAX_USER_ROWS).all(|n| bitonic_network(n).len() <= 21));
+    }
+
+    #[tokio::test]
+    async fn sorts_every_user() {
+        let world = TestWorld::default();
+        let mut rng = thread_rng();
+
+        // trigger values follow their timestamps, and every user keeps its rows
+        let user_rows = [1, 2, 5, 8, 3, 5];
+        let mut prfs = Vec::new();
+        let mut input = Vec::new();
+        for (user, &rows) in user_rows.iter().enumerate() {
+            let mut values = (0_u128..).take(rows).collect::<Vec<_>>();
+            values.shuffle(&mut rng);
+            prfs.extend(std::iter::repeat(u64::try_from(user).unwrap()).take(rows));
+            input.extend(
+                values
+                    .into_iter()
+                    .map(|v| (BA20::truncate_from(v * 10), BA3::truncate_from(v))),
+            );
+        }
+
+        let (timestamps, trigger_values): (Vec<_>, Vec<_>) = input.into_iter().unzip();
+
+        let (sorted_timestamps, sorted_values): (Vec<BA20>, Vec<BA3>) = world
+            .semi_honest(
+                (timestamps.into_iter(), trigger_values.into_iter()),
+                |ctx, (timestamps, trigger_values)| {
+                    let prfs = prfs.clone();
+                    async move {
+                        let rows = prfs
+                            .into_iter()
+                            .zip(timestamps.into_iter().zip(trigger_values))
+                            .map(|(prf_of_match_key, (timestamp, trigger_value))| {
+                                PrfShardedIpaInputRow {
+                                    prf_of_match_key,
+                                    is_trigger_bit: Replicated::ZERO,
+                                    breakdown_key: Replicated::<BA8>::ZERO,
+                                    trigger_value,
+                                    timestamp,
+                                    priority: None,
+                                }
+                            })
+                            .collect::<Vec<_>>();
+                        sort_rows_by_timestamp(ctx, rows)
+                            .await
+                            .unwrap()
+                            .into_iter()
+                            .map(|row| (row.timestamp, row.trigger_value))
+                            .unzip::<_, _, Vec<_>, Vec<_>>()
+                    }
+                },
+            )
+            .await
+            .reconstruct();
+
+        let mut start = 0;
+        for rows in user_rows {
+            let user = start..start + rows;
+            assert_eq!(
+                (0_u128..).take(rows).map(|v| v * 10).collect::<Vec<_>>(),
+                sorted_timestamps[user.clone()]
+                    .iter()
+                    .map(Field::as_u128)
+                    .collect::<Vec<_>>()
+            );
+            assert_eq!(
+                (0_u128..).take(rows).collect::<Vec<_>>(),
+                sorted_values[user]
+                    .iter()
+                    .map(Field::as_u128)
+                    .collect::<Vec<_>>()
+            );
+            start += rows;
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_large_users() {
+        let world = TestWorld::default();
+        let timestamps = vec![BA20::truncate_from(0_u128); MAX_USER_ROWS + 1];
+
+        world
+            .semi_honest(timestamps.into_iter(), |ctx, timestamps| async move {
+                let rows = timestamps
+                    .into_iter()
+                    .map(|timestamp| PrfShardedIpaInputRow {
+                        prf_of_match_key: 1,
+                        is_trigger_bit: Replicated::ZERO,
+                        breakdown_key: Replicated::<BA8>::ZERO,
+                        trigger_value: Replicated::<BA3>::ZERO,
+                        timestamp,
+                        priority: None,
+                    })
+                    .collect::<Vec<_>>();
+                assert!(matches!(
+                    sort_rows_by_timestamp(ctx, rows).await,
+                    Err(Error::Unsupported(_))
+                ));
+            })
+            .await;
+    }
+}
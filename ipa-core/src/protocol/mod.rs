@@ -4,6 +4,8 @@ pub mod basics;
 pub mod boolean;
 pub mod context;
 pub mod dp;
+#[cfg(feature = "descriptive-gate")]
+pub mod dzkp;
 pub mod ipa;
 pub mod ipa_prf;
 pub mod modulus_conversion;
@@ -22,6 +24,7 @@ pub use basics::BasicProtocols;
 use crate::{
     error::Error,
     ff::{Gf20Bit, Gf3Bit, Gf40Bit, Gf8Bit},
+    rand::Rng,
 };
 
 pub type MatchKey = Gf40Bit;
@@ -29,42 +32,40 @@ pub type BreakdownKey = Gf8Bit;
 pub type TriggerValue = Gf3Bit;
 pub type Timestamp = Gf20Bit;
 
-/// Unique identifier of the MPC query requested by report collectors
-/// TODO(615): Generating this unique id may be tricky as it may involve communication between helpers and
-/// them collaborating on constructing this unique id. These details haven't been flushed out yet,
-/// so for now it is just an empty struct. Once we know more about it, we will make necessary
-/// amendments to it
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+/// Unique identifier of the MPC query requested by report collectors. The helper that receives
+/// the request picks it at random and shares it with other helpers when it prepares the query, so
+/// helpers can run many queries at once without mixing up their records.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
 #[cfg_attr(
     feature = "serde",
     derive(serde::Serialize, serde::Deserialize),
-    serde(into = "&'static str", try_from = "&str")
+    serde(into = "String", try_from = "&str")
 )]
-pub struct QueryId;
+pub struct QueryId(u64);
 
-impl Display for QueryId {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        // fail when query id becomes meaningful and change the display implementation
-        let _: QueryId = QueryId;
-        write!(f, "{self:?}")
+impl QueryId {
+    /// Picks an identifier for a new query.
+    #[must_use]
+    pub fn random() -> Self {
+        Self(crate::rand::thread_rng().gen())
     }
 }
 
-impl QueryId {
-    fn repr() -> &'static str {
-        "0"
+impl Display for QueryId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
     }
 }
 
-impl AsRef<str> for QueryId {
-    fn as_ref(&self) -> &str {
-        QueryId::repr()
+impl From<u64> for QueryId {
+    fn from(v: u64) -> Self {
+        Self(v)
     }
 }
 
-impl From<QueryId> for &'static str {
-    fn from(_: QueryId) -> Self {
-        QueryId::repr()
+impl From<QueryId> for String {
+    fn from(query_id: QueryId) -> Self {
+        query_id.to_string()
     }
 }
 
@@ -72,11 +73,10 @@ impl TryFrom<&str> for QueryId {
     type Error = Error;
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        if value == QueryId::repr() {
-            Ok(QueryId)
-        } else {
-            Err(Error::path_parse_error(value))
-        }
+        value
+            .parse()
+            .map(Self)
+            .map_err(|_| Error::path_parse_error(value))
     }
 }
 
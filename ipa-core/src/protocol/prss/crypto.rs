@@ -1,6 +1,6 @@
 use aes::{
     cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit},
-    Aes256,
+    Aes256, Block,
 };
 use hkdf::Hkdf;
 use rand::{CryptoRng, RngCore};
@@ -20,6 +20,19 @@ pub trait SharedRandomness {
     #[must_use]
     fn generate_values<I: Into<u128>>(&self, index: I) -> (u128, u128);
 
+    /// Generate values of every index in `start..start + left.len()` at once, the same ones
+    /// [`Self::generate_values`] returns for each of them. Values known to the left helper go to
+    /// `left`, those known to the right helper to `right`.
+    ///
+    /// ## Panics
+    /// If `left` and `right` are not of the same length.
+    fn fill_values(&self, start: u128, left: &mut [u128], right: &mut [u128]) {
+        assert_eq!(left.len(), right.len());
+        for ((index, l), r) in (start..).zip(left.iter_mut()).zip(right.iter_mut()) {
+            (*l, *r) = self.generate_values(index);
+        }
+    }
+
     /// Generate two random field values, one that is known to the left helper
     /// and one that is known to the right helper.
     #[must_use]
@@ -28,6 +41,17 @@ pub trait SharedRandomness {
         (F::truncate_from(l), F::truncate_from(r))
     }
 
+    /// Fills `out` with field values of every index in `start..start + out.len()`, the same ones
+    /// [`Self::generate_fields`] returns for each of them.
+    fn fill_fields<F: Field>(&self, start: u128, out: &mut [(F, F)]) {
+        let mut left = vec![0; out.len()];
+        let mut right = vec![0; out.len()];
+        self.fill_values(start, &mut left, &mut right);
+        for ((v, l), r) in out.iter_mut().zip(left).zip(right) {
+            *v = (F::truncate_from(l), F::truncate_from(r));
+        }
+    }
+
     /// Generate two sequences of random Fp2 bits.
     #[must_use]
     fn generate_bit_arrays<B: GaloisField, I: Into<u128>>(&self, index: I) -> (B, B) {
@@ -49,6 +73,16 @@ pub trait SharedRandomness {
         Replicated::new(l, r)
     }
 
+    /// Fills `out` with sharings of every index in `start..start + out.len()`, the same ones
+    /// [`Self::generate_replicated`] returns for each of them.
+    fn fill_replicated<F: Field>(&self, start: u128, out: &mut [Replicated<F>]) {
+        let mut fields = vec![(F::ZERO, F::ZERO); out.len()];
+        self.fill_fields(start, &mut fields);
+        for (v, (l, r)) in out.iter_mut().zip(fields) {
+            *v = Replicated::new(l, r);
+        }
+    }
+
     /// Generate an additive share of zero.
     /// Each party generates two values, one that is shared with the party to their left,
     /// one with the party to their right.  If all entities add their left share
@@ -155,4 +189,22 @@ impl Generator {
 
         u128::from_le_bytes(buf) ^ index
     }
+
+    /// Generates values of every index in `start..start + out.len()` into `out`, the same ones
+    /// [`Self::generate`] returns for each of them. Indices are encrypted several blocks at a
+    /// time, which lets AES implementations pipeline them.
+    pub fn generate_into(&self, start: u128, out: &mut [u128]) {
+        const BLOCKS: usize = 8;
+        let mut blocks = [Block::default(); BLOCKS];
+        for (first, out) in (start..).step_by(BLOCKS).zip(out.chunks_mut(BLOCKS)) {
+            let blocks = &mut blocks[..out.len()];
+            for (index, block) in (first..).zip(blocks.iter_mut()) {
+                block.copy_from_slice(&index.to_le_bytes());
+            }
+            self.cipher.encrypt_blocks(blocks);
+            for ((index, block), v) in (first..).zip(blocks.iter()).zip(out.iter_mut()) {
+                *v = u128::from_le_bytes((*block).into()) ^ index;
+            }
+        }
+    }
 }
@@ -82,6 +82,17 @@ impl SharedRandomness for IndexedSharedRandomness {
 
         (self.left.generate(index), self.right.generate(index))
     }
+
+    fn fill_values(&self, start: u128, left: &mut [u128], right: &mut [u128]) {
+        assert_eq!(left.len(), right.len());
+        #[cfg(debug_assertions)]
+        for index in (start..).take(left.len()) {
+            self.used.insert(index);
+        }
+
+        self.left.generate_into(start, left);
+        self.right.generate_into(start, right);
+    }
 }
 
 /// An implementation of `RngCore` that uses the same underlying `Generator`.
@@ -408,6 +419,30 @@ pub mod test {
         assert_eq!(r3_l, r2_r);
     }
 
+    #[test]
+    fn generate_into() {
+        let (g1, _) = make();
+        let mut values = [0; 19];
+        g1.generate_into(5, &mut values);
+        for (index, v) in (5..).zip(values) {
+            assert_eq!(g1.generate(index), v);
+        }
+    }
+
+    #[test]
+    fn fill_values() {
+        let [p1, _, _] = participants();
+        let step = Gate::default();
+        let bulk = p1.indexed(&step.narrow("bulk"));
+        let single = p1.indexed(&step.narrow("single"));
+
+        let mut fields = [(Fp31::ZERO, Fp31::ZERO); 10];
+        bulk.fill_fields(3, &mut fields);
+        for (index, v) in (3_u128..).zip(fields) {
+            assert_eq!(single.generate_fields::<Fp31, _>(index), v);
+        }
+    }
+
     #[test]
     fn three_party_zero() {
         const IDX: u128 = 72;
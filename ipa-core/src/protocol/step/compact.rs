@@ -3,7 +3,13 @@ use std::fmt::{Debug, Display, Formatter};
 use ipa_macros::Gate;
 
 use super::StepNarrow;
-use crate::helpers::{prss_protocol::PrssExchangeStep, query::QueryType};
+use crate::{
+    helpers::{
+        heartbeat::HeartbeatStep, prss_protocol::PrssExchangeStep, query::QueryType,
+        FlowControlStep,
+    },
+    query::{CommitmentStep, ResultCacheStep},
+};
 
 #[derive(Gate, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Default)]
 #[cfg_attr(
@@ -38,13 +44,18 @@ const QUERY_TYPE_SEMIHONEST_STATE: u16 = 65533;
 const QUERY_TYPE_MALICIOUS_STATE: u16 = 65532;
 const PRSS_EXCHANGE_STATE: u16 = 65531;
 const QUERY_TYPE_OPRF_STATE: u16 = 65530;
+const HEARTBEAT_STATE: u16 = 65529;
+const RESULT_CACHE_STATE: u16 = 65528;
+const FLOW_CONTROL_STATE: u16 = 65527;
+const COMMITMENT_STATE: u16 = 65526;
 
 impl StepNarrow<QueryType> for Compact {
     fn narrow(&self, step: &QueryType) -> Self {
         match step {
             QueryType::SemiHonestIpa(_) => Self(QUERY_TYPE_SEMIHONEST_STATE),
             QueryType::MaliciousIpa(_) => Self(QUERY_TYPE_MALICIOUS_STATE),
-            QueryType::OprfIpa(_) => Self(QUERY_TYPE_OPRF_STATE),
+            // PRF-only queries run the first stage of OPRF IPA, so they share its steps.
+            QueryType::OprfIpa(_) | QueryType::OprfPseudonyms(_) => Self(QUERY_TYPE_OPRF_STATE),
             _ => panic!("cannot narrow from the invalid step {}", step.as_ref()),
         }
     }
@@ -56,6 +67,30 @@ impl StepNarrow<PrssExchangeStep> for Compact {
     }
 }
 
+impl StepNarrow<HeartbeatStep> for Compact {
+    fn narrow(&self, _step: &HeartbeatStep) -> Self {
+        Self(HEARTBEAT_STATE)
+    }
+}
+
+impl StepNarrow<ResultCacheStep> for Compact {
+    fn narrow(&self, _step: &ResultCacheStep) -> Self {
+        Self(RESULT_CACHE_STATE)
+    }
+}
+
+impl StepNarrow<FlowControlStep> for Compact {
+    fn narrow(&self, _step: &FlowControlStep) -> Self {
+        Self(FLOW_CONTROL_STATE)
+    }
+}
+
+impl StepNarrow<CommitmentStep> for Compact {
+    fn narrow(&self, _step: &CommitmentStep) -> Self {
+        Self(COMMITMENT_STATE)
+    }
+}
+
 // Reverse of `static_state_map` for `Compact::as_ref()`
 fn static_reverse_state_map(state: u16) -> &'static str {
     match state {
@@ -64,6 +99,10 @@ fn static_reverse_state_map(state: u16) -> &'static str {
         QUERY_TYPE_MALICIOUS_STATE => QueryType::MALICIOUS_IPA_STR,
         QUERY_TYPE_OPRF_STATE => QueryType::OPRF_IPA_STR,
         PRSS_EXCHANGE_STATE => PrssExchangeStep.as_ref(),
+        HEARTBEAT_STATE => HeartbeatStep.as_ref(),
+        RESULT_CACHE_STATE => ResultCacheStep.as_ref(),
+        FLOW_CONTROL_STATE => FlowControlStep.as_ref(),
+        COMMITMENT_STATE => CommitmentStep.as_ref(),
         _ => panic!("cannot as_ref() from the invalid state {state}"),
     }
 }
@@ -79,6 +118,14 @@ fn static_deserialize_state_map(s: &str) -> u16 {
         return QUERY_TYPE_OPRF_STATE;
     } else if s == PrssExchangeStep.as_ref() {
         return PRSS_EXCHANGE_STATE;
+    } else if s == HeartbeatStep.as_ref() {
+        return HEARTBEAT_STATE;
+    } else if s == ResultCacheStep.as_ref() {
+        return RESULT_CACHE_STATE;
+    } else if s == FlowControlStep.as_ref() {
+        return FLOW_CONTROL_STATE;
+    } else if s == CommitmentStep.as_ref() {
+        return COMMITMENT_STATE;
     }
 
     panic!("cannot deserialize from the invalid step \"{s}\"");
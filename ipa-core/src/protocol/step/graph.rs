@@ -0,0 +1,239 @@
+//! Graph of the gates protocols narrow their contexts into.
+//!
+//! Helpers find records and randomness of a step by its gate, so all of them must narrow their
+//! contexts into the same gates. A helper that narrows into a gate others don't use does not fail
+//! right away: it waits for records nobody sends or draws randomness nobody else draws, and the
+//! query stalls or produces garbage much later, far from the step that went wrong.
+//!
+//! Gateways record every gate contexts narrow into, along with the number of records set on it,
+//! in a [`StepRecorder`]. The [`StepGraph`] recorded by a reference run of a protocol can be
+//! exported to JSON, as a tree of steps:
+//!
+//! ```json
+//! {"steps": {"protocol": {"steps": {"run-0": {"steps": {"mul": {"records": 3}}}}}}}
+//! ```
+//!
+//! Gateways that are given the exported graph with [`StepRecorder::expect`] flag every narrow into
+//! a gate that is not in it, which catches steps that one helper takes and others don't before
+//! helpers run the protocol against each other.
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    path::Path,
+};
+
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    helpers::TotalRecords,
+    protocol::step::Gate,
+    sync::{Arc, Mutex},
+};
+
+/// Tree of gates, see the [module] documentation. Every node is a gate, its children are the
+/// gates it is narrowed into, keyed by step name.
+///
+/// [module]: self
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StepGraph {
+    /// Number of records set on this gate, if any. Gates that have been given different numbers
+    /// of records list the largest one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub records: Option<usize>,
+
+    /// Gates this gate is narrowed into.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub steps: BTreeMap<String, StepGraph>,
+}
+
+fn steps(gate: &str) -> impl Iterator<Item = &str> {
+    gate.split('/').filter(|step| !step.is_empty())
+}
+
+impl StepGraph {
+    /// Adds `gate` to this graph, along with every gate it was narrowed from.
+    pub fn insert(&mut self, gate: &str, records: Option<usize>) {
+        let node = steps(gate).fold(self, |node, step| {
+            node.steps.entry(step.to_owned()).or_default()
+        });
+        node.records = node.records.max(records);
+    }
+
+    /// Returns the node of `gate`, `None` if this graph does not have it.
+    #[must_use]
+    pub fn get(&self, gate: &str) -> Option<&StepGraph> {
+        steps(gate).try_fold(self, |node, step| node.steps.get(step))
+    }
+
+    #[must_use]
+    pub fn contains(&self, gate: &str) -> bool {
+        self.get(gate).is_some()
+    }
+
+    /// Returns all gates of this graph, in order of their names.
+    #[must_use]
+    pub fn gates(&self) -> Vec<String> {
+        fn collect(node: &StepGraph, prefix: &str, gates: &mut Vec<String>) {
+            for (step, child) in &node.steps {
+                let gate = format!("{prefix}/{step}");
+                collect(child, &gate, gates);
+                gates.push(gate);
+            }
+        }
+
+        let mut gates = Vec::new();
+        collect(self, "", &mut gates);
+        gates.sort();
+        gates
+    }
+
+    /// Serializes this graph to JSON.
+    ///
+    /// ## Panics
+    /// Never, all graphs can be serialized.
+    #[must_use]
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    /// Reads a graph exported with [`Self::to_json`].
+    ///
+    /// ## Errors
+    /// If `json` is not a valid graph.
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    /// Reads the graph exported to `path`.
+    ///
+    /// ## Errors
+    /// If the file can't be read or is not a valid graph.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::from_json(&fs::read_to_string(path)?)?)
+    }
+}
+
+/// Records gates that contexts of a query narrow into, and flags those that are missing from
+/// the graph it expects, see the [module] documentation.
+///
+/// [module]: self
+#[derive(Debug, Default)]
+pub struct StepRecorder {
+    graph: Mutex<StepGraph>,
+    expected: OnceCell<Arc<StepGraph>>,
+    unknown: Mutex<BTreeSet<String>>,
+}
+
+impl StepRecorder {
+    /// Called by contexts when they narrow into `gate` or set the number of its records.
+    pub(crate) fn observe(&self, gate: &Gate, total_records: TotalRecords) {
+        let gate = gate.as_ref();
+        self.graph
+            .lock()
+            .unwrap()
+            .insert(gate, total_records.count());
+        if let Some(expected) = self.expected.get() {
+            if !expected.contains(gate) && self.unknown.lock().unwrap().insert(gate.to_owned()) {
+                tracing::error!("narrowed into {gate}, which is not in the step graph");
+            }
+        }
+    }
+
+    /// Makes this recorder flag gates that are not in `graph`. Only the first call has an effect
+    /// and only gates narrowed into after it are checked.
+    pub fn expect(&self, graph: Arc<StepGraph>) {
+        let _ = self.expected.set(graph);
+    }
+
+    /// Returns the graph of gates recorded so far.
+    ///
+    /// ## Panics
+    /// If the lock is poisoned.
+    #[must_use]
+    pub fn graph(&self) -> StepGraph {
+        self.graph.lock().unwrap().clone()
+    }
+
+    /// Returns gates that were narrowed into, but are missing from the expected graph, in order of
+    /// their names.
+    ///
+    /// ## Panics
+    /// If the lock is poisoned.
+    #[must_use]
+    pub fn unknown_gates(&self) -> Vec<String> {
+        self.unknown.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use super::StepGraph;
+    use crate::{
+        ff::{Field, Fp31},
+        helpers::Role,
+        protocol::{basics::SecureMul, context::Context, RecordId},
+        sync::Arc,
+        test_fixture::{Runner, TestWorld},
+    };
+
+    #[test]
+    fn round_trip() {
+        let mut graph = StepGraph::default();
+        graph.insert("protocol/a/b", Some(4));
+        graph.insert("/protocol/a/b", Some(2));
+        graph.insert("protocol/c", None);
+        assert_eq!(
+            vec!["/protocol", "/protocol/a", "/protocol/a/b", "/protocol/c"],
+            graph.gates()
+        );
+        assert_eq!(Some(4), graph.get("protocol/a/b").unwrap().records);
+        assert!(!graph.contains("protocol/a/c"));
+
+        let json = graph.to_json();
+        assert_eq!(graph, StepGraph::from_json(&json).unwrap());
+        assert!(StepGraph::from_json(r#"{"steps": []}"#).is_err());
+    }
+
+    async fn multiply(world: &TestWorld, extra_step: bool) {
+        world
+            .semi_honest((Fp31::ONE, Fp31::ONE), |ctx, (a, b)| async move {
+                let ctx = ctx.narrow("mul").set_total_records(1);
+                a.multiply(&b, ctx.clone(), RecordId::FIRST).await.unwrap();
+                if extra_step {
+                    a.multiply(&b, ctx.narrow("extra"), RecordId::FIRST)
+                        .await
+                        .unwrap();
+                }
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn records_gates() {
+        let world = TestWorld::default();
+        multiply(&world, false).await;
+        let graph = world.gateway(Role::H1).steps().graph();
+        assert_eq!(Some(1), graph.get("protocol/run-0/mul").unwrap().records);
+        assert_eq!(graph, world.gateway(Role::H3).steps().graph());
+    }
+
+    #[tokio::test]
+    async fn flags_unknown_gates() {
+        let reference = TestWorld::default();
+        multiply(&reference, false).await;
+        let graph = Arc::new(reference.gateway(Role::H1).steps().graph());
+
+        let world = TestWorld::default();
+        for role in Role::all() {
+            world.gateway(*role).steps().expect(Arc::clone(&graph));
+        }
+        multiply(&world, true).await;
+        for role in Role::all() {
+            assert_eq!(
+                vec![String::from("protocol/run-0/mul/extra")],
+                world.gateway(*role).steps().unknown_gates()
+            );
+        }
+    }
+}
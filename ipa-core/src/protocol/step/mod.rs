@@ -2,11 +2,15 @@
 mod compact;
 #[cfg(feature = "descriptive-gate")]
 mod descriptive;
+#[cfg(feature = "step-graph")]
+mod graph;
 
 #[cfg(feature = "compact-gate")]
 pub use compact::Compact;
 #[cfg(feature = "descriptive-gate")]
 pub use descriptive::Descriptive;
+#[cfg(feature = "step-graph")]
+pub use graph::{StepGraph, StepRecorder};
 use ipa_macros::Step;
 
 #[cfg(feature = "descriptive-gate")]
@@ -0,0 +1,205 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures::Stream;
+use pin_project::pin_project;
+
+use crate::{
+    error::BoxError,
+    helpers::{query::QueryConfig, BytesStream},
+};
+
+/// Limits on the queries a helper admits, regardless of the resources it has available. Queries
+/// that exceed them are rejected when they are created, and inputs are cut off once they grow
+/// larger than the byte limit.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct AdmissionLimits {
+    pub max_records: u32,
+    pub max_input_bytes: u64,
+    pub max_concurrent_queries: usize,
+}
+
+/// Reasons for a helper to turn a query away.
+#[derive(Clone, Debug, thiserror::Error)]
+pub enum AdmissionError {
+    #[error("Query has {requested} records, but at most {limit} are accepted")]
+    TooManyRecords { requested: u32, limit: u32 },
+    #[error("Query input has {requested} bytes, but at most {limit} are accepted")]
+    InputTooLarge { requested: u64, limit: u64 },
+    #[error("{running} queries are already running, at most {limit} can run at the same time")]
+    TooManyQueries { running: usize, limit: usize },
+}
+
+impl AdmissionError {
+    /// Rejections that may go away if the query is submitted again later, as opposed to queries
+    /// that will never be admitted by this helper.
+    #[must_use]
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Self::TooManyQueries { .. })
+    }
+}
+
+impl AdmissionLimits {
+    /// Checks that a query with `config` can be admitted while `running` queries are in
+    /// progress on this helper.
+    ///
+    /// ## Errors
+    /// If the query is too large or too many queries are running already.
+    pub fn check(&self, config: &QueryConfig, running: usize) -> Result<(), AdmissionError> {
+        let records = u32::from(config.size);
+        if records > self.max_records {
+            return Err(AdmissionError::TooManyRecords {
+                requested: records,
+                limit: self.max_records,
+            });
+        }
+        let bytes = config.reservation().spool_bytes;
+        if bytes > self.max_input_bytes {
+            return Err(AdmissionError::InputTooLarge {
+                requested: bytes,
+                limit: self.max_input_bytes,
+            });
+        }
+        if running >= self.max_concurrent_queries {
+            return Err(AdmissionError::TooManyQueries {
+                running,
+                limit: self.max_concurrent_queries,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Returns a stream that yields the bytes of `input` and fails with
+    /// [`AdmissionError::InputTooLarge`] once it goes over the byte limit.
+    #[must_use]
+    pub fn limit_input<S: BytesStream>(&self, input: S) -> LimitedInput<S> {
+        LimitedInput {
+            inner: input,
+            received: 0,
+            limit: self.max_input_bytes,
+        }
+    }
+}
+
+#[pin_project]
+pub struct LimitedInput<S> {
+    #[pin]
+    inner: S,
+    received: u64,
+    limit: u64,
+}
+
+impl<S: BytesStream> Stream for LimitedInput<S> {
+    type Item = Result<Bytes, BoxError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        if *this.received > *this.limit {
+            return Poll::Ready(None);
+        }
+        match this.inner.poll_next(cx) {
+            Poll::Ready(Some(Ok(bytes))) => {
+                *this.received += u64::try_from(bytes.len()).unwrap();
+                if *this.received > *this.limit {
+                    Poll::Ready(Some(Err(AdmissionError::InputTooLarge {
+                        requested: *this.received,
+                        limit: *this.limit,
+                    }
+                    .into())))
+                } else {
+                    Poll::Ready(Some(Ok(bytes)))
+                }
+            }
+            other => other,
+        }
+    }
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use bytes::Bytes;
+    use futures::{stream, StreamExt};
+
+    use super::{AdmissionError, AdmissionLimits};
+    use crate::{
+        error::BoxError,
+        ff::FieldType,
+        helpers::query::{QueryConfig, QueryType},
+    };
+
+    const LIMITS: AdmissionLimits = AdmissionLimits {
+        max_records: 100,
+        max_input_bytes: 1600,
+        max_concurrent_queries: 1,
+    };
+
+    fn config(size: u32) -> QueryConfig {
+        QueryConfig::new(QueryType::TestMultiply, FieldType::Fp31, size).unwrap()
+    }
+
+    #[test]
+    fn check_limits() {
+        LIMITS.check(&config(100), 0).unwrap();
+        assert!(matches!(
+            LIMITS.check(&config(101), 0),
+            Err(AdmissionError::TooManyRecords {
+                requested: 101,
+                limit: 100,
+            })
+        ));
+        assert!(matches!(
+            AdmissionLimits {
+                max_input_bytes: 1000,
+                ..LIMITS
+            }
+            .check(&config(100), 0),
+            Err(AdmissionError::InputTooLarge {
+                requested: 1600,
+                limit: 1000,
+            })
+        ));
+        let err = LIMITS.check(&config(1), 1).unwrap_err();
+        assert!(matches!(
+            err,
+            AdmissionError::TooManyQueries {
+                running: 1,
+                limit: 1,
+            }
+        ));
+        assert!(err.is_transient());
+    }
+
+    #[tokio::test]
+    async fn limit_input() {
+        let limits = AdmissionLimits {
+            max_input_bytes: 4,
+            ..LIMITS
+        };
+        let chunks = || {
+            stream::iter([vec![1_u8, 2], vec![3, 4], vec![5]])
+                .map(|v| Ok::<_, BoxError>(Bytes::from(v)))
+        };
+
+        let items = limits
+            .limit_input(chunks().take(2))
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(2, items.len());
+        assert!(items.iter().all(Result::is_ok));
+
+        let items = limits.limit_input(chunks()).collect::<Vec<_>>().await;
+        assert_eq!(3, items.len());
+        let err = items[2].as_ref().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<AdmissionError>(),
+            Some(AdmissionError::InputTooLarge {
+                requested: 5,
+                limit: 4,
+            })
+        ));
+    }
+}
@@ -0,0 +1,438 @@
+//! Commitments to output shares.
+//!
+//! Every output share is held by two helpers, so a report collector that gets two different
+//! values for the same share can't reconstruct the output, and can't tell which helper returned
+//! the wrong one either. To make that possible, helpers can commit to their shares before they
+//! return them. Every helper computes a digest of both of its shares of every bucket of the
+//! output, sends the digests of the shares it holds together with each peer to that peer, and
+//! compares them with what the peer sent back. Digests are salted with randomness shared with the
+//! peer that holds the same share, so they don't give away shares to anyone else. Commitments
+//! and salts end up in the signed [`QueryReport`] of the helper.
+//!
+//! If two helpers return different values for the same share, the collector recomputes digests
+//! of both values with the salts from their reports, see [`OutputCommitments::check_output`]. A
+//! helper whose output does not match its own commitments changed it after it committed to it.
+//! If both outputs match, the helpers disagreed before the reveal, and both reports list the
+//! bucket among the ones that did not match the peer. Commitments are computed locally and take
+//! one round of messages, no MPC.
+//!
+//! Salts let anyone who has a report check guesses of the shares in it, so reports with
+//! commitments must be handled with the same care as output shares.
+//!
+//! [`QueryReport`]: crate::query::QueryReport
+use std::fmt::{Debug, Formatter};
+
+use futures::future::{try_join4, try_join_all};
+use generic_array::GenericArray;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use typenum::{Unsigned, U32};
+
+use crate::{
+    ff::{FieldType, Fp32BitPrime, Serializable},
+    helpers::{
+        query::{QueryConfig, QueryType},
+        ChannelId, Direction, Error, Gateway, Message, TotalRecords,
+    },
+    protocol::{
+        ipa_prf::pipeline::{Completeness, OutputShape},
+        prss::{Endpoint as PrssEndpoint, SharedRandomness},
+        step::{Gate, Step, StepNarrow},
+        RecordId,
+    },
+    query::{CacheStatus, ProtocolResult},
+};
+
+pub struct CommitmentStep;
+
+impl AsRef<str> for CommitmentStep {
+    fn as_ref(&self) -> &str {
+        "output_commitments"
+    }
+}
+
+impl Step for CommitmentStep {}
+
+/// Salted SHA-256 of a single output share.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShareDigest(#[serde(with = "hex")] pub [u8; 32]);
+
+impl ShareDigest {
+    fn new(salt: &[u8; 16], bucket: usize, share: &[u8]) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(salt);
+        hasher.update(u64::try_from(bucket).unwrap().to_le_bytes());
+        hasher.update(share);
+        Self(hasher.finalize().into())
+    }
+}
+
+impl Serializable for ShareDigest {
+    type Size = U32;
+
+    fn serialize(&self, buf: &mut GenericArray<u8, Self::Size>) {
+        buf.copy_from_slice(&self.0);
+    }
+
+    fn deserialize(buf: &GenericArray<u8, Self::Size>) -> Self {
+        Self((*buf).into())
+    }
+}
+
+impl Message for ShareDigest {}
+
+/// Digests of the output shares of a single helper, made before the output was revealed.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutputCommitments {
+    /// Salt of the digests of left shares, shared with the left peer.
+    #[serde(with = "hex")]
+    pub left_salt: [u8; 16],
+    /// Salt of the digests of right shares, shared with the right peer.
+    #[serde(with = "hex")]
+    pub right_salt: [u8; 16],
+    /// Digest of the left share of every bucket.
+    pub left: Vec<ShareDigest>,
+    /// Digest of the right share of every bucket.
+    pub right: Vec<ShareDigest>,
+    /// Buckets the left peer committed to a different left share for.
+    pub left_mismatches: Vec<usize>,
+    /// Buckets the right peer committed to a different right share for.
+    pub right_mismatches: Vec<usize>,
+}
+
+impl OutputCommitments {
+    /// Computes digests of every bucket of `output`, where every bucket holds a replicated share
+    /// of `share_size` bytes, left share first.
+    ///
+    /// ## Panics
+    /// If `output` does not consist of whole buckets.
+    #[must_use]
+    pub fn new(
+        left_salt: [u8; 16],
+        right_salt: [u8; 16],
+        output: &[u8],
+        share_size: usize,
+    ) -> Self {
+        let (left, right) = digests(&left_salt, &right_salt, output, share_size);
+        Self {
+            left_salt,
+            right_salt,
+            left,
+            right,
+            left_mismatches: Vec::new(),
+            right_mismatches: Vec::new(),
+        }
+    }
+
+    /// Returns buckets of `output` that don't match these commitments. Report collectors use it
+    /// to check the output a helper returned against the commitments in its report. If `output`
+    /// can't be split into as many buckets as there are commitments, none of them match.
+    #[must_use]
+    pub fn check_output(&self, output: &[u8]) -> Vec<usize> {
+        let buckets = self.left.len();
+        if buckets == 0 || output.len() % (2 * buckets) != 0 {
+            return (0..buckets).collect();
+        }
+        let (left, right) = digests(
+            &self.left_salt,
+            &self.right_salt,
+            output,
+            output.len() / buckets,
+        );
+
+        (0..buckets)
+            .filter(|&i| left[i] != self.left[i] || right[i] != self.right[i])
+            .collect()
+    }
+
+    /// Tells whether either peer committed to a share this helper holds too, but with a different
+    /// value.
+    #[must_use]
+    pub fn peers_disagree(&self) -> bool {
+        !(self.left_mismatches.is_empty() && self.right_mismatches.is_empty())
+    }
+}
+
+fn digests(
+    left_salt: &[u8; 16],
+    right_salt: &[u8; 16],
+    output: &[u8],
+    share_size: usize,
+) -> (Vec<ShareDigest>, Vec<ShareDigest>) {
+    if share_size == 0 {
+        return (Vec::new(), Vec::new());
+    }
+    assert_eq!(
+        0,
+        output.len() % share_size,
+        "output of {} bytes can't be split into shares of {share_size} bytes",
+        output.len()
+    );
+
+    output
+        .chunks_exact(share_size)
+        .enumerate()
+        .map(|(i, share)| {
+            let (left, right) = share.split_at(share_size / 2);
+            (
+                ShareDigest::new(left_salt, i, left),
+                ShareDigest::new(right_salt, i, right),
+            )
+        })
+        .unzip()
+}
+
+/// Returns the size of a single replicated output share of queries run with `config`, `None` if
+/// their outputs are not secret-shared.
+#[must_use]
+pub fn share_size(config: &QueryConfig) -> Option<usize> {
+    if matches!(config.query_type, QueryType::OprfPseudonyms(_)) {
+        return None;
+    }
+    let field_size = match config.field_type {
+        #[cfg(any(test, feature = "weak-field"))]
+        FieldType::Fp31 => <crate::ff::Fp31 as Serializable>::Size::USIZE,
+        FieldType::Fp32BitPrime => <Fp32BitPrime as Serializable>::Size::USIZE,
+    };
+
+    Some(2 * field_size)
+}
+
+/// Commits to `output` and exchanges commitments with both peers.
+///
+/// Commitments use a channel that is not part of any protocol, so this can only be done once per
+/// query. Buckets that don't match what a peer committed to are logged and listed in the
+/// returned commitments, they don't fail the query.
+///
+/// ## Errors
+/// If commitments can't be exchanged with peers.
+pub async fn commit(
+    gateway: &Gateway,
+    prss: &PrssEndpoint,
+    output: &[u8],
+    share_size: usize,
+) -> Result<OutputCommitments, Error> {
+    let gate = Gate::default().narrow(&CommitmentStep);
+    let (left_salt, right_salt) = prss.indexed(&gate).generate_values(0_u128);
+    let mut commitments = OutputCommitments::new(
+        left_salt.to_le_bytes(),
+        right_salt.to_le_bytes(),
+        output,
+        share_size,
+    );
+    let buckets = commitments.left.len();
+    if buckets == 0 {
+        return Ok(commitments);
+    }
+
+    let [left, right] = [Direction::Left, Direction::Right]
+        .map(|d| ChannelId::new(gateway.role().peer(d), gate.clone()));
+    // Left peer holds our left share as its right share, and the other way around.
+    let ((), (), from_left, from_right) = try_join4(
+        send(gateway, &left, &commitments.left),
+        send(gateway, &right, &commitments.right),
+        receive(gateway, &left, buckets),
+        receive(gateway, &right, buckets),
+    )
+    .await?;
+
+    commitments.left_mismatches = mismatches(&commitments.left, &from_left);
+    commitments.right_mismatches = mismatches(&commitments.right, &from_right);
+    if commitments.peers_disagree() {
+        tracing::warn!(
+            "peers committed to different output shares: buckets {:?} differ from the left peer, \
+             {:?} from the right peer",
+            commitments.left_mismatches,
+            commitments.right_mismatches
+        );
+    }
+
+    Ok(commitments)
+}
+
+async fn send(
+    gateway: &Gateway,
+    channel: &ChannelId,
+    digests: &[ShareDigest],
+) -> Result<(), Error> {
+    let sender = gateway.get_sender::<ShareDigest>(channel, TotalRecords::from(digests.len()));
+    try_join_all(
+        digests
+            .iter()
+            .enumerate()
+            .map(|(i, digest)| sender.send(RecordId::from(i), *digest)),
+    )
+    .await?;
+
+    Ok(())
+}
+
+async fn receive(
+    gateway: &Gateway,
+    channel: &ChannelId,
+    buckets: usize,
+) -> Result<Vec<ShareDigest>, Error> {
+    let receiver = gateway.get_receiver::<ShareDigest>(channel);
+    try_join_all((0..buckets).map(|i| receiver.receive(RecordId::from(i)))).await
+}
+
+fn mismatches(ours: &[ShareDigest], theirs: &[ShareDigest]) -> Vec<usize> {
+    (0..ours.len()).filter(|&i| ours[i] != theirs[i]).collect()
+}
+
+/// Commits to the output of `result` if it is secret-shared, see [`commit`]. Outputs of
+/// queries run with `config` are split into buckets of [`share_size`] bytes.
+///
+/// ## Errors
+/// If commitments can't be exchanged with peers.
+pub(super) async fn commit_result(
+    gateway: &Gateway,
+    prss: &PrssEndpoint,
+    config: &QueryConfig,
+    result: Box<dyn ProtocolResult>,
+) -> Result<Box<dyn ProtocolResult>, Error> {
+    let Some(share_size) = share_size(config) else {
+        return Ok(result);
+    };
+    let cache_status = result.cache_status();
+    let partial = result.partial();
+    let shape = result.shape();
+    let output = result.into_bytes();
+    let commitments = commit(gateway, prss, &output, share_size).await?;
+
+    Ok(Box::new(CommittedResult {
+        output,
+        cache_status,
+        partial,
+        shape,
+        commitments,
+    }))
+}
+
+/// Output of a query along with the commitments this helper made to it.
+struct CommittedResult {
+    output: Vec<u8>,
+    cache_status: Option<CacheStatus>,
+    partial: Option<Completeness>,
+    shape: Option<OutputShape>,
+    commitments: OutputCommitments,
+}
+
+impl Debug for CommittedResult {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "CommittedResult[{} bytes, {} buckets]",
+            self.output.len(),
+            self.commitments.left.len()
+        )
+    }
+}
+
+impl ProtocolResult for CommittedResult {
+    fn into_bytes(self: Box<Self>) -> Vec<u8> {
+        self.output
+    }
+
+    fn cache_status(&self) -> Option<CacheStatus> {
+        self.cache_status
+    }
+
+    fn partial(&self) -> Option<Completeness> {
+        self.partial
+    }
+
+    fn shape(&self) -> Option<OutputShape> {
+        self.shape
+    }
+
+    fn commitments(&self) -> Option<&OutputCommitments> {
+        Some(&self.commitments)
+    }
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use futures::future::try_join_all;
+    use rand::thread_rng;
+
+    use super::{commit, OutputCommitments};
+    use crate::{
+        ff::{Field, Fp31},
+        helpers::Role,
+        query::ProtocolResult,
+        secret_sharing::IntoShares,
+        test_fixture::{make_participants, TestWorld},
+    };
+
+    const SHARE_SIZE: usize = 2;
+
+    /// Output shares of every helper, serialized.
+    fn outputs() -> [Vec<u8>; 3] {
+        (0u128..4)
+            .map(Fp31::truncate_from)
+            .share()
+            .map(|shares| Box::new(shares).into_bytes())
+    }
+
+    async fn run(outputs: &[Vec<u8>; 3]) -> Vec<OutputCommitments> {
+        let world = TestWorld::default();
+        let participants = make_participants(&mut thread_rng());
+        try_join_all(Role::all().iter().map(|&role| {
+            commit(
+                world.gateway(role),
+                &participants[role],
+                &outputs[role],
+                SHARE_SIZE,
+            )
+        }))
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn honest_helpers_agree() {
+        let outputs = outputs();
+        let commitments = run(&outputs).await;
+
+        for (commitments, output) in commitments.iter().zip(&outputs) {
+            assert_eq!(4, commitments.left.len());
+            assert!(!commitments.peers_disagree());
+            assert!(commitments.check_output(output).is_empty());
+        }
+        // H1 shares its left share with H3, which holds it as its right share
+        assert_eq!(commitments[0].left_salt, commitments[2].right_salt);
+        assert_eq!(commitments[0].left, commitments[2].right);
+        assert_ne!(commitments[0].left, commitments[0].right);
+    }
+
+    #[tokio::test]
+    async fn deviation() {
+        let mut outputs = outputs();
+        // H2 changes its left share of the second bucket before it commits
+        outputs[1][SHARE_SIZE] = outputs[1][SHARE_SIZE].wrapping_add(1);
+        let commitments = run(&outputs).await;
+
+        assert_eq!(vec![1], commitments[1].left_mismatches);
+        assert!(commitments[1].right_mismatches.is_empty());
+        assert_eq!(vec![1], commitments[0].right_mismatches);
+        assert!(!commitments[2].peers_disagree());
+
+        // H3 changes its output after it committed to it
+        let mut output = outputs[2].clone();
+        output[3 * SHARE_SIZE + 1] = output[3 * SHARE_SIZE + 1].wrapping_add(1);
+        assert_eq!(vec![3], commitments[2].check_output(&output));
+        assert_eq!(vec![0, 1, 2, 3], commitments[2].check_output(&output[1..]));
+    }
+
+    #[test]
+    fn salted() {
+        let output = [1, 2, 1, 2];
+        let commitments = OutputCommitments::new([0; 16], [1; 16], &output, SHARE_SIZE);
+        assert_ne!(commitments.left[0], commitments.left[1]);
+        assert_ne!(
+            commitments.left,
+            OutputCommitments::new([2; 16], [1; 16], &output, SHARE_SIZE).left
+        );
+    }
+}
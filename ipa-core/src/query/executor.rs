@@ -12,6 +12,7 @@ use rand::rngs::StdRng;
 use rand_core::SeedableRng;
 #[cfg(all(feature = "shuttle", test))]
 use shuttle::future as tokio;
+use tracing::Instrument;
 use typenum::Unsigned;
 
 #[cfg(any(test, feature = "cli", feature = "test-fixture"))]
@@ -21,22 +22,198 @@ use crate::{
     helpers::{
         negotiate_prss,
         query::{QueryConfig, QueryType},
-        BodyStream, Gateway,
+        with_heartbeats, BodyStream, Direction, Gateway, QueryMetrics,
     },
     hpke::{KeyPair, KeyRegistry},
     protocol::{
         context::{MaliciousContext, SemiHonestContext},
+        ipa_prf::{
+            pipeline::{Completeness, OutputShape, PartialAggregate},
+            prf_eval::PersistentPrfKey,
+        },
         prss::Endpoint as PrssEndpoint,
         step::{Gate, StepNarrow},
     },
     query::{
-        runner::{IpaQuery, OprfIpaQuery, QueryResult, SparseAggregateQuery},
+        commitment::commit_result,
+        runner::{IpaQuery, OprfIpaQuery, OprfPseudonymQuery, QueryResult, SparseAggregateQuery},
         state::RunningQuery,
+        CacheStatus, OutputCommitments, ResultCache, SignedQueryReport,
     },
 };
 
 pub trait Result: Send + Debug {
     fn into_bytes(self: Box<Self>) -> Vec<u8>;
+
+    /// Tells whether this output was taken from [`ResultCache`] or computed by the query. `None`
+    /// if the query did not go through the cache.
+    fn cache_status(&self) -> Option<CacheStatus> {
+        None
+    }
+
+    /// Time spent and bytes exchanged by this helper in every stage of the query that produced
+    /// this output.
+    fn metrics(&self) -> Option<&QueryMetrics> {
+        None
+    }
+
+    /// Share of users this output covers if the query was stopped by its deadline before it
+    /// processed all of them. `None` if the output is complete.
+    fn partial(&self) -> Option<Completeness> {
+        None
+    }
+
+    /// Layout of the output if it has more than one value per breakdown. `None` if the output
+    /// has a single value per breakdown, or is not broken down at all.
+    fn shape(&self) -> Option<OutputShape> {
+        None
+    }
+
+    /// Signed summary of the query that produced this output, if this helper signs them. See
+    /// [`ReportSigner`].
+    ///
+    /// [`ReportSigner`]: crate::query::ReportSigner
+    fn report(&self) -> Option<&SignedQueryReport> {
+        None
+    }
+
+    /// Commitments this helper made to the output before it was revealed, if helpers commit to
+    /// their outputs. See [`OutputCommitments`].
+    fn commitments(&self) -> Option<&OutputCommitments> {
+        None
+    }
+}
+
+/// Output of a query along with the metrics collected while it was running.
+#[derive(Debug)]
+pub(super) struct MeasuredResult {
+    inner: Box<dyn Result>,
+    metrics: QueryMetrics,
+}
+
+impl MeasuredResult {
+    pub fn new(inner: Box<dyn Result>, metrics: QueryMetrics) -> Self {
+        Self { inner, metrics }
+    }
+}
+
+impl Result for MeasuredResult {
+    fn into_bytes(self: Box<Self>) -> Vec<u8> {
+        self.inner.into_bytes()
+    }
+
+    fn cache_status(&self) -> Option<CacheStatus> {
+        self.inner.cache_status()
+    }
+
+    fn metrics(&self) -> Option<&QueryMetrics> {
+        Some(&self.metrics)
+    }
+
+    fn partial(&self) -> Option<Completeness> {
+        self.inner.partial()
+    }
+
+    fn shape(&self) -> Option<OutputShape> {
+        self.inner.shape()
+    }
+
+    fn report(&self) -> Option<&SignedQueryReport> {
+        self.inner.report()
+    }
+
+    fn commitments(&self) -> Option<&OutputCommitments> {
+        self.inner.commitments()
+    }
+}
+
+/// Output of a query that covers only some of the users.
+#[derive(Debug)]
+pub(super) struct PartialResult {
+    inner: Box<dyn Result>,
+    completeness: Completeness,
+}
+
+impl PartialResult {
+    pub fn new(inner: Box<dyn Result>, completeness: Completeness) -> Self {
+        Self {
+            inner,
+            completeness,
+        }
+    }
+}
+
+impl Result for PartialResult {
+    fn into_bytes(self: Box<Self>) -> Vec<u8> {
+        self.inner.into_bytes()
+    }
+
+    fn cache_status(&self) -> Option<CacheStatus> {
+        self.inner.cache_status()
+    }
+
+    fn metrics(&self) -> Option<&QueryMetrics> {
+        self.inner.metrics()
+    }
+
+    fn partial(&self) -> Option<Completeness> {
+        Some(self.completeness)
+    }
+
+    fn shape(&self) -> Option<OutputShape> {
+        self.inner.shape()
+    }
+
+    fn report(&self) -> Option<&SignedQueryReport> {
+        self.inner.report()
+    }
+
+    fn commitments(&self) -> Option<&OutputCommitments> {
+        self.inner.commitments()
+    }
+}
+
+/// Output of a query along with its signed report.
+#[derive(Debug)]
+pub(super) struct ReportedResult {
+    inner: Box<dyn Result>,
+    report: SignedQueryReport,
+}
+
+impl ReportedResult {
+    pub fn new(inner: Box<dyn Result>, report: SignedQueryReport) -> Self {
+        Self { inner, report }
+    }
+}
+
+impl Result for ReportedResult {
+    fn into_bytes(self: Box<Self>) -> Vec<u8> {
+        self.inner.into_bytes()
+    }
+
+    fn cache_status(&self) -> Option<CacheStatus> {
+        self.inner.cache_status()
+    }
+
+    fn metrics(&self) -> Option<&QueryMetrics> {
+        self.inner.metrics()
+    }
+
+    fn partial(&self) -> Option<Completeness> {
+        self.inner.partial()
+    }
+
+    fn shape(&self) -> Option<OutputShape> {
+        self.inner.shape()
+    }
+
+    fn report(&self) -> Option<&SignedQueryReport> {
+        Some(&self.report)
+    }
+
+    fn commitments(&self) -> Option<&OutputCommitments> {
+        self.inner.commitments()
+    }
 }
 
 impl<T> Result for Vec<T>
@@ -56,33 +233,64 @@ where
     }
 }
 
+impl<T> Result for PartialAggregate<T>
+where
+    T: Serializable + Debug + Send,
+{
+    /// Attributed user counts, if the query counts them, follow the totals of all breakdowns.
+    fn into_bytes(self: Box<Self>) -> Vec<u8> {
+        let mut values = self.breakdowns;
+        values.extend(self.attributed_users.unwrap_or_default());
+        Box::new(values).into_bytes()
+    }
+
+    fn partial(&self) -> Option<Completeness> {
+        Some(self.completeness).filter(Completeness::is_partial)
+    }
+
+    fn shape(&self) -> Option<OutputShape> {
+        self.shape
+    }
+}
+
 #[allow(clippy::too_many_lines)]
 pub fn execute(
     config: QueryConfig,
     key_registry: Arc<KeyRegistry<KeyPair>>,
+    prf_key: Option<Arc<PersistentPrfKey>>,
     gateway: Gateway,
     input: BodyStream,
+    result_cache: Option<Arc<ResultCache>>,
 ) -> RunningQuery {
     match (config.query_type, config.field_type) {
         #[cfg(any(test, feature = "weak-field"))]
-        (QueryType::TestMultiply, FieldType::Fp31) => {
-            do_query(config, gateway, input, |prss, gateway, _config, input| {
+        (QueryType::TestMultiply, FieldType::Fp31) => do_query(
+            config,
+            gateway,
+            input,
+            result_cache,
+            |prss, gateway, _config, input| {
                 Box::pin(execute_test_multiply::<crate::ff::Fp31>(
                     prss, gateway, input,
                 ))
-            })
-        }
+            },
+        ),
         #[cfg(any(test, feature = "cli", feature = "test-fixture"))]
-        (QueryType::TestMultiply, FieldType::Fp32BitPrime) => {
-            do_query(config, gateway, input, |prss, gateway, _config, input| {
+        (QueryType::TestMultiply, FieldType::Fp32BitPrime) => do_query(
+            config,
+            gateway,
+            input,
+            result_cache,
+            |prss, gateway, _config, input| {
                 Box::pin(execute_test_multiply::<Fp32BitPrime>(prss, gateway, input))
-            })
-        }
+            },
+        ),
         #[cfg(any(test, feature = "weak-field"))]
         (QueryType::SemiHonestIpa(ipa_config), FieldType::Fp31) => do_query(
             config,
             gateway,
             input,
+            result_cache,
             move |prss, gateway, config, input| {
                 let ctx = SemiHonestContext::new(prss, gateway);
                 Box::pin(
@@ -96,6 +304,7 @@ pub fn execute(
             config,
             gateway,
             input,
+            result_cache,
             move |prss, gateway, config, input| {
                 let ctx = SemiHonestContext::new(prss, gateway);
                 Box::pin(
@@ -110,6 +319,7 @@ pub fn execute(
             config,
             gateway,
             input,
+            result_cache,
             move |prss, gateway, config, input| {
                 let ctx = MaliciousContext::new(prss, gateway);
                 Box::pin(
@@ -123,6 +333,7 @@ pub fn execute(
             config,
             gateway,
             input,
+            result_cache,
             move |prss, gateway, config, input| {
                 let ctx = MaliciousContext::new(prss, gateway);
                 Box::pin(
@@ -137,6 +348,7 @@ pub fn execute(
             config,
             gateway,
             input,
+            result_cache,
             move |prss, gateway, config, input| {
                 let ctx = SemiHonestContext::new(prss, gateway);
                 Box::pin(
@@ -154,6 +366,7 @@ pub fn execute(
                 config,
                 gateway,
                 input,
+                result_cache,
                 move |prss, gateway, config, input| {
                     let ctx = SemiHonestContext::new(prss, gateway);
                     Box::pin(
@@ -172,6 +385,7 @@ pub fn execute(
             config,
             gateway,
             input,
+            result_cache,
             move |prss, gateway, config, input| {
                 let ctx = MaliciousContext::new(prss, gateway);
                 Box::pin(
@@ -189,6 +403,7 @@ pub fn execute(
                 config,
                 gateway,
                 input,
+                result_cache,
                 move |prss, gateway, config, input| {
                     let ctx = MaliciousContext::new(prss, gateway);
                     Box::pin(
@@ -206,11 +421,12 @@ pub fn execute(
             config,
             gateway,
             input,
+            result_cache,
             move |prss, gateway, config, input| {
                 let ctx = SemiHonestContext::new(prss, gateway);
                 Box::pin(
-                    OprfIpaQuery::<_, Fp32BitPrime>::new(ipa_config)
-                        .execute(ctx, config.size, input)
+                    OprfIpaQuery::<_, Fp32BitPrime>::new(ipa_config, key_registry)
+                        .execute_partial(ctx, config.size, input)
                         .then(|res| ready(res.map(|out| Box::new(out) as Box<dyn Result>))),
                 )
             },
@@ -220,10 +436,33 @@ pub fn execute(
             config,
             gateway,
             input,
+            result_cache,
             move |prss, gateway, config, input| {
                 let ctx = SemiHonestContext::new(prss, gateway);
                 Box::pin(
-                    OprfIpaQuery::<_, crate::ff::Fp31>::new(ipa_config)
+                    OprfIpaQuery::<_, crate::ff::Fp31>::new(ipa_config, key_registry)
+                        .execute_partial(ctx, config.size, input)
+                        .then(|res| ready(res.map(|out| Box::new(out) as Box<dyn Result>))),
+                )
+            },
+        ),
+        // Pseudonyms do not depend on the field used for aggregation.
+        (QueryType::OprfPseudonyms(pseudonym_config), _) => do_query(
+            config,
+            gateway,
+            input,
+            result_cache,
+            move |prss, gateway, config, input| {
+                // Roles change from query to query, key components are tied to peer identities.
+                let prf_key = prf_key.and_then(|key| {
+                    key.share(
+                        gateway.peer_identity(Direction::Left),
+                        gateway.peer_identity(Direction::Right),
+                    )
+                });
+                let ctx = SemiHonestContext::new(prss, gateway);
+                Box::pin(
+                    OprfPseudonymQuery::new(pseudonym_config, key_registry, prf_key)
                         .execute(ctx, config.size, input)
                         .then(|res| ready(res.map(|out| Box::new(out) as Box<dyn Result>))),
                 )
@@ -236,6 +475,7 @@ pub fn do_query<F>(
     config: QueryConfig,
     gateway: Gateway,
     input_stream: BodyStream,
+    result_cache: Option<Arc<ResultCache>>,
     query_impl: F,
 ) -> RunningQuery
 where
@@ -249,21 +489,64 @@ where
         + 'static,
 {
     let (tx, rx) = oneshot::channel();
+    let progress = gateway.progress();
+    // Query runs on its own task, this span ties it back to the request that started it.
+    let span = tracing::info_span!(
+        "query_execution",
+        query_type = config.query_type.as_ref(),
+        role = ?gateway.role(),
+    );
 
-    let join_handle = tokio::spawn(async move {
-        // TODO: make it a generic argument for this function
-        let mut rng = StdRng::from_entropy();
-        // Negotiate PRSS first
-        let step = Gate::default().narrow(&config.query_type);
-        let prss = negotiate_prss(&gateway, &step, &mut rng).await.unwrap();
+    let join_handle = tokio::spawn(
+        async move {
+            // TODO: make it a generic argument for this function
+            let mut rng = StdRng::from_entropy();
+            let query = async {
+                // Negotiate PRSS first
+                let step = Gate::default().narrow(&config.query_type);
+                let prss = negotiate_prss(&gateway, &step, &mut rng)
+                    .instrument(tracing::info_span!("negotiate_prss"))
+                    .await
+                    .unwrap();
 
-        tx.send(query_impl(&prss, &gateway, &config, input_stream).await)
-            .unwrap();
-    });
+                let output = match result_cache {
+                    Some(cache) => {
+                        cache
+                            .run(&gateway, &config, input_stream, |input| {
+                                query_impl(&prss, &gateway, &config, input)
+                            })
+                            .await
+                    }
+                    None => query_impl(&prss, &gateway, &config, input_stream).await,
+                };
+
+                match output {
+                    Ok(output) if gateway.config().commit_outputs => {
+                        commit_result(&gateway, &prss, &config, output)
+                            .await
+                            .map_err(Into::into)
+                    }
+                    output => output,
+                }
+            };
+            let result = match gateway.config().heartbeat {
+                Some(heartbeat) => with_heartbeats(&gateway, heartbeat, query).await,
+                None => query.await,
+            }
+            .map(|output| {
+                Box::new(MeasuredResult::new(output, gateway.progress().metrics()))
+                    as Box<dyn Result>
+            });
+
+            tx.send(result).unwrap();
+        }
+        .instrument(span),
+    );
 
     RunningQuery {
         result: rx,
         join_handle,
+        progress,
     }
 }
 
@@ -1,13 +1,29 @@
+mod admission;
+mod commitment;
 mod completion;
 mod executor;
 mod processor;
+mod query_log;
+mod report;
+mod reservation;
+mod result_cache;
+mod results_store;
 mod runner;
 mod state;
 
+pub use admission::{AdmissionError, AdmissionLimits};
+pub(crate) use commitment::CommitmentStep;
+pub use commitment::{OutputCommitments, ShareDigest};
 use completion::Handle as CompletionHandle;
 pub use executor::Result as ProtocolResult;
 pub use processor::{
     NewQueryError, PrepareQueryError, Processor as QueryProcessor, QueryCompletionError,
     QueryInputError, QueryStatusError,
 };
-pub use state::QueryStatus;
+pub use query_log::{LogEntry, QueryLog, QueryManifest};
+pub use report::{DpParameters, QueryReport, ReportError, ReportSigner, SignedQueryReport};
+pub use reservation::{ReservationError, ResourceLimits};
+pub(crate) use result_cache::ResultCacheStep;
+pub use result_cache::{CacheStatus, ResultCache, ResultCacheConfig};
+pub use results_store::{FsResultsStore, ResultsStore};
+pub use state::{QueryStatus, QueryStatusReport};
@@ -1,7 +1,8 @@
 use std::{
-    collections::hash_map::Entry,
+    collections::{hash_map::Entry, HashMap},
     fmt::{Debug, Formatter},
-    sync::Arc,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
 };
 
 use futures::{future::try_join, stream};
@@ -9,15 +10,27 @@ use futures::{future::try_join, stream};
 use crate::{
     error::Error as ProtocolError,
     helpers::{
-        query::{PrepareQuery, QueryConfig, QueryInput},
-        Gateway, GatewayConfig, Role, RoleAssignment, Transport, TransportError, TransportImpl,
+        query::{
+            InputPart, IpaQueryConfig, PrepareQuery, QueryConfig, QueryConfigError, QueryInput,
+            QueryType, ResourceReservation,
+        },
+        BodyStream, Gateway, GatewayConfig, HeartbeatConfig, HelperCounters, HelperIdentity,
+        HelperMetrics, MemoryBudgetConfig, Role, RoleAssignment, Transport, TransportError,
+        TransportImpl,
     },
     hpke::{KeyPair, KeyRegistry},
-    protocol::QueryId,
+    protocol::{ipa_prf::prf_eval::PersistentPrfKey, QueryId},
     query::{
-        executor,
-        state::{QueryState, QueryStatus, RemoveQuery, RunningQueries, StateError},
-        CompletionHandle, ProtocolResult,
+        executor::{self, MeasuredResult, PartialResult, ReportedResult},
+        query_log::PendingEntry,
+        report::PendingReport,
+        result_cache::CachedResult,
+        results_store::{self, StoredResult},
+        state::{
+            QueryState, QueryStatus, QueryStatusReport, RemoveQuery, RunningQueries, StateError,
+        },
+        AdmissionError, AdmissionLimits, CompletionHandle, ProtocolResult, QueryLog, QueryManifest,
+        ReportSigner, ReservationError, ResourceLimits, ResultCache, ResultsStore,
     },
 };
 
@@ -41,6 +54,20 @@ use crate::{
 pub struct Processor {
     queries: RunningQueries,
     key_registry: Arc<KeyRegistry<KeyPair>>,
+    prf_key: Option<Arc<PersistentPrfKey>>,
+    results_store: Option<Arc<dyn ResultsStore>>,
+    query_log: Option<Arc<QueryLog>>,
+    pending_log_entries: Mutex<HashMap<QueryId, PendingEntry>>,
+    heartbeat: Option<HeartbeatConfig>,
+    flow_control: Option<NonZeroUsize>,
+    commit_outputs: bool,
+    memory_budget: Option<MemoryBudgetConfig>,
+    resource_limits: Option<ResourceLimits>,
+    admission_limits: Option<AdmissionLimits>,
+    result_cache: Option<Arc<ResultCache>>,
+    counters: Arc<HelperCounters>,
+    report_signer: Option<Arc<ReportSigner>>,
+    pending_reports: Mutex<HashMap<QueryId, PendingReport>>,
 }
 
 impl Default for Processor {
@@ -48,15 +75,35 @@ impl Default for Processor {
         Self {
             queries: RunningQueries::default(),
             key_registry: Arc::new(KeyRegistry::<KeyPair>::empty()),
+            prf_key: None,
+            results_store: None,
+            query_log: None,
+            pending_log_entries: Mutex::default(),
+            heartbeat: None,
+            flow_control: None,
+            commit_outputs: false,
+            memory_budget: None,
+            resource_limits: None,
+            admission_limits: None,
+            result_cache: None,
+            counters: Arc::default(),
+            report_signer: None,
+            pending_reports: Mutex::default(),
         }
     }
 }
 
 #[derive(thiserror::Error, Debug)]
 pub enum NewQueryError {
+    #[error(transparent)]
+    Config(#[from] QueryConfigError),
     #[error(transparent)]
     State(#[from] StateError),
     #[error(transparent)]
+    Reservation(#[from] ReservationError),
+    #[error(transparent)]
+    Admission(#[from] AdmissionError),
+    #[error(transparent)]
     Transport(#[from] TransportError),
 }
 
@@ -64,9 +111,17 @@ pub enum NewQueryError {
 pub enum PrepareQueryError {
     #[error("This helper is the query coordinator, cannot respond to Prepare requests")]
     WrongTarget,
+    #[error("Prepare request was sent by {from:?}, which is not the coordinator of this query")]
+    NotCoordinator { from: HelperIdentity },
     #[error("Query is already running")]
     AlreadyRunning,
     #[error(transparent)]
+    Config(#[from] QueryConfigError),
+    #[error(transparent)]
+    Reservation(#[from] ReservationError),
+    #[error(transparent)]
+    Admission(#[from] AdmissionError),
+    #[error(transparent)]
     StateError {
         #[from]
         source: StateError,
@@ -77,6 +132,13 @@ pub enum PrepareQueryError {
 pub enum QueryInputError {
     #[error("The query with id {0:?} does not exist")]
     NoSuchQuery(QueryId),
+    #[error("Received {received:?} while awaiting {awaiting:?}")]
+    UnexpectedInputPart {
+        received: InputPart,
+        awaiting: InputPart,
+    },
+    #[error("Split input requires reports with timestamps to order rows of every user")]
+    SplitInputWithoutTimestamps,
     #[error(transparent)]
     StateError {
         #[from]
@@ -101,6 +163,8 @@ pub enum QueryCompletionError {
     },
     #[error("query execution failed: {0}")]
     ExecutionError(#[from] ProtocolError),
+    #[error("failed to access stored query results: {0}")]
+    ResultsStore(#[from] std::io::Error),
 }
 
 impl Debug for Processor {
@@ -115,27 +179,143 @@ impl Processor {
         Self {
             queries: RunningQueries::default(),
             key_registry: Arc::new(key_registry),
+            prf_key: None,
+            results_store: None,
+            query_log: None,
+            pending_log_entries: Mutex::default(),
+            heartbeat: None,
+            flow_control: None,
+            commit_outputs: false,
+            memory_budget: None,
+            resource_limits: None,
+            admission_limits: None,
+            result_cache: None,
+            counters: Arc::default(),
+            report_signer: None,
+            pending_reports: Mutex::default(),
         }
     }
 
+    /// Writes outputs of completed queries to the given store, so they can be retrieved again
+    /// after they have been handed out once.
+    #[must_use]
+    pub fn with_results_store(mut self, results_store: Arc<dyn ResultsStore>) -> Self {
+        self.results_store = Some(results_store);
+        self
+    }
+
+    /// Logs inputs and outputs of completed queries, so they can be run again later.
+    #[must_use]
+    pub fn with_query_log(mut self, query_log: Arc<QueryLog>) -> Self {
+        self.query_log = Some(query_log);
+        self
+    }
+
+    /// Exchanges heartbeats with peers while queries are running, so queries fail if a peer goes
+    /// down instead of waiting for it forever.
+    #[must_use]
+    pub fn with_heartbeat(mut self, heartbeat: HeartbeatConfig) -> Self {
+        self.heartbeat = Some(heartbeat);
+        self
+    }
+
+    /// Limits how many records helpers send ahead of what their peers have consumed on every
+    /// channel. All helpers must use the same window. See [`GatewayConfig::flow_control`].
+    #[must_use]
+    pub fn with_flow_control(mut self, window: NonZeroUsize) -> Self {
+        self.flow_control = Some(window);
+        self
+    }
+
+    /// Commits to output shares and exchanges commitments with peers before outputs are returned,
+    /// so report collectors can tell which helper deviated if outputs don't reconstruct. All
+    /// helpers must enable it. Commitments are published in query reports, see
+    /// [`with_report_signer`] and [`OutputCommitments`].
+    ///
+    /// [`with_report_signer`]: Self::with_report_signer
+    /// [`OutputCommitments`]: crate::query::OutputCommitments
+    #[must_use]
+    pub fn with_output_commitments(mut self) -> Self {
+        self.commit_outputs = true;
+        self
+    }
+
+    /// Accounts for the memory every query uses against `budget` and warns about queries that
+    /// exceed it. See [`GatewayConfig::memory_budget`].
+    #[must_use]
+    pub fn with_memory_budget(mut self, budget: MemoryBudgetConfig) -> Self {
+        self.memory_budget = Some(budget);
+        self
+    }
+
+    /// Rejects queries that need more resources than `limits` allow. Both coordinator and
+    /// followers check them when the query is created.
+    #[must_use]
+    pub fn with_resource_limits(mut self, limits: ResourceLimits) -> Self {
+        self.resource_limits = Some(limits);
+        self
+    }
+
+    /// Turns away queries that exceed `limits`. Both coordinator and followers check them when the
+    /// query is created, and inputs are cut off once they exceed the byte limit.
+    #[must_use]
+    pub fn with_admission_limits(mut self, limits: AdmissionLimits) -> Self {
+        self.admission_limits = Some(limits);
+        self
+    }
+
+    /// Returns cached outputs of queries that are submitted again with the same input, instead of
+    /// running them. See [`ResultCache`].
+    #[must_use]
+    pub fn with_result_cache(mut self, result_cache: Arc<ResultCache>) -> Self {
+        self.result_cache = Some(result_cache);
+        self
+    }
+
+    /// Share of the PRF key this helper keeps across queries. Helpers only export pseudonyms,
+    /// see [`QueryType::OprfPseudonyms`], if they hold one.
+    ///
+    /// [`QueryType::OprfPseudonyms`]: crate::helpers::query::QueryType::OprfPseudonyms
+    #[must_use]
+    pub fn with_prf_key(mut self, prf_key: Arc<PersistentPrfKey>) -> Self {
+        self.prf_key = Some(prf_key);
+        self
+    }
+
+    /// Signs a report of every completed query and hands it to the report collector along with
+    /// the output. See [`QueryReport`].
+    ///
+    /// [`QueryReport`]: crate::query::QueryReport
+    #[must_use]
+    pub fn with_report_signer(mut self, signer: Arc<ReportSigner>) -> Self {
+        self.report_signer = Some(signer);
+        self
+    }
+
     /// Upon receiving a new query request:
     /// * processor generates new query id
     /// * assigns roles to helpers in the ring. Helper that received new query request becomes `Role::H1` (aka coordinator).
     /// The coordinator is in theory free to choose helpers for `Role::H2` and `Role::H3` arbitrarily (aka followers), however, this is not currently exercised.
     /// * Requests Infra and Network layer to create resources for this query
-    /// * sends `prepare` request that describes the query configuration (query id, query type, field type, roles -> endpoints or reverse) to followers and waits for the confirmation
+    /// * checks that this helper can afford resources the query needs and admits it
+    /// * sends `prepare` request that describes the query configuration (query id, query type, field type, roles -> endpoints or reverse) to followers along with resources to reserve for it and waits for the confirmation
     /// * records newly created query id internally and sets query state to awaiting data
     /// * returns query configuration
     ///
     /// ## Errors
-    /// When other peers failed to acknowledge this query
+    /// When this helper can't afford or admit the query or other peers failed to acknowledge it
     #[allow(clippy::missing_panics_doc)]
+    #[tracing::instrument("new_query", skip_all, fields(query_type = req.query_type.as_ref()))]
     pub async fn new_query(
         &self,
         transport: TransportImpl,
         req: QueryConfig,
     ) -> Result<PrepareQuery, NewQueryError> {
-        let query_id = QueryId;
+        req.validate()?;
+        let reservation = req.reservation();
+        self.check_resources(&reservation)?;
+        self.admit(&req)?;
+        let query_id = QueryId::random();
         let handle = self.queries.handle(query_id);
         handle.set_state(QueryState::Preparing(req))?;
         let guard = handle.remove_query_on_drop();
@@ -150,6 +330,7 @@ impl Processor {
             query_id,
             config: req,
             roles: roles.clone(),
+            reservation,
         };
 
         // Inform other parties about new query. If any of them rejects it, this join will fail
@@ -169,11 +350,15 @@ impl Processor {
     /// On prepare, each follower:
     /// * ensures that it is not the leader on this query
     /// * query is not registered yet
+    /// * agrees with the coordinator on resources the query needs and can afford them
+    /// * admits the query
     /// * creates gateway and network
     /// * registers query
     ///
     /// ## Errors
-    /// if query is already running or this helper cannot be a follower in it
+    /// if query is already running, this helper cannot be a follower in it, can't reserve
+    /// resources for it or does not admit it
+    #[tracing::instrument("prepare_query", skip_all, fields(query_id = %req.query_id))]
     pub fn prepare(
         &self,
         transport: &TransportImpl,
@@ -184,10 +369,21 @@ impl Processor {
         if my_role == Role::H1 {
             return Err(PrepareQueryError::WrongTarget);
         }
+        req.config.validate()?;
         let handle = self.queries.handle(req.query_id);
         if handle.status().is_some() {
             return Err(PrepareQueryError::AlreadyRunning);
         }
+        let expected = req.config.reservation();
+        if req.reservation != expected {
+            return Err(ReservationError::Mismatch {
+                requested: req.reservation,
+                expected,
+            }
+            .into());
+        }
+        self.check_resources(&req.reservation)?;
+        self.admit(&req.config)?;
 
         handle.set_state(QueryState::AwaitingInputs(
             req.query_id,
@@ -198,56 +394,202 @@ impl Processor {
         Ok(())
     }
 
-    /// Receive inputs for the specified query. That triggers query processing
+    fn check_resources(&self, reservation: &ResourceReservation) -> Result<(), ReservationError> {
+        self.resource_limits.map_or(Ok(()), |limits| {
+            limits.check(reservation, self.query_log.is_some())
+        })
+    }
+
+    fn admit(&self, config: &QueryConfig) -> Result<(), AdmissionError> {
+        self.admission_limits.map_or(Ok(()), |limits| {
+            limits.check(config, self.queries.inner.lock().unwrap().len())
+        })
+    }
+
+    /// Receive inputs for the specified query. That triggers query processing.
+    ///
+    /// Input may be split between two report collectors, one submitting source events and the
+    /// other one submitting trigger events. In this case, query processing starts once both parts
+    /// have arrived. Source events are placed before trigger events in the combined input, and
+    /// OPRF IPA sorts rows of every user by timestamp, so split input requires timestamps.
+    ///
+    /// If this helper has admission limits, every part of the input fails once it goes over the
+    /// byte limit, and so does the query.
     ///
     /// ## Errors
-    /// if query is not registered on this helper.
+    /// if query is not registered on this helper or this part of the input has already been
+    /// received.
     ///
     /// ## Panics
     /// If failed to obtain an exclusive access to the query collection.
+    #[tracing::instrument("receive_inputs", skip_all, fields(query_id = %input.query_id, part = ?input.part))]
     pub fn receive_inputs(
         &self,
         transport: TransportImpl,
-        input: QueryInput,
+        mut input: QueryInput,
     ) -> Result<(), QueryInputError> {
+        if let Some(limits) = self.admission_limits {
+            input.input_stream =
+                BodyStream::from_bytes_stream(limits.limit_input(input.input_stream));
+        }
         let mut queries = self.queries.inner.lock().unwrap();
         match queries.entry(input.query_id) {
             Entry::Occupied(entry) => {
                 let state = entry.remove();
-                if let QueryState::AwaitingInputs(query_id, config, role_assignment) = state {
-                    assert_eq!(
-                        input.query_id, query_id,
-                        "received inputs for a different query"
-                    );
-                    let gateway = Gateway::new(
-                        query_id,
-                        GatewayConfig::from(&config),
-                        role_assignment,
-                        transport,
-                    );
-                    queries.insert(
-                        input.query_id,
-                        QueryState::Running(executor::execute(
-                            config,
-                            Arc::clone(&self.key_registry),
-                            gateway,
-                            input.input_stream,
-                        )),
-                    );
-                    Ok(())
-                } else {
-                    let error = StateError::InvalidState {
-                        from: QueryStatus::from(&state),
-                        to: QueryStatus::Running,
-                    };
-                    queries.insert(input.query_id, state);
-                    Err(QueryInputError::StateError { source: error })
+                match state {
+                    QueryState::AwaitingInputs(query_id, config, role_assignment) => {
+                        assert_eq!(
+                            input.query_id, query_id,
+                            "received inputs for a different query"
+                        );
+                        if input.part != InputPart::All {
+                            if let Err(e) = split_input_config(config) {
+                                queries.insert(
+                                    query_id,
+                                    QueryState::AwaitingInputs(query_id, config, role_assignment),
+                                );
+                                return Err(e);
+                            }
+                        }
+                        let new_state = if input.part == InputPart::All {
+                            self.start(
+                                transport,
+                                query_id,
+                                config,
+                                role_assignment,
+                                input.input_stream,
+                            )
+                        } else {
+                            tracing::info!("received {:?}, awaiting the other part", input.part);
+                            QueryState::AwaitingSecondInput(
+                                query_id,
+                                config,
+                                role_assignment,
+                                input,
+                            )
+                        };
+                        queries.insert(query_id, new_state);
+                        Ok(())
+                    }
+                    QueryState::AwaitingSecondInput(query_id, config, role_assignment, first)
+                        if first.part.complement() == Some(input.part) =>
+                    {
+                        assert_eq!(
+                            input.query_id, query_id,
+                            "received inputs for a different query"
+                        );
+                        let (sources, triggers) = if first.part == InputPart::SourceEvents {
+                            (first, input)
+                        } else {
+                            (input, first)
+                        };
+                        // checked when the first part arrived
+                        let config = split_input_config(config).unwrap();
+                        queries.insert(
+                            query_id,
+                            self.start(
+                                transport,
+                                query_id,
+                                config,
+                                role_assignment,
+                                sources.input_stream.append(triggers.input_stream),
+                            ),
+                        );
+                        Ok(())
+                    }
+                    QueryState::AwaitingSecondInput(_, _, _, ref first) => {
+                        let error = QueryInputError::UnexpectedInputPart {
+                            received: input.part,
+                            awaiting: first.part.complement().unwrap(),
+                        };
+                        queries.insert(input.query_id, state);
+                        Err(error)
+                    }
+                    state => {
+                        let error = StateError::InvalidState {
+                            from: QueryStatus::from(&state),
+                            to: QueryStatus::Running,
+                        };
+                        queries.insert(input.query_id, state);
+                        Err(QueryInputError::StateError { source: error })
+                    }
                 }
             }
             Entry::Vacant(_) => Err(QueryInputError::NoSuchQuery(input.query_id)),
         }
     }
 
+    fn start(
+        &self,
+        transport: TransportImpl,
+        query_id: QueryId,
+        config: QueryConfig,
+        role_assignment: RoleAssignment,
+        input_stream: BodyStream,
+    ) -> QueryState {
+        let manifest = QueryManifest {
+            config,
+            role: role_assignment.role(transport.identity()),
+        };
+        let input_stream = match &self.query_log {
+            Some(log) => match log.start(&manifest) {
+                Ok(entry) => {
+                    let input_stream = entry.record_input(input_stream);
+                    // The previous query with this id was never completed.
+                    if let Some(stale) = self
+                        .pending_log_entries
+                        .lock()
+                        .unwrap()
+                        .insert(query_id, entry)
+                    {
+                        stale.discard();
+                    }
+                    input_stream
+                }
+                Err(e) => {
+                    tracing::warn!("failed to log query {query_id}: {e}");
+                    input_stream
+                }
+            },
+            None => input_stream,
+        };
+        let input_stream = if self.report_signer.is_some() {
+            let report = PendingReport::new(manifest);
+            let input_stream = report.record_input(input_stream);
+            self.pending_reports
+                .lock()
+                .unwrap()
+                .insert(query_id, report);
+            input_stream
+        } else {
+            input_stream
+        };
+
+        let gateway = Gateway::new(
+            query_id,
+            GatewayConfig {
+                heartbeat: self.heartbeat,
+                flow_control: self.flow_control,
+                commit_outputs: self.commit_outputs,
+                memory_budget: self.memory_budget,
+                ..GatewayConfig::from(&config)
+            },
+            role_assignment,
+            transport,
+        );
+        gateway
+            .progress_tracker()
+            .report_to(Arc::clone(&self.counters));
+        QueryState::Running(executor::execute(
+            config,
+            Arc::clone(&self.key_registry),
+            self.prf_key.clone(),
+            gateway,
+            input_stream,
+            self.result_cache.clone(),
+        ))
+    }
+
     /// Returns the query status.
     ///
     /// ## Errors
@@ -256,6 +598,21 @@ impl Processor {
     /// ## Panics
     /// If the query collection mutex is poisoned.
     pub fn query_status(&self, query_id: QueryId) -> Result<QueryStatus, QueryStatusError> {
+        self.query_status_report(query_id)
+            .map(|report| report.status)
+    }
+
+    /// Returns the query status along with the progress of the protocol, if query is running.
+    ///
+    /// ## Errors
+    /// If query is not registered on this helper.
+    ///
+    /// ## Panics
+    /// If the query collection mutex is poisoned.
+    pub fn query_status_report(
+        &self,
+        query_id: QueryId,
+    ) -> Result<QueryStatusReport, QueryStatusError> {
         let mut queries = self.queries.inner.lock().unwrap();
         let Some(mut state) = queries.remove(&query_id) else {
             return Err(QueryStatusError::NoSuchQuery(query_id));
@@ -267,21 +624,116 @@ impl Processor {
             }
         }
 
-        let status = QueryStatus::from(&state);
+        let report = QueryStatusReport::from(&state);
         queries.insert(query_id, state);
-        Ok(status)
+        Ok(report)
     }
 
-    /// Awaits the query completion
+    /// Returns the traffic and randomness used by all queries this helper ran so far, along with
+    /// the number of queries that are still running.
+    #[must_use]
+    pub fn metrics(&self) -> HelperMetrics {
+        self.counters.snapshot()
+    }
+
+    /// Awaits the query completion. If a results store is configured, the output is written
+    /// there and queries that are no longer running are looked up in it. If a query log is
+    /// configured, the query is added to it. If a report signer is configured, the output comes
+    /// with the signed report of the query.
     ///
     /// ## Errors
-    /// if query is not registered on this helper.
+    /// if query is not registered on this helper and its output is not stored, or if the
+    /// results store fails.
     ///
     /// ## Panics
     /// If failed to obtain an exclusive access to the query collection.
+    #[tracing::instrument("complete_query", skip_all, fields(query_id = %query_id))]
     pub async fn complete(
         &self,
         query_id: QueryId,
+    ) -> Result<Box<dyn ProtocolResult>, QueryCompletionError> {
+        let result = match self.complete_running(query_id).await {
+            Err(QueryCompletionError::NoSuchQuery(_)) => return self.load_result(query_id).await,
+            result => result,
+        };
+
+        let log_entry = self.pending_log_entries.lock().unwrap().remove(&query_id);
+        let pending_report = self.pending_reports.lock().unwrap().remove(&query_id);
+        let result = match result {
+            Ok(result) => result,
+            Err(e) => {
+                if let Some(entry) = log_entry {
+                    entry.discard();
+                }
+                return Err(e);
+            }
+        };
+        if self.results_store.is_none() && log_entry.is_none() && pending_report.is_none() {
+            return Ok(result);
+        }
+
+        let cache_status = result.cache_status();
+        let metrics = result.metrics().cloned();
+        let partial = result.partial();
+        let commitments = result.commitments().cloned();
+        let result = result.into_bytes();
+        let report = pending_report
+            .zip(self.report_signer.as_deref())
+            .map(|(pending, signer)| pending.finish(&result, metrics.clone(), commitments, signer));
+        if let (Some(entry), Some(log)) = (log_entry, &self.query_log) {
+            // Report collector should get the results even if the query could not be logged.
+            if let Err(e) = entry.commit(log, &result) {
+                tracing::warn!("failed to log query {query_id}: {e}");
+            }
+        }
+        let result = match &self.results_store {
+            Some(store) => {
+                let store = Arc::clone(store);
+                results_store::blocking(move || store.store(query_id, &result).map(|()| result))
+                    .await?
+            }
+            None => result,
+        };
+
+        let result: Box<dyn ProtocolResult> = match cache_status {
+            Some(status) => Box::new(CachedResult::new(result, status)),
+            None => Box::new(StoredResult(result)),
+        };
+        let result: Box<dyn ProtocolResult> = match partial {
+            Some(completeness) => Box::new(PartialResult::new(result, completeness)),
+            None => result,
+        };
+        let result: Box<dyn ProtocolResult> = match metrics {
+            Some(metrics) => Box::new(MeasuredResult::new(result, metrics)),
+            None => result,
+        };
+        Ok(match report {
+            Some(report) => Box::new(ReportedResult::new(result, report)),
+            None => result,
+        })
+    }
+
+    /// Looks up the output of a query that is no longer tracked by this processor.
+    async fn load_result(
+        &self,
+        query_id: QueryId,
+    ) -> Result<Box<dyn ProtocolResult>, QueryCompletionError> {
+        let stored = match &self.results_store {
+            Some(store) => {
+                let store = Arc::clone(store);
+                results_store::blocking(move || store.load(query_id)).await?
+            }
+            None => None,
+        };
+
+        stored
+            .map(|result| Box::new(StoredResult(result)) as Box<dyn ProtocolResult>)
+            .ok_or(QueryCompletionError::NoSuchQuery(query_id))
+    }
+
+    async fn complete_running(
+        &self,
+        query_id: QueryId,
     ) -> Result<Box<dyn ProtocolResult>, QueryCompletionError> {
         let handle = {
             let mut queries = self.queries.inner.lock().unwrap();
@@ -310,6 +762,27 @@ impl Processor {
     }
 }
 
+/// Returns the config to run `config` with once both parts of split input have arrived.
+///
+/// Rows of a user are spread over both parts, and OPRF IPA attributes rows of a user in the order
+/// they come in, so it needs to sort them by timestamp first. Other queries don't depend on the
+/// order of rows.
+fn split_input_config(config: QueryConfig) -> Result<QueryConfig, QueryInputError> {
+    match config.query_type {
+        QueryType::OprfIpa(ipa) if ipa.timestamp_bits == 0 => {
+            Err(QueryInputError::SplitInputWithoutTimestamps)
+        }
+        QueryType::OprfIpa(ipa) => Ok(QueryConfig {
+            query_type: QueryType::OprfIpa(IpaQueryConfig {
+                sort_by_timestamp: true,
+                ..ipa
+            }),
+            ..config
+        }),
+        _ => Ok(config),
+    }
+}
+
 #[cfg(all(test, unit_test))]
 mod tests {
     use std::{array, future::Future, sync::Arc};
@@ -375,7 +848,8 @@ mod tests {
         // poll future once to trigger query status change
         let _qc = poll_immediate(&mut qc_future).await;
 
-        assert_eq!(QueryStatus::Preparing, p0.query_status(QueryId).unwrap());
+        let query_id = *p0.queries.inner.lock().unwrap().keys().next().unwrap();
+        assert_eq!(QueryStatus::Preparing, p0.query_status(query_id).unwrap());
         // unblock sends
         barrier.wait().await;
 
@@ -384,20 +858,21 @@ mod tests {
 
         assert_eq!(
             PrepareQuery {
-                query_id: QueryId,
+                query_id,
                 config: request,
                 roles: expected_assignment,
+                reservation: request.reservation(),
             },
             qc
         );
         assert_eq!(
             QueryStatus::AwaitingInputs,
-            p0.query_status(QueryId).unwrap()
+            p0.query_status(query_id).unwrap()
         );
     }
 
     #[tokio::test]
-    async fn rejects_duplicate_query_id() {
+    async fn assigns_unique_query_ids() {
         let cb = array::from_fn(|_| TransportCallbacks {
             prepare_query: prepare_query_callback(|_, _| async { Ok(()) }),
             ..Default::default()
@@ -407,14 +882,20 @@ mod tests {
         let p0 = Processor::default();
         let request = test_multiply_config();
 
-        let _qc = p0
+        let first = p0
             .new_query(Transport::clone_ref(&t0), request)
             .await
             .unwrap();
-        assert!(matches!(
-            p0.new_query(t0, request).await,
-            Err(NewQueryError::State(StateError::AlreadyRunning)),
-        ));
+        let second = p0.new_query(t0, request).await.unwrap();
+        assert_ne!(first.query_id, second.query_id);
+        assert_eq!(
+            QueryStatus::AwaitingInputs,
+            p0.query_status(first.query_id).unwrap()
+        );
+        assert_eq!(
+            QueryStatus::AwaitingInputs,
+            p0.query_status(second.query_id).unwrap()
+        );
     }
 
     #[tokio::test]
@@ -440,6 +921,61 @@ mod tests {
         ));
     }
 
+    #[tokio::test]
+    async fn coordinator_rejects_unaffordable_query() {
+        let network = InMemoryNetwork::default();
+        let [t0, _, _] = network.transports();
+        let request = test_multiply_config();
+        let p0 = Processor::default().with_resource_limits(ResourceLimits {
+            memory_bytes: u64::MAX,
+            spool_bytes: u64::MAX,
+            parallelism: request.reservation().parallelism - 1,
+        });
+
+        assert!(matches!(
+            p0.new_query(t0, request).await.unwrap_err(),
+            NewQueryError::Reservation(ReservationError::Insufficient { .. })
+        ));
+        assert!(p0.queries.inner.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn coordinator_applies_admission_limits() {
+        let cb = array::from_fn(|_| TransportCallbacks {
+            prepare_query: prepare_query_callback(|_, _| async { Ok(()) }),
+            ..Default::default()
+        });
+        let network = InMemoryNetwork::new(cb);
+        let [t0, _, _] = network.transports();
+        let limits = AdmissionLimits {
+            max_records: 1,
+            max_input_bytes: u64::MAX,
+            max_concurrent_queries: 1,
+        };
+        let p0 = Processor::default().with_admission_limits(limits);
+
+        let too_large = QueryConfig::new(TestMultiply, FieldType::Fp31, 2).unwrap();
+        assert!(matches!(
+            p0.new_query(Transport::clone_ref(&t0), too_large)
+                .await
+                .unwrap_err(),
+            NewQueryError::Admission(AdmissionError::TooManyRecords { .. })
+        ));
+        assert!(p0.queries.inner.lock().unwrap().is_empty());
+
+        let request = test_multiply_config();
+        p0.new_query(Transport::clone_ref(&t0), request)
+            .await
+            .unwrap();
+        assert!(matches!(
+            p0.new_query(t0, request).await.unwrap_err(),
+            NewQueryError::Admission(AdmissionError::TooManyQueries {
+                running: 1,
+                limit: 1
+            })
+        ));
+    }
+
     #[tokio::test]
     async fn can_recover_from_prepare_error() {
         let cb2 = TransportCallbacks {
@@ -465,13 +1001,17 @@ mod tests {
     }
 
     mod prepare {
+        use std::num::NonZeroU32;
+
         use super::*;
+        use crate::helpers::query::IpaQueryConfig;
 
         fn prepare_query(identities: [HelperIdentity; 3]) -> PrepareQuery {
             PrepareQuery {
-                query_id: QueryId,
+                query_id: QueryId::default(),
                 config: test_multiply_config(),
                 roles: RoleAssignment::new(identities),
+                reservation: test_multiply_config().reservation(),
             }
         }
 
@@ -484,13 +1024,13 @@ mod tests {
             let processor = Processor::default();
 
             assert!(matches!(
-                processor.query_status(QueryId).unwrap_err(),
+                processor.query_status(QueryId::default()).unwrap_err(),
                 QueryStatusError::NoSuchQuery(_)
             ));
             processor.prepare(&transport, req).unwrap();
             assert_eq!(
                 QueryStatus::AwaitingInputs,
-                processor.query_status(QueryId).unwrap()
+                processor.query_status(QueryId::default()).unwrap()
             );
         }
 
@@ -521,6 +1061,374 @@ mod tests {
                 Err(PrepareQueryError::AlreadyRunning)
             ));
         }
+
+        #[tokio::test]
+        async fn rejects_attribution_window_too_large() {
+            let network = InMemoryNetwork::default();
+            let identities = HelperIdentity::make_three();
+            let mut req = prepare_query(identities);
+            req.config.query_type = QueryType::OprfIpa(IpaQueryConfig {
+                attribution_window_seconds: NonZeroU32::new(30 * 86_400),
+                timestamp_bits: 20,
+                ..Default::default()
+            });
+            let transport = network.transport(identities[1]);
+            let processor = Processor::default();
+
+            assert!(matches!(
+                processor.prepare(&transport, req),
+                Err(PrepareQueryError::Config(
+                    QueryConfigError::AttributionWindowTooLarge { .. }
+                ))
+            ));
+            assert!(processor.query_status(QueryId::default()).is_err());
+        }
+
+        #[tokio::test]
+        async fn rejects_reservation_mismatch() {
+            let network = InMemoryNetwork::default();
+            let identities = HelperIdentity::make_three();
+            let mut req = prepare_query(identities);
+            req.reservation.memory_bytes /= 2;
+            let transport = network.transport(identities[1]);
+            let processor = Processor::default();
+
+            assert!(matches!(
+                processor.prepare(&transport, req),
+                Err(PrepareQueryError::Reservation(
+                    ReservationError::Mismatch { .. }
+                ))
+            ));
+            assert!(processor.query_status(QueryId::default()).is_err());
+        }
+
+        #[tokio::test]
+        async fn rejects_unaffordable_query() {
+            let network = InMemoryNetwork::default();
+            let identities = HelperIdentity::make_three();
+            let req = prepare_query(identities);
+            let transport = network.transport(identities[1]);
+            let processor = Processor::default().with_resource_limits(ResourceLimits {
+                memory_bytes: req.reservation.memory_bytes - 1,
+                spool_bytes: u64::MAX,
+                parallelism: u32::MAX,
+            });
+
+            assert!(matches!(
+                processor.prepare(&transport, req),
+                Err(PrepareQueryError::Reservation(
+                    ReservationError::Insufficient { .. }
+                ))
+            ));
+            assert!(processor.query_status(QueryId::default()).is_err());
+        }
+
+        #[tokio::test]
+        async fn rejects_oversized_query() {
+            let network = InMemoryNetwork::default();
+            let identities = HelperIdentity::make_three();
+            let req = prepare_query(identities);
+            let transport = network.transport(identities[1]);
+            let processor = Processor::default().with_admission_limits(AdmissionLimits {
+                max_records: u32::MAX,
+                max_input_bytes: req.reservation.spool_bytes - 1,
+                max_concurrent_queries: 1,
+            });
+
+            assert!(matches!(
+                processor.prepare(&transport, req),
+                Err(PrepareQueryError::Admission(
+                    AdmissionError::InputTooLarge { .. }
+                ))
+            ));
+            assert!(processor.query_status(QueryId::default()).is_err());
+        }
+    }
+
+    mod receive_inputs {
+        use super::*;
+        use crate::helpers::{query::InputPart, InMemoryTransport};
+
+        fn input(part: InputPart) -> QueryInput {
+            QueryInput {
+                query_id: QueryId::default(),
+                part,
+                input_stream: vec![0u8; 4].into(),
+            }
+        }
+
+        #[tokio::test]
+        async fn awaits_second_part() {
+            let network = InMemoryNetwork::default();
+            let identities = HelperIdentity::make_three();
+            let transport = network.transport(identities[1]);
+            let processor = Processor::default();
+            processor
+                .prepare(
+                    &transport,
+                    PrepareQuery {
+                        query_id: QueryId::default(),
+                        config: test_multiply_config(),
+                        roles: RoleAssignment::new(identities),
+                        reservation: test_multiply_config().reservation(),
+                    },
+                )
+                .unwrap();
+
+            processor
+                .receive_inputs(
+                    <InMemoryTransport as Clone>::clone(&transport),
+                    input(InputPart::TriggerEvents),
+                )
+                .unwrap();
+            assert_eq!(
+                QueryStatus::AwaitingInputs,
+                processor.query_status(QueryId::default()).unwrap()
+            );
+
+            assert!(matches!(
+                processor.receive_inputs(
+                    <InMemoryTransport as Clone>::clone(&transport),
+                    input(InputPart::TriggerEvents)
+                ),
+                Err(QueryInputError::UnexpectedInputPart {
+                    received: InputPart::TriggerEvents,
+                    awaiting: InputPart::SourceEvents,
+                })
+            ));
+            assert!(matches!(
+                processor.receive_inputs(
+                    <InMemoryTransport as Clone>::clone(&transport),
+                    input(InputPart::All)
+                ),
+                Err(QueryInputError::UnexpectedInputPart { .. })
+            ));
+            assert_eq!(
+                QueryStatus::AwaitingInputs,
+                processor.query_status(QueryId::default()).unwrap()
+            );
+        }
+
+        #[tokio::test]
+        async fn split_input_requires_timestamps() {
+            let config = QueryConfig::new(
+                QueryType::OprfIpa(IpaQueryConfig {
+                    timestamp_bits: 0,
+                    ..IpaQueryConfig::default()
+                }),
+                FieldType::Fp32BitPrime,
+                1,
+            )
+            .unwrap();
+            let network = InMemoryNetwork::default();
+            let identities = HelperIdentity::make_three();
+            let transport = network.transport(identities[1]);
+            let processor = Processor::default();
+            processor
+                .prepare(
+                    &transport,
+                    PrepareQuery {
+                        query_id: QueryId::default(),
+                        config,
+                        roles: RoleAssignment::new(identities),
+                        reservation: config.reservation(),
+                    },
+                )
+                .unwrap();
+
+            assert!(matches!(
+                processor.receive_inputs(
+                    <InMemoryTransport as Clone>::clone(&transport),
+                    input(InputPart::SourceEvents)
+                ),
+                Err(QueryInputError::SplitInputWithoutTimestamps)
+            ));
+            assert_eq!(
+                QueryStatus::AwaitingInputs,
+                processor.query_status(QueryId::default()).unwrap()
+            );
+        }
+    }
+
+    mod results_store {
+        use super::*;
+        use crate::{
+            ff::{Field, Fp31},
+            query::{FsResultsStore, ResultsStore},
+        };
+
+        #[tokio::test]
+        async fn stores_and_loads() {
+            let dir = tempfile::tempdir().unwrap();
+            let store = Arc::new(FsResultsStore::new(dir.path()).unwrap());
+            let processor = Processor::default().with_results_store(Arc::clone(&store) as _);
+
+            let result = vec![Fp31::truncate_from(7_u128)];
+            processor.queries.inner.lock().unwrap().insert(
+                QueryId::default(),
+                QueryState::Completed(Ok(Box::new(result.clone()))),
+            );
+            let expected = (Box::new(result) as Box<dyn ProtocolResult>).into_bytes();
+
+            assert_eq!(
+                expected,
+                processor
+                    .complete(QueryId::default())
+                    .await
+                    .unwrap()
+                    .into_bytes()
+            );
+            assert_eq!(
+                Some(expected.clone()),
+                store.load(QueryId::default()).unwrap()
+            );
+
+            // query is no longer tracked, but its output can still be retrieved
+            assert_eq!(
+                expected,
+                processor
+                    .complete(QueryId::default())
+                    .await
+                    .unwrap()
+                    .into_bytes()
+            );
+        }
+
+        #[tokio::test]
+        async fn no_such_query() {
+            let dir = tempfile::tempdir().unwrap();
+            let processor = Processor::default()
+                .with_results_store(Arc::new(FsResultsStore::new(dir.path()).unwrap()));
+
+            assert!(matches!(
+                processor.complete(QueryId::default()).await.unwrap_err(),
+                QueryCompletionError::NoSuchQuery(_)
+            ));
+        }
+    }
+
+    mod query_log {
+        use std::num::NonZeroUsize;
+
+        use futures::TryStreamExt;
+
+        use super::*;
+        use crate::{
+            ff::{Field, Fp31},
+            query::{runner::QueryResult, QueryLog},
+        };
+
+        async fn log_query(processor: &Processor, log: &QueryLog, result: QueryResult) {
+            let manifest = QueryManifest {
+                config: test_multiply_config(),
+                role: Role::H1,
+            };
+            let entry = log.start(&manifest).unwrap();
+            entry
+                .record_input(BodyStream::from(vec![1, 2, 3]))
+                .try_concat()
+                .await
+                .unwrap();
+            processor
+                .pending_log_entries
+                .lock()
+                .unwrap()
+                .insert(QueryId::default(), entry);
+            processor
+                .queries
+                .inner
+                .lock()
+                .unwrap()
+                .insert(QueryId::default(), QueryState::Completed(result));
+        }
+
+        #[tokio::test]
+        async fn logs_completed() {
+            let dir = tempfile::tempdir().unwrap();
+            let log = Arc::new(QueryLog::new(dir.path(), NonZeroUsize::new(1).unwrap()).unwrap());
+            let processor = Processor::default().with_query_log(Arc::clone(&log));
+
+            let result = vec![Fp31::truncate_from(7_u128)];
+            let expected = (Box::new(result.clone()) as Box<dyn ProtocolResult>).into_bytes();
+            log_query(&processor, &log, Ok(Box::new(result))).await;
+
+            assert_eq!(
+                expected,
+                processor
+                    .complete(QueryId::default())
+                    .await
+                    .unwrap()
+                    .into_bytes()
+            );
+            let [entry] = <[_; 1]>::try_from(log.entries().unwrap()).unwrap();
+            assert_eq!(test_multiply_config(), entry.manifest().unwrap().config);
+            assert_eq!(vec![1, 2, 3], entry.input().unwrap());
+            assert_eq!(expected, entry.output().unwrap());
+        }
+
+        #[tokio::test]
+        async fn skips_failed() {
+            let dir = tempfile::tempdir().unwrap();
+            let log = Arc::new(QueryLog::new(dir.path(), NonZeroUsize::new(1).unwrap()).unwrap());
+            let processor = Processor::default().with_query_log(Arc::clone(&log));
+
+            log_query(&processor, &log, Err(ProtocolError::Internal)).await;
+
+            assert!(processor.complete(QueryId::default()).await.is_err());
+            assert!(log.entries().unwrap().is_empty());
+            assert_eq!(0, std::fs::read_dir(dir.path()).unwrap().count());
+        }
+    }
+
+    mod report {
+        use ed25519_dalek::SigningKey;
+        use futures::TryStreamExt;
+        use sha2::{Digest, Sha256};
+
+        use super::*;
+        use crate::{
+            ff::{Field, Fp31},
+            query::ReportSigner,
+        };
+
+        #[tokio::test]
+        async fn signs_completed() {
+            let signer = Arc::new(ReportSigner::new(SigningKey::from_bytes(&[7; 32])));
+            let processor = Processor::default().with_report_signer(Arc::clone(&signer));
+
+            let pending = PendingReport::new(QueryManifest {
+                config: test_multiply_config(),
+                role: Role::H1,
+            });
+            pending
+                .record_input(BodyStream::from(vec![1, 2, 3]))
+                .try_concat()
+                .await
+                .unwrap();
+            processor
+                .pending_reports
+                .lock()
+                .unwrap()
+                .insert(QueryId::default(), pending);
+            let result = vec![Fp31::truncate_from(7_u128)];
+            processor.queries.inner.lock().unwrap().insert(
+                QueryId::default(),
+                QueryState::Completed(Ok(Box::new(result))),
+            );
+
+            let result = processor.complete(QueryId::default()).await.unwrap();
+            let report = result.report().unwrap().clone();
+            report.verify(&signer.verifying_key()).unwrap();
+            assert_eq!(test_multiply_config(), report.report.manifest.config);
+            assert_eq!(
+                <[u8; 32]>::from(Sha256::digest([1, 2, 3])),
+                report.report.input_digest
+            );
+            assert_eq!(
+                <[u8; 32]>::from(Sha256::digest(result.into_bytes())),
+                report.report.output_digest
+            );
+        }
     }
 
     mod e2e {
@@ -558,6 +1466,29 @@ mod tests {
             ))
         }
 
+        #[tokio::test]
+        async fn complete_query_split_input() -> Result<(), BoxError> {
+            let app = TestApp::default();
+            let a = Fp31::truncate_from(4u128);
+            let b = Fp31::truncate_from(5u128);
+            let query_id = app
+                .start_query_split(
+                    vec![a].into_iter(),
+                    vec![b].into_iter(),
+                    test_multiply_config(),
+                )
+                .await?;
+
+            let results = app.complete_query(query_id).await?.map(|bytes| {
+                semi_honest::AdditiveShare::<Fp31>::from_byte_slice(&bytes).collect::<Vec<_>>()
+            });
+
+            Ok(assert_eq!(
+                vec![Fp31::truncate_from(20u128)],
+                results.reconstruct()
+            ))
+        }
+
         #[tokio::test]
         async fn complete_query_status_poll() -> Result<(), BoxError> {
             let app = TestApp::default();
@@ -623,6 +1554,7 @@ mod tests {
                             attribution_window_seconds: None,
                             num_multi_bits: 3,
                             plaintext_match_keys: true,
+                            ..Default::default()
                         }),
                     },
                 )
@@ -0,0 +1,305 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+
+use crate::helpers::{query::QueryConfig, BodyStream, Role};
+
+const MANIFEST_FILE: &str = "manifest.json";
+const INPUT_FILE: &str = "input.bin";
+const OUTPUT_FILE: &str = "output.bin";
+const PARTIAL_EXTENSION: &str = "partial";
+
+/// Describes how a logged query was run on this helper.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueryManifest {
+    pub config: QueryConfig,
+    pub role: Role,
+}
+
+/// Keeps the exact input and output of recently completed queries, so a query can be run again on
+/// the same input, for instance to compare its output before and after a protocol fix.
+///
+/// Every query is kept in its own directory, along with the [`QueryManifest`] needed to run it
+/// again. Inputs are stored as they were received from the report collector, so encrypted inputs
+/// stay encrypted. Only the most recent `retention` queries are kept. Queries that fail are not
+/// logged.
+///
+/// Logging blocks the thread that reads query input.
+#[derive(Debug)]
+pub struct QueryLog {
+    dir: PathBuf,
+    retention: NonZeroUsize,
+    last_id: AtomicU64,
+}
+
+impl QueryLog {
+    /// Creates a log in the given directory, creating it if it does not exist. Queries that were
+    /// still running when the log was last used are discarded.
+    ///
+    /// ## Errors
+    /// If the directory could not be created or read.
+    pub fn new<P: Into<PathBuf>>(dir: P, retention: NonZeroUsize) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == PARTIAL_EXTENSION) {
+                fs::remove_dir_all(path)?;
+            }
+        }
+
+        Ok(Self {
+            dir,
+            retention,
+            last_id: AtomicU64::new(0),
+        })
+    }
+
+    /// Returns the logged queries, oldest first.
+    ///
+    /// ## Errors
+    /// If the log directory could not be read.
+    pub fn entries(&self) -> io::Result<Vec<LogEntry>> {
+        let mut entries = fs::read_dir(&self.dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .filter(|path| {
+                path.as_ref()
+                    .map_or(true, |path| path.extension().is_none())
+            })
+            .map(|path| path.map(LogEntry::open))
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(entries)
+    }
+
+    /// Starts logging a query. The entry becomes visible in [`entries`] once it is
+    /// [`committed`].
+    ///
+    /// [`entries`]: Self::entries
+    /// [`committed`]: PendingEntry::commit
+    ///
+    /// ## Errors
+    /// If the entry could not be created.
+    pub(super) fn start(&self, manifest: &QueryManifest) -> io::Result<PendingEntry> {
+        // Entries are named after the time they were started, so they sort oldest first.
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| u64::try_from(d.as_millis()).unwrap_or(u64::MAX));
+        let last = self
+            .last_id
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| {
+                Some(now.max(last + 1))
+            })
+            .unwrap(); // the closure never returns `None`
+        let id = now.max(last + 1);
+
+        let path = self.dir.join(format!("{id:020}"));
+        let partial_path = path.with_extension(PARTIAL_EXTENSION);
+        fs::create_dir(&partial_path)?;
+        fs::write(
+            partial_path.join(MANIFEST_FILE),
+            serde_json::to_vec_pretty(manifest)?,
+        )?;
+
+        Ok(PendingEntry { path, partial_path })
+    }
+
+    /// Removes the oldest entries beyond the retention limit.
+    fn prune(&self) -> io::Result<()> {
+        let entries = self.entries()?;
+        let excess = entries.len().saturating_sub(self.retention.get());
+        for entry in &entries[..excess] {
+            fs::remove_dir_all(&entry.path)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A query that is being logged.
+#[derive(Debug)]
+pub(super) struct PendingEntry {
+    path: PathBuf,
+    partial_path: PathBuf,
+}
+
+impl PendingEntry {
+    /// Returns a stream that yields the same data as `input`, writing it to the log as it is
+    /// read. If writing fails, the query keeps running, but it will not be logged.
+    pub fn record_input(&self, input: BodyStream) -> BodyStream {
+        let path = self.partial_path.join(INPUT_FILE);
+        let mut file = match File::create(&path) {
+            Ok(file) => Some(file),
+            Err(e) => {
+                tracing::warn!("failed to log query input: {e}");
+                None
+            }
+        };
+
+        BodyStream::from_bytes_stream(input.map(move |chunk| {
+            if let (Some(f), Ok(bytes)) = (&mut file, &chunk) {
+                if let Err(e) = f.write_all(bytes) {
+                    tracing::warn!("failed to log query input: {e}");
+                    file = None;
+                    let _ = fs::remove_file(&path);
+                }
+            }
+            chunk
+        }))
+    }
+
+    /// Writes the output of the query and makes the entry visible, evicting old entries from
+    /// `log` if needed.
+    ///
+    /// ## Errors
+    /// If the input could not be logged, or if the entry could not be written.
+    pub fn commit(self, log: &QueryLog, output: &[u8]) -> io::Result<()> {
+        File::open(self.partial_path.join(INPUT_FILE))?.sync_all()?;
+        let mut file = File::create(self.partial_path.join(OUTPUT_FILE))?;
+        file.write_all(output)?;
+        file.sync_all()?;
+        fs::rename(&self.partial_path, &self.path)?;
+
+        log.prune()
+    }
+
+    /// Discards the entry, for instance because the query failed.
+    pub fn discard(self) {
+        if let Err(e) = fs::remove_dir_all(&self.partial_path) {
+            tracing::warn!("failed to remove {}: {e}", self.partial_path.display());
+        }
+    }
+}
+
+/// A query logged by [`QueryLog`].
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    path: PathBuf,
+}
+
+impl LogEntry {
+    /// Opens an entry at the given path, for instance one copied from a helper's log.
+    #[must_use]
+    pub fn open<P: Into<PathBuf>>(path: P) -> Self {
+        Self { path: path.into() }
+    }
+
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// ## Errors
+    /// If the manifest could not be read or parsed.
+    pub fn manifest(&self) -> io::Result<QueryManifest> {
+        Ok(serde_json::from_slice(&fs::read(
+            self.path.join(MANIFEST_FILE),
+        )?)?)
+    }
+
+    /// Returns the query input, exactly as it was received from the report collector.
+    ///
+    /// ## Errors
+    /// If the input could not be read.
+    pub fn input(&self) -> io::Result<Vec<u8>> {
+        fs::read(self.path.join(INPUT_FILE))
+    }
+
+    /// Returns the query output, as it was sent to the report collector.
+    ///
+    /// ## Errors
+    /// If the output could not be read.
+    pub fn output(&self) -> io::Result<Vec<u8>> {
+        fs::read(self.path.join(OUTPUT_FILE))
+    }
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use std::num::NonZeroUsize;
+
+    use futures::TryStreamExt;
+
+    use super::{QueryLog, QueryManifest};
+    use crate::{
+        ff::FieldType,
+        helpers::{
+            query::{QueryConfig, QueryType},
+            BodyStream, Role,
+        },
+    };
+
+    fn manifest() -> QueryManifest {
+        QueryManifest {
+            config: QueryConfig::new(QueryType::TestMultiply, FieldType::Fp31, 1).unwrap(),
+            role: Role::H2,
+        }
+    }
+
+    async fn log_query(log: &QueryLog, input: &[u8], output: &[u8]) {
+        let entry = log.start(&manifest()).unwrap();
+        let recorded = entry
+            .record_input(BodyStream::from(input.to_vec()))
+            .try_concat()
+            .await
+            .unwrap();
+        assert_eq!(input, recorded.as_ref());
+        entry.commit(log, output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = QueryLog::new(dir.path(), NonZeroUsize::new(5).unwrap()).unwrap();
+
+        log_query(&log, &[1, 2, 3], &[4, 5]).await;
+
+        let [entry] = <[_; 1]>::try_from(log.entries().unwrap()).unwrap();
+        assert_eq!(manifest(), entry.manifest().unwrap());
+        assert_eq!(vec![1, 2, 3], entry.input().unwrap());
+        assert_eq!(vec![4, 5], entry.output().unwrap());
+    }
+
+    #[tokio::test]
+    async fn retention() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = QueryLog::new(dir.path(), NonZeroUsize::new(2).unwrap()).unwrap();
+
+        for i in 0..4 {
+            log_query(&log, &[i], &[i]).await;
+        }
+
+        let inputs = log
+            .entries()
+            .unwrap()
+            .iter()
+            .map(|entry| entry.input().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(vec![vec![2], vec![3]], inputs);
+    }
+
+    #[tokio::test]
+    async fn pending_entries_are_hidden() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = QueryLog::new(dir.path(), NonZeroUsize::new(2).unwrap()).unwrap();
+
+        let discarded = log.start(&manifest()).unwrap();
+        let _running = log.start(&manifest()).unwrap();
+        assert!(log.entries().unwrap().is_empty());
+        discarded.discard();
+
+        // a pending entry left behind by a previous run is removed
+        let log = QueryLog::new(dir.path(), NonZeroUsize::new(2).unwrap()).unwrap();
+        assert_eq!(0, std::fs::read_dir(dir.path()).unwrap().count());
+        assert!(log.entries().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,259 @@
+use std::{
+    fs, io,
+    num::NonZeroU32,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    helpers::{
+        query::{DpParameter, QueryType},
+        BodyStream, QueryMetrics, StageMetrics,
+    },
+    query::{OutputCommitments, QueryManifest},
+};
+
+/// Parameters of differential privacy the query output was protected with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DpParameters {
+    pub epsilon: DpParameter,
+    pub delta: DpParameter,
+    /// Largest number of rows of dummy users added before PRF values were revealed, if any.
+    pub padding_max_user_rows: Option<NonZeroU32>,
+}
+
+impl DpParameters {
+    /// Returns the parameters used by queries of the given type, `None` if their output is exact.
+    #[must_use]
+    pub fn of(query_type: &QueryType) -> Option<Self> {
+        match query_type {
+            QueryType::SemiHonestIpa(config)
+            | QueryType::MaliciousIpa(config)
+            | QueryType::OprfIpa(config) => config.dp_epsilon.map(|epsilon| Self {
+                epsilon,
+                delta: config.dp_delta,
+                padding_max_user_rows: config.dp_padding_max_user_rows,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Summary of a query completed by a single helper. Together with the output it was produced
+/// with, it is the record of what the helper did, which auditors can check against what the report
+/// collector asked for and received.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueryReport {
+    pub manifest: QueryManifest,
+    /// SHA-256 of the query input, exactly as this helper received it.
+    #[serde(with = "hex")]
+    pub input_digest: [u8; 32],
+    pub dp: Option<DpParameters>,
+    /// Stages the query ran, with the time they took and the traffic they generated on this
+    /// helper.
+    pub stages: Vec<StageMetrics>,
+    /// SHA-256 of the output share this helper returned.
+    #[serde(with = "hex")]
+    pub output_digest: [u8; 32],
+    /// Commitments to the output shares this helper exchanged with its peers before it returned
+    /// them, if helpers commit to their outputs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commitments: Option<OutputCommitments>,
+}
+
+/// [`QueryReport`] signed by the helper that produced it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedQueryReport {
+    pub report: QueryReport,
+    /// Ed25519 signature over the JSON serialization of `report`.
+    #[serde(with = "hex")]
+    pub signature: [u8; 64],
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ReportError {
+    #[error("report signature is not valid: {0}")]
+    InvalidSignature(#[from] ed25519_dalek::SignatureError),
+}
+
+impl SignedQueryReport {
+    /// Checks that the report was signed with the key of the helper that is expected to have
+    /// produced it.
+    ///
+    /// ## Errors
+    /// If the signature does not match the report or `key`.
+    pub fn verify(&self, key: &VerifyingKey) -> Result<(), ReportError> {
+        key.verify(
+            &signed_bytes(&self.report),
+            &Signature::from_bytes(&self.signature),
+        )?;
+        Ok(())
+    }
+}
+
+fn signed_bytes(report: &QueryReport) -> Vec<u8> {
+    serde_json::to_vec(report).expect("reports can always be serialized")
+}
+
+/// Signs reports of queries completed by this helper.
+pub struct ReportSigner {
+    key: SigningKey,
+}
+
+impl ReportSigner {
+    #[must_use]
+    pub fn new(key: SigningKey) -> Self {
+        Self { key }
+    }
+
+    /// Reads the hex-encoded Ed25519 secret key from the given file.
+    ///
+    /// ## Errors
+    /// If the file can't be read or does not contain a valid key.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let secret = <[u8; 32]>::try_from(
+            hex::decode(fs::read_to_string(path)?.trim())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+        )
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "secret key must be 32 bytes"))?;
+
+        Ok(Self::new(SigningKey::from_bytes(&secret)))
+    }
+
+    /// Returns the key report signatures can be verified with.
+    #[must_use]
+    pub fn verifying_key(&self) -> VerifyingKey {
+        self.key.verifying_key()
+    }
+
+    #[must_use]
+    pub fn sign(&self, report: QueryReport) -> SignedQueryReport {
+        let signature = self.key.sign(&signed_bytes(&report)).to_bytes();
+        SignedQueryReport { report, signature }
+    }
+}
+
+/// Report of a query that is still running.
+pub(super) struct PendingReport {
+    manifest: QueryManifest,
+    input: Arc<Mutex<Sha256>>,
+}
+
+impl PendingReport {
+    pub fn new(manifest: QueryManifest) -> Self {
+        Self {
+            manifest,
+            input: Arc::default(),
+        }
+    }
+
+    /// Returns a stream that yields the same data as `input`, computing its digest as it is read.
+    pub fn record_input(&self, input: BodyStream) -> BodyStream {
+        let digest = Arc::clone(&self.input);
+        BodyStream::from_bytes_stream(input.map(move |chunk| {
+            if let Ok(bytes) = &chunk {
+                digest.lock().unwrap().update(bytes);
+            }
+            chunk
+        }))
+    }
+
+    /// Completes the report of the query that produced `output`.
+    pub fn finish(
+        self,
+        output: &[u8],
+        metrics: Option<QueryMetrics>,
+        commitments: Option<OutputCommitments>,
+        signer: &ReportSigner,
+    ) -> SignedQueryReport {
+        let input_digest = self.input.lock().unwrap().clone().finalize().into();
+        signer.sign(QueryReport {
+            dp: DpParameters::of(&self.manifest.config.query_type),
+            manifest: self.manifest,
+            input_digest,
+            stages: metrics.unwrap_or_default().stages,
+            output_digest: Sha256::digest(output).into(),
+            commitments,
+        })
+    }
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use ed25519_dalek::SigningKey;
+    use futures::TryStreamExt;
+    use rand::Rng;
+    use sha2::{Digest, Sha256};
+
+    use super::{PendingReport, ReportSigner, SignedQueryReport};
+    use crate::{
+        ff::FieldType,
+        helpers::{
+            query::{IpaQueryConfig, QueryConfig, QueryType},
+            BodyStream, Role,
+        },
+        query::QueryManifest,
+        rand::thread_rng,
+    };
+
+    fn signer() -> ReportSigner {
+        ReportSigner::new(SigningKey::from_bytes(&thread_rng().gen()))
+    }
+
+    async fn report(signer: &ReportSigner) -> SignedQueryReport {
+        let config = IpaQueryConfig {
+            dp_epsilon: Some(1.0.try_into().unwrap()),
+            ..IpaQueryConfig::default()
+        };
+        let pending = PendingReport::new(QueryManifest {
+            config: QueryConfig::new(QueryType::OprfIpa(config), FieldType::Fp32BitPrime, 1)
+                .unwrap(),
+            role: Role::H2,
+        });
+        pending
+            .record_input(BodyStream::from(vec![1, 2, 3]))
+            .try_concat()
+            .await
+            .unwrap();
+
+        pending.finish(&[4, 5], None, None, signer)
+    }
+
+    #[tokio::test]
+    async fn digests() {
+        let signed = report(&signer()).await;
+        assert_eq!(
+            <[u8; 32]>::from(Sha256::digest([1, 2, 3])),
+            signed.report.input_digest
+        );
+        assert_eq!(
+            <[u8; 32]>::from(Sha256::digest([4, 5])),
+            signed.report.output_digest
+        );
+        assert_eq!(
+            Some(1.0.try_into().unwrap()),
+            signed.report.dp.map(|dp| dp.epsilon)
+        );
+    }
+
+    #[tokio::test]
+    async fn signature() {
+        let helper = signer();
+        let signed = report(&helper).await;
+
+        let json = serde_json::to_string(&signed).unwrap();
+        let parsed = serde_json::from_str::<SignedQueryReport>(&json).unwrap();
+        assert_eq!(signed, parsed);
+        parsed.verify(&helper.verifying_key()).unwrap();
+
+        let mut tampered = parsed.clone();
+        tampered.report.manifest.role = Role::H3;
+        assert!(tampered.verify(&helper.verifying_key()).is_err());
+        assert!(parsed.verify(&signer().verifying_key()).is_err());
+    }
+}
@@ -0,0 +1,112 @@
+use crate::helpers::query::ResourceReservation;
+
+/// Resources this helper is willing to spend on a single query. Queries that need more than that
+/// are rejected when they are created, before any of the helpers starts working on them.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ResourceLimits {
+    pub memory_bytes: u64,
+    pub spool_bytes: u64,
+    pub parallelism: u32,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReservationError {
+    #[error("Query requires {requested} {resource}, but only {available} are available")]
+    Insufficient {
+        resource: &'static str,
+        requested: u64,
+        available: u64,
+    },
+    #[error("Coordinator reserved {requested:?} for this query, but it requires {expected:?}")]
+    Mismatch {
+        requested: ResourceReservation,
+        expected: ResourceReservation,
+    },
+}
+
+impl ResourceLimits {
+    /// Checks that `reservation` fits within these limits. Disk space is only checked if this
+    /// helper spools query inputs.
+    ///
+    /// ## Errors
+    /// If any of the reserved resources exceeds its limit.
+    pub fn check(
+        &self,
+        reservation: &ResourceReservation,
+        spools_inputs: bool,
+    ) -> Result<(), ReservationError> {
+        let check = |resource, requested, available| {
+            if requested > available {
+                Err(ReservationError::Insufficient {
+                    resource,
+                    requested,
+                    available,
+                })
+            } else {
+                Ok(())
+            }
+        };
+
+        check(
+            "bytes of memory",
+            reservation.memory_bytes,
+            self.memory_bytes,
+        )?;
+        if spools_inputs {
+            check(
+                "bytes of disk space",
+                reservation.spool_bytes,
+                self.spool_bytes,
+            )?;
+        }
+        check(
+            "parallel records",
+            u64::from(reservation.parallelism),
+            u64::from(self.parallelism),
+        )
+    }
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use super::{ReservationError, ResourceLimits};
+    use crate::helpers::query::ResourceReservation;
+
+    const LIMITS: ResourceLimits = ResourceLimits {
+        memory_bytes: 1000,
+        spool_bytes: 100,
+        parallelism: 10,
+    };
+
+    #[test]
+    fn check_limits() {
+        let reservation = ResourceReservation {
+            memory_bytes: 1000,
+            spool_bytes: 200,
+            parallelism: 10,
+        };
+
+        LIMITS.check(&reservation, false).unwrap();
+        assert!(matches!(
+            LIMITS.check(&reservation, true),
+            Err(ReservationError::Insufficient {
+                requested: 200,
+                available: 100,
+                ..
+            })
+        ));
+        assert!(matches!(
+            LIMITS.check(
+                &ResourceReservation {
+                    parallelism: 11,
+                    ..reservation
+                },
+                false
+            ),
+            Err(ReservationError::Insufficient {
+                resource: "parallel records",
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,452 @@
+//! Reuse of outputs of queries that are submitted again with the same input.
+//!
+//! Report collectors sometimes re-submit a query they have already run, for instance because the
+//! response got lost on the way to them. If the query config and the input this helper received
+//! are exactly the same as before, the output is the same as well, so there is no reason to run
+//! the protocol again.
+//!
+//! Every helper decides on its own whether it has the output cached, but the protocol can only be
+//! skipped if all of them do. The query starts right away and the input is hashed as the query
+//! reads it. Once it is read in full, helpers tell each other whether they have a cached output,
+//! and the query is abandoned if all of them do.
+//!
+//! Cached outputs are returned exactly as they were computed the first time, DP noise included.
+//! Drawing fresh noise for every repeated query would let the report collector average it out, so
+//! repeated queries never reveal more than the first one did.
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Formatter},
+    future::Future,
+    num::NonZeroUsize,
+    pin::pin,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use futures::{
+    channel::oneshot,
+    future::{select, try_join4, Either},
+    stream, StreamExt,
+};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    error::Error,
+    ff::boolean::Boolean,
+    helpers::{
+        query::QueryConfig, BodyStream, BytesStream, ChannelId, Direction, Gateway, Role,
+        TotalRecords,
+    },
+    protocol::{
+        step::{Gate, Step, StepNarrow},
+        RecordId,
+    },
+    query::{runner::QueryResult, ProtocolResult, QueryManifest},
+};
+
+pub struct ResultCacheStep;
+
+impl AsRef<str> for ResultCacheStep {
+    fn as_ref(&self) -> &str {
+        "result_cache"
+    }
+}
+
+impl Step for ResultCacheStep {}
+
+/// Controls how many query outputs [`ResultCache`] keeps and for how long.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ResultCacheConfig {
+    /// Maximum number of outputs kept. Once it is reached, the oldest one is evicted.
+    pub capacity: NonZeroUsize,
+    /// Outputs older than that are never returned.
+    pub ttl: Duration,
+}
+
+/// Tells whether the output of a query was computed by it or taken from [`ResultCache`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheStatus {
+    Hit,
+    Miss,
+}
+
+impl CacheStatus {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Hit => "hit",
+            Self::Miss => "miss",
+        }
+    }
+}
+
+type InputDigest = [u8; 32];
+
+struct CacheEntry {
+    output: Vec<u8>,
+    stored_at: Instant,
+}
+
+/// Keeps outputs of recently completed queries in memory, keyed by the digest of the query
+/// manifest and the input this helper received.
+pub struct ResultCache {
+    config: ResultCacheConfig,
+    entries: Mutex<HashMap<InputDigest, CacheEntry>>,
+}
+
+impl Debug for ResultCache {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ResultCache[{:?}]", self.config)
+    }
+}
+
+impl ResultCache {
+    #[must_use]
+    pub fn new(config: ResultCacheConfig) -> Self {
+        Self {
+            config,
+            entries: Mutex::default(),
+        }
+    }
+
+    fn get(&self, key: &InputDigest) -> Option<Vec<u8>> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some(entry) if entry.stored_at.elapsed() < self.config.ttl => {
+                Some(entry.output.clone())
+            }
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&self, key: InputDigest, output: Vec<u8>) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| entry.stored_at.elapsed() < self.config.ttl);
+        if !entries.contains_key(&key) && entries.len() >= self.config.capacity.get() {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.stored_at)
+                .map(|(key, _)| *key)
+                .unwrap();
+            entries.remove(&oldest);
+        }
+        entries.insert(
+            key,
+            CacheEntry {
+                output,
+                stored_at: Instant::now(),
+            },
+        );
+    }
+
+    /// Returns the cached output of the query if every helper has it, otherwise returns the output
+    /// of `query` and caches it.
+    ///
+    /// `query` starts right away and the input is hashed on its way to it. Once `query` has read
+    /// the input in full, the digest of it is used to look up the output. If all helpers have it,
+    /// `query` is abandoned and the cached output is returned, even if `query` completed already,
+    /// so repeated queries never reveal a freshly computed output. Input that `query` did not read
+    /// to the end is never looked up nor cached.
+    ///
+    /// ## Errors
+    /// If the input could not be read, if `query` fails or if communication with other helpers
+    /// fails.
+    pub(super) async fn run<F, Fut>(
+        &self,
+        gateway: &Gateway,
+        config: &QueryConfig,
+        input: BodyStream,
+        query: F,
+    ) -> QueryResult
+    where
+        F: FnOnce(BodyStream) -> Fut,
+        Fut: Future<Output = QueryResult>,
+    {
+        let (digest_tx, digest_rx) = oneshot::channel();
+        let input = hash_input(input, hasher(config, gateway.role()), digest_tx);
+        let mut query = pin!(query(BodyStream::from_bytes_stream(input)));
+        let mut lookup = pin!(async {
+            let key = digest_rx.await.ok();
+            let cached = key.as_ref().and_then(|key| self.get(key));
+            // Peers wait for this helper to tell them, even if it has nothing to look up.
+            let all_cached = all_cached(gateway, cached.is_some()).await?;
+            Ok::<_, Error>((key, cached.filter(|_| all_cached)))
+        });
+
+        let (key, output) = match select(lookup.as_mut(), query.as_mut()).await {
+            Either::Left((lookup, _)) => match lookup? {
+                (_, Some(output)) => return Ok(hit(output)),
+                (key, None) => (key, query.await?),
+            },
+            Either::Right((output, _)) => {
+                let output = output?;
+                match lookup.await? {
+                    (_, Some(output)) => return Ok(hit(output)),
+                    (key, None) => (key, output),
+                }
+            }
+        };
+
+        // Partial outputs depend on how fast helpers were, not only on the input.
+        if output.partial().is_some() {
+            return Ok(output);
+        }
+        let output = output.into_bytes();
+        if let Some(key) = key {
+            self.insert(key, output.clone());
+        }
+
+        Ok(Box::new(CachedResult {
+            output,
+            status: CacheStatus::Miss,
+        }))
+    }
+}
+
+fn hit(output: Vec<u8>) -> Box<dyn ProtocolResult> {
+    tracing::info!("all helpers have the output of this query cached");
+    Box::new(CachedResult {
+        output,
+        status: CacheStatus::Hit,
+    })
+}
+
+fn hasher(config: &QueryConfig, role: Role) -> Sha256 {
+    let manifest = QueryManifest {
+        config: *config,
+        role,
+    };
+    let mut hasher = Sha256::new();
+    hasher.update(serde_json::to_vec(&manifest).unwrap());
+
+    hasher
+}
+
+/// Passes `input` through, hashing it into `hasher` on the way. The digest is sent to `digest`
+/// once `input` ends, and never if it fails or is dropped before that.
+fn hash_input(
+    input: BodyStream,
+    hasher: Sha256,
+    digest: oneshot::Sender<InputDigest>,
+) -> impl BytesStream {
+    stream::unfold(Some((input, hasher, digest)), |state| async move {
+        let (mut input, mut hasher, digest) = state?;
+        match input.next().await {
+            Some(Ok(chunk)) => {
+                hasher.update(&chunk);
+                Some((Ok(chunk), Some((input, hasher, digest))))
+            }
+            Some(Err(e)) => Some((Err(e), None)),
+            None => {
+                // Receiver is gone only if the query is abandoned already.
+                let _ = digest.send(hasher.finalize().into());
+                None
+            }
+        }
+    })
+}
+
+/// Tells both peers whether this helper has the output cached and returns whether all helpers
+/// have it.
+async fn all_cached(gateway: &Gateway, cached: bool) -> Result<bool, Error> {
+    let gate = Gate::default().narrow(&ResultCacheStep);
+    let [left, right] = [Direction::Left, Direction::Right]
+        .map(|d| ChannelId::new(gateway.role().peer(d), gate.clone()));
+    let total_records = TotalRecords::from(1);
+    let cached = Boolean::from(cached);
+
+    let ((), (), from_left, from_right) = try_join4(
+        gateway
+            .get_sender::<Boolean>(&left, total_records)
+            .send(RecordId::FIRST, cached),
+        gateway
+            .get_sender::<Boolean>(&right, total_records)
+            .send(RecordId::FIRST, cached),
+        gateway
+            .get_receiver::<Boolean>(&left)
+            .receive(RecordId::FIRST),
+        gateway
+            .get_receiver::<Boolean>(&right)
+            .receive(RecordId::FIRST),
+    )
+    .await?;
+
+    Ok(bool::from(cached * from_left * from_right))
+}
+
+/// Output of a query that went through [`ResultCache`].
+pub(super) struct CachedResult {
+    output: Vec<u8>,
+    status: CacheStatus,
+}
+
+impl CachedResult {
+    pub fn new(output: Vec<u8>, status: CacheStatus) -> Self {
+        Self { output, status }
+    }
+}
+
+impl Debug for CachedResult {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "CachedResult[{} bytes, {}]",
+            self.output.len(),
+            self.status.as_str()
+        )
+    }
+}
+
+impl ProtocolResult for CachedResult {
+    fn into_bytes(self: Box<Self>) -> Vec<u8> {
+        self.output
+    }
+
+    fn cache_status(&self) -> Option<CacheStatus> {
+        Some(self.status)
+    }
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use std::{num::NonZeroUsize, time::Duration};
+
+    use futures::future::join_all;
+    use sha2::Digest;
+
+    use super::{CacheStatus, ResultCache, ResultCacheConfig};
+    use crate::{
+        ff::FieldType,
+        helpers::{
+            query::{QueryConfig, QueryType},
+            BodyStream, Role,
+        },
+        query::ProtocolResult,
+        test_fixture::TestWorld,
+    };
+
+    fn cache(ttl: Duration) -> ResultCache {
+        ResultCache::new(ResultCacheConfig {
+            capacity: NonZeroUsize::new(2).unwrap(),
+            ttl,
+        })
+    }
+
+    fn config() -> QueryConfig {
+        QueryConfig::new(QueryType::TestMultiply, FieldType::Fp31, 1).unwrap()
+    }
+
+    /// Runs a query that outputs its input on every helper.
+    async fn run(
+        caches: &[ResultCache; 3],
+        inputs: [&[u8]; 3],
+    ) -> Vec<(Vec<u8>, Option<CacheStatus>)> {
+        let world = TestWorld::default();
+        join_all(
+            Role::all()
+                .iter()
+                .zip(caches)
+                .zip(inputs)
+                .map(|((&role, cache), input)| {
+                    let world = &world;
+                    async move {
+                        let result =
+                            cache
+                                .run(
+                                    world.gateway(role),
+                                    &config(),
+                                    BodyStream::from(input.to_vec()),
+                                    |input| async move {
+                                        let output: Vec<u8> = input.to_vec().await;
+                                        Ok(Box::new(super::CachedResult::new(
+                                            output,
+                                            CacheStatus::Miss,
+                                        ))
+                                            as Box<dyn ProtocolResult>)
+                                    },
+                                )
+                                .await
+                                .unwrap();
+                        let status = result.cache_status();
+                        (result.into_bytes(), status)
+                    }
+                }),
+        )
+        .await
+    }
+
+    fn all(outputs: &[(Vec<u8>, Option<CacheStatus>)], expected: CacheStatus) -> bool {
+        outputs.iter().all(|(_, status)| *status == Some(expected))
+    }
+
+    #[tokio::test]
+    async fn reuses_output() {
+        let caches = [(); 3].map(|()| cache(Duration::from_secs(60)));
+        let inputs: [&[u8]; 3] = [&[1, 2], &[3], &[4, 5, 6]];
+
+        let first = run(&caches, inputs).await;
+        assert!(all(&first, CacheStatus::Miss));
+
+        let second = run(&caches, inputs).await;
+        assert!(all(&second, CacheStatus::Hit));
+        assert_eq!(
+            first.into_iter().map(|(o, _)| o).collect::<Vec<_>>(),
+            second.into_iter().map(|(o, _)| o).collect::<Vec<_>>(),
+        );
+    }
+
+    #[tokio::test]
+    async fn returns_cached_output_over_fresh_one() {
+        let caches = [(); 3].map(|()| cache(Duration::from_secs(60)));
+        for ((cache, role), input) in caches.iter().zip(Role::all()).zip(1..) {
+            let mut hasher = super::hasher(&config(), *role);
+            hasher.update([input]);
+            cache.insert(hasher.finalize().into(), vec![input + 10]);
+        }
+
+        let outputs = run(&caches, [&[1], &[2], &[3]]).await;
+
+        assert!(all(&outputs, CacheStatus::Hit));
+        assert_eq!(
+            vec![vec![11], vec![12], vec![13]],
+            outputs.into_iter().map(|(o, _)| o).collect::<Vec<_>>(),
+        );
+    }
+
+    #[tokio::test]
+    async fn runs_if_any_input_differs() {
+        let caches = [(); 3].map(|()| cache(Duration::from_secs(60)));
+
+        run(&caches, [&[1], &[2], &[3]]).await;
+        let outputs = run(&caches, [&[1], &[2], &[4]]).await;
+
+        assert!(all(&outputs, CacheStatus::Miss));
+        assert_eq!(vec![4], outputs[2].0);
+    }
+
+    #[tokio::test]
+    async fn expires() {
+        let caches = [(); 3].map(|()| cache(Duration::ZERO));
+
+        run(&caches, [&[1], &[2], &[3]]).await;
+        let outputs = run(&caches, [&[1], &[2], &[3]]).await;
+
+        assert!(all(&outputs, CacheStatus::Miss));
+    }
+
+    #[test]
+    fn evicts_oldest() {
+        let cache = cache(Duration::from_secs(60));
+        for i in 0..3 {
+            cache.insert([i; 32], vec![i]);
+        }
+
+        assert_eq!(None, cache.get(&[0; 32]));
+        assert_eq!(Some(vec![1]), cache.get(&[1; 32]));
+        assert_eq!(Some(vec![2]), cache.get(&[2; 32]));
+    }
+}
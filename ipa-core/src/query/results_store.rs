@@ -0,0 +1,136 @@
+use std::{
+    fmt::{Debug, Formatter},
+    fs::{self, File},
+    io::{self, ErrorKind, Write},
+    path::PathBuf,
+};
+
+use crate::{protocol::QueryId, query::ProtocolResult};
+
+/// Durable storage for outputs of completed queries.
+///
+/// Query processor keeps the result of a query in memory only until it is handed to the report
+/// collector. If a store is configured, every result is also written there, so it can be
+/// retrieved again after that, including after a helper restart.
+///
+/// Implementations may block the calling thread, query processor calls them from threads where
+/// blocking is fine, see [`blocking`].
+pub trait ResultsStore: Send + Sync {
+    /// Writes the output of the given query, replacing the one stored before, if any.
+    ///
+    /// ## Errors
+    /// If the output could not be written.
+    fn store(&self, query_id: QueryId, result: &[u8]) -> io::Result<()>;
+
+    /// Returns the output of the given query, or `None` if it has not been stored.
+    ///
+    /// ## Errors
+    /// If the output exists but could not be read.
+    fn load(&self, query_id: QueryId) -> io::Result<Option<Vec<u8>>>;
+}
+
+/// Keeps query outputs as files in a directory on the local filesystem, one file per query.
+#[derive(Debug)]
+pub struct FsResultsStore {
+    dir: PathBuf,
+}
+
+impl FsResultsStore {
+    /// Creates a store in the given directory, creating it if it does not exist.
+    ///
+    /// ## Errors
+    /// If the directory could not be created.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, query_id: QueryId) -> PathBuf {
+        self.dir.join(format!("{}.bin", query_id))
+    }
+}
+
+impl ResultsStore for FsResultsStore {
+    fn store(&self, query_id: QueryId, result: &[u8]) -> io::Result<()> {
+        // Write to a temporary file first, so a crash never leaves a partially written result
+        // behind.
+        let path = self.path(query_id);
+        let tmp_path = path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(result)?;
+        file.sync_all()?;
+        fs::rename(tmp_path, path)
+    }
+
+    fn load(&self, query_id: QueryId) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.path(query_id)) {
+            Ok(result) => Ok(Some(result)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Runs `f`, which uses a [`ResultsStore`], on a thread dedicated to blocking operations, so
+/// that reading and writing outputs does not hold up the tasks of running queries.
+///
+/// ## Errors
+/// If `f` fails or panics.
+pub(super) async fn blocking<T, F>(f: F) -> io::Result<T>
+where
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| io::Error::new(ErrorKind::Other, e))?
+}
+
+/// Query output that has been serialized already, for instance because it was read back from
+/// a [`ResultsStore`].
+pub(super) struct StoredResult(pub Vec<u8>);
+
+impl Debug for StoredResult {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "StoredResult[{} bytes]", self.0.len())
+    }
+}
+
+impl ProtocolResult for StoredResult {
+    fn into_bytes(self: Box<Self>) -> Vec<u8> {
+        self.0
+    }
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use super::{FsResultsStore, ResultsStore};
+    use crate::protocol::QueryId;
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FsResultsStore::new(dir.path().join("results")).unwrap();
+
+        assert_eq!(None, store.load(QueryId::default()).unwrap());
+
+        store.store(QueryId::default(), &[1, 2, 3]).unwrap();
+        assert_eq!(Some(vec![1, 2, 3]), store.load(QueryId::default()).unwrap());
+
+        store.store(QueryId::default(), &[4]).unwrap();
+        assert_eq!(Some(vec![4]), store.load(QueryId::default()).unwrap());
+    }
+
+    #[test]
+    fn survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        FsResultsStore::new(dir.path())
+            .unwrap()
+            .store(QueryId::default(), &[5; 10])
+            .unwrap();
+
+        let store = FsResultsStore::new(dir.path()).unwrap();
+        assert_eq!(Some(vec![5; 10]), store.load(QueryId::default()).unwrap());
+    }
+}
@@ -212,6 +212,7 @@ mod tests {
                 attribution_window_seconds: None,
                 max_breakdown_key: 3,
                 plaintext_match_keys: true,
+                ..Default::default()
             };
             let input = BodyStream::from(shares);
             // Note that we ignore the last 2 records to test that runner follows the rule
@@ -271,6 +272,7 @@ mod tests {
                 attribution_window_seconds: None,
                 max_breakdown_key: 3,
                 plaintext_match_keys: true,
+                ..Default::default()
             };
             IpaQuery::<Fp31, _, _>::new(query_config, Arc::new(KeyRegistry::empty())).execute(
                 ctx,
@@ -327,6 +329,7 @@ mod tests {
                 attribution_window_seconds: None,
                 max_breakdown_key: 3,
                 plaintext_match_keys: false,
+                ..Default::default()
             };
             let input = BodyStream::from(buffer);
             IpaQuery::<Fp31, _, _>::new(query_config, Arc::clone(&key_registry))
@@ -1,44 +1,118 @@
-use std::marker::PhantomData;
+use std::{
+    marker::PhantomData,
+    mem::size_of,
+    num::{NonZeroU32, NonZeroUsize},
+    ops::Deref,
+    time::{Duration, Instant},
+};
 
-use futures::TryStreamExt;
+use bytes::Bytes;
+use futures::{
+    future::{ready, try_join},
+    TryStreamExt,
+};
+use ipa_macros::Step;
 
+#[cfg(feature = "descriptive-gate")]
+use crate::protocol::ipa_prf::time_bucket::{BucketSize, TimeBucketParams};
 use crate::{
     error::Error,
     ff::{
         boolean::Boolean,
-        boolean_array::{BA20, BA3, BA4, BA5, BA6, BA7, BA8},
-        PrimeField, Serializable,
+        boolean_array::{BA16, BA20, BA3, BA32, BA4, BA5, BA8},
+        CustomArray, Field, PrimeField, Serializable,
     },
     helpers::{
-        query::{IpaQueryConfig, QuerySize},
-        BodyStream, RecordsStream,
+        query::{BreakdownKeyOverflow, IpaQueryConfig, QuerySize},
+        BodyStream, Direction, LengthDelimitedStream, RecordsStream,
     },
+    hpke::{KeyPair, KeyRegistry},
     protocol::{
         basics::ShareKnownValue,
-        context::{UpgradableContext, UpgradedContext},
-        ipa_prf::oprf_ipa,
+        context::{Context, UpgradableContext, UpgradedContext},
+        dp::BinomialNoise,
+        ipa_prf::{
+            padding::{PaddingParams, MAX_DUMMY_USER_ROWS},
+            pipeline::{PartialAggregate, PipelineBuilder},
+            segment::SegmentParams,
+        },
+        RecordId,
     },
-    report::OprfReport,
-    secret_sharing::replicated::{
-        malicious::ExtendableField, semi_honest::AdditiveShare as Replicated,
+    report::{EncryptedOprfReport, OprfReport},
+    secret_sharing::{
+        replicated::{malicious::ExtendableField, semi_honest::AdditiveShare as Replicated},
+        WeakSharedValue,
     },
+    seq_join::SeqJoin,
+    sync::Arc,
 };
 
+#[derive(Step)]
+pub(crate) enum Step {
+    AgreeOnValidReports,
+    AgreeOnBreakdownKeyOverflow,
+}
+
+/// Decides how many shards attribution is split into. The decision is made based on the query
+/// size only, so all helpers arrive at the same number independently.
+#[derive(Debug, Clone, Copy)]
+pub struct ShardCoordinator {
+    max_rows_per_shard: NonZeroUsize,
+    max_shards: NonZeroU32,
+}
+
+impl Default for ShardCoordinator {
+    /// Queries up to 1M rows are processed in a single shard.
+    fn default() -> Self {
+        Self::new(
+            NonZeroUsize::new(1 << 20).unwrap(),
+            // must not exceed `prf_sharding::sharding::MAX_SHARDS`
+            NonZeroU32::new(64).unwrap(),
+        )
+    }
+}
+
+impl ShardCoordinator {
+    /// Creates a coordinator that uses as many shards as needed to keep each one under
+    /// `max_rows_per_shard` rows on average, but no more than `max_shards`.
+    #[must_use]
+    pub fn new(max_rows_per_shard: NonZeroUsize, max_shards: NonZeroU32) -> Self {
+        Self {
+            max_rows_per_shard,
+            max_shards,
+        }
+    }
+
+    /// ## Panics
+    /// If the number of shards required does not fit into `u32`.
+    #[must_use]
+    pub fn shard_count(&self, query_size: usize) -> NonZeroU32 {
+        let required = u32::try_from(query_size.div_ceil(self.max_rows_per_shard.get())).unwrap();
+
+        NonZeroU32::new(required)
+            .unwrap_or(NonZeroU32::MIN)
+            .min(self.max_shards)
+    }
+}
+
 pub struct OprfIpaQuery<C, F> {
     config: IpaQueryConfig,
+    key_registry: Arc<KeyRegistry<KeyPair>>,
+    shard_coordinator: ShardCoordinator,
     phantom_data: PhantomData<(C, F)>,
 }
 
 impl<C, F> OprfIpaQuery<C, F> {
-    pub fn new(config: IpaQueryConfig) -> Self {
+    pub fn new(config: IpaQueryConfig, key_registry: Arc<KeyRegistry<KeyPair>>) -> Self {
         Self {
             config,
+            key_registry,
+            shard_coordinator: ShardCoordinator::default(),
             phantom_data: PhantomData,
         }
     }
 }
 
-#[allow(clippy::too_many_lines)]
 impl<C, F> OprfIpaQuery<C, F>
 where
     C: UpgradableContext,
@@ -48,41 +122,1423 @@ where
     Replicated<F>: Serializable + ShareKnownValue<C, F>,
     Replicated<Boolean>: Serializable + ShareKnownValue<C, Boolean>,
 {
-    #[tracing::instrument("oprf_ipa_query", skip_all, fields(sz=%query_size))]
-    pub async fn execute<'a>(
+    /// Runs the query and returns per-breakdown totals. If the query has a deadline, they may
+    /// cover only some of the users, see [`Self::execute_partial`].
+    ///
+    /// ## Errors
+    /// If query parameters are invalid or the protocol fails.
+    pub async fn execute(
         self,
         ctx: C,
         query_size: QuerySize,
         input_stream: BodyStream,
     ) -> Result<Vec<Replicated<F>>, Error> {
+        Ok(self
+            .execute_partial(ctx, query_size, input_stream)
+            .await?
+            .breakdowns)
+    }
+
+    /// Same as [`Self::execute`], but also returns the share of users that contributed to the
+    /// totals.
+    ///
+    /// ## Errors
+    /// If query parameters are invalid or the protocol fails.
+    #[tracing::instrument("oprf_ipa_query", skip_all, fields(sz=%query_size))]
+    pub async fn execute_partial(
+        self,
+        ctx: C,
+        query_size: QuerySize,
+        input_stream: BodyStream,
+    ) -> Result<PartialAggregate<Replicated<F>>, Error> {
+        tracing::info!("New query: {:?}", self.config);
+        let sz = usize::from(query_size);
+        // the deadline is counted from the moment this helper starts running the query
+        let deadline = self
+            .config
+            .deadline_seconds
+            .map(|seconds| Instant::now() + Duration::from_secs(seconds.get().into()));
+
+        let schema = self.config.report_schema();
+        match (
+            schema.breakdown_key_bits,
+            schema.trigger_value_bits,
+            schema.timestamp_bits,
+        ) {
+            (4, 3, Some(20)) => {
+                self.execute_typed::<BA4, BA3, BA20>(ctx, sz, input_stream, deadline)
+                    .await
+            }
+            (4, 8, Some(20)) => {
+                self.execute_typed::<BA4, BA8, BA20>(ctx, sz, input_stream, deadline)
+                    .await
+            }
+            (4, 16, Some(20)) => {
+                self.execute_typed::<BA4, BA16, BA20>(ctx, sz, input_stream, deadline)
+                    .await
+            }
+            (4, 32, Some(20)) => {
+                self.execute_typed::<BA4, BA32, BA20>(ctx, sz, input_stream, deadline)
+                    .await
+            }
+            (5, 3, Some(20)) => {
+                self.execute_typed::<BA5, BA3, BA20>(ctx, sz, input_stream, deadline)
+                    .await
+            }
+            (5, 8, Some(20)) => {
+                self.execute_typed::<BA5, BA8, BA20>(ctx, sz, input_stream, deadline)
+                    .await
+            }
+            (5, 16, Some(20)) => {
+                self.execute_typed::<BA5, BA16, BA20>(ctx, sz, input_stream, deadline)
+                    .await
+            }
+            (5, 32, Some(20)) => {
+                self.execute_typed::<BA5, BA32, BA20>(ctx, sz, input_stream, deadline)
+                    .await
+            }
+            (8, 3, Some(20)) => {
+                self.execute_typed::<BA8, BA3, BA20>(ctx, sz, input_stream, deadline)
+                    .await
+            }
+            (8, 8, Some(20)) => {
+                self.execute_typed::<BA8, BA8, BA20>(ctx, sz, input_stream, deadline)
+                    .await
+            }
+            (8, 16, Some(20)) => {
+                self.execute_typed::<BA8, BA16, BA20>(ctx, sz, input_stream, deadline)
+                    .await
+            }
+            (8, 32, Some(20)) => {
+                self.execute_typed::<BA8, BA32, BA20>(ctx, sz, input_stream, deadline)
+                    .await
+            }
+            (4, 3, None) => {
+                self.execute_typed::<BA4, BA3, NoTimestamp>(ctx, sz, input_stream, deadline)
+                    .await
+            }
+            (4, 8, None) => {
+                self.execute_typed::<BA4, BA8, NoTimestamp>(ctx, sz, input_stream, deadline)
+                    .await
+            }
+            (4, 16, None) => {
+                self.execute_typed::<BA4, BA16, NoTimestamp>(ctx, sz, input_stream, deadline)
+                    .await
+            }
+            (4, 32, None) => {
+                self.execute_typed::<BA4, BA32, NoTimestamp>(ctx, sz, input_stream, deadline)
+                    .await
+            }
+            (5, 3, None) => {
+                self.execute_typed::<BA5, BA3, NoTimestamp>(ctx, sz, input_stream, deadline)
+                    .await
+            }
+            (5, 8, None) => {
+                self.execute_typed::<BA5, BA8, NoTimestamp>(ctx, sz, input_stream, deadline)
+                    .await
+            }
+            (5, 16, None) => {
+                self.execute_typed::<BA5, BA16, NoTimestamp>(ctx, sz, input_stream, deadline)
+                    .await
+            }
+            (5, 32, None) => {
+                self.execute_typed::<BA5, BA32, NoTimestamp>(ctx, sz, input_stream, deadline)
+                    .await
+            }
+            (8, 3, None) => {
+                self.execute_typed::<BA8, BA3, NoTimestamp>(ctx, sz, input_stream, deadline)
+                    .await
+            }
+            (8, 8, None) => {
+                self.execute_typed::<BA8, BA8, NoTimestamp>(ctx, sz, input_stream, deadline)
+                    .await
+            }
+            (8, 16, None) => {
+                self.execute_typed::<BA8, BA16, NoTimestamp>(ctx, sz, input_stream, deadline)
+                    .await
+            }
+            (8, 32, None) => {
+                self.execute_typed::<BA8, BA32, NoTimestamp>(ctx, sz, input_stream, deadline)
+                    .await
+            }
+            _ => Err(Error::InvalidQueryParameter(
+                format!(
+                    "Unsupported OPRF IPA report layout: {schema}. Breakdown key must be 4, 5 or \
+                     8 bits, trigger value 3, 8, 16 or 32 bits and timestamp 20 bits, if reports \
+                     have one."
+                )
+                .into(),
+            )),
+        }
+    }
+
+    /// Runs OPRF IPA over reports with `BK`, `TV` and `TS` field widths.
+    async fn execute_typed<BK, TV, TS>(
+        self,
+        ctx: C,
+        sz: usize,
+        input_stream: BodyStream,
+        deadline: Option<Instant>,
+    ) -> Result<PartialAggregate<Replicated<F>>, Error>
+    where
+        BK: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+        TV: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+        TS: WeakSharedValue + CustomArray<Element = Boolean> + Field,
+        for<'a> &'a Replicated<TS>: IntoIterator<Item = Replicated<Boolean>>,
+        for<'a> &'a Replicated<TV>: IntoIterator<Item = Replicated<Boolean>>,
+        for<'a> &'a Replicated<BK>: IntoIterator<Item = Replicated<Boolean>>,
+        for<'a> <&'a Replicated<TV> as IntoIterator>::IntoIter: Send,
+        for<'a> <&'a Replicated<TS> as IntoIterator>::IntoIter: Send,
+        OprfReport<BK, TV, TS>: Serializable,
+    {
         let Self {
             config,
+            key_registry,
+            shard_coordinator,
             phantom_data: _,
         } = self;
-        tracing::info!("New query: {config:?}");
-        let sz = usize::from(query_size);
 
-        let input = if config.plaintext_match_keys {
-            let mut v = RecordsStream::<OprfReport<BA8, BA3, BA20>, _>::new(input_stream)
+        let bks = config.max_breakdown_key;
+        if u64::from(bks) > 1 << <BK as WeakSharedValue>::BITS {
+            return Err(Error::InvalidQueryParameter(
+                format!(
+                    "{bks} breakdowns do not fit into a {}-bit breakdown key",
+                    <BK as WeakSharedValue>::BITS
+                )
+                .into(),
+            ));
+        }
+
+        // reports with wide breakdown keys go to an extra breakdown that follows all others
+        let overflow = config.breakdown_key_overflow == BreakdownKeyOverflow::Overflow;
+        if overflow && u64::from(bks) >= 1 << <BK as WeakSharedValue>::BITS {
+            return Err(Error::InvalidQueryParameter(
+                format!(
+                    "overflow breakdown {bks} does not fit into a {}-bit breakdown key",
+                    <BK as WeakSharedValue>::BITS
+                )
+                .into(),
+            ));
+        }
+
+        if config.report_schema().timestamp_bits.is_none() && uses_timestamps(&config) {
+            return Err(Error::InvalidQueryParameter(
+                "query needs timestamps, but declares reports without them".into(),
+            ));
+        }
+
+        if let Some(window) = config.attribution_window_seconds {
+            if u128::from(window.get()) >= 1 << <TS as WeakSharedValue>::BITS {
+                return Err(Error::InvalidQueryParameter(
+                    format!(
+                        "attribution window of {window} seconds does not fit into a {}-bit \
+                         timestamp",
+                        <TS as WeakSharedValue>::BITS
+                    )
+                    .into(),
+                ));
+            }
+        }
+
+        if config.count_attributed_users && config.deadline_seconds.is_some() {
+            return Err(Error::InvalidQueryParameter(
+                "attributed users can't be counted in queries with a deadline".into(),
+            ));
+        }
+
+        if config.time_bucket_days.is_some() && config.deadline_seconds.is_some() {
+            return Err(Error::InvalidQueryParameter(
+                "totals can't be keyed by time bucket in queries with a deadline".into(),
+            ));
+        }
+
+        if config.count_attributed_users && config.user_segment_rows.is_some() {
+            return Err(Error::InvalidQueryParameter(
+                "attributed users can't be counted in queries that split users into segments"
+                    .into(),
+            ));
+        }
+        let segments = config.user_segment_rows.map(|rows| SegmentParams {
+            max_rows: rows,
+            max_segments: config.max_user_segments,
+        });
+        if segments
+            .is_some_and(|segments| segments.segment_cap(config.per_user_credit_cap).is_none())
+        {
+            return Err(Error::InvalidQueryParameter(
+                format!(
+                    "per-user cap {} can't be divided between {} segments",
+                    config.per_user_credit_cap, config.max_user_segments
+                )
+                .into(),
+            ));
+        }
+
+        if config.pseudonymized_input && config.dp_padding_max_user_rows.is_some() {
+            return Err(Error::InvalidQueryParameter(
+                "pseudonymized input can't be padded with dummy users, who have no pseudonyms"
+                    .into(),
+            ));
+        }
+
+        // reports are read in the layout the query declares, those without timestamps get zero
+        // timestamp shares
+        let schema = config.report_schema();
+        let mut pseudonyms = None;
+        let input = if config.plaintext_match_keys || config.pseudonymized_input {
+            let bytes = input_stream
+                .try_fold(Vec::new(), |mut bytes, chunk| {
+                    bytes.extend_from_slice(&chunk);
+                    ready(Ok(bytes))
+                })
+                .await
+                .map_err(Error::ParseError)?;
+            let bytes = if config.pseudonymized_input {
+                let epoch = config.epoch.ok_or_else(|| {
+                    Error::InvalidQueryParameter(
+                        "pseudonymized input requires the epoch to check pseudonyms against".into(),
+                    )
+                })?;
+                let (encrypted, bytes) = schema.read_pseudonymized::<TS>(bytes)?;
+                // helpers opened the same PRF values in the query that exported them, so a
+                // pseudonym that fails to open on one helper fails the query on all of them
+                pseudonyms = Some(
+                    encrypted
+                        .iter()
+                        .take(sz)
+                        .map(|pseudonym| pseudonym.open(&key_registry, epoch))
+                        .collect::<Result<Vec<_>, _>>()?,
+                );
+                bytes
+            } else {
+                schema.read_plaintext::<TS>(bytes)?
+            };
+            let mut v = RecordsStream::<OprfReport<BK, TV, TS>, _>::from(bytes)
+                .with_memory_budget(ctx.memory())
                 .try_concat()
                 .await?;
             v.truncate(sz);
             v
         } else {
-            panic!("Encrypted match key handling is not handled for OPRF flow as yet");
+            let batch = LengthDelimitedStream::<Bytes, _>::new(input_stream)
+                .try_concat()
+                .await?;
+            let (version, reports) = schema.read_version(&batch)?;
+            tracing::debug!("reading {version:?} reports with {schema}");
+            let reports = reports
+                .iter()
+                .take(sz)
+                .map(|report| {
+                    EncryptedOprfReport::<BK, TV, TS, _>::from_bytes(
+                        schema.read_encrypted::<TS>(report.clone())?,
+                    )
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            decrypt_reports(ctx.clone(), &reports, &key_registry, &config).await?
         };
+        // decoded reports are held until attribution is done with them
+        let _input_memory = ctx
+            .memory()
+            .reserve(input.len() * size_of::<OprfReport<BK, TV, TS>>());
 
         let aws = config.attribution_window_seconds;
-        match config.per_user_credit_cap {
-            8 => oprf_ipa::<C, BA8, BA3, BA20, BA3, F>(ctx, input, aws).await,
-            16 => oprf_ipa::<C, BA8, BA3, BA20, BA4, F>(ctx, input, aws).await,
-            32 => oprf_ipa::<C, BA8, BA3, BA20, BA5, F>(ctx, input, aws).await,
-            64 => oprf_ipa::<C, BA8, BA3, BA20, BA6, F>(ctx, input, aws).await,
-            128 => oprf_ipa::<C, BA8, BA3, BA20, BA7, F>(ctx, input, aws).await,
-            _ => panic!(
-                "Invalid value specified for per-user cap: {:?}. Must be one of 8, 16, 32, 64, or 128.",
-                config.per_user_credit_cap
-            ),
+        let cap = config.per_user_credit_cap;
+        if cap == 0 {
+            return Err(Error::InvalidQueryParameter(
+                "per-user cap must be positive".into(),
+            ));
+        }
+        // a single user contributes at most `cap` to all breakdowns combined
+        let noise = config
+            .dp_epsilon
+            .map(|epsilon| BinomialNoise::new(epsilon.into(), config.dp_delta.into(), cap))
+            .transpose()
+            .map_err(|e| Error::InvalidQueryParameter(e.into()))?;
+        let padding = match (config.dp_padding_max_user_rows, config.dp_epsilon) {
+            (None, _) => None,
+            (Some(_), None) => {
+                return Err(Error::InvalidQueryParameter(
+                    "padding with dummy users requires dp_epsilon to be set".into(),
+                ))
+            }
+            (Some(rows), Some(_)) if rows.get() > MAX_DUMMY_USER_ROWS => {
+                return Err(Error::InvalidQueryParameter(
+                    format!("dummy users can have at most {MAX_DUMMY_USER_ROWS} rows, got {rows}")
+                        .into(),
+                ))
+            }
+            (Some(rows), Some(epsilon)) => Some(PaddingParams {
+                max_user_rows: rows.get(),
+                // a single user changes the number of users with any given number of rows by 1
+                noise: BinomialNoise::new(epsilon.into(), config.dp_delta.into(), 1)
+                    .map_err(|e| Error::InvalidQueryParameter(e.into()))?,
+            }),
+        };
+        let mut shards = shard_coordinator.shard_count(sz);
+        let mut pipeline = PipelineBuilder::new(cap, bks + u32::from(overflow))
+            .with_attribution_window(aws)
+            .with_padding(padding)
+            .with_segments(segments)
+            .with_noise(noise)
+            .with_output_divisor(config.output_divisor)
+            .with_breakdown_allow_list(config.breakdown_allow_list);
+        if config.count_attributed_users {
+            if shards > NonZeroU32::MIN {
+                tracing::warn!(
+                    "{shards} shards requested, but sharded attribution does not count \
+                     attributed users, running a single shard"
+                );
+                shards = NonZeroU32::MIN;
+            }
+            pipeline = pipeline.with_attributed_user_counts();
+        }
+        if config.sort_by_timestamp {
+            pipeline = pipeline.with_timestamp_sort();
+        }
+        // priorities only break ties between timestamps, so queries that never look at timestamps
+        // ignore them
+        if config.report_schema().priority_bits.is_some() && uses_timestamps(&config) {
+            pipeline = pipeline.with_source_priority();
+        }
+        #[cfg(feature = "descriptive-gate")]
+        let pipeline = {
+            let time_buckets = config.time_bucket_days.map(|days| TimeBucketParams {
+                query_epoch: config.query_epoch_seconds,
+                bucket_size: BucketSize::Days(days),
+                utc_offset_seconds: config.utc_offset_seconds,
+            });
+            if time_buckets.is_some() && shards > NonZeroU32::MIN {
+                tracing::warn!(
+                    "{shards} shards requested, but sharded attribution does not key totals by \
+                     time bucket, running a single shard"
+                );
+                shards = NonZeroU32::MIN;
+            }
+            pipeline
+                .with_time_buckets(time_buckets)
+                .with_shards(shards)
+                .with_deadline(deadline)
+        };
+        #[cfg(not(feature = "descriptive-gate"))]
+        {
+            if config.time_bucket_days.is_some() {
+                return Err(Error::InvalidQueryParameter(
+                    "totals can't be keyed by time bucket with compact gate yet".into(),
+                ));
+            }
+            if shards > NonZeroU32::MIN {
+                tracing::warn!(
+                    "{shards} shards requested for {sz} rows, but sharded attribution is not \
+                     supported with compact gate yet, running a single shard"
+                );
+            }
+            if deadline.is_some() {
+                return Err(Error::InvalidQueryParameter(
+                    "query deadline is not supported with compact gate yet".into(),
+                ));
+            }
+        }
+        let pipeline = pipeline.build();
+
+        if uses_timestamps(&config) {
+            match pseudonyms {
+                Some(pseudonyms) => {
+                    pipeline
+                        .run_pseudonymized_partial::<C, BK, TV, TS, F>(ctx, pseudonyms, input)
+                        .await
+                }
+                None => pipeline.run_partial::<C, BK, TV, TS, F>(ctx, input).await,
+            }
+        } else {
+            let input = input
+                .into_iter()
+                .map(OprfReport::without_timestamp)
+                .collect::<Vec<_>>();
+            match pseudonyms {
+                Some(pseudonyms) => {
+                    pipeline
+                        .run_pseudonymized_partial::<C, BK, TV, NoTimestamp, F>(
+                            ctx, pseudonyms, input,
+                        )
+                        .await
+                }
+                None => {
+                    pipeline
+                        .run_partial::<C, BK, TV, NoTimestamp, F>(ctx, input)
+                        .await
+                }
+            }
+        }
+    }
+}
+
+/// Timestamp that rows of queries which never look at timestamps carry instead of the one in the
+/// reports: the narrowest one helpers support. Its shares are always zero, so it only saves the
+/// bytes rows take up whenever they are sent between helpers.
+type NoTimestamp = BA3;
+
+/// Whether the query needs timestamps of the reports: to check the attribution window, to key
+/// totals by time bucket or to sort rows of every user, which padding does too.
+fn uses_timestamps(config: &IpaQueryConfig) -> bool {
+    config.attribution_window_seconds.is_some()
+        || config.time_bucket_days.is_some()
+        || config.sort_by_timestamp
+        || config.dp_padding_max_user_rows.is_some()
+}
+
+/// Decrypts the match keys of `reports`, skipping the ones that cannot be decrypted or that were
+/// encrypted for a different epoch or with a different key than `config` requires.
+///
+/// A report may fail to decrypt on one helper only, for instance if the report collector corrupted
+/// a single share. Helpers must agree on the set of reports that enter the protocol, so every
+/// helper tells its peers which reports it failed to decrypt and all of them drop the reports
+/// rejected by any helper.
+///
+/// The same goes for breakdown keys that are wider than `BK`: only the helpers that hold the wide
+/// share see it. Unless `config` says to skip them like other invalid reports, helpers agree on
+/// the reports with wide keys too, and either fail the query or set their breakdown key to
+/// `max_breakdown_key`.
+async fn decrypt_reports<C, BK, TV, TS, B>(
+    ctx: C,
+    reports: &[EncryptedOprfReport<BK, TV, TS, B>],
+    key_registry: &KeyRegistry<KeyPair>,
+    config: &IpaQueryConfig,
+) -> Result<Vec<OprfReport<BK, TV, TS>>, Error>
+where
+    C: Context,
+    BK: WeakSharedValue + Field,
+    TV: WeakSharedValue,
+    TS: WeakSharedValue,
+    B: Deref<Target = [u8]>,
+    Replicated<BK>: Serializable,
+    Replicated<TV>: Serializable,
+    Replicated<TS>: Serializable,
+{
+    let detect_wide_keys = config.breakdown_key_overflow != BreakdownKeyOverflow::Skip;
+    let overflow_key =
+        Replicated::share_known_value(&ctx, BK::truncate_from(config.max_breakdown_key));
+    let mut failed = 0;
+    let decrypted = reports
+        .iter()
+        .enumerate()
+        .map(|(i, report)| {
+            let wide_key = detect_wide_keys && report.has_wide_breakdown_key();
+            report
+                .check_associated_data(config.epoch, config.key_id)
+                .and_then(|()| {
+                    if wide_key {
+                        report.decrypt_with_breakdown_key(key_registry, overflow_key.clone())
+                    } else {
+                        report.decrypt(key_registry)
+                    }
+                })
+                .map(|report| (report, wide_key))
+                .map_err(|e| {
+                    failed += 1;
+                    tracing::debug!("skipping report {i}: {e}");
+                })
+                .ok()
+        })
+        .collect::<Vec<_>>();
+    if failed > 0 {
+        tracing::warn!(
+            "{failed} out of {} reports were invalid and were skipped",
+            reports.len()
+        );
+    }
+
+    if decrypted.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let valid = set_on_all_helpers(
+        ctx.narrow(&Step::AgreeOnValidReports),
+        decrypted.iter().map(Option::is_some),
+    )
+    .await?;
+    // a key is wide unless none of the helpers saw it wide
+    let wide_keys = if detect_wide_keys {
+        set_on_all_helpers(
+            ctx.narrow(&Step::AgreeOnBreakdownKeyOverflow),
+            decrypted
+                .iter()
+                .map(|report| !report.as_ref().map_or(false, |&(_, wide_key)| wide_key)),
+        )
+        .await?
+        .into_iter()
+        .map(|narrow_key| !narrow_key)
+        .collect()
+    } else {
+        vec![false; decrypted.len()]
+    };
+
+    let mut wide = 0;
+    let input = decrypted
+        .into_iter()
+        .zip(valid)
+        .zip(wide_keys)
+        .filter_map(|((report, valid), wide_key)| {
+            let (mut report, _) = report.filter(|_| valid)?;
+            if wide_key {
+                wide += 1;
+                report.breakdown_key = overflow_key.clone();
+            }
+            Some(report)
+        })
+        .collect::<Vec<_>>();
+    if input.len() + failed < reports.len() {
+        tracing::warn!(
+            "{} reports were skipped because other helpers rejected them",
+            reports.len() - failed - input.len()
+        );
+    }
+    if wide > 0 {
+        let message = format!(
+            "{wide} reports have breakdown keys wider than {} bits",
+            <BK as WeakSharedValue>::BITS
+        );
+        if config.breakdown_key_overflow == BreakdownKeyOverflow::Reject {
+            return Err(Error::InvalidQueryParameter(message.into()));
+        }
+        tracing::warn!(
+            "{message}, they are attributed to breakdown {}",
+            config.max_breakdown_key
+        );
+    }
+
+    Ok(input)
+}
+
+/// Tells both peers which of `flags` are set on this helper and returns the ones that are set on
+/// all three helpers.
+async fn set_on_all_helpers<C, I>(ctx: C, flags: I) -> Result<Vec<bool>, Error>
+where
+    C: Context,
+    I: ExactSizeIterator<Item = bool>,
+{
+    let ctx = ctx.set_total_records(flags.len());
+    let (left, right) = (
+        ctx.role().peer(Direction::Left),
+        ctx.role().peer(Direction::Right),
+    );
+    let (left_sender, right_sender) = (ctx.send_channel(left), ctx.send_channel(right));
+    let (left_receiver, right_receiver) = (
+        ctx.recv_channel::<Boolean>(left),
+        ctx.recv_channel::<Boolean>(right),
+    );
+    ctx.try_join(flags.enumerate().map(|(i, flag)| {
+        let record_id = RecordId::from(i);
+        let flag = Boolean::from(flag);
+        let (left_sender, right_sender) = (&left_sender, &right_sender);
+        let (left_receiver, right_receiver) = (&left_receiver, &right_receiver);
+        async move {
+            try_join(
+                left_sender.send(record_id, flag),
+                right_sender.send(record_id, flag),
+            )
+            .await?;
+            let (from_left, from_right) = try_join(
+                left_receiver.receive(record_id),
+                right_receiver.receive(record_id),
+            )
+            .await?;
+            Ok::<_, Error>(bool::from(flag * from_left * from_right))
+        }
+    }))
+    .await
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use std::num::{NonZeroU32, NonZeroUsize};
+
+    use futures::future::join_all;
+    use generic_array::GenericArray;
+    use rand::rngs::StdRng;
+    use rand_core::SeedableRng;
+    use typenum::Unsigned;
+
+    use super::*;
+    use crate::{
+        ff::Fp32BitPrime,
+        report::{EncryptedPseudonym, Epoch, InvalidReportError, ReportSchema, DEFAULT_KEY_ID},
+        secret_sharing::IntoShares,
+        test_fixture::{ipa::TestRawDataRecord, join3v, Reconstruct, TestWorld},
+    };
+
+    #[test]
+    fn shard_count() {
+        assert_eq!(1, ShardCoordinator::default().shard_count(1_000_000).get());
+        assert_eq!(2, ShardCoordinator::default().shard_count(2_000_000).get());
+
+        let coordinator = ShardCoordinator::new(
+            NonZeroUsize::new(1000).unwrap(),
+            NonZeroU32::new(8).unwrap(),
+        );
+        assert_eq!(1, coordinator.shard_count(0).get());
+        assert_eq!(1, coordinator.shard_count(1000).get());
+        assert_eq!(2, coordinator.shard_count(1001).get());
+        assert_eq!(8, coordinator.shard_count(1_000_000).get());
+    }
+
+    #[tokio::test]
+    async fn custom_report_layout() {
+        // 16 breakdowns fit into a 4-bit breakdown key, while trigger values up to 255 require
+        // an 8-bit trigger value.
+        let records = vec![
+            TestRawDataRecord {
+                timestamp: 0,
+                user_id: 12345,
+                is_trigger_report: false,
+                breakdown_key: 15,
+                trigger_value: 0,
+            },
+            TestRawDataRecord {
+                timestamp: 5,
+                user_id: 12345,
+                is_trigger_report: true,
+                breakdown_key: 0,
+                trigger_value: 100,
+            },
+            TestRawDataRecord {
+                timestamp: 0,
+                user_id: 68362,
+                is_trigger_report: false,
+                breakdown_key: 3,
+                trigger_value: 0,
+            },
+            TestRawDataRecord {
+                timestamp: 5,
+                user_id: 68362,
+                is_trigger_report: true,
+                breakdown_key: 0,
+                trigger_value: 20,
+            },
+        ];
+        let query_size = QuerySize::try_from(records.len()).unwrap();
+        let config = IpaQueryConfig {
+            per_user_credit_cap: 128,
+            max_breakdown_key: 16,
+            attribution_window_seconds: None,
+            plaintext_match_keys: true,
+            breakdown_key_bits: 4,
+            trigger_value_bits: 8,
+            ..Default::default()
+        };
+
+        let buffers = records.into_iter().share().map(|shares| {
+            shares
+                .into_iter()
+                .flat_map(|share: OprfReport<BA4, BA8, BA20>| {
+                    let mut buf = [0u8; <OprfReport<BA4, BA8, BA20> as Serializable>::Size::USIZE];
+                    share.serialize(GenericArray::from_mut_slice(&mut buf));
+
+                    buf
+                })
+                .collect::<Vec<_>>()
+        });
+
+        let world = TestWorld::default();
+        let results = join3v(
+            world
+                .contexts()
+                .into_iter()
+                .zip(buffers)
+                .map(|(ctx, buffer)| {
+                    OprfIpaQuery::<_, Fp32BitPrime>::new(config, Arc::new(KeyRegistry::empty()))
+                        .execute(ctx, query_size, BodyStream::from(buffer))
+                }),
+        )
+        .await
+        .reconstruct();
+
+        let mut expected = [0_u128; 16];
+        expected[3] = 20;
+        expected[15] = 100;
+        assert_eq!(
+            expected.as_slice(),
+            results.iter().map(Field::as_u128).collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn revenue_trigger_values() {
+        // revenue in cents requires 16-bit trigger values and a cap that does not fit into 8 bits
+        let records = vec![
+            TestRawDataRecord {
+                timestamp: 0,
+                user_id: 12345,
+                is_trigger_report: false,
+                breakdown_key: 3,
+                trigger_value: 0,
+            },
+            TestRawDataRecord {
+                timestamp: 5,
+                user_id: 12345,
+                is_trigger_report: true,
+                breakdown_key: 0,
+                trigger_value: 1250,
+            },
+            TestRawDataRecord {
+                timestamp: 0,
+                user_id: 68362,
+                is_trigger_report: false,
+                breakdown_key: 7,
+                trigger_value: 0,
+            },
+            TestRawDataRecord {
+                timestamp: 5,
+                user_id: 68362,
+                is_trigger_report: true,
+                breakdown_key: 0,
+                trigger_value: 30_000,
+            },
+            TestRawDataRecord {
+                timestamp: 6,
+                user_id: 68362,
+                is_trigger_report: true,
+                breakdown_key: 0,
+                trigger_value: 20_000,
+            },
+        ];
+        let query_size = QuerySize::try_from(records.len()).unwrap();
+        let config = IpaQueryConfig {
+            per_user_credit_cap: 40_000,
+            max_breakdown_key: 8,
+            attribution_window_seconds: None,
+            plaintext_match_keys: true,
+            breakdown_key_bits: 5,
+            trigger_value_bits: 16,
+            ..Default::default()
+        };
+
+        let buffers = records.into_iter().share().map(|shares| {
+            shares
+                .into_iter()
+                .flat_map(|share: OprfReport<BA5, BA16, BA20>| {
+                    let mut buf = [0u8; <OprfReport<BA5, BA16, BA20> as Serializable>::Size::USIZE];
+                    share.serialize(GenericArray::from_mut_slice(&mut buf));
+
+                    buf
+                })
+                .collect::<Vec<_>>()
+        });
+
+        let world = TestWorld::default();
+        let results = join3v(
+            world
+                .contexts()
+                .into_iter()
+                .zip(buffers)
+                .map(|(ctx, buffer)| {
+                    OprfIpaQuery::<_, Fp32BitPrime>::new(config, Arc::new(KeyRegistry::empty()))
+                        .execute(ctx, query_size, BodyStream::from(buffer))
+                }),
+        )
+        .await
+        .reconstruct();
+
+        let mut expected = [0_u128; 8];
+        expected[3] = 1250;
+        // the second user contributed 50000, which is capped
+        expected[7] = 40_000;
+        assert_eq!(
+            expected.as_slice(),
+            results.iter().map(Field::as_u128).collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_without_timestamps() {
+        let records = vec![
+            TestRawDataRecord {
+                timestamp: 0,
+                user_id: 12345,
+                is_trigger_report: false,
+                breakdown_key: 1,
+                trigger_value: 0,
+            },
+            TestRawDataRecord {
+                timestamp: 0,
+                user_id: 12345,
+                is_trigger_report: true,
+                breakdown_key: 0,
+                trigger_value: 5,
+            },
+        ];
+        let query_size = QuerySize::try_from(records.len()).unwrap();
+        let config = IpaQueryConfig {
+            attribution_window_seconds: None,
+            plaintext_match_keys: true,
+            timestamp_bits: 0,
+            ..Default::default()
+        };
+
+        // report collectors leave out timestamp shares, which follow match key shares
+        let ts_len = <Replicated<BA20> as Serializable>::Size::USIZE;
+        let buffers = records.into_iter().share().map(|shares| {
+            shares
+                .into_iter()
+                .flat_map(|share: OprfReport<BA8, BA3, BA20>| {
+                    let mut buf = [0u8; <OprfReport<BA8, BA3, BA20> as Serializable>::Size::USIZE];
+                    share.serialize(GenericArray::from_mut_slice(&mut buf));
+
+                    let mut buf = buf.to_vec();
+                    buf.drain(16..16 + ts_len);
+                    buf
+                })
+                .collect::<Vec<_>>()
+        });
+
+        let world = TestWorld::default();
+        let results = join3v(
+            world
+                .contexts()
+                .into_iter()
+                .zip(buffers)
+                .map(|(ctx, buffer)| {
+                    OprfIpaQuery::<_, Fp32BitPrime>::new(config, Arc::new(KeyRegistry::empty()))
+                        .execute(ctx, query_size, BodyStream::from(buffer))
+                }),
+        )
+        .await
+        .reconstruct();
+
+        assert_eq!(
+            [0, 3].as_slice(),
+            &results.iter().map(Field::as_u128).collect::<Vec<_>>()[..2]
+        );
+    }
+
+    #[tokio::test]
+    async fn pseudonymized_input() {
+        let records = vec![
+            TestRawDataRecord {
+                timestamp: 0,
+                user_id: 12345,
+                is_trigger_report: false,
+                breakdown_key: 1,
+                trigger_value: 0,
+            },
+            TestRawDataRecord {
+                timestamp: 0,
+                user_id: 68362,
+                is_trigger_report: false,
+                breakdown_key: 2,
+                trigger_value: 0,
+            },
+            TestRawDataRecord {
+                timestamp: 5,
+                user_id: 12345,
+                is_trigger_report: true,
+                breakdown_key: 0,
+                trigger_value: 2,
+            },
+            TestRawDataRecord {
+                timestamp: 5,
+                user_id: 68362,
+                is_trigger_report: true,
+                breakdown_key: 0,
+                trigger_value: 3,
+            },
+        ];
+        let query_size = QuerySize::try_from(records.len()).unwrap();
+        let config = IpaQueryConfig {
+            attribution_window_seconds: None,
+            epoch: Some(3),
+            pseudonymized_input: true,
+            ..Default::default()
+        };
+        let mut rng = StdRng::seed_from_u64(42);
+        let key_registries: [_; 3] =
+            std::array::from_fn(|_| Arc::new(KeyRegistry::<KeyPair>::random(1, &mut rng)));
+
+        // every helper gets pseudonyms encrypted to its own key in place of match key shares,
+        // users with the same pseudonym are the same user
+        let user_ids = records.iter().map(|r| r.user_id).collect::<Vec<_>>();
+        let buffers = records
+            .into_iter()
+            .share()
+            .into_iter()
+            .zip(&key_registries)
+            .map(|(shares, key_registry)| {
+                shares
+                    .into_iter()
+                    .zip(&user_ids)
+                    .flat_map(|(share, &user_id): (OprfReport<BA8, BA3, BA20>, _)| {
+                        let mut buf =
+                            [0u8; <OprfReport<BA8, BA3, BA20> as Serializable>::Size::USIZE];
+                        share.serialize(GenericArray::from_mut_slice(&mut buf));
+                        let pseudonym = EncryptedPseudonym::seal(
+                            user_id,
+                            3,
+                            4,
+                            DEFAULT_KEY_ID,
+                            key_registry.as_ref(),
+                            &mut rng,
+                        )
+                        .unwrap();
+                        let mut pseudonym_buf = GenericArray::default();
+                        pseudonym.serialize(&mut pseudonym_buf);
+
+                        [pseudonym_buf.as_slice(), &buf[16..]].concat()
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        // pseudonyms can't be opened after they expire
+        let world = TestWorld::default();
+        let [ctx, _, _] = world.contexts();
+        let expired = OprfIpaQuery::<_, Fp32BitPrime>::new(
+            IpaQueryConfig {
+                epoch: Some(5),
+                ..config
+            },
+            Arc::clone(&key_registries[0]),
+        )
+        .execute(ctx, query_size, BodyStream::from(buffers[0].clone()))
+        .await;
+        assert!(matches!(expired, Err(Error::InvalidPseudonym(_))));
+
+        let world = TestWorld::default();
+        let results = join3v(
+            world
+                .contexts()
+                .into_iter()
+                .zip(buffers)
+                .zip(key_registries)
+                .map(|((ctx, buffer), key_registry)| {
+                    OprfIpaQuery::<_, Fp32BitPrime>::new(config, key_registry).execute(
+                        ctx,
+                        query_size,
+                        BodyStream::from(buffer),
+                    )
+                }),
+        )
+        .await
+        .reconstruct();
+
+        assert_eq!(
+            [0, 2, 3].as_slice(),
+            &results.iter().map(Field::as_u128).collect::<Vec<_>>()[..3]
+        );
+    }
+
+    #[tokio::test]
+    async fn input_does_not_match_schema() {
+        let config = IpaQueryConfig {
+            attribution_window_seconds: None,
+            plaintext_match_keys: true,
+            ..Default::default()
+        };
+        // a report with one byte missing
+        let len = config.report_schema().plaintext_len() - 1;
+
+        let world = TestWorld::default();
+        let results = join_all(world.contexts().into_iter().map(|ctx| {
+            OprfIpaQuery::<_, Fp32BitPrime>::new(config, Arc::new(KeyRegistry::empty())).execute(
+                ctx,
+                QuerySize::try_from(1).unwrap(),
+                BodyStream::from(vec![0_u8; len]),
+            )
+        }))
+        .await;
+
+        for result in results {
+            assert!(matches!(
+                result,
+                Err(Error::InvalidReport(
+                    InvalidReportError::SchemaMismatch { .. }
+                ))
+            ));
+        }
+    }
+
+    fn attributed_records() -> Vec<TestRawDataRecord> {
+        vec![
+            TestRawDataRecord {
+                timestamp: 0,
+                user_id: 12345,
+                is_trigger_report: false,
+                breakdown_key: 2,
+                trigger_value: 0,
+            },
+            TestRawDataRecord {
+                timestamp: 5,
+                user_id: 12345,
+                is_trigger_report: true,
+                breakdown_key: 0,
+                trigger_value: 5,
+            },
+            TestRawDataRecord {
+                timestamp: 0,
+                user_id: 68362,
+                is_trigger_report: false,
+                breakdown_key: 1,
+                trigger_value: 0,
+            },
+            TestRawDataRecord {
+                timestamp: 5,
+                user_id: 68362,
+                is_trigger_report: true,
+                breakdown_key: 0,
+                trigger_value: 2,
+            },
+        ]
+    }
+
+    /// Shares `records` and encrypts every share, the i-th report for epoch `epochs[i]`.
+    fn encrypt_records(
+        records: Vec<TestRawDataRecord>,
+        epochs: &[Epoch],
+        key_registry: &KeyRegistry<KeyPair>,
+        rng: &mut StdRng,
+    ) -> [Vec<Vec<u8>>; 3] {
+        let shares: [Vec<OprfReport<BA8, BA3, BA20>>; 3] = records.into_iter().share();
+        shares.map(|shares| {
+            shares
+                .into_iter()
+                .zip(epochs)
+                .map(|(share, &epoch)| {
+                    let mut buf = Vec::new();
+                    share
+                        .delimited_encrypt_to(
+                            DEFAULT_KEY_ID,
+                            epoch,
+                            "www.example.com",
+                            key_registry,
+                            rng,
+                            &mut buf,
+                        )
+                        .unwrap();
+                    buf
+                })
+                .collect::<Vec<_>>()
+        })
+    }
+
+    async fn run_encrypted(
+        config: IpaQueryConfig,
+        key_registry: &Arc<KeyRegistry<KeyPair>>,
+        buffers: [Vec<Vec<u8>>; 3],
+    ) -> Vec<u128> {
+        let query_size = QuerySize::try_from(buffers[0].len()).unwrap();
+        let world = TestWorld::default();
+        let results: Vec<Fp32BitPrime> = join3v(world.contexts().into_iter().zip(buffers).map(
+            |(ctx, reports)| {
+                OprfIpaQuery::<_, Fp32BitPrime>::new(config, Arc::clone(key_registry)).execute(
+                    ctx,
+                    query_size,
+                    BodyStream::from(reports.concat()),
+                )
+            },
+        ))
+        .await
+        .reconstruct();
+
+        results.iter().map(Field::as_u128).collect()
+    }
+
+    #[tokio::test]
+    async fn encrypted_match_keys() {
+        let config = IpaQueryConfig {
+            per_user_credit_cap: 8,
+            max_breakdown_key: 3,
+            attribution_window_seconds: None,
+            plaintext_match_keys: false,
+            ..Default::default()
+        };
+
+        let mut rng = StdRng::seed_from_u64(42);
+        let key_registry = Arc::new(KeyRegistry::random(1, &mut rng));
+        let mut buffers = encrypt_records(
+            attributed_records(),
+            &[0; 4],
+            key_registry.as_ref(),
+            &mut rng,
+        );
+        // Corrupt the match key ciphertext of the second report sent to the first helper only.
+        // The other helpers must skip it as well, which leaves breakdown 2 without conversions.
+        buffers[0][1][2 + 40] ^= 1;
+
+        assert_eq!(
+            vec![0, 2, 0],
+            run_encrypted(config, &key_registry, buffers).await
+        );
+    }
+
+    /// Helpers read batches with and without a version header alike, so one helper may receive
+    /// its shares from a report collector that writes headers while the others don't yet.
+    #[tokio::test]
+    async fn versioned_batches() {
+        let config = IpaQueryConfig {
+            per_user_credit_cap: 8,
+            max_breakdown_key: 3,
+            attribution_window_seconds: None,
+            plaintext_match_keys: false,
+            ..Default::default()
+        };
+
+        let mut rng = StdRng::seed_from_u64(42);
+        let key_registry = Arc::new(KeyRegistry::random(1, &mut rng));
+        let mut buffers = encrypt_records(
+            attributed_records(),
+            &[0; 4],
+            key_registry.as_ref(),
+            &mut rng,
+        );
+        let mut header = Vec::new();
+        config.report_schema().delimited_header_to(&mut header);
+        buffers[1].insert(0, header);
+
+        assert_eq!(
+            vec![0, 2, 5],
+            run_encrypted(config, &key_registry, buffers.clone()).await
+        );
+
+        // a header that declares another layout than the query does fails the query
+        let with_timestamps = ReportSchema {
+            timestamp_bits: Some(20),
+            ..config.report_schema()
+        };
+        let mut header = Vec::new();
+        with_timestamps.delimited_header_to(&mut header);
+        buffers[1][0] = header;
+        // the helper fails before it talks to the others
+        let world = TestWorld::default();
+        let [_, ctx, _] = world.contexts();
+        let result = OprfIpaQuery::<_, Fp32BitPrime>::new(config, key_registry)
+            .execute(
+                ctx,
+                QuerySize::try_from(4).unwrap(),
+                BodyStream::from(buffers[1].concat()),
+            )
+            .await;
+        assert!(matches!(
+            result,
+            Err(Error::InvalidReport(
+                InvalidReportError::HeaderMismatch { .. }
+            ))
+        ));
+    }
+
+    /// Queries without a window drop timestamps after decryption, but still attribute the same
+    /// way as queries with a window that all trigger events fall into.
+    #[tokio::test]
+    async fn windowless_query() {
+        let windowless = IpaQueryConfig {
+            per_user_credit_cap: 8,
+            max_breakdown_key: 3,
+            attribution_window_seconds: None,
+            plaintext_match_keys: false,
+            ..Default::default()
+        };
+        let with_window = IpaQueryConfig {
+            attribution_window_seconds: NonZeroU32::new(86_400),
+            ..windowless
+        };
+        assert!(!uses_timestamps(&windowless));
+        assert!(uses_timestamps(&with_window));
+
+        let mut rng = StdRng::seed_from_u64(42);
+        let key_registry = Arc::new(KeyRegistry::random(1, &mut rng));
+        for config in [windowless, with_window] {
+            let buffers = encrypt_records(
+                attributed_records(),
+                &[0; 4],
+                key_registry.as_ref(),
+                &mut rng,
+            );
+            assert_eq!(
+                vec![0, 2, 5],
+                run_encrypted(config, &key_registry, buffers).await
+            );
+        }
+    }
+
+    /// Offset of breakdown key shares in delimited encrypted reports with 20-bit timestamps, after
+    /// the length, the encapsulated key, the match key ciphertext and the timestamp.
+    const BREAKDOWN_KEY_OFFSET: usize = 2 + 32 + 32 + 6;
+
+    #[tokio::test]
+    async fn wide_breakdown_keys() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let key_registry = Arc::new(KeyRegistry::random(1, &mut rng));
+        // reports are shared with 8-bit breakdown keys, but all keys fit into 4 bits, so clearing
+        // the high bits of every share turns them into valid reports with 4-bit keys
+        let mut buffers = encrypt_records(
+            attributed_records(),
+            &[0; 4],
+            key_registry.as_ref(),
+            &mut rng,
+        );
+        for report in buffers.iter_mut().flatten() {
+            report[BREAKDOWN_KEY_OFFSET] &= 0x0f;
+            report[BREAKDOWN_KEY_OFFSET + 1] &= 0x0f;
         }
+        // the smallest key that does not fit, seen by the first helper only
+        buffers[0][0][BREAKDOWN_KEY_OFFSET] |= 0x10;
+
+        let config = |breakdown_key_overflow| IpaQueryConfig {
+            per_user_credit_cap: 8,
+            max_breakdown_key: 3,
+            attribution_window_seconds: None,
+            breakdown_key_bits: 4,
+            breakdown_key_overflow,
+            ..Default::default()
+        };
+        assert_eq!(
+            vec![0, 2, 0],
+            run_encrypted(
+                config(BreakdownKeyOverflow::Skip),
+                &key_registry,
+                buffers.clone()
+            )
+            .await
+        );
+        assert_eq!(
+            vec![0, 2, 0, 5],
+            run_encrypted(
+                config(BreakdownKeyOverflow::Overflow),
+                &key_registry,
+                buffers.clone()
+            )
+            .await
+        );
+
+        let world = TestWorld::default();
+        let results = join_all(
+            world
+                .contexts()
+                .into_iter()
+                .zip(buffers)
+                .map(|(ctx, reports)| {
+                    OprfIpaQuery::<_, Fp32BitPrime>::new(
+                        config(BreakdownKeyOverflow::Reject),
+                        Arc::clone(&key_registry),
+                    )
+                    .execute(
+                        ctx,
+                        QuerySize::try_from(4).unwrap(),
+                        BodyStream::from(reports.concat()),
+                    )
+                }),
+        )
+        .await;
+        assert!(results
+            .iter()
+            .all(|result| matches!(result, Err(Error::InvalidQueryParameter(_)))));
+    }
+
+    #[tokio::test]
+    async fn unexpected_epoch() {
+        let config = IpaQueryConfig {
+            per_user_credit_cap: 8,
+            max_breakdown_key: 3,
+            attribution_window_seconds: None,
+            plaintext_match_keys: false,
+            epoch: Some(1),
+            key_id: Some(DEFAULT_KEY_ID),
+            ..Default::default()
+        };
+
+        let mut rng = StdRng::seed_from_u64(42);
+        let key_registry = Arc::new(KeyRegistry::random(1, &mut rng));
+        // The last report decrypts fine, but it is from another epoch, which leaves breakdown 1
+        // without conversions.
+        let buffers = encrypt_records(
+            attributed_records(),
+            &[1, 1, 1, 2],
+            key_registry.as_ref(),
+            &mut rng,
+        );
+
+        assert_eq!(
+            vec![0, 0, 5],
+            run_encrypted(config, &key_registry, buffers).await
+        );
+    }
+
+    #[tokio::test]
+    async fn dp_noise() {
+        let config = IpaQueryConfig {
+            per_user_credit_cap: 8,
+            max_breakdown_key: 3,
+            attribution_window_seconds: None,
+            plaintext_match_keys: false,
+            dp_epsilon: Some(10.0.try_into().unwrap()),
+            ..Default::default()
+        };
+
+        let mut rng = StdRng::seed_from_u64(42);
+        let key_registry = Arc::new(KeyRegistry::random(1, &mut rng));
+        let buffers = encrypt_records(
+            attributed_records(),
+            &[0; 4],
+            key_registry.as_ref(),
+            &mut rng,
+        );
+
+        let results = run_encrypted(config, &key_registry, buffers).await;
+        let p = i64::from(Fp32BitPrime::PRIME);
+        let noise = results
+            .iter()
+            .zip([0, 2, 5])
+            .map(|(&v, exact)| {
+                let v = i64::try_from(v).unwrap();
+                (if v > p / 2 { v - p } else { v }) - exact
+            })
+            .collect::<Vec<_>>();
+
+        // Standard deviation of the noise is about 10, so it is very unlikely to be 0 for all
+        // breakdowns or to exceed 60 for any of them.
+        assert!(noise.iter().any(|&n| n != 0), "no noise added: {results:?}");
+        assert!(
+            noise.iter().all(|n| n.abs() < 60),
+            "too much noise: {noise:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn unsupported_report_layout() {
+        let config = IpaQueryConfig {
+            plaintext_match_keys: true,
+            trigger_value_bits: 5,
+            ..Default::default()
+        };
+        let world = TestWorld::default();
+        let [ctx, _, _] = world.contexts();
+        let result = OprfIpaQuery::<_, Fp32BitPrime>::new(config, Arc::new(KeyRegistry::empty()))
+            .execute(
+                ctx,
+                QuerySize::try_from(1).unwrap(),
+                BodyStream::from(Vec::new()),
+            )
+            .await;
+
+        assert!(matches!(result, Err(Error::InvalidQueryParameter(_))));
+    }
+
+    #[tokio::test]
+    async fn segments_exceed_cap() {
+        let config = IpaQueryConfig {
+            per_user_credit_cap: 2,
+            plaintext_match_keys: true,
+            user_segment_rows: NonZeroU32::new(10),
+            max_user_segments: NonZeroU32::new(4).unwrap(),
+            ..Default::default()
+        };
+        let world = TestWorld::default();
+        let [ctx, _, _] = world.contexts();
+        let result = OprfIpaQuery::<_, Fp32BitPrime>::new(config, Arc::new(KeyRegistry::empty()))
+            .execute(
+                ctx,
+                QuerySize::try_from(1).unwrap(),
+                BodyStream::from(Vec::new()),
+            )
+            .await;
+
+        assert!(matches!(result, Err(Error::InvalidQueryParameter(_))));
     }
 }
@@ -0,0 +1,286 @@
+use std::sync::Arc;
+
+use futures::TryStreamExt;
+use rand::rngs::StdRng;
+use rand_core::SeedableRng;
+
+use crate::{
+    error::Error,
+    ff::{boolean_array::BA64, ec_prime_field::Fp25519},
+    helpers::{
+        query::{PseudonymQueryConfig, QuerySize},
+        BodyStream, RecordsStream,
+    },
+    hpke::{KeyPair, KeyRegistry},
+    protocol::{context::Context, ipa_prf::compute_match_key_pseudonyms},
+    report::{EncryptedPseudonym, DEFAULT_KEY_ID},
+    secret_sharing::replicated::semi_honest::AdditiveShare as Replicated,
+};
+
+/// Runs the PRF stage of OPRF IPA and exports its output instead of attributing reports.
+///
+/// Input rows are plaintext match key shares. For every row, this helper returns the PRF of the
+/// match key encrypted to its own key, so it can be presented to this helper again in a later
+/// query that runs within the configured range of epochs, see
+/// [`IpaQueryConfig::pseudonymized_input`].
+///
+/// PRF is evaluated under the share of the helpers' [`PersistentPrfKey`], so pseudonyms exported
+/// by different queries match. Helpers that do not hold that key reject the query.
+///
+/// [`IpaQueryConfig::pseudonymized_input`]: crate::helpers::query::IpaQueryConfig::pseudonymized_input
+/// [`PersistentPrfKey`]: crate::protocol::ipa_prf::prf_eval::PersistentPrfKey
+pub struct OprfPseudonymQuery {
+    config: PseudonymQueryConfig,
+    key_registry: Arc<KeyRegistry<KeyPair>>,
+    prf_key: Option<Replicated<Fp25519>>,
+}
+
+impl OprfPseudonymQuery {
+    pub fn new(
+        config: PseudonymQueryConfig,
+        key_registry: Arc<KeyRegistry<KeyPair>>,
+        prf_key: Option<Replicated<Fp25519>>,
+    ) -> Self {
+        Self {
+            config,
+            key_registry,
+            prf_key,
+        }
+    }
+
+    #[tracing::instrument("oprf_pseudonym_query", skip_all, fields(sz=%query_size))]
+    pub async fn execute<C: Context>(
+        self,
+        ctx: C,
+        query_size: QuerySize,
+        input_stream: BodyStream,
+    ) -> Result<Vec<EncryptedPseudonym>, Error> {
+        let Self {
+            config,
+            key_registry,
+            prf_key,
+        } = self;
+        tracing::info!("New query: {config:?}");
+
+        if !config.allow_linkable_output {
+            return Err(Error::InvalidQueryParameter(
+                "Pseudonyms allow linking reports of the same user. Set allow_linkable_output \
+                 to request them."
+                    .into(),
+            ));
+        }
+        let last_epoch = config.last_epoch().ok_or_else(|| {
+            Error::InvalidQueryParameter(
+                format!(
+                    "Pseudonyms can be valid for 1 to {max} epochs, starting at epoch {epoch}, \
+                     got {requested}",
+                    max = PseudonymQueryConfig::MAX_VALIDITY_EPOCHS,
+                    epoch = config.epoch,
+                    requested = config.validity_epochs,
+                )
+                .into(),
+            )
+        })?;
+        let prf_key = prf_key.ok_or_else(|| {
+            Error::InvalidQueryParameter(
+                "Pseudonyms can only be exported by helpers that share a persistent PRF key".into(),
+            )
+        })?;
+
+        let mut match_keys = RecordsStream::<Replicated<BA64>, _>::new(input_stream)
+            .try_concat()
+            .await?;
+        match_keys.truncate(usize::from(query_size));
+
+        let pseudonyms = compute_match_key_pseudonyms(ctx, &prf_key, match_keys).await?;
+
+        let mut rng = StdRng::from_entropy();
+        pseudonyms
+            .into_iter()
+            .map(|pseudonym| {
+                EncryptedPseudonym::seal(
+                    pseudonym,
+                    config.epoch,
+                    last_epoch,
+                    DEFAULT_KEY_ID,
+                    key_registry.as_ref(),
+                    &mut rng,
+                )
+                .map_err(Error::from)
+            })
+            .collect()
+    }
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use generic_array::GenericArray;
+    use rand::{thread_rng, Rng};
+    use typenum::Unsigned;
+
+    use super::*;
+    use crate::{
+        ff::{Field, Serializable},
+        report::InvalidPseudonymError,
+        secret_sharing::IntoShares,
+        test_fixture::{join3v, TestWorld},
+    };
+
+    fn config(validity_epochs: u16) -> PseudonymQueryConfig {
+        PseudonymQueryConfig {
+            epoch: 7,
+            validity_epochs,
+            allow_linkable_output: true,
+        }
+    }
+
+    /// Runs the query over `match_keys` with PRF key `prf_key` and returns the pseudonyms issued
+    /// by every helper.
+    async fn export(
+        match_keys: &[u64],
+        prf_key: Fp25519,
+        key_registries: &[Arc<KeyRegistry<KeyPair>>; 3],
+    ) -> [Vec<EncryptedPseudonym>; 3] {
+        let query_size = QuerySize::try_from(match_keys.len()).unwrap();
+        let buffers = match_keys
+            .iter()
+            .copied()
+            .map(BA64::truncate_from)
+            .share()
+            .map(|shares| {
+                shares
+                    .into_iter()
+                    .flat_map(|share: Replicated<BA64>| {
+                        let mut buf = [0u8; <Replicated<BA64> as Serializable>::Size::USIZE];
+                        share.serialize(GenericArray::from_mut_slice(&mut buf));
+
+                        buf
+                    })
+                    .collect::<Vec<_>>()
+            });
+
+        let world = TestWorld::default();
+        join3v(
+            world
+                .contexts()
+                .into_iter()
+                .zip(buffers)
+                .zip(prf_key.share())
+                .zip(key_registries.clone())
+                .map(|(((ctx, buffer), prf_key), key_registry)| {
+                    OprfPseudonymQuery::new(config(2), key_registry, Some(prf_key)).execute(
+                        ctx,
+                        query_size,
+                        BodyStream::from(buffer),
+                    )
+                }),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn pseudonyms() {
+        let match_keys = [12345_u64, 68362, 12345, 1];
+        let key_registries: [_; 3] =
+            std::array::from_fn(|_| Arc::new(KeyRegistry::random(1, &mut thread_rng())));
+        let prf_key = thread_rng().gen::<Fp25519>();
+        let results = export(&match_keys, prf_key, &key_registries).await;
+
+        let open = |results: &[Vec<EncryptedPseudonym>; 3]| {
+            results
+                .iter()
+                .zip(&key_registries)
+                .map(|(pseudonyms, key_registry)| {
+                    pseudonyms
+                        .iter()
+                        .map(|p| {
+                            assert_eq!((7, 8), (p.epoch(), p.last_epoch()));
+                            p.open(key_registry, 8).unwrap()
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        };
+        let opened = open(&results);
+
+        // All helpers learn the same PRF values, rows of the same user get the same pseudonym.
+        assert_eq!(opened[0], opened[1]);
+        assert_eq!(opened[0], opened[2]);
+        assert_eq!(opened[0][0], opened[0][2]);
+        assert_ne!(opened[0][0], opened[0][1]);
+        assert_ne!(opened[0][1], opened[0][3]);
+
+        // The same key gives the same pseudonyms in another query, which is what makes them
+        // reusable, while another key gives different ones.
+        let reissued = open(&export(&match_keys, prf_key, &key_registries).await);
+        assert_eq!(opened, reissued);
+        let rekeyed = open(&export(&match_keys, prf_key + Fp25519::ONE, &key_registries).await);
+        assert_ne!(opened[0][0], rekeyed[0][0]);
+
+        // Pseudonyms expire and can only be opened by the helper that issued them.
+        let pseudonym = &results[0][0];
+        assert!(matches!(
+            pseudonym.open(&key_registries[0], 6),
+            Err(InvalidPseudonymError::Expired { .. })
+        ));
+        assert!(matches!(
+            pseudonym.open(&key_registries[0], 9),
+            Err(InvalidPseudonymError::Expired { .. })
+        ));
+        assert!(matches!(
+            pseudonym.open(&key_registries[1], 7),
+            Err(InvalidPseudonymError::Crypt(_))
+        ));
+
+        // Extending the expiry in the clear does not extend the pseudonym's lifetime.
+        let mut buf = GenericArray::default();
+        pseudonym.serialize(&mut buf);
+        buf[3..5].copy_from_slice(&9_u16.to_le_bytes());
+        assert!(matches!(
+            EncryptedPseudonym::deserialize(&buf).open(&key_registries[0], 9),
+            Err(InvalidPseudonymError::ExpiryMismatch)
+        ));
+    }
+
+    #[tokio::test]
+    async fn privacy_controls() {
+        for config in [
+            PseudonymQueryConfig {
+                allow_linkable_output: false,
+                ..config(1)
+            },
+            config(0),
+            config(PseudonymQueryConfig::MAX_VALIDITY_EPOCHS + 1),
+        ] {
+            let world = TestWorld::default();
+            let [ctx, _, _] = world.contexts();
+            let key_registry = Arc::new(KeyRegistry::random(1, &mut thread_rng()));
+            let result = OprfPseudonymQuery::new(config, key_registry, Some(Replicated::ZERO))
+                .execute(
+                    ctx,
+                    QuerySize::try_from(1).unwrap(),
+                    BodyStream::from(Vec::new()),
+                )
+                .await;
+
+            assert!(
+                matches!(result, Err(Error::InvalidQueryParameter(_))),
+                "{config:?} must be rejected"
+            );
+        }
+
+        // helpers without a persistent PRF key can't issue pseudonyms that are valid across
+        // queries
+        let world = TestWorld::default();
+        let [ctx, _, _] = world.contexts();
+        let key_registry = Arc::new(KeyRegistry::random(1, &mut thread_rng()));
+        let result = OprfPseudonymQuery::new(config(1), key_registry, None)
+            .execute(
+                ctx,
+                QuerySize::try_from(1).unwrap(),
+                BodyStream::from(Vec::new()),
+            )
+            .await;
+        assert!(matches!(result, Err(Error::InvalidQueryParameter(_))));
+    }
+}
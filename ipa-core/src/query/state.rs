@@ -10,10 +10,13 @@ use futures::{ready, FutureExt};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    helpers::{query::QueryConfig, RoleAssignment},
+    helpers::{
+        query::{QueryConfig, QueryInput},
+        ProgressTracker, QueryMetrics, QueryProgress, RoleAssignment,
+    },
     protocol::QueryId,
     query::runner::QueryResult,
-    sync::Mutex,
+    sync::{Arc, Mutex},
     task::JoinHandle,
 };
 
@@ -36,12 +39,44 @@ pub enum QueryStatus {
     Completed,
 }
 
+/// Status of a query along with its progress, as reported to clients.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueryStatusReport {
+    pub status: QueryStatus,
+    /// Only available while the query is running.
+    pub progress: Option<QueryProgress>,
+    /// Per-stage metrics collected so far. Available while the query is running and once it
+    /// has completed successfully.
+    pub metrics: Option<QueryMetrics>,
+}
+
+impl From<&QueryState> for QueryStatusReport {
+    fn from(source: &QueryState) -> Self {
+        let (progress, metrics) = match source {
+            QueryState::Running(running) => (
+                Some(running.progress.snapshot()),
+                Some(running.progress.metrics()),
+            ),
+            QueryState::Completed(Ok(result)) => (None, result.metrics().cloned()),
+            _ => (None, None),
+        };
+
+        Self {
+            status: QueryStatus::from(source),
+            progress,
+            metrics,
+        }
+    }
+}
+
 impl From<&QueryState> for QueryStatus {
     fn from(source: &QueryState) -> Self {
         match source {
             QueryState::Empty => panic!("Query cannot be in the empty state"),
             QueryState::Preparing(_) => QueryStatus::Preparing,
-            QueryState::AwaitingInputs(_, _, _) => QueryStatus::AwaitingInputs,
+            QueryState::AwaitingInputs(_, _, _) | QueryState::AwaitingSecondInput(_, _, _, _) => {
+                QueryStatus::AwaitingInputs
+            }
             QueryState::Running(_) => QueryStatus::Running,
             QueryState::AwaitingCompletion => QueryStatus::AwaitingCompletion,
             QueryState::Completed(_) => QueryStatus::Completed,
@@ -54,6 +89,9 @@ pub enum QueryState {
     Empty,
     Preparing(QueryConfig),
     AwaitingInputs(QueryId, QueryConfig, RoleAssignment),
+    /// One of the two report collectors has submitted its part of the input, query is waiting
+    /// for the other one.
+    AwaitingSecondInput(QueryId, QueryConfig, RoleAssignment, QueryInput),
     Running(RunningQuery),
     AwaitingCompletion,
     Completed(QueryResult),
@@ -88,6 +126,9 @@ pub struct RunningQuery {
     /// We could return the result via the JoinHandle, except that we want to check the status
     /// of the task, and shuttle doesn't implement `JoinHandle::is_finished`.
     pub join_handle: JoinHandle<()>,
+
+    /// Counts records sent by this helper while the query is running.
+    pub progress: Arc<ProgressTracker>,
 }
 
 impl RunningQuery {
@@ -5,21 +5,22 @@ use std::{
     ops::{Add, Deref},
 };
 
-use bytes::{BufMut, Bytes};
+use bytes::{BufMut, Bytes, BytesMut};
 use generic_array::{ArrayLength, GenericArray};
 use hpke::Serializable as _;
 use rand_core::{CryptoRng, RngCore};
-use typenum::{Unsigned, U1, U18, U8};
+use typenum::{Unsigned, U1, U20, U63, U8};
 
 use crate::{
     ff::{
-        boolean::Boolean, boolean_array::BA64, GaloisField, Gf40Bit, Gf8Bit, PrimeField,
-        Serializable,
+        boolean::Boolean, boolean_array::BA64, Error as FieldError, GaloisField, Gf40Bit, Gf8Bit,
+        PrimeField, Serializable,
     },
     hpke::{
         open_in_place, seal_in_place, CryptError, FieldShareCrypt, Info, KeyPair, KeyRegistry,
         PublicKeyRegistry,
     },
+    protocol::ipa_prf::prf_sharding::SourcePriority,
     secret_sharing::{replicated::semi_honest::AdditiveShare as Replicated, WeakSharedValue},
 };
 
@@ -72,6 +73,14 @@ impl Serializable for EventType {
             2_u8..=u8::MAX => panic!("Unreachable code"),
         }
     }
+
+    fn try_deserialize(buf: &GenericArray<u8, Self::Size>) -> Result<Self, FieldError> {
+        match buf[0] {
+            0 => Ok(EventType::Trigger),
+            1 => Ok(EventType::Source),
+            2_u8..=u8::MAX => Err(FieldError::invalid_encoding::<Self>(buf)),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -154,6 +163,34 @@ pub enum InvalidReportError {
     Timestamp(Timestamp),
     #[error("en/decryption failure: {0}")]
     Crypt(#[from] CryptError),
+    #[error("report is {0} bytes long, at least {1} bytes are required")]
+    TooShort(usize, usize),
+    #[error(
+        "input of {len} bytes does not consist of whole {report_len}-byte reports with {schema}"
+    )]
+    SchemaMismatch {
+        len: usize,
+        report_len: usize,
+        schema: ReportSchema,
+    },
+    #[error("bad trigger value: {0}")]
+    BadTriggerValue(#[from] FieldError),
+    #[error("report is from epoch {actual}, expected epoch {expected}")]
+    UnexpectedEpoch { expected: Epoch, actual: Epoch },
+    #[error("report is encrypted with key {actual}, expected key {expected}")]
+    UnexpectedKeyId {
+        expected: KeyIdentifier,
+        actual: KeyIdentifier,
+    },
+    #[error("report format version {0} is not supported")]
+    UnsupportedVersion(u8),
+    #[error("header of version {0} reports is {1} bytes long, expected {2} bytes")]
+    MalformedHeader(u8, usize, usize),
+    #[error("reports have {declared}, but the query expects {schema}")]
+    HeaderMismatch {
+        declared: ReportSchema,
+        schema: ReportSchema,
+    },
 }
 
 /// A binary report as submitted by a report collector, containing encrypted match key shares.
@@ -179,7 +216,7 @@ where
 //  * a..b: `encap_key`
 //  * b..c: `mk_ciphertext`
 //  * c: `event_type`
-//  * c+1: `key_id`
+//  * c+1: `key_id` (`EPOCH_KEY_ID` if the match key is encrypted to the key of `epoch`)
 //  * c+2..c+4: `epoch`
 //  * c+4..: `site_domain`
 impl<F, B> EncryptedReport<F, Gf40Bit, Gf8Bit, B>
@@ -252,6 +289,15 @@ where
     /// ## Errors
     /// If the report contents are invalid.
     pub fn from_bytes(bytes: B) -> Result<Self, InvalidReportError> {
+        if bytes.len() < Self::SITE_DOMAIN_OFFSET {
+            return Err(InvalidReportError::TooShort(
+                bytes.len(),
+                Self::SITE_DOMAIN_OFFSET,
+            ));
+        }
+        Replicated::<F>::try_deserialize(GenericArray::from_slice(
+            &bytes[5..Self::ENCAP_KEY_OFFSET],
+        ))?;
         EventType::try_from(bytes[Self::EVENT_TYPE_OFFSET])?;
         let site_domain = &bytes[Self::SITE_DOMAIN_OFFSET..];
         if !site_domain.is_ascii() {
@@ -425,6 +471,30 @@ where
     pub breakdown_key: Replicated<BK>,
     pub trigger_value: Replicated<TV>,
     pub timestamp: Replicated<TS>,
+    /// Source events with the same timestamp are ordered by priority, the highest one wins
+    /// attribution. Zero for reports that carry no priority.
+    pub priority: Replicated<SourcePriority>,
+}
+
+impl<BK, TV, TS> OprfReport<BK, TV, TS>
+where
+    BK: WeakSharedValue,
+    TV: WeakSharedValue,
+    TS: WeakSharedValue,
+{
+    /// Returns this report with its timestamp replaced by a zero share of `NTS`, for queries that
+    /// never look at timestamps.
+    #[must_use]
+    pub fn without_timestamp<NTS: WeakSharedValue>(self) -> OprfReport<BK, TV, NTS> {
+        OprfReport {
+            match_key: self.match_key,
+            is_trigger: self.is_trigger,
+            breakdown_key: self.breakdown_key,
+            trigger_value: self.trigger_value,
+            timestamp: Replicated::ZERO,
+            priority: self.priority,
+        }
+    }
 }
 
 impl Serializable for u64 {
@@ -448,29 +518,30 @@ where
     Replicated<BK>: Serializable,
     Replicated<TV>: Serializable,
     Replicated<TS>: Serializable,
-    <Replicated<BK> as Serializable>::Size: Add<U18>,
+    <Replicated<BK> as Serializable>::Size: Add<U20>,
     <Replicated<TS> as Serializable>::Size:
-        Add<<<Replicated<BK> as Serializable>::Size as Add<U18>>::Output>,
+        Add<<<Replicated<BK> as Serializable>::Size as Add<U20>>::Output>,
     <Replicated<TV> as Serializable>::Size: Add<
         <<Replicated<TS> as Serializable>::Size as Add<
-            <<Replicated<BK> as Serializable>::Size as Add<U18>>::Output,
+            <<Replicated<BK> as Serializable>::Size as Add<U20>>::Output,
         >>::Output,
     >,
     <<Replicated<TV> as Serializable>::Size as Add<
         <<Replicated<TS> as Serializable>::Size as Add<
-            <<Replicated<BK> as Serializable>::Size as Add<U18>>::Output,
+            <<Replicated<BK> as Serializable>::Size as Add<U20>>::Output,
         >>::Output,
     >>::Output: ArrayLength,
 {
     type Size = <<Replicated<TV> as Serializable>::Size as Add<
         <<Replicated<TS> as Serializable>::Size as Add<
-            <<Replicated<BK> as Serializable>::Size as Add<U18>>::Output,
+            <<Replicated<BK> as Serializable>::Size as Add<U20>>::Output,
         >>::Output,
     >>::Output;
 
     fn serialize(&self, buf: &mut GenericArray<u8, Self::Size>) {
         let sizeof_matchkey = size_of::<u64>() * 2;
         let sizeof_eventtype = size_of::<Boolean>() * 2;
+        let sizeof_priority = <Replicated<SourcePriority> as Serializable>::Size::USIZE;
         let ts_sz = <Replicated<TS> as Serializable>::Size::USIZE;
         let bk_sz = <Replicated<BK> as Serializable>::Size::USIZE;
         let tv_sz = <Replicated<TV> as Serializable>::Size::USIZE;
@@ -494,38 +565,905 @@ where
             &mut buf[sizeof_matchkey + ts_sz + bk_sz + tv_sz
                 ..sizeof_matchkey + ts_sz + bk_sz + tv_sz + sizeof_eventtype],
         ));
+
+        self.priority.serialize(GenericArray::from_mut_slice(
+            &mut buf[sizeof_matchkey + ts_sz + bk_sz + tv_sz + sizeof_eventtype
+                ..sizeof_matchkey + ts_sz + bk_sz + tv_sz + sizeof_eventtype + sizeof_priority],
+        ));
     }
 
+    /// ## Panics
+    /// If `buf` does not hold a valid report.
     fn deserialize(buf: &GenericArray<u8, Self::Size>) -> Self {
+        Self::try_deserialize(buf).unwrap()
+    }
+
+    fn try_deserialize(buf: &GenericArray<u8, Self::Size>) -> Result<Self, FieldError> {
         let sizeof_matchkey = size_of::<u64>() * 2;
         let sizeof_eventtype = size_of::<Boolean>() * 2;
+        let sizeof_priority = <Replicated<SourcePriority> as Serializable>::Size::USIZE;
 
         let ts_sz = <Replicated<TS> as Serializable>::Size::USIZE;
         let bk_sz = <Replicated<BK> as Serializable>::Size::USIZE;
         let tv_sz = <Replicated<TV> as Serializable>::Size::USIZE;
 
         let match_key =
-            Replicated::<BA64>::deserialize(GenericArray::from_slice(&buf[..sizeof_matchkey]));
-        let timestamp = Replicated::<TS>::deserialize(GenericArray::from_slice(
+            Replicated::<BA64>::try_deserialize(GenericArray::from_slice(&buf[..sizeof_matchkey]))?;
+        let timestamp = Replicated::<TS>::try_deserialize(GenericArray::from_slice(
             &buf[sizeof_matchkey..sizeof_matchkey + ts_sz],
-        ));
-        let breakdown_key = Replicated::<BK>::deserialize(GenericArray::from_slice(
+        ))?;
+        let breakdown_key = Replicated::<BK>::try_deserialize(GenericArray::from_slice(
             &buf[sizeof_matchkey + ts_sz..sizeof_matchkey + ts_sz + bk_sz],
-        ));
-        let trigger_value = Replicated::<TV>::deserialize(GenericArray::from_slice(
+        ))?;
+        let trigger_value = Replicated::<TV>::try_deserialize(GenericArray::from_slice(
             &buf[sizeof_matchkey + ts_sz + bk_sz..sizeof_matchkey + ts_sz + bk_sz + tv_sz],
-        ));
-        let is_trigger = Replicated::<Boolean>::deserialize(GenericArray::from_slice(
+        ))?;
+        let is_trigger = Replicated::<Boolean>::try_deserialize(GenericArray::from_slice(
             &buf[sizeof_matchkey + ts_sz + bk_sz + tv_sz
                 ..sizeof_matchkey + ts_sz + bk_sz + tv_sz + sizeof_eventtype],
-        ));
-        Self {
+        ))?;
+        let priority = Replicated::<SourcePriority>::try_deserialize(GenericArray::from_slice(
+            &buf[sizeof_matchkey + ts_sz + bk_sz + tv_sz + sizeof_eventtype
+                ..sizeof_matchkey + ts_sz + bk_sz + tv_sz + sizeof_eventtype + sizeof_priority],
+        ))?;
+        Ok(Self {
             match_key,
             is_trigger,
             breakdown_key,
             trigger_value,
             timestamp,
+            priority,
+        })
+    }
+}
+
+/// Layout of OPRF IPA reports a query declares: the fields reports carry and how wide they are.
+/// Helpers read reports according to the layout the query declares rather than guessing it, so
+/// report collectors can submit reports of any supported layout, see
+/// [`IpaQueryConfig::report_schema`].
+///
+/// Fields of a report come in the same order in every layout, fields that a layout does not have
+/// are left out. Reports without timestamps or priorities are read as [`OprfReport`]s with zero
+/// timestamp or priority shares.
+///
+/// [`IpaQueryConfig::report_schema`]: crate::helpers::query::IpaQueryConfig::report_schema
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ReportSchema {
+    pub breakdown_key_bits: u32,
+    pub trigger_value_bits: u32,
+    /// `None` if reports carry no timestamp.
+    pub timestamp_bits: Option<u32>,
+    /// `None` if reports carry no source event priority.
+    pub priority_bits: Option<u32>,
+}
+
+impl ReportSchema {
+    /// Timestamps follow match key shares in reports with plaintext match keys.
+    const PLAINTEXT_TIMESTAMP_OFFSET: usize = 16;
+    /// Timestamps follow the encapsulated key and the match key ciphertext in encrypted reports,
+    /// see [`EncryptedOprfReport`].
+    const ENCRYPTED_TIMESTAMP_OFFSET: usize = 64;
+
+    /// Bytes taken by replicated shares of a `bits` wide value.
+    fn share_len(bits: u32) -> usize {
+        2 * usize::try_from(bits.div_ceil(8)).unwrap()
+    }
+
+    /// Bytes taken by the timestamp, breakdown key, trigger value, event type and priority shares.
+    /// The priority comes last.
+    fn fields_len(&self) -> usize {
+        self.timestamp_bits.map_or(0, Self::share_len)
+            + Self::share_len(self.breakdown_key_bits)
+            + Self::share_len(self.trigger_value_bits)
+            + Self::share_len(1)
+            + self.priority_bits.map_or(0, Self::share_len)
+    }
+
+    /// Length of reports with plaintext match keys.
+    #[must_use]
+    pub fn plaintext_len(&self) -> usize {
+        Self::PLAINTEXT_TIMESTAMP_OFFSET + self.fields_len()
+    }
+
+    /// Length of encrypted reports, not counting the site domain at the end.
+    #[must_use]
+    pub fn encrypted_len(&self) -> usize {
+        // key id and epoch
+        Self::ENCRYPTED_TIMESTAMP_OFFSET + self.fields_len() + 3
+    }
+
+    /// Checks that `input` consists of whole reports with plaintext match keys and returns them
+    /// laid out as [`OprfReport<BK, TV, TS>`] expects.
+    ///
+    /// ## Errors
+    /// If `input` does not split into reports of this layout.
+    ///
+    /// ## Panics
+    /// If this layout has timestamps that are not as wide as `TS`, or priorities that are not as
+    /// wide as [`SourcePriority`].
+    pub fn read_plaintext<TS>(&self, input: Vec<u8>) -> Result<Vec<u8>, InvalidReportError>
+    where
+        TS: WeakSharedValue,
+        Replicated<TS>: Serializable,
+    {
+        let report_len = self.plaintext_len();
+        if input.len() % report_len != 0 {
+            return Err(InvalidReportError::SchemaMismatch {
+                len: input.len(),
+                report_len,
+                schema: *self,
+            });
+        }
+        let (ts_len, priority_len) = match (
+            self.missing_timestamp_len::<TS>(),
+            self.missing_priority_len(),
+        ) {
+            (None, None) => return Ok(input),
+            (ts_len, priority_len) => (ts_len.unwrap_or(0), priority_len.unwrap_or(0)),
+        };
+
+        let full_len = report_len + ts_len + priority_len;
+        let mut reports = Vec::with_capacity(input.len() / report_len * full_len);
+        for report in input.chunks_exact(report_len) {
+            let (match_key, fields) = report.split_at(Self::PLAINTEXT_TIMESTAMP_OFFSET);
+            reports.extend_from_slice(match_key);
+            reports.resize(reports.len() + ts_len, 0);
+            reports.extend_from_slice(fields);
+            reports.resize(reports.len() + priority_len, 0);
+        }
+        Ok(reports)
+    }
+
+    /// Length of pseudonymized reports: reports with plaintext match keys that carry an
+    /// [`EncryptedPseudonym`] instead of the match key shares.
+    #[must_use]
+    pub fn pseudonymized_len(&self) -> usize {
+        <EncryptedPseudonym as Serializable>::Size::USIZE + self.fields_len()
+    }
+
+    /// Checks that `input` consists of whole pseudonymized reports and returns their pseudonyms
+    /// along with the reports laid out as [`OprfReport<BK, TV, TS>`] expects, with zero match key
+    /// shares.
+    ///
+    /// ## Errors
+    /// If `input` does not split into pseudonymized reports of this layout.
+    ///
+    /// ## Panics
+    /// If this layout has timestamps that are not as wide as `TS`, or priorities that are not as
+    /// wide as [`SourcePriority`].
+    pub fn read_pseudonymized<TS>(
+        &self,
+        input: Vec<u8>,
+    ) -> Result<(Vec<EncryptedPseudonym>, Vec<u8>), InvalidReportError>
+    where
+        TS: WeakSharedValue,
+        Replicated<TS>: Serializable,
+    {
+        let report_len = self.pseudonymized_len();
+        if input.len() % report_len != 0 {
+            return Err(InvalidReportError::SchemaMismatch {
+                len: input.len(),
+                report_len,
+                schema: *self,
+            });
+        }
+
+        let count = input.len() / report_len;
+        let mut pseudonyms = Vec::with_capacity(count);
+        let mut reports = Vec::with_capacity(count * self.plaintext_len());
+        for report in input.chunks_exact(report_len) {
+            let (pseudonym, fields) =
+                report.split_at(<EncryptedPseudonym as Serializable>::Size::USIZE);
+            pseudonyms.push(EncryptedPseudonym::deserialize(GenericArray::from_slice(
+                pseudonym,
+            )));
+            reports.resize(reports.len() + Self::PLAINTEXT_TIMESTAMP_OFFSET, 0);
+            reports.extend_from_slice(fields);
+        }
+        Ok((pseudonyms, self.read_plaintext::<TS>(reports)?))
+    }
+
+    /// Checks that `report` is an encrypted report of this layout and returns it laid out as
+    /// [`EncryptedOprfReport<BK, TV, TS, _>`] expects.
+    ///
+    /// ## Errors
+    /// If `report` is too short to hold all fields of this layout.
+    ///
+    /// ## Panics
+    /// If this layout has timestamps that are not as wide as `TS`, or priorities that are not as
+    /// wide as [`SourcePriority`].
+    pub fn read_encrypted<TS>(&self, report: Bytes) -> Result<Bytes, InvalidReportError>
+    where
+        TS: WeakSharedValue,
+        Replicated<TS>: Serializable,
+    {
+        if report.len() < self.encrypted_len() {
+            return Err(InvalidReportError::TooShort(
+                report.len(),
+                self.encrypted_len(),
+            ));
+        }
+        let (ts_len, priority_len) = match (
+            self.missing_timestamp_len::<TS>(),
+            self.missing_priority_len(),
+        ) {
+            (None, None) => return Ok(report),
+            (ts_len, priority_len) => (ts_len.unwrap_or(0), priority_len.unwrap_or(0)),
+        };
+
+        let fields_end = Self::ENCRYPTED_TIMESTAMP_OFFSET + self.fields_len();
+        let mut full = BytesMut::with_capacity(report.len() + ts_len + priority_len);
+        full.put_slice(&report[..Self::ENCRYPTED_TIMESTAMP_OFFSET]);
+        full.put_bytes(0, ts_len);
+        full.put_slice(&report[Self::ENCRYPTED_TIMESTAMP_OFFSET..fields_end]);
+        full.put_bytes(0, priority_len);
+        full.put_slice(&report[fields_end..]);
+        Ok(full.freeze())
+    }
+
+    /// Length of the zero timestamp shares that reports of this layout need to be read with `TS`
+    /// timestamps, `None` if they carry timestamps already.
+    fn missing_timestamp_len<TS>(&self) -> Option<usize>
+    where
+        TS: WeakSharedValue,
+        Replicated<TS>: Serializable,
+    {
+        let ts_len = <Replicated<TS> as Serializable>::Size::USIZE;
+        match self.timestamp_bits {
+            Some(bits) => {
+                assert_eq!(
+                    Self::share_len(bits),
+                    ts_len,
+                    "{bits}-bit timestamps can't be read as {}-bit ones",
+                    TS::BITS
+                );
+                None
+            }
+            None => Some(ts_len),
+        }
+    }
+
+    /// Length of the zero priority shares that reports of this layout need to be read with
+    /// priorities, `None` if they carry priorities already.
+    fn missing_priority_len(&self) -> Option<usize> {
+        let priority_len = <Replicated<SourcePriority> as Serializable>::Size::USIZE;
+        match self.priority_bits {
+            Some(bits) => {
+                assert_eq!(
+                    Self::share_len(bits),
+                    priority_len,
+                    "{bits}-bit priorities can't be read as {}-bit ones",
+                    SourcePriority::BITS
+                );
+                None
+            }
+            None => Some(priority_len),
+        }
+    }
+}
+
+/// Versions of the wire format of encrypted OPRF reports.
+///
+/// Report collectors that know about versions open every batch of encrypted reports with a header,
+/// see [`ReportSchema::delimited_header_to`], which names the version of the reports that follow
+/// and their layout. Batches without a header are read as [`ReportVersion::Unversioned`], so
+/// batches that were encrypted before report collectors started sending headers can still be
+/// submitted after helpers learned to read them, and helpers can be upgraded before report
+/// collectors are. Later versions may change how reports are laid out; helpers keep reading every
+/// version listed here.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ReportVersion {
+    /// Reports of batches without a header.
+    Unversioned,
+    /// Reports laid out as unversioned ones, in batches with a header.
+    V1,
+    /// Reports that carry a source event priority after their event type if the header of their
+    /// batch declares one.
+    V2,
+}
+
+impl ReportVersion {
+    /// The version report collectors write.
+    pub const CURRENT: Self = Self::V2;
+
+    fn from_byte(version: u8) -> Result<Self, InvalidReportError> {
+        match version {
+            1 => Ok(Self::V1),
+            2 => Ok(Self::V2),
+            _ => Err(InvalidReportError::UnsupportedVersion(version)),
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            Self::Unversioned => 0,
+            Self::V1 => 1,
+            Self::V2 => 2,
+        }
+    }
+
+    /// Length of the header that opens batches of this version.
+    const fn header_len(self) -> usize {
+        match self {
+            Self::Unversioned => 0,
+            Self::V1 => 8,
+            Self::V2 => 9,
+        }
+    }
+}
+
+// Header structure:
+//  * 0..4: `HEADER_MAGIC`
+//  * 4: version
+//  * 5: `breakdown_key_bits`
+//  * 6: `trigger_value_bits`
+//  * 7: `timestamp_bits`, zero if reports carry no timestamp
+//  * 8: `priority_bits`, zero if reports carry no priority, from `ReportVersion::V2` on
+//
+// Headers are shorter than any encrypted report, so they are never mistaken for one.
+impl ReportSchema {
+    const HEADER_MAGIC: &'static [u8; 4] = b"IPAR";
+    const HEADER_LEN: usize = ReportVersion::CURRENT.header_len();
+
+    /// Writes the header that opens a batch of encrypted reports of this layout, preceded by its
+    /// length like the reports of the batch are.
+    ///
+    /// ## Panics
+    /// If any field of this layout is 256 bits or wider.
+    pub fn delimited_header_to<B: BufMut>(&self, out: &mut B) {
+        let bits = |bits: u32| u8::try_from(bits).unwrap();
+        out.put_u16_le(u16::try_from(Self::HEADER_LEN).unwrap());
+        out.put_slice(Self::HEADER_MAGIC);
+        out.put_slice(&[
+            ReportVersion::CURRENT.to_byte(),
+            bits(self.breakdown_key_bits),
+            bits(self.trigger_value_bits),
+            self.timestamp_bits.map_or(0, bits),
+            self.priority_bits.map_or(0, bits),
+        ]);
+    }
+
+    /// Reads the version of a batch of encrypted reports from its header, if it has one, and
+    /// returns the reports that follow the header.
+    ///
+    /// ## Errors
+    /// If the batch has a header, but its version is not supported, it is malformed or it declares
+    /// a different layout than this one.
+    pub fn read_version<'a, B>(
+        &self,
+        batch: &'a [B],
+    ) -> Result<(ReportVersion, &'a [B]), InvalidReportError>
+    where
+        B: Deref<Target = [u8]>,
+    {
+        let header = match batch.first() {
+            Some(first)
+                if (Self::HEADER_MAGIC.len() + 1..=Self::HEADER_LEN).contains(&first.len())
+                    && first.starts_with(Self::HEADER_MAGIC) =>
+            {
+                &first[Self::HEADER_MAGIC.len()..]
+            }
+            _ => return Ok((ReportVersion::Unversioned, batch)),
+        };
+
+        let version = ReportVersion::from_byte(header[0])?;
+        let header_len = Self::HEADER_MAGIC.len() + header.len();
+        if header_len != version.header_len() {
+            return Err(InvalidReportError::MalformedHeader(
+                header[0],
+                header_len,
+                version.header_len(),
+            ));
+        }
+        let bits = |i: usize| {
+            header
+                .get(i)
+                .map(|&bits| u32::from(bits))
+                .filter(|&bits| bits != 0)
+        };
+        let declared = Self {
+            breakdown_key_bits: header[1].into(),
+            trigger_value_bits: header[2].into(),
+            timestamp_bits: bits(3),
+            priority_bits: bits(4),
+        };
+        if declared != *self {
+            return Err(InvalidReportError::HeaderMismatch {
+                declared,
+                schema: *self,
+            });
+        }
+        Ok((version, &batch[1..]))
+    }
+}
+
+impl Display for ReportSchema {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}-bit breakdown key, {}-bit trigger value, ",
+            self.breakdown_key_bits, self.trigger_value_bits
+        )?;
+        match self.timestamp_bits {
+            Some(bits) => write!(f, "{bits}-bit timestamp"),
+            None => write!(f, "no timestamp"),
+        }?;
+        match self.priority_bits {
+            Some(bits) => write!(f, ", {bits}-bit priority"),
+            None => write!(f, ", no priority"),
+        }
+    }
+}
+
+// The event type of an OPRF report is secret-shared along with the other fields, so it cannot be
+// bound to the match key ciphertext. All OPRF reports are sealed as if they were source events.
+const OPRF_INFO_EVENT_TYPE: EventType = EventType::Source;
+
+/// A binary OPRF report as submitted by a report collector, containing encrypted match key shares.
+///
+/// Unlike [`EncryptedReport`], every field except the match key is secret-shared rather than
+/// in the clear, so only the match key is encrypted.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct EncryptedOprfReport<BK, TV, TS, B>
+where
+    B: Deref<Target = [u8]>,
+    BK: WeakSharedValue,
+    TV: WeakSharedValue,
+    TS: WeakSharedValue,
+{
+    data: B,
+    phantom_data: PhantomData<(BK, TV, TS)>,
+}
+
+// Report structure:
+//  * 0..32: `encap_key`
+//  * 32..64: `mk_ciphertext`
+//  * 64..a: `timestamp`
+//  * a..b: `breakdown_key`
+//  * b..c: `trigger_value`
+//  * c..d: `is_trigger`
+//  * d..e: `priority`
+//  * e: `key_id` (`EPOCH_KEY_ID` if the match key is encrypted to the key of `epoch`)
+//  * e+1..e+3: `epoch`
+//  * e+3..: `site_domain`
+impl<BK, TV, TS, B> EncryptedOprfReport<BK, TV, TS, B>
+where
+    B: Deref<Target = [u8]>,
+    BK: WeakSharedValue,
+    TV: WeakSharedValue,
+    TS: WeakSharedValue,
+    Replicated<BK>: Serializable,
+    Replicated<TV>: Serializable,
+    Replicated<TS>: Serializable,
+{
+    const CIPHERTEXT_OFFSET: usize = 32;
+    const TIMESTAMP_OFFSET: usize =
+        Self::CIPHERTEXT_OFFSET + <Replicated<BA64> as Serializable>::Size::USIZE + 16;
+    const BREAKDOWN_KEY_OFFSET: usize =
+        Self::TIMESTAMP_OFFSET + <Replicated<TS> as Serializable>::Size::USIZE;
+    const TRIGGER_VALUE_OFFSET: usize =
+        Self::BREAKDOWN_KEY_OFFSET + <Replicated<BK> as Serializable>::Size::USIZE;
+    const IS_TRIGGER_OFFSET: usize =
+        Self::TRIGGER_VALUE_OFFSET + <Replicated<TV> as Serializable>::Size::USIZE;
+    const PRIORITY_OFFSET: usize =
+        Self::IS_TRIGGER_OFFSET + <Replicated<Boolean> as Serializable>::Size::USIZE;
+    const KEY_ID_OFFSET: usize =
+        Self::PRIORITY_OFFSET + <Replicated<SourcePriority> as Serializable>::Size::USIZE;
+    const SITE_DOMAIN_OFFSET: usize = Self::KEY_ID_OFFSET + 3;
+
+    pub fn encap_key(&self) -> &[u8] {
+        &self.data[..Self::CIPHERTEXT_OFFSET]
+    }
+
+    pub fn match_key_ciphertext(&self) -> &[u8] {
+        &self.data[Self::CIPHERTEXT_OFFSET..Self::TIMESTAMP_OFFSET]
+    }
+
+    pub fn key_id(&self) -> KeyIdentifier {
+        self.data[Self::KEY_ID_OFFSET]
+    }
+
+    /// ## Panics
+    /// Never.
+    pub fn epoch(&self) -> Epoch {
+        u16::from_le_bytes(
+            self.data[Self::KEY_ID_OFFSET + 1..Self::SITE_DOMAIN_OFFSET]
+                .try_into()
+                .unwrap(), // infallible slice-to-array conversion
+        )
+    }
+
+    /// ## Errors
+    /// If the site domain is not an ASCII string.
+    pub fn site_domain(&self) -> Result<&str, NonAsciiStringError> {
+        let site_domain = &self.data[Self::SITE_DOMAIN_OFFSET..];
+        match std::str::from_utf8(site_domain) {
+            Ok(s) if s.is_ascii() => Ok(s),
+            _ => Err(NonAsciiStringError::from(site_domain)),
+        }
+    }
+
+    /// Checks the data that is authenticated along with the match key against the values the
+    /// query expects, if any. Decryption fails if this data has been tampered with, so reports
+    /// that pass this check and decrypt successfully were encrypted for the expected epoch and
+    /// key.
+    ///
+    /// ## Errors
+    /// If the report was encrypted for a different epoch or with a different key.
+    pub fn check_associated_data(
+        &self,
+        epoch: Option<Epoch>,
+        key_id: Option<KeyIdentifier>,
+    ) -> Result<(), InvalidReportError> {
+        match (epoch, key_id) {
+            (Some(expected), _) if expected != self.epoch() => {
+                Err(InvalidReportError::UnexpectedEpoch {
+                    expected,
+                    actual: self.epoch(),
+                })
+            }
+            (_, Some(expected)) if expected != self.key_id() => {
+                Err(InvalidReportError::UnexpectedKeyId {
+                    expected,
+                    actual: self.key_id(),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// ## Errors
+    /// If the report is too short to hold all fields. The contents of the fields are validated
+    /// by [`decrypt`], so that a single malformed report does not invalidate the whole input.
+    ///
+    /// [`decrypt`]: Self::decrypt
+    pub fn from_bytes(bytes: B) -> Result<Self, InvalidReportError> {
+        if bytes.len() < Self::SITE_DOMAIN_OFFSET {
+            return Err(InvalidReportError::TooShort(
+                bytes.len(),
+                Self::SITE_DOMAIN_OFFSET,
+            ));
+        }
+        Ok(Self {
+            data: bytes,
+            phantom_data: PhantomData,
+        })
+    }
+
+    /// Tells whether any of the breakdown key shares in the report has bits set above `BK::BITS`,
+    /// which happens if the report collector encodes wider breakdown keys than the query expects.
+    /// [`decrypt`] rejects such reports.
+    ///
+    /// [`decrypt`]: Self::decrypt
+    pub fn has_wide_breakdown_key(&self) -> bool {
+        Replicated::<BK>::try_deserialize(GenericArray::from_slice(
+            &self.data[Self::BREAKDOWN_KEY_OFFSET..Self::TRIGGER_VALUE_OFFSET],
+        ))
+        .is_err()
+    }
+
+    /// ## Errors
+    /// If the match key shares in the report cannot be decrypted (e.g. due to a
+    /// failure of the authenticated encryption), or if any of the shares is malformed.
+    pub fn decrypt(
+        &self,
+        key_registry: &KeyRegistry<KeyPair>,
+    ) -> Result<OprfReport<BK, TV, TS>, InvalidReportError> {
+        self.decrypt_with(key_registry, None)
+    }
+
+    /// Same as [`decrypt`], but uses `breakdown_key` in place of the breakdown key shares in the
+    /// report, which are not checked.
+    ///
+    /// ## Errors
+    /// If the match key shares in the report cannot be decrypted, or if any of the other shares is
+    /// malformed.
+    ///
+    /// [`decrypt`]: Self::decrypt
+    pub fn decrypt_with_breakdown_key(
+        &self,
+        key_registry: &KeyRegistry<KeyPair>,
+        breakdown_key: Replicated<BK>,
+    ) -> Result<OprfReport<BK, TV, TS>, InvalidReportError> {
+        self.decrypt_with(key_registry, Some(breakdown_key))
+    }
+
+    fn decrypt_with(
+        &self,
+        key_registry: &KeyRegistry<KeyPair>,
+        breakdown_key: Option<Replicated<BK>>,
+    ) -> Result<OprfReport<BK, TV, TS>, InvalidReportError> {
+        let info = Info::new(
+            self.key_id(),
+            self.epoch(),
+            OPRF_INFO_EVENT_TYPE,
+            HELPER_ORIGIN,
+            self.site_domain()?,
+        )?;
+
+        let mut ciphertext = self.match_key_ciphertext().to_vec();
+        let plaintext = open_in_place(key_registry, self.encap_key(), &mut ciphertext, &info)?;
+
+        Ok(OprfReport {
+            match_key: Replicated::<BA64>::try_deserialize(GenericArray::from_slice(plaintext))?,
+            timestamp: Replicated::<TS>::try_deserialize(GenericArray::from_slice(
+                &self.data[Self::TIMESTAMP_OFFSET..Self::BREAKDOWN_KEY_OFFSET],
+            ))?,
+            breakdown_key: match breakdown_key {
+                Some(breakdown_key) => breakdown_key,
+                None => Replicated::<BK>::try_deserialize(GenericArray::from_slice(
+                    &self.data[Self::BREAKDOWN_KEY_OFFSET..Self::TRIGGER_VALUE_OFFSET],
+                ))?,
+            },
+            trigger_value: Replicated::<TV>::try_deserialize(GenericArray::from_slice(
+                &self.data[Self::TRIGGER_VALUE_OFFSET..Self::IS_TRIGGER_OFFSET],
+            ))?,
+            is_trigger: Replicated::<Boolean>::try_deserialize(GenericArray::from_slice(
+                &self.data[Self::IS_TRIGGER_OFFSET..Self::PRIORITY_OFFSET],
+            ))?,
+            priority: Replicated::<SourcePriority>::try_deserialize(GenericArray::from_slice(
+                &self.data[Self::PRIORITY_OFFSET..Self::KEY_ID_OFFSET],
+            ))?,
+        })
+    }
+}
+
+impl<BK, TV, TS> TryFrom<Bytes> for EncryptedOprfReport<BK, TV, TS, Bytes>
+where
+    BK: WeakSharedValue,
+    TV: WeakSharedValue,
+    TS: WeakSharedValue,
+    Replicated<BK>: Serializable,
+    Replicated<TV>: Serializable,
+    Replicated<TS>: Serializable,
+{
+    type Error = InvalidReportError;
+
+    fn try_from(bytes: Bytes) -> Result<Self, InvalidReportError> {
+        EncryptedOprfReport::from_bytes(bytes)
+    }
+}
+
+impl<BK, TV, TS> OprfReport<BK, TV, TS>
+where
+    BK: WeakSharedValue,
+    TV: WeakSharedValue,
+    TS: WeakSharedValue,
+    Replicated<BK>: Serializable,
+    Replicated<TV>: Serializable,
+    Replicated<TS>: Serializable,
+{
+    /// # Panics
+    /// If report length does not fit in u16.
+    pub fn encrypted_len(site_domain: &str) -> u16 {
+        let len = EncryptedOprfReport::<BK, TV, TS, &[u8]>::SITE_DOMAIN_OFFSET + site_domain.len();
+        len.try_into().unwrap()
+    }
+
+    /// # Errors
+    /// If there is a problem encrypting the report.
+    pub fn delimited_encrypt_to<R: CryptoRng + RngCore, B: BufMut>(
+        &self,
+        key_id: KeyIdentifier,
+        epoch: Epoch,
+        site_domain: &str,
+        key_registry: &impl PublicKeyRegistry,
+        rng: &mut R,
+        out: &mut B,
+    ) -> Result<(), InvalidReportError> {
+        out.put_u16_le(Self::encrypted_len(site_domain));
+        self.encrypt_to(key_id, epoch, site_domain, key_registry, rng, out)
+    }
+
+    /// # Errors
+    /// If there is a problem encrypting the report.
+    pub fn encrypt_to<R: CryptoRng + RngCore, B: BufMut>(
+        &self,
+        key_id: KeyIdentifier,
+        epoch: Epoch,
+        site_domain: &str,
+        key_registry: &impl PublicKeyRegistry,
+        rng: &mut R,
+        out: &mut B,
+    ) -> Result<(), InvalidReportError> {
+        let info = Info::new(
+            key_id,
+            epoch,
+            OPRF_INFO_EVENT_TYPE,
+            HELPER_ORIGIN,
+            site_domain,
+        )?;
+
+        let mut plaintext = GenericArray::default();
+        self.match_key.serialize(&mut plaintext);
+
+        let (encap_key, ciphertext, tag) =
+            seal_in_place(key_registry, plaintext.as_mut(), &info, rng)?;
+
+        out.put_slice(&encap_key.to_bytes());
+        out.put_slice(ciphertext);
+        out.put_slice(&tag.to_bytes());
+
+        let mut timestamp = GenericArray::default();
+        self.timestamp.serialize(&mut timestamp);
+        out.put_slice(timestamp.as_slice());
+
+        let mut breakdown_key = GenericArray::default();
+        self.breakdown_key.serialize(&mut breakdown_key);
+        out.put_slice(breakdown_key.as_slice());
+
+        let mut trigger_value = GenericArray::default();
+        self.trigger_value.serialize(&mut trigger_value);
+        out.put_slice(trigger_value.as_slice());
+
+        let mut is_trigger = GenericArray::default();
+        self.is_trigger.serialize(&mut is_trigger);
+        out.put_slice(is_trigger.as_slice());
+
+        let mut priority = GenericArray::default();
+        self.priority.serialize(&mut priority);
+        out.put_slice(priority.as_slice());
+
+        out.put_slice(&[key_id]);
+        out.put_slice(&epoch.to_le_bytes());
+        out.put_slice(site_domain.as_bytes());
+
+        Ok(())
+    }
+}
+
+// Pseudonyms are encrypted with a different `helper_origin` than match keys, so a pseudonym
+// ciphertext can never be opened as a match key and vice versa.
+static PSEUDONYM_ORIGIN: &str = "github.com/private-attribution/pseudonym";
+
+#[derive(Debug, thiserror::Error)]
+pub enum InvalidPseudonymError {
+    #[error(
+        "pseudonym is valid in epochs [{first}, {last}], but was presented in epoch {current}"
+    )]
+    Expired {
+        first: Epoch,
+        last: Epoch,
+        current: Epoch,
+    },
+    #[error("pseudonym expiry does not match its encrypted payload")]
+    ExpiryMismatch,
+    #[error("en/decryption failure: {0}")]
+    Crypt(#[from] CryptError),
+}
+
+/// Pseudonymous user id exported by the PRF-only query.
+///
+/// All helpers learn the PRF of every match key, but the report collector must not. Instead,
+/// each helper encrypts the PRF value to its own public key. The report collector can hand the
+/// ciphertext back to the same helper in a later query, which accepts it only within the range of
+/// epochs the pseudonym was issued for. The last valid epoch is kept in the clear, so report
+/// collectors can drop stale pseudonyms, and inside the ciphertext, so it cannot be extended.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EncryptedPseudonym {
+    data: GenericArray<u8, U63>,
+}
+
+// Pseudonym structure:
+//  * 0: `key_id`
+//  * 1..3: `epoch` (first epoch the pseudonym is valid in)
+//  * 3..5: `last_epoch` (last epoch the pseudonym is valid in)
+//  * 5..37: `encap_key`
+//  * 37..47: encrypted `pseudonym` (8 bytes) and `last_epoch` (2 bytes)
+//  * 47..63: AEAD tag
+impl EncryptedPseudonym {
+    const ENCAP_KEY_OFFSET: usize = 5;
+    const CIPHERTEXT_OFFSET: usize = Self::ENCAP_KEY_OFFSET + 32;
+
+    /// Encrypts `pseudonym` to the helper that owns `key_registry`. The pseudonym will be accepted
+    /// in epochs `[epoch, last_epoch]`.
+    ///
+    /// ## Errors
+    /// If there is a problem encrypting the pseudonym.
+    /// ## Panics
+    /// If `last_epoch` precedes `epoch`.
+    pub fn seal<R: CryptoRng + RngCore>(
+        pseudonym: u64,
+        epoch: Epoch,
+        last_epoch: Epoch,
+        key_id: KeyIdentifier,
+        key_registry: &impl PublicKeyRegistry,
+        rng: &mut R,
+    ) -> Result<Self, InvalidPseudonymError> {
+        assert!(
+            epoch <= last_epoch,
+            "pseudonym expires in epoch {last_epoch} before it becomes valid in epoch {epoch}"
+        );
+        let info = Info::new(key_id, epoch, EventType::Source, PSEUDONYM_ORIGIN, "").unwrap(); // ASCII constants
+
+        let mut plaintext = [0u8; 10];
+        plaintext[..8].copy_from_slice(&pseudonym.to_le_bytes());
+        plaintext[8..].copy_from_slice(&last_epoch.to_le_bytes());
+
+        let (encap_key, ciphertext, tag) = seal_in_place(key_registry, &mut plaintext, &info, rng)?;
+
+        let mut data = GenericArray::<u8, U63>::default();
+        data[0] = key_id;
+        data[1..3].copy_from_slice(&epoch.to_le_bytes());
+        data[3..5].copy_from_slice(&last_epoch.to_le_bytes());
+        data[Self::ENCAP_KEY_OFFSET..Self::CIPHERTEXT_OFFSET]
+            .copy_from_slice(&encap_key.to_bytes());
+        data[Self::CIPHERTEXT_OFFSET..Self::CIPHERTEXT_OFFSET + 10].copy_from_slice(ciphertext);
+        data[Self::CIPHERTEXT_OFFSET + 10..].copy_from_slice(&tag.to_bytes());
+
+        Ok(Self { data })
+    }
+
+    pub fn key_id(&self) -> KeyIdentifier {
+        self.data[0]
+    }
+
+    /// ## Panics
+    /// Never.
+    pub fn epoch(&self) -> Epoch {
+        u16::from_le_bytes(self.data[1..3].try_into().unwrap()) // infallible slice-to-array conversion
+    }
+
+    /// ## Panics
+    /// Never.
+    pub fn last_epoch(&self) -> Epoch {
+        u16::from_le_bytes(self.data[3..5].try_into().unwrap()) // infallible slice-to-array conversion
+    }
+
+    /// Recovers the pseudonym, provided that it is valid in `current_epoch`.
+    ///
+    /// ## Errors
+    /// If the pseudonym has expired or is not yet valid, or if it cannot be decrypted with
+    /// `key_registry` (e.g. because it was issued by a different helper or was tampered with).
+    /// ## Panics
+    /// Never.
+    pub fn open(
+        &self,
+        key_registry: &KeyRegistry<KeyPair>,
+        current_epoch: Epoch,
+    ) -> Result<u64, InvalidPseudonymError> {
+        let (first, last) = (self.epoch(), self.last_epoch());
+        if !(first..=last).contains(&current_epoch) {
+            return Err(InvalidPseudonymError::Expired {
+                first,
+                last,
+                current: current_epoch,
+            });
         }
+
+        let info = Info::new(
+            self.key_id(),
+            first,
+            EventType::Source,
+            PSEUDONYM_ORIGIN,
+            "",
+        )
+        .unwrap(); // ASCII constants
+        let mut ciphertext = self.data[Self::CIPHERTEXT_OFFSET..].to_vec();
+        let plaintext = open_in_place(
+            key_registry,
+            &self.data[Self::ENCAP_KEY_OFFSET..Self::CIPHERTEXT_OFFSET],
+            &mut ciphertext,
+            &info,
+        )?;
+
+        if plaintext[8..10] != self.data[3..5] {
+            return Err(InvalidPseudonymError::ExpiryMismatch);
+        }
+
+        Ok(u64::from_le_bytes(plaintext[..8].try_into().unwrap())) // infallible slice-to-array conversion
+    }
+}
+
+impl Serializable for EncryptedPseudonym {
+    type Size = U63;
+
+    fn serialize(&self, buf: &mut GenericArray<u8, Self::Size>) {
+        buf.copy_from_slice(&self.data);
+    }
+
+    fn deserialize(buf: &GenericArray<u8, Self::Size>) -> Self {
+        Self { data: *buf }
     }
 }
 
@@ -535,7 +1473,13 @@ mod test {
     use rand_core::SeedableRng;
 
     use super::*;
-    use crate::ff::{Fp32BitPrime, Gf40Bit, Gf8Bit};
+    use crate::{
+        ff::{
+            boolean_array::{BA20, BA3, BA4, BA5, BA8},
+            Field, Fp32BitPrime, Gf40Bit, Gf8Bit,
+        },
+        hpke::{EpochKeySchedule, EPOCH_KEY_ID},
+    };
 
     #[test]
     fn enc_dec_roundtrip() {
@@ -633,4 +1577,479 @@ mod test {
             .unwrap();
         assert!(matches!(err, InvalidReportError::NonAsciiString(_)));
     }
+
+    #[test]
+    fn too_short() {
+        let bytes = hex::decode("3301e8d7528e0867").unwrap();
+
+        let err = EncryptedReport::<Fp32BitPrime, Gf40Bit, Gf8Bit, _>::from_bytes(bytes.as_slice())
+            .err()
+            .unwrap();
+        assert!(matches!(err, InvalidReportError::TooShort(8, _)));
+    }
+
+    #[test]
+    fn invalid_trigger_value() {
+        // trigger value share is 0xffffffff, which is larger than the prime
+        let bytes = hex::decode(
+            "\
+            3301e8d752ffffffff18d2164dc80a3403e4aadd01be4263b723ba2204638c20\
+            830500710b2bdb931f5f429f234abddf09109ecb2f730b368b7fa4fda0acf3db\
+            52c5d509681e8a0100783b6c64466e5531386d6c44\
+        ",
+        )
+        .unwrap();
+
+        let err = EncryptedReport::<Fp32BitPrime, Gf40Bit, Gf8Bit, _>::from_bytes(bytes.as_slice())
+            .err()
+            .unwrap();
+        assert!(matches!(err, InvalidReportError::BadTriggerValue(_)));
+    }
+
+    fn oprf_report(rng: &mut StdRng) -> OprfReport<BA8, BA3, BA20> {
+        OprfReport {
+            match_key: (rng.gen(), rng.gen()).into(),
+            is_trigger: (rng.gen(), rng.gen()).into(),
+            breakdown_key: (rng.gen(), rng.gen()).into(),
+            trigger_value: (rng.gen(), rng.gen()).into(),
+            timestamp: (rng.gen(), rng.gen()).into(),
+            priority: (rng.gen(), rng.gen()).into(),
+        }
+    }
+
+    #[test]
+    fn oprf_enc_dec_roundtrip() {
+        let mut rng = StdRng::from_seed([1_u8; 32]);
+        let report = oprf_report(&mut rng);
+        let key_registry = KeyRegistry::random(1, &mut rng);
+
+        let mut enc_report_bytes = Vec::new();
+        report
+            .encrypt_to(
+                0,
+                5,
+                "www.example.com",
+                &key_registry,
+                &mut rng,
+                &mut enc_report_bytes,
+            )
+            .unwrap();
+        assert_eq!(
+            usize::from(OprfReport::<BA8, BA3, BA20>::encrypted_len(
+                "www.example.com"
+            )),
+            enc_report_bytes.len()
+        );
+
+        let enc_report =
+            EncryptedOprfReport::<BA8, BA3, BA20, _>::from_bytes(enc_report_bytes.as_slice())
+                .unwrap();
+        assert_eq!(5, enc_report.epoch());
+        assert_eq!(report, enc_report.decrypt(&key_registry).unwrap());
+    }
+
+    #[test]
+    fn oprf_epoch_key_roundtrip() {
+        let mut rng = StdRng::from_seed([1_u8; 32]);
+        let report = oprf_report(&mut rng);
+        let schedule = EpochKeySchedule::new(4, rng.gen());
+        let key_registry = KeyRegistry::<KeyPair>::empty().with_epoch_keys(schedule.key_pairs(2));
+
+        let encrypt = |epoch, rng: &mut StdRng| {
+            let mut bytes = Vec::new();
+            report
+                .encrypt_to(
+                    EPOCH_KEY_ID,
+                    epoch,
+                    "www.example.com",
+                    &key_registry,
+                    rng,
+                    &mut bytes,
+                )
+                .map(|()| bytes)
+        };
+
+        let enc_report_bytes = encrypt(5, &mut rng).unwrap();
+        let enc_report =
+            EncryptedOprfReport::<BA8, BA3, BA20, _>::from_bytes(enc_report_bytes.as_slice())
+                .unwrap();
+        assert_eq!(EPOCH_KEY_ID, enc_report.key_id());
+        assert_eq!(report, enc_report.decrypt(&key_registry).unwrap());
+
+        assert!(matches!(
+            encrypt(6, &mut rng),
+            Err(InvalidReportError::Crypt(CryptError::NoSuchEpochKey(6)))
+        ));
+    }
+
+    #[test]
+    fn oprf_tampered() {
+        let mut rng = StdRng::from_seed([1_u8; 32]);
+        let report = oprf_report(&mut rng);
+        let key_registry = KeyRegistry::random(1, &mut rng);
+
+        let mut enc_report_bytes = Vec::new();
+        report
+            .encrypt_to(
+                0,
+                5,
+                "www.example.com",
+                &key_registry,
+                &mut rng,
+                &mut enc_report_bytes,
+            )
+            .unwrap();
+
+        // changing the epoch, the site domain or the ciphertext makes decryption fail
+        for offset in [40, enc_report_bytes.len() - 16, enc_report_bytes.len() - 1] {
+            let mut bytes = enc_report_bytes.clone();
+            bytes[offset] ^= 1;
+            let enc_report =
+                EncryptedOprfReport::<BA8, BA3, BA20, _>::from_bytes(bytes.as_slice()).unwrap();
+            assert!(matches!(
+                enc_report.decrypt(&key_registry),
+                Err(InvalidReportError::Crypt(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn oprf_associated_data() {
+        let mut rng = StdRng::from_seed([1_u8; 32]);
+        let report = oprf_report(&mut rng);
+        let key_registry = KeyRegistry::random(2, &mut rng);
+
+        let mut enc_report_bytes = Vec::new();
+        report
+            .encrypt_to(
+                1,
+                5,
+                "www.example.com",
+                &key_registry,
+                &mut rng,
+                &mut enc_report_bytes,
+            )
+            .unwrap();
+        let enc_report =
+            EncryptedOprfReport::<BA8, BA3, BA20, _>::from_bytes(enc_report_bytes.as_slice())
+                .unwrap();
+
+        assert_eq!(
+            Ok("www.example.com"),
+            enc_report.site_domain().map_err(|_| ())
+        );
+        enc_report.check_associated_data(None, None).unwrap();
+        enc_report.check_associated_data(Some(5), Some(1)).unwrap();
+        assert!(matches!(
+            enc_report.check_associated_data(Some(4), Some(1)),
+            Err(InvalidReportError::UnexpectedEpoch {
+                expected: 4,
+                actual: 5
+            })
+        ));
+        assert!(matches!(
+            enc_report.check_associated_data(None, Some(0)),
+            Err(InvalidReportError::UnexpectedKeyId {
+                expected: 0,
+                actual: 1
+            })
+        ));
+    }
+
+    /// Breakdown keys are stored in whole bytes, so 4- and 8-bit keys have the same layout, and
+    /// reports with 8-bit keys can be read as reports with 4-bit keys. Shares that fit into 4 bits
+    /// are read as they are, wider ones are detected rather than truncated.
+    #[test]
+    fn oprf_wide_breakdown_key() {
+        let mut rng = StdRng::from_seed([1_u8; 32]);
+        let key_registry = KeyRegistry::random(1, &mut rng);
+        let encrypt = |left: u128, right: u128, rng: &mut StdRng| {
+            let report = OprfReport::<BA8, BA3, BA20> {
+                breakdown_key: (BA8::truncate_from(left), BA8::truncate_from(right)).into(),
+                ..oprf_report(rng)
+            };
+            let mut bytes = Vec::new();
+            report
+                .encrypt_to(0, 5, "www.example.com", &key_registry, rng, &mut bytes)
+                .unwrap();
+            bytes
+        };
+
+        let bytes = encrypt(15, 3, &mut rng);
+        let enc_report =
+            EncryptedOprfReport::<BA4, BA3, BA20, _>::from_bytes(bytes.as_slice()).unwrap();
+        assert!(!enc_report.has_wide_breakdown_key());
+        assert_eq!(
+            Replicated::from((BA4::truncate_from(15_u128), BA4::truncate_from(3_u128))),
+            enc_report.decrypt(&key_registry).unwrap().breakdown_key
+        );
+
+        for (left, right) in [(16, 0), (0, 16), (255, 15)] {
+            let bytes = encrypt(left, right, &mut rng);
+            let enc_report =
+                EncryptedOprfReport::<BA4, BA3, BA20, _>::from_bytes(bytes.as_slice()).unwrap();
+            assert!(enc_report.has_wide_breakdown_key());
+            assert!(matches!(
+                enc_report.decrypt(&key_registry),
+                Err(InvalidReportError::BadTriggerValue(_))
+            ));
+            let replacement =
+                Replicated::from((BA4::truncate_from(0_u128), BA4::truncate_from(7_u128)));
+            assert_eq!(
+                replacement,
+                enc_report
+                    .decrypt_with_breakdown_key(&key_registry, replacement.clone())
+                    .unwrap()
+                    .breakdown_key
+            );
+        }
+
+        // 5-bit keys fit into the same byte as well
+        let bytes = encrypt(31, 32, &mut rng);
+        let enc_report =
+            EncryptedOprfReport::<BA5, BA3, BA20, _>::from_bytes(bytes.as_slice()).unwrap();
+        assert!(enc_report.has_wide_breakdown_key());
+    }
+
+    #[test]
+    fn report_schema_lengths() {
+        let schema = ReportSchema {
+            breakdown_key_bits: 8,
+            trigger_value_bits: 3,
+            timestamp_bits: Some(20),
+            priority_bits: Some(8),
+        };
+        assert_eq!(
+            <OprfReport<BA8, BA3, BA20> as Serializable>::Size::USIZE,
+            schema.plaintext_len()
+        );
+        assert_eq!(
+            usize::from(OprfReport::<BA8, BA3, BA20>::encrypted_len(
+                "www.example.com"
+            )),
+            schema.encrypted_len() + "www.example.com".len()
+        );
+        assert_eq!(
+            "8-bit breakdown key, 3-bit trigger value, 20-bit timestamp, 8-bit priority",
+            schema.to_string()
+        );
+    }
+
+    #[test]
+    fn report_versions() {
+        let schema = ReportSchema {
+            breakdown_key_bits: 8,
+            trigger_value_bits: 3,
+            timestamp_bits: None,
+            priority_bits: Some(8),
+        };
+        let mut header = Vec::new();
+        schema.delimited_header_to(&mut header);
+        assert_eq!(ReportSchema::HEADER_LEN + 2, header.len());
+        let header = Bytes::from(header).slice(2..);
+        assert!(header.len() < schema.encrypted_len());
+        let report = Bytes::from(vec![7_u8; schema.encrypted_len()]);
+
+        let batch = [header.clone(), report.clone()];
+        let (version, reports) = schema.read_version(&batch).unwrap();
+        assert_eq!(ReportVersion::CURRENT, version);
+        assert_eq!(&[report.clone()], reports);
+
+        // batches from before report collectors sent headers
+        let batch = [report.clone(), report.clone()];
+        let (version, reports) = schema.read_version(&batch).unwrap();
+        assert_eq!(ReportVersion::Unversioned, version);
+        assert_eq!(&batch, reports);
+        assert_eq!(
+            (ReportVersion::Unversioned, &[] as &[Bytes]),
+            schema.read_version::<Bytes>(&[]).unwrap()
+        );
+
+        let with_timestamps = ReportSchema {
+            timestamp_bits: Some(20),
+            ..schema
+        };
+        assert!(matches!(
+            with_timestamps.read_version(&[header.clone(), report.clone()]),
+            Err(InvalidReportError::HeaderMismatch { declared, .. }) if declared == schema
+        ));
+
+        // batches from before reports carried priorities
+        let v1 = Bytes::from([ReportSchema::HEADER_MAGIC.as_slice(), &[1, 8, 3, 0]].concat());
+        let without_priority = ReportSchema {
+            priority_bits: None,
+            ..schema
+        };
+        let batch = [v1.clone(), report.clone()];
+        let (version, reports) = without_priority.read_version(&batch).unwrap();
+        assert_eq!(ReportVersion::V1, version);
+        assert_eq!(&[report.clone()], reports);
+        assert!(matches!(
+            schema.read_version(&batch),
+            Err(InvalidReportError::HeaderMismatch { declared, .. }) if declared == without_priority
+        ));
+
+        let mut truncated = v1.to_vec();
+        truncated[4] = 2;
+        assert!(matches!(
+            schema.read_version(&[Bytes::from(truncated), report.clone()]),
+            Err(InvalidReportError::MalformedHeader(2, 8, 9))
+        ));
+
+        let mut future = header.to_vec();
+        future[4] = 3;
+        assert!(matches!(
+            schema.read_version(&[Bytes::from(future), report]),
+            Err(InvalidReportError::UnsupportedVersion(3))
+        ));
+    }
+
+    #[test]
+    fn report_schema_without_timestamps() {
+        let mut rng = StdRng::from_seed([1_u8; 32]);
+        let schema = ReportSchema {
+            breakdown_key_bits: 8,
+            trigger_value_bits: 3,
+            timestamp_bits: None,
+            priority_bits: Some(8),
+        };
+        let report = oprf_report(&mut rng).without_timestamp::<BA3>();
+        // timestamp shares of `BA3` take 2 bytes, that report collectors leave out
+        let strip_timestamp = |mut bytes: Vec<u8>, offset: usize| {
+            bytes.drain(offset..offset + 2);
+            bytes
+        };
+
+        let mut plaintext = GenericArray::default();
+        report.serialize(&mut plaintext);
+        let input = [plaintext.to_vec(), plaintext.to_vec()].concat();
+        let input = strip_timestamp(strip_timestamp(input, plaintext.len() + 16), 16);
+        assert_eq!(2 * schema.plaintext_len(), input.len());
+        let full = schema.read_plaintext::<BA3>(input.clone()).unwrap();
+        assert_eq!([plaintext.to_vec(), plaintext.to_vec()].concat(), full);
+        assert!(matches!(
+            schema.read_plaintext::<BA3>(input[1..].to_vec()),
+            Err(InvalidReportError::SchemaMismatch { .. })
+        ));
+
+        let key_registry = KeyRegistry::random(1, &mut rng);
+        let mut encrypted = Vec::new();
+        report
+            .encrypt_to(
+                0,
+                5,
+                "www.example.com",
+                &key_registry,
+                &mut rng,
+                &mut encrypted,
+            )
+            .unwrap();
+        let encrypted = Bytes::from(strip_timestamp(encrypted, 64));
+        let full = schema.read_encrypted::<BA3>(encrypted.clone()).unwrap();
+        let enc_report = EncryptedOprfReport::<BA8, BA3, BA3, _>::from_bytes(full).unwrap();
+        assert_eq!(report, enc_report.decrypt(&key_registry).unwrap());
+        assert!(matches!(
+            schema.read_encrypted::<BA3>(encrypted.slice(..schema.encrypted_len() - 1)),
+            Err(InvalidReportError::TooShort(_, _))
+        ));
+    }
+
+    #[test]
+    fn report_schema_pseudonymized() {
+        let mut rng = StdRng::from_seed([1_u8; 32]);
+        let schema = ReportSchema {
+            breakdown_key_bits: 8,
+            trigger_value_bits: 3,
+            timestamp_bits: Some(20),
+            priority_bits: None,
+        };
+        let report = OprfReport {
+            match_key: Replicated::ZERO,
+            priority: Replicated::ZERO,
+            ..oprf_report(&mut rng)
+        };
+        let key_registry = KeyRegistry::random(1, &mut rng);
+        let pseudonym = EncryptedPseudonym::seal(12, 3, 4, 0, &key_registry, &mut rng).unwrap();
+
+        // pseudonymized reports carry the pseudonym and all fields but the match key and the
+        // priority, that this schema leaves out
+        let mut plaintext = GenericArray::default();
+        report.serialize(&mut plaintext);
+        let mut pseudonym_bytes = GenericArray::default();
+        pseudonym.serialize(&mut pseudonym_bytes);
+        let pseudonymized = [
+            pseudonym_bytes.as_slice(),
+            &plaintext[16..plaintext.len() - 2],
+        ]
+        .concat();
+        let input = [pseudonymized.clone(), pseudonymized].concat();
+        assert_eq!(2 * schema.pseudonymized_len(), input.len());
+
+        let (pseudonyms, full) = schema.read_pseudonymized::<BA20>(input.clone()).unwrap();
+        assert_eq!(vec![pseudonym.clone(), pseudonym], pseudonyms);
+        assert_eq!([plaintext.to_vec(), plaintext.to_vec()].concat(), full);
+        assert_eq!(12, pseudonyms[0].open(&key_registry, 4).unwrap());
+        assert!(matches!(
+            schema.read_pseudonymized::<BA20>(input[1..].to_vec()),
+            Err(InvalidReportError::SchemaMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn report_schema_without_priorities() {
+        let mut rng = StdRng::from_seed([1_u8; 32]);
+        let schema = ReportSchema {
+            breakdown_key_bits: 8,
+            trigger_value_bits: 3,
+            timestamp_bits: Some(20),
+            priority_bits: None,
+        };
+        let report = OprfReport {
+            priority: Replicated::ZERO,
+            ..oprf_report(&mut rng)
+        };
+        // priority shares take 2 bytes, that report collectors leave out
+        let strip_priority = |mut bytes: Vec<u8>, offset: usize| {
+            bytes.drain(offset - 2..offset);
+            bytes
+        };
+
+        let mut plaintext = GenericArray::default();
+        report.serialize(&mut plaintext);
+        let input = [plaintext.to_vec(), plaintext.to_vec()].concat();
+        let input = strip_priority(strip_priority(input, 2 * plaintext.len()), plaintext.len());
+        assert_eq!(2 * schema.plaintext_len(), input.len());
+        let full = schema.read_plaintext::<BA20>(input).unwrap();
+        assert_eq!([plaintext.to_vec(), plaintext.to_vec()].concat(), full);
+
+        let key_registry = KeyRegistry::random(1, &mut rng);
+        let mut encrypted = Vec::new();
+        report
+            .encrypt_to(
+                0,
+                5,
+                "www.example.com",
+                &key_registry,
+                &mut rng,
+                &mut encrypted,
+            )
+            .unwrap();
+        let key_id_offset = EncryptedOprfReport::<BA8, BA3, BA20, &[u8]>::KEY_ID_OFFSET;
+        let encrypted = Bytes::from(strip_priority(encrypted, key_id_offset));
+        assert_eq!(
+            schema.encrypted_len() + "www.example.com".len(),
+            encrypted.len()
+        );
+        let full = schema.read_encrypted::<BA20>(encrypted).unwrap();
+        let enc_report = EncryptedOprfReport::<BA8, BA3, BA20, _>::from_bytes(full).unwrap();
+        assert_eq!(report, enc_report.decrypt(&key_registry).unwrap());
+    }
+
+    #[test]
+    fn oprf_too_short() {
+        let err = EncryptedOprfReport::<BA8, BA3, BA20, _>::from_bytes([0_u8; 40].as_slice())
+            .err()
+            .unwrap();
+        assert!(matches!(err, InvalidReportError::TooShort(40, _)));
+    }
 }
@@ -7,7 +7,7 @@ use generic_array::{ArrayLength, GenericArray};
 use typenum::Unsigned;
 
 use crate::{
-    ff::{ArrayAccess, Expand, Serializable},
+    ff::{ArrayAccess, Error, Expand, Serializable},
     secret_sharing::{
         replicated::ReplicatedSecretSharing, Linear as LinearSecretSharing, SecretSharing,
         SharedValue, WeakSharedValue,
@@ -227,7 +227,7 @@ impl<V: std::ops::Not<Output = V> + WeakSharedValue> std::ops::Not for AdditiveS
     }
 }
 
-impl<V: SharedValue> Serializable for AdditiveShare<V>
+impl<V: WeakSharedValue> Serializable for AdditiveShare<V>
 where
     V::Size: Add<V::Size>,
     <V::Size as Add<V::Size>>::Output: ArrayLength,
@@ -246,6 +246,13 @@ where
 
         Self::new(left, right)
     }
+
+    fn try_deserialize(buf: &GenericArray<u8, Self::Size>) -> Result<Self, Error> {
+        let left = V::try_deserialize(GenericArray::from_slice(&buf[..V::Size::USIZE]))?;
+        let right = V::try_deserialize(GenericArray::from_slice(&buf[V::Size::USIZE..]))?;
+
+        Ok(Self::new(left, right))
+    }
 }
 
 /// Implement `ArrayAccess` for `AdditiveShare` over `WeakSharedValue` that implements `ArrayAccess`
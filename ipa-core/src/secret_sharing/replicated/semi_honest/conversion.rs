@@ -0,0 +1,154 @@
+//! Conversions between replicated sharings of boolean arrays and of binary Galois fields.
+//!
+//! Both are shared by XOR and store their bits least significant first, so every share can be
+//! reinterpreted on its own, without talking to other helpers. Bits `x_i` of a boolean array
+//! become coefficients of `x^i` in the Galois field and vice versa. Only the representation
+//! changes: additions are the same on both sides, but multiplication in the field is not the
+//! bitwise AND of boolean arrays, and integer arithmetic on boolean arrays is not field arithmetic.
+//!
+//! Zeroing high bits commutes with XOR, so converting to a wider type is always correct. Converting
+//! to a narrower one is only correct if the shared value fits into it, which helpers can't check
+//! without revealing it: the high bits of every share are random, whatever the shared value is.
+//! [`try_convert`] therefore refuses to narrow, and [`truncate`] leaves it to the caller to know
+//! that the value fits.
+use crate::{
+    error::Error,
+    ff::{
+        boolean_array::{BA20, BA3, BA32, BA5, BA8},
+        Field, Gf20Bit, Gf32Bit, Gf3Bit, Gf5Bit, Gf8Bit,
+    },
+    secret_sharing::replicated::semi_honest::AdditiveShare,
+};
+
+/// Converts a sharing of `S` into a sharing of `T`, filling bits that `S` does not have with zeros.
+///
+/// ## Errors
+/// If `T` is narrower than `S`, as the shared value may not fit.
+/// ## Panics
+/// If either type is wider than 128 bits.
+pub fn try_convert<S: Field, T: Field>(x: &AdditiveShare<S>) -> Result<AdditiveShare<T>, Error> {
+    if S::BITS > T::BITS {
+        return Err(Error::FieldValueTruncation(format!(
+            "{}-bit shares may not fit into {} bits",
+            S::BITS,
+            T::BITS
+        )));
+    }
+    Ok(truncate(x))
+}
+
+/// Converts a sharing of `S` into a sharing of `T`, dropping bits that do not fit into `T`. The
+/// result shares the same value only if it fits into `T::BITS` bits.
+///
+/// ## Panics
+/// If either type is wider than 128 bits.
+#[must_use]
+pub fn truncate<S: Field, T: Field>(x: &AdditiveShare<S>) -> AdditiveShare<T> {
+    assert!(
+        S::BITS <= u128::BITS && T::BITS <= u128::BITS,
+        "conversion of {}-bit to {}-bit shares is not supported",
+        S::BITS,
+        T::BITS
+    );
+    AdditiveShare(
+        T::truncate_from(x.0.as_u128()),
+        T::truncate_from(x.1.as_u128()),
+    )
+}
+
+/// Reinterprets shares of types with the same width, which can't fail.
+macro_rules! reinterpret {
+    ($($ba:ty => $gf:ty),+ $(,)?) => {
+        $(
+            impl From<&AdditiveShare<$ba>> for AdditiveShare<$gf> {
+                fn from(x: &AdditiveShare<$ba>) -> Self {
+                    truncate(x)
+                }
+            }
+
+            impl From<&AdditiveShare<$gf>> for AdditiveShare<$ba> {
+                fn from(x: &AdditiveShare<$gf>) -> Self {
+                    truncate(x)
+                }
+            }
+        )+
+    };
+}
+
+reinterpret!(
+    BA3 => Gf3Bit,
+    BA5 => Gf5Bit,
+    BA8 => Gf8Bit,
+    BA20 => Gf20Bit,
+    BA32 => Gf32Bit,
+);
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use super::{truncate, try_convert};
+    use crate::{
+        error::Error,
+        ff::{
+            boolean_array::{BA32, BA64, BA8},
+            Field, Gf32Bit, Gf40Bit, Gf8Bit,
+        },
+        protocol::{basics::SecureMul, context::Context, RecordId},
+        rand::{thread_rng, Rng},
+        secret_sharing::replicated::semi_honest::AdditiveShare,
+        test_executor::run,
+        test_fixture::{Reconstruct, Runner, TestWorld},
+    };
+
+    #[test]
+    fn round_trip() {
+        run(|| async move {
+            let x = thread_rng().gen::<BA32>();
+            let result: Vec<BA32> = TestWorld::default()
+                .semi_honest(x, |_ctx, x| async move {
+                    let gf = AdditiveShare::<Gf32Bit>::from(&x);
+                    let wide = try_convert::<_, Gf40Bit>(&x).unwrap();
+                    vec![AdditiveShare::<BA32>::from(&gf), truncate::<_, BA32>(&wide)]
+                })
+                .await
+                .reconstruct();
+            assert_eq!(vec![x, x], result);
+        });
+    }
+
+    #[test]
+    fn galois_multiplication() {
+        run(|| async move {
+            let mut rng = thread_rng();
+            let (a, b) = (rng.gen::<BA8>(), rng.gen::<BA8>());
+            let result: Gf8Bit = TestWorld::default()
+                .semi_honest((a, b), |ctx, (a, b)| async move {
+                    let (a, b) = (
+                        AdditiveShare::<Gf8Bit>::from(&a),
+                        AdditiveShare::<Gf8Bit>::from(&b),
+                    );
+                    a.multiply(&b, ctx.set_total_records(1), RecordId::FIRST)
+                        .await
+                        .unwrap()
+                })
+                .await
+                .reconstruct();
+            assert_eq!(
+                Gf8Bit::truncate_from(a.as_u128()) * Gf8Bit::truncate_from(b.as_u128()),
+                result
+            );
+        });
+    }
+
+    #[test]
+    fn narrowing() {
+        let x = AdditiveShare(BA64::truncate_from(u64::MAX), BA64::truncate_from(1_u128));
+        assert!(matches!(
+            try_convert::<_, Gf32Bit>(&x),
+            Err(Error::FieldValueTruncation(_))
+        ));
+
+        let truncated = truncate::<_, Gf32Bit>(&x);
+        assert_eq!(Gf32Bit::truncate_from(u32::MAX), truncated.0);
+        assert_eq!(Gf32Bit::ONE, truncated.1);
+    }
+}
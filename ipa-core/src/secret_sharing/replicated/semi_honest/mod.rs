@@ -1,3 +1,5 @@
 mod additive_share;
+mod conversion;
 
 pub use additive_share::{ASIterator, AdditiveShare};
+pub use conversion::{truncate, try_convert};
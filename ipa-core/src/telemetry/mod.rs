@@ -1,3 +1,5 @@
+#[cfg(feature = "otel")]
+pub mod otel;
 pub mod stats;
 mod step_stats;
 
@@ -6,6 +8,7 @@ pub use step_stats::CsvExporter as StepStatsCsvExporter;
 pub mod labels {
     pub const STEP: &str = "step";
     pub const ROLE: &str = "role";
+    pub const PEER: &str = "peer";
 }
 
 pub mod metrics {
@@ -14,9 +17,12 @@ pub mod metrics {
     pub const REQUESTS_RECEIVED: &str = "requests.received";
     pub const RECORDS_SENT: &str = "records.sent";
     pub const BYTES_SENT: &str = "bytes.sent";
+    pub const RECORDS_RECV_EXPIRED: &str = "records.recv.expired";
     pub const INDEXED_PRSS_GENERATED: &str = "i.prss.gen";
     pub const SEQUENTIAL_PRSS_GENERATED: &str = "s.prss.gen";
     pub const STEP_NARROWED: &str = "step.narrowed";
+    pub const THROTTLED_SENDS: &str = "net.throttled.sends";
+    pub const THROTTLE_WAIT_MICROS: &str = "net.throttle.wait.us";
 
     #[cfg(feature = "web-app")]
     pub mod web {
@@ -88,6 +94,12 @@ pub mod metrics {
             "Bytes sent from the infrastructure layer to the network"
         );
 
+        describe_counter!(
+            RECORDS_RECV_EXPIRED,
+            Unit::Count,
+            "Number of records that did not arrive within the configured record TTL"
+        );
+
         describe_counter!(
             INDEXED_PRSS_GENERATED,
             Unit::Count,
@@ -105,5 +117,17 @@ pub mod metrics {
             Unit::Count,
             "Number of times the step is narrowed"
         );
+
+        describe_counter!(
+            THROTTLED_SENDS,
+            Unit::Count,
+            "Number of chunks of records held back by the bandwidth limit of a peer"
+        );
+
+        describe_counter!(
+            THROTTLE_WAIT_MICROS,
+            Unit::Microseconds,
+            "Time chunks of records spent waiting for the bandwidth limit of a peer"
+        );
     }
 }
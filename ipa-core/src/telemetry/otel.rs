@@ -0,0 +1,119 @@
+//! OpenTelemetry integration.
+//!
+//! Helpers export their `tracing` spans to an OTLP collector and propagate the trace context to
+//! each other using [`W3C Trace Context`] headers. Report collector requests carry the context
+//! too, so creating a query, sending inputs and collecting results end up in one distributed trace
+//! that covers all three helpers.
+//!
+//! [`W3C Trace Context`]: https://www.w3.org/TR/trace-context/
+use hyper::{
+    header::{HeaderName, HeaderValue},
+    HeaderMap,
+};
+use opentelemetry::{
+    global,
+    propagation::{Extractor, Injector},
+    trace::TraceError,
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{propagation::TraceContextPropagator, runtime, trace, Resource};
+use tracing::{Span, Subscriber};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{registry::LookupSpan, Layer};
+
+/// Builds a `tracing` layer that exports spans to the OTLP collector listening on `endpoint`
+/// and installs the trace context propagator used by [`inject_context`] and
+/// [`set_remote_parent`].
+///
+/// ## Errors
+/// If the OTLP exporter cannot be created.
+pub fn layer<S>(service_name: String, endpoint: &str) -> Result<impl Layer<S>, TraceError>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            trace::config()
+                .with_resource(Resource::new([KeyValue::new("service.name", service_name)])),
+        )
+        .install_batch(runtime::Tokio)?;
+
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// Flushes all spans that have not been exported yet. Must be called before the process exits,
+/// otherwise the tail of the trace is lost.
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+/// Writes the trace context of `span` into the outgoing request headers.
+pub fn inject_context(span: &Span, headers: &mut HeaderMap) {
+    let context = span.context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(headers));
+    });
+}
+
+/// Makes `span` a child of the remote span whose context is carried in the request headers. If
+/// headers don't have any trace context, `span` is left untouched.
+pub fn set_remote_parent(span: &Span, headers: &HeaderMap) {
+    let parent =
+        global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+    span.set_parent(parent);
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use hyper::HeaderMap;
+    use opentelemetry::propagation::{Extractor, Injector};
+
+    use super::{HeaderExtractor, HeaderInjector};
+
+    #[test]
+    fn header_round_trip() {
+        const TRACEPARENT: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+        let mut headers = HeaderMap::new();
+        HeaderInjector(&mut headers).set("traceparent", TRACEPARENT.to_string());
+        HeaderInjector(&mut headers).set("bad header", "ignored".to_string());
+
+        let extractor = HeaderExtractor(&headers);
+        assert_eq!(Some(TRACEPARENT), extractor.get("traceparent"));
+        assert_eq!(vec!["traceparent"], extractor.keys());
+    }
+}
@@ -7,14 +7,14 @@ use crate::{
     app::Error,
     ff::Serializable,
     helpers::{
-        query::{QueryConfig, QueryInput},
-        InMemoryNetwork, InMemoryTransport,
+        query::{InputPart, QueryConfig, QueryInput},
+        InMemoryNetwork,
     },
     protocol::QueryId,
     query::QueryStatus,
     secret_sharing::IntoShares,
     test_fixture::try_join3_array,
-    AppSetup, HelperApp,
+    AppSetup,
 };
 
 pub trait IntoBuf {
@@ -41,6 +41,8 @@ where
 
 /// [`TestApp`] runs IPA queries end-to-end using [`InMemoryNetwork`]
 /// It orchestrates the interaction between several components to drive queries to completion.
+/// Every request goes through the in-memory transport, the same way report collectors reach
+/// helpers over HTTP, so callbacks and stream teardown are exercised by these tests as well.
 ///
 /// In contrast with [`TestWorld`] which can only run computations tied up to a single query, this
 /// can potentially be used to run multiple queries in parallel. The guidance is to use `[TestWorld`]
@@ -49,32 +51,16 @@ where
 /// [`InMemoryNetwork`]: crate::test_fixture::network::InMemoryNetwork
 /// [`TestWorld`]: crate::test_fixture::TestWorld
 pub struct TestApp {
-    drivers: [HelperApp; 3],
     network: InMemoryNetwork,
 }
 
-fn unzip_tuple_array<T, U>(input: [(T, U); 3]) -> ([T; 3], [U; 3]) {
-    let [v0, v1, v2] = input;
-    ([v0.0, v1.0, v2.0], [v0.1, v1.1, v2.1])
-}
-
 impl Default for TestApp {
     fn default() -> Self {
-        let (setup, callbacks) =
-            unzip_tuple_array([AppSetup::new(), AppSetup::new(), AppSetup::new()]);
-
-        let network = InMemoryNetwork::new(callbacks);
-        let drivers = network
-            .transports()
-            .iter()
-            .zip(setup)
-            .map(|(t, s)| s.connect(<InMemoryTransport as Clone>::clone(t)))
-            .collect::<Vec<_>>()
-            .try_into()
-            .map_err(|_| "infallible")
-            .unwrap();
-
-        Self { drivers, network }
+        let callbacks = [(); 3].map(|()| AppSetup::new().1);
+
+        Self {
+            network: InMemoryNetwork::new(callbacks),
+        }
     }
 }
 
@@ -96,19 +82,61 @@ impl TestApp {
         let helpers_input = input.share().map(IntoBuf::into_buf);
 
         // helper 1 initiates the query
-        let query_id = self.drivers[0].start_query(query_config).await?;
+        let query_id = self.network.transports[0]
+            .receive_query(query_config)
+            .await?;
 
         // Send inputs
-        helpers_input
-            .into_iter()
-            .enumerate()
-            .map(|(i, input)| {
-                self.drivers[i].execute_query(QueryInput {
+        for (transport, input) in zip(&self.network.transports, helpers_input) {
+            transport
+                .query_input(QueryInput {
                     query_id,
+                    part: InputPart::All,
                     input_stream: input.into(),
                 })
-            })
-            .collect::<Result<Vec<_>, _>>()?;
+                .await?;
+        }
+
+        Ok(query_id)
+    }
+
+    /// Initiates a new query on all helpers and sends its input in two parts, as if source and
+    /// trigger events were submitted by two different report collectors.
+    ///
+    /// ## Errors
+    /// Returns an error if it can't start a query or send query input.
+    pub async fn start_query_split<I, A>(
+        &self,
+        sources: I,
+        triggers: I,
+        query_config: QueryConfig,
+    ) -> Result<QueryId, Error>
+    where
+        I: IntoShares<A>,
+        A: IntoBuf,
+    {
+        let sources = sources.share().map(IntoBuf::into_buf);
+        let triggers = triggers.share().map(IntoBuf::into_buf);
+
+        let query_id = self.network.transports[0]
+            .receive_query(query_config)
+            .await?;
+
+        // trigger events are sent first to make sure helpers don't rely on the arrival order
+        for (part, inputs) in [
+            (InputPart::TriggerEvents, triggers),
+            (InputPart::SourceEvents, sources),
+        ] {
+            for (transport, input) in zip(&self.network.transports, inputs) {
+                transport
+                    .query_input(QueryInput {
+                        query_id,
+                        part,
+                        input_stream: input.into(),
+                    })
+                    .await?;
+            }
+        }
 
         Ok(query_id)
     }
@@ -117,12 +145,12 @@ impl TestApp {
     /// Propagates errors retrieving the query status.
     /// ## Panics
     /// Never.
-    pub fn query_status(&self, query_id: QueryId) -> Result<[QueryStatus; 3], Error> {
-        Ok((0..3)
-            .map(|i| self.drivers[i].query_status(query_id))
-            .collect::<Result<Vec<_>, _>>()?
-            .try_into()
-            .unwrap())
+    pub async fn query_status(&self, query_id: QueryId) -> Result<[QueryStatus; 3], Error> {
+        let transports = &self.network.transports;
+        try_join3_array([0, 1, 2].map(|i| async move {
+            Ok::<_, Error>(transports[i].query_status(query_id).await?.status)
+        }))
+        .await
     }
 
     /// ## Errors
@@ -130,10 +158,11 @@ impl TestApp {
     /// ## Panics
     /// Never.
     pub async fn complete_query(&self, query_id: QueryId) -> Result<[Vec<u8>; 3], Error> {
-        let results =
-            try_join3_array([0, 1, 2].map(|i| self.drivers[i].complete_query(query_id))).await;
-        self.network.reset();
-        results
+        let transports = &self.network.transports;
+        try_join3_array([0, 1, 2].map(|i| async move {
+            Ok::<_, Error>(transports[i].complete_query(query_id).await?.into_bytes())
+        }))
+        .await
     }
 
     /// Initiates a new query on all helpers and drives it to completion.
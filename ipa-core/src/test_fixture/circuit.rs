@@ -1,6 +1,13 @@
 use futures_util::future::join_all;
 
 use super::join3v;
+#[cfg(feature = "descriptive-gate")]
+pub use crate::protocol::ipa_prf::boolean_ops::AdderStrategy;
+#[cfg(feature = "descriptive-gate")]
+use crate::{
+    ff::boolean_array::BA64, protocol::ipa_prf::boolean_ops::integer_add_with, rand::Rng,
+    seq_join::SeqJoin, test_fixture::Runner,
+};
 use crate::{
     ff::Field,
     helpers::TotalRecords,
@@ -43,6 +50,49 @@ where
     assert_eq!(sum, u128::from(width));
 }
 
+/// Adds `width` pairs of random 64-bit integers using the given boolean adder. All additions
+/// proceed in parallel, so the time it takes is dominated by the number of communication rounds:
+/// 64 for the ripple-carry adder and 7 for the parallel prefix adder.
+///
+/// # Panics
+/// panics when the adder did not produce the expected value.
+#[cfg(feature = "descriptive-gate")]
+pub async fn adder(width: u32, strategy: AdderStrategy) {
+    let world = TestWorld::default();
+    let mut rng = thread_rng();
+    let inputs = (0..width)
+        .map(|_| (rng.gen::<BA64>(), rng.gen::<BA64>()))
+        .collect::<Vec<_>>();
+
+    let results = world
+        .semi_honest(
+            inputs.clone().into_iter(),
+            |ctx, inputs: Vec<(Replicated<BA64>, Replicated<BA64>)>| async move {
+                let ctx = ctx.set_total_records(inputs.len());
+                ctx.parallel_join(inputs.iter().enumerate().map(|(i, (x, y))| {
+                    let ctx = ctx.clone();
+                    async move {
+                        integer_add_with::<_, BA64, BA64>(strategy, ctx, RecordId::from(i), x, y)
+                            .await
+                            .map(|(sum, _carry)| sum)
+                    }
+                }))
+                .await
+                .unwrap()
+            },
+        )
+        .await
+        .reconstruct();
+
+    for ((x, y), sum) in inputs.iter().zip(results) {
+        assert_eq!(
+            (x.as_u128() + y.as_u128()) % (1 << 64),
+            sum.as_u128(),
+            "{x:?} + {y:?}"
+        );
+    }
+}
+
 async fn circuit<'a, F>(
     top_ctx: &[SemiHonestContext<'a>; 3],
     record_id: RecordId,
@@ -0,0 +1,390 @@
+//! Reads cleartext events from CSV or JSON Lines datasets and turns them into query inputs.
+//!
+//! Events are secret-shared locally and every helper gets its shares of [`OprfReport`]s with
+//! plaintext match keys, serialized the way the query input path reads them. That lets end-to-end
+//! tests run on collected datasets without producing the binary share format first.
+//!
+//! Both formats describe the same fields as [`TestRawDataRecord`]. CSV rows list them in the order
+//! `cli::CsvSerializer` writes them, `timestamp,user_id,is_trigger_report,breakdown_key,
+//! trigger_value`, unless the first row is a header that names the columns. JSON Lines objects
+//! name them. In both formats, `match_key` may stand for `user_id`, and `is_trigger_report` may be
+//! `0` or `1`; JSON Lines objects may use booleans for it as well.
+use std::{
+    io::{self, BufRead},
+    path::Path,
+};
+
+use generic_array::GenericArray;
+use rand::Rng;
+use typenum::Unsigned;
+
+use crate::{
+    ff::{Field, Serializable},
+    helpers::BodyStream,
+    report::OprfReport,
+    secret_sharing::{replicated::semi_honest::AdditiveShare as Replicated, IntoShares},
+    test_fixture::ipa::TestRawDataRecord,
+};
+
+/// Formats of cleartext event datasets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Csv,
+    JsonLines,
+}
+
+impl Format {
+    /// Tells the format of the dataset at `path` by its extension.
+    #[must_use]
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "csv" => Some(Self::Csv),
+            "jsonl" | "ndjson" => Some(Self::JsonLines),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to read events: {0}")]
+    Io(#[from] io::Error),
+    #[error("line {line}: {reason}")]
+    Parse { line: usize, reason: String },
+    #[error("event {index}: {field} {value} does not fit into {bits} bits")]
+    OutOfRange {
+        index: usize,
+        field: &'static str,
+        value: u64,
+        bits: u32,
+    },
+}
+
+const COLUMNS: [&str; 5] = [
+    "timestamp",
+    "user_id",
+    "is_trigger_report",
+    "breakdown_key",
+    "trigger_value",
+];
+
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum Flag {
+    Bool(bool),
+    Int(u8),
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonEvent {
+    timestamp: u64,
+    #[serde(alias = "match_key")]
+    user_id: u64,
+    is_trigger_report: Flag,
+    breakdown_key: u32,
+    trigger_value: u32,
+}
+
+fn parse_flag(flag: Flag) -> Result<bool, String> {
+    match flag {
+        Flag::Bool(b) => Ok(b),
+        Flag::Int(0) => Ok(false),
+        Flag::Int(1) => Ok(true),
+        Flag::Int(v) => Err(format!("is_trigger_report must be 0 or 1, not {v}")),
+    }
+}
+
+/// Reads events from `input`, skipping blank lines.
+///
+/// ## Errors
+/// If `input` can't be read, or any of its lines is not a valid event. Errors name the line,
+/// counting from 1.
+pub fn read_events<R: BufRead>(input: R, format: Format) -> Result<Vec<TestRawDataRecord>, Error> {
+    // position of every field of `COLUMNS` in CSV rows
+    let mut columns = None;
+    let mut events = Vec::new();
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let parse_error = |reason: String| Error::Parse {
+            line: i + 1,
+            reason,
+        };
+        match format {
+            Format::JsonLines => {
+                let event: JsonEvent =
+                    serde_json::from_str(line).map_err(|e| parse_error(e.to_string()))?;
+                events.push(TestRawDataRecord {
+                    timestamp: event.timestamp,
+                    user_id: event.user_id,
+                    is_trigger_report: parse_flag(event.is_trigger_report).map_err(parse_error)?,
+                    breakdown_key: event.breakdown_key,
+                    trigger_value: event.trigger_value,
+                });
+            }
+            Format::Csv => {
+                let fields = line.split(',').map(str::trim).collect::<Vec<_>>();
+                if columns.is_none() && fields.iter().any(|f| f.parse::<u64>().is_err()) {
+                    columns = Some(csv_header(&fields).map_err(parse_error)?);
+                    continue;
+                }
+                let columns = columns.get_or_insert([0, 1, 2, 3, 4]);
+                events.push(csv_event(&fields, columns).map_err(parse_error)?);
+            }
+        }
+    }
+    Ok(events)
+}
+
+/// Reads events from the dataset at `path`, in the format its extension tells.
+///
+/// ## Errors
+/// If the extension is not one of a known format, or if [`read_events`] fails.
+pub fn read_events_from_file(path: &Path) -> Result<Vec<TestRawDataRecord>, Error> {
+    let format = Format::from_path(path).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is neither a CSV nor a JSON Lines file", path.display()),
+        )
+    })?;
+    read_events(io::BufReader::new(std::fs::File::open(path)?), format)
+}
+
+fn csv_header(fields: &[&str]) -> Result<[usize; 5], String> {
+    if fields.len() != COLUMNS.len() {
+        return Err(format!(
+            "header must name {} columns, not {}",
+            COLUMNS.len(),
+            fields.len()
+        ));
+    }
+    let mut columns = [usize::MAX; 5];
+    for (position, &name) in fields.iter().enumerate() {
+        let name = if name == "match_key" { "user_id" } else { name };
+        let column = COLUMNS
+            .iter()
+            .position(|&c| c == name)
+            .ok_or_else(|| format!("unknown column {name}"))?;
+        if columns[column] != usize::MAX {
+            return Err(format!("column {name} appears twice"));
+        }
+        columns[column] = position;
+    }
+    Ok(columns)
+}
+
+fn csv_event(fields: &[&str], columns: &[usize; 5]) -> Result<TestRawDataRecord, String> {
+    if fields.len() != COLUMNS.len() {
+        return Err(format!(
+            "expected {} fields, found {}",
+            COLUMNS.len(),
+            fields.len()
+        ));
+    }
+    let field = |column: usize| -> Result<u64, String> {
+        let value = fields[columns[column]];
+        value
+            .parse()
+            .map_err(|e| format!("{}: {value:?} {e}", COLUMNS[column]))
+    };
+    let narrow = |column: usize| -> Result<u32, String> {
+        u32::try_from(field(column)?).map_err(|e| format!("{}: {e}", COLUMNS[column]))
+    };
+    Ok(TestRawDataRecord {
+        timestamp: field(0)?,
+        user_id: field(1)?,
+        is_trigger_report: parse_flag(Flag::Int(
+            u8::try_from(field(2)?).map_err(|e| format!("{}: {e}", COLUMNS[2]))?,
+        ))?,
+        breakdown_key: narrow(3)?,
+        trigger_value: narrow(4)?,
+    })
+}
+
+/// Shares `events` as [`OprfReport<BK, TV, TS>`]s and returns the serialized shares of every
+/// helper, in the order of `TestWorld::contexts`, ready to be passed to a query as its input.
+///
+/// ## Errors
+/// If any field of an event is too wide for its report field.
+pub fn into_body_streams<BK, TV, TS, R>(
+    events: Vec<TestRawDataRecord>,
+    rng: &mut R,
+) -> Result<[BodyStream; 3], Error>
+where
+    BK: Field + IntoShares<Replicated<BK>>,
+    TV: Field + IntoShares<Replicated<TV>>,
+    TS: Field + IntoShares<Replicated<TS>>,
+    R: Rng,
+    OprfReport<BK, TV, TS>: Serializable,
+{
+    for (index, event) in events.iter().enumerate() {
+        for (field, value, bits) in [
+            ("timestamp", event.timestamp, TS::BITS),
+            ("breakdown_key", event.breakdown_key.into(), BK::BITS),
+            ("trigger_value", event.trigger_value.into(), TV::BITS),
+        ] {
+            if value.checked_shr(bits).unwrap_or(0) != 0 {
+                return Err(Error::OutOfRange {
+                    index,
+                    field,
+                    value,
+                    bits,
+                });
+            }
+        }
+    }
+
+    let report_len = <OprfReport<BK, TV, TS> as Serializable>::Size::USIZE;
+    let mut buffers: [Vec<u8>; 3] =
+        std::array::from_fn(|_| Vec::with_capacity(events.len() * report_len));
+    for event in events {
+        let shares: [OprfReport<BK, TV, TS>; 3] = event.share_with(rng);
+        for (share, buf) in shares.iter().zip(&mut buffers) {
+            let mut bytes = GenericArray::default();
+            share.serialize(&mut bytes);
+            buf.extend_from_slice(&bytes);
+        }
+    }
+    Ok(buffers.map(BodyStream::from))
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use futures::TryStreamExt;
+
+    use super::{into_body_streams, read_events, Error, Format};
+    use crate::{
+        ff::{
+            boolean_array::{BA20, BA3, BA8},
+            Field,
+        },
+        helpers::RecordsStream,
+        rand::thread_rng,
+        report::OprfReport,
+        test_fixture::{ipa::TestRawDataRecord, Reconstruct},
+    };
+
+    fn fields(event: &TestRawDataRecord) -> (u64, u64, bool, u32, u32) {
+        (
+            event.timestamp,
+            event.user_id,
+            event.is_trigger_report,
+            event.breakdown_key,
+            event.trigger_value,
+        )
+    }
+
+    #[test]
+    fn csv() {
+        let events = read_events("0,12345,0,2,0\n\n5,12345,1,0,7\n".as_bytes(), Format::Csv)
+            .unwrap()
+            .iter()
+            .map(fields)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![(0, 12345, false, 2, 0), (5, 12345, true, 0, 7)],
+            events
+        );
+
+        // columns in any order, named by a header
+        let input = "match_key,timestamp,breakdown_key,trigger_value,is_trigger_report\n\
+                     12345,5,0,7,1\n";
+        let events = read_events(input.as_bytes(), Format::Csv).unwrap();
+        assert_eq!((5, 12345, true, 0, 7), fields(&events[0]));
+    }
+
+    #[test]
+    fn json_lines() {
+        let input = concat!(
+            r#"{"timestamp": 0, "user_id": 12345, "is_trigger_report": false, "#,
+            r#""breakdown_key": 2, "trigger_value": 0}"#,
+            "\n",
+            r#"{"timestamp": 5, "match_key": 12345, "is_trigger_report": 1, "#,
+            r#""breakdown_key": 0, "trigger_value": 7}"#,
+        );
+        let events = read_events(input.as_bytes(), Format::JsonLines)
+            .unwrap()
+            .iter()
+            .map(fields)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![(0, 12345, false, 2, 0), (5, 12345, true, 0, 7)],
+            events
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        for (input, format, line) in [
+            ("0,1,0,2,0\n0,1,2,2,0", Format::Csv, 2),
+            ("0,1,0,2", Format::Csv, 1),
+            // unknown column
+            (
+                "timestamp,user_id,is_trigger,breakdown_key,trigger_value",
+                Format::Csv,
+                1,
+            ),
+            ("\n{\"timestamp\": 0}", Format::JsonLines, 2),
+        ] {
+            assert!(
+                matches!(
+                    read_events(input.as_bytes(), format),
+                    Err(Error::Parse { line: l, .. }) if l == line
+                ),
+                "{input}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn shares_events() {
+        let events = read_events("0,12345,0,2,0\n5,12345,1,0,7\n".as_bytes(), Format::Csv).unwrap();
+        let streams = into_body_streams::<BA8, BA3, BA20, _>(events, &mut thread_rng()).unwrap();
+
+        let mut shares = Vec::new();
+        for stream in streams {
+            shares.push(
+                RecordsStream::<OprfReport<BA8, BA3, BA20>, _>::new(stream)
+                    .try_concat()
+                    .await
+                    .unwrap(),
+            );
+        }
+        let [h1, h2, h3] = <[_; 3]>::try_from(shares).unwrap();
+        let trigger_values = h1
+            .iter()
+            .zip(&h2)
+            .zip(&h3)
+            .map(|((a, b), c)| {
+                [&a.trigger_value, &b.trigger_value, &c.trigger_value]
+                    .reconstruct()
+                    .as_u128()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(vec![0, 7], trigger_values);
+        assert_eq!(
+            12345,
+            [&h1[1].match_key, &h2[1].match_key, &h3[1].match_key]
+                .reconstruct()
+                .as_u128()
+        );
+    }
+
+    #[test]
+    fn out_of_range() {
+        let events = read_events("0,1,1,0,8".as_bytes(), Format::Csv).unwrap();
+        assert!(matches!(
+            into_body_streams::<BA8, BA3, BA20, _>(events, &mut thread_rng()),
+            Err(Error::OutOfRange {
+                index: 0,
+                field: "trigger_value",
+                value: 8,
+                bits: 3,
+            })
+        ));
+    }
+}
@@ -395,6 +395,7 @@ where
                 is_trigger: is_trigger_share,
                 breakdown_key: bk_share,
                 trigger_value: tv_share,
+                priority: Replicated::ZERO,
             },
         )
         .collect::<Vec<_>>()
@@ -248,7 +248,7 @@ pub async fn test_oprf_ipa<F>(
     Replicated<F>: Serializable,
 {
     use crate::{
-        ff::boolean_array::{BA20, BA3, BA4, BA5, BA6, BA7, BA8},
+        ff::boolean_array::{BA20, BA3, BA8},
         protocol::ipa_prf::oprf_ipa,
         report::OprfReport,
         test_fixture::Runner,
@@ -258,45 +258,32 @@ pub async fn test_oprf_ipa<F>(
     records.sort_by(|a, b| b.user_id.cmp(&a.user_id));
 
     let aws = config.attribution_window_seconds;
+    let bks = config.max_breakdown_key;
 
     let result: Vec<_> = world
         .semi_honest(
             records.into_iter(),
             |ctx, input_rows: Vec<OprfReport<BA8, BA3, BA20>>| async move {
-
-                match config.per_user_credit_cap {
-                    8 => oprf_ipa::<_, BA8, BA3, BA20, BA3, F>(ctx, input_rows, aws)
-                    .await
-                    .unwrap(),
-                    16 => oprf_ipa::<_, BA8, BA3, BA20, BA4, F>(ctx, input_rows, aws)
-                    .await
-                    .unwrap(),
-                    32 => oprf_ipa::<_, BA8, BA3, BA20, BA5, F>(ctx, input_rows, aws)
-                    .await
-                    .unwrap(),
-                    64 => oprf_ipa::<_, BA8, BA3, BA20, BA6, F>(ctx, input_rows, aws)
-                    .await
-                    .unwrap(),
-                    128 => oprf_ipa::<_, BA8, BA3, BA20, BA7, F>(ctx, input_rows, aws)
-                    .await
-                    .unwrap(),
-                    _ =>
-                    panic!(
-                        "Invalid value specified for per-user cap: {:?}. Must be one of 8, 16, 32, 64, or 128.",
-                        config.per_user_credit_cap
-                    ),
-                }
+                oprf_ipa::<_, BA8, BA3, BA20, F>(
+                    ctx,
+                    input_rows,
+                    aws,
+                    config.per_user_credit_cap,
+                    bks,
+                    None,
+                    None,
+                )
+                .await
+                .unwrap()
             },
         )
         .await
         .reconstruct();
 
-    let mut result = result
+    let result = result
         .into_iter()
         .map(|v| u32::try_from(v.as_u128()).unwrap())
         .collect::<Vec<_>>();
 
-    //TODO(richaj): To be removed once the function supports non power of 2 breakdowns
-    let _ = result.split_off(expected_results.len());
     assert_eq!(result, expected_results);
 }
@@ -11,9 +11,13 @@ mod app;
 #[cfg(feature = "in-memory-infra")]
 pub mod circuit;
 mod event_gen;
+#[cfg(feature = "enable-serde")]
+pub mod ingest;
 pub mod ipa;
 pub mod logging;
 pub mod metrics;
+#[cfg(feature = "in-memory-infra")]
+mod replay;
 
 use std::fmt::Debug;
 
@@ -23,6 +27,8 @@ pub use event_gen::{Config as EventGeneratorConfig, EventGenerator};
 use futures::TryFuture;
 use rand::{distributions::Standard, prelude::Distribution, rngs::mock::StepRng};
 use rand_core::{CryptoRng, RngCore};
+#[cfg(feature = "in-memory-infra")]
+pub use replay::Replay;
 pub use sharing::{get_bits, into_bits, Reconstruct};
 #[cfg(feature = "in-memory-infra")]
 pub use world::{Runner, TestWorld, TestWorldConfig};
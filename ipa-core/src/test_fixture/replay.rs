@@ -0,0 +1,153 @@
+use std::{io, path::Path};
+
+use rand::rngs::StdRng;
+use rand_core::SeedableRng;
+
+use crate::{
+    helpers::{CapturedTraffic, Gateway, InMemoryNetwork, Role, RoleAssignment, Traffic},
+    protocol::{
+        context::{Context, SemiHonestContext},
+        prss::Endpoint as PrssEndpoint,
+        QueryId,
+    },
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    test_fixture::{logging, make_participants, TestWorld, TestWorldConfig},
+};
+
+/// Runs the protocol of a single helper against traffic captured by a [`TestWorld`], without
+/// the other two helpers.
+///
+/// Records this helper receives come from the capture and records it sends are compared to the
+/// captured ones, so protocol code fails with [`Error::ReplayMismatch`] at the first record that
+/// differs from the original run. For the replay to match, it must use the same seed and role
+/// assignment as the world that captured the traffic, and run the same executions in the same
+/// order.
+///
+/// [`Error::ReplayMismatch`]: crate::helpers::Error::ReplayMismatch
+pub struct Replay {
+    gateway: Gateway,
+    participant: PrssEndpoint,
+    executions: AtomicUsize,
+    _network: InMemoryNetwork,
+}
+
+impl Replay {
+    /// Replays `captured` traffic of the helper with the given `role`.
+    #[must_use]
+    pub fn new(config: &TestWorldConfig, role: Role, captured: CapturedTraffic) -> Self {
+        logging::setup();
+
+        let [p1, p2, p3] = make_participants(&mut StdRng::seed_from_u64(config.seed));
+        let participant = match role {
+            Role::H1 => p1,
+            Role::H2 => p2,
+            Role::H3 => p3,
+        };
+        // the network is never used to send anything, it only provides transport for the gateway
+        let network = InMemoryNetwork::default();
+        let role_assignment = config
+            .role_assignment
+            .clone()
+            .unwrap_or_else(|| RoleAssignment::new(network.helper_identities()));
+        let transport = network.transport(role_assignment.identity(role));
+        let gateway = Gateway::with_traffic(
+            QueryId::default(),
+            config.gateway_config,
+            role_assignment,
+            transport,
+            Traffic::Replay(Arc::new(captured)),
+        );
+
+        Self {
+            gateway,
+            participant,
+            executions: AtomicUsize::new(0),
+            _network: network,
+        }
+    }
+
+    /// Replays traffic the helper with the given `role` captured to `dir`, see
+    /// [`TestWorldConfig::capture_traffic`].
+    ///
+    /// ## Errors
+    /// If the capture file can't be read.
+    pub fn load(config: &TestWorldConfig, role: Role, dir: &Path) -> io::Result<Self> {
+        let captured = CapturedTraffic::load(TestWorldConfig::capture_file(dir, role))?;
+        Ok(Self::new(config, role, captured))
+    }
+
+    /// Creates the context for the next execution, the same one [`TestWorld::contexts`] would
+    /// create for this helper.
+    #[must_use]
+    pub fn context(&self) -> SemiHonestContext<'_> {
+        let execution = self.executions.fetch_add(1, Ordering::Relaxed);
+        SemiHonestContext::new(&self.participant, &self.gateway)
+            .narrow(&TestWorld::execution_step(execution))
+    }
+}
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use std::iter::zip;
+
+    use futures::future::try_join_all;
+
+    use super::Replay;
+    use crate::{
+        ff::{Field, Fp32BitPrime},
+        helpers::{Error, Role},
+        protocol::{basics::SecureMul, context::Context, RecordId},
+        rand::thread_rng,
+        secret_sharing::{
+            replicated::{semi_honest::AdditiveShare, ReplicatedSecretSharing},
+            IntoShares,
+        },
+        test_fixture::{Reconstruct, TestWorld, TestWorldConfig},
+    };
+
+    type Share = AdditiveShare<Fp32BitPrime>;
+
+    async fn multiply<C: Context>(ctx: C, a: Share, b: Share) -> Result<Share, Error> {
+        a.multiply(&b, ctx.set_total_records(1), RecordId::FIRST)
+            .await
+    }
+
+    #[tokio::test]
+    async fn replays_single_helper() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = TestWorldConfig::default().with_capture_traffic(dir.path());
+        let world = TestWorld::new_with(config.clone());
+        let (a, b) = (
+            Fp32BitPrime::truncate_from(5_u128),
+            Fp32BitPrime::truncate_from(6_u128),
+        );
+
+        let mut rng = thread_rng();
+        let inputs = zip(a.share_with(&mut rng), b.share_with(&mut rng)).collect::<Vec<_>>();
+        let shares = try_join_all(
+            zip(world.contexts(), inputs.clone()).map(|(ctx, (a, b))| multiply(ctx, a, b)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(a * b, [&shares[0], &shares[1], &shares[2]].reconstruct());
+        world.flush_captured_traffic().unwrap();
+
+        let (a2, b2) = inputs[1].clone();
+        let replay = Replay::load(&config, Role::H2, dir.path()).unwrap();
+        let replayed = multiply(replay.context(), a2.clone(), b2.clone())
+            .await
+            .unwrap();
+        assert_eq!(shares[1], replayed);
+
+        // H2 sends something else when its input is different
+        let replay = Replay::load(&config, Role::H2, dir.path()).unwrap();
+        let a2 = &a2 + &Share::new(Fp32BitPrime::ONE, Fp32BitPrime::ZERO);
+        assert!(matches!(
+            multiply(replay.context(), a2, b2).await,
+            Err(Error::ReplayMismatch { .. })
+        ));
+    }
+}
@@ -1,7 +1,7 @@
 use std::{borrow::Borrow, iter::zip, ops::Deref};
 
 use crate::{
-    ff::{Field, PrimeField},
+    ff::{curve_points::RP25519, Field, PrimeField},
     protocol::boolean::RandomBitsShare,
     secret_sharing::{
         replicated::{
@@ -62,6 +62,24 @@ impl<F: Field> Reconstruct<F> for [Replicated<F>; 3] {
     }
 }
 
+impl Reconstruct<RP25519> for [&Replicated<RP25519>; 3] {
+    fn reconstruct(&self) -> RP25519 {
+        let [s0, s1, s2] = self;
+
+        assert_eq!(s0.right(), s1.left());
+        assert_eq!(s1.right(), s2.left());
+        assert_eq!(s2.right(), s0.left());
+
+        s0.left() + s1.left() + s2.left()
+    }
+}
+
+impl Reconstruct<RP25519> for [Replicated<RP25519>; 3] {
+    fn reconstruct(&self) -> RP25519 {
+        [&self[0], &self[1], &self[2]].reconstruct()
+    }
+}
+
 impl<T, U, V, W> Reconstruct<(V, W)> for [(T, U); 3]
 where
     for<'t> [&'t T; 3]: Reconstruct<V>,
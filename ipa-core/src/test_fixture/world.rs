@@ -1,4 +1,9 @@
-use std::{fmt::Debug, io::stdout, iter::zip};
+use std::{
+    fmt::Debug,
+    io::{self, stdout},
+    iter::zip,
+    path::{Path, PathBuf},
+};
 
 use async_trait::async_trait;
 use futures::{future::join_all, Future};
@@ -7,7 +12,10 @@ use rand_core::{RngCore, SeedableRng};
 use tracing::{Instrument, Level, Span};
 
 use crate::{
-    helpers::{Gateway, GatewayConfig, InMemoryNetwork, Role, RoleAssignment},
+    helpers::{
+        Gateway, GatewayConfig, InMemoryNetwork, NetworkConditions, Role, RoleAssignment, Traffic,
+        TrafficCapture,
+    },
     protocol::{
         context::{
             Context, MaliciousContext, SemiHonestContext, UpgradableContext, UpgradeContext,
@@ -23,7 +31,7 @@ use crate::{
     },
     sync::{
         atomic::{AtomicUsize, Ordering},
-        Arc,
+        Arc, Mutex,
     },
     telemetry::{stats::Metrics, StepStatsCsvExporter},
     test_fixture::{
@@ -38,8 +46,13 @@ use crate::{
 pub struct TestWorld {
     gateways: [Gateway; 3],
     participants: [PrssEndpoint; 3],
+    seed: u64,
+    /// Generator that inputs are shared with, and that [`Self::rng`] derives generators from.
+    /// It continues the one PRSS states are derived from.
+    rng: Mutex<StdRng>,
     executions: AtomicUsize,
     metrics_handle: MetricsHandle,
+    captures: Vec<Arc<TrafficCapture>>,
     _network: InMemoryNetwork,
 }
 
@@ -52,10 +65,24 @@ pub struct TestWorldConfig {
     pub metrics_level: Level,
     /// Assignment of roles to helpers. If `None`, a default assignment will be used.
     pub role_assignment: Option<RoleAssignment>,
-    /// Seed for random generators used in PRSS
+    /// Seed that PRSS states of all helpers, input shares and generators handed out by
+    /// [`TestWorld::rng`] are derived from. Worlds created with the same seed run protocols the
+    /// same way, so failures can be reproduced. Unless set, the seed is taken from the
+    /// `IPA_TEST_SEED` environment variable, or chosen at random. Worlds print their seed when a
+    /// test fails.
     pub seed: u64,
+    /// Conditions of links between helpers. Data is delivered instantly by default.
+    pub network_conditions: NetworkConditions,
+    /// Directory where every helper writes the records it exchanges with peers, to
+    /// `{role}.txt`. Captures can be replayed with [`Replay`]. Nothing is captured if `None`.
+    ///
+    /// [`Replay`]: crate::test_fixture::Replay
+    pub capture_traffic: Option<PathBuf>,
 }
 
+/// Environment variable that sets the seed of test worlds, see [`TestWorldConfig::seed`].
+const SEED_ENV_VAR: &str = "IPA_TEST_SEED";
+
 impl Default for TestWorldConfig {
     fn default() -> Self {
         Self {
@@ -65,7 +92,15 @@ impl Default for TestWorldConfig {
             // Can be overridden by setting `RUST_LOG` environment variable to match this level.
             metrics_level: Level::DEBUG,
             role_assignment: None,
-            seed: thread_rng().next_u64(),
+            seed: std::env::var(SEED_ENV_VAR).map_or_else(
+                |_| thread_rng().next_u64(),
+                |seed| {
+                    seed.parse()
+                        .unwrap_or_else(|e| panic!("{SEED_ENV_VAR}={seed:?} is not a seed: {e}"))
+                },
+            ),
+            network_conditions: NetworkConditions::default(),
+            capture_traffic: None,
         }
     }
 }
@@ -82,6 +117,25 @@ impl TestWorldConfig {
         self.seed = seed;
         self
     }
+
+    #[must_use]
+    pub fn with_network_conditions(mut self, conditions: NetworkConditions) -> Self {
+        self.network_conditions = conditions;
+        self
+    }
+
+    #[must_use]
+    pub fn with_capture_traffic<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.capture_traffic = Some(dir.into());
+        self
+    }
+
+    /// Returns the file where the helper with the given role captures its traffic, see
+    /// [`Self::capture_traffic`].
+    #[must_use]
+    pub fn capture_file(dir: &Path, role: Role) -> PathBuf {
+        dir.join(format!("{}.txt", role.as_static_str()))
+    }
 }
 
 impl Default for TestWorld {
@@ -93,27 +147,43 @@ impl Default for TestWorld {
 impl TestWorld {
     /// Creates a new `TestWorld` instance using the provided `config`.
     /// # Panics
-    /// Never.
+    /// If traffic capture is enabled and capture files can't be created.
     #[must_use]
     pub fn new_with(config: TestWorldConfig) -> Self {
         logging::setup();
 
         let metrics_handle = MetricsHandle::new(config.metrics_level);
-        let participants = make_participants(&mut StdRng::seed_from_u64(config.seed));
-        let network = InMemoryNetwork::default();
+        let mut rng = StdRng::seed_from_u64(config.seed);
+        let participants = make_participants(&mut rng);
+        let network =
+            InMemoryNetwork::with_conditions(Default::default(), config.network_conditions);
         let role_assignment = config
             .role_assignment
             .unwrap_or_else(|| RoleAssignment::new(network.helper_identities()));
 
         let mut gateways = [None, None, None];
+        let mut captures = Vec::new();
         for i in 0..3 {
             let transport = &network.transports[i];
+            let traffic = config
+                .capture_traffic
+                .as_ref()
+                .map_or(Traffic::Live, |dir| {
+                    let role = role_assignment.role(transport.identity());
+                    let capture = Arc::new(
+                        TrafficCapture::create(TestWorldConfig::capture_file(dir, role))
+                            .expect("capture file should be created"),
+                    );
+                    captures.push(Arc::clone(&capture));
+                    Traffic::Capture(capture)
+                });
             let role_assignment = role_assignment.clone();
-            let gateway = Gateway::new(
-                QueryId,
+            let gateway = Gateway::with_traffic(
+                QueryId::default(),
                 config.gateway_config,
                 role_assignment,
                 Arc::downgrade(transport),
+                traffic,
             );
             let role = gateway.role();
             gateways[role] = Some(gateway);
@@ -123,8 +193,11 @@ impl TestWorld {
         TestWorld {
             gateways,
             participants,
+            seed: config.seed,
+            rng: Mutex::new(rng),
             executions: AtomicUsize::new(0),
             metrics_handle,
+            captures,
             _network: network,
         }
     }
@@ -172,14 +245,40 @@ impl TestWorld {
         format!("run-{execution}")
     }
 
+    /// Returns the seed this world was created with, see [`TestWorldConfig::seed`].
+    #[must_use]
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Returns a generator derived from the seed of this world. Tests that generate their inputs
+    /// with it, rather than with `thread_rng`, can be reproduced from the seed as well.
+    ///
+    /// ## Panics
+    /// If the lock is poisoned.
+    #[must_use]
+    pub fn rng(&self) -> StdRng {
+        StdRng::seed_from_u64(self.rng.lock().unwrap().next_u64())
+    }
+
     pub fn gateway(&self, role: Role) -> &Gateway {
         &self.gateways[role]
     }
 
+    /// Writes records captured so far to capture files, so they can be replayed while this
+    /// world is still around.
+    ///
+    /// ## Errors
+    /// If writing to any of the capture files fails.
+    pub fn flush_captured_traffic(&self) -> io::Result<()> {
+        self.captures.iter().try_for_each(|capture| capture.flush())
+    }
+
     /// See `Runner` below.
     async fn run_either<'a, C, I, A, O, H, R>(
         contexts: [C; 3],
         span: Span,
+        mut rng: StdRng,
         input: I,
         helper_fn: H,
     ) -> [O; 3]
@@ -191,7 +290,7 @@ impl TestWorld {
         H: Fn(C, A) -> R + Send + Sync,
         R: Future<Output = O> + Send,
     {
-        let input_shares = input.share_with(&mut thread_rng());
+        let input_shares = input.share_with(&mut rng);
         #[allow(clippy::disallowed_methods)] // It's just 3 items.
         let output =
             join_all(zip(contexts, input_shares).map(|(ctx, shares)| helper_fn(ctx, shares)))
@@ -203,6 +302,13 @@ impl TestWorld {
 
 impl Drop for TestWorld {
     fn drop(&mut self) {
+        if std::thread::panicking() {
+            eprintln!(
+                "test world was created with seed {seed}, run the test with \
+                 {SEED_ENV_VAR}={seed} to reproduce it",
+                seed = self.seed
+            );
+        }
         if tracing::span_enabled!(Level::DEBUG) {
             let metrics = self.metrics_handle.snapshot();
             metrics.export(&mut stdout()).unwrap();
@@ -271,6 +377,7 @@ impl Runner for TestWorld {
         Self::run_either(
             self.contexts(),
             self.metrics_handle.span(),
+            self.rng(),
             input,
             helper_fn,
         )
@@ -288,6 +395,7 @@ impl Runner for TestWorld {
         Self::run_either(
             self.malicious_contexts(),
             self.metrics_handle.span(),
+            self.rng(),
             input,
             helper_fn,
         )
@@ -335,3 +443,34 @@ impl Runner for TestWorld {
         output
     }
 }
+
+#[cfg(all(test, unit_test))]
+mod tests {
+    use rand::Rng;
+
+    use crate::{
+        ff::Fp31,
+        protocol::{context::Context, prss::SharedRandomness, RecordId},
+        secret_sharing::replicated::semi_honest::AdditiveShare,
+        test_fixture::{Runner, TestWorld, TestWorldConfig},
+    };
+
+    /// Shares each helper is given and randomness it draws from PRSS.
+    async fn run(world: &TestWorld) -> Vec<(AdditiveShare<Fp31>, (Fp31, Fp31))> {
+        let input = world.rng().gen::<Fp31>();
+        world
+            .semi_honest(input, |ctx, share| async move {
+                (share, ctx.prss().generate_fields(RecordId::FIRST))
+            })
+            .await
+            .to_vec()
+    }
+
+    #[tokio::test]
+    async fn same_seed_same_randomness() {
+        let world = |seed| TestWorld::new_with(TestWorldConfig::default().with_seed(seed));
+        assert_eq!(42, world(42).seed());
+        assert_eq!(run(&world(42)).await, run(&world(42)).await);
+        assert_ne!(run(&world(42)).await, run(&world(43)).await);
+    }
+}